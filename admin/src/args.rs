@@ -28,6 +28,10 @@ pub enum Command {
     /// Viewing and managing stats
     #[command(subcommand)]
     Stats(StatsCommand),
+
+    /// Manage which client IPs can reach a project
+    #[command(subcommand)]
+    IpRules(IpRulesCommand),
 }
 
 #[derive(Subcommand, Debug)]
@@ -43,21 +47,87 @@ pub enum AcmeCommand {
         acme_server: Option<String>,
     },
 
+    /// Show the TXT record a project has to publish to prove it controls a FQDN, and whether it is there yet. The
+    /// FQDN can only be added to the project once it is
+    Verification {
+        /// Fqdn to verify
+        #[arg(long, alias = "domain")]
+        fqdn: String,
+
+        /// Project to add the fqdn to
+        #[arg(long)]
+        project: ProjectName,
+    },
+
     /// Request a certificate for a FQDN
     RequestCertificate {
-        /// Fqdn to request certificate for
-        #[arg(long)]
-        fqdn: String,
+        /// Fqdn to request certificate for. Can be repeated to
+        /// request a single SAN certificate for several domains
+        #[arg(long, alias = "domain", required = true)]
+        fqdn: Vec<String>,
 
         /// Project to request certificate for
         #[arg(long)]
         project: ProjectName,
 
+        /// Environment of the project the domain should point at. The `--project` given has to be the one this
+        /// environment deploys to, as named in its `[env.<name>]` section of Shuttle.toml
+        #[arg(long)]
+        environment: Option<String>,
+
         /// Path to acme credentials file
         /// This should have been created with `acme create-account`
         #[arg(long)]
         credentials: PathBuf,
     },
+
+    /// Serve the stored certificate of a custom domain (and the other domains sharing it) again, after it was rotated
+    /// outside of the gateway. Takes effect for new connections without a restart
+    ReloadCertificate {
+        /// Fqdn to reload the certificate of
+        #[arg(long, alias = "domain")]
+        fqdn: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum IpRulesCommand {
+    /// List the IP rules of a project
+    List {
+        /// Project to list the rules of
+        #[arg(long)]
+        project: ProjectName,
+    },
+
+    /// Only allow clients in a CIDR (and those of other allow rules) to reach a project
+    Allow {
+        /// Project to add the rule to
+        #[arg(long)]
+        project: ProjectName,
+
+        /// IPv4 or IPv6 CIDR, or a single address
+        cidr: String,
+    },
+
+    /// Block clients in a CIDR from reaching a project
+    Deny {
+        /// Project to add the rule to
+        #[arg(long)]
+        project: ProjectName,
+
+        /// IPv4 or IPv6 CIDR, or a single address
+        cidr: String,
+    },
+
+    /// Remove an IP rule from a project
+    Remove {
+        /// Project to remove the rule from
+        #[arg(long)]
+        project: ProjectName,
+
+        /// CIDR of the rule to remove
+        cidr: String,
+    },
 }
 
 #[derive(Subcommand, Debug)]
@@ -1,7 +1,7 @@
 use anyhow::{Context, Result};
 use serde::{de::DeserializeOwned, Serialize};
 use shuttle_common::{
-    models::{project, stats, ToJson},
+    models::{domain_verification, ip_rule, project, stats, ToJson},
     project::ProjectName,
 };
 use tracing::trace;
@@ -31,14 +31,58 @@ impl Client {
 
     pub async fn acme_request_certificate(
         &self,
-        fqdn: &str,
+        fqdns: &[String],
         project_name: &ProjectName,
+        environment: Option<&str>,
         credentials: &serde_json::Value,
     ) -> Result<String> {
-        let path = format!("/admin/acme/request/{project_name}/{fqdn}");
+        let mut path = format!("/admin/acme/request/{project_name}/{}", fqdns.join(","));
+
+        if let Some(environment) = environment {
+            path.push_str(&format!("?environment={environment}"));
+        }
+
         self.post(&path, Some(credentials)).await
     }
 
+    pub async fn get_domain_verification(
+        &self,
+        fqdn: &str,
+        project_name: &ProjectName,
+    ) -> Result<domain_verification::Response> {
+        self.get(&format!("/admin/acme/verification/{project_name}/{fqdn}"))
+            .await
+    }
+
+    pub async fn reload_certificate(&self, fqdn: &str) -> Result<String> {
+        let path = format!("/admin/tls/reload/{fqdn}");
+        self.post(&path, Option::<String>::None).await
+    }
+
+    pub async fn get_ip_rules(&self, project_name: &ProjectName) -> Result<Vec<ip_rule::Rule>> {
+        self.get(&format!("/admin/projects/{project_name}/ip-rules"))
+            .await
+    }
+
+    pub async fn set_ip_rule(
+        &self,
+        project_name: &ProjectName,
+        rule: ip_rule::Rule,
+    ) -> Result<Vec<ip_rule::Rule>> {
+        let path = format!("/admin/projects/{project_name}/ip-rules");
+        self.post(&path, Some(rule)).await
+    }
+
+    pub async fn delete_ip_rule(
+        &self,
+        project_name: &ProjectName,
+        cidr: String,
+    ) -> Result<Vec<ip_rule::Rule>> {
+        let path = format!("/admin/projects/{project_name}/ip-rules");
+        self.delete(&path, Some(ip_rule::DeleteRequest { cidr }))
+            .await
+    }
+
     pub async fn get_projects(&self) -> Result<Vec<project::AdminResponse>> {
         self.get("/admin/projects").await
     }
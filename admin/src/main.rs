@@ -1,9 +1,10 @@
 use clap::Parser;
 use shuttle_admin::{
-    args::{AcmeCommand, Args, Command, StatsCommand},
+    args::{AcmeCommand, Args, Command, IpRulesCommand, StatsCommand},
     client::Client,
     config::get_api_key,
 };
+use shuttle_common::models::ip_rule;
 use std::{
     collections::{hash_map::RandomState, HashMap},
     fmt::Write,
@@ -36,9 +37,15 @@ async fn main() {
 
             res
         }
+        Command::Acme(AcmeCommand::Verification { fqdn, project }) => client
+            .get_domain_verification(&fqdn, &project)
+            .await
+            .expect("to get the domain verification")
+            .to_string(),
         Command::Acme(AcmeCommand::RequestCertificate {
             fqdn,
             project,
+            environment,
             credentials,
         }) => {
             let credentials = fs::read_to_string(credentials).expect("to read credentials file");
@@ -46,10 +53,14 @@ async fn main() {
                 serde_json::from_str(&credentials).expect("to parse content of credentials file");
 
             client
-                .acme_request_certificate(&fqdn, &project, &credentials)
+                .acme_request_certificate(&fqdn, &project, environment.as_deref(), &credentials)
                 .await
                 .expect("to get a certificate challenge response")
         }
+        Command::Acme(AcmeCommand::ReloadCertificate { fqdn }) => client
+            .reload_certificate(&fqdn)
+            .await
+            .expect("to reload the certificate"),
         Command::ProjectNames => {
             let projects = client
                 .get_projects()
@@ -141,6 +152,49 @@ async fn main() {
 
             res
         }
+        Command::IpRules(command) => {
+            let rules = match command {
+                IpRulesCommand::List { project } => client.get_ip_rules(&project).await,
+                IpRulesCommand::Allow { project, cidr } => {
+                    client
+                        .set_ip_rule(
+                            &project,
+                            ip_rule::Rule {
+                                cidr,
+                                action: ip_rule::Action::Allow,
+                            },
+                        )
+                        .await
+                }
+                IpRulesCommand::Deny { project, cidr } => {
+                    client
+                        .set_ip_rule(
+                            &project,
+                            ip_rule::Rule {
+                                cidr,
+                                action: ip_rule::Action::Deny,
+                            },
+                        )
+                        .await
+                }
+                IpRulesCommand::Remove { project, cidr } => {
+                    client.delete_ip_rule(&project, cidr).await
+                }
+            }
+            .expect("to manage IP rules");
+
+            let mut res = String::new();
+
+            if rules.is_empty() {
+                writeln!(res, "no IP rules, all clients can reach the project").unwrap();
+            }
+
+            for rule in rules {
+                writeln!(res, "{:<6}{}", rule.action, rule.cidr).unwrap();
+            }
+
+            res
+        }
         Command::Stats(StatsCommand::Load { clear }) => {
             let resp = if clear {
                 client.clear_load().await.expect("to delete load stats")
@@ -210,6 +210,7 @@ impl From<AccountTier> for Vec<Scope> {
                 Scope::AcmeCreate,
                 Scope::CustomDomainCreate,
                 Scope::Admin,
+                Scope::Deployer,
             ]);
         }
 
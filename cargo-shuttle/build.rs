@@ -0,0 +1,27 @@
+use std::path::Path;
+use std::process::Command;
+
+/// Embed the commit and date of the build, for `cargo shuttle --version --output-format json`
+fn main() {
+    let commit = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|commit| commit.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+
+    println!("cargo:rustc-env=SHUTTLE_GIT_COMMIT={commit}");
+    println!(
+        "cargo:rustc-env=SHUTTLE_BUILD_DATE={}",
+        chrono::Utc::now().format("%Y-%m-%d")
+    );
+
+    println!("cargo:rerun-if-changed=build.rs");
+
+    // Builds from a published crate have no repository to follow
+    if Path::new("../.git/HEAD").exists() {
+        println!("cargo:rerun-if-changed=../.git/HEAD");
+    }
+}
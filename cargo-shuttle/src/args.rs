@@ -3,21 +3,32 @@ use std::{
     fs::create_dir_all,
     io::{self, ErrorKind},
     path::PathBuf,
+    str::FromStr,
 };
 
-use clap::builder::{OsStringValueParser, PossibleValue, TypedValueParser};
-use clap::Parser;
+use chrono::{DateTime, Duration, Utc};
+use clap::builder::{BoolishValueParser, OsStringValueParser, PossibleValue, TypedValueParser};
+use clap::{ArgAction, Parser, ValueEnum};
 use clap_complete::Shell;
+use crossterm::tty::IsTty;
 use dunce::canonicalize;
-use shuttle_common::{models::project::IDLE_MINUTES, project::ProjectName};
+use shuttle_common::{
+    deployment::{Environment, State},
+    models::deployment::LATEST_TAG,
+    project::ProjectName,
+};
 use uuid::Uuid;
 
+use crate::config::InitDefaults;
 use crate::init::Framework;
 
 #[derive(Parser)]
 #[command(
     version,
     about,
+    // `--version` is handled by hand so that it can be given as JSON too
+    disable_version_flag = true,
+    arg_required_else_help = true,
     // Cargo passes in the subcommand name to the invoked executable. Use a
     // hidden, optional positional argument to deal with it.
     arg(clap::Arg::new("dummy") 
@@ -30,27 +41,96 @@ pub struct Args {
     /// (allows targeting a custom deployed instance for this command only)
     #[arg(long, env = "SHUTTLE_API")]
     pub api_url: Option<String>,
+    /// print version
+    #[arg(short = 'V', long, action = ArgAction::SetTrue)]
+    pub version: bool,
+    /// format to print `--version`, `init --list-templates`, `account usage`, `deployment list` and `deployment status` in
+    #[arg(global = true, long, value_enum, default_value_t = OutputFormat::Text)]
+    pub output_format: OutputFormat,
+    /// when to color the output, `auto` colors it for a terminal unless `NO_COLOR` is set
+    #[arg(global = true, long, value_enum, value_name = "WHEN", default_value_t = ColorChoice::Auto)]
+    pub color: ColorChoice,
+    /// only print what a command is asked for and errors, leaving out progress, build logs and warnings
+    #[arg(global = true, short, long, conflicts_with = "verbose")]
+    pub quiet: bool,
+    /// print the API calls made and how they went, give it twice to trace everything the CLI does
+    #[arg(global = true, short, long, action = ArgAction::Count)]
+    pub verbose: u8,
     #[command(flatten)]
     pub project_args: ProjectArgs,
     #[command(subcommand)]
-    pub cmd: Command,
+    pub cmd: Option<Command>,
+}
+
+impl Args {
+    /// Which logs to show for `--quiet` and `--verbose`, when `RUST_LOG` does not say
+    pub fn log_filter(&self) -> &'static str {
+        match (self.quiet, self.verbose) {
+            (true, _) | (false, 0) => "error",
+            (false, 1) => "error,cargo_shuttle=debug",
+            (false, _) => "debug,cargo_shuttle=trace",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+/// Formats the logs of a deployment can be exported in
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ExportFormat {
+    /// One JSON array of all the lines
+    Json,
+    /// A header row, then one row a line
+    Csv,
+    /// One JSON object a line
+    Ndjson,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    /// Whether output should be colored, following <https://no-color.org> when left to `auto`
+    pub fn colors(self) -> bool {
+        match self {
+            Self::Always => true,
+            Self::Never => false,
+            Self::Auto => {
+                std::env::var_os("NO_COLOR").map_or(true, |no_color| no_color.is_empty())
+                    && io::stdout().is_tty()
+            }
+        }
+    }
 }
 
 // Common args for subcommands that deal with projects.
 #[derive(Parser, Debug)]
 pub struct ProjectArgs {
     /// Specify the working directory
-    #[arg(global = true, long, default_value = ".", value_parser = OsStringValueParser::new().try_map(parse_path))]
+    #[arg(global = true, long, env = "SHUTTLE_WORKING_DIRECTORY", default_value = ".", value_parser = OsStringValueParser::new().try_map(parse_path))]
     pub working_directory: PathBuf,
     /// Specify the name of the project (overrides crate name)
-    #[arg(global = true, long)]
+    #[arg(global = true, long, env = "SHUTTLE_PROJECT_NAME")]
     pub name: Option<ProjectName>,
+    /// Layer the `[env.<ENVIRONMENT>]` section of Shuttle.toml over its base settings
+    #[arg(global = true, long, env = "SHUTTLE_ENVIRONMENT")]
+    pub environment: Option<String>,
 }
 
 #[derive(Parser)]
 pub enum Command {
     /// deploy a shuttle service
     Deploy(DeployArgs),
+    /// check that a deploy would package, build and pass the tests of this service, without calling the API
+    Check(CheckArgs),
     /// manage deployments of a shuttle service
     #[command(subcommand)]
     Deployment(DeploymentCommand),
@@ -67,21 +147,58 @@ pub enum Command {
     },
     /// view the status of a shuttle service
     Status,
+    /// check that the running deployment is the one recorded in a manifest
+    Verify {
+        /// manifest written by `cargo shuttle deploy --write-manifest`
+        #[arg(long, value_name = "PATH")]
+        manifest: PathBuf,
+    },
+    /// open the deployed shuttle service in the browser
+    Open,
     /// view the logs of a deployment in this shuttle service
     Logs {
-        /// Deployment ID to get logs for. Defaults to currently running deployment
-        id: Option<Uuid>,
+        /// Deployment ID, or `latest`, to get logs for. Defaults to currently running deployment
+        id: Option<DeploymentRef>,
 
         #[arg(short, long)]
         /// Follow log output
         follow: bool,
+
+        #[arg(long)]
+        /// Tag every line the service printed with the stream it was printed to
+        show_stream: bool,
+
+        #[arg(long, conflicts_with = "stdout")]
+        /// Only show lines printed to stderr
+        stderr: bool,
+
+        #[arg(long)]
+        /// Only show lines printed to stdout
+        stdout: bool,
+
+        #[arg(long, value_name = "N")]
+        /// Only show the last N lines. When following, these are shown before new lines are streamed
+        tail: Option<u32>,
+
+        #[arg(long, value_name = "FORMAT", conflicts_with_all = ["follow", "tail"])]
+        /// Write out every line of the deployment in this format instead of showing them
+        export: Option<ExportFormat>,
+
+        #[arg(long, value_name = "PATH", requires = "export")]
+        /// File to export the logs to. Defaults to stdout
+        out: Option<PathBuf>,
     },
     /// remove artifacts that were generated by cargo
     Clean,
     /// stop this shuttle service
     Stop,
+    /// restart the running deployment of this shuttle service without building it again
+    Restart,
     /// manage secrets for this shuttle service
-    Secrets,
+    Secrets {
+        #[command(subcommand)]
+        cmd: Option<SecretsCommand>,
+    },
     /// login to the shuttle platform
     Login(LoginArgs),
     /// log out of the shuttle platform
@@ -93,26 +210,109 @@ pub enum Command {
     /// manage a project on shuttle
     #[command(subcommand)]
     Project(ProjectCommand),
+    /// view details of the account logged in
+    #[command(subcommand)]
+    Account(AccountCommand),
+}
+
+#[derive(Parser)]
+pub enum SecretsCommand {
+    /// make the secrets of this service exactly those of a TOML file, showing what changes before applying it
+    Sync {
+        /// file with the secrets, like Secrets.toml
+        file: PathBuf,
+        /// apply the changes without asking
+        #[arg(long, short)]
+        yes: bool,
+        /// remove the secrets which are not in the file, which are otherwise kept
+        #[arg(long)]
+        prune: bool,
+    },
+}
+
+#[derive(Parser)]
+pub enum AccountCommand {
+    /// show what the account uses of the platform, against its limits
+    Usage,
 }
 
 #[derive(Parser)]
 pub enum DeploymentCommand {
     /// list all the deployments for a service
-    List,
+    List {
+        /// only list deployments in this state, can be given more than once
+        #[arg(long = "status", value_name = "STATE")]
+        states: Vec<State>,
+        /// only list deployments last updated at or after this time, either RFC 3339 or how long ago like `2h` or `7d`
+        #[arg(long, value_name = "TIME", value_parser = parse_time)]
+        since: Option<DateTime<Utc>>,
+        /// only list deployments last updated at or before this time, either RFC 3339 or how long ago like `2h` or `7d`
+        #[arg(long, value_name = "TIME", value_parser = parse_time)]
+        until: Option<DateTime<Utc>>,
+    },
     /// view status of a deployment
     Status {
-        /// ID of deployment to get status for
-        id: Uuid,
+        /// ID of deployment, or `latest`, to get status for
+        id: DeploymentRef,
+        /// print everything known about the deployment as JSON, like its state history and last error
+        #[arg(long)]
+        json: bool,
+    },
+    /// cancel a deployment which is still queued or building
+    Cancel {
+        /// ID of deployment, or `latest`, to cancel
+        id: DeploymentRef,
+    },
+    /// delete the stored artifact and logs of a deployment, keeping its record and state history
+    Rm {
+        /// ID of deployment, or `latest`, to delete the artifact of
+        id: DeploymentRef,
+        /// stop the deployment first if it is the one running
+        #[arg(long)]
+        force: bool,
     },
+    /// capture a CPU profile of a running deployment as a flamegraph, when the deployer allows it
+    Profile {
+        /// ID of deployment, or `latest`, to profile
+        id: DeploymentRef,
+        /// how long to sample the deployment for, up to 60
+        #[arg(long, default_value_t = 10)]
+        seconds: u64,
+        /// file to write the flamegraph SVG to
+        #[arg(long, short, default_value = "profile.svg")]
+        output: PathBuf,
+    },
+}
+
+/// A deployment given by its immutable ID, or by the tag moved along by `deploy --tag-latest`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DeploymentRef {
+    Id(Uuid),
+    Latest,
+}
+
+impl FromStr for DeploymentRef {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == LATEST_TAG {
+            Ok(Self::Latest)
+        } else {
+            s.parse()
+                .map(Self::Id)
+                .map_err(|_| format!("'{s}' is neither a deployment ID nor '{LATEST_TAG}'"))
+        }
+    }
 }
 
 #[derive(Parser)]
 pub enum ProjectCommand {
     /// create an environment for this project on shuttle
     New {
-        #[arg(long, default_value_t = IDLE_MINUTES)]
-        /// How long to wait before putting the project in an idle state due to inactivity. 0 means the project will never idle
-        idle_minutes: u64,
+        #[arg(long)]
+        /// How long to wait before putting the project in an idle state due to inactivity. 0 means the project will never idle.
+        /// Defaults to the `idle-minutes` of Shuttle.toml, or 30
+        idle_minutes: Option<u64>,
     },
     /// list all projects belonging to the calling account
     List {
@@ -124,27 +324,203 @@ pub enum ProjectCommand {
     Rm,
     /// show the status of this project's environment on shuttle
     Status {
-        #[arg(short, long)]
+        #[arg(short, long, conflicts_with = "history")]
         /// Follow status of project command
         follow: bool,
+        /// show when the project was created, went idle, woke up, errored and recovered
+        #[arg(long)]
+        history: bool,
+    },
+    /// show request counts, error rates and latencies of recent traffic to this project
+    Stats,
+    /// keep this project, and the deployment it runs, from idling until it is unpinned, whatever its idle timer says
+    Pin,
+    /// let this project idle again once its idle timer runs out
+    Unpin,
+    /// hand this project, with its deployments, custom domains and secrets, over to another account, which has to
+    /// accept it. Lists the pending transfers to and from this account without arguments
+    Transfer {
+        /// account to offer this project to
+        #[arg(conflicts_with_all = ["accept", "cancel"])]
+        to_account: Option<String>,
+        /// accept this project from the account offering it to this one
+        #[arg(long, conflicts_with = "cancel")]
+        accept: bool,
+        /// call off the transfer of this project, or decline it when it is offered to this account
+        #[arg(long)]
+        cancel: bool,
+    },
+    /// show or change the `Host` header requests to this project are forwarded with
+    HostHeader {
+        /// forward the public host the client asked for (the default)
+        #[arg(long, conflicts_with_all = ["backend", "fixed"])]
+        preserve: bool,
+        /// rewrite it to the internal address of the project's backend
+        #[arg(long, conflicts_with = "fixed")]
+        backend: bool,
+        /// always forward this host
+        #[arg(long, value_name = "HOST")]
+        fixed: Option<String>,
+    },
+    /// show or change how long requests to this project wait on its service
+    Timeouts {
+        /// answer requests with a 504 when the service has not started responding within this many seconds
+        #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+        response: Option<u64>,
+        /// close the connection once a response body goes this many seconds without sending anything
+        #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..), conflicts_with = "abort_stalled")]
+        body_idle: Option<u64>,
+        /// abort responses whose body is still being sent when the response timeout is up
+        #[arg(long)]
+        abort_stalled: bool,
+        /// log a warning for requests the service takes more than this many milliseconds to start responding to
+        #[arg(long, value_name = "MILLISECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+        slow_request: Option<u64>,
+        /// wait on responses for as long as they take and log slow requests by the threshold of the platform, which is
+        /// the default
+        #[arg(long, conflicts_with_all = ["response", "body_idle", "abort_stalled", "slow_request"])]
+        reset: bool,
+    },
+    /// show or change how large the headers of requests to this project and of its responses can be
+    HeaderLimits {
+        /// answer requests whose headers take more than this many bytes together with a 431
+        #[arg(long, value_name = "BYTES", value_parser = clap::value_parser!(u64).range(1..))]
+        max_total_size: Option<u64>,
+        /// answer requests with more than this many headers with a 431
+        #[arg(long, value_name = "COUNT", value_parser = clap::value_parser!(u64).range(1..))]
+        max_count: Option<u64>,
+        /// answer requests with a header of more than this many bytes with a 431
+        #[arg(long, value_name = "BYTES", value_parser = clap::value_parser!(u64).range(1..))]
+        max_header_size: Option<u64>,
+        /// go back to the limits of the platform
+        #[arg(long, conflicts_with_all = ["max_total_size", "max_count", "max_header_size"])]
+        reset: bool,
+    },
+    /// show or change which headers of responses from this project are overridden or removed
+    ResponseHeaders {
+        /// always send this header with this value, like `server=my-app`
+        #[arg(long, value_name = "NAME=VALUE", value_parser = parse_header)]
+        set: Vec<(String, String)>,
+        /// never send this header
+        #[arg(long, value_name = "NAME")]
+        remove: Vec<String>,
+        /// send this header as the service gives it again, which is the default
+        #[arg(long, value_name = "NAME")]
+        allow: Vec<String>,
+    },
+    /// show or change the username and password requests to this project need
+    BasicAuth {
+        /// ask requests for the password of this user, prompting for the password
+        #[arg(long, value_name = "USER")]
+        username: Option<String>,
+        /// the password, instead of prompting for it
+        #[arg(
+            long,
+            env = "SHUTTLE_BASIC_AUTH_PASSWORD",
+            hide_env_values = true,
+            requires = "username"
+        )]
+        password: Option<String>,
+        /// let requests to this path, and everything under it, through without a password, like `/health`
+        #[arg(long, value_name = "PATH", requires = "username")]
+        exempt: Vec<String>,
+        /// stop asking requests for a password
+        #[arg(long, conflicts_with = "username")]
+        remove: bool,
     },
 }
 
 #[derive(Parser, Clone, Debug)]
 pub struct LoginArgs {
     /// api key for the shuttle platform
-    #[arg(long)]
+    #[arg(long, env = "SHUTTLE_API_KEY", hide_env_values = true)]
     pub api_key: Option<String>,
 }
 
 #[derive(Parser)]
 pub struct DeployArgs {
     /// allow dirty working directories to be packaged
-    #[arg(long)]
+    #[arg(long, env = "SHUTTLE_ALLOW_DIRTY", value_parser = BoolishValueParser::new())]
     pub allow_dirty: bool,
     /// allows pre-deploy tests to be skipped
+    #[arg(long, env = "SHUTTLE_NO_TEST", value_parser = BoolishValueParser::new())]
+    pub no_test: bool,
+    /// path on the service to call before the deployment is stopped (overrides `pre-stop` in Shuttle.toml)
     #[arg(long)]
+    pub pre_stop: Option<String>,
+    /// point the `latest` tag to this deployment
+    #[arg(long)]
+    pub tag_latest: bool,
+    /// build without `--locked`, letting cargo update Cargo.lock if it is out of date
+    #[arg(long)]
+    pub no_locked: bool,
+    /// package this directory instead of just the crate, so path dependencies outside the crate are uploaded too (it
+    /// has to contain the crate)
+    #[arg(long, value_name = "PATH")]
+    pub package_root: Option<PathBuf>,
+    /// cancel the deployments of this service which are still queued or building before deploying
+    #[arg(long)]
+    pub cancel_previous: bool,
+    /// once the deployment is running, record it in a manifest at this path which `cargo shuttle verify` can check
+    /// against later
+    #[arg(long, value_name = "PATH")]
+    pub write_manifest: Option<PathBuf>,
+    /// run this pre-built container image, like `ghcr.io/org/app:1.0`, instead of packaging and building the crate. It
+    /// has to listen on the port given in `PORT`
+    #[arg(long, value_name = "REFERENCE", conflicts_with_all = ["allow_dirty", "no_test", "no_locked", "package_root", "secrets", "secret"])]
+    pub image: Option<String>,
+    /// stop the deployment once it has been running for this many seconds, instead of letting it run until it is
+    /// replaced or stopped
+    #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u32).range(1..))]
+    pub run_budget: Option<u32>,
+    /// let the deployment expire this many seconds after it is made, stopping it then even if it is restarted in
+    /// between
+    #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u32).range(1..))]
+    pub ttl: Option<u32>,
+    /// fail instead of warning when the shuttle dependencies of the crate do not match the version the platform runs
+    #[arg(long)]
+    pub strict: bool,
+    /// keep printing the logs of the deployment once it is running, until interrupted
+    #[arg(long)]
+    pub follow: bool,
+    /// when following, start with the last N lines of the deployment instead of the lines logged since it started
+    /// running
+    #[arg(long, requires = "follow")]
+    pub tail: Option<u32>,
+    /// only print what the service logs to stdout, as it logged it or as JSON with `--output-format json`, for piping
+    /// it into another tool. Everything else is printed to stderr, without colors, progress or tables
+    #[arg(long)]
+    pub raw: bool,
+    /// deploy again up to N times, waiting longer every time, when the deployment fails for a reason on the side of the
+    /// platform, like a build which timed out. Failures of the code itself are not retried
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub retry_on_failure: u32,
+    /// send the secrets in this TOML file instead of the `Secrets.toml` of the crate. Like those, they are only applied
+    /// once the deployment builds, so a failed deployment leaves the secrets of the running one as they are
+    #[arg(long, value_name = "PATH")]
+    pub secrets: Option<PathBuf>,
+    /// send this secret along with the deployment, overriding the one with the same key in the secrets file
+    #[arg(long, value_name = "KEY=VALUE", value_parser = parse_secret)]
+    pub secret: Vec<(String, String)>,
+}
+
+#[derive(Parser, Debug)]
+pub struct CheckArgs {
+    /// allow dirty working directories to be packaged
+    #[arg(long, env = "SHUTTLE_ALLOW_DIRTY", value_parser = BoolishValueParser::new())]
+    pub allow_dirty: bool,
+    /// skip the tests which would run before the deployment
+    #[arg(long, env = "SHUTTLE_NO_TEST", value_parser = BoolishValueParser::new())]
     pub no_test: bool,
+    /// build without `--locked`, letting cargo update Cargo.lock if it is out of date
+    #[arg(long)]
+    pub no_locked: bool,
+    /// package this directory instead of just the crate, like `deploy --package-root`
+    #[arg(long, value_name = "PATH")]
+    pub package_root: Option<PathBuf>,
+    /// fail instead of warning when the shuttle dependencies of the crate do not match the version the platform runs
+    #[arg(long)]
+    pub strict: bool,
 }
 
 #[derive(Parser, Debug)]
@@ -158,6 +534,17 @@ pub struct RunArgs {
     /// Use release mode for building the project.
     #[arg(long, short = 'r')]
     pub release: bool,
+    /// environment reported to the service by `Factory::get_environment()` (local or production)
+    #[arg(long, env = "SHUTTLE_ENVIRONMENT_KIND", default_value = "local")]
+    pub environment_kind: Environment,
+    /// stop the service after this many seconds without requests and start it again on the next one, like the
+    /// platform does with idle projects
+    #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub idle: Option<u64>,
+    /// print the process ID and wait for a debugger to attach before the service starts, so that its startup can be
+    /// stepped through. Needs a debug build
+    #[arg(long, conflicts_with_all = &["release", "idle"])]
+    pub wait_for_debugger: bool,
 }
 
 #[derive(Parser, Debug)]
@@ -201,8 +588,17 @@ pub struct InitArgs {
     /// Whether to create the environment for this project on Shuttle
     #[arg(long)]
     pub new: bool,
+    /// Initialize in a non-empty directory, overwriting only the files generated by init
+    #[arg(long)]
+    pub force: bool,
+    /// List the framework templates to initialize with, then exit without initializing anything
+    #[arg(long, conflicts_with_all = &["new", "force"])]
+    pub list_templates: bool,
     #[command(flatten)]
     pub login_args: LoginArgs,
+    /// Defaults from the global configuration for anything not given as a flag
+    #[arg(skip)]
+    pub defaults: InitDefaults,
     /// Path to initialize a new shuttle project
     #[arg(default_value = ".", value_parser = OsStringValueParser::new().try_map(parse_path) )]
     pub path: PathBuf,
@@ -235,9 +631,14 @@ impl InitArgs {
         } else if self.no_framework {
             Some(Framework::None)
         } else {
-            None
+            self.defaults.framework
         }
     }
+
+    /// Whether to create the environment for this project on Shuttle
+    pub fn create_environment(&self) -> bool {
+        self.new || self.defaults.new
+    }
 }
 
 // Helper function to parse and return the absolute path
@@ -245,6 +646,51 @@ fn parse_path(path: OsString) -> Result<PathBuf, String> {
     canonicalize(&path).map_err(|e| format!("could not turn {path:?} into a real path: {e}"))
 }
 
+// Helper function to parse a time given either in RFC 3339 or as how long ago it was, like `30m` or `7d`
+fn parse_time(time: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(time) {
+        return Ok(time.with_timezone(&Utc));
+    }
+
+    let error = || format!("{time:?} is neither an RFC 3339 time nor a duration like `2h` or `7d`");
+    let split = time.len().checked_sub(1).ok_or_else(error)?;
+    let (count, unit) = time.split_at(split);
+    let count: i64 = count.parse().map_err(|_| error())?;
+    let ago = match unit {
+        "s" => Duration::seconds(count),
+        "m" => Duration::minutes(count),
+        "h" => Duration::hours(count),
+        "d" => Duration::days(count),
+        "w" => Duration::weeks(count),
+        _ => return Err(error()),
+    };
+
+    Ok(Utc::now() - ago)
+}
+
+// Helper function to parse a header given as `name=value`
+fn parse_header(header: &str) -> Result<(String, String), String> {
+    let (name, value) = header
+        .split_once('=')
+        .ok_or_else(|| format!("{header:?} is not of the form `name=value`"))?;
+
+    Ok((name.trim().to_lowercase(), value.trim().to_string()))
+}
+
+// Helper function to parse a secret given as `key=value`, keeping the value as it is
+fn parse_secret(secret: &str) -> Result<(String, String), String> {
+    let (key, value) = secret
+        .split_once('=')
+        .ok_or_else(|| format!("{secret:?} is not of the form `key=value`"))?;
+    let key = key.trim();
+
+    if key.is_empty() {
+        return Err(format!("{secret:?} has an empty key"));
+    }
+
+    Ok((key.to_string(), value.to_string()))
+}
+
 // Helper function to parse, create if not exists, and return the absolute path
 pub(crate) fn parse_init_path(path: OsString) -> Result<PathBuf, io::Error> {
     // Create the directory if does not exist
@@ -260,6 +706,7 @@ pub(crate) fn parse_init_path(path: OsString) -> Result<PathBuf, io::Error> {
 
 #[cfg(test)]
 mod tests {
+    use clap::CommandFactory;
     use strum::IntoEnumIterator;
 
     use super::*;
@@ -279,7 +726,10 @@ mod tests {
             thruster: false,
             no_framework: false,
             new: false,
+            force: false,
+            list_templates: false,
             login_args: LoginArgs { api_key: None },
+            defaults: Default::default(),
             path: PathBuf::new(),
         };
 
@@ -309,4 +759,697 @@ mod tests {
             assert_eq!(args.framework(), Some(framework));
         }
     }
+
+    #[test]
+    fn test_init_args_framework_defaults() {
+        let mut args = init_args_factory("rocket");
+        args.defaults = InitDefaults {
+            framework: Some(Framework::Axum),
+            new: true,
+        };
+
+        // Flags win over the configured defaults
+        assert_eq!(args.framework(), Some(Framework::Rocket));
+
+        args.rocket = false;
+        assert_eq!(args.framework(), Some(Framework::Axum));
+        assert!(args.create_environment());
+    }
+
+    #[test]
+    fn test_framework_flags() {
+        for framework in Framework::iter() {
+            let args = InitArgs::try_parse_from(["init", &framework.flag()]).unwrap();
+            assert_eq!(args.framework(), Some(framework));
+        }
+    }
+
+    #[test]
+    fn test_init_list_templates() {
+        let args = InitArgs::try_parse_from(["init", "--list-templates"]).unwrap();
+        assert!(args.list_templates);
+        assert_eq!(args.framework(), None);
+
+        assert!(InitArgs::try_parse_from(["init", "--list-templates", "--new"]).is_err());
+    }
+
+    #[test]
+    fn test_deployment_ref() {
+        let id = Uuid::new_v4();
+
+        assert_eq!(id.to_string().parse(), Ok(DeploymentRef::Id(id)));
+        assert_eq!("latest".parse(), Ok(DeploymentRef::Latest));
+        assert!("newest".parse::<DeploymentRef>().is_err());
+    }
+
+    #[test]
+    fn test_wait_for_debugger() {
+        assert!(Args::try_parse_from(["cargo-shuttle", "run", "--wait-for-debugger"]).is_ok());
+        assert!(
+            Args::try_parse_from(["cargo-shuttle", "run", "--wait-for-debugger", "--release"])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_color_choice() {
+        assert!(ColorChoice::Always.colors());
+        assert!(!ColorChoice::Never.colors());
+
+        let args = Args::try_parse_from(["cargo-shuttle", "status", "--color", "never"]).unwrap();
+        assert_eq!(args.color, ColorChoice::Never);
+        assert!(Args::try_parse_from(["cargo-shuttle", "status", "--color", "sometimes"]).is_err());
+    }
+
+    #[test]
+    fn test_verbosity() {
+        let args = Args::try_parse_from(["cargo-shuttle", "status"]).unwrap();
+        assert!(!args.quiet);
+        assert_eq!(args.log_filter(), "error");
+
+        let args = Args::try_parse_from(["cargo-shuttle", "-q", "status"]).unwrap();
+        assert!(args.quiet);
+        assert_eq!(args.log_filter(), "error");
+
+        let args = Args::try_parse_from(["cargo-shuttle", "status", "--verbose"]).unwrap();
+        assert_eq!(args.verbose, 1);
+        assert_eq!(args.log_filter(), "error,cargo_shuttle=debug");
+
+        let args = Args::try_parse_from(["cargo-shuttle", "deploy", "-vv"]).unwrap();
+        assert_eq!(args.verbose, 2);
+        assert_eq!(args.log_filter(), "debug,cargo_shuttle=trace");
+
+        assert!(Args::try_parse_from(["cargo-shuttle", "status", "-q", "-v"]).is_err());
+    }
+
+    #[test]
+    fn test_deploy_raw() {
+        let Some(Command::Deploy(args)) = Args::try_parse_from([
+            "cargo-shuttle",
+            "deploy",
+            "--raw",
+            "--follow",
+            "--tail",
+            "20",
+        ])
+        .unwrap()
+        .cmd
+        else {
+            panic!("expected a deploy");
+        };
+
+        assert!(args.raw);
+        assert!(args.follow);
+        assert_eq!(args.tail, Some(20));
+        assert!(Args::try_parse_from(["cargo-shuttle", "deploy", "--tail", "20"]).is_err());
+    }
+
+    #[test]
+    fn test_deploy_retry_on_failure() {
+        let Some(Command::Deploy(args)) =
+            Args::try_parse_from(["cargo-shuttle", "deploy", "--retry-on-failure", "3"])
+                .unwrap()
+                .cmd
+        else {
+            panic!("expected a deploy");
+        };
+        assert_eq!(args.retry_on_failure, 3);
+
+        let Some(Command::Deploy(args)) = Args::try_parse_from(["cargo-shuttle", "deploy"])
+            .unwrap()
+            .cmd
+        else {
+            panic!("expected a deploy");
+        };
+        assert_eq!(args.retry_on_failure, 0);
+    }
+
+    #[test]
+    fn test_deploy_secrets() {
+        let Some(Command::Deploy(args)) = Args::try_parse_from([
+            "cargo-shuttle",
+            "deploy",
+            "--secrets",
+            "Secrets.prod.toml",
+            "--secret",
+            "API_KEY=abc=",
+            "--secret",
+            "EMPTY=",
+        ])
+        .unwrap()
+        .cmd
+        else {
+            panic!("expected a deploy");
+        };
+
+        assert_eq!(args.secrets, Some(PathBuf::from("Secrets.prod.toml")));
+        assert_eq!(
+            args.secret,
+            [
+                ("API_KEY".to_string(), "abc=".to_string()),
+                ("EMPTY".to_string(), String::new()),
+            ]
+        );
+
+        assert!(Args::try_parse_from(["cargo-shuttle", "deploy", "--secret", "API_KEY"]).is_err());
+        assert!(Args::try_parse_from(["cargo-shuttle", "deploy", "--secret", "=abc"]).is_err());
+        assert!(Args::try_parse_from([
+            "cargo-shuttle",
+            "deploy",
+            "--image",
+            "ghcr.io/org/app:1.0",
+            "--secret",
+            "API_KEY=abc",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_check() {
+        let Some(Command::Check(args)) =
+            Args::try_parse_from(["cargo-shuttle", "check", "--no-test", "--allow-dirty"])
+                .unwrap()
+                .cmd
+        else {
+            panic!("expected a check");
+        };
+
+        assert!(args.no_test);
+        assert!(args.allow_dirty);
+        assert!(!args.no_locked);
+        assert!(Args::try_parse_from(["cargo-shuttle", "check", "--image", "app:1.0"]).is_err());
+    }
+
+    #[test]
+    fn test_deployment_rm() {
+        let Some(Command::Deployment(DeploymentCommand::Rm { id, force })) =
+            Args::try_parse_from(["cargo-shuttle", "deployment", "rm", "latest", "--force"])
+                .unwrap()
+                .cmd
+        else {
+            panic!("expected a deployment rm");
+        };
+
+        assert_eq!(id, DeploymentRef::Latest);
+        assert!(force);
+        assert!(Args::try_parse_from(["cargo-shuttle", "deployment", "rm"]).is_err());
+    }
+
+    #[test]
+    fn test_deployment_profile() {
+        let Some(Command::Deployment(DeploymentCommand::Profile {
+            id,
+            seconds,
+            output,
+        })) = Args::try_parse_from(["cargo-shuttle", "deployment", "profile", "latest"])
+            .unwrap()
+            .cmd
+        else {
+            panic!("expected a deployment profile");
+        };
+
+        assert_eq!(id, DeploymentRef::Latest);
+        assert_eq!(seconds, 10);
+        assert_eq!(output, PathBuf::from("profile.svg"));
+
+        let Some(Command::Deployment(DeploymentCommand::Profile {
+            seconds, output, ..
+        })) = Args::try_parse_from([
+            "cargo-shuttle",
+            "deployment",
+            "profile",
+            "latest",
+            "--seconds",
+            "30",
+            "-o",
+            "neo.svg",
+        ])
+        .unwrap()
+        .cmd
+        else {
+            panic!("expected a deployment profile");
+        };
+
+        assert_eq!(seconds, 30);
+        assert_eq!(output, PathBuf::from("neo.svg"));
+    }
+
+    #[test]
+    fn test_logs_export() {
+        let Some(Command::Logs { export, out, .. }) =
+            Args::try_parse_from(["cargo-shuttle", "logs", "--export", "ndjson"])
+                .unwrap()
+                .cmd
+        else {
+            panic!("expected logs");
+        };
+
+        assert_eq!(export, Some(ExportFormat::Ndjson));
+        assert_eq!(out, None);
+
+        let Some(Command::Logs { export, out, .. }) = Args::try_parse_from([
+            "cargo-shuttle",
+            "logs",
+            "latest",
+            "--export",
+            "csv",
+            "--out",
+            "logs.csv",
+        ])
+        .unwrap()
+        .cmd
+        else {
+            panic!("expected logs");
+        };
+
+        assert_eq!(export, Some(ExportFormat::Csv));
+        assert_eq!(out, Some(PathBuf::from("logs.csv")));
+
+        assert!(Args::try_parse_from(["cargo-shuttle", "logs", "--out", "logs.csv"]).is_err());
+        assert!(
+            Args::try_parse_from(["cargo-shuttle", "logs", "--export", "json", "--follow"])
+                .is_err()
+        );
+        assert!(Args::try_parse_from(["cargo-shuttle", "logs", "--export", "xml"]).is_err());
+    }
+
+    #[test]
+    fn test_deployment_list_filters() {
+        let Some(Command::Deployment(DeploymentCommand::List {
+            states,
+            since,
+            until,
+        })) = Args::try_parse_from([
+            "cargo-shuttle",
+            "deployment",
+            "list",
+            "--status",
+            "running",
+            "--status",
+            "crashed",
+            "--since",
+            "2d",
+            "--until",
+            "2023-01-02T03:04:05Z",
+        ])
+        .unwrap()
+        .cmd
+        else {
+            panic!("expected a deployment list");
+        };
+
+        assert_eq!(states, vec![State::Running, State::Crashed]);
+
+        let since = Utc::now() - since.unwrap();
+        assert!(since >= Duration::days(2) && since < Duration::days(2) + Duration::minutes(1));
+        assert_eq!(until.unwrap().to_rfc3339(), "2023-01-02T03:04:05+00:00");
+
+        assert!(parse_time("2 days").is_err());
+        assert!(parse_time("").is_err());
+        assert!(
+            Args::try_parse_from(["cargo-shuttle", "deployment", "list", "--status", "done"])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_deploy_image() {
+        let Some(Command::Deploy(args)) = Args::try_parse_from([
+            "cargo-shuttle",
+            "deploy",
+            "--image",
+            "ghcr.io/neo/matrix:1.0",
+        ])
+        .unwrap()
+        .cmd
+        else {
+            panic!("expected a deploy");
+        };
+        assert_eq!(args.image.as_deref(), Some("ghcr.io/neo/matrix:1.0"));
+
+        assert!(Args::try_parse_from([
+            "cargo-shuttle",
+            "deploy",
+            "--image",
+            "ghcr.io/neo/matrix:1.0",
+            "--no-test",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_project_status_history() {
+        let Some(Command::Project(ProjectCommand::Status { follow, history })) =
+            Args::try_parse_from(["cargo-shuttle", "project", "status", "--history"])
+                .unwrap()
+                .cmd
+        else {
+            panic!("expected a project status");
+        };
+        assert!(!follow);
+        assert!(history);
+
+        assert!(Args::try_parse_from([
+            "cargo-shuttle",
+            "project",
+            "status",
+            "--history",
+            "--follow"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_project_basic_auth() {
+        let Some(Command::Project(ProjectCommand::BasicAuth {
+            username,
+            password,
+            exempt,
+            remove,
+        })) = Args::try_parse_from([
+            "cargo-shuttle",
+            "project",
+            "basic-auth",
+            "--username",
+            "neo",
+            "--exempt",
+            "/health",
+        ])
+        .unwrap()
+        .cmd
+        else {
+            panic!("expected a project basic-auth");
+        };
+        assert_eq!(username.as_deref(), Some("neo"));
+        assert_eq!(password, None);
+        assert_eq!(exempt, ["/health"]);
+        assert!(!remove);
+
+        assert!(Args::try_parse_from([
+            "cargo-shuttle",
+            "project",
+            "basic-auth",
+            "--exempt",
+            "/health"
+        ])
+        .is_err());
+        assert!(Args::try_parse_from([
+            "cargo-shuttle",
+            "project",
+            "basic-auth",
+            "--username",
+            "neo",
+            "--remove"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_deploy_run_budget() {
+        let Some(Command::Deploy(args)) =
+            Args::try_parse_from(["cargo-shuttle", "deploy", "--run-budget", "3600"])
+                .unwrap()
+                .cmd
+        else {
+            panic!("expected a deploy");
+        };
+        assert_eq!(args.run_budget, Some(3600));
+
+        assert!(Args::try_parse_from(["cargo-shuttle", "deploy", "--run-budget", "0"]).is_err());
+    }
+
+    // Reading the variables themselves would race with the other tests parsing the same arguments, so only their names
+    // are checked
+    #[test]
+    fn test_env_vars() {
+        let command = Args::command();
+        let env = |command: &clap::Command, id: &str| {
+            command
+                .get_arguments()
+                .find(|arg| arg.get_id() == id)
+                .and_then(|arg| arg.get_env())
+                .map(|env| env.to_string_lossy().into_owned())
+        };
+
+        assert_eq!(env(&command, "api_url").as_deref(), Some("SHUTTLE_API"));
+        assert_eq!(
+            env(&command, "name").as_deref(),
+            Some("SHUTTLE_PROJECT_NAME")
+        );
+        assert_eq!(
+            env(&command, "environment").as_deref(),
+            Some("SHUTTLE_ENVIRONMENT")
+        );
+
+        let login = command.find_subcommand("login").unwrap();
+        assert_eq!(env(login, "api_key").as_deref(), Some("SHUTTLE_API_KEY"));
+
+        for name in ["deploy", "check"] {
+            let subcommand = command.find_subcommand(name).unwrap();
+            assert_eq!(
+                env(subcommand, "allow_dirty").as_deref(),
+                Some("SHUTTLE_ALLOW_DIRTY")
+            );
+            assert_eq!(
+                env(subcommand, "no_test").as_deref(),
+                Some("SHUTTLE_NO_TEST")
+            );
+        }
+
+        // Every variable shares the prefix, so that they are easy to tell apart in a CI configuration
+        let mut commands = vec![&command];
+        while let Some(command) = commands.pop() {
+            for arg in command.get_arguments() {
+                if let Some(env) = arg.get_env() {
+                    assert!(
+                        env.to_string_lossy().starts_with("SHUTTLE_"),
+                        "{env:?} of `{}` should start with SHUTTLE_",
+                        command.get_name()
+                    );
+                }
+            }
+            commands.extend(command.get_subcommands());
+        }
+    }
+
+    #[test]
+    fn test_project_transfer() {
+        let Some(Command::Project(ProjectCommand::Transfer {
+            to_account,
+            accept,
+            cancel,
+        })) = Args::try_parse_from(["cargo-shuttle", "project", "transfer", "trinity"])
+            .unwrap()
+            .cmd
+        else {
+            panic!("expected a project transfer");
+        };
+        assert_eq!(to_account.as_deref(), Some("trinity"));
+        assert!(!accept && !cancel);
+
+        assert!(Args::try_parse_from(["cargo-shuttle", "project", "transfer", "--accept"]).is_ok());
+        assert!(Args::try_parse_from([
+            "cargo-shuttle",
+            "project",
+            "transfer",
+            "trinity",
+            "--accept"
+        ])
+        .is_err());
+        assert!(Args::try_parse_from([
+            "cargo-shuttle",
+            "project",
+            "transfer",
+            "--accept",
+            "--cancel"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_deploy_ttl() {
+        let Some(Command::Deploy(args)) =
+            Args::try_parse_from(["cargo-shuttle", "deploy", "--ttl", "86400"])
+                .unwrap()
+                .cmd
+        else {
+            panic!("expected a deploy");
+        };
+        assert_eq!(args.ttl, Some(86400));
+
+        assert!(Args::try_parse_from(["cargo-shuttle", "deploy", "--ttl", "0"]).is_err());
+    }
+
+    #[test]
+    fn test_deploy_strict() {
+        let Some(Command::Deploy(args)) =
+            Args::try_parse_from(["cargo-shuttle", "deploy", "--strict"])
+                .unwrap()
+                .cmd
+        else {
+            panic!("expected a deploy");
+        };
+        assert!(args.strict);
+    }
+
+    #[test]
+    fn test_secrets_sync() {
+        let Some(Command::Secrets { cmd: None }) =
+            Args::try_parse_from(["cargo-shuttle", "secrets"])
+                .unwrap()
+                .cmd
+        else {
+            panic!("expected secrets to list them");
+        };
+
+        let Some(Command::Secrets {
+            cmd: Some(SecretsCommand::Sync { file, yes, prune }),
+        }) = Args::try_parse_from([
+            "cargo-shuttle",
+            "secrets",
+            "sync",
+            "Secrets.prod.toml",
+            "--prune",
+        ])
+        .unwrap()
+        .cmd
+        else {
+            panic!("expected a secrets sync");
+        };
+
+        assert_eq!(file, PathBuf::from("Secrets.prod.toml"));
+        assert!(!yes);
+        assert!(prune);
+    }
+
+    #[test]
+    fn test_response_headers() {
+        let Some(Command::Project(ProjectCommand::ResponseHeaders { set, remove, allow })) =
+            Args::try_parse_from([
+                "cargo-shuttle",
+                "project",
+                "response-headers",
+                "--set",
+                "Server=my-app",
+                "--set",
+                "x-frame-options = DENY",
+                "--remove",
+                "x-powered-by",
+            ])
+            .unwrap()
+            .cmd
+        else {
+            panic!("expected a project response-headers");
+        };
+
+        assert_eq!(
+            set,
+            vec![
+                ("server".to_string(), "my-app".to_string()),
+                ("x-frame-options".to_string(), "DENY".to_string())
+            ]
+        );
+        assert_eq!(remove, vec!["x-powered-by".to_string()]);
+        assert!(allow.is_empty());
+
+        assert!(parse_header("server").is_err());
+    }
+
+    #[test]
+    fn test_header_limits() {
+        let Some(Command::Project(ProjectCommand::HeaderLimits {
+            max_total_size,
+            max_count,
+            max_header_size,
+            reset,
+        })) = Args::try_parse_from([
+            "cargo-shuttle",
+            "project",
+            "header-limits",
+            "--max-total-size",
+            "65536",
+            "--max-header-size",
+            "16384",
+        ])
+        .unwrap()
+        .cmd
+        else {
+            panic!("expected a project header-limits");
+        };
+
+        assert_eq!(max_total_size, Some(65536));
+        assert_eq!(max_count, None);
+        assert_eq!(max_header_size, Some(16384));
+        assert!(!reset);
+
+        assert!(Args::try_parse_from([
+            "cargo-shuttle",
+            "project",
+            "header-limits",
+            "--max-count",
+            "0"
+        ])
+        .is_err());
+        assert!(Args::try_parse_from([
+            "cargo-shuttle",
+            "project",
+            "header-limits",
+            "--reset",
+            "--max-count",
+            "10"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_project_timeouts() {
+        let Some(Command::Project(ProjectCommand::Timeouts {
+            response,
+            slow_request,
+            reset,
+            ..
+        })) = Args::try_parse_from([
+            "cargo-shuttle",
+            "project",
+            "timeouts",
+            "--response",
+            "30",
+            "--slow-request",
+            "500",
+        ])
+        .unwrap()
+        .cmd
+        else {
+            panic!("expected a project timeouts");
+        };
+
+        assert_eq!(response, Some(30));
+        assert_eq!(slow_request, Some(500));
+        assert!(!reset);
+
+        let Some(Command::Project(ProjectCommand::Timeouts {
+            slow_request,
+            reset,
+            ..
+        })) = Args::try_parse_from(["cargo-shuttle", "project", "timeouts", "--reset"])
+            .unwrap()
+            .cmd
+        else {
+            panic!("expected a project timeouts");
+        };
+
+        assert_eq!(slow_request, None);
+        assert!(reset);
+
+        // Resetting goes back to the threshold of the platform, so a threshold cannot be set along with it
+        assert!(Args::try_parse_from([
+            "cargo-shuttle",
+            "project",
+            "timeouts",
+            "--reset",
+            "--slow-request",
+            "500"
+        ])
+        .is_err());
+    }
 }
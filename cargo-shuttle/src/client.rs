@@ -1,21 +1,90 @@
-use std::fmt::Write;
+use std::time::Instant;
 
 use anyhow::{Context, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
 use headers::{Authorization, HeaderMapExt};
 use reqwest::Response;
 use reqwest_middleware::{ClientBuilder, ClientWithMiddleware, RequestBuilder};
 use reqwest_retry::policies::ExponentialBackoff;
 use reqwest_retry::RetryTransientMiddleware;
 use serde::{Deserialize, Serialize};
-use shuttle_common::models::{deployment, project, secret, service, ToJson};
+use shuttle_common::deployment::State;
+use shuttle_common::models::{
+    basic_auth, deployment, header_limits, host_header, project, response_header, secret, service,
+    stats, timeouts, usage, version, ToJson,
+};
 use shuttle_common::project::ProjectName;
 use shuttle_common::{ApiKey, ApiUrl, LogItem};
 use tokio::net::TcpStream;
 use tokio_tungstenite::tungstenite::client::IntoClientRequest;
 use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
-use tracing::error;
+use tracing::{debug, error};
 use uuid::Uuid;
 
+/// How a deployment should be made, beyond the archive of the service
+#[derive(Default)]
+pub struct DeployOptions<'a> {
+    pub no_test: bool,
+    pub pre_stop: Option<&'a str>,
+    pub tag_latest: bool,
+    /// Where the crate is in the archive, when it was packaged from a directory above the crate
+    pub crate_path: Option<&'a str>,
+    /// Let the build update `Cargo.lock` instead of failing when it is out of date
+    pub no_locked: bool,
+    /// Container image to run instead of building the uploaded archive
+    pub image: Option<&'a str>,
+    /// Seconds the deployment may run for before it is stopped
+    pub run_budget: Option<u32>,
+    /// Seconds after which the deployment expires
+    pub ttl: Option<u32>,
+}
+
+/// Which deployments of a service to list, all of them by default
+#[derive(Default)]
+pub struct DeploymentFilter {
+    pub states: Vec<State>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+}
+
+impl DeployOptions<'_> {
+    fn apply(&self, mut builder: RequestBuilder) -> RequestBuilder {
+        if self.no_test {
+            builder = builder.query(&[("no-test", "")]);
+        }
+
+        if let Some(pre_stop) = self.pre_stop {
+            builder = builder.query(&[("pre-stop", pre_stop)]);
+        }
+
+        if self.tag_latest {
+            builder = builder.query(&[("tag-latest", "")]);
+        }
+
+        if let Some(crate_path) = self.crate_path {
+            builder = builder.query(&[("crate-path", crate_path)]);
+        }
+
+        if self.no_locked {
+            builder = builder.query(&[("no-locked", "")]);
+        }
+
+        if let Some(image) = self.image {
+            builder = builder.query(&[("image", image)]);
+        }
+
+        if let Some(run_budget) = self.run_budget {
+            builder = builder.query(&[("run-budget", run_budget)]);
+        }
+
+        if let Some(ttl) = self.ttl {
+            builder = builder.query(&[("ttl", ttl)]);
+        }
+
+        builder
+    }
+}
+
 pub struct Client {
     api_url: ApiUrl,
     api_key: Option<ApiKey>,
@@ -37,34 +106,120 @@ impl Client {
         &self,
         data: Vec<u8>,
         project: &ProjectName,
-        no_test: bool,
+        options: &DeployOptions<'_>,
     ) -> Result<deployment::Response> {
-        let mut path = format!(
+        let path = format!(
             "/projects/{}/services/{}",
             project.as_str(),
             project.as_str()
         );
 
-        if no_test {
-            let _ = write!(path, "?no-test");
-        }
-
         let url = format!("{}{}", self.api_url, path);
 
-        let mut builder = Self::get_retry_client().post(url);
+        let mut builder = options.apply(Self::get_retry_client().post(url));
 
         builder = self.set_builder_auth(builder);
 
-        builder
-            .body(data)
-            .header("Transfer-Encoding", "chunked")
-            .send()
+        Self::send(builder.body(data).header("Transfer-Encoding", "chunked"))
             .await
             .context("failed to send deployment to the Shuttle server")?
             .to_json()
             .await
     }
 
+    /// Start a resumable upload of a deployment archive of `length` bytes. Gives `None` when the deployer does not
+    /// support resumable uploads.
+    pub async fn start_upload(
+        &self,
+        project: &ProjectName,
+        length: u64,
+    ) -> Result<Option<deployment::UploadStatus>> {
+        let url = format!(
+            "{}/projects/{}/services/{}/uploads",
+            self.api_url,
+            project.as_str(),
+            project.as_str()
+        );
+
+        let builder = Self::get_retry_client()
+            .post(url)
+            .query(&[("length", length)]);
+        let builder = self.set_builder_auth(builder);
+
+        let response = Self::send(builder)
+            .await
+            .context("failed to start upload")?;
+
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        response.to_json().await.map(Some)
+    }
+
+    pub async fn get_upload(
+        &self,
+        project: &ProjectName,
+        upload_id: &Uuid,
+    ) -> Result<deployment::UploadStatus> {
+        let path = format!(
+            "/projects/{}/services/{}/uploads/{upload_id}",
+            project.as_str(),
+            project.as_str()
+        );
+
+        self.get(path).await
+    }
+
+    /// Append a chunk to an upload. `offset` has to be where the upload is at.
+    pub async fn upload_chunk(
+        &self,
+        project: &ProjectName,
+        upload_id: &Uuid,
+        offset: u64,
+        chunk: Vec<u8>,
+    ) -> Result<deployment::UploadStatus> {
+        let url = format!(
+            "{}/projects/{}/services/{}/uploads/{upload_id}",
+            self.api_url,
+            project.as_str(),
+            project.as_str()
+        );
+
+        let builder = Self::get_retry_client()
+            .put(url)
+            .query(&[("offset", offset)]);
+
+        Self::send(self.set_builder_auth(builder).body(chunk))
+            .await
+            .context("failed to send chunk")?
+            .to_json()
+            .await
+    }
+
+    /// Deploy the archive of a completed upload
+    pub async fn deploy_upload(
+        &self,
+        project: &ProjectName,
+        upload_id: &Uuid,
+        options: &DeployOptions<'_>,
+    ) -> Result<deployment::Response> {
+        let url = format!(
+            "{}/projects/{}/services/{}/uploads/{upload_id}/deploy",
+            self.api_url,
+            project.as_str(),
+            project.as_str()
+        );
+
+        let builder = options.apply(Self::get_retry_client().post(url));
+
+        Self::send(self.set_builder_auth(builder))
+            .await
+            .context("failed to deploy upload")?
+            .to_json()
+            .await
+    }
+
     pub async fn stop_service(&self, project: &ProjectName) -> Result<service::Summary> {
         let path = format!(
             "/projects/{}/services/{}",
@@ -75,6 +230,20 @@ impl Client {
         self.delete(path).await
     }
 
+    pub async fn restart_service(&self, project: &ProjectName) -> Result<service::Summary> {
+        let path = format!(
+            "/projects/{}/services/{}/restart",
+            project.as_str(),
+            project.as_str()
+        );
+
+        self.post(path, Option::<String>::None)
+            .await
+            .context("failed to restart service")?
+            .to_json()
+            .await
+    }
+
     pub async fn get_service_details(&self, project: &ProjectName) -> Result<service::Detailed> {
         let path = format!(
             "/projects/{}/services/{}",
@@ -85,6 +254,43 @@ impl Client {
         self.get(path).await
     }
 
+    /// Get the details of a service, only listing the deployments the filter lets through
+    pub async fn get_filtered_service_details(
+        &self,
+        project: &ProjectName,
+        filter: &DeploymentFilter,
+    ) -> Result<service::Detailed> {
+        let mut path = format!(
+            "/projects/{}/services/{}",
+            project.as_str(),
+            project.as_str()
+        );
+
+        let mut query = Vec::new();
+        if !filter.states.is_empty() {
+            let states: Vec<_> = filter.states.iter().map(ToString::to_string).collect();
+            query.push(format!("status={}", states.join(",")));
+        }
+        // Without a `+` in the offset, which would be read back as a space
+        if let Some(since) = filter.since {
+            query.push(format!(
+                "since={}",
+                since.to_rfc3339_opts(SecondsFormat::Secs, true)
+            ));
+        }
+        if let Some(until) = filter.until {
+            query.push(format!(
+                "until={}",
+                until.to_rfc3339_opts(SecondsFormat::Secs, true)
+            ));
+        }
+        if !query.is_empty() {
+            path.push_str(&format!("?{}", query.join("&")));
+        }
+
+        self.get(path).await
+    }
+
     pub async fn get_service_summary(&self, project: &ProjectName) -> Result<service::Summary> {
         let path = format!(
             "/projects/{}/services/{}/summary",
@@ -125,6 +331,190 @@ impl Client {
         self.get(path).await
     }
 
+    pub async fn get_project_events(&self, project: &ProjectName) -> Result<Vec<project::Event>> {
+        let path = format!("/projects/{}/events", project.as_str());
+
+        self.get(path).await
+    }
+
+    pub async fn get_project_traffic(
+        &self,
+        project: &ProjectName,
+    ) -> Result<stats::TrafficResponse> {
+        let path = format!("/projects/{}/traffic", project.as_str());
+
+        self.get(path).await
+    }
+
+    pub async fn get_host_header(&self, project: &ProjectName) -> Result<host_header::Policy> {
+        let path = format!("/projects/{}/host-header", project.as_str());
+
+        self.get(path).await
+    }
+
+    pub async fn set_host_header(
+        &self,
+        project: &ProjectName,
+        policy: host_header::Policy,
+    ) -> Result<host_header::Policy> {
+        let path = format!("/projects/{}/host-header", project.as_str());
+
+        self.post(path, Some(policy))
+            .await
+            .context("failed to make host header request")?
+            .to_json()
+            .await
+    }
+
+    pub async fn get_proxy_timeouts(&self, project: &ProjectName) -> Result<timeouts::Config> {
+        let path = format!("/projects/{}/timeouts", project.as_str());
+
+        self.get(path).await
+    }
+
+    pub async fn get_basic_auth(&self, project: &ProjectName) -> Result<basic_auth::Response> {
+        let path = format!("/projects/{}/basic-auth", project.as_str());
+
+        self.get(path).await
+    }
+
+    pub async fn set_basic_auth(
+        &self,
+        project: &ProjectName,
+        config: basic_auth::Config,
+    ) -> Result<basic_auth::Response> {
+        let path = format!("/projects/{}/basic-auth", project.as_str());
+
+        self.post(path, Some(config))
+            .await
+            .context("failed to make basic auth request")?
+            .to_json()
+            .await
+    }
+
+    pub async fn remove_basic_auth(&self, project: &ProjectName) -> Result<basic_auth::Response> {
+        let path = format!("/projects/{}/basic-auth", project.as_str());
+
+        self.delete(path).await
+    }
+
+    pub async fn set_proxy_timeouts(
+        &self,
+        project: &ProjectName,
+        config: timeouts::Config,
+    ) -> Result<timeouts::Config> {
+        let path = format!("/projects/{}/timeouts", project.as_str());
+
+        self.post(path, Some(config))
+            .await
+            .context("failed to make timeouts request")?
+            .to_json()
+            .await
+    }
+
+    pub async fn pin_project(&self, project: &ProjectName) -> Result<project::Pin> {
+        let path = format!("/projects/{}/pin", project.as_str());
+
+        self.post(path, Option::<String>::None)
+            .await
+            .context("failed to make pin request")?
+            .to_json()
+            .await
+    }
+
+    pub async fn unpin_project(&self, project: &ProjectName) -> Result<project::Pin> {
+        let path = format!("/projects/{}/pin", project.as_str());
+
+        self.delete(path).await
+    }
+
+    pub async fn get_transfers(&self) -> Result<Vec<project::Transfer>> {
+        self.get("/transfers".to_string()).await
+    }
+
+    pub async fn transfer_project(
+        &self,
+        project: &ProjectName,
+        to_account: String,
+    ) -> Result<project::Transfer> {
+        let path = format!("/projects/{}/transfer", project.as_str());
+
+        self.post(path, Some(project::TransferRequest { to_account }))
+            .await
+            .context("failed to make transfer request")?
+            .to_json()
+            .await
+    }
+
+    pub async fn accept_transfer(&self, project: &ProjectName) -> Result<project::Response> {
+        let path = format!("/projects/{}/transfer/accept", project.as_str());
+
+        self.post(path, Option::<String>::None)
+            .await
+            .context("failed to make transfer accept request")?
+            .to_json()
+            .await
+    }
+
+    pub async fn cancel_transfer(&self, project: &ProjectName) -> Result<Vec<project::Transfer>> {
+        let path = format!("/projects/{}/transfer", project.as_str());
+
+        self.delete(path).await
+    }
+
+    pub async fn get_header_limits(&self, project: &ProjectName) -> Result<header_limits::Config> {
+        let path = format!("/projects/{}/header-limits", project.as_str());
+
+        self.get(path).await
+    }
+
+    pub async fn set_header_limits(
+        &self,
+        project: &ProjectName,
+        config: header_limits::Config,
+    ) -> Result<header_limits::Config> {
+        let path = format!("/projects/{}/header-limits", project.as_str());
+
+        self.post(path, Some(config))
+            .await
+            .context("failed to make header limits request")?
+            .to_json()
+            .await
+    }
+
+    pub async fn get_response_headers(
+        &self,
+        project: &ProjectName,
+    ) -> Result<response_header::Rules> {
+        let path = format!("/projects/{}/response-headers", project.as_str());
+
+        self.get(path).await
+    }
+
+    pub async fn set_response_headers(
+        &self,
+        project: &ProjectName,
+        rules: response_header::Rules,
+    ) -> Result<response_header::Rules> {
+        let path = format!("/projects/{}/response-headers", project.as_str());
+
+        self.post(path, Some(rules))
+            .await
+            .context("failed to make response headers request")?
+            .to_json()
+            .await
+    }
+
+    pub async fn get_version(&self) -> Result<version::Response> {
+        self.get("/version".to_string()).await
+    }
+
+    pub async fn get_account_usage(&self) -> Result<usage::Response> {
+        let path = "/account/usage".to_string();
+
+        self.get(path).await
+    }
+
     pub async fn get_projects_list(&self) -> Result<Vec<project::Response>> {
         let path = "/projects".to_string();
 
@@ -156,31 +546,105 @@ impl Client {
         self.get(path).await
     }
 
+    /// Make the secrets of the service those of `request`, or only find out what that changes on a `dry_run`
+    pub async fn sync_secrets(
+        &self,
+        project: &ProjectName,
+        request: secret::SyncRequest,
+        dry_run: bool,
+    ) -> Result<secret::SyncDiff> {
+        let mut path = format!(
+            "/projects/{}/secrets/{}",
+            project.as_str(),
+            project.as_str()
+        );
+
+        if dry_run {
+            path.push_str("?dry-run");
+        }
+
+        self.post(path, Some(request))
+            .await
+            .context("failed to make secrets sync request")?
+            .to_json()
+            .await
+    }
+
+    /// Get the logs of a deployment, or only the last `tail` lines of them
     pub async fn get_logs(
         &self,
         project: &ProjectName,
         deployment_id: &Uuid,
+        tail: Option<u32>,
     ) -> Result<Vec<LogItem>> {
-        let path = format!(
+        let mut path = format!(
             "/projects/{}/deployments/{}/logs",
             project.as_str(),
             deployment_id
         );
 
+        if let Some(tail) = tail {
+            path.push_str(&format!("?tail={tail}"));
+        }
+
         self.get(path).await
     }
 
+    /// Get up to `limit` lines of the logs of a deployment, oldest first, only giving those logged after `after` when
+    /// it is set
+    pub async fn get_logs_page(
+        &self,
+        project: &ProjectName,
+        deployment_id: &Uuid,
+        after: Option<DateTime<Utc>>,
+        limit: u32,
+    ) -> Result<Vec<LogItem>> {
+        let mut path = format!(
+            "/projects/{}/deployments/{}/logs?limit={limit}",
+            project.as_str(),
+            deployment_id
+        );
+
+        if let Some(after) = after {
+            path.push_str(&format!(
+                "&after={}",
+                after.to_rfc3339_opts(SecondsFormat::Nanos, true)
+            ));
+        }
+
+        self.get(path).await
+    }
+
+    /// Stream the logs of a deployment, starting with all of its logs so far or only the last `tail` lines of them, and
+    /// only giving those logged after `after` when it is set. The time is sent with nanoseconds, since that is how
+    /// precisely logs are told apart.
     pub async fn get_logs_ws(
         &self,
         project: &ProjectName,
         deployment_id: &Uuid,
+        tail: Option<u32>,
+        after: Option<DateTime<Utc>>,
     ) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>> {
-        let path = format!(
+        let mut path = format!(
             "/projects/{}/ws/deployments/{}/logs",
             project.as_str(),
             deployment_id
         );
 
+        let mut query = Vec::new();
+        if let Some(tail) = tail {
+            query.push(format!("tail={tail}"));
+        }
+        if let Some(after) = after {
+            query.push(format!(
+                "after={}",
+                after.to_rfc3339_opts(SecondsFormat::Nanos, true)
+            ));
+        }
+        if !query.is_empty() {
+            path.push_str(&format!("?{}", query.join("&")));
+        }
+
         self.ws_get(path).await
     }
 
@@ -198,6 +662,112 @@ impl Client {
         self.get(path).await
     }
 
+    pub async fn cancel_deployment(
+        &self,
+        project: &ProjectName,
+        deployment_id: &Uuid,
+    ) -> Result<deployment::Response> {
+        let path = format!(
+            "/projects/{}/deployments/{}/cancel",
+            project.as_str(),
+            deployment_id
+        );
+
+        self.post(path, Option::<String>::None)
+            .await
+            .context("failed to cancel deployment")?
+            .to_json()
+            .await
+    }
+
+    pub async fn delete_deployment_artifact(
+        &self,
+        project: &ProjectName,
+        deployment_id: &Uuid,
+        force: bool,
+    ) -> Result<deployment::ArtifactRemoval> {
+        let mut path = format!(
+            "/projects/{}/deployments/{}/artifact",
+            project.as_str(),
+            deployment_id
+        );
+
+        if force {
+            path.push_str("?force");
+        }
+
+        self.delete(path).await
+    }
+
+    /// The flamegraph SVG of a CPU profile of a running deployment, sampled for `seconds`
+    pub async fn profile_deployment(
+        &self,
+        project: &ProjectName,
+        deployment_id: &Uuid,
+        seconds: u64,
+    ) -> Result<Vec<u8>> {
+        let path = format!(
+            "/projects/{}/deployments/{}/profile?seconds={seconds}",
+            project.as_str(),
+            deployment_id
+        );
+
+        let response = self
+            .post(path, Option::<String>::None)
+            .await
+            .context("failed to profile deployment")?;
+
+        if response.status().is_success() {
+            Ok(response.bytes().await?.to_vec())
+        } else {
+            // Errors are JSON like those of every other call, and never parse as a success
+            response.to_json().await
+        }
+    }
+
+    pub async fn get_deployment_summary(
+        &self,
+        project: &ProjectName,
+        deployment_id: &Uuid,
+    ) -> Result<deployment::Summary> {
+        let path = format!(
+            "/projects/{}/deployments/{}/summary",
+            project.as_str(),
+            deployment_id
+        );
+
+        self.get(path).await
+    }
+
+    pub async fn get_deployment_status(
+        &self,
+        project: &ProjectName,
+        deployment_id: &Uuid,
+    ) -> Result<deployment::Status> {
+        let path = format!(
+            "/projects/{}/deployments/{}/status",
+            project.as_str(),
+            deployment_id
+        );
+
+        self.get(path).await
+    }
+
+    pub async fn get_tagged_deployment(
+        &self,
+        project: &ProjectName,
+        tag: &str,
+    ) -> Result<deployment::Response> {
+        let path = format!(
+            "/projects/{}/services/{}/tags/{}",
+            project.as_str(),
+            project.as_str(),
+            tag
+        );
+
+        self.get(path).await
+    }
+
     async fn ws_get(&self, path: String) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>> {
         let ws_scheme = self.api_url.clone().replace("http", "ws");
         let url = format!("{}{}", ws_scheme, path);
@@ -226,8 +796,7 @@ impl Client {
 
         builder = self.set_builder_auth(builder);
 
-        builder
-            .send()
+        Self::send(builder)
             .await
             .context("failed to make get request")?
             .to_json()
@@ -247,7 +816,7 @@ impl Client {
             builder = builder.header("Content-Type", "application/json");
         }
 
-        Ok(builder.send().await?)
+        Ok(Self::send(builder).await?)
     }
 
     async fn delete<M>(&self, path: String) -> Result<M>
@@ -260,14 +829,33 @@ impl Client {
 
         builder = self.set_builder_auth(builder);
 
-        builder
-            .send()
+        Self::send(builder)
             .await
             .context("failed to make delete request")?
             .to_json()
             .await
     }
 
+    /// Send a request, logging where it went and how it went for `--verbose`
+    async fn send(builder: RequestBuilder) -> reqwest_middleware::Result<Response> {
+        let request = builder.build()?;
+        let method = request.method().clone();
+        let url = request.url().clone();
+
+        let started = Instant::now();
+        let result = Self::get_retry_client().execute(request).await;
+        let elapsed_ms = started.elapsed().as_millis() as u64;
+
+        match &result {
+            Ok(response) => {
+                debug!(%method, %url, status = %response.status(), elapsed_ms, "API call")
+            }
+            Err(error) => debug!(%method, %url, %error, elapsed_ms, "API call failed"),
+        }
+
+        result
+    }
+
     fn set_builder_auth(&self, builder: RequestBuilder) -> RequestBuilder {
         if let Some(ref api_key) = self.api_key {
             builder.bearer_auth(api_key)
@@ -1,3 +1,4 @@
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
@@ -10,6 +11,7 @@ use shuttle_common::{ApiKey, ApiUrl, API_URL_DEFAULT};
 use tracing::trace;
 
 use crate::args::ProjectArgs;
+use crate::init::Framework;
 
 /// Helper trait for dispatching fs ops for different config files
 pub trait ConfigManager: Sized {
@@ -125,6 +127,17 @@ impl ConfigManager for LocalConfigManager {
 pub struct GlobalConfig {
     pub api_key: Option<ApiKey>,
     pub api_url: Option<ApiUrl>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub init: Option<InitDefaults>,
+}
+
+/// Defaults for `cargo shuttle init`, used for anything not given as a flag
+#[derive(Deserialize, Serialize, Default, Clone, Debug, PartialEq, Eq)]
+pub struct InitDefaults {
+    pub framework: Option<Framework>,
+    /// Create the project environment on Shuttle right away
+    #[serde(default)]
+    pub new: bool,
 }
 
 impl GlobalConfig {
@@ -143,12 +156,80 @@ impl GlobalConfig {
     pub fn api_url(&self) -> Option<ApiKey> {
         self.api_url.clone()
     }
+
+    pub fn init_defaults(&self) -> InitDefaults {
+        self.init.clone().unwrap_or_default()
+    }
 }
 
 /// Project-local config for things like customizing project name
 #[derive(Deserialize, Serialize, Default)]
 pub struct ProjectConfig {
     pub name: Option<ProjectName>,
+    /// Path on the service to call before a deployment is stopped
+    #[serde(rename = "pre-stop")]
+    pub pre_stop: Option<String>,
+    /// Minutes of inactivity after which the project idles, used when the project is created
+    #[serde(rename = "idle-minutes")]
+    pub idle_minutes: Option<u64>,
+    /// Seconds a deployment may run for before it is stopped
+    #[serde(rename = "run-budget")]
+    pub run_budget: Option<u32>,
+    /// File with the secrets to send with deployments, relative to the working directory
+    pub secrets: Option<PathBuf>,
+    /// The `[env.<name>]` sections, keyed by environment name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, EnvironmentConfig>,
+    /// The environment whose section has been layered over the base settings, if any
+    #[serde(skip)]
+    pub environment: Option<String>,
+}
+
+/// Settings of an environment which take precedence over the base ones of [`ProjectConfig`]
+#[derive(Deserialize, Serialize, Default, Clone)]
+pub struct EnvironmentConfig {
+    pub name: Option<ProjectName>,
+    #[serde(rename = "pre-stop")]
+    pub pre_stop: Option<String>,
+    #[serde(rename = "idle-minutes")]
+    pub idle_minutes: Option<u64>,
+    #[serde(rename = "run-budget")]
+    pub run_budget: Option<u32>,
+    pub secrets: Option<PathBuf>,
+}
+
+impl ProjectConfig {
+    /// Layer the section of `environment` over the base settings. Only the keys set in that section are replaced.
+    pub fn apply_environment(&mut self, environment: &str) -> Result<()> {
+        let section =
+            self.env.get(environment).cloned().ok_or_else(|| {
+                anyhow!("no `[env.{environment}]` section was found in Shuttle.toml")
+            })?;
+
+        if let Some(name) = section.name {
+            self.name = Some(name);
+        }
+
+        if let Some(pre_stop) = section.pre_stop {
+            self.pre_stop = Some(pre_stop);
+        }
+
+        if let Some(idle_minutes) = section.idle_minutes {
+            self.idle_minutes = Some(idle_minutes);
+        }
+
+        if let Some(run_budget) = section.run_budget {
+            self.run_budget = Some(run_budget);
+        }
+
+        if let Some(secrets) = section.secrets {
+            self.secrets = Some(secrets);
+        }
+
+        self.environment = Some(environment.to_string());
+
+        Ok(())
+    }
 }
 
 /// A handler for configuration files. The type parameter `M` is the [`ConfigManager`] which handles
@@ -302,13 +383,18 @@ impl RequestContext {
 
         let config = project.as_mut().unwrap();
 
+        if let Some(environment) = &project_args.environment {
+            trace!(environment, "applying environment section of Shuttle.toml");
+            config.apply_environment(environment)?;
+        }
+
         match (&project_args.name, &config.name) {
             // Command-line name parameter trumps everything
             (Some(name_from_args), _) => {
                 trace!("using command-line project name");
                 config.name = Some(name_from_args.clone());
             }
-            // If key exists in config then keep it as it is
+            // If key exists in config, either in the environment section or at the top, then keep it as it is
             (None, Some(_)) => {
                 trace!("using Shuttle.toml project name");
             }
@@ -372,6 +458,10 @@ impl RequestContext {
             .as_path()
     }
 
+    pub fn init_defaults(&self) -> InitDefaults {
+        self.global.as_ref().unwrap().init_defaults()
+    }
+
     /// Set the API key to the global configuration. Will persist the file.
     pub fn set_api_key(&mut self, api_key: ApiKey) -> Result<Option<ApiKey>> {
         let res = self.global.as_mut().unwrap().set_api_key(api_key);
@@ -398,17 +488,79 @@ impl RequestContext {
             .as_ref()
             .unwrap()
     }
+
+    /// # Panics
+    /// Panics if the project configuration has not been loaded.
+    pub fn pre_stop_hook(&self) -> Option<&str> {
+        self.project
+            .as_ref()
+            .unwrap()
+            .as_ref()
+            .unwrap()
+            .pre_stop
+            .as_deref()
+    }
+
+    /// # Panics
+    /// Panics if the project configuration has not been loaded.
+    pub fn idle_minutes(&self) -> Option<u64> {
+        self.project
+            .as_ref()
+            .unwrap()
+            .as_ref()
+            .unwrap()
+            .idle_minutes
+    }
+
+    /// # Panics
+    /// Panics if the project configuration has not been loaded.
+    pub fn run_budget(&self) -> Option<u32> {
+        self.project.as_ref().unwrap().as_ref().unwrap().run_budget
+    }
+
+    /// Get the secrets file set in `Shuttle.toml`, resolved against the working directory.
+    ///
+    /// # Panics
+    /// Panics if the project configuration has not been loaded.
+    pub fn secrets_file(&self) -> Option<PathBuf> {
+        self.project
+            .as_ref()
+            .unwrap()
+            .as_ref()
+            .unwrap()
+            .secrets
+            .as_ref()
+            .map(|secrets| self.working_directory().join(secrets))
+    }
+
+    /// Get the environment whose `Shuttle.toml` section applies, if one was selected.
+    ///
+    /// # Panics
+    /// Panics if the project configuration has not been loaded.
+    pub fn environment(&self) -> Option<&str> {
+        self.project
+            .as_ref()
+            .unwrap()
+            .as_ref()
+            .unwrap()
+            .environment
+            .as_deref()
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{path::PathBuf, str::FromStr};
+    use std::{
+        path::{Path, PathBuf},
+        str::FromStr,
+    };
 
     use shuttle_common::project::ProjectName;
 
     use crate::{args::ProjectArgs, config::RequestContext};
 
-    use super::{Config, LocalConfigManager, ProjectConfig};
+    use super::{Config, GlobalConfig, InitDefaults, LocalConfigManager, ProjectConfig};
+    use crate::init::Framework;
 
     fn path_from_workspace_root(path: &str) -> PathBuf {
         PathBuf::from(std::env::var("CARGO_MANIFEST_DIR").unwrap())
@@ -425,6 +577,7 @@ mod tests {
         let project_args = ProjectArgs {
             working_directory: path_from_workspace_root("examples/axum/hello-world/"),
             name: None,
+            environment: None,
         };
 
         let local_config = RequestContext::get_local_config(&project_args).unwrap();
@@ -437,10 +590,86 @@ mod tests {
         let project_args = ProjectArgs {
             working_directory: path_from_workspace_root("examples/axum/hello-world/"),
             name: Some(ProjectName::from_str("my-fancy-project-name").unwrap()),
+            environment: None,
         };
 
         let local_config = RequestContext::get_local_config(&project_args).unwrap();
 
         assert_eq!(unwrap_project_name(&local_config), "my-fancy-project-name");
     }
+
+    #[test]
+    fn project_config_environment_overrides() {
+        let mut config: ProjectConfig = toml::from_str(
+            r#"
+            name = "my-project"
+            pre-stop = "/shutdown"
+            idle-minutes = 30
+            secrets = "Secrets.toml"
+
+            [env.staging]
+            name = "my-project-staging"
+            idle-minutes = 5
+            run-budget = 3600
+            secrets = "Secrets.staging.toml"
+
+            [env.production]
+            pre-stop = "/drain"
+            "#,
+        )
+        .unwrap();
+
+        config.apply_environment("staging").unwrap();
+        assert_eq!(
+            config.name.as_ref().unwrap().to_string(),
+            "my-project-staging"
+        );
+        assert_eq!(config.pre_stop.as_deref(), Some("/shutdown"));
+        assert_eq!(config.idle_minutes, Some(5));
+        assert_eq!(config.run_budget, Some(3600));
+        assert_eq!(
+            config.secrets.as_deref(),
+            Some(Path::new("Secrets.staging.toml"))
+        );
+        assert_eq!(config.environment.as_deref(), Some("staging"));
+
+        let mut config: ProjectConfig = toml::from_str(
+            r#"
+            name = "my-project"
+
+            [env.production]
+            pre-stop = "/drain"
+            "#,
+        )
+        .unwrap();
+
+        config.apply_environment("production").unwrap();
+        assert_eq!(config.name.as_ref().unwrap().to_string(), "my-project");
+        assert_eq!(config.pre_stop.as_deref(), Some("/drain"));
+
+        assert!(config.apply_environment("dev").is_err());
+    }
+
+    #[test]
+    fn global_config_init_defaults() {
+        let config: GlobalConfig = toml::from_str(
+            r#"
+            [init]
+            framework = "actix-web"
+            new = true
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.init_defaults(),
+            InitDefaults {
+                framework: Some(Framework::ActixWeb),
+                new: true,
+            }
+        );
+
+        let config: GlobalConfig = toml::from_str("").unwrap();
+        assert_eq!(config.init_defaults(), InitDefaults::default());
+    }
 }
@@ -0,0 +1,111 @@
+use anyhow::{Context, Result};
+use cargo_metadata::semver::{Version, VersionReq};
+use toml_edit::{Document, Item};
+
+/// A `shuttle-*` dependency of a crate whose version requirement does not allow the version the platform runs
+#[derive(Debug, PartialEq, Eq)]
+pub struct OutdatedDependency {
+    pub name: String,
+    pub requirement: String,
+}
+
+/// Find the shuttle dependencies in the `Cargo.toml` of a crate which will not resolve to `supported`. The shuttle crates
+/// are released together, so they all have to match it.
+///
+/// Dependencies without a version of their own, like those taken from the workspace or given by path or git, are not
+/// checked.
+pub fn outdated_shuttle_dependencies(
+    cargo_toml: &str,
+    supported: &Version,
+) -> Result<Vec<OutdatedDependency>> {
+    let document: Document = cargo_toml.parse().context("failed to parse Cargo.toml")?;
+
+    let Some(dependencies) = document.get("dependencies").and_then(Item::as_table_like) else {
+        return Ok(Vec::new());
+    };
+
+    let outdated = dependencies
+        .iter()
+        .filter(|(name, _)| name.starts_with("shuttle-"))
+        .filter_map(|(name, item)| {
+            let requirement = match item.as_str() {
+                Some(requirement) => requirement,
+                None => item.as_table_like()?.get("version")?.as_str()?,
+            };
+
+            // Requirements which cannot be parsed are left for cargo to report
+            let matches = VersionReq::parse(requirement)
+                .map(|req| req.matches(supported))
+                .unwrap_or(true);
+
+            (!matches).then(|| OutdatedDependency {
+                name: name.to_string(),
+                requirement: requirement.to_string(),
+            })
+        })
+        .collect();
+
+    Ok(outdated)
+}
+
+#[cfg(test)]
+mod tests {
+    use cargo_metadata::semver::Version;
+    use indoc::indoc;
+
+    use super::{outdated_shuttle_dependencies, OutdatedDependency};
+
+    #[test]
+    fn finds_outdated() {
+        let cargo_toml = indoc! {r#"
+            [package]
+            name = "hello-world"
+
+            [dependencies]
+            axum = "0.5"
+            shuttle-service = { version = "0.10.0", features = ["web-axum"] }
+            shuttle-secrets = "0.11.0"
+            shuttle-shared-db = "0.9"
+            shuttle-persist = { workspace = true }
+            shuttle-static-folder = { path = "../static-folder" }
+        "#};
+
+        let outdated = outdated_shuttle_dependencies(cargo_toml, &Version::new(0, 11, 0)).unwrap();
+
+        assert_eq!(
+            outdated,
+            vec![
+                OutdatedDependency {
+                    name: "shuttle-service".to_string(),
+                    requirement: "0.10.0".to_string(),
+                },
+                OutdatedDependency {
+                    name: "shuttle-shared-db".to_string(),
+                    requirement: "0.9".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn nothing_outdated() {
+        let cargo_toml = indoc! {r#"
+            [package]
+            name = "hello-world"
+
+            [dependencies]
+            shuttle-service = { version = "0.11", features = ["web-axum"] }
+        "#};
+
+        assert!(
+            outdated_shuttle_dependencies(cargo_toml, &Version::new(0, 11, 2))
+                .unwrap()
+                .is_empty()
+        );
+        assert!(
+            outdated_shuttle_dependencies("[package]", &Version::new(0, 11, 2))
+                .unwrap()
+                .is_empty()
+        );
+    }
+}
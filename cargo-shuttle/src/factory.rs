@@ -9,7 +9,6 @@ use bollard::{
 };
 use crossterm::{
     cursor::{MoveDown, MoveUp},
-    style::Stylize,
     terminal::{Clear, ClearType},
     QueueableCommand,
 };
@@ -17,6 +16,7 @@ use futures::StreamExt;
 use portpicker::pick_unused_port;
 use shuttle_common::{
     database::{AwsRdsEngine, SharedEngine},
+    style::Stylize,
     DatabaseReadyInfo,
 };
 use shuttle_service::{database::Type, error::CustomError, Environment, Factory, ServiceName};
@@ -34,6 +34,7 @@ pub struct LocalFactory {
     service_name: ServiceName,
     secrets: BTreeMap<String, String>,
     working_directory: PathBuf,
+    environment: Environment,
 }
 
 impl LocalFactory {
@@ -41,12 +42,14 @@ impl LocalFactory {
         service_name: ServiceName,
         secrets: BTreeMap<String, String>,
         working_directory: PathBuf,
+        environment: Environment,
     ) -> Result<Self> {
         Ok(Self {
             docker: Docker::connect_with_local_defaults()?,
             service_name,
             secrets,
             working_directory,
+            environment,
         })
     }
 }
@@ -185,7 +188,7 @@ impl Factory for LocalFactory {
     }
 
     fn get_environment(&self) -> Environment {
-        Environment::Local
+        self.environment
     }
 
     fn get_build_path(&self) -> Result<PathBuf, shuttle_service::Error> {
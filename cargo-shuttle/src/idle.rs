@@ -0,0 +1,263 @@
+//! Emulation of the platform putting an idle project to sleep and waking it on its next request, for
+//! `cargo shuttle run --idle`.
+//!
+//! The service is loaded on a port of its own, behind a small proxy listening on the address the user asked for. Once
+//! no request has come in for the idle period, the service is stopped. The next request loads it again before being
+//! passed on, so the cold start can be seen locally.
+
+use std::{
+    convert::Infallible,
+    net::{Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use hyper::{
+    client::HttpConnector,
+    service::{make_service_fn, service_fn},
+    Body, Client, Request, Response, Server, StatusCode, Uri,
+};
+use portpicker::pick_unused_port;
+use shuttle_common::style::Stylize;
+use shuttle_common::LogItem;
+use shuttle_service::{
+    loader::{LoadedService, Loader},
+    Logger,
+};
+use tokio::{
+    net::TcpStream,
+    sync::{mpsc::UnboundedSender, Mutex},
+    task::JoinError,
+    time::{interval, sleep},
+};
+use tracing::{error, trace};
+use uuid::Uuid;
+
+use crate::factory::LocalFactory;
+
+/// How long a waking service gets to start listening
+const WAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct IdleRunner {
+    so_path: PathBuf,
+    factory: LocalFactory,
+    log_send: UnboundedSender<LogItem>,
+    id: Uuid,
+    service_addr: SocketAddr,
+    idle: Duration,
+    service: Option<LoadedService>,
+    last_request: Instant,
+    in_flight: usize,
+}
+
+impl IdleRunner {
+    pub fn new(
+        so_path: PathBuf,
+        factory: LocalFactory,
+        log_send: UnboundedSender<LogItem>,
+        id: Uuid,
+        idle: Duration,
+    ) -> Result<Self> {
+        let port = pick_unused_port().context("could not find a free port for the service")?;
+
+        Ok(Self {
+            so_path,
+            factory,
+            log_send,
+            id,
+            service_addr: SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port),
+            idle,
+            service: None,
+            last_request: Instant::now(),
+            in_flight: 0,
+        })
+    }
+
+    /// Start the service and put it to sleep and wake it up as requests come and go on `addr`. Only returns once the
+    /// service stops on its own.
+    pub async fn serve(mut self, addr: SocketAddr) -> Result<()> {
+        self.wake().await?;
+
+        let idle = self.idle;
+        let runner = Arc::new(Mutex::new(self));
+        let client = Client::new();
+
+        let make_service = make_service_fn({
+            let runner = runner.clone();
+
+            move |_| {
+                let runner = runner.clone();
+                let client = client.clone();
+
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req| {
+                        forward(runner.clone(), client.clone(), req)
+                    }))
+                }
+            }
+        });
+
+        let server = Server::try_bind(&addr)?.serve(make_service);
+
+        tokio::select! {
+            result = server => result.map_err(Into::into),
+            result = watch(runner, idle) => result,
+        }
+    }
+
+    async fn wake(&mut self) -> Result<()> {
+        let started = Instant::now();
+
+        let loader = Loader::from_so_file(&self.so_path)?;
+        let logger = Logger::new(self.log_send.clone(), self.id);
+        let (handle, so) = loader
+            .load(&mut self.factory, self.service_addr, logger)
+            .await?;
+
+        // The service binds its address in the background, so wait until it can be reached
+        let deadline = started + WAKE_TIMEOUT;
+        while TcpStream::connect(self.service_addr).await.is_err() {
+            if handle.is_finished() {
+                handle.await??;
+                bail!("service stopped before it started listening");
+            }
+
+            if Instant::now() > deadline {
+                bail!(
+                    "service did not start listening within {}s",
+                    WAKE_TIMEOUT.as_secs()
+                );
+            }
+
+            sleep(Duration::from_millis(10)).await;
+        }
+
+        println!(
+            "{:>12} in {:.2}s",
+            "Awake".bold().green(),
+            started.elapsed().as_secs_f64()
+        );
+
+        self.service = Some((handle, so));
+        self.last_request = Instant::now();
+
+        Ok(())
+    }
+
+    async fn put_to_sleep(&mut self) {
+        if let Some(service) = self.service.take() {
+            service.0.abort();
+
+            match unload(service).await {
+                Err(error) if !error.is_cancelled() => {
+                    println!("{:>12} {error}", "Sleep failed".bold().red())
+                }
+                _ => {}
+            }
+
+            println!(
+                "{:>12} after {}s without requests",
+                "Idling".bold().yellow(),
+                self.idle.as_secs()
+            );
+        }
+    }
+}
+
+/// Pass a request on to the service, waking it up first if it is asleep
+async fn forward(
+    runner: Arc<Mutex<IdleRunner>>,
+    client: Client<HttpConnector>,
+    mut req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let service_addr = {
+        let mut runner = runner.lock().await;
+
+        if runner.service.is_none() {
+            println!("{:>12} on incoming request", "Waking".bold().green());
+
+            if let Err(error) = runner.wake().await {
+                println!("{:>12} {error}", "Wake failed".bold().red());
+
+                return Ok(Response::builder()
+                    .status(StatusCode::BAD_GATEWAY)
+                    .body(Body::from(format!("service failed to wake up: {error}")))
+                    .unwrap());
+            }
+        }
+
+        runner.in_flight += 1;
+        runner.service_addr
+    };
+
+    let path = req
+        .uri()
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/");
+    let uri: Uri = format!("http://{service_addr}{path}")
+        .parse()
+        .expect("a valid uri");
+    *req.uri_mut() = uri;
+
+    let response = client.request(req).await.unwrap_or_else(|error| {
+        Response::builder()
+            .status(StatusCode::BAD_GATEWAY)
+            .body(Body::from(format!("service did not respond: {error}")))
+            .unwrap()
+    });
+
+    let mut runner = runner.lock().await;
+    runner.in_flight -= 1;
+    runner.last_request = Instant::now();
+
+    Ok(response)
+}
+
+/// Put the service to sleep once it has been idle for long enough. Returns when the service stops on its own.
+async fn watch(runner: Arc<Mutex<IdleRunner>>, idle: Duration) -> Result<()> {
+    let mut interval = interval(Duration::from_millis(250));
+
+    loop {
+        interval.tick().await;
+
+        let mut runner = runner.lock().await;
+
+        let finished = runner
+            .service
+            .as_ref()
+            .map_or(false, |(handle, _)| handle.is_finished());
+        if finished {
+            let service = runner.service.take().unwrap();
+
+            return unload(service).await?.map_err(Into::into);
+        }
+
+        if runner.in_flight == 0 && runner.last_request.elapsed() >= idle {
+            runner.put_to_sleep().await;
+        }
+    }
+}
+
+/// Wait for the task of a service to end before closing its so file, since the task runs code from that file
+async fn unload(
+    (handle, so): LoadedService,
+) -> std::result::Result<Result<(), shuttle_service::Error>, JoinError> {
+    let result = handle.await;
+
+    let closed = tokio::task::spawn_blocking(move || {
+        trace!("closing so file");
+        so.close()
+    })
+    .await;
+
+    match closed {
+        Ok(Ok(())) => {}
+        Ok(Err(error)) => error!(%error, "failed to close so file"),
+        Err(error) => error!(%error, "failed to close so file"),
+    }
+
+    result
+}
@@ -1,16 +1,27 @@
-use std::fs::{read_to_string, File};
+use std::fs::{read_dir, read_to_string, remove_file, File};
 use std::io::Write;
 use std::path::{Path, PathBuf};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use cargo::ops::NewOptions;
 use cargo_edit::{find, get_latest_dependency, registry_url};
 use indoc::indoc;
 use toml_edit::{value, Array, Document, Item, Table};
 use url::Url;
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, strum::Display, strum::EnumIter)]
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    strum::Display,
+    strum::EnumIter,
+    serde::Deserialize,
+    serde::Serialize,
+)]
 #[strum(serialize_all = "kebab-case")]
+#[serde(rename_all = "kebab-case")]
 pub enum Framework {
     ActixWeb,
     Axum,
@@ -46,6 +57,53 @@ impl Framework {
             Framework::None => Box::new(ShuttleInitNoOp),
         }
     }
+
+    /// What the template of this framework sets up
+    pub fn description(&self) -> &'static str {
+        match self {
+            Framework::ActixWeb => "web service on actix-web",
+            Framework::Axum => "web service on axum",
+            Framework::Rocket => "web service on Rocket",
+            Framework::Tide => "web service on tide",
+            Framework::Tower => "web service on a bare tower service",
+            Framework::Poem => "web service on poem",
+            Framework::Salvo => "web service on salvo",
+            Framework::Serenity => "Discord bot on serenity",
+            Framework::Poise => "Discord bot on poise, with slash commands",
+            Framework::Warp => "web service on warp",
+            Framework::Thruster => "web service on thruster",
+            Framework::None => "empty library, to bring your own service",
+        }
+    }
+
+    /// Features of `shuttle-service` the template turns on
+    pub fn shuttle_features(&self) -> Vec<String> {
+        let feature = match self {
+            Framework::ActixWeb => "web-actix-web",
+            Framework::Axum => "web-axum",
+            Framework::Rocket => "web-rocket",
+            Framework::Tide => "web-tide",
+            Framework::Tower => "web-tower",
+            Framework::Poem => "web-poem",
+            Framework::Salvo => "web-salvo",
+            Framework::Serenity => "bot-serenity",
+            Framework::Poise => "bot-poise",
+            Framework::Warp => "web-warp",
+            Framework::Thruster => "web-thruster",
+            Framework::None => return Vec::new(),
+        };
+
+        vec![feature.to_string()]
+    }
+
+    /// Flag of `cargo shuttle init` which picks this framework
+    pub fn flag(&self) -> String {
+        match self {
+            Framework::ActixWeb => "--actix_web".to_string(),
+            Framework::None => "--no-framework".to_string(),
+            framework => format!("--{framework}"),
+        }
+    }
 }
 
 pub trait ShuttleInit {
@@ -81,7 +139,7 @@ impl ShuttleInit for ShuttleInitActixWeb {
         set_inline_table_dependency_features(
             "shuttle-service",
             dependencies,
-            vec!["web-actix-web".to_string()],
+            Framework::ActixWeb.shuttle_features(),
         );
     }
 
@@ -127,7 +185,7 @@ impl ShuttleInit for ShuttleInitAxum {
         set_inline_table_dependency_features(
             "shuttle-service",
             dependencies,
-            vec!["web-axum".to_string()],
+            Framework::Axum.shuttle_features(),
         );
         set_key_value_dependency_version(
             "sync_wrapper",
@@ -180,7 +238,7 @@ impl ShuttleInit for ShuttleInitRocket {
         set_inline_table_dependency_features(
             "shuttle-service",
             dependencies,
-            vec!["web-rocket".to_string()],
+            Framework::Rocket.shuttle_features(),
         );
     }
 
@@ -216,7 +274,7 @@ impl ShuttleInit for ShuttleInitTide {
         set_inline_table_dependency_features(
             "shuttle-service",
             dependencies,
-            vec!["web-tide".to_string()],
+            Framework::Tide.shuttle_features(),
         );
 
         set_key_value_dependency_version(
@@ -256,7 +314,7 @@ impl ShuttleInit for ShuttleInitPoem {
         set_inline_table_dependency_features(
             "shuttle-service",
             dependencies,
-            vec!["web-poem".to_string()],
+            Framework::Poem.shuttle_features(),
         );
 
         set_key_value_dependency_version(
@@ -300,7 +358,7 @@ impl ShuttleInit for ShuttleInitSalvo {
         set_inline_table_dependency_features(
             "shuttle-service",
             dependencies,
-            vec!["web-salvo".to_string()],
+            Framework::Salvo.shuttle_features(),
         );
 
         set_key_value_dependency_version(
@@ -344,7 +402,7 @@ impl ShuttleInit for ShuttleInitSerenity {
         set_inline_table_dependency_features(
             "shuttle-service",
             dependencies,
-            vec!["bot-serenity".to_string()],
+            Framework::Serenity.shuttle_features(),
         );
 
         set_key_value_dependency_version(
@@ -461,7 +519,7 @@ impl ShuttleInit for ShuttleInitPoise {
         set_inline_table_dependency_features(
             "shuttle-service",
             dependencies,
-            vec!["bot-poise".to_string()],
+            Framework::Poise.shuttle_features(),
         );
 
         set_key_value_dependency_version(
@@ -561,7 +619,7 @@ impl ShuttleInit for ShuttleInitTower {
         set_inline_table_dependency_features(
             "shuttle-service",
             dependencies,
-            vec!["web-tower".to_string()],
+            Framework::Tower.shuttle_features(),
         );
 
         set_inline_table_dependency_version(
@@ -639,7 +697,7 @@ impl ShuttleInit for ShuttleInitWarp {
         set_inline_table_dependency_features(
             "shuttle-service",
             dependencies,
-            vec!["web-warp".to_string()],
+            Framework::Warp.shuttle_features(),
         );
 
         set_key_value_dependency_version(
@@ -678,7 +736,7 @@ impl ShuttleInit for ShuttleInitThruster {
         set_inline_table_dependency_features(
             "shuttle-service",
             dependencies,
-            vec!["web-thruster".to_string()],
+            Framework::Thruster.shuttle_features(),
         );
 
         set_inline_table_dependency_version(
@@ -736,6 +794,39 @@ impl ShuttleInit for ShuttleInitNoOp {
     }
 }
 
+/// Files generated by init, which are the only ones it may overwrite in a non-empty directory.
+const TEMPLATE_FILES: [&str; 2] = ["Cargo.toml", "src/lib.rs"];
+
+/// Returns the (sorted) names of the entries already present in the directory at `path`.
+/// A directory that does not exist yet has no entries.
+pub fn existing_entries(path: &Path) -> Result<Vec<String>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut entries = read_dir(path)
+        .with_context(|| format!("failed to read directory {}", path.display()))?
+        .map(|entry| Ok(entry?.file_name().to_string_lossy().to_string()))
+        .collect::<Result<Vec<_>>>()?;
+    entries.sort();
+
+    Ok(entries)
+}
+
+/// Removes the template files from `path` so they can be regenerated. Every other file is left alone.
+pub fn remove_template_files(path: &Path) -> Result<()> {
+    for file in TEMPLATE_FILES {
+        let file_path = path.join(file);
+
+        if file_path.is_file() {
+            remove_file(&file_path)
+                .with_context(|| format!("failed to remove {}", file_path.display()))?;
+        }
+    }
+
+    Ok(())
+}
+
 /// Interoprates with `cargo` crate and calls `cargo init --libs [path]`.
 pub fn cargo_init(path: PathBuf) -> Result<()> {
     let opts = NewOptions::new(None, false, true, path, None, None, None)?;
@@ -882,6 +973,7 @@ pub fn write_lib_file(boilerplate: &'static str, lib_path: &Path) -> Result<()>
 #[cfg(test)]
 mod shuttle_init_tests {
     use super::*;
+    use std::fs::{create_dir_all, write};
 
     fn cargo_toml_factory() -> Document {
         indoc! {r#"
@@ -1351,4 +1443,31 @@ mod shuttle_init_tests {
 
         assert_eq!(version, expected);
     }
+
+    #[test]
+    fn test_existing_entries_and_remove_template_files() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let path = temp_dir.path();
+
+        assert!(existing_entries(&path.join("missing")).unwrap().is_empty());
+        assert!(existing_entries(path).unwrap().is_empty());
+
+        create_dir_all(path.join("src")).unwrap();
+        write(path.join("Cargo.toml"), "[package]").unwrap();
+        write(path.join("src").join("lib.rs"), "").unwrap();
+        write(path.join("src").join("main.rs"), "fn main() {}").unwrap();
+        write(path.join("README.md"), "# My project").unwrap();
+
+        assert_eq!(
+            existing_entries(path).unwrap(),
+            vec!["Cargo.toml", "README.md", "src"]
+        );
+
+        remove_template_files(path).unwrap();
+
+        assert!(!path.join("Cargo.toml").exists());
+        assert!(!path.join("src").join("lib.rs").exists());
+        assert!(path.join("src").join("main.rs").exists());
+        assert!(path.join("README.md").exists());
+    }
 }
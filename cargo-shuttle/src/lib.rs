@@ -1,53 +1,108 @@
 mod args;
 mod client;
 pub mod config;
+mod dependencies;
 mod factory;
+mod idle;
 mod init;
+mod log_export;
+mod manifest;
+#[macro_use]
+mod output;
 
 use indicatif::ProgressBar;
+use shuttle_common::log::Stream;
 use shuttle_common::models::project::{State, IDLE_MINUTES};
 use shuttle_common::project::ProjectName;
 
+use std::cmp::Ordering;
 use std::collections::BTreeMap;
 use std::ffi::OsString;
 use std::fs::{read_to_string, File};
-use std::io::stdout;
+use std::io::{stderr, stdin, stdout, BufWriter};
 use std::net::{Ipv4Addr, SocketAddr};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
 use anyhow::{anyhow, bail, Context, Result};
-pub use args::{Args, Command, DeployArgs, InitArgs, LoginArgs, ProjectArgs, RunArgs};
+pub use args::{
+    Args, CheckArgs, ColorChoice, Command, DeployArgs, ExportFormat, InitArgs, LoginArgs,
+    OutputFormat, ProjectArgs, RunArgs,
+};
 use cargo_metadata::Message;
+use chrono::{DateTime, Utc};
 use clap::CommandFactory;
 use clap_complete::{generate, Shell};
 use config::RequestContext;
-use crossterm::style::Stylize;
+use crossterm::tty::IsTty;
 use dialoguer::{theme::ColorfulTheme, Confirm, FuzzySelect, Input, Password};
 use factory::LocalFactory;
 use flate2::write::GzEncoder;
 use flate2::Compression;
 use futures::StreamExt;
 use git2::{Repository, StatusOptions};
+use idle::IdleRunner;
 use ignore::overrides::OverrideBuilder;
 use ignore::WalkBuilder;
-use shuttle_common::models::{project, secret};
+use serde::Serialize;
+use shuttle_common::models::deployment::{self, LATEST_TAG};
+use shuttle_common::models::{
+    basic_auth, header_limits, host_header, project, response_header, secret, stats, timeouts,
+    usage, version,
+};
+use shuttle_common::style::Stylize;
 use shuttle_service::loader::{build_crate, Loader};
 use shuttle_service::Logger;
 use std::fmt::Write;
 use strum::IntoEnumIterator;
-use tar::Builder;
+use tar::{Builder, Header};
 use tokio::sync::mpsc;
 use tracing::trace;
 use uuid::Uuid;
 
-use crate::args::{DeploymentCommand, ProjectCommand};
-use crate::client::Client;
+use crate::args::{
+    AccountCommand, DeploymentCommand, DeploymentRef, ProjectCommand, SecretsCommand,
+};
+use crate::client::{Client, DeployOptions, DeploymentFilter};
+use crate::log_export::LogExporter;
+use crate::manifest::{DeployConfig, Manifest};
+
+/// Size of the pieces a deployment archive is uploaded in
+const UPLOAD_CHUNK_SIZE: u64 = 1024 * 1024;
+/// Number of times in a row a chunk can fail before the upload is given up on
+const MAX_UPLOAD_ATTEMPTS: u32 = 5;
+const UPLOAD_RETRY_DELAY: std::time::Duration = std::time::Duration::from_secs(1);
+/// Failed attempts in a row at picking up a dropped log stream before giving up on it
+const MAX_RECONNECT_ATTEMPTS: u32 = 10;
+const RECONNECT_DELAY: std::time::Duration = std::time::Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: std::time::Duration = std::time::Duration::from_secs(30);
+const DEPLOY_RETRY_DELAY: std::time::Duration = std::time::Duration::from_secs(10);
+const MAX_DEPLOY_RETRY_DELAY: std::time::Duration = std::time::Duration::from_secs(120);
+/// Lines of logs fetched at a time when exporting them
+const EXPORT_PAGE_SIZE: u32 = 1000;
 
 pub struct Shuttle {
     ctx: RequestContext,
 }
 
+/// What `--version --output-format json` prints
+#[derive(Serialize)]
+struct VersionInfo {
+    version: &'static str,
+    git_commit: &'static str,
+    build_date: &'static str,
+    protocol_version: u32,
+}
+
+/// A template `init` can start a project from, as `init --list-templates --output-format json` prints it
+#[derive(Serialize)]
+struct TemplateInfo {
+    framework: init::Framework,
+    flag: String,
+    description: &'static str,
+    shuttle_features: Vec<String>,
+}
+
 impl Shuttle {
     pub fn new() -> Result<Self> {
         let ctx = RequestContext::load_global()?;
@@ -56,15 +111,40 @@ impl Shuttle {
 
     pub async fn run(mut self, mut args: Args) -> Result<CommandOutcome> {
         trace!("running local client");
+        // Whatever reads the output of the service should not have to deal with escape codes
+        let raw = matches!(
+            &args.cmd,
+            Some(Command::Deploy(DeployArgs { raw: true, .. }))
+        );
+        shuttle_common::style::set_colors(args.color.colors() && !raw);
+        output::set_quiet(args.quiet);
+        output::set_raw(raw);
+
+        if args.version {
+            return self.version(args.output_format).map(|_| CommandOutcome::Ok);
+        }
+
+        let Some(cmd) = args.cmd else {
+            Args::command()
+                .error(
+                    clap::error::ErrorKind::MissingSubcommand,
+                    "a subcommand is required",
+                )
+                .exit();
+        };
+
         if matches!(
-            args.cmd,
+            cmd,
             Command::Deploy(..)
+                | Command::Check(..)
                 | Command::Deployment(..)
                 | Command::Project(..)
                 | Command::Stop
+                | Command::Restart
                 | Command::Clean
-                | Command::Secrets
+                | Command::Secrets { .. }
                 | Command::Status
+                | Command::Open
                 | Command::Logs { .. }
                 | Command::Run(..)
         ) {
@@ -73,42 +153,176 @@ impl Shuttle {
 
         self.ctx.set_api_url(args.api_url);
 
-        match args.cmd {
+        match cmd {
+            Command::Init(init_args) if init_args.list_templates => {
+                self.list_templates(args.output_format)
+            }
             Command::Init(init_args) => self.init(init_args, args.project_args).await,
             Command::Generate { shell, output } => self.complete(shell, output).await,
             Command::Login(login_args) => self.login(login_args).await,
             Command::Logout => self.logout().await,
             Command::Feedback => self.feedback().await,
             Command::Run(run_args) => self.local_run(run_args).await,
+            Command::Check(check_args) => return self.check(check_args).await,
             need_client => {
                 let mut client = Client::new(self.ctx.api_url());
                 client.set_api_key(self.ctx.api_key()?);
 
                 match need_client {
                     Command::Deploy(deploy_args) => {
-                        return self.deploy(deploy_args, &client).await;
+                        return self.deploy(deploy_args, &client, args.output_format).await;
                     }
                     Command::Status => self.status(&client).await,
-                    Command::Logs { id, follow } => self.logs(&client, id, follow).await,
-                    Command::Deployment(DeploymentCommand::List) => {
-                        self.deployments_list(&client).await
+                    Command::Verify { manifest } => self.verify(&client, &manifest).await,
+                    Command::Open => self.open(&client).await,
+                    Command::Logs {
+                        id,
+                        follow,
+                        show_stream,
+                        stderr,
+                        stdout,
+                        tail,
+                        export,
+                        out,
+                    } => {
+                        let only = match (stderr, stdout) {
+                            (true, _) => Some(Stream::Stderr),
+                            (_, true) => Some(Stream::Stdout),
+                            _ => None,
+                        };
+
+                        match export {
+                            Some(format) => self.export_logs(&client, id, format, out, only).await,
+                            None => {
+                                self.logs(&client, id, follow, tail, only, show_stream)
+                                    .await
+                            }
+                        }
+                    }
+                    Command::Deployment(DeploymentCommand::List {
+                        states,
+                        since,
+                        until,
+                    }) => {
+                        let filter = DeploymentFilter {
+                            states,
+                            since,
+                            until,
+                        };
+
+                        self.deployments_list(&client, &filter, args.output_format)
+                            .await
+                    }
+                    Command::Deployment(DeploymentCommand::Status { id, json }) => {
+                        if json || args.output_format == OutputFormat::Json {
+                            self.deployment_status_json(&client, id).await
+                        } else {
+                            self.deployment_get(&client, id).await
+                        }
                     }
-                    Command::Deployment(DeploymentCommand::Status { id }) => {
-                        self.deployment_get(&client, id).await
+                    Command::Deployment(DeploymentCommand::Cancel { id }) => {
+                        self.deployment_cancel(&client, id).await
                     }
+                    Command::Deployment(DeploymentCommand::Rm { id, force }) => {
+                        self.deployment_rm(&client, id, force).await
+                    }
+                    Command::Deployment(DeploymentCommand::Profile {
+                        id,
+                        seconds,
+                        output,
+                    }) => self.deployment_profile(&client, id, seconds, output).await,
                     Command::Stop => self.stop(&client).await,
+                    Command::Restart => self.restart(&client).await,
                     Command::Clean => self.clean(&client).await,
-                    Command::Secrets => self.secrets(&client).await,
+                    Command::Secrets { cmd: None } => self.secrets(&client).await,
+                    Command::Secrets {
+                        cmd: Some(SecretsCommand::Sync { file, yes, prune }),
+                    } => self.secrets_sync(&client, &file, yes, prune).await,
                     Command::Project(ProjectCommand::New { idle_minutes }) => {
+                        let idle_minutes = idle_minutes
+                            .or(self.ctx.idle_minutes())
+                            .unwrap_or(IDLE_MINUTES);
+
                         self.project_create(&client, idle_minutes).await
                     }
-                    Command::Project(ProjectCommand::Status { follow }) => {
+                    Command::Project(ProjectCommand::Status { history: true, .. }) => {
+                        self.project_history(&client).await
+                    }
+                    Command::Project(ProjectCommand::Status { follow, .. }) => {
                         self.project_status(&client, follow).await
                     }
                     Command::Project(ProjectCommand::List { filter }) => {
                         self.projects_list(&client, filter).await
                     }
                     Command::Project(ProjectCommand::Rm) => self.project_delete(&client).await,
+                    Command::Project(ProjectCommand::Stats) => self.project_stats(&client).await,
+                    Command::Project(ProjectCommand::Pin) => self.project_pin(&client, true).await,
+                    Command::Project(ProjectCommand::Unpin) => {
+                        self.project_pin(&client, false).await
+                    }
+                    Command::Project(ProjectCommand::Transfer {
+                        to_account,
+                        accept,
+                        cancel,
+                    }) => {
+                        self.project_transfer(&client, to_account, accept, cancel)
+                            .await
+                    }
+                    Command::Project(ProjectCommand::HostHeader {
+                        preserve,
+                        backend,
+                        fixed,
+                    }) => {
+                        self.project_host_header(&client, preserve, backend, fixed)
+                            .await
+                    }
+                    Command::Project(ProjectCommand::Timeouts {
+                        response,
+                        body_idle,
+                        abort_stalled,
+                        slow_request,
+                        reset,
+                    }) => {
+                        self.project_timeouts(
+                            &client,
+                            response,
+                            body_idle,
+                            abort_stalled,
+                            slow_request,
+                            reset,
+                        )
+                        .await
+                    }
+                    Command::Project(ProjectCommand::HeaderLimits {
+                        max_total_size,
+                        max_count,
+                        max_header_size,
+                        reset,
+                    }) => {
+                        let limits = header_limits::Config {
+                            max_total_size,
+                            max_count,
+                            max_header_size,
+                        };
+
+                        self.project_header_limits(&client, limits, reset).await
+                    }
+                    Command::Project(ProjectCommand::ResponseHeaders { set, remove, allow }) => {
+                        self.project_response_headers(&client, set, remove, allow)
+                            .await
+                    }
+                    Command::Project(ProjectCommand::BasicAuth {
+                        username,
+                        password,
+                        exempt,
+                        remove,
+                    }) => {
+                        self.project_basic_auth(&client, username, password, exempt, remove)
+                            .await
+                    }
+                    Command::Account(AccountCommand::Usage) => {
+                        self.account_usage(&client, args.output_format).await
+                    }
                     _ => {
                         unreachable!("commands that don't need a client have already been matched")
                     }
@@ -118,13 +332,113 @@ impl Shuttle {
         .map(|_| CommandOutcome::Ok)
     }
 
+    fn version(&self, output_format: OutputFormat) -> Result<()> {
+        let version = VersionInfo {
+            version: env!("CARGO_PKG_VERSION"),
+            git_commit: env!("SHUTTLE_GIT_COMMIT"),
+            build_date: env!("SHUTTLE_BUILD_DATE"),
+            protocol_version: version::PROTOCOL_VERSION,
+        };
+
+        match output_format {
+            OutputFormat::Text => println!("{} {}", env!("CARGO_PKG_NAME"), version.version),
+            OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&version)?),
+        }
+
+        Ok(())
+    }
+
+    fn list_templates(&self, output_format: OutputFormat) -> Result<()> {
+        let templates: Vec<_> = init::Framework::iter()
+            .map(|framework| TemplateInfo {
+                framework,
+                flag: framework.flag(),
+                description: framework.description(),
+                shuttle_features: framework.shuttle_features(),
+            })
+            .collect();
+
+        match output_format {
+            OutputFormat::Text => {
+                for template in templates {
+                    let features = if template.shuttle_features.is_empty() {
+                        "no shuttle-service features".to_string()
+                    } else {
+                        format!(
+                            "shuttle-service features: {}",
+                            template.shuttle_features.join(", ")
+                        )
+                    };
+
+                    println!(
+                        "{:<16} {} ({features})",
+                        template.flag, template.description
+                    );
+                }
+            }
+            OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&templates)?),
+        }
+
+        Ok(())
+    }
+
+    /// Warn when the platform speaks a different version of the API than this CLI. Platforms which predate the
+    /// version endpoint are left alone.
+    async fn check_protocol_version(&self, client: &Client) {
+        let server = match client.get_version().await {
+            Ok(server) => server,
+            Err(error) => {
+                trace!(?error, "could not get the version of the platform");
+                return;
+            }
+        };
+
+        match server.protocol_version.cmp(&version::PROTOCOL_VERSION) {
+            Ordering::Greater => note!(
+                "{}",
+                format!(
+                    "The platform speaks protocol version {}, which is newer than the {} of this CLI. Update with `cargo install cargo-shuttle` if something goes wrong.",
+                    server.protocol_version,
+                    version::PROTOCOL_VERSION
+                )
+                .yellow()
+            ),
+            Ordering::Less => note!(
+                "{}",
+                format!(
+                    "The platform speaks protocol version {}, which is older than the {} of this CLI. Some features may not be available.",
+                    server.protocol_version,
+                    version::PROTOCOL_VERSION
+                )
+                .yellow()
+            ),
+            Ordering::Equal => {}
+        }
+    }
+
     /// Log in, initialize a project and potentially create the Shuttle environment for it.
     ///
     /// If both a project name and framework are passed as arguments, it will run without any extra
     /// interaction.
-    async fn init(&mut self, args: InitArgs, mut project_args: ProjectArgs) -> Result<()> {
+    async fn init(&mut self, mut args: InitArgs, mut project_args: ProjectArgs) -> Result<()> {
+        args.defaults = self.ctx.init_defaults();
         let interactive = project_args.name.is_none() || args.framework().is_none();
 
+        // Prompts need a terminal, so scripts have to give everything as flags
+        if interactive && !(stdin().is_tty() && stderr().is_tty()) {
+            if project_args.name.is_none() {
+                bail!("Not running in a terminal, so the project name cannot be asked for. Pass it with `--name`");
+            }
+
+            let flags: Vec<_> = init::Framework::iter()
+                .map(|framework| framework.flag())
+                .collect();
+            bail!(
+                "Not running in a terminal, so the framework cannot be asked for. Pass one of {}",
+                flags.join(", ")
+            );
+        }
+
         let theme = ColorfulTheme::default();
 
         // 1. Log in (if not logged in yet)
@@ -133,7 +447,7 @@ impl Shuttle {
                 println!("First, let's log in to your Shuttle account.");
                 self.login(args.login_args.clone()).await?;
                 println!();
-            } else if args.new && args.login_args.api_key.is_some() {
+            } else if args.create_environment() && args.login_args.api_key.is_some() {
                 self.login(args.login_args.clone()).await?;
             } else {
                 bail!("Tried to login to create a Shuttle environment, but no API key was set.")
@@ -182,15 +496,37 @@ impl Shuttle {
             }
         };
 
-        // 5. Initialize locally
+        // 5. Initialize locally, without clobbering an existing directory by accident
+        let existing_entries = init::existing_entries(&path)?;
+        if !existing_entries.is_empty() {
+            if !args.force {
+                let mut error = format!(
+                    "The directory {} is not empty. It already contains:",
+                    path.display()
+                );
+                writeln!(error).expect("to append error");
+
+                for entry in existing_entries {
+                    writeln!(error, "  {entry}").expect("to append error");
+                }
+
+                writeln!(error).expect("to append error");
+                writeln!(error, "to initialize in it anyway, pass the `--force` flag. Only the files generated by init will be overwritten").expect("to append error");
+
+                bail!(error);
+            }
+
+            init::remove_template_files(&path)?;
+        }
+
         init::cargo_init(path.clone())?;
         init::cargo_shuttle_init(path.clone(), framework)?;
         println!();
 
         // 6. Confirm that the user wants to create the project environment on Shuttle
         let should_create_environment = if !interactive {
-            args.new
-        } else if args.new {
+            args.create_environment()
+        } else if args.create_environment() {
             true
         } else {
             let should_create = Confirm::with_theme(&theme)
@@ -210,7 +546,8 @@ impl Shuttle {
             self.load_project(&mut project_args)?;
             let mut client = Client::new(self.ctx.api_url());
             client.set_api_key(self.ctx.api_key()?);
-            self.project_create(&client, IDLE_MINUTES).await?;
+            self.project_create(&client, self.ctx.idle_minutes().unwrap_or(IDLE_MINUTES))
+                .await?;
         }
 
         Ok(())
@@ -302,6 +639,52 @@ impl Shuttle {
         Ok(())
     }
 
+    async fn restart(&self, client: &Client) -> Result<()> {
+        let service = client.restart_service(self.ctx.project_name()).await?;
+
+        let Some(mut deployment) = service.deployment else {
+            println!(
+                "{}",
+                "No deployment is currently running for this service, there is nothing to restart."
+                    .yellow()
+            );
+
+            return Ok(());
+        };
+
+        let progress_bar = create_spinner();
+        loop {
+            match deployment.state {
+                shuttle_common::deployment::State::Running
+                | shuttle_common::deployment::State::Crashed => break,
+                _ => {}
+            }
+
+            progress_bar.set_message(format!("Restarting {}", deployment.id));
+            tokio::time::sleep(std::time::Duration::from_millis(350)).await;
+            deployment = client
+                .get_deployment_details(self.ctx.project_name(), &deployment.id)
+                .await?;
+        }
+        progress_bar.finish_and_clear();
+
+        if let shuttle_common::deployment::State::Crashed = deployment.state {
+            println!("{}", "Deployment crashed while restarting".red());
+            println!("{deployment}");
+
+            return Ok(());
+        }
+
+        println!(
+            r#"{}
+{}"#,
+            "Successfully restarted service".bold(),
+            deployment
+        );
+
+        Ok(())
+    }
+
     async fn complete(&self, shell: Shell, output: Option<PathBuf>) -> Result<()> {
         let name = env!("CARGO_PKG_NAME");
         let mut app = Command::command();
@@ -321,6 +704,23 @@ impl Shuttle {
         Ok(())
     }
 
+    /// Open the public URL of the deployed service, which is its custom domain if it has one
+    async fn open(&self, client: &Client) -> Result<()> {
+        let summary = client.get_service_summary(self.ctx.project_name()).await?;
+
+        if summary.deployment.is_none() {
+            println!("{}", "No deployment is currently running for this service, the URL will only respond once one is.".yellow());
+        }
+
+        if webbrowser::open(&summary.uri).is_ok() {
+            println!("Opening {} in your browser", summary.uri);
+        } else {
+            println!("{}", summary.uri);
+        }
+
+        Ok(())
+    }
+
     async fn secrets(&self, client: &Client) -> Result<()> {
         let secrets = client.get_secrets(self.ctx.project_name()).await?;
         let table = secret::get_table(&secrets);
@@ -330,6 +730,59 @@ impl Shuttle {
         Ok(())
     }
 
+    async fn secrets_sync(
+        &self,
+        client: &Client,
+        file: &Path,
+        yes: bool,
+        prune: bool,
+    ) -> Result<()> {
+        let secrets: BTreeMap<String, String> = read_to_string(file)
+            .with_context(|| format!("failed to read secrets from {}", file.display()))?
+            .parse::<toml::Value>()?
+            .try_into()
+            .context("secrets should be a table of strings")?;
+        let request = |secrets| secret::SyncRequest { secrets, prune };
+
+        let diff = client
+            .sync_secrets(self.ctx.project_name(), request(secrets.clone()), true)
+            .await?;
+
+        print!("{diff}");
+
+        if diff.is_empty() {
+            println!("Secrets are already in sync");
+            return Ok(());
+        }
+
+        if !yes
+            && !Confirm::with_theme(&ColorfulTheme::default())
+                .with_prompt("Apply these changes?")
+                .default(false)
+                .interact()?
+        {
+            println!("Secrets were left as they are");
+            return Ok(());
+        }
+
+        let diff = client
+            .sync_secrets(self.ctx.project_name(), request(secrets), false)
+            .await?;
+
+        println!(
+            "Secrets synced: {} added, {} changed, {} removed",
+            diff.added.len(),
+            diff.changed.len(),
+            diff.removed.len()
+        );
+
+        if diff.restarted {
+            println!("The running deployment was restarted to load them");
+        }
+
+        Ok(())
+    }
+
     async fn clean(&self, client: &Client) -> Result<()> {
         let lines = client.clean_project(self.ctx.project_name()).await?;
 
@@ -342,76 +795,313 @@ impl Shuttle {
         Ok(())
     }
 
-    async fn logs(&self, client: &Client, id: Option<Uuid>, follow: bool) -> Result<()> {
-        let id = if let Some(id) = id {
-            id
+    async fn logs(
+        &self,
+        client: &Client,
+        id: Option<DeploymentRef>,
+        follow: bool,
+        tail: Option<u32>,
+        only: Option<Stream>,
+        show_stream: bool,
+    ) -> Result<()> {
+        let id = self.logs_deployment(client, id).await?;
+
+        if follow {
+            self.follow_logs(client, &id, tail, None, |log| {
+                print_log(log, only, show_stream)
+            })
+            .await?;
+        } else {
+            let logs = client.get_logs(self.ctx.project_name(), &id, tail).await?;
+
+            for log in logs.iter() {
+                print_log(log, only, show_stream);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The deployment to get the logs of, which is the running one unless another one is given
+    async fn logs_deployment(&self, client: &Client, id: Option<DeploymentRef>) -> Result<Uuid> {
+        if let Some(id) = id {
+            self.resolve_deployment(client, id).await
         } else {
             let summary = client.get_service_summary(self.ctx.project_name()).await?;
 
             if let Some(deployment) = summary.deployment {
-                deployment.id
+                Ok(deployment.id)
             } else {
-                return Err(anyhow!("could not automatically find a running deployment for '{}'. Try passing a deployment ID manually", self.ctx.project_name()));
+                Err(anyhow!("could not automatically find a running deployment for '{}'. Try passing a deployment ID manually", self.ctx.project_name()))
             }
+        }
+    }
+
+    /// Write every line of the logs of a deployment to `out`, or to stdout without one. The logs are fetched and
+    /// written a page at a time, so that a long history is never held in memory all at once.
+    async fn export_logs(
+        &self,
+        client: &Client,
+        id: Option<DeploymentRef>,
+        format: ExportFormat,
+        out: Option<PathBuf>,
+        only: Option<Stream>,
+    ) -> Result<()> {
+        let id = self.logs_deployment(client, id).await?;
+
+        let writer: Box<dyn std::io::Write> = match &out {
+            Some(path) => Box::new(BufWriter::new(File::create(path).with_context(|| {
+                format!("failed to create export file '{}'", path.display())
+            })?)),
+            None => Box::new(BufWriter::new(stdout())),
         };
+        let mut exporter = LogExporter::new(writer, format);
+        let mut after = None;
 
-        if follow {
-            let mut stream = client.get_logs_ws(self.ctx.project_name(), &id).await?;
+        loop {
+            let page = client
+                .get_logs_page(self.ctx.project_name(), &id, after, EXPORT_PAGE_SIZE)
+                .await?;
+
+            // Pages can come back short of the limit when the deployer leaves lines out, so only an empty one is the end
+            let Some(last) = page.last() else {
+                break;
+            };
+            // Older deployers ignore `after` and `limit`, sending every line each time. A page which gets no further
+            // than the one before is taken as the end, so that their lines are only exported once.
+            if after.map_or(false, |after| last.timestamp <= after) {
+                break;
+            }
+            after = Some(last.timestamp);
+
+            for log in page.iter() {
+                if is_shown(log, only) {
+                    exporter.write(log)?;
+                }
+            }
+        }
+
+        let lines = exporter.finish()?;
+
+        if let Some(path) = out {
+            eprintln!("Exported {lines} log lines to {}", path.display());
+        }
+
+        Ok(())
+    }
+
+    /// Print the logs of a deployment as they come, connecting to the stream again when it drops. The stream then
+    /// picks up after the last line printed, so nothing is missed or printed twice. Only the lines after `after` are
+    /// printed when it is given.
+    async fn follow_logs(
+        &self,
+        client: &Client,
+        id: &Uuid,
+        tail: Option<u32>,
+        after: Option<DateTime<Utc>>,
+        mut print: impl FnMut(&shuttle_common::LogItem),
+    ) -> Result<()> {
+        let mut last_timestamp = after;
+        let mut attempts = 0;
+
+        loop {
+            // The lines to tail have all been printed by the time the stream is picked up again
+            let tail = tail.filter(|_| last_timestamp.is_none());
+
+            let mut stream = match client
+                .get_logs_ws(self.ctx.project_name(), id, tail, last_timestamp)
+                .await
+            {
+                Ok(stream) => stream,
+                // A first connection which fails is most likely not a blip
+                Err(error) if last_timestamp.is_none() && attempts == 0 => return Err(error),
+                Err(error) if attempts < MAX_RECONNECT_ATTEMPTS => {
+                    trace!(?error, attempts, "failed to reconnect to the log stream");
+                    attempts += 1;
+                    tokio::time::sleep(reconnect_delay(attempts)).await;
+                    continue;
+                }
+                Err(error) => {
+                    return Err(error).context("lost the log stream and could not reconnect")
+                }
+            };
+
+            if attempts > 0 {
+                note!("{}", "[reconnected]".dim());
+                attempts = 0;
+            }
 
             while let Some(Ok(msg)) = stream.next().await {
                 if let tokio_tungstenite::tungstenite::Message::Text(line) = msg {
                     let log_item: shuttle_common::LogItem =
                         serde_json::from_str(&line).expect("to parse log line");
-                    println!("{log_item}")
+                    last_timestamp = Some(log_item.timestamp);
+                    print(&log_item);
                 }
             }
-        } else {
-            let logs = client.get_logs(self.ctx.project_name(), &id).await?;
 
-            for log in logs.into_iter() {
-                println!("{log}");
+            trace!("log stream ended, reconnecting");
+            attempts = 1;
+            tokio::time::sleep(reconnect_delay(attempts)).await;
+        }
+    }
+
+    async fn deployments_list(
+        &self,
+        client: &Client,
+        filter: &DeploymentFilter,
+        output_format: OutputFormat,
+    ) -> Result<()> {
+        let details = client
+            .get_filtered_service_details(self.ctx.project_name(), filter)
+            .await?;
+
+        match output_format {
+            OutputFormat::Text => println!("{details}"),
+            OutputFormat::Json => {
+                println!("{}", serde_json::to_string_pretty(&details.deployments)?)
             }
         }
 
         Ok(())
     }
 
-    async fn deployments_list(&self, client: &Client) -> Result<()> {
-        let details = client.get_service_details(self.ctx.project_name()).await?;
+    async fn deployment_get(&self, client: &Client, deployment_id: DeploymentRef) -> Result<()> {
+        let deployment = match deployment_id {
+            DeploymentRef::Id(id) => {
+                client
+                    .get_deployment_details(self.ctx.project_name(), &id)
+                    .await?
+            }
+            DeploymentRef::Latest => {
+                client
+                    .get_tagged_deployment(self.ctx.project_name(), LATEST_TAG)
+                    .await?
+            }
+        };
 
-        println!("{details}");
+        println!("{deployment}");
 
         Ok(())
     }
 
-    async fn deployment_get(&self, client: &Client, deployment_id: Uuid) -> Result<()> {
-        let deployment = client
-            .get_deployment_details(self.ctx.project_name(), &deployment_id)
+    async fn deployment_status_json(
+        &self,
+        client: &Client,
+        deployment_id: DeploymentRef,
+    ) -> Result<()> {
+        let id = self.resolve_deployment(client, deployment_id).await?;
+        let status = client
+            .get_deployment_status(self.ctx.project_name(), &id)
             .await?;
 
-        println!("{deployment}");
+        println!("{}", serde_json::to_string_pretty(&status)?);
 
         Ok(())
     }
 
-    async fn local_run(&self, run_args: RunArgs) -> Result<()> {
-        trace!("starting a local run for a service: {run_args:?}");
+    async fn deployment_cancel(&self, client: &Client, deployment_id: DeploymentRef) -> Result<()> {
+        let id = self.resolve_deployment(client, deployment_id).await?;
 
-        let (tx, rx): (crossbeam_channel::Sender<Message>, _) = crossbeam_channel::bounded(0);
-        tokio::task::spawn_blocking(move || {
-            while let Ok(message) = rx.recv() {
-                match message {
-                    Message::TextLine(line) => println!("{line}"),
-                    Message::CompilerMessage(message) => {
-                        if let Some(rendered) = message.message.rendered {
-                            println!("{rendered}");
-                        }
-                    }
-                    _ => {}
+        client
+            .cancel_deployment(self.ctx.project_name(), &id)
+            .await?;
+
+        println!("Cancelled deployment {id}");
+
+        Ok(())
+    }
+
+    async fn deployment_rm(
+        &self,
+        client: &Client,
+        deployment_id: DeploymentRef,
+        force: bool,
+    ) -> Result<()> {
+        let id = self.resolve_deployment(client, deployment_id).await?;
+
+        let removal = client
+            .delete_deployment_artifact(self.ctx.project_name(), &id, force)
+            .await?;
+
+        println!(
+            "Deleted the artifact of deployment {}, freeing {:.1} MiB and {} log lines",
+            removal.id,
+            removal.freed_bytes as f64 / (1024.0 * 1024.0),
+            removal.removed_logs
+        );
+
+        Ok(())
+    }
+
+    async fn deployment_profile(
+        &self,
+        client: &Client,
+        deployment_id: DeploymentRef,
+        seconds: u64,
+        output: PathBuf,
+    ) -> Result<()> {
+        let id = self.resolve_deployment(client, deployment_id).await?;
+
+        println!("Profiling deployment {id} for {seconds}s...");
+
+        let svg = client
+            .profile_deployment(self.ctx.project_name(), &id, seconds)
+            .await?;
+
+        std::fs::write(&output, svg)
+            .with_context(|| format!("failed to write the profile to {}", output.display()))?;
+
+        println!("Wrote the flamegraph to {}", output.display());
+
+        Ok(())
+    }
+
+    /// Cancel the deployments of this service which have not been built yet
+    async fn cancel_unbuilt_deployments(&self, client: &Client) -> Result<()> {
+        let details = client.get_service_details(self.ctx.project_name()).await?;
+
+        for deployment in details.deployments {
+            if matches!(
+                deployment.state,
+                shuttle_common::deployment::State::Queued
+                    | shuttle_common::deployment::State::Building
+            ) {
+                // The build may have finished since the deployments were listed
+                match client
+                    .cancel_deployment(self.ctx.project_name(), &deployment.id)
+                    .await
+                {
+                    Ok(_) => note!("Cancelled previous deployment {}", deployment.id),
+                    Err(error) => note!(
+                        "Could not cancel previous deployment {}: {error}",
+                        deployment.id
+                    ),
                 }
             }
-        });
+        }
+
+        Ok(())
+    }
+
+    /// Turn a deployment reference given on the command line into the ID of the deployment
+    async fn resolve_deployment(&self, client: &Client, deployment: DeploymentRef) -> Result<Uuid> {
+        match deployment {
+            DeploymentRef::Id(id) => Ok(id),
+            DeploymentRef::Latest => client
+                .get_tagged_deployment(self.ctx.project_name(), LATEST_TAG)
+                .await
+                .map(|deployment| deployment.id)
+                .with_context(|| {
+                    format!("no deployment of '{}' has been tagged as {LATEST_TAG}. Deploy with `--tag-latest` to tag one", self.ctx.project_name())
+                }),
+        }
+    }
+
+    async fn local_run(&self, run_args: RunArgs) -> Result<()> {
+        trace!("starting a local run for a service: {run_args:?}");
 
+        let tx = print_cargo_messages();
         let working_directory = self.ctx.working_directory();
         let id = Default::default();
 
@@ -422,7 +1112,7 @@ impl Shuttle {
             working_directory.display()
         );
 
-        let so_path = build_crate(id, working_directory, run_args.release, tx).await?;
+        let so_path = build_crate(id, working_directory, run_args.release, false, tx).await?;
 
         trace!("loading secrets");
 
@@ -445,12 +1135,11 @@ impl Shuttle {
                 Default::default()
             };
 
-        let loader = Loader::from_so_file(so_path)?;
-
         let mut factory = LocalFactory::new(
             self.ctx.project_name().clone(),
             secrets,
             working_directory.to_path_buf(),
+            run_args.environment_kind,
         )?;
         let addr = if run_args.external {
             std::net::IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0))
@@ -475,6 +1164,25 @@ impl Shuttle {
             }
         });
 
+        if let Some(idle) = run_args.idle {
+            let runner = IdleRunner::new(
+                so_path,
+                factory,
+                tx,
+                id,
+                std::time::Duration::from_secs(idle),
+            )?;
+
+            return runner.serve(addr).await;
+        }
+
+        let loader = Loader::from_so_file(so_path)?;
+
+        // The service is loaded by now, so breakpoints in it can be set before any of its code runs
+        if run_args.wait_for_debugger {
+            wait_for_debugger().await?;
+        }
+
         let logger = Logger::new(tx, id);
         let (handle, so) = loader.load(&mut factory, addr, logger).await?;
 
@@ -488,68 +1196,506 @@ impl Shuttle {
         Ok(())
     }
 
-    async fn deploy(&self, args: DeployArgs, client: &Client) -> Result<CommandOutcome> {
-        if !args.allow_dirty {
-            self.is_dirty()?;
+    /// Package the crate, or all of `package_root` when given, returning the archive and where the crate is in it
+    fn package(
+        &self,
+        allow_dirty: bool,
+        package_root: Option<PathBuf>,
+        secrets: Option<&BTreeMap<String, String>>,
+    ) -> Result<(Vec<u8>, Option<String>)> {
+        let package_root = package_root
+            .map(|package_root| {
+                dunce::canonicalize(&package_root).with_context(|| {
+                    format!("could not find package root {}", package_root.display())
+                })
+            })
+            .transpose()?;
+        let crate_path = package_root
+            .as_deref()
+            .map(|package_root| self.crate_path(package_root))
+            .transpose()?
+            .flatten();
+
+        if !allow_dirty {
+            self.is_dirty(
+                package_root
+                    .as_deref()
+                    .unwrap_or(self.ctx.working_directory()),
+            )?;
         }
 
-        let data = self.make_archive()?;
+        let data = self.make_archive(package_root.as_deref(), secrets)?;
 
-        let deployment = client
-            .deploy(data, self.ctx.project_name(), args.no_test)
-            .await?;
+        Ok((data, crate_path))
+    }
 
-        let mut stream = client
-            .get_logs_ws(self.ctx.project_name(), &deployment.id)
-            .await?;
+    /// The secrets to send with a deployment when they are given on the command line: those of `file`, or of the
+    /// `Secrets.toml` of the crate, with `overrides` on top. Without either, the `Secrets.toml` is packaged as it is.
+    fn deploy_secrets(
+        &self,
+        file: Option<&Path>,
+        overrides: &[(String, String)],
+    ) -> Result<Option<BTreeMap<String, String>>> {
+        if file.is_none() && overrides.is_empty() {
+            return Ok(None);
+        }
 
-        while let Some(Ok(msg)) = stream.next().await {
-            if let tokio_tungstenite::tungstenite::Message::Text(line) = msg {
-                let log_item: shuttle_common::LogItem =
-                    serde_json::from_str(&line).expect("to parse log line");
+        let path = match file {
+            Some(file) => file.to_path_buf(),
+            None => self.ctx.working_directory().join("Secrets.toml"),
+        };
 
-                match log_item.state {
-                    shuttle_common::deployment::State::Queued
-                    | shuttle_common::deployment::State::Building
-                    | shuttle_common::deployment::State::Built
-                    | shuttle_common::deployment::State::Loading => {
-                        println!("{log_item}");
-                    }
-                    shuttle_common::deployment::State::Crashed => {
-                        println!();
-                        println!("{}", "Deployment crashed".red());
-                        println!("Run the following for more details");
-                        println!();
-                        print!("cargo shuttle logs {}", deployment.id);
-                        println!();
-
-                        return Ok(CommandOutcome::DeploymentFailure);
-                    }
-                    shuttle_common::deployment::State::Running
-                    | shuttle_common::deployment::State::Completed
-                    | shuttle_common::deployment::State::Stopped
-                    | shuttle_common::deployment::State::Unknown => break,
+        let mut secrets: BTreeMap<String, String> = if file.is_some() || path.exists() {
+            read_to_string(&path)
+                .with_context(|| format!("failed to read secrets from {}", path.display()))?
+                .parse::<toml::Value>()?
+                .try_into()
+                .context("secrets should be a table of strings")?
+        } else {
+            BTreeMap::new()
+        };
+
+        secrets.extend(overrides.iter().cloned());
+
+        Ok(Some(secrets))
+    }
+
+    /// Catch shuttle dependencies which the platform will refuse to build with before going through an upload and a
+    /// remote build for it
+    fn check_shuttle_dependencies(&self, strict: bool) -> Result<()> {
+        let cargo_toml_path = self.ctx.working_directory().join("Cargo.toml");
+        let Ok(cargo_toml) = read_to_string(&cargo_toml_path) else {
+            trace!(path = %cargo_toml_path.display(), "no Cargo.toml to check the shuttle dependencies of");
+            return Ok(());
+        };
+
+        let supported = cargo_metadata::semver::Version::parse(shuttle_service::VERSION)?;
+        let outdated = dependencies::outdated_shuttle_dependencies(&cargo_toml, &supported)?;
+
+        if outdated.is_empty() {
+            return Ok(());
+        }
+
+        for dependency in &outdated {
+            note!(
+                "{}",
+                format!(
+                    "Cargo.toml has `{} = \"{}\"`, which does not match the {supported} platform this CLI deploys to. Update it to \"{supported}\".",
+                    dependency.name, dependency.requirement
+                )
+                .yellow()
+            );
+        }
+
+        if strict {
+            bail!(
+                "the shuttle dependencies of this crate do not match the version of the platform"
+            );
+        }
+
+        note!(
+            "{}",
+            "The build will likely fail on the platform. Pass `--strict` to stop before deploying instead.".yellow()
+        );
+
+        Ok(())
+    }
+
+    /// Go through the steps of a deploy which happen on this machine, or that the deployer repeats, without calling the
+    /// API. `Shuttle.toml` has already been read by the time this runs, so a broken one never gets here.
+    async fn check(&self, args: CheckArgs) -> Result<CommandOutcome> {
+        let working_directory = self.ctx.working_directory();
+
+        println!(
+            "{:>12} {}",
+            "Checking".bold().green(),
+            self.ctx.project_name()
+        );
+
+        self.check_shuttle_dependencies(args.strict)?;
+
+        let (data, _) = self.package(args.allow_dirty, args.package_root, None)?;
+        note!("{:>12} {} bytes", "Packaged".bold().green(), data.len());
+
+        // The deployer builds and tests in release mode, and finds out whether the crate is a service in the build
+        build_crate(
+            Default::default(),
+            working_directory,
+            true,
+            !args.no_locked,
+            print_cargo_messages(),
+        )
+        .await
+        .context("the service does not build")?;
+        note!(
+            "{:>12} {}",
+            "Built".bold().green(),
+            working_directory.display()
+        );
+
+        if !args.no_test {
+            let cargo = std::env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+            let mut test = std::process::Command::new(cargo);
+            test.args(["test", "--release"])
+                .current_dir(working_directory);
+
+            if !args.no_locked {
+                test.arg("--locked");
+            }
+            if output::quiet() {
+                test.arg("--quiet");
+            }
+
+            if !test.status().context("failed to run cargo test")?.success() {
+                println!("{}", "The tests which run before deploying failed".red());
+
+                return Ok(CommandOutcome::DeploymentFailure);
+            }
+        }
+
+        println!("{}", "Ready to deploy".green());
+
+        Ok(CommandOutcome::Ok)
+    }
+
+    /// Upload the archive in chunks, resuming from where the deployer got to when a chunk fails. Deployers which do not
+    /// support resumable uploads get the whole archive in one go instead.
+    async fn upload_and_deploy(
+        &self,
+        client: &Client,
+        data: Vec<u8>,
+        options: &DeployOptions<'_>,
+    ) -> Result<deployment::Response> {
+        let project = self.ctx.project_name();
+
+        let total = data.len() as u64;
+
+        let Some(upload) = client.start_upload(project, total).await? else {
+            trace!("resumable upload not available, sending the archive at once");
+            return client.deploy(data, project, options).await;
+        };
+
+        let progress_bar = create_upload_progress_bar(total);
+        let mut offset = upload.offset;
+        let mut attempts = 0;
+
+        while offset < total {
+            let end = (offset + UPLOAD_CHUNK_SIZE).min(total);
+            let chunk = data[offset as usize..end as usize].to_vec();
+
+            match client
+                .upload_chunk(project, &upload.id, offset, chunk)
+                .await
+            {
+                Ok(status) => {
+                    offset = status.offset;
+                    attempts = 0;
+                }
+                Err(error) if attempts < MAX_UPLOAD_ATTEMPTS => {
+                    attempts += 1;
+                    progress_bar.suspend(|| note!("Upload interrupted ({error}), resuming"));
+                    tokio::time::sleep(UPLOAD_RETRY_DELAY * attempts).await;
+
+                    // Part of the chunk may have arrived even if the response to it did not
+                    if let Ok(status) = client.get_upload(project, &upload.id).await {
+                        offset = status.offset;
+                    }
+                }
+                Err(error) => {
+                    progress_bar.abandon();
+                    return Err(error).context("failed to upload the deployment archive");
                 }
             }
+
+            progress_bar.set_position(offset);
+        }
+
+        progress_bar.finish_and_clear();
+
+        client.deploy_upload(project, &upload.id, options).await
+    }
+
+    async fn deploy(
+        &self,
+        args: DeployArgs,
+        client: &Client,
+        output_format: OutputFormat,
+    ) -> Result<CommandOutcome> {
+        self.check_protocol_version(client).await;
+
+        if args.image.is_none() {
+            self.check_shuttle_dependencies(args.strict)?;
+        }
+
+        let secrets_file = args.secrets.clone().or_else(|| self.ctx.secrets_file());
+        let secrets = self.deploy_secrets(secrets_file.as_deref(), &args.secret)?;
+
+        // A pre-built image has nothing to package
+        let (data, crate_path) = match args.image {
+            Some(_) => (Vec::new(), None),
+            None => self.package(args.allow_dirty, args.package_root, secrets.as_ref())?,
+        };
+
+        if let Some(environment) = self.ctx.environment() {
+            note!("Using the `[env.{environment}]` settings of Shuttle.toml");
+        }
+
+        if args.cancel_previous {
+            self.cancel_unbuilt_deployments(client).await?;
         }
 
+        let options = DeployOptions {
+            no_test: args.no_test,
+            pre_stop: args.pre_stop.as_deref().or(self.ctx.pre_stop_hook()),
+            tag_latest: args.tag_latest,
+            crate_path: crate_path.as_deref(),
+            no_locked: args.no_locked,
+            image: args.image.as_deref(),
+            run_budget: args.run_budget.or(self.ctx.run_budget()),
+            ttl: args.ttl,
+        };
+        let mut retries = 0;
+        let (deployment, last_timestamp) = loop {
+            let deployment = match options.image {
+                Some(_) => {
+                    client
+                        .deploy(data.clone(), self.ctx.project_name(), &options)
+                        .await?
+                }
+                None => {
+                    self.upload_and_deploy(client, data.clone(), &options)
+                        .await?
+                }
+            };
+
+            match self.watch_deployment(client, &deployment.id).await? {
+                DeployAttempt::Done { last_timestamp } => break (deployment, last_timestamp),
+                DeployAttempt::Failed { retryable: true } if retries < args.retry_on_failure => {
+                    retries += 1;
+                    let delay = deploy_retry_delay(retries);
+
+                    status!();
+                    status!(
+                        "{}",
+                        format!(
+                            "Deployment failed on the platform's side, retrying in {}s ({retries}/{})",
+                            delay.as_secs(),
+                            args.retry_on_failure
+                        )
+                        .yellow()
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                DeployAttempt::Failed { retryable } => {
+                    if args.retry_on_failure > 0 {
+                        if retryable {
+                            status!("Giving up after {retries} retries");
+                        } else {
+                            status!(
+                                "Not deploying again, since another try would fail the same way"
+                            );
+                        }
+                    }
+
+                    return Ok(CommandOutcome::DeploymentFailure);
+                }
+            }
+        };
+
+        self.print_deployment_summary(client, &deployment.id).await;
+
         let service = client.get_service_summary(self.ctx.project_name()).await?;
 
         // A deployment will only exist if there is currently one in the running state
         if let Some(ref new_deployment) = service.deployment {
-            println!("{service}");
+            if output::raw() {
+                status!(
+                    "Deployment {} is {}",
+                    new_deployment.id,
+                    new_deployment.state
+                );
+            } else {
+                println!("{service}");
+            }
 
-            Ok(match new_deployment.state {
-                shuttle_common::deployment::State::Crashed => CommandOutcome::DeploymentFailure,
-                _ => CommandOutcome::Ok,
-            })
+            if new_deployment.state == shuttle_common::deployment::State::Crashed {
+                return Ok(CommandOutcome::DeploymentFailure);
+            }
+
+            if let Some(path) = args.write_manifest {
+                let config = DeployConfig {
+                    environment: self.ctx.environment().map(ToString::to_string),
+                    pre_stop: options.pre_stop.map(ToString::to_string),
+                    no_test: options.no_test,
+                    tag_latest: options.tag_latest,
+                    crate_path: options.crate_path.map(ToString::to_string),
+                    no_locked: options.no_locked,
+                };
+                Manifest::new(
+                    &service,
+                    new_deployment,
+                    self.ctx.working_directory(),
+                    config,
+                )?
+                .write(&path)?;
+
+                note!("Recorded the deployment in {}", path.display());
+            }
+
+            if args.follow {
+                // Going on from the last line seen, the lines of the service logged meanwhile are not missed
+                let after = last_timestamp.filter(|_| args.tail.is_none());
+
+                self.follow_logs(client, &deployment.id, args.tail, after, |log| {
+                    if output::raw() {
+                        print_raw_log(log, output_format);
+                    } else {
+                        print_log(log, None, false);
+                    }
+                })
+                .await?;
+            }
+
+            Ok(CommandOutcome::Ok)
         } else {
-            println!("Deployment has not entered the running state");
+            status!("Deployment has not entered the running state");
 
             Ok(CommandOutcome::DeploymentFailure)
         }
     }
 
+    /// Follow the progress of a new deployment until it is running or has failed
+    async fn watch_deployment(
+        &self,
+        client: &Client,
+        deployment_id: &Uuid,
+    ) -> Result<DeployAttempt> {
+        let mut stream = client
+            .get_logs_ws(self.ctx.project_name(), deployment_id, None, None)
+            .await?;
+
+        let mut build_progress: Option<ProgressBar> = None;
+        let mut last_timestamp = None;
+
+        while let Some(Ok(msg)) = stream.next().await {
+            if let tokio_tungstenite::tungstenite::Message::Text(line) = msg {
+                let log_item: shuttle_common::LogItem =
+                    serde_json::from_str(&line).expect("to parse log line");
+                last_timestamp = Some(log_item.timestamp);
+
+                if let Some(progress) = log_item.build_progress() {
+                    let bar = build_progress.get_or_insert_with(create_build_progress_bar);
+                    bar.set_position(progress.percentage().into());
+                    bar.set_message(format!(
+                        "{}/{} crates compiled",
+                        progress.compiled, progress.total
+                    ));
+
+                    continue;
+                }
+
+                if !matches!(log_item.state, shuttle_common::deployment::State::Building) {
+                    if let Some(bar) = build_progress.take() {
+                        bar.finish_and_clear();
+                    }
+                }
+
+                match log_item.state {
+                    shuttle_common::deployment::State::Queued
+                    | shuttle_common::deployment::State::Building
+                    | shuttle_common::deployment::State::Built
+                    | shuttle_common::deployment::State::Loading => match &build_progress {
+                        Some(bar) => bar.suspend(|| note!("{log_item}")),
+                        None => note!("{log_item}"),
+                    },
+                    shuttle_common::deployment::State::Crashed => {
+                        status!();
+                        status!("{}", "Deployment crashed".red());
+                        let summary = self.print_deployment_summary(client, deployment_id).await;
+                        status!("Run the following for more details");
+                        status!();
+                        status!("cargo shuttle logs {}", deployment_id);
+
+                        return Ok(DeployAttempt::Failed {
+                            retryable: summary
+                                .and_then(|summary| summary.retryable)
+                                .unwrap_or_default(),
+                        });
+                    }
+                    shuttle_common::deployment::State::Cancelled => {
+                        status!();
+                        status!("{}", "Deployment was cancelled".yellow());
+
+                        return Ok(DeployAttempt::Failed { retryable: false });
+                    }
+                    shuttle_common::deployment::State::Running
+                    | shuttle_common::deployment::State::Completed
+                    | shuttle_common::deployment::State::Stopped
+                    | shuttle_common::deployment::State::BudgetExceeded
+                    | shuttle_common::deployment::State::Expired
+                    | shuttle_common::deployment::State::Unknown => break,
+                }
+            }
+        }
+
+        Ok(DeployAttempt::Done { last_timestamp })
+    }
+
+    /// Print the outcome of a deployment in one go, and hand it back. Deployers which predate summaries are skipped
+    /// over, and `--raw` leaves the table out.
+    async fn print_deployment_summary(
+        &self,
+        client: &Client,
+        deployment_id: &Uuid,
+    ) -> Option<deployment::Summary> {
+        match client
+            .get_deployment_summary(self.ctx.project_name(), deployment_id)
+            .await
+        {
+            Ok(summary) => {
+                if !output::raw() {
+                    println!();
+                    print!("{summary}");
+                    println!();
+                }
+
+                Some(summary)
+            }
+            Err(error) => {
+                trace!(?error, "could not get the deployment summary");
+
+                None
+            }
+        }
+    }
+
+    async fn verify(&self, client: &Client, manifest_path: &Path) -> Result<()> {
+        let manifest = Manifest::read(manifest_path)?;
+        let service = client.get_service_summary(&manifest.project).await?;
+        let drift = manifest.drift(&service);
+
+        if !drift.is_empty() {
+            for difference in &drift {
+                println!("{} {difference}", "-".red());
+            }
+
+            bail!(
+                "{} does not match what is deployed for {}",
+                manifest_path.display(),
+                manifest.project
+            );
+        }
+
+        println!(
+            "Deployment {} of {} matches {}",
+            manifest.deployment_id,
+            manifest.project,
+            manifest_path.display()
+        );
+
+        Ok(())
+    }
+
     async fn project_create(&self, client: &Client, idle_minutes: u64) -> Result<()> {
         let config = project::Config { idle_minutes };
 
@@ -590,6 +1736,14 @@ impl Shuttle {
         Ok(())
     }
 
+    async fn project_history(&self, client: &Client) -> Result<()> {
+        let events = client.get_project_events(self.ctx.project_name()).await?;
+
+        print!("{}", project::get_history(&events));
+
+        Ok(())
+    }
+
     async fn project_status(&self, client: &Client, follow: bool) -> Result<()> {
         match follow {
             true => {
@@ -616,6 +1770,257 @@ impl Shuttle {
         Ok(())
     }
 
+    async fn account_usage(&self, client: &Client, output_format: OutputFormat) -> Result<()> {
+        let usage = client.get_account_usage().await?;
+
+        match output_format {
+            OutputFormat::Text => {
+                print!("{}", usage::get_usage_table(&usage));
+
+                if usage.projects.is_exhausted() {
+                    println!(
+                        "{}",
+                        "The account cannot create any more projects until one is deleted".yellow()
+                    );
+                }
+            }
+            OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&usage)?),
+        }
+
+        Ok(())
+    }
+
+    async fn project_stats(&self, client: &Client) -> Result<()> {
+        let traffic = client.get_project_traffic(self.ctx.project_name()).await?;
+
+        let table = stats::get_traffic_table(&traffic);
+
+        println!("{table}");
+
+        Ok(())
+    }
+
+    async fn project_pin(&self, client: &Client, pin: bool) -> Result<()> {
+        let project_name = self.ctx.project_name();
+
+        if pin {
+            client.pin_project(project_name).await?;
+            println!("Project {project_name} is pinned and will not idle until it is unpinned");
+        } else {
+            client.unpin_project(project_name).await?;
+            println!("Project {project_name} is unpinned and idles by its idle timer again");
+        }
+
+        Ok(())
+    }
+
+    async fn project_transfer(
+        &self,
+        client: &Client,
+        to_account: Option<String>,
+        accept: bool,
+        cancel: bool,
+    ) -> Result<()> {
+        let project_name = self.ctx.project_name();
+
+        match (to_account, accept, cancel) {
+            (Some(to_account), _, _) => {
+                let transfer = client.transfer_project(project_name, to_account).await?;
+                println!(
+                    "Project {project_name} is offered to {}, it moves over once they run `cargo shuttle project transfer --accept --name {project_name}`",
+                    transfer.to_account
+                );
+            }
+            (_, true, _) => {
+                client.accept_transfer(project_name).await?;
+                println!("Project {project_name} now belongs to this account");
+            }
+            (_, _, true) => {
+                let transfers = client.cancel_transfer(project_name).await?;
+                println!("The transfer of project {project_name} is called off");
+                print!("{}", project::get_transfers_table(&transfers));
+            }
+            _ => {
+                let transfers = client.get_transfers().await?;
+                print!("{}", project::get_transfers_table(&transfers));
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn project_host_header(
+        &self,
+        client: &Client,
+        preserve: bool,
+        backend: bool,
+        fixed: Option<String>,
+    ) -> Result<()> {
+        let policy = match (preserve, backend, fixed) {
+            (true, _, _) => Some(host_header::Policy::Preserve),
+            (_, true, _) => Some(host_header::Policy::Backend),
+            (_, _, Some(host)) => Some(host_header::Policy::Fixed(host)),
+            _ => None,
+        };
+
+        let policy = match policy {
+            Some(policy) => {
+                client
+                    .set_host_header(self.ctx.project_name(), policy)
+                    .await?
+            }
+            None => client.get_host_header(self.ctx.project_name()).await?,
+        };
+
+        println!("Host header: {policy}");
+
+        Ok(())
+    }
+
+    async fn project_basic_auth(
+        &self,
+        client: &Client,
+        username: Option<String>,
+        password: Option<String>,
+        exempt: Vec<String>,
+        remove: bool,
+    ) -> Result<()> {
+        let basic_auth = match username {
+            Some(username) => {
+                let password = match password {
+                    Some(password) => password,
+                    None => Password::with_theme(&ColorfulTheme::default())
+                        .with_prompt(format!("Password for {username}"))
+                        .with_confirmation("Repeat the password", "The passwords do not match")
+                        .interact()?,
+                };
+                let config = basic_auth::Config {
+                    username,
+                    password,
+                    exempt,
+                };
+
+                client
+                    .set_basic_auth(self.ctx.project_name(), config)
+                    .await?
+            }
+            None if remove => client.remove_basic_auth(self.ctx.project_name()).await?,
+            None => client.get_basic_auth(self.ctx.project_name()).await?,
+        };
+
+        println!("Basic auth: {basic_auth}");
+
+        Ok(())
+    }
+
+    async fn project_timeouts(
+        &self,
+        client: &Client,
+        response: Option<u64>,
+        body_idle: Option<u64>,
+        abort_stalled: bool,
+        slow_request: Option<u64>,
+        reset: bool,
+    ) -> Result<()> {
+        let mut config = client.get_proxy_timeouts(self.ctx.project_name()).await?;
+        let current = config.clone();
+
+        // Clears the slow request threshold of the project too, so that the one of the platform applies again
+        if reset {
+            config = timeouts::Config::default();
+        }
+
+        if let Some(secs) = response {
+            config.response_secs = Some(secs);
+        }
+
+        if let Some(idle_secs) = body_idle {
+            config.stalled_body = timeouts::StalledBody::Wait { idle_secs };
+        } else if abort_stalled {
+            config.stalled_body = timeouts::StalledBody::Abort;
+        }
+
+        if let Some(ms) = slow_request {
+            config.slow_request_ms = Some(ms);
+        }
+
+        if config != current {
+            config = client
+                .set_proxy_timeouts(self.ctx.project_name(), config)
+                .await?;
+        }
+
+        println!("Timeouts: {config}");
+
+        Ok(())
+    }
+
+    /// Change the header limits of the project by the limits which are set in `changes`
+    async fn project_header_limits(
+        &self,
+        client: &Client,
+        changes: header_limits::Config,
+        reset: bool,
+    ) -> Result<()> {
+        let mut config = client.get_header_limits(self.ctx.project_name()).await?;
+        let current = config.clone();
+
+        if reset {
+            config = header_limits::Config::default();
+        }
+
+        config = changes.or(&config);
+
+        if config != current {
+            config = client
+                .set_header_limits(self.ctx.project_name(), config)
+                .await?;
+        }
+
+        println!("Header limits: {config}");
+
+        Ok(())
+    }
+
+    async fn project_response_headers(
+        &self,
+        client: &Client,
+        set: Vec<(String, String)>,
+        remove: Vec<String>,
+        allow: Vec<String>,
+    ) -> Result<()> {
+        let mut rules = client.get_response_headers(self.ctx.project_name()).await?;
+        let current = rules.clone();
+
+        for (name, value) in set {
+            rules.insert(name, response_header::Rule::Set(value));
+        }
+
+        for name in remove {
+            rules.insert(name.to_lowercase(), response_header::Rule::Remove);
+        }
+
+        for name in allow {
+            rules.remove(&name.to_lowercase());
+        }
+
+        if rules != current {
+            rules = client
+                .set_response_headers(self.ctx.project_name(), rules)
+                .await?;
+        }
+
+        if rules.is_empty() {
+            println!("Response headers are sent as the service gives them");
+        }
+
+        for (name, rule) in rules {
+            println!("{name}: {rule}");
+        }
+
+        Ok(())
+    }
+
     async fn wait_with_spinner<'a, Fut>(
         &self,
         states_to_check: &[project::State],
@@ -659,23 +2064,50 @@ impl Shuttle {
         Ok(())
     }
 
-    fn make_archive(&self) -> Result<Vec<u8>> {
+    /// Where the crate is inside `package_root`, as a `/` separated path. `None` when the root is the crate itself.
+    fn crate_path(&self, package_root: &Path) -> Result<Option<String>> {
+        let crate_path = self
+            .ctx
+            .working_directory()
+            .strip_prefix(package_root)
+            .with_context(|| {
+                format!(
+                    "the package root {} does not contain the crate",
+                    package_root.display()
+                )
+            })?;
+
+        let parts: Vec<_> = crate_path
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect();
+
+        Ok((!parts.is_empty()).then(|| parts.join("/")))
+    }
+
+    /// Package the crate, or all of `package_root` when given, into an archive
+    fn make_archive(
+        &self,
+        package_root: Option<&Path>,
+        secrets: Option<&BTreeMap<String, String>>,
+    ) -> Result<Vec<u8>> {
         let encoder = GzEncoder::new(Vec::new(), Compression::fast());
         let mut tar = Builder::new(encoder);
 
         let working_directory = self.ctx.working_directory();
-        let base_directory = working_directory
+        let package_root = package_root.unwrap_or(working_directory);
+        let base_directory = package_root
             .parent()
-            .context("get parent directory of crate")?;
+            .context("get parent directory of package root")?;
 
         // Make sure the target folder is excluded at all times
-        let overrides = OverrideBuilder::new(working_directory)
+        let overrides = OverrideBuilder::new(package_root)
             .add("!target/")
             .context("add `!target/` override")?
             .build()
             .context("build an override")?;
 
-        for dir_entry in WalkBuilder::new(working_directory)
+        for dir_entry in WalkBuilder::new(package_root)
             .hidden(false)
             .overrides(overrides)
             .build()
@@ -696,10 +2128,25 @@ impl Shuttle {
                 .context("archive entry")?;
         }
 
-        // Make sure to add any `Secrets.toml` files
-        let secrets_path = self.ctx.working_directory().join("Secrets.toml");
-        if secrets_path.exists() {
-            tar.append_path_with_name(secrets_path, Path::new("shuttle").join("Secrets.toml"))?;
+        // Make sure to add any `Secrets.toml` files, or the secrets given on the command line in their place
+        let crate_path = working_directory
+            .strip_prefix(package_root)
+            .context("strip the package root from the crate")?;
+        let archived_secrets_path = Path::new("shuttle").join(crate_path).join("Secrets.toml");
+        let secrets_path = working_directory.join("Secrets.toml");
+
+        if let Some(secrets) = secrets {
+            let contents = toml::to_string(secrets).context("serialize secrets")?;
+
+            let mut header = Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o600);
+            header.set_cksum();
+
+            tar.append_data(&mut header, archived_secrets_path, contents.as_bytes())
+                .context("archive secrets")?;
+        } else if secrets_path.exists() {
+            tar.append_path_with_name(secrets_path, archived_secrets_path)?;
         }
 
         let encoder = tar.into_inner().context("get encoder from tar archive")?;
@@ -708,8 +2155,8 @@ impl Shuttle {
         Ok(bytes)
     }
 
-    fn is_dirty(&self) -> Result<()> {
-        let working_directory = self.ctx.working_directory();
+    /// Check for uncommitted changes in `working_directory`, which is the crate or the package root above it
+    fn is_dirty(&self, working_directory: &Path) -> Result<()> {
         if let Ok(repo) = Repository::discover(working_directory) {
             let repo_path = dunce::canonicalize(
                 repo.workdir()
@@ -768,7 +2215,151 @@ impl Shuttle {
     }
 }
 
+/// Print a log line, unless it was not printed to `only`
+fn print_log(log: &shuttle_common::LogItem, only: Option<Stream>, show_stream: bool) {
+    if !is_shown(log, only) {
+        return;
+    }
+
+    match log.stream() {
+        Some(stream) if show_stream => {
+            let tag = format!("[{stream}]");
+            let tag = match stream {
+                Stream::Stdout => tag.dim(),
+                Stream::Stderr => tag.red(),
+            };
+
+            println!("{tag} {log}");
+        }
+        _ => println!("{log}"),
+    }
+}
+
+/// Whether a log line was printed to `only`. Lines which were logged rather than printed are on no stream at all.
+fn is_shown(log: &shuttle_common::LogItem, only: Option<Stream>) -> bool {
+    only.map_or(true, |only| log.stream() == Some(only))
+}
+
+/// Print only what the service logged, for `deploy --raw`. Build logs and the lines marking new states are left to
+/// the CLI, which prints them to stderr while deploying.
+fn print_raw_log(log: &shuttle_common::LogItem, output_format: OutputFormat) {
+    if log.state != shuttle_common::deployment::State::Running {
+        return;
+    }
+
+    let Some(message) = log.message() else {
+        return;
+    };
+
+    match output_format {
+        OutputFormat::Text => println!("{message}"),
+        OutputFormat::Json => println!(
+            "{}",
+            serde_json::to_string(log).expect("a log line to serialize")
+        ),
+    }
+}
+
+/// Block until a debugger is attached to this process, or until Enter is pressed
+async fn wait_for_debugger() -> Result<()> {
+    println!(
+        "{:>12} for a debugger to attach to process {}, press Enter to go on without one",
+        "Waiting".bold().yellow(),
+        std::process::id()
+    );
+
+    // Reading stdin cannot be stopped, so it goes on a thread of its own which is left behind once a debugger attaches.
+    // A blocking task left reading would keep the runtime from shutting down when the service exits.
+    let (enter_send, enter) = tokio::sync::oneshot::channel();
+    std::thread::spawn(move || {
+        let mut line = String::new();
+        let _ = enter_send.send(stdin().read_line(&mut line).map(|_| ()));
+    });
+
+    tokio::select! {
+        result = enter => result.expect("the stdin thread to send whether Enter was pressed")?,
+        _ = debugger_attached() => println!("{:>12}", "Attached".bold().green()),
+    }
+
+    Ok(())
+}
+
+/// Resolves once a tracer is attached to this process. Linux tells through procfs, other platforms have no such way
+/// and only go on with Enter
+#[cfg(target_os = "linux")]
+async fn debugger_attached() {
+    loop {
+        let traced = read_to_string("/proc/self/status").map_or(false, |status| {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix("TracerPid:"))
+                .map_or(false, |pid| pid.trim() != "0")
+        });
+
+        if traced {
+            return;
+        }
+
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn debugger_attached() {
+    futures::future::pending().await
+}
+
+/// How long to wait before connecting to the log stream again, doubling with every attempt
+fn reconnect_delay(attempts: u32) -> std::time::Duration {
+    RECONNECT_DELAY
+        .saturating_mul(2u32.saturating_pow(attempts.saturating_sub(1)))
+        .min(MAX_RECONNECT_DELAY)
+}
+
+/// How long to wait before deploying again after a failure of the platform, doubling with every retry
+fn deploy_retry_delay(retries: u32) -> std::time::Duration {
+    DEPLOY_RETRY_DELAY
+        .saturating_mul(2u32.saturating_pow(retries.saturating_sub(1)))
+        .min(MAX_DEPLOY_RETRY_DELAY)
+}
+
+fn create_upload_progress_bar(total: u64) -> ProgressBar {
+    if !output::progress() {
+        return ProgressBar::hidden();
+    }
+
+    let pb = indicatif::ProgressBar::new(total);
+    pb.set_style(
+        indicatif::ProgressStyle::with_template(
+            "Uploading {bar:40.orange} {bytes}/{total_bytes} ({bytes_per_sec})",
+        )
+        .unwrap()
+        .progress_chars("=> "),
+    );
+
+    pb
+}
+
+fn create_build_progress_bar() -> ProgressBar {
+    if !output::progress() {
+        return ProgressBar::hidden();
+    }
+
+    let pb = indicatif::ProgressBar::new(100);
+    pb.set_style(
+        indicatif::ProgressStyle::with_template("{bar:40.orange} {pos:>3}% {msg}")
+            .unwrap()
+            .progress_chars("=> "),
+    );
+
+    pb
+}
+
 fn create_spinner() -> ProgressBar {
+    if !output::progress() {
+        return ProgressBar::hidden();
+    }
+
     let pb = indicatif::ProgressBar::new_spinner();
     pb.enable_steady_tick(std::time::Duration::from_millis(350));
     pb.set_style(
@@ -792,11 +2383,43 @@ fn create_spinner() -> ProgressBar {
     pb
 }
 
+/// Print what cargo says while building a crate as it comes in
+fn print_cargo_messages() -> crossbeam_channel::Sender<Message> {
+    let (tx, rx): (crossbeam_channel::Sender<Message>, _) = crossbeam_channel::bounded(0);
+
+    tokio::task::spawn_blocking(move || {
+        while let Ok(message) = rx.recv() {
+            match message {
+                Message::TextLine(line) => println!("{line}"),
+                Message::CompilerMessage(message) => {
+                    if let Some(rendered) = message.message.rendered {
+                        println!("{rendered}");
+                    }
+                }
+                _ => {}
+            }
+        }
+    });
+
+    tx
+}
+
 pub enum CommandOutcome {
     Ok,
     DeploymentFailure,
 }
 
+/// How far one try at a deployment got
+enum DeployAttempt {
+    /// It is running, or went past running already, with the time of the last log line seen
+    Done {
+        last_timestamp: Option<DateTime<Utc>>,
+    },
+    Failed {
+        retryable: bool,
+    },
+}
+
 #[cfg(test)]
 mod tests {
     use dunce::canonicalize;
@@ -806,9 +2429,11 @@ mod tests {
     use tempfile::TempDir;
 
     use crate::args::ProjectArgs;
-    use crate::Shuttle;
+    use crate::{deploy_retry_delay, reconnect_delay, Shuttle};
+    use std::collections::BTreeMap;
     use std::fs;
-    use std::path::PathBuf;
+    use std::io::Read;
+    use std::path::{Path, PathBuf};
     use std::str::FromStr;
 
     fn path_from_workspace_root(path: &str) -> PathBuf {
@@ -817,11 +2442,14 @@ mod tests {
             .join(path)
     }
 
-    fn get_archive_entries(mut project_args: ProjectArgs) -> Vec<String> {
+    fn get_archive_entries(
+        mut project_args: ProjectArgs,
+        package_root: Option<&Path>,
+    ) -> Vec<String> {
         let mut shuttle = Shuttle::new().unwrap();
         shuttle.load_project(&mut project_args).unwrap();
 
-        let archive = shuttle.make_archive().unwrap();
+        let archive = shuttle.make_archive(package_root, None).unwrap();
 
         // Make sure the Secrets.toml file is not initially present
         let tar = GzDecoder::new(&archive[..]);
@@ -844,6 +2472,21 @@ mod tests {
             .collect()
     }
 
+    #[test]
+    fn reconnect_delay_backs_off() {
+        assert_eq!(reconnect_delay(1), std::time::Duration::from_secs(1));
+        assert_eq!(reconnect_delay(3), std::time::Duration::from_secs(4));
+        assert_eq!(reconnect_delay(6), std::time::Duration::from_secs(30));
+        assert_eq!(reconnect_delay(40), std::time::Duration::from_secs(30));
+    }
+
+    #[test]
+    fn deploy_retry_delay_backs_off() {
+        assert_eq!(deploy_retry_delay(1), std::time::Duration::from_secs(10));
+        assert_eq!(deploy_retry_delay(2), std::time::Duration::from_secs(20));
+        assert_eq!(deploy_retry_delay(5), std::time::Duration::from_secs(120));
+    }
+
     #[test]
     fn find_root_directory_returns_proper_directory() {
         let working_directory = path_from_workspace_root("examples/axum/hello-world/src");
@@ -861,6 +2504,7 @@ mod tests {
         let mut project_args = ProjectArgs {
             working_directory: path_from_workspace_root("examples/axum/hello-world/src"),
             name: None,
+            environment: None,
         };
 
         let mut shuttle = Shuttle::new().unwrap();
@@ -886,9 +2530,10 @@ mod tests {
         let project_args = ProjectArgs {
             working_directory,
             name: None,
+            environment: None,
         };
 
-        let mut entries = get_archive_entries(project_args);
+        let mut entries = get_archive_entries(project_args, None);
         entries.sort();
 
         assert_eq!(
@@ -905,6 +2550,96 @@ mod tests {
         );
     }
 
+    #[test]
+    fn make_archive_given_secrets() {
+        let tmp_dir = TempDir::new().unwrap();
+        let working_directory = tmp_dir.path();
+
+        fs::write(working_directory.join("Cargo.toml"), "[package]").unwrap();
+        fs::write(
+            working_directory.join("Secrets.toml"),
+            "API_KEY = 'dev'\nDATABASE = 'local'",
+        )
+        .unwrap();
+        fs::write(
+            working_directory.join("Secrets.prod.toml"),
+            "API_KEY = 'prod'",
+        )
+        .unwrap();
+        fs::write(working_directory.join(".ignore"), "Secrets*.toml").unwrap();
+
+        let mut project_args = ProjectArgs {
+            working_directory: working_directory.to_path_buf(),
+            name: Some(ProjectName::from_str("secrets").unwrap()),
+            environment: None,
+        };
+        let mut shuttle = Shuttle::new().unwrap();
+        shuttle.load_project(&mut project_args).unwrap();
+
+        let archived_secrets = |secrets: Option<&BTreeMap<String, String>>| {
+            let archive = shuttle.make_archive(None, secrets).unwrap();
+            let mut archive = Archive::new(GzDecoder::new(&archive[..]));
+
+            let mut secrets = archive
+                .entries()
+                .unwrap()
+                .map(Result::unwrap)
+                .filter(|entry| entry.path().unwrap().ends_with("Secrets.toml"))
+                .map(|mut entry| {
+                    let mut contents = String::new();
+                    entry.read_to_string(&mut contents).unwrap();
+                    toml::from_str::<BTreeMap<String, String>>(&contents).unwrap()
+                });
+            let archived = secrets.next();
+            assert!(
+                secrets.next().is_none(),
+                "only one secrets file is archived"
+            );
+
+            archived
+        };
+
+        // Without any given, the `Secrets.toml` of the crate is archived as it is
+        assert_eq!(shuttle.deploy_secrets(None, &[]).unwrap(), None);
+        assert_eq!(
+            archived_secrets(None).unwrap(),
+            BTreeMap::from([
+                ("API_KEY".to_string(), "dev".to_string()),
+                ("DATABASE".to_string(), "local".to_string()),
+            ])
+        );
+
+        let secrets = shuttle
+            .deploy_secrets(None, &[("DATABASE".to_string(), "remote".to_string())])
+            .unwrap();
+        assert_eq!(
+            archived_secrets(secrets.as_ref()).unwrap(),
+            BTreeMap::from([
+                ("API_KEY".to_string(), "dev".to_string()),
+                ("DATABASE".to_string(), "remote".to_string()),
+            ])
+        );
+
+        // A secrets file replaces the one of the crate
+        let secrets = shuttle
+            .deploy_secrets(
+                Some(&working_directory.join("Secrets.prod.toml")),
+                &[("EXTRA".to_string(), "value".to_string())],
+            )
+            .unwrap();
+        assert_eq!(
+            archived_secrets(secrets.as_ref()).unwrap(),
+            BTreeMap::from([
+                ("API_KEY".to_string(), "prod".to_string()),
+                ("EXTRA".to_string(), "value".to_string()),
+            ])
+        );
+
+        assert!(shuttle
+            .deploy_secrets(Some(&working_directory.join("Missing.toml")), &[])
+            .is_err());
+    }
+
     #[test]
     fn make_archive_respect_ignore() {
         let tmp_dir = TempDir::new().unwrap();
@@ -917,9 +2652,10 @@ mod tests {
         let project_args = ProjectArgs {
             working_directory: working_directory.to_path_buf(),
             name: Some(ProjectName::from_str("secret").unwrap()),
+            environment: None,
         };
 
-        let mut entries = get_archive_entries(project_args);
+        let mut entries = get_archive_entries(project_args, None);
         entries.sort();
 
         assert_eq!(entries, vec![".ignore", "Cargo.toml"]);
@@ -937,11 +2673,49 @@ mod tests {
         let project_args = ProjectArgs {
             working_directory: working_directory.to_path_buf(),
             name: Some(ProjectName::from_str("exclude_target").unwrap()),
+            environment: None,
         };
 
-        let mut entries = get_archive_entries(project_args);
+        let mut entries = get_archive_entries(project_args, None);
         entries.sort();
 
         assert_eq!(entries, vec!["Cargo.toml"]);
     }
+
+    #[test]
+    fn make_archive_package_root() {
+        let tmp_dir = TempDir::new().unwrap();
+        let package_root = tmp_dir.path();
+        let working_directory = package_root.join("services").join("api");
+
+        fs::create_dir_all(package_root.join("shared").join("src")).unwrap();
+        fs::write(package_root.join("shared").join("Cargo.toml"), "[package]").unwrap();
+        fs::write(package_root.join("shared").join("src").join("lib.rs"), "").unwrap();
+        fs::create_dir_all(package_root.join("target")).unwrap();
+        fs::write(package_root.join("target").join("binary"), "12345").unwrap();
+        fs::create_dir_all(&working_directory).unwrap();
+        fs::write(working_directory.join("Cargo.toml"), "[package]").unwrap();
+        fs::write(working_directory.join(".ignore"), "Secrets.toml").unwrap();
+        fs::write(working_directory.join("Secrets.toml"), "KEY = 'value'").unwrap();
+
+        let project_args = ProjectArgs {
+            working_directory,
+            name: Some(ProjectName::from_str("package-root").unwrap()),
+            environment: None,
+        };
+
+        let mut entries = get_archive_entries(project_args, Some(package_root));
+        entries.sort();
+
+        assert_eq!(
+            entries,
+            vec![
+                "services/api/.ignore",
+                "services/api/Cargo.toml",
+                "services/api/Secrets.toml",
+                "shared/Cargo.toml",
+                "shared/src/lib.rs",
+            ]
+        );
+    }
 }
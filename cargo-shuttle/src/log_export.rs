@@ -0,0 +1,229 @@
+//! The logs of a deployment written out in bulk by `cargo shuttle logs --export`, one line at a time so that long
+//! histories never have to be held in memory.
+
+use std::io::{self, Write};
+
+use serde::Serialize;
+use shuttle_common::{
+    log::{Level, Stream},
+    LogItem, STATE_MESSAGE,
+};
+
+use crate::args::ExportFormat;
+
+/// The fields every exported line has, whatever the format
+#[derive(Serialize)]
+struct ExportedLine {
+    timestamp: String,
+    level: Level,
+    /// Stream the line was printed to, when it was printed rather than logged
+    stream: Option<Stream>,
+    message: String,
+}
+
+impl From<&LogItem> for ExportedLine {
+    fn from(log: &LogItem) -> Self {
+        Self {
+            timestamp: log
+                .timestamp
+                .to_rfc3339_opts(chrono::SecondsFormat::Nanos, true),
+            level: log.level.clone(),
+            stream: log.stream(),
+            message: message(log),
+        }
+    }
+}
+
+pub struct LogExporter<W: Write> {
+    writer: W,
+    format: ExportFormat,
+    lines: u64,
+}
+
+impl<W: Write> LogExporter<W> {
+    pub fn new(writer: W, format: ExportFormat) -> Self {
+        Self {
+            writer,
+            format,
+            lines: 0,
+        }
+    }
+
+    pub fn write(&mut self, log: &LogItem) -> io::Result<()> {
+        let line = ExportedLine::from(log);
+
+        match self.format {
+            ExportFormat::Json => {
+                let separator = if self.lines == 0 { "[\n  " } else { ",\n  " };
+                write!(self.writer, "{separator}{}", to_json(&line)?)?;
+            }
+            ExportFormat::Ndjson => writeln!(self.writer, "{}", to_json(&line)?)?,
+            ExportFormat::Csv => {
+                if self.lines == 0 {
+                    writeln!(self.writer, "timestamp,level,stream,message")?;
+                }
+
+                let level = serde_json::to_value(&line.level)?;
+                let stream = line.stream.map(|stream| stream.to_string());
+                writeln!(
+                    self.writer,
+                    "{},{},{},{}",
+                    csv_field(&line.timestamp),
+                    csv_field(level.as_str().unwrap_or_default()),
+                    csv_field(stream.as_deref().unwrap_or_default()),
+                    csv_field(&line.message)
+                )?;
+            }
+        }
+
+        self.lines += 1;
+
+        Ok(())
+    }
+
+    /// Close off the export and flush it, returning how many lines it has
+    pub fn finish(mut self) -> io::Result<u64> {
+        match self.format {
+            ExportFormat::Json if self.lines == 0 => writeln!(self.writer, "[]")?,
+            ExportFormat::Json => writeln!(self.writer, "\n]")?,
+            ExportFormat::Csv if self.lines == 0 => {
+                writeln!(self.writer, "timestamp,level,stream,message")?
+            }
+            ExportFormat::Csv | ExportFormat::Ndjson => {}
+        }
+
+        self.writer.flush()?;
+
+        Ok(self.lines)
+    }
+}
+
+/// The message of a line, with the one `cargo shuttle logs` shows for lines marking a new state
+fn message(log: &LogItem) -> String {
+    if let Some(message) = log.message() {
+        return message;
+    }
+
+    match serde_json::from_slice(&log.fields) {
+        Ok(serde_json::Value::String(message)) if message == STATE_MESSAGE => {
+            format!("Entering {} state", log.state)
+        }
+        _ => String::new(),
+    }
+}
+
+fn to_json(line: &ExportedLine) -> io::Result<String> {
+    Ok(serde_json::to_string(line)?)
+}
+
+/// Quote a CSV field when it has to be, doubling the quotes in it
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+    use serde_json::json;
+    use shuttle_common::deployment::State;
+    use shuttle_common::log::{Level, STREAM_FIELD};
+    use shuttle_common::{LogItem, STATE_MESSAGE};
+    use uuid::Uuid;
+
+    use super::LogExporter;
+    use crate::args::ExportFormat;
+
+    fn logs() -> Vec<LogItem> {
+        let log = |second, state, level, fields: serde_json::Value| LogItem {
+            id: Uuid::nil(),
+            timestamp: Utc.with_ymd_and_hms(2023, 1, 1, 0, 0, second).unwrap(),
+            state,
+            level,
+            file: None,
+            line: None,
+            target: String::new(),
+            fields: serde_json::to_vec(&fields).unwrap(),
+        };
+
+        vec![
+            log(0, State::Running, Level::Info, json!(STATE_MESSAGE)),
+            log(
+                1,
+                State::Running,
+                Level::Error,
+                json!({ "message": "no \"spoon\", there is", STREAM_FIELD: "stderr" }),
+            ),
+        ]
+    }
+
+    fn export(format: ExportFormat, logs: &[LogItem]) -> String {
+        let mut output = Vec::new();
+        let mut exporter = LogExporter::new(&mut output, format);
+
+        for log in logs {
+            exporter.write(log).unwrap();
+        }
+        assert_eq!(exporter.finish().unwrap(), logs.len() as u64);
+
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn csv() {
+        assert_eq!(
+            export(ExportFormat::Csv, &logs()),
+            "timestamp,level,stream,message\n\
+             2023-01-01T00:00:00.000000000Z,info,,Entering running state\n\
+             2023-01-01T00:00:01.000000000Z,error,stderr,\"no \"\"spoon\"\", there is\"\n"
+        );
+        assert_eq!(
+            export(ExportFormat::Csv, &[]),
+            "timestamp,level,stream,message\n"
+        );
+    }
+
+    #[test]
+    fn json() {
+        let exported: serde_json::Value =
+            serde_json::from_str(&export(ExportFormat::Json, &logs())).unwrap();
+
+        assert_eq!(
+            exported,
+            json!([
+                {
+                    "timestamp": "2023-01-01T00:00:00.000000000Z",
+                    "level": "info",
+                    "stream": null,
+                    "message": "Entering running state",
+                },
+                {
+                    "timestamp": "2023-01-01T00:00:01.000000000Z",
+                    "level": "error",
+                    "stream": "stderr",
+                    "message": "no \"spoon\", there is",
+                },
+            ])
+        );
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&export(ExportFormat::Json, &[])).unwrap(),
+            json!([])
+        );
+    }
+
+    #[test]
+    fn ndjson() {
+        let exported = export(ExportFormat::Ndjson, &logs());
+        let lines: Vec<serde_json::Value> = exported
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1]["stream"], "stderr");
+        assert_eq!(lines[1]["level"], "error");
+    }
+}
@@ -1,12 +1,21 @@
 use anyhow::Result;
 use cargo_shuttle::{Args, CommandOutcome, Shuttle};
 use clap::Parser;
+use tracing_subscriber::EnvFilter;
 
 #[tokio::main(flavor = "multi_thread")]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
+    let args = Args::parse();
 
-    let result = Shuttle::new()?.run(Args::parse()).await;
+    // Logs go to stderr so that they do not get mixed into output which is piped, like JSON
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(args.log_filter())),
+        )
+        .with_writer(std::io::stderr)
+        .init();
+
+    let result = Shuttle::new()?.run(args).await;
 
     if matches!(result, Ok(CommandOutcome::DeploymentFailure)) {
         // Deployment failure results in a shell error exit code being returned (this allows
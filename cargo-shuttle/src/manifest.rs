@@ -0,0 +1,198 @@
+//! The record of a deployment written by `cargo shuttle deploy --write-manifest`, meant to be checked in next to the
+//! service and compared against what is running with `cargo shuttle verify`.
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use git2::Repository;
+use serde::{Deserialize, Serialize};
+use shuttle_common::models::{deployment, service};
+use shuttle_common::project::ProjectName;
+use uuid::Uuid;
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct Manifest {
+    pub project: ProjectName,
+    pub deployment_id: Uuid,
+    /// Commit checked out when the deployment was made, if the service is in a git repository
+    pub git_commit: Option<String>,
+    pub deployed_at: DateTime<Utc>,
+    /// Type of every resource bound to the service. Their connection details are left out on purpose since they
+    /// hold credentials.
+    pub resources: Vec<String>,
+    pub config: DeployConfig,
+}
+
+/// What the deployment was made with, after `Shuttle.toml` and the command line were resolved
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct DeployConfig {
+    pub environment: Option<String>,
+    pub pre_stop: Option<String>,
+    pub no_test: bool,
+    pub tag_latest: bool,
+    pub crate_path: Option<String>,
+    /// Manifests written before the option existed leave it out
+    #[serde(default)]
+    pub no_locked: bool,
+}
+
+impl Manifest {
+    pub fn new(
+        summary: &service::Summary,
+        deployment: &deployment::Response,
+        working_directory: &Path,
+        config: DeployConfig,
+    ) -> Result<Self> {
+        Ok(Self {
+            project: summary.name.parse().context("parsing the service name")?,
+            deployment_id: deployment.id,
+            git_commit: git_commit(working_directory),
+            deployed_at: Utc::now(),
+            resources: resource_types(summary),
+            config,
+        })
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        let manifest = fs::read_to_string(path)
+            .with_context(|| format!("could not read manifest {}", path.display()))?;
+
+        toml::from_str(&manifest)
+            .with_context(|| format!("could not parse manifest {}", path.display()))
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let manifest = toml::to_string_pretty(self).context("serializing the manifest")?;
+
+        fs::write(path, manifest)
+            .with_context(|| format!("could not write manifest {}", path.display()))
+    }
+
+    /// Every way the service currently on the platform differs from this manifest
+    pub fn drift(&self, summary: &service::Summary) -> Vec<String> {
+        let mut drift = Vec::new();
+
+        match &summary.deployment {
+            Some(running) if running.id == self.deployment_id => {}
+            Some(running) => drift.push(format!(
+                "deployment {} is running instead of {}",
+                running.id, self.deployment_id
+            )),
+            None => drift.push(format!(
+                "no deployment is running, expected {}",
+                self.deployment_id
+            )),
+        }
+
+        let mut expected = self.resources.clone();
+        expected.sort();
+        let actual = resource_types(summary);
+        if actual != expected {
+            drift.push(format!(
+                "the service has the resources [{}] instead of [{}]",
+                actual.join(", "),
+                expected.join(", ")
+            ));
+        }
+
+        drift
+    }
+}
+
+fn resource_types(summary: &service::Summary) -> Vec<String> {
+    let mut types: Vec<_> = summary
+        .resources
+        .iter()
+        .map(|resource| resource.r#type.to_string())
+        .collect();
+    types.sort();
+
+    types
+}
+
+fn git_commit(working_directory: &Path) -> Option<String> {
+    let repo = Repository::discover(working_directory).ok()?;
+    let commit = repo.head().ok()?.peel_to_commit().ok()?;
+
+    Some(commit.id().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use serde_json::json;
+    use shuttle_common::database;
+    use shuttle_common::models::{deployment, resource, service};
+    use tempfile::Builder;
+    use uuid::Uuid;
+
+    use super::{DeployConfig, Manifest};
+
+    fn summary(deployment_id: Uuid, resources: Vec<resource::Type>) -> service::Summary {
+        let service_id = Uuid::new_v4();
+
+        service::Summary {
+            name: "matrix".to_string(),
+            deployment: Some(deployment::Response {
+                id: deployment_id,
+                service_id,
+                state: deployment::State::Running,
+                last_update: Utc::now(),
+                peak_memory: None,
+                locked: None,
+            }),
+            resources: resources
+                .into_iter()
+                .map(|r#type| resource::Response {
+                    service_id,
+                    r#type,
+                    data: json!({ "password": "secret" }),
+                    metadata: Default::default(),
+                })
+                .collect(),
+            uri: "https://matrix.shuttleapp.rs".to_string(),
+        }
+    }
+
+    #[test]
+    fn roundtrip_and_drift() {
+        let deployment_id = Uuid::new_v4();
+        let postgres =
+            resource::Type::Database(database::Type::Shared(database::SharedEngine::Postgres));
+        let deployed = summary(deployment_id, vec![postgres.clone()]);
+        let config = DeployConfig {
+            pre_stop: Some("/drain".to_string()),
+            ..Default::default()
+        };
+
+        let dir = Builder::new().prefix("manifest").tempdir().unwrap();
+        let path = dir.path().join("Shuttle.lock");
+        let manifest = Manifest::new(
+            &deployed,
+            deployed.deployment.as_ref().unwrap(),
+            dir.path(),
+            config,
+        )
+        .unwrap();
+        manifest.write(&path).unwrap();
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        assert!(
+            !written.contains("secret"),
+            "resource details should not be written"
+        );
+
+        let read = Manifest::read(&path).unwrap();
+        assert_eq!(read, manifest);
+        assert!(read.drift(&deployed).is_empty());
+
+        let redeployed = summary(Uuid::new_v4(), vec![postgres]);
+        assert_eq!(read.drift(&redeployed).len(), 1);
+
+        let mut stopped = summary(deployment_id, Vec::new());
+        stopped.deployment = None;
+        assert_eq!(read.drift(&stopped).len(), 2);
+    }
+}
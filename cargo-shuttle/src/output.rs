@@ -0,0 +1,53 @@
+//! How much the CLI prints besides what a command is asked for, and where.
+//!
+//! `--quiet` and `deploy --raw` are checked through [quiet] and [raw] rather than passed around, the same way colors
+//! are, so that progress bars made deep inside a command follow them too.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static QUIET: AtomicBool = AtomicBool::new(false);
+static RAW: AtomicBool = AtomicBool::new(false);
+
+/// Leave out progress, build logs, notes and warnings for the whole process
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+/// Whether only results and errors are printed
+pub fn quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+/// Leave stdout to the output of the service, sending what the CLI prints itself to stderr
+pub fn set_raw(raw: bool) {
+    RAW.store(raw, Ordering::Relaxed);
+}
+
+pub fn raw() -> bool {
+    RAW.load(Ordering::Relaxed)
+}
+
+/// Whether progress bars and spinners are drawn
+pub fn progress() -> bool {
+    !quiet() && !raw()
+}
+
+/// Print a line telling how a command went, which goes to stderr when stdout is left to the service
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if $crate::output::raw() {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
+
+/// Print a line which is only there to keep the user informed, and so is left out by `--quiet`
+macro_rules! note {
+    ($($arg:tt)*) => {
+        if !$crate::output::quiet() {
+            status!($($arg)*);
+        }
+    };
+}
@@ -164,6 +164,62 @@ fn interactive_rocket_init_dont_prompt_name() -> Result<(), Box<dyn std::error::
     Ok(())
 }
 
+#[tokio::test]
+async fn non_interactive_init_refuses_non_empty_directory() {
+    let temp_dir = Builder::new().prefix("non-empty-init").tempdir().unwrap();
+    let temp_dir_path = temp_dir.path().to_owned();
+
+    std::fs::write(temp_dir_path.join("notes.txt"), "keep me").unwrap();
+
+    let args = Args::parse_from([
+        "cargo-shuttle",
+        "--api-url",
+        "http://shuttle.invalid:80",
+        "init",
+        "--api-key",
+        "fake-api-key",
+        "--name",
+        "my-project",
+        "--no-framework",
+        temp_dir_path.to_str().unwrap(),
+    ]);
+    let error = Shuttle::new().unwrap().run(args).await.err().unwrap();
+
+    assert!(error.to_string().contains("notes.txt"));
+    assert!(!temp_dir_path.join("Cargo.toml").exists());
+}
+
+#[tokio::test]
+async fn non_interactive_init_force_keeps_other_files() {
+    let temp_dir = Builder::new().prefix("force-init").tempdir().unwrap();
+    let temp_dir_path = temp_dir.path().to_owned();
+
+    std::fs::write(temp_dir_path.join("notes.txt"), "keep me").unwrap();
+    std::fs::write(temp_dir_path.join("Cargo.toml"), "[package]").unwrap();
+
+    let args = Args::parse_from([
+        "cargo-shuttle",
+        "--api-url",
+        "http://shuttle.invalid:80",
+        "init",
+        "--api-key",
+        "fake-api-key",
+        "--name",
+        "my-project",
+        "--no-framework",
+        "--force",
+        temp_dir_path.to_str().unwrap(),
+    ]);
+    Shuttle::new().unwrap().run(args).await.unwrap();
+
+    let cargo_toml = read_to_string(temp_dir_path.join("Cargo.toml")).unwrap();
+    assert!(cargo_toml.contains("shuttle-service = { version = "));
+    assert_eq!(
+        read_to_string(temp_dir_path.join("notes.txt")).unwrap(),
+        "keep me"
+    );
+}
+
 fn assert_valid_rocket_project(path: &Path, name_prefix: &str) {
     let cargo_toml = read_to_string(path.join("Cargo.toml")).unwrap();
     assert!(cargo_toml.contains(&format!("name = \"{name_prefix}")));
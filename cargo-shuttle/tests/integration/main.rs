@@ -1,7 +1,9 @@
 mod init;
 mod run;
 
-use cargo_shuttle::{Args, Command, CommandOutcome, ProjectArgs, Shuttle};
+use cargo_shuttle::{
+    Args, ColorChoice, Command, CommandOutcome, OutputFormat, ProjectArgs, Shuttle,
+};
 use std::path::Path;
 
 /// creates a `cargo-shuttle` run instance with some reasonable defaults set.
@@ -15,11 +17,15 @@ async fn cargo_shuttle_command(
         .unwrap()
         .run(Args {
             api_url: Some("http://shuttle.invalid:80".to_string()),
+            version: false,
+            output_format: OutputFormat::Text,
+            color: ColorChoice::Never,
             project_args: ProjectArgs {
                 working_directory,
                 name: None,
+                environment: None,
             },
-            cmd,
+            cmd: Some(cmd),
         })
         .await
 }
@@ -1,7 +1,8 @@
-use cargo_shuttle::{Args, Command, ProjectArgs, RunArgs, Shuttle};
+use cargo_shuttle::{Args, ColorChoice, Command, OutputFormat, ProjectArgs, RunArgs, Shuttle};
 use dunce::canonicalize;
 use portpicker::pick_unused_port;
 use reqwest::StatusCode;
+use shuttle_common::deployment::Environment;
 use std::{process::exit, time::Duration};
 use tokio::time::sleep;
 
@@ -21,15 +22,22 @@ async fn cargo_shuttle_run(working_directory: &str, external: bool) -> String {
         port,
         external,
         release: false,
+        environment_kind: Environment::Local,
+        idle: None,
+        wait_for_debugger: false,
     };
 
     let runner = Shuttle::new().unwrap().run(Args {
         api_url: Some("http://shuttle.invalid:80".to_string()),
+        version: false,
+        output_format: OutputFormat::Text,
+        color: ColorChoice::Never,
         project_args: ProjectArgs {
             working_directory: working_directory.clone(),
             name: None,
+            environment: None,
         },
-        cmd: Command::Run(run_args),
+        cmd: Some(Command::Run(run_args)),
     });
 
     let working_directory_clone = working_directory.clone();
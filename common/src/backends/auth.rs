@@ -138,6 +138,9 @@ pub enum Scope {
 
     /// Admin level scope to internals
     Admin,
+
+    /// Act as the deployer of a project, like registering its instances with the gateway
+    Deployer,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -66,6 +66,10 @@ impl Header for XShuttleAccountName {
     }
 }
 
+/// Set by deployers on their answer to a proxied request for a service which has no running deployment, so that the
+/// gateway can tell it apart from a response of the service itself
+pub static X_SHUTTLE_NOT_DEPLOYED: HeaderName = HeaderName::from_static("x-shuttle-not-deployed");
+
 pub static X_SHUTTLE_PROJECT: HeaderName = HeaderName::from_static("x-shuttle-project");
 
 pub struct XShuttleProject(pub String);
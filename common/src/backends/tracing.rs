@@ -17,20 +17,29 @@ use pin_project::pin_project;
 use tower::{Layer, Service};
 use tracing::{debug_span, instrument::Instrumented, Instrument, Span, Subscriber};
 use tracing_opentelemetry::OpenTelemetrySpanExt;
-use tracing_subscriber::{fmt, prelude::*, registry::LookupSpan, EnvFilter};
+use tracing_subscriber::{fmt, fmt::MakeWriter, prelude::*, registry::LookupSpan, EnvFilter};
 
 use super::future::ResponseFuture;
 
 pub fn setup_tracing<S>(subscriber: S, service_name: &str)
 where
     S: Subscriber + for<'a> LookupSpan<'a> + Send + Sync,
+{
+    setup_tracing_with_writer(subscriber, service_name, std::io::stdout)
+}
+
+/// Like [setup_tracing], but with the logs printed to `writer` rather than to stdout
+pub fn setup_tracing_with_writer<S, W>(subscriber: S, service_name: &str, writer: W)
+where
+    S: Subscriber + for<'a> LookupSpan<'a> + Send + Sync,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
 {
     global::set_text_map_propagator(TraceContextPropagator::new());
 
     let filter_layer = EnvFilter::try_from_default_env()
         .or_else(|_| EnvFilter::try_new("info"))
         .unwrap();
-    let fmt_layer = fmt::layer();
+    let fmt_layer = fmt::layer().with_writer(writer);
 
     let tracer = opentelemetry_otlp::new_pipeline()
         .tracing()
@@ -1,7 +1,7 @@
 use serde::{Deserialize, Serialize};
-use strum::Display;
+use strum::{Display, EnumString};
 
-#[derive(Clone, Debug, Deserialize, Display, Serialize)]
+#[derive(Clone, Debug, Deserialize, Display, EnumString, PartialEq, Eq, Serialize)]
 #[serde(rename_all = "lowercase")]
 #[strum(serialize_all = "lowercase")]
 pub enum State {
@@ -12,11 +12,21 @@ pub enum State {
     Running,
     Completed,
     Stopped,
+    #[serde(rename = "budget-exceeded")]
+    #[strum(serialize = "budget-exceeded")]
+    BudgetExceeded,
+    Expired,
     Crashed,
+    Cancelled,
     Unknown,
 }
 
 /// This which environment is this deployment taking place
+///
+/// `cargo shuttle run` uses [Environment::Local] and the deployer [Environment::Production], unless they are told
+/// otherwise, for example to try out the production branch of a service from CI.
+#[derive(Clone, Copy, Debug, Display, EnumString, PartialEq, Eq)]
+#[strum(serialize_all = "lowercase")]
 pub enum Environment {
     Local,
     Production,
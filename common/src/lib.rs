@@ -6,6 +6,8 @@ pub mod log;
 #[cfg(feature = "models")]
 pub mod models;
 pub mod project;
+#[cfg(feature = "display")]
+pub mod style;
 
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
@@ -2,15 +2,21 @@
 use std::fmt::Write;
 
 use chrono::{DateTime, Utc};
-#[cfg(feature = "display")]
-use crossterm::style::{StyledContent, Stylize};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 use crate::deployment::State;
+#[cfg(feature = "display")]
+use crate::style::{Styled, Stylize};
 
 pub const STATE_MESSAGE: &str = "NEW STATE";
 
+/// Field of a build log carrying a [BuildProgress]
+pub const BUILD_PROGRESS_FIELD: &str = "build_progress";
+
+/// Field of a runtime log carrying the [Stream] it was written to
+pub const STREAM_FIELD: &str = "output_stream";
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Item {
     pub id: Uuid,
@@ -23,6 +29,33 @@ pub struct Item {
     pub fields: Vec<u8>,
 }
 
+#[cfg(feature = "models")]
+impl Item {
+    /// Get the build progress if this is a progress update rather than a normal log line
+    pub fn build_progress(&self) -> Option<BuildProgress> {
+        let fields: serde_json::Value = serde_json::from_slice(&self.fields).ok()?;
+
+        serde_json::from_value(fields.get(BUILD_PROGRESS_FIELD)?.clone()).ok()
+    }
+
+    /// The message of the line as it was logged, without its timestamp, level or fields. Lines marking a new state
+    /// have none.
+    pub fn message(&self) -> Option<String> {
+        match serde_json::from_slice(&self.fields).ok()? {
+            serde_json::Value::Object(fields) => Some(fields.get("message")?.as_str()?.to_string()),
+            _ => None,
+        }
+    }
+
+    /// The stream this line was written to. Lines which were logged rather than printed, like build output or the
+    /// tracing events of a service, have none.
+    pub fn stream(&self) -> Option<Stream> {
+        let fields: serde_json::Value = serde_json::from_slice(&self.fields).ok()?;
+
+        serde_json::from_value(fields.get(STREAM_FIELD)?.clone()).ok()
+    }
+}
+
 #[cfg(feature = "display")]
 impl std::fmt::Display for Item {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -43,6 +76,7 @@ impl std::fmt::Display for Item {
                 for (key, value) in map.iter() {
                     match key.as_str() {
                         "message" => simple = value.as_str(),
+                        STREAM_FIELD => {}
                         _ => extra.push(format!("{key}={value}")),
                     }
                 }
@@ -77,6 +111,33 @@ impl std::fmt::Display for Item {
     }
 }
 
+/// How many of the units in the crate graph of a deployment have been compiled so far
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, Eq, PartialEq)]
+pub struct BuildProgress {
+    pub compiled: usize,
+    pub total: usize,
+}
+
+impl BuildProgress {
+    /// A rough percentage which never goes over 100
+    pub fn percentage(&self) -> u8 {
+        if self.total == 0 {
+            return 100;
+        }
+
+        (self.compiled.min(self.total) * 100 / self.total) as u8
+    }
+}
+
+/// Output stream a service printed a line to
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, Eq, PartialEq, strum::Display)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize, Eq, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum Level {
@@ -89,7 +150,7 @@ pub enum Level {
 
 #[cfg(feature = "display")]
 impl Level {
-    fn get_colored(&self) -> StyledContent<&str> {
+    fn get_colored(&self) -> Styled<&str> {
         match self {
             Level::Trace => "TRACE".magenta(),
             Level::Debug => "DEBUG".blue(),
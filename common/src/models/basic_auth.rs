@@ -0,0 +1,39 @@
+use std::fmt::{Display, Formatter};
+
+use serde::{Deserialize, Serialize};
+
+/// Credentials the proxy asks for before letting a request through to a project. The password is only sent when
+/// setting them, and is stored hashed.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Config {
+    pub username: String,
+    pub password: String,
+    /// Paths, like `/health`, which are let through without credentials along with everything under them
+    #[serde(default)]
+    pub exempt: Vec<String>,
+}
+
+/// Whether a project is protected with basic auth, and for whom
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Response {
+    pub username: Option<String>,
+    #[serde(default)]
+    pub exempt: Vec<String>,
+}
+
+impl Display for Response {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.username {
+            Some(username) => {
+                write!(f, "requests need the password of '{username}'")?;
+
+                if !self.exempt.is_empty() {
+                    write!(f, ", except to {}", self.exempt.join(", "))?;
+                }
+
+                Ok(())
+            }
+            None => write!(f, "off"),
+        }
+    }
+}
@@ -1,12 +1,16 @@
 use std::fmt::Display;
+use std::net::SocketAddr;
 
 use chrono::{DateTime, Utc};
 use comfy_table::Color;
-use crossterm::style::Stylize;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 use crate::deployment::State;
+use crate::style::Stylize;
+
+/// Tag which `--tag-latest` moves to the deployment being made
+pub const LATEST_TAG: &str = "latest";
 
 #[derive(Deserialize, Serialize)]
 pub struct Response {
@@ -14,6 +18,253 @@ pub struct Response {
     pub service_id: Uuid,
     pub state: State,
     pub last_update: DateTime<Utc>,
+
+    /// Highest resident memory in bytes reached by the latest run, when it is known
+    #[serde(default)]
+    pub peak_memory: Option<u64>,
+
+    /// Whether the deployment is built with `--locked`, when it is known
+    #[serde(default)]
+    pub locked: Option<bool>,
+}
+
+/// Everything known about a deployment, for tools to go by instead of piecing it together from several commands
+#[derive(Deserialize, Serialize)]
+pub struct Status {
+    #[serde(flatten)]
+    pub deployment: Response,
+
+    /// Address the service is bound to, once it has been loaded
+    pub address: Option<SocketAddr>,
+
+    /// Every state the deployment went through, oldest first
+    pub history: Vec<StateChange>,
+
+    /// Message of the last error the deployment logged, which tends to be why it crashed
+    pub last_error: Option<String>,
+
+    /// Number of times the deployment was started again after it was first loaded
+    pub restarts: u32,
+
+    /// When the artifact and logs of the deployment were removed, which means it cannot be started again
+    #[serde(default)]
+    pub artifact_deleted_at: Option<DateTime<Utc>>,
+}
+
+/// The outcome of a deployment in one record, once it is running or failed to get there
+#[derive(Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct Summary {
+    pub id: Uuid,
+    pub state: State,
+
+    /// Milliseconds spent building, for deployments which were built
+    pub build_ms: Option<u64>,
+
+    /// Milliseconds the latest load took to get the service running, or to fail
+    pub load_ms: Option<u64>,
+
+    /// Milliseconds from the deployment being made until it first ran
+    pub ready_ms: Option<u64>,
+
+    /// Address the service is bound to, once it has been loaded
+    pub address: Option<SocketAddr>,
+
+    /// Checksum of the built library, or the container image the deployment runs
+    pub artifact: Option<String>,
+
+    /// Message of the last error the deployment logged
+    pub error: Option<String>,
+
+    /// Whether a crashed deployment failed for a reason another try may get past, like a hiccup of the platform rather
+    /// than code which does not compile. Not set for deployments which did not crash, or by deployers which predate it
+    pub retryable: Option<bool>,
+}
+
+impl Summary {
+    /// Start a summary with the timings of the states a deployment went through, oldest first
+    pub fn from_history(id: Uuid, state: State, history: &[StateChange]) -> Self {
+        // Time from entering a state until the deployment moved on from it
+        let time_in = |index: usize| {
+            let entered = &history[index];
+
+            history[index..]
+                .iter()
+                .find(|change| change.state != entered.state)
+                .map(|left| elapsed_ms(entered.timestamp, left.timestamp))
+        };
+
+        let build_ms = history
+            .iter()
+            .position(|change| change.state == State::Building)
+            .and_then(time_in);
+        let load_ms = history
+            .iter()
+            .rposition(|change| change.state == State::Loading)
+            .and_then(time_in);
+        let ready_ms = history.first().and_then(|made| {
+            history
+                .iter()
+                .find(|change| change.state == State::Running)
+                .map(|running| elapsed_ms(made.timestamp, running.timestamp))
+        });
+
+        Self {
+            id,
+            state,
+            build_ms,
+            load_ms,
+            ready_ms,
+            address: None,
+            artifact: None,
+            error: None,
+            retryable: None,
+        }
+    }
+}
+
+fn elapsed_ms(from: DateTime<Utc>, to: DateTime<Utc>) -> u64 {
+    (to - from).num_milliseconds().max(0) as u64
+}
+
+impl Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let secs = |ms: Option<u64>| match ms {
+            Some(ms) => format!("{:.1}s", ms as f64 / 1000.0),
+            None => "-".to_string(),
+        };
+
+        writeln!(
+            f,
+            "Deployment {} is {}",
+            self.id,
+            self.state.to_string().with(self.state.get_color())
+        )?;
+        writeln!(f, "  build:    {}", secs(self.build_ms))?;
+        writeln!(f, "  load:     {}", secs(self.load_ms))?;
+        writeln!(f, "  ready in: {}", secs(self.ready_ms))?;
+
+        if let Some(address) = self.address {
+            writeln!(f, "  address:  {address}")?;
+        }
+
+        if let Some(artifact) = &self.artifact {
+            writeln!(f, "  artifact: {artifact}")?;
+        }
+
+        if let Some(error) = &self.error {
+            writeln!(f, "  error:    {}", error.as_str().red())?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct StateChange {
+    pub state: State,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// What a deployer is doing right now, for operators to look into one which seems stuck
+#[derive(Deserialize, Serialize)]
+pub struct DebugDump {
+    /// Built deployments waiting for the run task to start them, first in line first
+    pub run_queue: Vec<PendingRun>,
+
+    /// Deployments which have not reached a final state yet
+    pub active: Vec<ActiveDeployment>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PendingRun {
+    pub id: Uuid,
+    pub service_name: String,
+    pub queued_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ActiveDeployment {
+    pub id: Uuid,
+    pub service_id: Uuid,
+    pub state: State,
+
+    /// Address the service is bound to, once it has been loaded
+    pub address: Option<SocketAddr>,
+
+    /// When the deployment entered its current state
+    pub since: DateTime<Utc>,
+
+    /// Seconds the deployment has been running for, when it is running
+    pub uptime_secs: Option<u64>,
+
+    /// Message of the last error the deployment logged
+    pub last_error: Option<String>,
+}
+
+/// What removing the artifact of a deployment freed
+#[derive(Deserialize, Serialize)]
+pub struct ArtifactRemoval {
+    pub id: Uuid,
+
+    /// Bytes of stored files which were deleted, none for a deployment which ran an image
+    pub freed_bytes: u64,
+
+    /// Number of log lines which were deleted. The state changes of the deployment are kept.
+    pub removed_logs: u64,
+}
+
+/// Request to create a deployment from an archive hosted elsewhere
+#[derive(Deserialize, Serialize)]
+pub struct CreateFromArchive {
+    /// Where the `.tar.gz` archive of the service can be downloaded from
+    pub archive_url: String,
+
+    /// Skip running the tests of the service before deploying it
+    #[serde(default)]
+    pub no_test: bool,
+
+    /// Path on the service to call before the deployment is stopped
+    #[serde(default)]
+    pub pre_stop: Option<String>,
+
+    /// Let cargo update the `Cargo.lock` of the archive instead of building with `--locked`
+    #[serde(default)]
+    pub no_locked: bool,
+
+    /// Seconds the deployment may run for before it is stopped, unbounded when not given
+    #[serde(default)]
+    pub run_budget: Option<u32>,
+
+    /// Seconds after which the deployment expires and is stopped, counted from when it is made. Unbounded when not
+    /// given.
+    #[serde(default)]
+    pub ttl: Option<u32>,
+}
+
+/// A resumable upload of a deployment archive
+#[derive(Deserialize, Serialize)]
+pub struct UploadStatus {
+    pub id: Uuid,
+
+    /// Number of bytes received so far, which is where the next chunk should start
+    pub offset: u64,
+}
+
+/// Progress of a deployer which is being drained before it is decommissioned
+#[derive(Deserialize, Serialize)]
+pub struct DrainStatus {
+    /// Whether new deployments are being turned away
+    pub draining: bool,
+
+    /// Number of deployments which have not reached a final state yet
+    pub remaining: u32,
+}
+
+impl DrainStatus {
+    /// The host can be retired once nothing is left on it
+    pub fn drained(&self) -> bool {
+        self.draining && self.remaining == 0
+    }
 }
 
 impl Display for Response {
@@ -27,7 +278,21 @@ impl Display for Response {
                 .dim(),
             self.id,
             self.state.to_string().cyan()
-        )
+        )?;
+
+        if let Some(peak_memory) = self.peak_memory {
+            write!(
+                f,
+                ", peak memory {:.1} MiB",
+                peak_memory as f64 / (1024.0 * 1024.0)
+            )?;
+        }
+
+        if self.locked == Some(false) {
+            write!(f, ", built without --locked")?;
+        }
+
+        Ok(())
     }
 }
 
@@ -36,9 +301,52 @@ impl State {
         match self {
             State::Queued | State::Building | State::Built | State::Loading => Color::Cyan,
             State::Running => Color::Green,
-            State::Completed | State::Stopped => Color::Blue,
+            State::Completed
+            | State::Stopped
+            | State::BudgetExceeded
+            | State::Expired
+            | State::Cancelled => Color::Blue,
             State::Crashed => Color::Red,
             State::Unknown => Color::Yellow,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone, Utc};
+    use uuid::Uuid;
+
+    use super::{StateChange, Summary};
+    use crate::deployment::State;
+
+    #[test]
+    fn summary_timings() {
+        let made = Utc.with_ymd_and_hms(2023, 2, 1, 12, 0, 0).unwrap();
+        let at = |state, secs| StateChange {
+            state,
+            timestamp: made + Duration::seconds(secs),
+        };
+        let history = [
+            at(State::Queued, 0),
+            at(State::Building, 2),
+            at(State::Built, 32),
+            at(State::Loading, 33),
+            at(State::Running, 36),
+            // Started again later on, which is the load that counts
+            at(State::Loading, 100),
+            at(State::Crashed, 101),
+        ];
+
+        let summary = Summary::from_history(Uuid::nil(), State::Crashed, &history);
+
+        assert_eq!(summary.build_ms, Some(30_000));
+        assert_eq!(summary.load_ms, Some(1_000));
+        assert_eq!(summary.ready_ms, Some(36_000));
+
+        let summary = Summary::from_history(Uuid::nil(), State::Building, &history[..2]);
+
+        assert_eq!(summary.build_ms, None);
+        assert_eq!(summary.ready_ms, None);
+    }
+}
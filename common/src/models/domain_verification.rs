@@ -0,0 +1,27 @@
+use std::fmt::{Display, Formatter};
+
+use serde::{Deserialize, Serialize};
+
+/// The TXT record a project has to publish to prove it controls a custom domain, before the domain can be added to it
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Response {
+    pub fqdn: String,
+    pub record_name: String,
+    pub record_value: String,
+    /// Whether the record can be found right now
+    pub verified: bool,
+}
+
+impl Display for Response {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.verified {
+            write!(f, "{} is verified", self.fqdn)
+        } else {
+            write!(
+                f,
+                "add a TXT record named '{}' with the value '{}' to verify {}",
+                self.record_name, self.record_value, self.fqdn
+            )
+        }
+    }
+}
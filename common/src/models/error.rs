@@ -1,11 +1,12 @@
 use std::fmt::{Display, Formatter};
 
 use comfy_table::Color;
-use crossterm::style::Stylize;
 use http::StatusCode;
 use serde::{Deserialize, Serialize};
 use tracing::{error, warn};
 
+use crate::style::Stylize;
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ApiError {
     pub message: String,
@@ -43,11 +44,33 @@ pub enum ErrorKind {
     ProjectNotFound,
     InvalidProjectName,
     ProjectAlreadyExists,
+    ProjectLimitReached,
+    CustomDomainLimitReached,
     ProjectNotReady,
+    ProjectNotDeployed,
+    ProjectErrored,
     ProjectUnavailable,
+    ProjectConnectionReset,
     CustomDomainNotFound,
     InvalidCustomDomain,
+    InvalidCertificate,
     CustomDomainAlreadyExists,
+    DomainNotVerified,
+    TransferNotFound,
+    InvalidTransfer,
+    InvalidIpRule,
+    IpRuleNotFound,
+    InvalidHostHeader,
+    InvalidTimeouts,
+    InvalidHeaderLimits,
+    InvalidResponseHeader,
+    InvalidBasicAuth,
+    InvalidInstance,
+    GatewayTimeout,
+    RequestTimeout,
+    RequestTooLarge,
+    RequestHeadersTooLarge,
+    ResponseHeadersTooLarge,
     InvalidOperation,
     Internal,
     NotReady,
@@ -72,9 +95,21 @@ impl From<ErrorKind> for ApiError {
                 "project not found. Run `cargo shuttle project new` to create a new project.",
             ),
             ErrorKind::ProjectNotReady => (StatusCode::SERVICE_UNAVAILABLE, "project not ready"),
+            ErrorKind::ProjectNotDeployed => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "this project has not been deployed yet. Run `cargo shuttle deploy` to deploy it.",
+            ),
+            ErrorKind::ProjectErrored => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "project is in an error state, try again in a little bit",
+            ),
             ErrorKind::ProjectUnavailable => {
                 (StatusCode::BAD_GATEWAY, "project returned invalid response")
             }
+            ErrorKind::ProjectConnectionReset => (
+                StatusCode::BAD_GATEWAY,
+                "project closed the connection before it responded, it may have crashed",
+            ),
             ErrorKind::InvalidProjectName => (
                 StatusCode::BAD_REQUEST,
                 r#"
@@ -95,12 +130,84 @@ impl From<ErrorKind> for ApiError {
                 StatusCode::BAD_REQUEST,
                 "a project with the same name already exists",
             ),
+            ErrorKind::ProjectLimitReached => (
+                StatusCode::FORBIDDEN,
+                "the account has reached its maximum number of projects, see `cargo shuttle account usage`",
+            ),
+            ErrorKind::CustomDomainLimitReached => (
+                StatusCode::FORBIDDEN,
+                "the project has reached its maximum number of custom domains",
+            ),
             ErrorKind::InvalidCustomDomain => (StatusCode::BAD_REQUEST, "invalid custom domain"),
+            ErrorKind::InvalidCertificate => (
+                StatusCode::BAD_REQUEST,
+                "invalid certificate, expected a chain and private key valid for the domain",
+            ),
             ErrorKind::CustomDomainNotFound => (StatusCode::NOT_FOUND, "custom domain not found"),
             ErrorKind::CustomDomainAlreadyExists => {
-                (StatusCode::BAD_REQUEST, "custom domain already in use")
+                (StatusCode::CONFLICT, "custom domain already in use")
             }
+            ErrorKind::DomainNotVerified => (
+                StatusCode::FORBIDDEN,
+                "the project has not proven it controls the custom domain, publish the TXT record of `acme verification` first",
+            ),
+            ErrorKind::TransferNotFound => (
+                StatusCode::NOT_FOUND,
+                "no transfer of this project to or from the account is pending",
+            ),
+            ErrorKind::InvalidTransfer => (
+                StatusCode::BAD_REQUEST,
+                "invalid transfer, expected the name of an account other than the one owning the project",
+            ),
             ErrorKind::Unauthorized => (StatusCode::UNAUTHORIZED, "unauthorized"),
+            ErrorKind::InvalidIpRule => (
+                StatusCode::BAD_REQUEST,
+                "invalid IP rule, expected an IPv4 or IPv6 CIDR",
+            ),
+            ErrorKind::IpRuleNotFound => (StatusCode::NOT_FOUND, "IP rule not found"),
+            ErrorKind::InvalidHostHeader => (
+                StatusCode::BAD_REQUEST,
+                "invalid host header, expected a host with an optional port",
+            ),
+            ErrorKind::InvalidTimeouts => (
+                StatusCode::BAD_REQUEST,
+                "invalid timeouts, aborting stalled bodies needs a response timeout and none of them, nor the slow request threshold, can be 0 or over a day",
+            ),
+            ErrorKind::InvalidHeaderLimits => (
+                StatusCode::BAD_REQUEST,
+                "invalid header limits, none of them can be 0",
+            ),
+            ErrorKind::InvalidBasicAuth => (
+                StatusCode::BAD_REQUEST,
+                "invalid basic auth, expected a username without ':', a password and exempt paths starting with '/'",
+            ),
+            ErrorKind::InvalidResponseHeader => (
+                StatusCode::BAD_REQUEST,
+                "invalid response header rule, expected a valid header name and value which does not change how the response is framed",
+            ),
+            ErrorKind::InvalidInstance => (
+                StatusCode::BAD_REQUEST,
+                "invalid instance, expected an address and a weight of at least 1",
+            ),
+            ErrorKind::GatewayTimeout => (
+                StatusCode::GATEWAY_TIMEOUT,
+                "project did not respond in time",
+            ),
+            ErrorKind::RequestTimeout => (
+                StatusCode::REQUEST_TIMEOUT,
+                "the request took too long to be handled",
+            ),
+            ErrorKind::RequestTooLarge => {
+                (StatusCode::PAYLOAD_TOO_LARGE, "the request body is too large")
+            }
+            ErrorKind::RequestHeadersTooLarge => (
+                StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+                "the request headers are too large",
+            ),
+            ErrorKind::ResponseHeadersTooLarge => (
+                StatusCode::BAD_GATEWAY,
+                "the response headers of the project are too large",
+            ),
             ErrorKind::Forbidden => (StatusCode::FORBIDDEN, "forbidden"),
             ErrorKind::NotReady => (StatusCode::INTERNAL_SERVER_ERROR, "service not ready"),
         };
@@ -0,0 +1,45 @@
+use std::fmt::{Display, Formatter};
+
+use serde::{Deserialize, Serialize};
+
+/// Limits on the headers of the requests the proxy passes on to a project and of the responses it gets back. The
+/// limits which are not set are those of the gateway.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Config {
+    /// Bytes of the names and values of all the headers together
+    pub max_total_size: Option<u64>,
+
+    /// Number of headers, counting every value of a repeated header
+    pub max_count: Option<u64>,
+
+    /// Bytes of the name and value of any one header
+    pub max_header_size: Option<u64>,
+}
+
+impl Config {
+    /// These limits, with the ones which are not set taken from `defaults`
+    pub fn or(&self, defaults: &Config) -> Config {
+        Config {
+            max_total_size: self.max_total_size.or(defaults.max_total_size),
+            max_count: self.max_count.or(defaults.max_count),
+            max_header_size: self.max_header_size.or(defaults.max_header_size),
+        }
+    }
+}
+
+impl Display for Config {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let limit = |limit: Option<u64>| match limit {
+            Some(limit) => limit.to_string(),
+            None => "default".to_string(),
+        };
+
+        write!(
+            f,
+            "{} bytes in total, {} headers, {} bytes per header",
+            limit(self.max_total_size),
+            limit(self.max_count),
+            limit(self.max_header_size)
+        )
+    }
+}
@@ -0,0 +1,28 @@
+use std::fmt::{Display, Formatter};
+
+use serde::{Deserialize, Serialize};
+
+/// Which `Host` header requests to a project are forwarded with
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(tag = "policy", content = "value", rename_all = "lowercase")]
+pub enum Policy {
+    /// Keep the public host the client asked for
+    #[default]
+    Preserve,
+
+    /// Rewrite to the internal address of the project's backend
+    Backend,
+
+    /// Always forward this host, like `api.internal` or `example.com:8080`
+    Fixed(String),
+}
+
+impl Display for Policy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Policy::Preserve => write!(f, "preserve the public host"),
+            Policy::Backend => write!(f, "rewrite to the backend address"),
+            Policy::Fixed(host) => write!(f, "always send '{host}'"),
+        }
+    }
+}
@@ -0,0 +1,31 @@
+use std::net::SocketAddr;
+
+use serde::{Deserialize, Serialize};
+
+/// An instance of a project, which the proxy spreads the requests of the project over along with its own container
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Registration {
+    /// Where the instance serves the project
+    pub address: SocketAddr,
+
+    /// Share of the requests the instance gets, relative to the weights of the other instances
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+/// How the proxy sees an instance of a project
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Response {
+    pub address: SocketAddr,
+    pub weight: u32,
+
+    /// Whether requests are being sent to the instance. One which failed a request is skipped for a while.
+    pub healthy: bool,
+
+    /// Whether the instance registered itself, rather than being the container of the project
+    pub registered: bool,
+}
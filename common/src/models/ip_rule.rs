@@ -0,0 +1,27 @@
+use serde::{Deserialize, Serialize};
+use strum::{Display, EnumString};
+
+/// What happens to clients matching an IP rule
+#[derive(Clone, Copy, Debug, Deserialize, Display, EnumString, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum Action {
+    /// Only clients matching an allow rule can reach the project, unless a deny rule matches them too
+    Allow,
+
+    /// Clients matching a deny rule can never reach the project
+    Deny,
+}
+
+/// A rule on which client IPs can reach a project
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Rule {
+    /// IPv4 or IPv6 CIDR, like `10.0.0.0/8` or `2001:db8::/32`. A single address is also accepted.
+    pub cidr: String,
+    pub action: Action,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DeleteRequest {
+    pub cidr: String,
+}
@@ -0,0 +1,36 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use strum::{Display, EnumString};
+
+/// Kinds of external sinks the logs of a service can be forwarded to
+#[derive(Clone, Copy, Debug, Deserialize, Display, EnumString, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum SinkType {
+    /// Batches of logs are `POST`ed as JSON to an HTTP endpoint
+    Http,
+
+    /// Every log line is sent as a syslog message over UDP
+    Syslog,
+
+    /// Batches of logs are `PUT` as JSON objects under an object store (bucket) URL
+    ObjectStore,
+}
+
+/// Log forwarding configuration for a service
+#[derive(Deserialize, Serialize)]
+pub struct Config {
+    pub sink: SinkType,
+    pub endpoint: String,
+
+    /// Value for the `Authorization` header sent to the sink. It is stored encrypted and is never returned.
+    pub auth: Option<String>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct Response {
+    pub sink: SinkType,
+    pub endpoint: String,
+    pub has_auth: bool,
+    pub last_update: DateTime<Utc>,
+}
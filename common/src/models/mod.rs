@@ -1,11 +1,22 @@
+pub mod basic_auth;
 pub mod deployment;
+pub mod domain_verification;
 pub mod error;
+pub mod header_limits;
+pub mod host_header;
+pub mod instance;
+pub mod ip_rule;
+pub mod log_forwarding;
 pub mod project;
 pub mod resource;
+pub mod response_header;
 pub mod secret;
 pub mod service;
 pub mod stats;
+pub mod timeouts;
+pub mod usage;
 pub mod user;
+pub mod version;
 
 use anyhow::{Context, Result};
 use async_trait::async_trait;
@@ -1,12 +1,14 @@
+use chrono::{DateTime, SecondsFormat, Utc};
 use comfy_table::{
     modifiers::UTF8_ROUND_CORNERS, presets::UTF8_FULL, Cell, CellAlignment, Color,
     ContentArrangement, Table,
 };
-use crossterm::style::Stylize;
 use serde::{Deserialize, Serialize};
 use std::fmt::{Display, Formatter};
 use strum::EnumString;
 
+use crate::style::{style_table, Stylize};
+
 // Timeframe before a project is considered idle
 pub const IDLE_MINUTES: u64 = 30;
 
@@ -158,12 +160,90 @@ impl State {
     }
 }
 
+/// A state a project settled in, as recorded by the gateway. The states a project only passes through, like
+/// starting, are left out.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Event {
+    pub state: State,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl Event {
+    /// What happened to the project, going by the state it was in before
+    pub fn transition(&self, previous: Option<&State>) -> String {
+        match (previous, &self.state) {
+            (None, State::Creating { .. }) => "created".to_string(),
+            (Some(_), State::Creating { .. }) => "recreated".to_string(),
+            (Some(State::Stopped), State::Ready) => "woke up".to_string(),
+            (Some(State::Errored { .. }), State::Ready) => "recovered".to_string(),
+            (_, State::Ready) => "ready".to_string(),
+            (_, State::Stopped) => "stopped".to_string(),
+            (_, State::Destroyed) => "destroyed".to_string(),
+            (_, State::Errored { message }) => format!("errored: {message}"),
+            (_, state) => format!("{state}"),
+        }
+    }
+}
+
+/// Timeline of the recorded states of a project, oldest first
+pub fn get_history(events: &[Event]) -> String {
+    if events.is_empty() {
+        return format!(
+            "{}\n",
+            "No state changes have been recorded for this project yet".yellow()
+        );
+    }
+
+    let mut history = String::new();
+    let mut previous = None;
+
+    for event in events {
+        let transition = event.transition(previous);
+        let transition = match event.state {
+            State::Errored { .. } => transition.red().to_string(),
+            _ => transition,
+        };
+
+        history.push_str(&format!(
+            "{}  {transition}\n",
+            event
+                .timestamp
+                .to_rfc3339_opts(SecondsFormat::Secs, true)
+                .dim()
+        ));
+        previous = Some(&event.state);
+    }
+
+    history
+}
+
 /// Config when creating a new project
 #[derive(Deserialize, Serialize)]
 pub struct Config {
     pub idle_minutes: u64,
 }
 
+/// Whether a project is pinned, which keeps it from idling whatever its idle timer says
+#[derive(Deserialize, Serialize)]
+pub struct Pin {
+    pub pinned: bool,
+}
+
+/// Request to hand a project over to another account
+#[derive(Deserialize, Serialize, Debug)]
+pub struct TransferRequest {
+    pub to_account: String,
+}
+
+/// A project being handed over to another account, which it only moves to once that account accepts it
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct Transfer {
+    pub project_name: String,
+    pub from_account: String,
+    pub to_account: String,
+    pub created_at: DateTime<Utc>,
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct AdminResponse {
     pub project_name: String,
@@ -178,6 +258,7 @@ pub fn get_table(projects: &Vec<Response>) -> String {
         )
     } else {
         let mut table = Table::new();
+        style_table(&mut table);
         table
             .load_preset(UTF8_FULL)
             .apply_modifier(UTF8_ROUND_CORNERS)
@@ -205,3 +286,87 @@ These projects are linked to this account
         )
     }
 }
+
+pub fn get_transfers_table(transfers: &[Transfer]) -> String {
+    if transfers.is_empty() {
+        return format!(
+            "{}\n",
+            "No project transfers to or from this account are pending".yellow()
+        );
+    }
+
+    let mut table = Table::new();
+    style_table(&mut table);
+    table
+        .load_preset(UTF8_FULL)
+        .apply_modifier(UTF8_ROUND_CORNERS)
+        .set_content_arrangement(ContentArrangement::DynamicFullWidth)
+        .set_header(vec![
+            Cell::new("Project Name").set_alignment(CellAlignment::Center),
+            Cell::new("From").set_alignment(CellAlignment::Center),
+            Cell::new("To").set_alignment(CellAlignment::Center),
+            Cell::new("Since").set_alignment(CellAlignment::Center),
+        ]);
+
+    for transfer in transfers {
+        table.add_row(vec![
+            Cell::new(&transfer.project_name),
+            Cell::new(&transfer.from_account),
+            Cell::new(&transfer.to_account),
+            Cell::new(
+                transfer
+                    .created_at
+                    .to_rfc3339_opts(SecondsFormat::Secs, true),
+            ),
+        ]);
+    }
+
+    format!(
+        r#"
+These project transfers are waiting to be accepted
+{table}
+"#
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::{get_history, Event, State};
+    use crate::style::set_colors;
+
+    #[test]
+    fn history() {
+        set_colors(false);
+
+        let events: Vec<_> = [
+            State::Creating { recreate_count: 0 },
+            State::Ready,
+            State::Stopped,
+            State::Ready,
+            State::Errored {
+                message: "container went away".to_string(),
+            },
+            State::Ready,
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(hour, state)| Event {
+            state,
+            timestamp: Utc.with_ymd_and_hms(2023, 3, 1, hour as u32, 0, 0).unwrap(),
+        })
+        .collect();
+
+        assert_eq!(
+            get_history(&events),
+            "2023-03-01T00:00:00Z  created
+2023-03-01T01:00:00Z  ready
+2023-03-01T02:00:00Z  stopped
+2023-03-01T03:00:00Z  woke up
+2023-03-01T04:00:00Z  errored: container went away
+2023-03-01T05:00:00Z  recovered
+"
+        );
+    }
+}
@@ -1,4 +1,4 @@
-use std::fmt::Display;
+use std::{collections::BTreeMap, fmt::Display};
 
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
@@ -11,6 +11,9 @@ pub struct Response {
     pub service_id: Uuid,
     pub r#type: Type,
     pub data: Value,
+    /// Key-value pairs the service tagged the resource with
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
 }
 
 /// Trait used to get information from all the resources we manage
@@ -0,0 +1,28 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+
+use serde::{Deserialize, Serialize};
+
+/// Rules for the headers of responses from a project, by lowercase header name. Headers without a rule are sent to
+/// clients as the service gave them.
+pub type Rules = BTreeMap<String, Rule>;
+
+/// What the proxy does with one header of the responses of a project
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(tag = "action", content = "value", rename_all = "lowercase")]
+pub enum Rule {
+    /// Always send this value, replacing whatever the service sent
+    Set(String),
+
+    /// Never send the header
+    Remove,
+}
+
+impl Display for Rule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Rule::Set(value) => write!(f, "set to '{value}'"),
+            Rule::Remove => write!(f, "removed"),
+        }
+    }
+}
@@ -1,22 +1,81 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+
 use chrono::{DateTime, Utc};
 use comfy_table::{
     modifiers::UTF8_ROUND_CORNERS, presets::UTF8_FULL, Cell, CellAlignment, ContentArrangement,
     Table,
 };
-use crossterm::style::Stylize;
 use serde::{Deserialize, Serialize};
 
+use crate::style::{style_table, Stylize};
+
 #[derive(Deserialize, Serialize)]
 pub struct Response {
     pub key: String,
     pub last_update: DateTime<Utc>,
 }
 
+/// The full set of secrets a service should have
+#[derive(Deserialize, Serialize)]
+pub struct SyncRequest {
+    pub secrets: BTreeMap<String, String>,
+    /// Remove the stored secrets which are not in `secrets`, instead of leaving them alone
+    pub prune: bool,
+}
+
+/// What a sync changes, or would change, by key. Values are never sent back.
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct SyncDiff {
+    pub added: Vec<String>,
+    pub changed: Vec<String>,
+    pub removed: Vec<String>,
+    /// Stored secrets which are not in the request, and are kept since it does not prune
+    pub unmanaged: Vec<String>,
+    /// Whether the running deployment was restarted to load the changed secrets, as its restart policy asks
+    #[serde(default)]
+    pub restarted: bool,
+}
+
+impl SyncDiff {
+    /// Whether the sync leaves the stored secrets as they are
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.changed.is_empty() && self.removed.is_empty()
+    }
+}
+
+impl Display for SyncDiff {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for key in &self.added {
+            writeln!(f, "{}", format!("+ {key} = ********").green())?;
+        }
+
+        for key in &self.changed {
+            writeln!(f, "{}", format!("~ {key} = ********").yellow())?;
+        }
+
+        for key in &self.removed {
+            writeln!(f, "{}", format!("- {key}").red())?;
+        }
+
+        for key in &self.unmanaged {
+            writeln!(
+                f,
+                "{}",
+                format!("  {key} (not in the file, kept without --prune)").dim()
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
 pub fn get_table(secrets: &Vec<Response>) -> String {
     if secrets.is_empty() {
         format!("{}\n", "No secrets are linked to this service".bold())
     } else {
         let mut table = Table::new();
+        style_table(&mut table);
         table
             .load_preset(UTF8_FULL)
             .apply_modifier(UTF8_ROUND_CORNERS)
@@ -1,5 +1,6 @@
 use crate::{
     models::{deployment, resource, resource::ResourceInfo, secret},
+    style::{style_table, Stylize},
     DatabaseReadyInfo,
 };
 
@@ -7,7 +8,6 @@ use comfy_table::{
     modifiers::UTF8_ROUND_CORNERS, presets::UTF8_FULL, Cell, CellAlignment, ContentArrangement,
     Table,
 };
-use crossterm::style::Stylize;
 use serde::{Deserialize, Serialize};
 use std::fmt::Display;
 use uuid::Uuid;
@@ -94,6 +94,7 @@ fn get_deployments_table(deployments: &Vec<deployment::Response>, service_name:
         )
     } else {
         let mut table = Table::new();
+        style_table(&mut table);
         table
             .load_preset(UTF8_FULL)
             .apply_modifier(UTF8_ROUND_CORNERS)
@@ -132,6 +133,7 @@ fn get_resources_table(resources: &Vec<resource::Response>) -> String {
         format!("{}\n", "No resources are linked to this service".bold())
     } else {
         let mut table = Table::new();
+        style_table(&mut table);
         table
             .load_preset(UTF8_FULL)
             .apply_modifier(UTF8_ROUND_CORNERS)
@@ -139,12 +141,20 @@ fn get_resources_table(resources: &Vec<resource::Response>) -> String {
             .set_header(vec![
                 Cell::new("Type").set_alignment(CellAlignment::Center),
                 Cell::new("Connection string").set_alignment(CellAlignment::Center),
+                Cell::new("Metadata").set_alignment(CellAlignment::Center),
             ]);
 
         for resource in resources.iter() {
+            let metadata: Vec<_> = resource
+                .metadata
+                .iter()
+                .map(|(key, value)| format!("{key}={value}"))
+                .collect();
+
             table.add_row(vec![
                 resource.r#type.to_string(),
                 resource.get_resource_info().connection_string_public(),
+                metadata.join("\n"),
             ]);
         }
 
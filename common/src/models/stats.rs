@@ -1,6 +1,12 @@
+use comfy_table::{
+    modifiers::UTF8_ROUND_CORNERS, presets::UTF8_FULL, Cell, CellAlignment, ContentArrangement,
+    Table,
+};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use crate::style::{style_table, Stylize};
+
 #[derive(Deserialize, Serialize)]
 pub struct LoadRequest {
     pub id: Uuid,
@@ -11,3 +17,77 @@ pub struct LoadResponse {
     pub builds_count: usize,
     pub has_capacity: bool,
 }
+
+/// Traffic the proxy saw going to a project over the last `seconds`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TrafficWindow {
+    pub seconds: u64,
+    pub requests: u64,
+    /// Requests answered with a 5xx status
+    pub errors: u64,
+    pub p50_ms: Option<u64>,
+    pub p95_ms: Option<u64>,
+}
+
+impl TrafficWindow {
+    pub fn error_rate(&self) -> f64 {
+        if self.requests == 0 {
+            0.0
+        } else {
+            self.errors as f64 / self.requests as f64
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TrafficResponse {
+    pub windows: Vec<TrafficWindow>,
+}
+
+pub fn get_traffic_table(traffic: &TrafficResponse) -> String {
+    if traffic.windows.iter().all(|window| window.requests == 0) {
+        return format!("{}\n", "No recent traffic to this project".bold());
+    }
+
+    let mut table = Table::new();
+    style_table(&mut table);
+    table
+        .load_preset(UTF8_FULL)
+        .apply_modifier(UTF8_ROUND_CORNERS)
+        .set_content_arrangement(ContentArrangement::DynamicFullWidth)
+        .set_header(vec![
+            Cell::new("Window").set_alignment(CellAlignment::Center),
+            Cell::new("Requests").set_alignment(CellAlignment::Center),
+            Cell::new("Error rate").set_alignment(CellAlignment::Center),
+            Cell::new("p50").set_alignment(CellAlignment::Center),
+            Cell::new("p95").set_alignment(CellAlignment::Center),
+        ]);
+
+    let latency = |ms: Option<u64>| ms.map(|ms| format!("≤ {ms}ms")).unwrap_or_default();
+
+    for window in traffic.windows.iter() {
+        let seconds = window.seconds;
+        let span = if seconds % 3600 == 0 {
+            format!("{}h", seconds / 3600)
+        } else if seconds % 60 == 0 {
+            format!("{}m", seconds / 60)
+        } else {
+            format!("{seconds}s")
+        };
+
+        table.add_row(vec![
+            span,
+            window.requests.to_string(),
+            format!("{:.1}%", window.error_rate() * 100.0),
+            latency(window.p50_ms),
+            latency(window.p95_ms),
+        ]);
+    }
+
+    format!(
+        r#"Traffic to this project
+{}
+"#,
+        table
+    )
+}
@@ -0,0 +1,60 @@
+use std::fmt::{Display, Formatter};
+
+use serde::{Deserialize, Serialize};
+
+/// Longest any of the timeouts can be set to, which is also the most the slow request threshold can be
+pub const MAX_SECS: u64 = 24 * 60 * 60;
+
+/// How long the proxy waits on the backend of a project. Nothing is timed out by default.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Config {
+    /// Seconds the backend gets to send the headers of its response. Requests which get nothing back in time are
+    /// answered with a 504.
+    pub response_secs: Option<u64>,
+
+    /// What happens to a response whose body stalls once its headers were passed on. A clean 504 cannot be sent at
+    /// that point, so the connection is closed on the client instead of leaving it hanging.
+    #[serde(default)]
+    pub stalled_body: StalledBody,
+
+    /// Milliseconds after which a request, counted until the backend sent the headers of its response, is logged as
+    /// slow. The threshold of the gateway applies when not set.
+    #[serde(default)]
+    pub slow_request_ms: Option<u64>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+pub enum StalledBody {
+    /// Keep passing the body on, and close the connection once it goes `idle_secs` without sending anything
+    Wait { idle_secs: u64 },
+
+    /// Hold the whole response, body included, to `response_secs` and abort the connection once they are up
+    Abort,
+
+    /// Wait on the body for as long as it takes
+    #[default]
+    Unlimited,
+}
+
+impl Display for Config {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.response_secs {
+            Some(secs) => write!(f, "response within {secs}s")?,
+            None => write!(f, "no response timeout")?,
+        }
+
+        match &self.stalled_body {
+            StalledBody::Wait { idle_secs } => {
+                write!(f, ", close stalled bodies after {idle_secs}s without data")?
+            }
+            StalledBody::Abort => write!(f, ", abort bodies still streaming at the deadline")?,
+            StalledBody::Unlimited => write!(f, ", wait on stalled bodies")?,
+        }
+
+        match self.slow_request_ms {
+            Some(ms) => write!(f, ", log requests slower than {ms}ms"),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,65 @@
+use comfy_table::{
+    modifiers::UTF8_ROUND_CORNERS, presets::UTF8_FULL, Cell, CellAlignment, ContentArrangement,
+    Table,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::style::style_table;
+
+/// What an account currently uses of the platform, next to what it is allowed
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Response {
+    pub account_name: String,
+    /// Projects owned by the account, not counting destroyed ones
+    pub projects: Quota,
+    /// Projects whose environment is up and able to run deployments
+    pub running_projects: u64,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Quota {
+    pub used: u64,
+    /// Left out when there is no limit
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u64>,
+}
+
+impl Quota {
+    /// Whether nothing more can be used
+    pub fn is_exhausted(&self) -> bool {
+        self.limit.map_or(false, |limit| self.used >= limit)
+    }
+}
+
+pub fn get_usage_table(usage: &Response) -> String {
+    let mut table = Table::new();
+    style_table(&mut table);
+    table
+        .load_preset(UTF8_FULL)
+        .apply_modifier(UTF8_ROUND_CORNERS)
+        .set_content_arrangement(ContentArrangement::DynamicFullWidth)
+        .set_header(vec![
+            Cell::new("Quota").set_alignment(CellAlignment::Center),
+            Cell::new("Used").set_alignment(CellAlignment::Center),
+            Cell::new("Limit").set_alignment(CellAlignment::Center),
+        ]);
+
+    let limit = |quota: &Quota| {
+        quota
+            .limit
+            .map_or_else(|| "unlimited".to_string(), |limit| limit.to_string())
+    };
+
+    table.add_row(vec![
+        "Projects".to_string(),
+        usage.projects.used.to_string(),
+        limit(&usage.projects),
+    ]);
+    table.add_row(vec![
+        "Running projects".to_string(),
+        usage.running_projects.to_string(),
+        String::new(),
+    ]);
+
+    format!("Usage of account '{}':\n{table}\n", usage.account_name)
+}
@@ -0,0 +1,15 @@
+use serde::{Deserialize, Serialize};
+
+/// Version of the API between the CLI and the platform. It is bumped whenever a change to the API needs both sides to
+/// be updated.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// What a server reports about its own version
+#[derive(Deserialize, Serialize)]
+pub struct Response {
+    /// Version of the server's package
+    pub version: String,
+
+    /// The [PROTOCOL_VERSION] the server speaks
+    pub protocol_version: u32,
+}
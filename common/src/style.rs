@@ -0,0 +1,106 @@
+//! Styling of output which follows a single color policy.
+//!
+//! Everything printed with a style goes through [Stylize] from this module rather than the one of crossterm, so that
+//! turning colors off once drops the escape codes from all of it, tables included.
+
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use comfy_table::Table;
+use crossterm::style::{Color, ContentStyle, StyledContent};
+
+static COLORS: AtomicBool = AtomicBool::new(true);
+
+/// Turn styling of output on or off for the whole process
+pub fn set_colors(enabled: bool) {
+    COLORS.store(enabled, Ordering::Relaxed);
+}
+
+/// Whether output is currently styled
+pub fn colors() -> bool {
+    COLORS.load(Ordering::Relaxed)
+}
+
+/// Make a table follow the color policy instead of only checking whether stdout is a terminal
+pub fn style_table(table: &mut Table) {
+    if colors() {
+        table.enforce_styling();
+    } else {
+        table.force_no_tty();
+    }
+}
+
+/// Content which is printed with its style only while colors are on
+#[derive(Clone, Debug)]
+pub struct Styled<D: Display>(StyledContent<D>);
+
+impl<D: Display> Display for Styled<D> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if colors() {
+            self.0.fmt(f)
+        } else {
+            self.0.content().fmt(f)
+        }
+    }
+}
+
+macro_rules! styles {
+    ($($method:ident),*) => {
+        $(
+            fn $method(self) -> Styled<Self::Content> {
+                let Styled(content) = self.into_styled();
+
+                Styled(crossterm::style::Stylize::$method(content))
+            }
+        )*
+    };
+}
+
+/// The subset of crossterm's `Stylize` used for output, giving [Styled] content
+pub trait Stylize: Sized {
+    type Content: Display;
+
+    fn into_styled(self) -> Styled<Self::Content>;
+
+    fn with(self, color: Color) -> Styled<Self::Content> {
+        let Styled(content) = self.into_styled();
+
+        Styled(crossterm::style::Stylize::with(content, color))
+    }
+
+    styles!(
+        bold,
+        dim,
+        red,
+        green,
+        yellow,
+        dark_yellow,
+        blue,
+        cyan,
+        magenta
+    );
+}
+
+impl<'a> Stylize for &'a str {
+    type Content = &'a str;
+
+    fn into_styled(self) -> Styled<Self::Content> {
+        Styled(StyledContent::new(ContentStyle::new(), self))
+    }
+}
+
+impl Stylize for String {
+    type Content = String;
+
+    fn into_styled(self) -> Styled<Self::Content> {
+        Styled(StyledContent::new(ContentStyle::new(), self))
+    }
+}
+
+impl<D: Display> Stylize for Styled<D> {
+    type Content = D;
+
+    fn into_styled(self) -> Styled<Self::Content> {
+        self
+    }
+}
@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS log_forwarding (
+    service_id TEXT PRIMARY KEY, -- Identifier of the service whose logs are forwarded.
+    sink TEXT,                   -- Type of external sink the logs are sent to.
+    endpoint TEXT,               -- Where the sink can be reached.
+    auth TEXT,                   -- Encrypted authorization value to present to the sink.
+    last_update INTEGER,         -- Unix epoch of the last configuration update
+    FOREIGN KEY(service_id) REFERENCES services(id)
+);
@@ -0,0 +1 @@
+ALTER TABLE deployments ADD COLUMN pre_stop_hook TEXT; -- Path on the service to call before the deployment is stopped
@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS deployment_tags (
+    service_id TEXT,    -- Identifier of the service the tag belongs to.
+    tag TEXT,           -- Symbolic name, like `latest`, which moves between deployments.
+    deployment_id TEXT, -- Identifier of the deployment the tag currently points to.
+    PRIMARY KEY (service_id, tag),
+    FOREIGN KEY(service_id) REFERENCES services(id),
+    FOREIGN KEY(deployment_id) REFERENCES deployments(id)
+);
@@ -0,0 +1 @@
+ALTER TABLE deployments ADD COLUMN peak_memory INTEGER; -- Highest resident memory in bytes reached by the latest run of the deployment
@@ -0,0 +1 @@
+CREATE INDEX IF NOT EXISTS deployments_by_service_state ON deployments (service_id, state, last_update); -- Listing the deployments of a service in some states and time range
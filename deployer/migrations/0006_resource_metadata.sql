@@ -0,0 +1 @@
+ALTER TABLE resources ADD COLUMN metadata TEXT NOT NULL DEFAULT '{}'; -- Key-value pairs the service tagged this resource with.
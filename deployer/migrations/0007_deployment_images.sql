@@ -0,0 +1 @@
+ALTER TABLE deployments ADD COLUMN image TEXT; -- Container image the deployment runs instead of a library built from its source
@@ -0,0 +1 @@
+ALTER TABLE deployments ADD COLUMN run_budget INTEGER; -- Seconds a deployment may run for before it is stopped, unbounded when NULL
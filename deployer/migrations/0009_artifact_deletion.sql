@@ -0,0 +1 @@
+ALTER TABLE deployments ADD COLUMN artifact_deleted_at DATETIME; -- When the artifact and logs of the deployment were removed, NULL while they are kept
@@ -0,0 +1 @@
+ALTER TABLE deployments ADD COLUMN expires_at DATETIME; -- When the deployment is stopped for outliving its time to live, never when NULL
@@ -0,0 +1 @@
+ALTER TABLE deployments ADD COLUMN locked BOOLEAN; -- Whether the deployment is built with --locked, unknown for deployments made before this was recorded
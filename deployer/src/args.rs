@@ -1,9 +1,9 @@
 use std::{net::SocketAddr, path::PathBuf};
 
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use fqdn::FQDN;
 use hyper::Uri;
-use shuttle_common::{project::ProjectName, Port};
+use shuttle_common::{deployment::Environment, project::ProjectName, Port};
 
 /// Program to handle the deploys for a single project
 /// Handling includes, building, testing, and running each service
@@ -50,7 +50,112 @@ pub struct Args {
     #[clap(long, default_value = "http://127.0.0.1:8008")]
     pub auth_uri: Uri,
 
+    /// Key used to encrypt sensitive configuration values at rest. Derived from the admin secret when not set
+    #[clap(long)]
+    pub encryption_key: Option<String>,
+
     /// Uri to folder to store all artifacts
     #[clap(long, default_value = "/tmp")]
     pub artifacts_path: PathBuf,
+
+    /// Environment reported to services by `Factory::get_environment()`. Only meant to be changed for testing
+    #[clap(long, default_value = "production")]
+    pub environment_kind: Environment,
+
+    /// Maximum number of lines a deployment may log per second. Lines above it are dropped
+    #[clap(long, default_value = "1000")]
+    pub log_rate_limit: u32,
+
+    /// Load every built artifact once, with all of its symbols resolved, before it is run. Artifacts which fail to
+    /// load make their deployment crash with a validation error instead of failing on start up or mid-run
+    #[clap(long)]
+    pub dry_load: bool,
+
+    /// Build every deployment in a directory of its own, which is removed once the build is done. Concurrent builds
+    /// then never share sources or a target directory, at the cost of building every dependency from scratch
+    #[clap(long)]
+    pub isolate_builds: bool,
+
+    /// Write an access log line for one in this many proxied requests. Requests of the same trace are either all
+    /// logged or all skipped. Access logging is off when 0
+    #[clap(long, default_value = "0")]
+    pub access_log_sample: u32,
+
+    /// Write an access log line for every request failing with a server error, even when it is not sampled
+    #[clap(long)]
+    pub access_log_keep_errors: bool,
+
+    /// Number of kill signals running deployments can fall behind on. Every deployment sees the kill signals of all the
+    /// others, and one falling further behind has to look up whether it was killed itself
+    #[clap(long, default_value = "10")]
+    pub kill_buffer_size: usize,
+
+    /// Megabytes of built artifacts to keep in memory, so that loading them again does not wait on the disk. The
+    /// artifacts of running deployments are always kept. Off when not set
+    #[clap(long)]
+    pub warm_cache_size: Option<u64>,
+
+    /// Allow CPU profiles to be captured from running deployments through the API. Sampling slows the deployer down
+    /// while a profile is being captured, so this is off by default
+    #[clap(long)]
+    pub allow_profiling: bool,
+
+    /// Drain the deployment of a service which is deployed again: the proxy sends new requests to the new deployment as
+    /// soon as it runs, while the one it replaces gets this many seconds to finish the requests it is serving before it
+    /// is stopped. Without it, the old deployment is stopped right before the new one starts running
+    #[clap(long)]
+    pub drain_replaced_secs: Option<u64>,
+
+    /// What to do with running deployments when the deployer is told to terminate
+    #[clap(long, value_enum, default_value = "stop")]
+    pub shutdown_policy: ShutdownPolicy,
+
+    /// Seconds to wait on running deployments to stop before exiting anyway when the deployer is told to terminate
+    #[clap(long, default_value = "30")]
+    pub shutdown_timeout: u64,
+
+    /// CLI of a container engine, like `docker` or `podman`, to run deployments of a pre-built container image with.
+    /// Such deployments are refused without one
+    #[clap(long)]
+    pub image_runtime: Option<PathBuf>,
+
+    /// Network image containers are attached to. The port a container is told to listen on in `PORT` is published on
+    /// the loopback address of the host, except on the `host` network, which containers only share with the host when
+    /// it is asked for explicitly
+    #[clap(long, default_value = "bridge")]
+    pub image_network: String,
+
+    /// Address the gateway can reach the proxy of this deployer at, when it is an extra instance of its project rather
+    /// than the container of the project. It is registered with the gateway while a deployment is running
+    #[clap(long)]
+    pub instance_address: Option<SocketAddr>,
+
+    /// Share of the requests of the project this instance gets, relative to the other instances
+    #[clap(long, default_value = "1", value_parser = clap::value_parser!(u32).range(1..))]
+    pub instance_weight: u32,
+
+    /// Hosts the archives of deployments created through the v1 API may be downloaded from. Archives are only downloaded
+    /// over https and never from hosts resolving to private, loopback or link-local addresses. Such deployments are
+    /// refused when no host is set
+    #[clap(long, value_delimiter = ',')]
+    pub archive_hosts: Vec<String>,
+
+    /// Megabytes the archive of a deployment may have at most, when it is downloaded through the v1 API or uploaded in
+    /// chunks
+    #[clap(long, default_value = "100")]
+    pub archive_size_limit: u64,
+
+    /// Number of threads the async runtime runs tasks on, deployments included. One for every CPU core when not set
+    #[clap(long)]
+    pub worker_threads: Option<usize>,
+}
+
+/// How running deployments are dealt with when the deployer shuts down. Either way they keep their running state, so
+/// they are started again once the deployer is back.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ShutdownPolicy {
+    /// Call the pre-stop hook of every deployment and stop it before exiting
+    Stop,
+    /// Leave deployments running until the process exits
+    Detach,
 }
@@ -0,0 +1,50 @@
+//! Tells the gateway when what serves the project changes.
+//!
+//! The gateway keeps pooled keep-alive connections to the proxy of the deployer. Whenever a deployment starts or stops
+//! running, the gateway is told to drop them so that no request goes out on a connection which was made for the
+//! deployment before.
+
+use shuttle_common::project::ProjectName;
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+use tracing::{debug, error, warn};
+
+use super::deploy_layer::{self, LogType};
+use super::gateway_client::GatewayClient;
+use crate::persistence::State;
+
+/// Run a task which tells the gateway to drop its pooled connections to `project` every time one of its deployments
+/// starts or stops running
+pub async fn task(
+    mut log_recv: Receiver<deploy_layer::Log>,
+    gateway_client: GatewayClient,
+    project: ProjectName,
+) {
+    let path = format!("backends/{project}");
+
+    loop {
+        match log_recv.recv().await {
+            Ok(log)
+                if log.r#type == LogType::State
+                    && matches!(
+                        log.state,
+                        State::Running | State::Stopped | State::Completed | State::Crashed
+                    ) =>
+            {
+                debug!(id = %log.id, state = %log.state, "backend of the project changed");
+            }
+            Ok(_) => continue,
+            // One of the skipped logs might have been a state change
+            Err(RecvError::Lagged(skipped)) => {
+                warn!(skipped, "backend events fell behind the log stream");
+            }
+            Err(RecvError::Closed) => break,
+        }
+
+        if let Err(error) = gateway_client.delete::<(), ()>(&path, None).await {
+            error!(
+                error = &error as &dyn std::error::Error,
+                "failed to tell the gateway the backend of the project changed"
+            );
+        }
+    }
+}
@@ -0,0 +1,166 @@
+//! Builds which run in a process of their own, so that a cancelled build can be stopped in the middle of compiling
+//! rather than once cargo is done.
+//!
+//! The process is the deployer executable started again with [BUILD_REQUEST_ENV] set to what it should build. It passes
+//! the messages of cargo on over its stdout, and ends its stderr with the path of the built library or with the error
+//! the build failed with.
+
+use std::io::{self, BufReader, Read, Write};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use cargo_metadata::Message;
+use crossbeam_channel::Sender;
+use serde::{Deserialize, Serialize};
+use shuttle_service::loader::build_crate;
+use tracing::{error, trace, warn};
+use uuid::Uuid;
+
+use super::queue::Cancel;
+use crate::error::{Error, Result};
+
+/// Holds the build to run when the deployer executable is started as a build process
+const BUILD_REQUEST_ENV: &str = "SHUTTLE_DEPLOYER_BUILD_REQUEST";
+
+#[derive(Deserialize, Serialize)]
+struct BuildRequest {
+    deployment_id: Uuid,
+    project_path: PathBuf,
+    locked: bool,
+}
+
+/// Build the crate when this process was started as a build process, returning the code to exit with. Returns `None`
+/// when it was started as a deployer.
+pub fn run_if_requested() -> Option<i32> {
+    let request = std::env::var(BUILD_REQUEST_ENV).ok()?;
+
+    let result = serde_json::from_str::<BuildRequest>(&request)
+        .map_err(anyhow::Error::from)
+        .and_then(build_in_this_process);
+
+    Some(match result {
+        Ok(so_path) => {
+            eprintln!("{}", so_path.display());
+            0
+        }
+        Err(error) => {
+            eprintln!("{error:#}");
+            1
+        }
+    })
+}
+
+fn build_in_this_process(request: BuildRequest) -> anyhow::Result<PathBuf> {
+    // `build_crate` spawns threads of its own which do not start on a current thread runtime
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+
+    let (tx, rx) = crossbeam_channel::unbounded::<Message>();
+    let forward = std::thread::spawn(move || {
+        let mut stdout = io::stdout().lock();
+
+        for message in rx {
+            if serde_json::to_writer(&mut stdout, &message).is_err() || writeln!(stdout).is_err() {
+                break;
+            }
+        }
+    });
+
+    let result = runtime.block_on(build_crate(
+        request.deployment_id,
+        &request.project_path,
+        true,
+        request.locked,
+        tx,
+    ));
+    let _ = forward.join();
+
+    result
+}
+
+/// Build the crate at `project_path` in a process of `executable`. Cancelling `cancel` kills the process along with the
+/// compiler processes it started, and the build then fails with [Error::Cancelled].
+pub async fn build(
+    executable: &Path,
+    deployment_id: Uuid,
+    project_path: &Path,
+    locked: bool,
+    tx: Sender<Message>,
+    cancel: &Cancel,
+) -> Result<PathBuf> {
+    let request = serde_json::to_string(&BuildRequest {
+        deployment_id,
+        project_path: project_path.to_path_buf(),
+        locked,
+    })
+    .expect("a build request to serialize");
+
+    let mut child = Command::new(executable)
+        .env(BUILD_REQUEST_ENV, request)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        // A group of its own, so that killing it also kills the rustc processes cargo starts
+        .process_group(0)
+        .spawn()?;
+    let process_group = child.id() as libc::pid_t;
+    trace!(process_group, "started build process");
+
+    let stdout = child.stdout.take().expect("stdout to be piped");
+    let mut stderr = child.stderr.take().expect("stderr to be piped");
+
+    let messages = tokio::task::spawn_blocking(move || {
+        for message in Message::parse_stream(BufReader::new(stdout)) {
+            match message {
+                Ok(message) => {
+                    if let Err(error) = tx.send(message) {
+                        error!("failed to send cargo message on channel: {error}");
+                    }
+                }
+                Err(error) => {
+                    error!("failed to parse cargo message: {error}");
+                }
+            }
+        }
+    });
+    let output = tokio::task::spawn_blocking(move || {
+        let mut output = String::new();
+        stderr.read_to_string(&mut output).map(|_| output)
+    });
+    let mut status = tokio::task::spawn_blocking(move || child.wait());
+
+    let status = tokio::select! {
+        status = &mut status => status,
+        _ = cancel.cancelled() => {
+            // SAFETY: only signals the process group the build process was started in
+            if unsafe { libc::killpg(process_group, libc::SIGKILL) } != 0 {
+                warn!(
+                    error = &io::Error::last_os_error() as &dyn std::error::Error,
+                    "failed to kill the build process"
+                );
+            }
+
+            let _ = status.await;
+            let _ = messages.await;
+
+            return Err(Error::Cancelled);
+        }
+    };
+
+    let status = status.map_err(|error| Error::Build(Box::new(error)))??;
+    let _ = messages.await;
+    let output = output
+        .await
+        .map_err(|error| Error::Build(Box::new(error)))??;
+    let last_line = output.lines().last().unwrap_or_default().to_string();
+
+    if status.success() {
+        Ok(PathBuf::from(last_line))
+    } else {
+        Err(Error::Build(
+            anyhow::anyhow!("{}", output.trim_end()).into(),
+        ))
+    }
+}
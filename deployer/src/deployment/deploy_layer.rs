@@ -364,8 +364,8 @@ mod tests {
     use crate::{
         deployment::{
             deploy_layer::LogType, gateway_client::BuildQueueClient, provisioner_factory,
-            runtime_logger, storage_manager::StorageManager, ActiveDeploymentsGetter, Built,
-            DeploymentManager, Queued,
+            runtime_logger, storage_manager::StorageManager, ActiveDeploymentsGetter,
+            BuildRecorder, Built, DeploymentManager, Queued,
         },
         persistence::{SecretRecorder, State},
     };
@@ -443,6 +443,23 @@ mod tests {
         ) -> Result<(), Self::Err> {
             panic!("no tests should set secrets")
         }
+
+        async fn replace_secrets(
+            &self,
+            _service_id: &Uuid,
+            _secrets: &BTreeMap<String, String>,
+        ) -> Result<(), Self::Err> {
+            panic!("no tests should set secrets")
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl BuildRecorder for Arc<Mutex<RecorderMock>> {
+        type Err = std::io::Error;
+
+        async fn record_locked(&self, _id: &Uuid, _locked: bool) -> Result<(), Self::Err> {
+            Ok(())
+        }
     }
 
     impl<R: LogRecorder> LogRecorder for Arc<Mutex<R>> {
@@ -893,6 +910,10 @@ mod tests {
                 service_id: Uuid::new_v4(),
                 tracing_context: Default::default(),
                 claim: None,
+                pre_stop_hook: None,
+                image: None,
+                run_budget: None,
+                expires_at: None,
             })
             .await;
 
@@ -930,6 +951,83 @@ mod tests {
         );
     }
 
+    #[tokio::test(flavor = "multi_thread")]
+    async fn deployment_cancelled() {
+        let deployment_manager = get_deployment_manager();
+
+        let queued = get_queue("sleep-async");
+        let id = queued.id;
+        deployment_manager.queue_push(queued).await;
+
+        let building = async {
+            loop {
+                let states = RECORDER.lock().unwrap().get_deployment_states(&id);
+
+                if states.iter().any(|log| log.state == State::Building) {
+                    break;
+                }
+
+                sleep(Duration::from_millis(50)).await;
+            }
+        };
+
+        select! {
+            _ = sleep(Duration::from_secs(60)) => {
+                panic!("deployment should start building");
+            }
+            _ = building => {}
+        }
+
+        assert!(deployment_manager.cancel(id).await);
+
+        // The build only stops once cargo is done with the step it is on
+        let cancelled = async {
+            loop {
+                let states = RECORDER.lock().unwrap().get_deployment_states(&id);
+
+                if states.iter().any(|log| log.state == State::Cancelled) {
+                    break;
+                }
+
+                sleep(Duration::from_millis(50)).await;
+            }
+        };
+
+        select! {
+            _ = sleep(Duration::from_secs(120)) => {
+                panic!("deployment should be cancelled");
+            }
+            _ = cancelled => {}
+        }
+        sleep(Duration::from_secs(1)).await;
+
+        assert!(!deployment_manager.cancel(id).await);
+
+        let recorder = RECORDER.lock().unwrap();
+        let states = recorder.get_deployment_states(&id);
+
+        assert_eq!(
+            *states,
+            vec![
+                StateLog {
+                    id,
+                    state: State::Queued,
+                    has_address: false,
+                },
+                StateLog {
+                    id,
+                    state: State::Building,
+                    has_address: false,
+                },
+                StateLog {
+                    id,
+                    state: State::Cancelled,
+                    has_address: false,
+                },
+            ]
+        );
+    }
+
     #[tokio::test]
     async fn scope_with_nil_id() {
         let deployment_manager = get_deployment_manager();
@@ -944,6 +1042,11 @@ mod tests {
                 will_run_tests: false,
                 tracing_context: Default::default(),
                 claim: None,
+                pre_stop_hook: None,
+                crate_path: None,
+                locked: false,
+                run_budget: None,
+                expires_at: None,
             })
             .await;
 
@@ -965,6 +1068,7 @@ mod tests {
             .runtime_logger_factory(StubRuntimeLoggerFactory)
             .build_log_recorder(RECORDER.clone())
             .secret_recorder(RECORDER.clone())
+            .build_recorder(RECORDER.clone())
             .active_deployment_getter(StubActiveDeploymentGetter)
             .artifacts_path(PathBuf::from("/tmp"))
             .queue_client(StubBuildQueueClient)
@@ -1001,6 +1105,11 @@ mod tests {
             will_run_tests: false,
             tracing_context: Default::default(),
             claim: None,
+            pre_stop_hook: None,
+            crate_path: None,
+            locked: false,
+            run_budget: None,
+            expires_at: None,
         }
     }
 }
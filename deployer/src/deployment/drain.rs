@@ -0,0 +1,192 @@
+//! Draining of the deployment a service is running on when a new one replaces it.
+//!
+//! Clients never connect to a deployment directly: the proxy of the deployer holds the listening socket and picks the
+//! deployment for every request. No socket is passed between deployments; the proxy sends new requests to the new
+//! deployment as soon as it runs, while it counts the requests the old deployment is still serving so that the old
+//! deployment is only stopped once they are done.
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use once_cell::sync::Lazy;
+use tokio::sync::Notify;
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+/// Requests the proxy is in the middle of, by the address of the deployment serving them
+pub static IN_FLIGHT: Lazy<InFlight> = Lazy::new(InFlight::default);
+
+#[derive(Default)]
+pub struct InFlight {
+    counts: Mutex<HashMap<SocketAddr, usize>>,
+    changed: Notify,
+}
+
+/// Counts a request as in flight until it is dropped
+pub struct InFlightGuard<'a> {
+    in_flight: &'a InFlight,
+    address: SocketAddr,
+}
+
+impl InFlight {
+    /// Count a request to the deployment at `address` until the returned guard is dropped
+    pub fn track(&self, address: SocketAddr) -> InFlightGuard<'_> {
+        *self.counts.lock().unwrap().entry(address).or_default() += 1;
+
+        InFlightGuard {
+            in_flight: self,
+            address,
+        }
+    }
+
+    pub fn count(&self, address: SocketAddr) -> usize {
+        self.counts
+            .lock()
+            .unwrap()
+            .get(&address)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Wait for the requests to the deployment at `address` to be done, for at most `timeout`. Returns whether they
+    /// all were.
+    pub async fn drained(&self, address: SocketAddr, timeout: Duration) -> bool {
+        tokio::time::timeout(timeout, async {
+            loop {
+                // Made before checking, so that a request finishing in between is not missed
+                let changed = self.changed.notified();
+
+                if self.count(address) == 0 {
+                    return;
+                }
+
+                changed.await;
+            }
+        })
+        .await
+        .is_ok()
+    }
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        let mut counts = self.in_flight.counts.lock().unwrap();
+
+        if let Some(count) = counts.get_mut(&self.address) {
+            *count -= 1;
+
+            if *count == 0 {
+                counts.remove(&self.address);
+            }
+        }
+        drop(counts);
+
+        self.in_flight.changed.notify_waiters();
+    }
+}
+
+/// Deployments which are being replaced, and so are drained rather than stopped right away
+#[derive(Clone, Debug)]
+pub struct DrainReplaced {
+    drain: Duration,
+    replaced: Arc<Mutex<HashSet<Uuid>>>,
+}
+
+impl DrainReplaced {
+    /// Give replaced deployments up to `drain` to finish the requests they are serving
+    pub fn new(drain: Duration) -> Self {
+        Self {
+            drain,
+            replaced: Default::default(),
+        }
+    }
+
+    /// Mark a deployment as replaced, before it is told to stop
+    pub fn replace(&self, id: Uuid) {
+        self.replaced.lock().unwrap().insert(id);
+    }
+
+    /// Let a deployment which is told to stop finish the requests `in_flight` counts for it first, if it is being
+    /// replaced
+    pub async fn drain(&self, in_flight: &InFlight, id: Uuid, address: SocketAddr) {
+        if !self.replaced.lock().unwrap().remove(&id) {
+            return;
+        }
+
+        debug!(%id, in_flight = in_flight.count(address), "draining replaced deployment");
+
+        if !in_flight.drained(address, self.drain).await {
+            warn!(
+                %id,
+                in_flight = in_flight.count(address),
+                "replaced deployment did not finish its requests within {}s, stopping it anyway",
+                self.drain.as_secs()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use futures::FutureExt;
+    use uuid::Uuid;
+
+    use super::{DrainReplaced, InFlight};
+
+    #[tokio::test]
+    async fn drained() {
+        let in_flight = InFlight::default();
+        let address: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+        let other: SocketAddr = "127.0.0.1:9001".parse().unwrap();
+
+        assert!(in_flight.drained(address, Duration::ZERO).await);
+
+        let first = in_flight.track(address);
+        let second = in_flight.track(address);
+        let _other = in_flight.track(other);
+        assert_eq!(in_flight.count(address), 2);
+
+        drop(first);
+        assert!(!in_flight.drained(address, Duration::from_millis(50)).await);
+
+        tokio::join!(
+            async {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                drop(second);
+            },
+            async { assert!(in_flight.drained(address, Duration::from_secs(1)).await) }
+        );
+        assert_eq!(in_flight.count(address), 0);
+        assert_eq!(in_flight.count(other), 1);
+    }
+
+    #[tokio::test]
+    async fn only_replaced_deployments_drain() {
+        // Long enough for the drain to only end when the request does
+        let drain_replaced = DrainReplaced::new(Duration::from_secs(3600));
+        let in_flight = InFlight::default();
+        let address: SocketAddr = "127.0.0.1:9002".parse().unwrap();
+        let id = Uuid::new_v4();
+        let request = in_flight.track(address);
+
+        // Stopping a deployment which is not being replaced does not wait on its requests
+        assert!(drain_replaced
+            .drain(&in_flight, id, address)
+            .now_or_never()
+            .is_some());
+
+        drain_replaced.replace(id);
+        let mut draining = Box::pin(drain_replaced.drain(&in_flight, id, address));
+        assert!((&mut draining).now_or_never().is_none());
+
+        drop(request);
+        draining.await;
+        assert_eq!(in_flight.count(address), 0);
+    }
+}
@@ -1,7 +1,9 @@
+use axum::headers::HeaderMapExt;
 use hyper::{body, client::HttpConnector, Body, Client, Method, Request, Uri};
 use opentelemetry::global;
 use opentelemetry_http::HeaderInjector;
 use serde::{de::DeserializeOwned, Serialize};
+use shuttle_common::backends::headers::XShuttleAdminSecret;
 use shuttle_common::models::stats;
 use thiserror::Error;
 use tracing::{trace, Span};
@@ -33,13 +35,16 @@ pub trait BuildQueueClient: Clone + Send + Sync + 'static {
 pub struct GatewayClient {
     client: Client<HttpConnector>,
     base: Uri,
+    /// The secret the gateway started this deployer with, which it checks to let the deployer act on its project
+    admin_secret: String,
 }
 
 impl GatewayClient {
-    pub fn new(uri: Uri) -> Self {
+    pub fn new(uri: Uri, admin_secret: String) -> Self {
         Self {
             client: Client::new(),
             base: uri,
+            admin_secret,
         }
     }
 
@@ -75,10 +80,13 @@ impl GatewayClient {
             .uri(uri)
             .header("Content-Type", "application/json");
 
+        let headers = req.headers_mut().unwrap();
+        headers.typed_insert(XShuttleAdminSecret(self.admin_secret.clone()));
+
         let cx = Span::current().context();
 
         global::get_text_map_propagator(|propagator| {
-            propagator.inject_context(&cx, &mut HeaderInjector(req.headers_mut().unwrap()))
+            propagator.inject_context(&cx, &mut HeaderInjector(headers))
         });
 
         let req = if let Some(body) = body {
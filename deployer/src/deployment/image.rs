@@ -0,0 +1,208 @@
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    process::Stdio,
+};
+
+use anyhow::anyhow;
+use shuttle_common::log::{Stream, STREAM_FIELD};
+use shuttle_service::{Logger, ServeHandle};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, BufReader},
+    process::Command,
+};
+use tracing::{info, instrument, warn, Dispatch};
+use tracing_subscriber::{layer::SubscriberExt, registry};
+use uuid::Uuid;
+
+use crate::error::Result;
+
+/// Network which has containers share the network of the host instead of being isolated from it
+const HOST_NETWORK: &str = "host";
+
+/// Runs deployments from a container image which was built elsewhere, through the CLI of a container engine like
+/// `docker` or `podman`
+#[derive(Clone, Debug)]
+pub struct ImageRuntime {
+    cli: PathBuf,
+    network: String,
+}
+
+impl ImageRuntime {
+    /// Containers are attached to `network`, as it is given to `--network`. The port they listen on is published to
+    /// the deployer, unless `network` is `host`, which has them listen on the host itself.
+    pub fn new(cli: PathBuf, network: String) -> Self {
+        Self { cli, network }
+    }
+
+    /// Start the container of a deployment. It is told the port to listen on in `PORT`, and gets the secrets of its
+    /// service as environment variables.
+    ///
+    /// The container is removed once the returned handle is done or aborted. Anything it prints goes to the logs of
+    /// the deployment.
+    #[instrument(skip(self, secrets, logger))]
+    pub async fn start(
+        &self,
+        id: &Uuid,
+        image: &str,
+        address: SocketAddr,
+        secrets: BTreeMap<String, String>,
+        logger: Logger,
+    ) -> Result<ServeHandle> {
+        let container = Container {
+            cli: self.cli.clone(),
+            name: format!("shuttle-{id}"),
+        };
+
+        // A container of an earlier run is left behind when the deployer itself was killed
+        container.remove().await;
+
+        let mut command = Command::new(&self.cli);
+        command
+            .args(["run", "--rm", "--name", &container.name])
+            .args(["--network", &self.network])
+            .args(["--env", "PORT"])
+            .env("PORT", address.port().to_string());
+
+        if self.network != HOST_NETWORK {
+            command.arg("--publish").arg(publish(address));
+        }
+
+        // Values are passed through the environment of the CLI so that they never show up in a process listing
+        for (key, value) in secrets {
+            command.arg("--env").arg(&key).env(key, value);
+        }
+
+        let mut child = command
+            .arg("--")
+            .arg(image)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+
+        let dispatch = Dispatch::new(registry().with(logger));
+        if let Some(stdout) = child.stdout.take() {
+            tokio::spawn(forward_output(stdout, Stream::Stdout, dispatch.clone()));
+        }
+        if let Some(stderr) = child.stderr.take() {
+            tokio::spawn(forward_output(stderr, Stream::Stderr, dispatch));
+        }
+
+        Ok(tokio::spawn(async move {
+            // Moved in so that the container goes away with the task, however it ends
+            let _container = container;
+            let status = child.wait().await?;
+
+            if status.success() {
+                Ok(())
+            } else {
+                Err(anyhow!("container exited with {status}").into())
+            }
+        }))
+    }
+}
+
+/// The `--publish` argument which makes the port of a container reachable at `address` on the host, and nowhere else
+fn publish(address: SocketAddr) -> String {
+    let ip = match address.ip() {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("[{ip}]"),
+    };
+
+    format!("{ip}:{port}:{port}", port = address.port())
+}
+
+/// Send every line of a container's output to the logs of its deployment, tagged with the stream it came from
+async fn forward_output(output: impl AsyncRead + Unpin, stream: Stream, dispatch: Dispatch) {
+    let mut lines = BufReader::new(output).lines();
+
+    while let Ok(Some(line)) = lines.next_line().await {
+        tracing::dispatcher::with_default(
+            &dispatch,
+            || info!({ STREAM_FIELD } = %stream, "{line}"),
+        );
+    }
+}
+
+/// A named container which is removed once dropped
+struct Container {
+    cli: PathBuf,
+    name: String,
+}
+
+impl Container {
+    async fn remove(&self) {
+        let status = Command::new(&self.cli)
+            .args(["rm", "--force", &self.name])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .await;
+
+        if let Err(error) = status {
+            warn!(
+                error = &error as &dyn std::error::Error,
+                "failed to remove container '{}'", self.name
+            );
+        }
+    }
+}
+
+impl Drop for Container {
+    fn drop(&mut self) {
+        // Killing the CLI does not stop the container it started, so the engine is told to remove it instead. Nothing
+        // waits on the command here, which leaves reaping it to the runtime.
+        let spawned = Command::new(&self.cli)
+            .args(["rm", "--force", &self.name])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn();
+
+        if let Err(error) = spawned {
+            warn!(
+                error = &error as &dyn std::error::Error,
+                "failed to remove container '{}'", self.name
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shuttle_common::log::Stream;
+    use shuttle_service::Logger;
+    use tokio::sync::mpsc;
+    use tracing::Dispatch;
+    use tracing_subscriber::{layer::SubscriberExt, registry};
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn forward_output() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let dispatch = Dispatch::new(registry().with(Logger::new(tx, Uuid::new_v4())));
+
+        super::forward_output(&b"panicked\nat the disco\n"[..], Stream::Stderr, dispatch).await;
+
+        for message in ["panicked", "at the disco"] {
+            let log = rx.recv().await.unwrap();
+
+            assert_eq!(log.message().as_deref(), Some(message));
+            assert_eq!(log.stream(), Some(Stream::Stderr));
+        }
+    }
+
+    #[test]
+    fn publish() {
+        assert_eq!(
+            super::publish("127.0.0.1:8000".parse().unwrap()),
+            "127.0.0.1:8000:8000"
+        );
+        assert_eq!(
+            super::publish("[::1]:8000".parse().unwrap()),
+            "[::1]:8000:8000"
+        );
+    }
+}
@@ -0,0 +1,101 @@
+//! Registration of this deployer as an instance of its project with the gateway.
+//!
+//! A project can be served by more than its own container. Each extra deployer registers the address the gateway can
+//! reach its proxy at while it has a deployment running, and the gateway spreads the requests of the project over all
+//! of them. Registrations lapse on the gateway unless they are renewed, so they are sent again every
+//! [RENEW_INTERVAL], which also gets them back after a restart of the gateway.
+
+use std::time::Duration;
+
+use async_trait::async_trait;
+use shuttle_common::{models::instance, project::ProjectName};
+use tokio::{sync::oneshot, time::interval};
+use tracing::{error, info};
+
+use super::gateway_client::{self, GatewayClient};
+
+const RENEW_INTERVAL: Duration = Duration::from_secs(10);
+
+#[async_trait]
+pub trait RunningDeploymentGetter: Clone + Send + Sync + 'static {
+    type Err: std::error::Error + Send;
+
+    /// Whether any deployment is running on this deployer
+    async fn has_running_deployment(&self) -> Result<bool, Self::Err>;
+}
+
+/// Run a task which keeps this deployer registered as an instance of `project` for as long as it has a deployment
+/// running, until `stopped` fires
+pub async fn task(
+    gateway_client: GatewayClient,
+    project: ProjectName,
+    registration: instance::Registration,
+    running_deployment_getter: impl RunningDeploymentGetter,
+    mut stopped: oneshot::Receiver<()>,
+) {
+    let path = format!("instances/{project}");
+    let mut interval = interval(RENEW_INTERVAL);
+    let mut registered = false;
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = &mut stopped => break,
+        }
+
+        let running = match running_deployment_getter.has_running_deployment().await {
+            Ok(running) => running,
+            Err(error) => {
+                error!(
+                    error = &error as &dyn std::error::Error,
+                    "failed to check for a running deployment"
+                );
+                continue;
+            }
+        };
+
+        let result = match (running, registered) {
+            (true, _) => register(&gateway_client, &path, &registration).await,
+            (false, true) => deregister(&gateway_client, &path, &registration).await,
+            (false, false) => continue,
+        };
+
+        match result {
+            Ok(()) => {
+                if running != registered {
+                    info!(address = %registration.address, running, "updated the instance registration");
+                }
+                registered = running;
+            }
+            Err(error) => error!(
+                error = &error as &dyn std::error::Error,
+                "failed to update the instance registration"
+            ),
+        }
+    }
+
+    if registered {
+        if let Err(error) = deregister(&gateway_client, &path, &registration).await {
+            error!(
+                error = &error as &dyn std::error::Error,
+                "failed to deregister the instance"
+            );
+        }
+    }
+}
+
+async fn register(
+    gateway_client: &GatewayClient,
+    path: &str,
+    registration: &instance::Registration,
+) -> Result<(), gateway_client::Error> {
+    gateway_client.post(path, Some(registration)).await
+}
+
+async fn deregister(
+    gateway_client: &GatewayClient,
+    path: &str,
+    registration: &instance::Registration,
+) -> Result<(), gateway_client::Error> {
+    gateway_client.delete(path, Some(registration)).await
+}
@@ -0,0 +1,119 @@
+//! Kill signals for running deployments.
+//!
+//! Every running deployment listens on the same broadcast channel, which lags behind for a deployment when more signals
+//! are sent than it has taken in. So that a deployment cannot miss its own signal, the deployments which were sent one
+//! are also kept in a set until they see it.
+
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::broadcast::{
+    self,
+    error::{RecvError, SendError},
+};
+use tracing::warn;
+use uuid::Uuid;
+
+type Killed = Arc<Mutex<HashSet<Uuid>>>;
+
+#[derive(Clone)]
+pub struct KillSender {
+    send: broadcast::Sender<Uuid>,
+    killed: Killed,
+}
+
+pub struct KillReceiver {
+    recv: broadcast::Receiver<Uuid>,
+    killed: Killed,
+}
+
+/// A kill channel which can have `capacity` signals waiting on its slowest receiver
+pub fn channel(capacity: usize) -> (KillSender, KillReceiver) {
+    let (send, recv) = broadcast::channel(capacity);
+    let killed = Killed::default();
+
+    (
+        KillSender {
+            send,
+            killed: killed.clone(),
+        },
+        KillReceiver { recv, killed },
+    )
+}
+
+impl KillSender {
+    /// Tell deployment `id` to stop
+    pub fn send(&self, id: Uuid) -> Result<usize, SendError<Uuid>> {
+        self.killed.lock().unwrap().insert(id);
+
+        self.send.send(id)
+    }
+
+    pub fn subscribe(&self) -> KillReceiver {
+        KillReceiver {
+            recv: self.send.subscribe(),
+            killed: self.killed.clone(),
+        }
+    }
+
+    pub fn receiver_count(&self) -> usize {
+        self.send.receiver_count()
+    }
+}
+
+impl KillReceiver {
+    /// Wait until deployment `id` is told to stop, even when its signal was lost to the channel lagging
+    pub async fn killed(&mut self, id: &Uuid) {
+        loop {
+            if self.killed.lock().unwrap().remove(id) {
+                return;
+            }
+
+            match self.recv.recv().await {
+                Ok(kill_id) if &kill_id == id => {
+                    self.killed.lock().unwrap().remove(id);
+                    return;
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!(
+                        skipped,
+                        "missed kill signals, checking whether deployment '{id}' was killed"
+                    );
+                }
+                Err(RecvError::Closed) => futures::future::pending().await,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::timeout;
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn killed_after_lagging() {
+        let (kill_send, mut kill_recv) = super::channel(1);
+        let id = Uuid::new_v4();
+        let other = Uuid::new_v4();
+
+        kill_send.send(id).unwrap();
+        kill_send.send(other).unwrap();
+
+        timeout(Duration::from_secs(1), kill_recv.killed(&id))
+            .await
+            .expect("the lost signal to be noticed");
+        assert!(
+            timeout(
+                Duration::from_millis(100),
+                kill_recv.killed(&Uuid::new_v4())
+            )
+            .await
+            .is_err(),
+            "deployments which were not killed keep waiting"
+        );
+    }
+}
@@ -0,0 +1,480 @@
+//! Ships the logs of deployments to the external sink configured for their service.
+//!
+//! Logs are picked up from the persistence broadcast stream and batched per deployment. A batch is flushed once it is
+//! full or once [FLUSH_INTERVAL] has passed, at which point the configuration of the deployment's service is looked up.
+//! Batches of deployments without a configuration are simply dropped. Whatever is batched when the task is stopped is
+//! flushed before it returns.
+//!
+//! Sinks are only ever reached on public addresses, so that users cannot point the deployer at its own network. Their
+//! hosts are checked when they are set, and resolved again on every send since their records can change in between.
+
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
+
+use chrono::{DateTime, Utc};
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN},
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::Serialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tokio::{
+    net::UdpSocket,
+    sync::{
+        broadcast::{error::RecvError, Receiver},
+        oneshot,
+    },
+    time::{interval, sleep},
+};
+use tracing::{debug, error, instrument, warn};
+use uuid::Uuid;
+
+use super::deploy_layer::{self, LogType};
+use crate::network;
+use crate::persistence::{LogForwarding, LogForwardingGetter, SinkType};
+
+/// Number of logs after which a deployment's batch is sent without waiting for the flush interval
+const BATCH_SIZE: usize = 100;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+const MAX_ATTEMPTS: u32 = 3;
+const RETRY_BACKOFF: Duration = Duration::from_secs(1);
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("failed to decrypt sink authorization")]
+    Decrypt,
+    #[error("failed to serialize logs: {0}")]
+    Serialize(#[from] serde_json::Error),
+    #[error("request to sink failed: {0}")]
+    Request(#[from] reqwest::Error),
+    #[error("sink responded with status {0}")]
+    Status(reqwest::StatusCode),
+    #[error("failed to send to syslog: {0}")]
+    Syslog(#[from] std::io::Error),
+    #[error("'{0}' is not a valid sink endpoint")]
+    Endpoint(String),
+    #[error(transparent)]
+    Network(#[from] network::Error),
+}
+
+/// Resolve the host of a sink's endpoint to the addresses to send to, failing unless they are all public
+pub async fn resolve_endpoint(sink: &SinkType, endpoint: &str) -> Result<Vec<SocketAddr>, Error> {
+    let invalid = || Error::Endpoint(endpoint.to_string());
+
+    let (host, port) = match sink {
+        SinkType::Http | SinkType::ObjectStore => {
+            let url = reqwest::Url::parse(endpoint).map_err(|_| invalid())?;
+            let host = url.host_str().ok_or_else(invalid)?.to_string();
+            let port = url.port_or_known_default().ok_or_else(invalid)?;
+
+            (host, port)
+        }
+        SinkType::Syslog => {
+            let (host, port) = endpoint.rsplit_once(':').ok_or_else(invalid)?;
+
+            (host.to_string(), port.parse().map_err(|_| invalid())?)
+        }
+    };
+
+    Ok(network::resolve_public(&host, port).await?)
+}
+
+/// Resolves the hosts of http sinks for the client sending to them, so that a connection is only ever made to a public
+/// address even when the records of a host change after it was checked
+struct PublicResolver;
+
+impl reqwest::dns::Resolve for PublicResolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        Box::pin(async move {
+            let addresses = network::resolve_public(name.as_str(), 0).await?;
+
+            Ok(Box::new(addresses.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+/// Encrypts the sensitive values of a log forwarding configuration before they are stored
+#[derive(Clone)]
+pub struct Cipher {
+    key: [u8; 32],
+}
+
+impl Cipher {
+    /// Derive the encryption key from a secret
+    pub fn new(secret: &str) -> Self {
+        Self {
+            key: Sha256::digest(secret.as_bytes()).into(),
+        }
+    }
+
+    /// Encrypt a value into a hex encoded `nonce || ciphertext`
+    pub fn encrypt(&self, value: &str) -> String {
+        let mut nonce = [0; NONCE_LEN];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .expect("to generate a nonce");
+
+        let mut in_out = value.as_bytes().to_vec();
+        self.less_safe_key()
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::empty(),
+                &mut in_out,
+            )
+            .expect("to encrypt value");
+
+        hex::encode([nonce.as_slice(), &in_out].concat())
+    }
+
+    pub fn decrypt(&self, encrypted: &str) -> Result<String, Error> {
+        let bytes = hex::decode(encrypted).map_err(|_| Error::Decrypt)?;
+
+        if bytes.len() < NONCE_LEN {
+            return Err(Error::Decrypt);
+        }
+
+        let (nonce, ciphertext) = bytes.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| Error::Decrypt)?;
+        let mut in_out = ciphertext.to_vec();
+        let plain = self
+            .less_safe_key()
+            .open_in_place(nonce, Aad::empty(), &mut in_out)
+            .map_err(|_| Error::Decrypt)?;
+
+        String::from_utf8(plain.to_vec()).map_err(|_| Error::Decrypt)
+    }
+
+    fn less_safe_key(&self) -> LessSafeKey {
+        LessSafeKey::new(UnboundKey::new(&AES_256_GCM, &self.key).expect("key to be 32 bytes"))
+    }
+}
+
+/// The shape of a log as it is sent to a sink
+#[derive(Clone, Debug, Serialize)]
+struct ForwardedLog {
+    deployment_id: Uuid,
+    timestamp: DateTime<Utc>,
+    state: String,
+    level: shuttle_common::log::Level,
+    file: Option<String>,
+    line: Option<u32>,
+    target: String,
+    fields: Value,
+}
+
+impl From<deploy_layer::Log> for ForwardedLog {
+    fn from(log: deploy_layer::Log) -> Self {
+        Self {
+            deployment_id: log.id,
+            timestamp: log.timestamp,
+            state: log.state.to_string(),
+            level: log.level.into(),
+            file: log.file,
+            line: log.line,
+            target: log.target,
+            fields: log.fields,
+        }
+    }
+}
+
+impl ForwardedLog {
+    /// Format as an RFC 5424 syslog message
+    fn to_syslog(&self) -> String {
+        // User-level facility
+        let facility = 1;
+        let severity = match self.level {
+            shuttle_common::log::Level::Error => 3,
+            shuttle_common::log::Level::Warn => 4,
+            shuttle_common::log::Level::Info => 6,
+            shuttle_common::log::Level::Debug | shuttle_common::log::Level::Trace => 7,
+        };
+        let message = match self.fields.get("message") {
+            Some(Value::String(message)) => message.clone(),
+            _ => self.fields.to_string(),
+        };
+
+        format!(
+            "<{}>1 {} - shuttle {} - - {}",
+            facility * 8 + severity,
+            self.timestamp.to_rfc3339(),
+            self.deployment_id,
+            message
+        )
+    }
+}
+
+/// Run a task which batches the logs from the log stream and forwards them to their configured sinks
+pub async fn task(
+    mut log_recv: Receiver<deploy_layer::Log>,
+    log_forwarding_getter: impl LogForwardingGetter,
+    cipher: Cipher,
+    mut stop: oneshot::Receiver<()>,
+) {
+    let mut batches: HashMap<Uuid, Vec<ForwardedLog>> = HashMap::new();
+    let mut flush_interval = interval(FLUSH_INTERVAL);
+    // Shared by all sends, so that connections to sinks are pooled. Redirects could lead anywhere, so they are not
+    // followed.
+    let client = reqwest::Client::builder()
+        .dns_resolver(Arc::new(PublicResolver))
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .expect("to build the sink client");
+
+    loop {
+        tokio::select! {
+            log = log_recv.recv() => {
+                let log = match log {
+                    Ok(log) => log,
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!(skipped, "log forwarder fell behind the log stream");
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+
+                // State changes are platform events rather than logs of the service
+                if log.r#type == LogType::State {
+                    continue;
+                }
+
+                let id = log.id;
+                let batch = batches.entry(id).or_default();
+                batch.push(log.into());
+
+                if batch.len() >= BATCH_SIZE {
+                    let batch = batches.remove(&id).unwrap_or_default();
+                    flush(id, batch, &log_forwarding_getter, &cipher, &client).await;
+                }
+            }
+            _ = flush_interval.tick() => {
+                for (id, batch) in batches.drain() {
+                    flush(id, batch, &log_forwarding_getter, &cipher, &client).await;
+                }
+            }
+            _ = &mut stop => {
+                for (id, batch) in batches.drain() {
+                    flush(id, batch, &log_forwarding_getter, &cipher, &client).await;
+                }
+                break;
+            }
+        }
+    }
+}
+
+async fn flush(
+    deployment_id: Uuid,
+    batch: Vec<ForwardedLog>,
+    log_forwarding_getter: &impl LogForwardingGetter,
+    cipher: &Cipher,
+    client: &reqwest::Client,
+) {
+    let config = match log_forwarding_getter
+        .get_log_forwarding_for_deployment(&deployment_id)
+        .await
+    {
+        Ok(Some(config)) => config,
+        Ok(None) => return,
+        Err(error) => {
+            error!(
+                error = &error as &dyn std::error::Error,
+                "failed to get log forwarding configuration"
+            );
+            return;
+        }
+    };
+
+    let auth = match config.auth.as_deref().map(|auth| cipher.decrypt(auth)) {
+        Some(Ok(auth)) => Some(auth),
+        Some(Err(error)) => {
+            error!(
+                error = &error as &dyn std::error::Error,
+                "dropping logs for sink with unusable authorization"
+            );
+            return;
+        }
+        None => None,
+    };
+
+    // Don't hold up the log stream while a sink is being retried
+    tokio::spawn(send_with_retry(
+        client.clone(),
+        deployment_id,
+        config,
+        auth,
+        batch,
+    ));
+}
+
+#[instrument(skip(client, config, auth, batch), fields(sink = %config.sink, batch_size = batch.len()))]
+async fn send_with_retry(
+    client: reqwest::Client,
+    deployment_id: Uuid,
+    config: LogForwarding,
+    auth: Option<String>,
+    batch: Vec<ForwardedLog>,
+) {
+    let mut backoff = RETRY_BACKOFF;
+
+    for attempt in 1..=MAX_ATTEMPTS {
+        match send(&client, deployment_id, &config, auth.as_deref(), &batch).await {
+            Ok(()) => {
+                debug!("forwarded logs");
+                return;
+            }
+            Err(error) if attempt < MAX_ATTEMPTS => {
+                warn!(
+                    error = &error as &dyn std::error::Error,
+                    attempt, "failed to forward logs, retrying"
+                );
+                sleep(backoff).await;
+                backoff *= 2;
+            }
+            Err(error) => {
+                error!(
+                    error = &error as &dyn std::error::Error,
+                    "failed to forward logs, dropping batch"
+                );
+            }
+        }
+    }
+}
+
+async fn send(
+    client: &reqwest::Client,
+    deployment_id: Uuid,
+    config: &LogForwarding,
+    auth: Option<&str>,
+    batch: &[ForwardedLog],
+) -> Result<(), Error> {
+    let addresses = resolve_endpoint(&config.sink, &config.endpoint).await?;
+
+    match config.sink {
+        SinkType::Http => {
+            let request = client.post(&config.endpoint);
+
+            send_http(request, auth, batch).await
+        }
+        SinkType::ObjectStore => {
+            let timestamp = batch
+                .first()
+                .map(|log| log.timestamp)
+                .unwrap_or_else(Utc::now);
+            let url = format!(
+                "{}/{deployment_id}/{}.json",
+                config.endpoint.trim_end_matches('/'),
+                timestamp.timestamp_millis()
+            );
+            let request = client.put(url);
+
+            send_http(request, auth, batch).await
+        }
+        SinkType::Syslog => {
+            let socket = UdpSocket::bind("0.0.0.0:0").await?;
+            socket.connect(addresses[0]).await?;
+
+            for log in batch {
+                socket.send(log.to_syslog().as_bytes()).await?;
+            }
+
+            Ok(())
+        }
+    }
+}
+
+async fn send_http(
+    request: reqwest::RequestBuilder,
+    auth: Option<&str>,
+    batch: &[ForwardedLog],
+) -> Result<(), Error> {
+    let mut request = request
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(serde_json::to_vec(batch)?);
+
+    if let Some(auth) = auth {
+        request = request.header(reqwest::header::AUTHORIZATION, auth);
+    }
+
+    let response = request.send().await?;
+
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(Error::Status(response.status()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+    use serde_json::json;
+    use uuid::Uuid;
+
+    use super::{resolve_endpoint, Cipher, ForwardedLog};
+    use crate::persistence::SinkType;
+
+    #[test]
+    fn cipher_round_trip() {
+        let cipher = Cipher::new("admin-secret");
+
+        let encrypted = cipher.encrypt("Bearer token");
+        assert!(!encrypted.contains("token"));
+        assert_ne!(
+            encrypted,
+            cipher.encrypt("Bearer token"),
+            "nonce should be random"
+        );
+        assert_eq!(cipher.decrypt(&encrypted).unwrap(), "Bearer token");
+
+        assert!(
+            Cipher::new("other-secret").decrypt(&encrypted).is_err(),
+            "a different key should not be able to decrypt"
+        );
+        assert!(cipher.decrypt("not hex").is_err());
+    }
+
+    #[tokio::test]
+    async fn refuses_internal_endpoints() {
+        for (sink, endpoint) in [
+            (SinkType::Http, "http://127.0.0.1:8000/logs"),
+            (SinkType::Http, "http://169.254.169.254/latest/meta-data"),
+            (SinkType::ObjectStore, "https://[::1]/bucket"),
+            (SinkType::Syslog, "10.0.0.1:514"),
+            (SinkType::Syslog, "localhost:514"),
+            (SinkType::Syslog, "1.1.1.1"),
+        ] {
+            assert!(
+                resolve_endpoint(&sink, endpoint).await.is_err(),
+                "{endpoint} is refused"
+            );
+        }
+
+        assert_eq!(
+            resolve_endpoint(&SinkType::Syslog, "1.1.1.1:514")
+                .await
+                .unwrap(),
+            vec!["1.1.1.1:514".parse().unwrap()]
+        );
+    }
+
+    #[test]
+    fn to_syslog() {
+        let deployment_id = Uuid::new_v4();
+        let log = ForwardedLog {
+            deployment_id,
+            timestamp: Utc.with_ymd_and_hms(2022, 12, 1, 10, 30, 0).unwrap(),
+            state: "Running".to_string(),
+            level: shuttle_common::log::Level::Warn,
+            file: None,
+            line: None,
+            target: "app".to_string(),
+            fields: json!({"message": "disk almost full"}),
+        };
+
+        assert_eq!(
+            log.to_syslog(),
+            format!(
+                "<12>1 2022-12-01T10:30:00+00:00 - shuttle {deployment_id} - - disk almost full"
+            )
+        );
+    }
+}
@@ -0,0 +1,92 @@
+//! Peak resident memory of running deployments.
+//!
+//! Deployments are loaded into the deployer process itself, so the high-water mark of the process is what a deployment
+//! reached. The mark is reset when a deployment starts running and sampled every [SAMPLE_INTERVAL] into all running
+//! deployments. Builds going on at the same time count towards it too. Only Linux exposes the mark, so nothing is
+//! recorded on other platforms.
+
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tokio::time::interval;
+use tracing::{error, warn};
+
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(5);
+
+#[async_trait]
+pub trait PeakMemoryRecorder: Clone + Send + Sync + 'static {
+    type Err: std::error::Error + Send;
+
+    /// Record `bytes` as the peak memory of every running deployment
+    async fn record_peak_memory(&self, bytes: u64) -> Result<(), Self::Err>;
+}
+
+/// Run a task which keeps the peak memory of running deployments up to date
+pub async fn task(peak_memory_recorder: impl PeakMemoryRecorder) {
+    let mut interval = interval(SAMPLE_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let Some(bytes) = peak() else {
+            continue;
+        };
+
+        if let Err(error) = peak_memory_recorder.record_peak_memory(bytes).await {
+            error!(
+                error = &error as &dyn std::error::Error,
+                "failed to record peak memory"
+            );
+        }
+    }
+}
+
+/// Start tracking the peak from the current memory use again
+pub fn reset_peak() {
+    if cfg!(target_os = "linux") {
+        // Writing 5 resets the high-water mark of the resident set size
+        if let Err(error) = std::fs::write("/proc/self/clear_refs", "5") {
+            warn!(
+                error = &error as &dyn std::error::Error,
+                "failed to reset peak memory"
+            );
+        }
+    }
+}
+
+/// Highest resident memory in bytes since the last reset
+fn peak() -> Option<u64> {
+    if !cfg!(target_os = "linux") {
+        return None;
+    }
+
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+
+    parse_peak(&status)
+}
+
+fn parse_peak(status: &str) -> Option<u64> {
+    let kilobytes = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmHWM:"))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+
+    Some(kilobytes * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_peak;
+
+    #[test]
+    fn parse() {
+        let status = "Name:\tshuttle-deployer\nVmPeak:\t  612340 kB\nVmHWM:\t   48212 kB\nVmRSS:\t   40100 kB\n";
+
+        assert_eq!(parse_peak(status), Some(48212 * 1024));
+        assert_eq!(parse_peak("Name:\tshuttle-deployer\n"), None);
+    }
+}
@@ -1,46 +1,90 @@
+pub mod backend;
+pub mod build_process;
 pub mod deploy_layer;
+pub mod drain;
 pub mod gateway_client;
+mod image;
+pub mod instance;
+mod kill;
+pub mod log_forwarder;
+pub mod memory;
+pub mod output;
+mod profiler;
 pub mod provisioner_factory;
 mod queue;
+mod readiness;
+pub mod reaper;
+mod restart_policy;
 mod run;
+pub mod run_queue;
 pub mod runtime_logger;
-mod storage_manager;
-
-use std::path::PathBuf;
+mod smoke_test;
+pub mod storage_manager;
+pub mod timings;
+mod warm_cache;
+
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
-pub use queue::Queued;
+pub use image::ImageRuntime;
+pub use profiler::{ProfileError, Profiler};
+pub use queue::{BuildRecorder, Queued};
+pub use restart_policy::RestartPolicy;
 pub use run::{ActiveDeploymentsGetter, Built};
-use tracing::{instrument, Span};
+use tracing::{info, instrument, warn, Span};
 use tracing_opentelemetry::OpenTelemetrySpanExt;
 
+use crate::args::ShutdownPolicy;
 use crate::persistence::{SecretRecorder, State};
-use tokio::sync::{broadcast, mpsc};
+use tokio::sync::{mpsc, watch};
 use uuid::Uuid;
 
 use self::{
-    deploy_layer::LogRecorder, gateway_client::BuildQueueClient, storage_manager::StorageManager,
+    deploy_layer::LogRecorder,
+    drain::DrainReplaced,
+    gateway_client::BuildQueueClient,
+    kill::{KillReceiver, KillSender},
+    queue::Builds,
+    run_queue::RunQueue,
+    storage_manager::StorageManager,
 };
 
 const QUEUE_BUFFER_SIZE: usize = 100;
 const RUN_BUFFER_SIZE: usize = 100;
 const KILL_BUFFER_SIZE: usize = 10;
 
-pub struct DeploymentManagerBuilder<AF, RLF, LR, SR, ADG, QC> {
+pub struct DeploymentManagerBuilder<AF, RLF, LR, SR, BR, ADG, QC> {
     abstract_factory: Option<AF>,
     runtime_logger_factory: Option<RLF>,
     build_log_recorder: Option<LR>,
     secret_recorder: Option<SR>,
+    build_recorder: Option<BR>,
     active_deployment_getter: Option<ADG>,
     artifacts_path: Option<PathBuf>,
     queue_client: Option<QC>,
+    dry_load: Option<bool>,
+    isolate_builds: Option<bool>,
+    kill_buffer_size: Option<usize>,
+    image_runtime: Option<ImageRuntime>,
+    drain_replaced: Option<DrainReplaced>,
+    warm_cache: Option<u64>,
+    profiling: Option<bool>,
+    build_process: Option<PathBuf>,
 }
 
-impl<AF, RLF, LR, SR, ADG, QC> DeploymentManagerBuilder<AF, RLF, LR, SR, ADG, QC>
+impl<AF, RLF, LR, SR, BR, ADG, QC> DeploymentManagerBuilder<AF, RLF, LR, SR, BR, ADG, QC>
 where
     AF: provisioner_factory::AbstractFactory,
     RLF: runtime_logger::Factory,
     LR: LogRecorder,
     SR: SecretRecorder,
+    BR: BuildRecorder,
     ADG: ActiveDeploymentsGetter,
     QC: BuildQueueClient,
 {
@@ -68,6 +112,12 @@ where
         self
     }
 
+    pub fn build_recorder(mut self, build_recorder: BR) -> Self {
+        self.build_recorder = Some(build_recorder);
+
+        self
+    }
+
     pub fn active_deployment_getter(mut self, active_deployment_getter: ADG) -> Self {
         self.active_deployment_getter = Some(active_deployment_getter);
 
@@ -86,6 +136,69 @@ where
         self
     }
 
+    /// Load every built artifact once without running it, so that deployments which cannot be loaded fail before they
+    /// are handed to the run queue. Off by default.
+    pub fn dry_load(mut self, dry_load: bool) -> Self {
+        self.dry_load = Some(dry_load);
+
+        self
+    }
+
+    /// Build each deployment in its own directory rather than in the one its service keeps between builds. Off by
+    /// default, since the service's directory keeps the build cache around for the next deployment.
+    pub fn isolate_builds(mut self, isolate_builds: bool) -> Self {
+        self.isolate_builds = Some(isolate_builds);
+
+        self
+    }
+
+    /// Number of kill signals which can be waiting on the slowest running deployment. A deployment falling further
+    /// behind misses the signals of others, but still stops when it was sent one.
+    pub fn kill_buffer_size(mut self, kill_buffer_size: usize) -> Self {
+        self.kill_buffer_size = Some(kill_buffer_size);
+
+        self
+    }
+
+    /// Run deployments of a container image with this runtime. Without one, such deployments are refused.
+    pub fn image_runtime(mut self, image_runtime: ImageRuntime) -> Self {
+        self.image_runtime = Some(image_runtime);
+
+        self
+    }
+
+    /// Drain the deployments a new one replaces: the proxy sends new requests to the new deployment as soon as it runs,
+    /// and the deployments it replaces get up to `drain` to finish their requests before they are stopped. By default
+    /// the old deployments are stopped first.
+    pub fn drain_replaced(mut self, drain: Duration) -> Self {
+        self.drain_replaced = Some(DrainReplaced::new(drain));
+
+        self
+    }
+
+    /// Keep up to `capacity` bytes of built artifacts in memory, evicting the least recently used ones first. Off by
+    /// default.
+    pub fn warm_cache(mut self, capacity: u64) -> Self {
+        self.warm_cache = Some(capacity);
+
+        self
+    }
+
+    /// Allow CPU profiles of the deployer to be captured on demand. Off by default.
+    pub fn profiling(mut self, profiling: bool) -> Self {
+        self.profiling = Some(profiling);
+
+        self
+    }
+
+    /// Build deployments in processes of `executable`, which has to be a deployer, so that cancelling a build kills
+    /// cargo straight away. By default builds run in this process, and a cancelled one stops once cargo is done.
+    pub fn build_process(mut self, executable: PathBuf) -> Self {
+        self.build_process = Some(executable);
+
+        self
+    }
+
     /// Creates two Tokio tasks, one for building queued services, the other for
     /// executing/deploying built services. Two multi-producer, single consumer
     /// channels are also created which are for moving on-going service
@@ -101,16 +214,29 @@ where
             .build_log_recorder
             .expect("a build log recorder to be set");
         let secret_recorder = self.secret_recorder.expect("a secret recorder to be set");
+        let build_recorder = self.build_recorder.expect("a build recorder to be set");
         let active_deployment_getter = self
             .active_deployment_getter
             .expect("an active deployment getter to be set");
         let artifacts_path = self.artifacts_path.expect("artifacts path to be set");
         let queue_client = self.queue_client.expect("a queue client to be set");
+        let dry_load = self.dry_load.unwrap_or_default();
+        let isolate_builds = self.isolate_builds.unwrap_or_default();
+        let kill_buffer_size = self.kill_buffer_size.unwrap_or(KILL_BUFFER_SIZE);
+        let runs_images = self.image_runtime.is_some();
+        let profiler = self.profiling.unwrap_or_default().then(Profiler::default);
 
         let (queue_send, queue_recv) = mpsc::channel(QUEUE_BUFFER_SIZE);
         let (run_send, run_recv) = mpsc::channel(RUN_BUFFER_SIZE);
-        let (kill_send, _) = broadcast::channel(KILL_BUFFER_SIZE);
-        let storage_manager = StorageManager::new(artifacts_path);
+        let (kill_send, _) = kill::channel(kill_buffer_size);
+        let (shutdown_send, shutdown_recv) = watch::channel(None);
+        let mut storage_manager = StorageManager::new(artifacts_path);
+        if let Some(capacity) = self.warm_cache {
+            storage_manager = storage_manager.with_warm_cache(capacity);
+        }
+        let draining = Arc::new(AtomicBool::new(false));
+        let builds = Builds::default();
+        let run_queue = Arc::new(RunQueue::default());
 
         let run_send_clone = run_send.clone();
 
@@ -119,23 +245,40 @@ where
             run_send_clone,
             build_log_recorder,
             secret_recorder,
+            build_recorder,
             storage_manager.clone(),
             queue_client,
+            builds.clone(),
+            run_queue.clone(),
+            self.build_process,
+            dry_load,
+            isolate_builds,
         ));
         tokio::spawn(run::task(
             run_recv,
             kill_send.clone(),
+            shutdown_recv,
+            run_queue.clone(),
             abstract_factory,
             runtime_logger_factory,
             active_deployment_getter,
             storage_manager.clone(),
+            self.image_runtime,
+            draining.clone(),
+            self.drain_replaced,
         ));
 
         DeploymentManager {
             queue_send,
             run_send,
             kill_send,
+            shutdown_send: Arc::new(shutdown_send),
             storage_manager,
+            builds,
+            run_queue,
+            draining,
+            runs_images,
+            profiler,
         }
     }
 }
@@ -145,7 +288,13 @@ pub struct DeploymentManager {
     queue_send: QueueSender,
     run_send: RunSender,
     kill_send: KillSender,
+    shutdown_send: Arc<ShutdownSender>,
     storage_manager: StorageManager,
+    builds: Builds,
+    run_queue: Arc<RunQueue>,
+    draining: Arc<AtomicBool>,
+    runs_images: bool,
+    profiler: Option<Profiler>,
 }
 
 /// ```no-test
@@ -165,16 +314,25 @@ pub struct DeploymentManager {
 impl DeploymentManager {
     /// Create a new deployment manager. Manages one or more 'pipelines' for
     /// processing service building, loading, and deployment.
-    pub fn builder<AF, RLF, LR, SR, ADG, QC>() -> DeploymentManagerBuilder<AF, RLF, LR, SR, ADG, QC>
-    {
+    pub fn builder<AF, RLF, LR, SR, BR, ADG, QC>(
+    ) -> DeploymentManagerBuilder<AF, RLF, LR, SR, BR, ADG, QC> {
         DeploymentManagerBuilder {
             abstract_factory: None,
             runtime_logger_factory: None,
             build_log_recorder: None,
             secret_recorder: None,
+            build_recorder: None,
             active_deployment_getter: None,
             artifacts_path: None,
             queue_client: None,
+            dry_load: None,
+            isolate_builds: None,
+            kill_buffer_size: None,
+            image_runtime: None,
+            drain_replaced: None,
+            warm_cache: None,
+            profiling: None,
+            build_process: None,
         }
     }
 
@@ -185,11 +343,13 @@ impl DeploymentManager {
             propagator.inject_context(&cx, &mut queued.tracing_context);
         });
 
+        self.builds.start(queued.id);
         self.queue_send.send(queued).await.unwrap();
     }
 
     #[instrument(skip(self), fields(id = %built.id, state = %State::Built))]
     pub async fn run_push(&self, built: Built) {
+        self.run_queue.push(built.id, &built.service_name);
         self.run_send.send(built).await.unwrap();
     }
 
@@ -199,9 +359,62 @@ impl DeploymentManager {
         }
     }
 
+    /// Cancel the build of a deployment which is still queued or building. A build process is killed straight away,
+    /// while a build in this process stops once cargo is done. Returns whether the build could be cancelled.
+    pub async fn cancel(&self, id: Uuid) -> bool {
+        self.builds.cancel(&id)
+    }
+
     pub fn storage_manager(&self) -> StorageManager {
         self.storage_manager.clone()
     }
+
+    /// Built deployments the run task has yet to take on
+    pub fn run_queue(&self) -> &RunQueue {
+        &self.run_queue
+    }
+
+    /// Whether deployments of a container image can be run
+    pub fn runs_images(&self) -> bool {
+        self.runs_images
+    }
+
+    /// What captures profiles, when profiling is allowed
+    pub fn profiler(&self) -> Option<Profiler> {
+        self.profiler.clone()
+    }
+
+    /// Stop taking on new deployments so that this deployer can be decommissioned. Deployments which are already
+    /// running are left alone. There is no way back other than restarting the deployer.
+    pub fn start_draining(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// Stop taking on new deployments and deal with the running ones as `policy` says, so that the deployer can exit.
+    /// Returns once every running deployment is stopped or detached, or once `timeout` is up.
+    ///
+    /// Builds which are still going are not waited on. They are cleaned up when the deployer is started again.
+    pub async fn shutdown(&self, policy: ShutdownPolicy, timeout: Duration) {
+        info!(?policy, "shutting down deployments");
+        self.start_draining();
+
+        // Only fails when the run task and every deployment are already gone
+        let _ = self.shutdown_send.send(Some(policy));
+
+        if tokio::time::timeout(timeout, self.shutdown_send.closed())
+            .await
+            .is_err()
+        {
+            warn!(
+                "deployments did not shut down within {}s, leaving them to be killed",
+                timeout.as_secs()
+            );
+        }
+    }
 }
 
 type QueueSender = mpsc::Sender<queue::Queued>;
@@ -210,5 +423,5 @@ type QueueReceiver = mpsc::Receiver<queue::Queued>;
 type RunSender = mpsc::Sender<run::Built>;
 type RunReceiver = mpsc::Receiver<run::Built>;
 
-type KillSender = broadcast::Sender<Uuid>;
-type KillReceiver = broadcast::Receiver<Uuid>;
+type ShutdownSender = watch::Sender<Option<ShutdownPolicy>>;
+type ShutdownReceiver = watch::Receiver<Option<ShutdownPolicy>>;
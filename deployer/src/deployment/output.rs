@@ -0,0 +1,120 @@
+//! The stdout and stderr of services which are loaded into the deployer, and so print to those of the deployer itself.
+//!
+//! [OutputCapture::start] swaps both for pipes. Every line is still passed on to where it went before, and also goes to
+//! the logs of the deployment attached last, tagged with the [Stream] it was printed to. Deployments stay attached from
+//! the time they are loaded until they stop, so while a new deployment takes over from an old one the lines of both are
+//! put on the new one.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+
+use shuttle_common::log::{Stream, STREAM_FIELD};
+use shuttle_service::Logger;
+use tracing::{info, Dispatch};
+use tracing_subscriber::prelude::*;
+use uuid::Uuid;
+
+type Attached = Arc<Mutex<Option<(Uuid, Dispatch)>>>;
+
+#[derive(Clone)]
+pub struct OutputCapture {
+    attached: Attached,
+}
+
+impl OutputCapture {
+    /// Capture the stdout and stderr of this process. Also returns the stdout from before, for the deployer to print
+    /// its own logs to.
+    pub fn start() -> io::Result<(Self, File)> {
+        let attached = Attached::default();
+
+        let stdout = capture(libc::STDOUT_FILENO, Stream::Stdout, attached.clone())?;
+        capture(libc::STDERR_FILENO, Stream::Stderr, attached.clone())?;
+
+        Ok((Self { attached }, stdout))
+    }
+
+    /// Send the output to the logs of deployment `id` until the returned guard is dropped
+    pub fn attach(&self, id: Uuid, logger: Logger) -> CapturedOutput {
+        let dispatch = Dispatch::new(tracing_subscriber::registry().with(logger));
+        *self.attached.lock().unwrap() = Some((id, dispatch));
+
+        CapturedOutput {
+            id,
+            attached: self.attached.clone(),
+        }
+    }
+}
+
+/// Output going to the logs of a deployment. Dropping it stops that, unless another deployment was attached since.
+pub struct CapturedOutput {
+    id: Uuid,
+    attached: Attached,
+}
+
+impl Drop for CapturedOutput {
+    fn drop(&mut self) {
+        let mut attached = self.attached.lock().unwrap();
+
+        if matches!(&*attached, Some((id, _)) if *id == self.id) {
+            *attached = None;
+        }
+    }
+}
+
+/// Point `fd` at a pipe whose lines are passed on to a copy of what `fd` was before and logged for the attached
+/// deployment. Returns that copy.
+fn capture(fd: RawFd, stream: Stream, attached: Attached) -> io::Result<File> {
+    // SAFETY: the descriptors opened here are only ever owned by the files made from them
+    let original = check(unsafe { libc::dup(fd) })?;
+    let original = unsafe { File::from_raw_fd(original) };
+
+    let mut pipe = [0; 2];
+    check(unsafe { libc::pipe(pipe.as_mut_ptr()) })?;
+    let (reader, writer) = unsafe { (File::from_raw_fd(pipe[0]), File::from_raw_fd(pipe[1])) };
+
+    // SAFETY: `fd` is left open, just pointing at the pipe
+    check(unsafe { libc::dup2(pipe[1], fd) })?;
+    // `fd` now holds the write end on its own
+    drop(writer);
+
+    let mut passed_on = original.try_clone()?;
+    std::thread::Builder::new()
+        .name(format!("capture-{stream}"))
+        .spawn(move || {
+            let mut reader = BufReader::new(reader);
+            let mut line = Vec::new();
+
+            loop {
+                line.clear();
+                match reader.read_until(b'\n', &mut line) {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => {}
+                }
+
+                let _ = passed_on.write_all(&line);
+
+                let Some((_, dispatch)) = attached.lock().unwrap().clone() else {
+                    continue;
+                };
+                let line = String::from_utf8_lossy(&line);
+                let line = line.trim_end_matches(['\n', '\r']);
+
+                tracing::dispatcher::with_default(
+                    &dispatch,
+                    || info!({ STREAM_FIELD } = %stream, "{line}"),
+                );
+            }
+        })?;
+
+    Ok(original)
+}
+
+fn check(result: libc::c_int) -> io::Result<libc::c_int> {
+    if result == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
@@ -0,0 +1,83 @@
+//! On-demand CPU profiles of the services a deployer runs.
+//!
+//! Services are loaded into the deployer and run on its own threads, so there is no process of a deployment to
+//! sample on its own. A profile samples the whole deployer instead, which serves a single project: its deployment
+//! makes up most of what shows up in it, next to the proxy and the deployer itself. Deployments of a container image
+//! run out of process and cannot be profiled from here.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use pprof::ProfilerGuardBuilder;
+use thiserror::Error;
+use tracing::debug;
+
+/// Samples taken a second. Off from 100 so that sampling does not line up with work done on a timer.
+const SAMPLING_FREQUENCY: i32 = 99;
+
+/// Frames of these libraries are left out, since unwinding through them can crash
+const BLOCKLIST: &[&str] = &["libc", "libgcc", "pthread", "vdso"];
+
+#[derive(Error, Debug)]
+pub enum ProfileError {
+    #[error("a profile is already being captured")]
+    Busy,
+    #[error("failed to capture profile: {0}")]
+    Capture(#[from] pprof::Error),
+    #[error("profiling task failed: {0}")]
+    Join(#[from] tokio::task::JoinError),
+}
+
+/// Captures profiles one at a time, since samples are taken for the whole process
+#[derive(Clone, Default)]
+pub struct Profiler {
+    busy: Arc<AtomicBool>,
+}
+
+impl Profiler {
+    /// Sample the deployer for `duration` and render the samples as a flamegraph in SVG
+    pub async fn flamegraph(&self, duration: Duration) -> Result<Vec<u8>, ProfileError> {
+        if self.busy.swap(true, Ordering::SeqCst) {
+            return Err(ProfileError::Busy);
+        }
+
+        debug!(?duration, "capturing profile");
+
+        // Released by the capture itself, which goes on even when whoever asked for it is gone
+        let busy = Busy(self.busy.clone());
+
+        tokio::task::spawn_blocking(move || {
+            let _busy = busy;
+            capture(duration)
+        })
+        .await?
+    }
+}
+
+/// Marks the profiler as free again once dropped
+struct Busy(Arc<AtomicBool>);
+
+impl Drop for Busy {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+fn capture(duration: Duration) -> Result<Vec<u8>, ProfileError> {
+    let guard = ProfilerGuardBuilder::default()
+        .frequency(SAMPLING_FREQUENCY)
+        .blocklist(BLOCKLIST)
+        .build()?;
+
+    std::thread::sleep(duration);
+
+    let mut svg = Vec::new();
+    guard.report().build()?.flamegraph(&mut svg)?;
+
+    Ok(svg)
+}
@@ -12,6 +12,7 @@ use shuttle_proto::provisioner::{
     database_request::DbType, provisioner_client::ProvisionerClient, DatabaseRequest,
 };
 use shuttle_service::{Environment, Factory, ServiceName};
+use sqlx::types::Json;
 use thiserror::Error;
 use tonic::{
     transport::{Channel, Endpoint},
@@ -48,6 +49,7 @@ pub struct AbstractProvisionerFactory<R: ResourceManager, S: SecretGetter> {
     provisioner_uri: Endpoint,
     resource_manager: R,
     secret_getter: S,
+    environment: Environment,
 }
 
 #[async_trait]
@@ -80,6 +82,7 @@ impl<R: ResourceManager, S: SecretGetter> AbstractFactory for AbstractProvisione
             resource_manager: self.resource_manager.clone(),
             secret_getter: self.secret_getter.clone(),
             claim,
+            environment: self.environment,
             info: None,
             secrets: None,
         })
@@ -87,11 +90,17 @@ impl<R: ResourceManager, S: SecretGetter> AbstractFactory for AbstractProvisione
 }
 
 impl<R: ResourceManager, S: SecretGetter> AbstractProvisionerFactory<R, S> {
-    pub fn new(provisioner_uri: Endpoint, resource_manager: R, secret_getter: S) -> Self {
+    pub fn new(
+        provisioner_uri: Endpoint,
+        resource_manager: R,
+        secret_getter: S,
+        environment: Environment,
+    ) -> Self {
         Self {
             provisioner_uri,
             resource_manager,
             secret_getter,
+            environment,
         }
     }
 }
@@ -114,6 +123,7 @@ pub struct ProvisionerFactory<R: ResourceManager, S: SecretGetter> {
     secret_getter: S,
     secrets: Option<BTreeMap<String, String>>,
     claim: Option<Claim>,
+    environment: Environment,
 }
 
 #[async_trait]
@@ -121,6 +131,15 @@ impl<R: ResourceManager, S: SecretGetter> Factory for ProvisionerFactory<R, S> {
     async fn get_db_connection_string(
         &mut self,
         db_type: database::Type,
+    ) -> Result<String, shuttle_service::Error> {
+        self.get_db_connection_string_with_metadata(db_type, BTreeMap::new())
+            .await
+    }
+
+    async fn get_db_connection_string_with_metadata(
+        &mut self,
+        db_type: database::Type,
+        metadata: BTreeMap<String, String>,
     ) -> Result<String, shuttle_service::Error> {
         if let Some(ref info) = self.info {
             debug!("A database has already been provisioned for this deployment, so reusing it");
@@ -160,6 +179,7 @@ impl<R: ResourceManager, S: SecretGetter> Factory for ProvisionerFactory<R, S> {
                             "failed to convert DatabaseReadyInfo to json: {err}",
                         ))
                     })?,
+                    metadata: Json(metadata),
                 })
                 .await
                 .map_err(|err| {
@@ -234,7 +254,7 @@ impl<R: ResourceManager, S: SecretGetter> Factory for ProvisionerFactory<R, S> {
     }
 
     fn get_environment(&self) -> Environment {
-        Environment::Production
+        self.environment
     }
 
     fn get_build_path(&self) -> Result<PathBuf, shuttle_service::Error> {
@@ -1,18 +1,26 @@
+use super::build_process;
 use super::deploy_layer::{Log, LogRecorder, LogType};
 use super::gateway_client::BuildQueueClient;
+use super::readiness::ReadinessProbe;
+use super::smoke_test::SmokeTest;
 use super::storage_manager::StorageManager;
-use super::{Built, QueueReceiver, RunSender, State};
-use crate::error::{Error, Result, TestError};
+use super::timings::{Phase, PHASE_TIMINGS};
+use super::{reaper, run_queue::RunQueue, Built, QueueReceiver, RunSender, State};
+use crate::error::{Error, Fault, Result, TestError};
 use crate::persistence::{LogLevel, SecretRecorder};
 
 use cargo::util::interning::InternedString;
 use cargo_metadata::Message;
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use crossbeam_channel::Sender;
 use opentelemetry::global;
 use serde_json::json;
 use shuttle_common::backends::auth::Claim;
-use shuttle_service::loader::{build_crate, get_config};
+use shuttle_common::log::{BuildProgress, BUILD_PROGRESS_FIELD};
+use shuttle_service::loader::{
+    build_crate, compile_unit_count, get_config, lock_dependencies, Loader,
+};
+use tokio::sync::Notify;
 use tokio::time::{sleep, timeout};
 use tracing::{debug, debug_span, error, info, instrument, trace, warn, Instrument, Span};
 use tracing_opentelemetry::OpenTelemetrySpanExt;
@@ -21,9 +29,12 @@ use uuid::Uuid;
 use std::collections::{BTreeMap, HashMap};
 use std::fmt;
 use std::fs::remove_file;
+use std::future::Future;
 use std::io::Read;
 use std::path::{Path, PathBuf};
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use cargo::core::compiler::{CompileMode, MessageFormat};
 use cargo::core::Workspace;
@@ -32,13 +43,94 @@ use flate2::read::GzDecoder;
 use tar::Archive;
 use tokio::fs;
 
+/// Tells a build to stop, and wakes whatever waits on the build to stop it straight away
+#[derive(Default)]
+pub struct Cancel {
+    cancelled: AtomicBool,
+    notify: Notify,
+}
+
+impl Cancel {
+    fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        self.notify.notify_waiters();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Wait for the build to be cancelled
+    pub async fn cancelled(&self) {
+        loop {
+            // Made before checking so that a cancel in between is not missed
+            let notified = self.notify.notified();
+
+            if self.is_cancelled() {
+                return;
+            }
+
+            notified.await;
+        }
+    }
+}
+
+/// Builds which can still be cancelled, each with what tells it to stop
+#[derive(Clone, Default)]
+pub struct Builds {
+    cancels: Arc<Mutex<HashMap<Uuid, Arc<Cancel>>>>,
+}
+
+impl Builds {
+    /// Track a build, or get the cancel of one which is tracked already. Deployments are tracked as they are queued
+    /// so that they can be cancelled straight away.
+    pub fn start(&self, id: Uuid) -> Arc<Cancel> {
+        self.cancels.lock().unwrap().entry(id).or_default().clone()
+    }
+
+    /// Stop tracking a build, which can no longer be cancelled after this. Returns whether it was cancelled before.
+    fn finish(&self, id: &Uuid) -> bool {
+        self.cancels
+            .lock()
+            .unwrap()
+            .remove(id)
+            .map_or(false, |cancel| cancel.is_cancelled())
+    }
+
+    /// Returns `false` when the deployment is not being built, or got too far to be stopped
+    pub fn cancel(&self, id: &Uuid) -> bool {
+        match self.cancels.lock().unwrap().get(id) {
+            Some(cancel) => {
+                cancel.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+pub trait BuildRecorder: Clone + Send + Sync + 'static {
+    type Err: std::error::Error + Send;
+
+    /// Record whether deployment `id` was built with `--locked`
+    async fn record_locked(&self, id: &Uuid, locked: bool) -> std::result::Result<(), Self::Err>;
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn task(
     mut recv: QueueReceiver,
     run_send: RunSender,
     log_recorder: impl LogRecorder,
     secret_recorder: impl SecretRecorder,
+    build_recorder: impl BuildRecorder,
     storage_manager: StorageManager,
     queue_client: impl BuildQueueClient,
+    builds: Builds,
+    run_queue: Arc<RunQueue>,
+    build_process: Option<PathBuf>,
+    dry_load: bool,
+    isolate_builds: bool,
 ) {
     info!("Queue task started");
 
@@ -50,8 +142,14 @@ pub async fn task(
         let run_send_cloned = run_send.clone();
         let log_recorder = log_recorder.clone();
         let secret_recorder = secret_recorder.clone();
+        let build_recorder = build_recorder.clone();
         let storage_manager = storage_manager.clone();
         let queue_client = queue_client.clone();
+        let builds = builds.clone();
+        let cancel = builds.start(id);
+        let run_queue = run_queue.clone();
+        let build_process = build_process.clone();
+        let expires_at = queued.expires_at;
 
         tokio::spawn(async move {
             let parent_cx = global::get_text_map_propagator(|propagator| {
@@ -61,28 +159,57 @@ pub async fn task(
             span.set_parent(parent_cx);
 
             async move {
-                match timeout(
-                    Duration::from_secs(60 * 3), // Timeout after 3 minutes if the build queue hangs or it takes too long for a slot to become available
-                    wait_for_queue(queue_client.clone(), id),
+                match timed_unless_cancelled(
+                    Phase::Queue,
+                    &cancel,
+                    timeout(
+                        Duration::from_secs(60 * 3), // Timeout after 3 minutes if the build queue hangs or it takes too long for a slot to become available
+                        wait_for_queue(queue_client.clone(), id, &cancel),
+                    ),
                 )
                 .await
                 {
                     Ok(_) => {}
-                    Err(err) => return build_failed(&id, err),
+                    Err(err) => {
+                        builds.finish(&id);
+                        return build_failed(&id, Fault::Platform, err);
+                    }
                 }
 
-                match queued
-                    .handle(storage_manager, log_recorder, secret_recorder)
-                    .await
-                {
-                    Ok(built) => {
-                        remove_from_queue(queue_client, id).await;
-                        promote_to_run(built, run_send_cloned).await
-                    }
-                    Err(err) => {
-                        remove_from_queue(queue_client, id).await;
-                        build_failed(&id, err)
+                // A cancel kills the build process, when builds run in one, and otherwise stops the build at its next
+                // step. Either way the build path is only cleaned up once nothing builds in it anymore.
+                let result = timed_unless_cancelled(
+                    Phase::Build,
+                    &cancel,
+                    queued.handle(
+                        storage_manager,
+                        log_recorder,
+                        secret_recorder,
+                        build_recorder,
+                        build_process.as_deref(),
+                        dry_load,
+                        isolate_builds,
+                        &cancel,
+                    ),
+                )
+                .await;
+
+                remove_from_queue(queue_client, id).await;
+
+                if builds.finish(&id) {
+                    // The reaper cancels it like anyone else could, so whether it expired is told by its time to live
+                    if reaper::has_expired(expires_at) {
+                        reaper::expired_cleanup(&id);
+                    } else {
+                        build_cancelled(&id);
                     }
+
+                    return;
+                }
+
+                match result {
+                    Ok(built) => promote_to_run(built, run_send_cloned, &run_queue).await,
+                    Err(err) => build_failed(&id, err.fault(), err),
                 }
             }
             .instrument(span)
@@ -92,17 +219,55 @@ pub async fn task(
 }
 
 #[instrument(skip(_id), fields(id = %_id, state = %State::Crashed))]
-fn build_failed(_id: &Uuid, error: impl std::error::Error + 'static) {
+fn build_failed(_id: &Uuid, fault: Fault, error: impl std::error::Error + 'static) {
     error!(
         error = &error as &dyn std::error::Error,
+        %fault,
         "service build encountered an error"
     );
 }
 
+#[instrument(skip(_id), fields(id = %_id, state = %State::Cancelled))]
+fn build_cancelled(_id: &Uuid) {
+    info!("service build was cancelled");
+}
+
+/// Stop the build at this step when it was cancelled
+fn check_cancelled(cancel: &Cancel) -> Result<()> {
+    if cancel.is_cancelled() {
+        Err(Error::Cancelled)
+    } else {
+        Ok(())
+    }
+}
+
+/// Time a phase of a build like [timed](super::timings::timed) does, except that a cancelled build is left out since it neither went through
+/// nor failed
+async fn timed_unless_cancelled<T, E>(
+    phase: Phase,
+    cancel: &Cancel,
+    phase_future: impl Future<Output = std::result::Result<T, E>>,
+) -> std::result::Result<T, E> {
+    let started = Instant::now();
+    let result = phase_future.await;
+
+    if !cancel.is_cancelled() {
+        PHASE_TIMINGS.observe(phase, result.is_ok(), started.elapsed());
+    }
+
+    result
+}
+
 #[instrument(skip(queue_client), fields(state = %State::Queued))]
-async fn wait_for_queue(queue_client: impl BuildQueueClient, id: Uuid) -> Result<()> {
+async fn wait_for_queue(
+    queue_client: impl BuildQueueClient,
+    id: Uuid,
+    cancel: &Cancel,
+) -> Result<()> {
     trace!("getting a build slot");
     loop {
+        check_cancelled(cancel)?;
+
         let got_slot = queue_client.get_slot(id).await?;
 
         if got_slot {
@@ -127,16 +292,19 @@ async fn remove_from_queue(queue_client: impl BuildQueueClient, id: Uuid) {
     }
 }
 
-#[instrument(fields(id = %built.id, state = %State::Built))]
-async fn promote_to_run(mut built: Built, run_send: RunSender) {
+#[instrument(skip(run_queue), fields(id = %built.id, state = %State::Built))]
+async fn promote_to_run(mut built: Built, run_send: RunSender, run_queue: &RunQueue) {
     let cx = Span::current().context();
 
     opentelemetry::global::get_text_map_propagator(|propagator| {
         propagator.inject_context(&cx, &mut built.tracing_context);
     });
 
+    run_queue.push(built.id, &built.service_name);
+
     if let Err(err) = run_send.send(built.clone()).await {
-        build_failed(&built.id, err);
+        run_queue.take(&built.id);
+        build_failed(&built.id, Fault::Platform, err);
     }
 }
 
@@ -148,32 +316,172 @@ pub struct Queued {
     pub will_run_tests: bool,
     pub tracing_context: HashMap<String, String>,
     pub claim: Option<Claim>,
+    pub pre_stop_hook: Option<String>,
+    /// Where the service's crate is inside the archive, when the archive holds more than just the crate
+    pub crate_path: Option<PathBuf>,
+    /// Build with `--locked` so that the dependencies are exactly those of the archive's `Cargo.lock`
+    pub locked: bool,
+    /// How long the deployment may run for before it is stopped
+    pub run_budget: Option<Duration>,
+    /// When the deployment expires, if it was made with a time to live
+    pub expires_at: Option<DateTime<Utc>>,
 }
 
 impl Queued {
-    #[instrument(skip(self, storage_manager, log_recorder, secret_recorder), fields(id = %self.id, state = %State::Building))]
+    #[allow(clippy::too_many_arguments)]
+    #[instrument(skip(self, storage_manager, log_recorder, secret_recorder, build_recorder, cancel), fields(id = %self.id, state = %State::Building))]
     async fn handle(
         self,
         storage_manager: StorageManager,
         log_recorder: impl LogRecorder,
         secret_recorder: impl SecretRecorder,
+        build_recorder: impl BuildRecorder,
+        build_process: Option<&Path>,
+        dry_load: bool,
+        isolate_builds: bool,
+        cancel: &Cancel,
     ) -> Result<Built> {
+        check_cancelled(cancel)?;
+
+        let build_path = if isolate_builds {
+            storage_manager.deployment_build_path(&self.id)?
+        } else {
+            storage_manager.service_build_path(&self.service_name)?
+        };
+
+        let result = self
+            .build(
+                &build_path,
+                &storage_manager,
+                log_recorder,
+                secret_recorder,
+                build_recorder,
+                build_process,
+                cancel,
+            )
+            .await;
+
+        if isolate_builds {
+            if let Err(error) = fs::remove_dir_all(&build_path).await {
+                warn!(
+                    error = &error as &dyn std::error::Error,
+                    "failed to remove isolated build directory"
+                );
+            }
+        }
+
+        result?;
+        check_cancelled(cancel)?;
+
+        if dry_load {
+            info!("Dry loading built library");
+
+            let so_path = storage_manager.deployment_library_path(&self.id)?;
+            tokio::task::spawn_blocking(move || Loader::dry_load(so_path))
+                .await
+                .map_err(|error| Error::PrepareLoad(error.to_string()))?
+                .map_err(Error::Validation)?;
+        }
+
+        let built = Built {
+            id: self.id,
+            service_name: self.service_name,
+            service_id: self.service_id,
+            tracing_context: Default::default(),
+            claim: self.claim,
+            pre_stop_hook: self.pre_stop_hook,
+            image: None,
+            run_budget: self.run_budget,
+            expires_at: self.expires_at,
+        };
+
+        Ok(built)
+    }
+
+    /// Extract the archive into `project_path`, build it and move the built library into storage
+    async fn build(
+        &self,
+        project_path: &Path,
+        storage_manager: &StorageManager,
+        log_recorder: impl LogRecorder,
+        secret_recorder: impl SecretRecorder,
+        build_recorder: impl BuildRecorder,
+        build_process: Option<&Path>,
+        cancel: &Cancel,
+    ) -> Result<()> {
         info!("Extracting received data");
 
-        let project_path = storage_manager.service_build_path(&self.service_name)?;
+        extract_tar_gz_data(self.data.as_slice(), project_path).await?;
 
-        extract_tar_gz_data(self.data.as_slice(), &project_path).await?;
+        // Path dependencies outside of the crate come along in the archive, but only the crate itself is built
+        let project_root = project_path;
+        let project_path = match &self.crate_path {
+            Some(crate_path) => project_path.join(crate_path),
+            None => project_path.to_path_buf(),
+        };
 
+        // Taken out of the sources straight away, but only applied once the deployment is built. A deployment which
+        // fails to build leaves the secrets the running one uses as they are.
         let secrets = get_secrets(&project_path).await?;
-        set_secrets(secrets, &self.service_id, secret_recorder).await?;
 
-        info!("Building deployment");
+        // Stored next to the library so that it is still around when the deployment is started again later
+        if let Some(probe) = ReadinessProbe::from_project(&project_path)? {
+            info!(?probe, "using the readiness probe of Shuttle.toml");
+            probe.store(&storage_manager.deployment_readiness_probe_path(&self.id)?)?;
+        }
+        if let Some(smoke_test) = SmokeTest::from_project(&project_path)? {
+            info!(?smoke_test, "using the smoke test of Shuttle.toml");
+            smoke_test.store(&storage_manager.deployment_smoke_test_path(&self.id)?)?;
+        }
+        if let Some(policy) = RestartPolicy::from_project(&project_path)? {
+            info!(?policy, "using the restart policy of Shuttle.toml");
+            policy.store(&storage_manager.deployment_restart_policy_path(&self.id)?)?;
+        }
+
+        // Without a lockfile there is nothing to hold the build to, which is fine for a first deployment
+        let locked = self.locked && has_lockfile(project_root, &project_path);
+        if locked {
+            info!(
+                build_line =
+                    "Building with --locked, using the exact dependency versions in Cargo.lock",
+                "Building deployment with --locked"
+            );
+        } else if self.locked {
+            warn!(
+                build_line = "No Cargo.lock was uploaded, dependencies will be resolved to their latest compatible versions",
+                "Building deployment without a lockfile"
+            );
+        } else {
+            info!(
+                build_line = "Building without --locked, Cargo.lock may be updated",
+                "Building deployment without --locked"
+            );
+        }
+
+        if let Err(error) = build_recorder.record_locked(&self.id, locked).await {
+            error!(
+                error = &error as &dyn std::error::Error,
+                "failed to record whether the deployment is built with --locked"
+            );
+        }
+
+        // The total is only needed to report progress, so not being able to get it should not stop the build
+        let total_units = match compile_unit_count(&project_path, true, locked) {
+            Ok(total) => Some(total),
+            Err(error) => {
+                warn!(error = %error, "failed to count the units to compile");
+                None
+            }
+        };
 
         let (tx, rx): (crossbeam_channel::Sender<Message>, _) = crossbeam_channel::bounded(0);
         let id = self.id;
         tokio::task::spawn_blocking(move || {
+            let mut compiled = 0;
+
             while let Ok(message) = rx.recv() {
                 trace!(?message, "received cargo message");
+                let is_artifact = matches!(message, Message::CompilerArtifact(_));
                 // TODO: change these to `info!(...)` as [valuable] support increases.
                 // Currently it is not possible to turn these serde `message`s into a `valuable`, but once it is the passing down of `log_recorder` should be removed.
                 let log = match message {
@@ -203,11 +511,29 @@ impl Queued {
                     },
                 };
                 log_recorder.record(log);
+
+                // Artifacts past the total, like those of the test build, are not counted
+                if let (true, Some(total)) = (is_artifact, total_units) {
+                    if compiled < total {
+                        compiled += 1;
+                        log_recorder.record(build_progress_log(id, compiled, total));
+                    }
+                }
             }
         });
 
         let project_path = project_path.canonicalize()?;
-        let so_path = build_deployment(self.id, &project_path, tx.clone()).await?;
+        check_cancelled(cancel)?;
+        let so_path = build_deployment(
+            self.id,
+            &project_path,
+            locked,
+            build_process,
+            cancel,
+            tx.clone(),
+        )
+        .await?;
+        check_cancelled(cancel)?;
 
         if self.will_run_tests {
             info!(
@@ -215,22 +541,17 @@ impl Queued {
                 "Running deployment's unit tests"
             );
 
-            run_pre_deploy_tests(&project_path, tx).await?;
+            run_pre_deploy_tests(&project_path, locked, tx).await?;
+            check_cancelled(cancel)?;
         }
 
         info!("Moving built library");
 
-        store_lib(&storage_manager, so_path, &self.id).await?;
+        store_lib(storage_manager, so_path, &self.id).await?;
 
-        let built = Built {
-            id: self.id,
-            service_name: self.service_name,
-            service_id: self.service_id,
-            tracing_context: Default::default(),
-            claim: self.claim,
-        };
+        set_secrets(secrets, &self.service_id, secret_recorder).await?;
 
-        Ok(built)
+        Ok(())
     }
 }
 
@@ -241,6 +562,9 @@ impl fmt::Debug for Queued {
             .field("service_name", &self.service_name)
             .field("service_id", &self.service_id)
             .field("will_run_tests", &self.will_run_tests)
+            .field("locked", &self.locked)
+            .field("run_budget", &self.run_budget)
+            .field("expires_at", &self.expires_at)
             .finish_non_exhaustive()
     }
 }
@@ -268,16 +592,17 @@ async fn set_secrets(
     service_id: &Uuid,
     secret_recorder: impl SecretRecorder,
 ) -> Result<()> {
-    for (key, value) in secrets.into_iter() {
-        debug!(key, "setting secret");
-
-        secret_recorder
-            .insert_secret(service_id, &key, &value)
-            .await
-            .map_err(|e| Error::SecretsSet(Box::new(e)))?;
+    // A deployment without a secrets file keeps the secrets the service has
+    if secrets.is_empty() {
+        return Ok(());
     }
 
-    Ok(())
+    debug!(keys = ?secrets.keys().collect::<Vec<_>>(), "setting secrets");
+
+    secret_recorder
+        .replace_secrets(service_id, &secrets)
+        .await
+        .map_err(|e| Error::SecretsSet(Box::new(e)))
 }
 
 /// Equivalent to the command: `tar -xzf --strip-components 1`
@@ -313,15 +638,50 @@ async fn extract_tar_gz_data(data: impl Read, dest: impl AsRef<Path>) -> Result<
     Ok(())
 }
 
-#[instrument(skip(project_path, tx))]
+fn build_progress_log(id: Uuid, compiled: usize, total: usize) -> Log {
+    Log {
+        id,
+        state: State::Building,
+        level: LogLevel::Info,
+        timestamp: Utc::now(),
+        file: None,
+        line: None,
+        target: String::new(),
+        fields: json!({ BUILD_PROGRESS_FIELD: BuildProgress { compiled, total } }),
+        r#type: LogType::Event,
+        address: None,
+    }
+}
+
+/// Whether the crate at `project_path` has a `Cargo.lock`, either of its own or of a workspace it is in. Only
+/// directories inside `project_root` are looked at since nothing outside of it came with the archive.
+fn has_lockfile(project_root: &Path, project_path: &Path) -> bool {
+    project_path
+        .ancestors()
+        .take_while(|dir| dir.starts_with(project_root))
+        .any(|dir| dir.join("Cargo.lock").is_file())
+}
+
+/// Build in a process of `build_process` when given, which a cancel kills straight away. Otherwise the build runs in
+/// the deployer and a cancel has to wait for cargo to finish.
+#[instrument(skip(project_path, cancel, tx))]
 async fn build_deployment(
     deployment_id: Uuid,
     project_path: &Path,
+    locked: bool,
+    build_process: Option<&Path>,
+    cancel: &Cancel,
     tx: crossbeam_channel::Sender<Message>,
 ) -> Result<PathBuf> {
-    let so_path = build_crate(deployment_id, project_path, true, tx)
-        .await
-        .map_err(|e| Error::Build(e.into()))?;
+    let so_path = match build_process {
+        Some(executable) => {
+            build_process::build(executable, deployment_id, project_path, locked, tx, cancel)
+                .await?
+        }
+        None => build_crate(deployment_id, project_path, true, locked, tx)
+            .await
+            .map_err(|e| Error::Build(e.into()))?,
+    };
 
     trace!(?so_path, "got so path");
 
@@ -331,6 +691,7 @@ async fn build_deployment(
 #[instrument(skip(project_path, tx))]
 async fn run_pre_deploy_tests(
     project_path: &Path,
+    locked: bool,
     tx: Sender<Message>,
 ) -> std::result::Result<(), TestError> {
     let (read, write) = pipe::pipe();
@@ -352,7 +713,10 @@ async fn run_pre_deploy_tests(
         }
     });
 
-    let config = get_config(write)?;
+    let mut config = get_config(write)?;
+    if locked {
+        lock_dependencies(&mut config)?;
+    }
     let manifest_path = project_path.join("Cargo.toml");
 
     let ws = Workspace::new(&manifest_path, &config)?;
@@ -397,19 +761,94 @@ async fn store_lib(
     let new_so_path = storage_manager.deployment_library_path(id)?;
 
     fs::rename(so_path, new_so_path).await?;
+    storage_manager.record_library_checksum(id)?;
 
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{collections::BTreeMap, fs::File, io::Write, path::Path};
+    use std::{
+        collections::BTreeMap,
+        fs::File,
+        io::Write,
+        path::Path,
+        sync::{Arc, Mutex},
+    };
 
+    use flate2::{write::GzEncoder, Compression};
     use tempfile::Builder;
     use tokio::fs;
     use uuid::Uuid;
 
-    use crate::{deployment::storage_manager::StorageManager, error::TestError};
+    use super::{BuildRecorder, Cancel, Queued};
+    use crate::{
+        deployment::{
+            deploy_layer::{Log, LogRecorder},
+            storage_manager::StorageManager,
+        },
+        error::TestError,
+        persistence::SecretRecorder,
+    };
+
+    /// Keeps the secrets a deployment sets, and ignores everything else
+    #[derive(Clone, Default)]
+    struct RecorderMock {
+        secrets: Arc<Mutex<Vec<BTreeMap<String, String>>>>,
+    }
+
+    impl LogRecorder for RecorderMock {
+        fn record(&self, _log: Log) {}
+    }
+
+    #[async_trait::async_trait]
+    impl SecretRecorder for RecorderMock {
+        type Err = std::io::Error;
+
+        async fn insert_secret(
+            &self,
+            _service_id: &Uuid,
+            _key: &str,
+            _value: &str,
+        ) -> Result<(), Self::Err> {
+            panic!("deployments set all of their secrets at once")
+        }
+
+        async fn replace_secrets(
+            &self,
+            _service_id: &Uuid,
+            secrets: &BTreeMap<String, String>,
+        ) -> Result<(), Self::Err> {
+            self.secrets.lock().unwrap().push(secrets.clone());
+            Ok(())
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl BuildRecorder for RecorderMock {
+        type Err = std::io::Error;
+
+        async fn record_locked(&self, _id: &Uuid, _locked: bool) -> Result<(), Self::Err> {
+            Ok(())
+        }
+    }
+
+    /// Archive of a crate in the form `extract_tar_gz_data` expects, with everything under a directory of its own
+    fn archive(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::fast()));
+
+        for (path, contents) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, format!("crate/{path}"), contents.as_bytes())
+                .unwrap();
+        }
+
+        builder.into_inner().unwrap().finish().unwrap()
+    }
 
     #[tokio::test]
     async fn extract_tar_gz_data() {
@@ -523,12 +962,12 @@ ff0e55bda1ff01000000000000000000e0079c01ff12a55500280000",
 
         let failure_project_path = root.join("tests/resources/tests-fail");
         assert!(matches!(
-            super::run_pre_deploy_tests(&failure_project_path, tx.clone()).await,
+            super::run_pre_deploy_tests(&failure_project_path, false, tx.clone()).await,
             Err(TestError::Failed(_))
         ));
 
         let pass_project_path = root.join("tests/resources/tests-pass");
-        super::run_pre_deploy_tests(&pass_project_path, tx)
+        super::run_pre_deploy_tests(&pass_project_path, false, tx)
             .await
             .unwrap();
     }
@@ -559,6 +998,62 @@ ff0e55bda1ff01000000000000000000e0079c01ff12a55500280000",
                 .unwrap(),
             "barfoo"
         );
+
+        // Checksum recorded for the stored '.so'?
+        assert_eq!(
+            fs::read_to_string(libs_p.join("shuttle-libs").join(format!("{id}.sha256")))
+                .await
+                .unwrap(),
+            "88ecde925da3c6f8ec3d140683da9d2a422f26c1ae1d9212da1e5a53416dcc88"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn failed_build_keeps_secrets() {
+        let dir = Builder::new().prefix("failed-build").tempdir().unwrap();
+        let storage_manager = StorageManager::new(dir.path().to_path_buf());
+        let recorder = RecorderMock::default();
+
+        let queued = Queued {
+            id: Uuid::new_v4(),
+            service_name: "broken".to_string(),
+            service_id: Uuid::new_v4(),
+            data: archive(&[
+                (
+                    "Cargo.toml",
+                    "[package]\nname = \"broken\"\nversion = \"0.1.0\"\nedition = \"2021\"\n",
+                ),
+                ("src/lib.rs", "this does not compile"),
+                ("Secrets.toml", "KEY = 'new'"),
+            ]),
+            will_run_tests: false,
+            tracing_context: Default::default(),
+            claim: None,
+            pre_stop_hook: None,
+            crate_path: None,
+            locked: false,
+            run_budget: None,
+            expires_at: None,
+        };
+
+        let result = queued
+            .handle(
+                storage_manager,
+                recorder.clone(),
+                recorder.clone(),
+                recorder.clone(),
+                None,
+                false,
+                true,
+                &Cancel::default(),
+            )
+            .await;
+
+        assert!(result.is_err(), "the build should fail");
+        assert!(
+            recorder.secrets.lock().unwrap().is_empty(),
+            "a failed build should not touch the secrets"
+        );
     }
 
     #[tokio::test]
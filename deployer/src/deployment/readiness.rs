@@ -0,0 +1,319 @@
+use std::{fs, io, net::SocketAddr, path::Path, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use shuttle_service::ServeHandle;
+use tokio::{net::TcpStream, time::sleep};
+use tracing::{debug, instrument};
+
+use crate::error::{Error, Result};
+
+/// Check telling when a loaded deployment is ready to receive traffic, set in the `[readiness]` section of
+/// `Shuttle.toml`. The semantics follow those of Kubernetes probes: after the initial delay, the check runs every
+/// interval until it succeeds `success-threshold` times in a row or fails `failure-threshold` times in a row.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ReadinessProbe {
+    #[serde(rename = "type")]
+    pub kind: ProbeKind,
+    /// Path requested by HTTP probes
+    pub path: String,
+    /// Port to probe, when the service listens on one besides the address it was given
+    pub port: Option<u16>,
+    pub initial_delay_secs: u64,
+    pub interval_secs: u64,
+    pub timeout_secs: u64,
+    pub success_threshold: u32,
+    pub failure_threshold: u32,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProbeKind {
+    /// A GET request answered with a success status
+    #[default]
+    Http,
+    /// A TCP connection being accepted
+    Tcp,
+    /// Ready as soon as it is loaded
+    None,
+}
+
+/// Only used to pick the `[readiness]` section out of `Shuttle.toml`
+#[derive(Deserialize)]
+struct ShuttleToml {
+    readiness: Option<ReadinessProbe>,
+}
+
+impl Default for ReadinessProbe {
+    /// What keys left out of a `[readiness]` section fall back to, starting with an HTTP request to the root
+    fn default() -> Self {
+        Self {
+            kind: ProbeKind::Http,
+            path: "/".to_string(),
+            port: None,
+            initial_delay_secs: 0,
+            interval_secs: 1,
+            timeout_secs: 1,
+            success_threshold: 1,
+            failure_threshold: 30,
+        }
+    }
+}
+
+impl ReadinessProbe {
+    /// The check run when `Shuttle.toml` does not configure one. It gives up quickly since plenty of services, like
+    /// bots, never answer HTTP requests.
+    pub fn fallback() -> Self {
+        Self {
+            interval_secs: 0,
+            failure_threshold: 4,
+            ..Default::default()
+        }
+    }
+
+    /// Read the probe configured in the `Shuttle.toml` of a project, if any
+    pub fn from_project(project_path: &Path) -> Result<Option<Self>> {
+        let config_path = project_path.join("Shuttle.toml");
+
+        if !config_path.is_file() {
+            return Ok(None);
+        }
+
+        let config: ShuttleToml = toml::from_str(&fs::read_to_string(config_path)?)
+            .map_err(|error| Error::ReadinessConfig(error.to_string()))?;
+
+        if let Some(probe) = &config.readiness {
+            probe.validate()?;
+        }
+
+        Ok(config.readiness)
+    }
+
+    /// Read a probe stored with [ReadinessProbe::store]
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        match fs::read(path) {
+            Ok(probe) => serde_json::from_slice(&probe)
+                .map(Some)
+                .map_err(|error| Error::ReadinessConfig(error.to_string())),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    pub fn store(&self, path: &Path) -> Result<()> {
+        let probe = serde_json::to_vec(self).expect("a probe to serialize");
+
+        Ok(fs::write(path, probe)?)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if !self.path.starts_with('/') {
+            return Err(Error::ReadinessConfig(
+                "the probe path should start with '/'".to_string(),
+            ));
+        }
+
+        if self.success_threshold == 0 || self.failure_threshold == 0 {
+            return Err(Error::ReadinessConfig(
+                "the success and failure thresholds should be at least 1".to_string(),
+            ));
+        }
+
+        if self.timeout_secs == 0 {
+            return Err(Error::ReadinessConfig(
+                "the probe timeout should be at least 1 second".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Probe the service at `address` until it is ready. Returns the reason of the last failed check once the
+    /// failure threshold is reached.
+    ///
+    /// A service which stops while being probed is left for the caller to deal with, as it would be once running.
+    #[instrument(skip(self, handle), fields(kind = ?self.kind, path = %self.path))]
+    pub async fn wait_until_ready(
+        &self,
+        address: SocketAddr,
+        handle: &ServeHandle,
+    ) -> std::result::Result<(), String> {
+        if self.kind == ProbeKind::None {
+            return Ok(());
+        }
+
+        let address = SocketAddr::new(address.ip(), self.port.unwrap_or(address.port()));
+        let timeout = Duration::from_secs(self.timeout_secs);
+        let client = reqwest::Client::new();
+        let mut successes = 0;
+        let mut failures = 0;
+
+        sleep(Duration::from_secs(self.initial_delay_secs)).await;
+
+        loop {
+            if handle.is_finished() {
+                return Ok(());
+            }
+
+            match self.check(&client, address, timeout).await {
+                Ok(()) => {
+                    failures = 0;
+                    successes += 1;
+
+                    if successes >= self.success_threshold {
+                        return Ok(());
+                    }
+                }
+                Err(reason) => {
+                    debug!(reason, "readiness check failed");
+                    successes = 0;
+                    failures += 1;
+
+                    if failures >= self.failure_threshold {
+                        return Err(reason);
+                    }
+                }
+            }
+
+            // Even without an interval, give the service a moment between checks
+            sleep(Duration::from_secs(self.interval_secs).max(Duration::from_millis(250))).await;
+        }
+    }
+
+    async fn check(
+        &self,
+        client: &reqwest::Client,
+        address: SocketAddr,
+        timeout: Duration,
+    ) -> std::result::Result<(), String> {
+        match self.kind {
+            ProbeKind::Http => {
+                let response = client
+                    .get(format!("http://{address}{}", self.path))
+                    .timeout(timeout)
+                    .send()
+                    .await
+                    .map_err(|error| error.to_string())?;
+
+                if response.status().is_success() {
+                    Ok(())
+                } else {
+                    Err(format!("{} answered with {}", self.path, response.status()))
+                }
+            }
+            ProbeKind::Tcp => {
+                match tokio::time::timeout(timeout, TcpStream::connect(address)).await {
+                    Ok(Ok(_)) => Ok(()),
+                    Ok(Err(error)) => Err(error.to_string()),
+                    Err(_) => Err(format!("no connection within {}s", timeout.as_secs())),
+                }
+            }
+            ProbeKind::None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        convert::Infallible,
+        net::{Ipv4Addr, SocketAddr},
+    };
+
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Body, Request, Response, Server, StatusCode,
+    };
+    use tempfile::Builder;
+
+    use super::{ProbeKind, ReadinessProbe};
+
+    #[test]
+    fn from_project() {
+        let dir = Builder::new().prefix("readiness").tempdir().unwrap();
+
+        assert_eq!(ReadinessProbe::from_project(dir.path()).unwrap(), None);
+
+        std::fs::write(dir.path().join("Shuttle.toml"), "name = 'matrix'\n").unwrap();
+        assert_eq!(ReadinessProbe::from_project(dir.path()).unwrap(), None);
+
+        std::fs::write(
+            dir.path().join("Shuttle.toml"),
+            r#"
+name = "matrix"
+
+[readiness]
+type = "http"
+path = "/health"
+interval-secs = 5
+failure-threshold = 3
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            ReadinessProbe::from_project(dir.path()).unwrap(),
+            Some(ReadinessProbe {
+                path: "/health".to_string(),
+                interval_secs: 5,
+                failure_threshold: 3,
+                ..Default::default()
+            })
+        );
+
+        std::fs::write(
+            dir.path().join("Shuttle.toml"),
+            "[readiness]\npath = 'health'\n",
+        )
+        .unwrap();
+        assert!(ReadinessProbe::from_project(dir.path()).is_err());
+    }
+
+    #[tokio::test]
+    async fn wait_until_ready() {
+        let addr = SocketAddr::new(
+            Ipv4Addr::LOCALHOST.into(),
+            portpicker::pick_unused_port().unwrap(),
+        );
+
+        // Stand in for a service which is only healthy on one path
+        let make_service = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+                let status = if req.uri().path() == "/health" {
+                    StatusCode::OK
+                } else {
+                    StatusCode::SERVICE_UNAVAILABLE
+                };
+
+                Ok::<_, Infallible>(
+                    Response::builder()
+                        .status(status)
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+            }))
+        });
+        tokio::spawn(Server::bind(&addr).serve(make_service));
+
+        let handle = tokio::spawn(std::future::pending::<Result<(), shuttle_service::Error>>());
+        let probe = ReadinessProbe {
+            path: "/health".to_string(),
+            interval_secs: 0,
+            failure_threshold: 2,
+            ..Default::default()
+        };
+
+        probe.wait_until_ready(addr, &handle).await.unwrap();
+
+        let unhealthy = ReadinessProbe {
+            path: "/".to_string(),
+            ..probe.clone()
+        };
+        assert!(unhealthy.wait_until_ready(addr, &handle).await.is_err());
+
+        let tcp = ReadinessProbe {
+            kind: ProbeKind::Tcp,
+            ..unhealthy
+        };
+        tcp.wait_until_ready(addr, &handle).await.unwrap();
+    }
+}
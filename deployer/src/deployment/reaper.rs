@@ -0,0 +1,86 @@
+//! Deployments made with a time to live, for things like the preview of a pull request.
+//!
+//! A deployment expires once its time to live is up, counted from when it was made, whether it got to run by then or
+//! not. Every [SWEEP_INTERVAL] the expired deployments which are not done yet have their build cancelled, or are
+//! stopped going through their pre-stop hook. The task which holds the deployment then records it as `Expired`, since
+//! only it knows when the deployment actually stopped. A deployment can outlive its time to live by up to one interval.
+
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use tokio::time::interval;
+use tracing::{debug, error, info, instrument};
+use uuid::Uuid;
+
+use super::DeploymentManager;
+use crate::persistence::State;
+
+const SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+
+#[async_trait]
+pub trait ExpiredDeploymentsGetter: Clone + Send + Sync + 'static {
+    type Err: std::error::Error + Send;
+
+    /// Deployments which expired by `now`, but are still on their way to running or running
+    async fn get_expired_deployments(&self, now: DateTime<Utc>) -> Result<Vec<Uuid>, Self::Err>;
+}
+
+/// Run a task which stops deployments once they expire
+pub async fn task(
+    expired_deployments_getter: impl ExpiredDeploymentsGetter,
+    deployment_manager: DeploymentManager,
+) {
+    let mut interval = interval(SWEEP_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let expired = match expired_deployments_getter
+            .get_expired_deployments(Utc::now())
+            .await
+        {
+            Ok(expired) => expired,
+            Err(error) => {
+                error!(
+                    error = &error as &dyn std::error::Error,
+                    "failed to get expired deployments"
+                );
+                continue;
+            }
+        };
+
+        // Deployments still stopping since an earlier sweep are told again, which they pay no attention to
+        for id in expired {
+            debug!(%id, "deployment expired, stopping it");
+
+            // Only one of them reaches the deployment, depending on whether it was built yet
+            deployment_manager.cancel(id).await;
+            deployment_manager.kill(id).await;
+        }
+    }
+}
+
+/// Whether a deployment which expires at `expires_at` has expired already
+pub fn has_expired(expires_at: Option<DateTime<Utc>>) -> bool {
+    expires_at.map_or(false, |expires_at| expires_at <= Utc::now())
+}
+
+#[instrument(skip(_id), fields(id = %_id, state = %State::Expired))]
+pub fn expired_cleanup(_id: &Uuid) {
+    info!("deployment was stopped because it outlived its time to live");
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, Utc};
+
+    use super::has_expired;
+
+    #[test]
+    fn expiry() {
+        assert!(!has_expired(None));
+        assert!(!has_expired(Some(Utc::now() + Duration::minutes(1))));
+        assert!(has_expired(Some(Utc::now() - Duration::minutes(1))));
+    }
+}
@@ -0,0 +1,89 @@
+use std::{fs, io, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+
+/// When a deployment is restarted on its own, set in the `[restart]` section of `Shuttle.toml`. Without one, changes
+/// like new secrets only reach a service on its next deployment or restart.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct RestartPolicy {
+    /// Restart the running deployment whenever its secrets are changed, so that it loads them again
+    pub on_secret_change: bool,
+}
+
+/// Only used to pick the `[restart]` section out of `Shuttle.toml`
+#[derive(Deserialize)]
+struct ShuttleToml {
+    restart: Option<RestartPolicy>,
+}
+
+impl RestartPolicy {
+    /// Read the policy configured in the `Shuttle.toml` of a project, if any
+    pub fn from_project(project_path: &Path) -> Result<Option<Self>> {
+        let config_path = project_path.join("Shuttle.toml");
+
+        if !config_path.is_file() {
+            return Ok(None);
+        }
+
+        let config: ShuttleToml = toml::from_str(&fs::read_to_string(config_path)?)
+            .map_err(|error| Error::RestartPolicyConfig(error.to_string()))?;
+
+        Ok(config.restart)
+    }
+
+    /// Read a policy stored with [RestartPolicy::store], or the default one when the deployment was built without any
+    pub fn load(path: &Path) -> Result<Self> {
+        match fs::read(path) {
+            Ok(policy) => serde_json::from_slice(&policy)
+                .map_err(|error| Error::RestartPolicyConfig(error.to_string())),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    pub fn store(&self, path: &Path) -> Result<()> {
+        let policy = serde_json::to_vec(self).expect("a restart policy to serialize");
+
+        Ok(fs::write(path, policy)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::Builder;
+
+    use super::RestartPolicy;
+
+    #[test]
+    fn from_project() {
+        let dir = Builder::new().prefix("restart-policy").tempdir().unwrap();
+
+        assert_eq!(RestartPolicy::from_project(dir.path()).unwrap(), None);
+
+        std::fs::write(dir.path().join("Shuttle.toml"), "name = 'matrix'\n").unwrap();
+        assert_eq!(RestartPolicy::from_project(dir.path()).unwrap(), None);
+
+        std::fs::write(
+            dir.path().join("Shuttle.toml"),
+            "[restart]\non-secret-change = true\n",
+        )
+        .unwrap();
+        let policy = RestartPolicy::from_project(dir.path()).unwrap().unwrap();
+        assert!(policy.on_secret_change);
+
+        let stored = dir.path().join("policy.json");
+        assert_eq!(RestartPolicy::load(&stored).unwrap(), Default::default());
+        policy.store(&stored).unwrap();
+        assert_eq!(RestartPolicy::load(&stored).unwrap(), policy);
+
+        std::fs::write(
+            dir.path().join("Shuttle.toml"),
+            "[restart]\non-deploy = true\n",
+        )
+        .unwrap();
+        assert!(RestartPolicy::from_project(dir.path()).is_err());
+    }
+}
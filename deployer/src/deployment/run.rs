@@ -1,60 +1,121 @@
 use std::{
     collections::HashMap,
+    fs,
     net::{Ipv4Addr, SocketAddr},
-    path::PathBuf,
+    path::{Path, PathBuf},
     str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use opentelemetry::global;
 use portpicker::pick_unused_port;
 use shuttle_common::{backends::auth::Claim, project::ProjectName as ServiceName};
 use shuttle_service::{
-    loader::{LoadedService, Loader},
-    Factory, Logger,
+    loader::{Library, LoadedService, Loader},
+    Factory, Logger, ServeHandle,
 };
-use tokio::task::JoinError;
-use tracing::{debug, debug_span, error, info, instrument, trace, Instrument};
+use tokio::{task::JoinError, time::Instant};
+use tracing::{debug, debug_span, error, info, instrument, trace, warn, Instrument};
 use tracing_opentelemetry::OpenTelemetrySpanExt;
 use uuid::Uuid;
 
 use super::{
-    provisioner_factory, runtime_logger, storage_manager::StorageManager, KillReceiver, KillSender,
-    RunReceiver, State,
+    drain::{DrainReplaced, IN_FLIGHT},
+    image::ImageRuntime,
+    memory,
+    output::CapturedOutput,
+    provisioner_factory,
+    readiness::ReadinessProbe,
+    reaper,
+    run_queue::RunQueue,
+    runtime_logger,
+    smoke_test::SmokeTest,
+    storage_manager::{file_checksum, StorageManager},
+    timings::{timed, Phase},
+    KillReceiver, KillSender, RunReceiver, ShutdownReceiver, State,
 };
-use crate::error::{Error, Result};
+use crate::args::ShutdownPolicy;
+use crate::error::{service_error_fault, Error, Fault, Result};
+
+/// Maximum time a deployment's pre-stop hook gets before the deployment is aborted anyway
+const PRE_STOP_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// How long a deployment replacing others which are drained is waited on to get the traffic of its service
+const ROUTING_TIMEOUT: Duration = Duration::from_secs(5);
+const ROUTING_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A started deployment, along with the library it was loaded from unless it runs from a container image
+type Started = (ServeHandle, Option<Library>);
 
 /// Run a task which takes runnable deploys from a channel and starts them up with a factory provided by the
 /// abstract factory and a runtime logger provided by the logger factory
 /// A deploy is killed when it receives a signal from the kill channel
+/// Once `draining` is set, deploys are no longer started and crash instead
+/// Once the deployer shuts down, no more deploys are taken from the channel
+/// Deploys of a container image are run with the image runtime, and crash when there is none
+/// With `drain_replaced`, a deploy takes over the traffic of its service before the deploy it replaces is stopped
+#[allow(clippy::too_many_arguments)]
 pub async fn task(
     mut recv: RunReceiver,
     kill_send: KillSender,
+    mut shutdown_recv: ShutdownReceiver,
+    run_queue: Arc<RunQueue>,
     abstract_factory: impl provisioner_factory::AbstractFactory,
     logger_factory: impl runtime_logger::Factory,
     active_deployment_getter: impl ActiveDeploymentsGetter,
     storage_manager: StorageManager,
+    image_runtime: Option<ImageRuntime>,
+    draining: Arc<AtomicBool>,
+    drain_replaced: Option<DrainReplaced>,
 ) {
     info!("Run task started");
 
-    while let Some(built) = recv.recv().await {
+    loop {
+        let built = tokio::select! {
+            Some(built) = recv.recv() => built,
+            Ok(()) = shutdown_recv.changed() => {
+                info!("deployer is shutting down, no longer taking deployments from the run queue");
+                break;
+            }
+            else => break,
+        };
         let id = built.id;
+        run_queue.take(&id);
 
         info!("Built deployment at the front of run queue: {id}");
 
+        // It may have waited for its turn past its expiry, or been picked up again after the deployer restarted
+        if reaper::has_expired(built.expires_at) {
+            reaper::expired_cleanup(&id);
+            continue;
+        }
+
+        if draining.load(Ordering::SeqCst) {
+            let err = Error::Draining;
+            start_crashed_cleanup(&id, err.fault(), err);
+            continue;
+        }
+
         let kill_send = kill_send.clone();
         let kill_recv = kill_send.subscribe();
+        let shutdown_recv = shutdown_recv.clone();
         let storage_manager = storage_manager.clone();
+        let image_runtime = image_runtime.clone();
+        let drain_replaced = drain_replaced.clone();
 
         let port = match pick_unused_port() {
             Some(port) => port,
             None => {
-                start_crashed_cleanup(
-                    &id,
-                    Error::PrepareLoad(
-                        "could not find a free port to deploy service on".to_string(),
-                    ),
+                let err = Error::PrepareLoad(
+                    "could not find a free port to deploy service on".to_string(),
                 );
+                start_crashed_cleanup(&id, err.fault(), err);
                 continue;
             }
         };
@@ -62,7 +123,7 @@ pub async fn task(
         let service_name = match ServiceName::from_str(&built.service_name) {
             Ok(name) => name,
             Err(err) => {
-                start_crashed_cleanup(&id, err);
+                start_crashed_cleanup(&id, Fault::Platform, err);
                 continue;
             }
         };
@@ -78,28 +139,36 @@ pub async fn task(
         {
             Ok(factory) => factory,
             Err(err) => {
-                start_crashed_cleanup(&id, err);
+                start_crashed_cleanup(&id, Fault::Platform, err);
                 continue;
             }
         };
         let logger = logger_factory.get_logger(id);
+        // What a service loaded into the deployer prints goes to its logs, while containers have output of their own
+        let output = match built.image {
+            Some(_) => None,
+            None => logger_factory.capture_output(id),
+        };
 
         let old_deployments_killer = kill_old_deployments(
             built.service_id,
             id,
             active_deployment_getter.clone(),
             kill_send,
+            drain_replaced.clone(),
         );
+        let deployments_getter = active_deployment_getter.clone();
         let cleanup = move |result: std::result::Result<
             std::result::Result<(), shuttle_service::Error>,
             JoinError,
         >| match result {
             Ok(inner) => match inner {
                 Ok(()) => completed_cleanup(&id),
-                Err(err) => crashed_cleanup(&id, err),
+                Err(err) => crashed_cleanup(&id, service_error_fault(&err), err),
             },
             Err(err) if err.is_cancelled() => stopped_cleanup(&id),
-            Err(err) => start_crashed_cleanup(&id, err),
+            // The service's task panicked
+            Err(err) => crashed_cleanup(&id, Fault::User, err),
         };
 
         tokio::spawn(async move {
@@ -114,15 +183,20 @@ pub async fn task(
                     .handle(
                         addr,
                         storage_manager,
+                        image_runtime,
                         &mut factory,
                         logger,
+                        output,
                         kill_recv,
+                        shutdown_recv,
+                        deployments_getter,
                         old_deployments_killer,
+                        drain_replaced,
                         cleanup,
                     )
                     .await
                 {
-                    start_crashed_cleanup(&id, err)
+                    start_crashed_cleanup(&id, err.fault(), err)
                 }
 
                 info!("deployment done");
@@ -133,12 +207,13 @@ pub async fn task(
     }
 }
 
-#[instrument(skip(active_deployment_getter, kill_send))]
+#[instrument(skip(active_deployment_getter, kill_send, drain_replaced))]
 async fn kill_old_deployments(
     service_id: Uuid,
     deployment_id: Uuid,
     active_deployment_getter: impl ActiveDeploymentsGetter,
     kill_send: KillSender,
+    drain_replaced: Option<DrainReplaced>,
 ) -> Result<()> {
     for old_id in active_deployment_getter
         .clone()
@@ -149,6 +224,9 @@ async fn kill_old_deployments(
         .filter(|old_id| old_id != &deployment_id)
     {
         trace!(%old_id, "stopping old deployment");
+        if let Some(drain_replaced) = &drain_replaced {
+            drain_replaced.replace(old_id);
+        }
         kill_send
             .send(old_id)
             .map_err(|e| Error::OldCleanup(Box::new(e)))?;
@@ -167,19 +245,52 @@ fn stopped_cleanup(_id: &Uuid) {
     info!("service was stopped by the user");
 }
 
-#[instrument(skip(_id), fields(id = %_id, state = %State::Crashed))]
-fn crashed_cleanup(_id: &Uuid, error: impl std::error::Error + 'static) {
-    error!(
-        error = &error as &dyn std::error::Error,
-        "service encountered an error"
+#[instrument(skip(_id), fields(id = %_id, state = %State::BudgetExceeded))]
+fn budget_exceeded_cleanup(_id: &Uuid, run_budget: Duration) {
+    info!(
+        "service was stopped after running for its budget of {}s",
+        run_budget.as_secs()
     );
 }
 
-#[instrument(skip(_id), fields(id = %_id, state = %State::Crashed))]
-fn start_crashed_cleanup(_id: &Uuid, error: impl std::error::Error + 'static) {
+#[instrument(skip(id, error), fields(id = %id, state = %State::Crashed))]
+fn crashed_cleanup(id: &Uuid, fault: Fault, error: impl std::error::Error + 'static) {
+    match fault {
+        Fault::User => error!(
+            error = &error as &dyn std::error::Error,
+            %fault,
+            "your service encountered an error"
+        ),
+        Fault::Platform => report_platform_error(id, &error, "service was stopped"),
+    }
+}
+
+#[instrument(skip(id, error), fields(id = %id, state = %State::Crashed))]
+fn start_crashed_cleanup(id: &Uuid, fault: Fault, error: impl std::error::Error + 'static) {
+    match fault {
+        Fault::User => error!(
+            error = &error as &dyn std::error::Error,
+            %fault,
+            "your service encountered an error while starting up"
+        ),
+        Fault::Platform => report_platform_error(id, &error, "service could not be started"),
+    }
+}
+
+/// Platform errors can leak internal details, so only a generic message goes to the deployment's logs while the
+/// details go to the operator logs. The message is tagged with the fault, which tells clients the deployment may go
+/// through on another try.
+fn report_platform_error(id: &Uuid, error: &(dyn std::error::Error + 'static), what: &str) {
+    // Without a parent, this event is outside the deployment's scope and is not recorded in its logs
     error!(
-        error = &error as &dyn std::error::Error,
-        "service startup encountered an error"
+        parent: None,
+        deployment_id = %id,
+        error,
+        "platform error in deployment"
+    );
+    error!(
+        fault = %Fault::Platform,
+        "{what} because of an internal platform error, this was not caused by your code"
     );
 }
 
@@ -187,6 +298,7 @@ fn start_crashed_cleanup(_id: &Uuid, error: impl std::error::Error + 'static) {
 pub trait ActiveDeploymentsGetter: Clone + Send + Sync + 'static {
     type Err: std::error::Error + Send;
 
+    /// Get the running deployments of a service, in the order they started running
     async fn get_active_deployments(
         &self,
         service_id: &Uuid,
@@ -200,108 +312,432 @@ pub struct Built {
     pub service_id: Uuid,
     pub tracing_context: HashMap<String, String>,
     pub claim: Option<Claim>,
+    /// Path on the service to call before it is stopped
+    pub pre_stop_hook: Option<String>,
+    /// Container image to run instead of a library built from source
+    pub image: Option<String>,
+    /// How long the deployment may run for before it is stopped
+    pub run_budget: Option<Duration>,
+    /// When the deployment expires, if it was made with a time to live
+    pub expires_at: Option<DateTime<Utc>>,
 }
 
 impl Built {
-    #[instrument(skip(self, storage_manager, factory, logger, kill_recv, kill_old_deployments, cleanup), fields(id = %self.id, state = %State::Loading))]
+    #[instrument(skip(self, storage_manager, image_runtime, factory, logger, output, kill_recv, shutdown_recv, active_deployment_getter, kill_old_deployments, drain_replaced, cleanup), fields(id = %self.id, state = %State::Loading))]
     #[allow(clippy::too_many_arguments)]
     async fn handle(
         self,
         address: SocketAddr,
         storage_manager: StorageManager,
+        image_runtime: Option<ImageRuntime>,
         factory: &mut dyn Factory,
         logger: Logger,
+        output: Option<CapturedOutput>,
         kill_recv: KillReceiver,
+        shutdown_recv: ShutdownReceiver,
+        active_deployment_getter: impl ActiveDeploymentsGetter,
         kill_old_deployments: impl futures::Future<Output = Result<()>>,
+        drain_replaced: Option<DrainReplaced>,
         cleanup: impl FnOnce(std::result::Result<std::result::Result<(), shuttle_service::Error>, JoinError>)
             + Send
             + 'static,
     ) -> Result<()> {
-        let so_path = storage_manager.deployment_library_path(&self.id)?;
-        let service = load_deployment(address, so_path, factory, logger).await?;
+        let probe_path = storage_manager.deployment_readiness_probe_path(&self.id)?;
+        let probe = ReadinessProbe::load(&probe_path)?;
+        let smoke_test = SmokeTest::load(&storage_manager.deployment_smoke_test_path(&self.id)?)?;
+        let mut warm = None;
+        let mut service = timed(Phase::Load, async {
+            Ok::<_, Error>(match &self.image {
+                Some(image) => {
+                    let image_runtime = image_runtime.ok_or_else(|| {
+                        Error::PrepareLoad(
+                            "this deployer does not run container images".to_string(),
+                        )
+                    })?;
+                    let secrets = factory.get_secrets().await?;
+                    let handle = image_runtime
+                        .start(&self.id, image, address, secrets, logger)
+                        .await?;
+
+                    (handle, None)
+                }
+                None => {
+                    // Only there to speed the load up, which reads the library from disk otherwise
+                    warm = match storage_manager.warm_library(&self.id).await {
+                        Ok(warm) => warm,
+                        Err(error) => {
+                            warn!(
+                                error = &error as &dyn std::error::Error,
+                                "failed to warm the artifact of the deployment"
+                            );
+                            None
+                        }
+                    };
+                    let so_path = storage_manager.deployment_library_path(&self.id)?;
+                    let checksum_path =
+                        storage_manager.deployment_library_checksum_path(&self.id)?;
+                    let (handle, library) =
+                        load_deployment(address, so_path, checksum_path, factory, logger).await?;
+
+                    (handle, Some(library))
+                }
+            })
+        })
+        .await?;
 
-        kill_old_deployments.await?;
+        timed(
+            Phase::Readiness,
+            wait_until_ready(address, &mut service, probe),
+        )
+        .await?;
+        timed(
+            Phase::SmokeTest,
+            run_smoke_test(address, &mut service, smoke_test),
+        )
+        .await?;
+
+        // Only now is the deployment before this one stopped, so it keeps the traffic when this one fails a check
+        if drain_replaced.is_none() {
+            kill_old_deployments.await?;
+        }
 
         info!("got handle for deployment");
         // Execute loaded service
-        tokio::spawn(run(self.id, service, address, kill_recv, cleanup));
+        let running = run(
+            self.id,
+            service,
+            address,
+            kill_recv,
+            shutdown_recv,
+            self.pre_stop_hook,
+            self.run_budget,
+            self.expires_at,
+            drain_replaced.clone(),
+            cleanup,
+        );
+        tokio::spawn(async move {
+            // The artifact is kept warm, and the output of the service captured, for as long as the deployment runs
+            let _warm = warm;
+            let _output = output;
+            running.await
+        });
+
+        // When draining, the old deployments finish their requests while this one takes the new ones
+        if drain_replaced.is_some() {
+            wait_until_routed(self.id, self.service_id, &active_deployment_getter).await;
+
+            if let Err(error) = kill_old_deployments.await {
+                error!(
+                    error = &error as &dyn std::error::Error,
+                    "failed to stop the deployments this one replaces"
+                );
+            }
+        }
 
         Ok(())
     }
 }
 
-#[instrument(skip(service, kill_recv, cleanup), fields(address = %_address, state = %State::Running))]
+#[instrument(skip(service, kill_recv, shutdown_recv, drain_replaced, cleanup), fields(address = %address, state = %State::Running))]
+#[allow(clippy::too_many_arguments)]
 async fn run(
     id: Uuid,
-    service: LoadedService,
-    _address: SocketAddr,
+    service: Started,
+    address: SocketAddr,
     mut kill_recv: KillReceiver,
+    shutdown_recv: ShutdownReceiver,
+    pre_stop_hook: Option<String>,
+    run_budget: Option<Duration>,
+    expires_at: Option<DateTime<Utc>>,
+    drain_replaced: Option<DrainReplaced>,
     cleanup: impl FnOnce(std::result::Result<std::result::Result<(), shuttle_service::Error>, JoinError>)
         + Send
         + 'static,
 ) {
     info!("starting up service");
+    memory::reset_peak();
     let (mut handle, library) = service;
+    let mut shutdown_recv = Some(shutdown_recv);
+    let budget_deadline = run_budget.map(|run_budget| Instant::now() + run_budget);
+    let mut budget_exceeded = false;
+    let mut expired = false;
     let result;
     loop {
         tokio::select! {
-             Ok(kill_id) = kill_recv.recv() => {
-                 if kill_id == id {
-                     debug!("deployment '{id}' killed");
-                     handle.abort();
-                     result = handle.await;
-                     break;
+             () = kill_recv.killed(&id) => {
+                 debug!("deployment '{id}' killed");
+                 // The reaper kills it like any other stop, so whether it expired is told by its time to live
+                 expired = reaper::has_expired(expires_at);
+                 if let Some(drain_replaced) = &drain_replaced {
+                     drain_replaced.drain(&IN_FLIGHT, id, address).await;
                  }
+                 result = stop(&mut handle, address, pre_stop_hook.as_deref()).await;
+                 break;
+             }
+             () = budget_spent(budget_deadline) => {
+                 debug!("deployment '{id}' used up its run budget");
+                 let _ = stop(&mut handle, address, pre_stop_hook.as_deref()).await;
+                 budget_exceeded = true;
+                 result = Ok(Ok(()));
+                 break;
              }
              rsl = &mut handle => {
                  result = rsl;
                  break;
              }
+             policy = shutdown_policy(&mut shutdown_recv) => match policy {
+                 ShutdownPolicy::Stop => {
+                     debug!("deployment '{id}' stopped for deployer shutdown");
+                     let _ = stop(&mut handle, address, pre_stop_hook.as_deref()).await;
+
+                     // No state change is recorded, so the deployment is started again once the deployer is back
+                     if let Err(err) = close(library) {
+                         crashed_cleanup(&id, Fault::Platform, err);
+                     }
+                     return;
+                 }
+                 ShutdownPolicy::Detach => {
+                     debug!("deployment '{id}' left running for deployer shutdown");
+                     // Dropping the receiver tells the deployer not to wait on this deployment
+                     shutdown_recv = None;
+                 }
+             }
         }
     }
 
-    if let Err(err) = library.close() {
-        crashed_cleanup(&id, err);
+    if let Err(err) = close(library) {
+        crashed_cleanup(&id, Fault::Platform, err);
+    } else if budget_exceeded {
+        budget_exceeded_cleanup(&id, run_budget.unwrap_or_default());
+    } else if expired {
+        reaper::expired_cleanup(&id);
     } else {
         cleanup(result);
     }
 }
 
-#[instrument(skip(addr, so_path, factory, logger))]
+/// Let go of the library a deployment was loaded from, if it was not started from an image
+fn close(library: Option<Library>) -> std::result::Result<(), impl std::error::Error + 'static> {
+    library.map_or(Ok(()), Library::close)
+}
+
+/// Wait for a deployment to be recorded as the latest running one of its service, which is the one the proxy sends
+/// requests to. Gives up after a while, since the deployments it replaces have to be stopped either way.
+async fn wait_until_routed(
+    id: Uuid,
+    service_id: Uuid,
+    active_deployment_getter: &impl ActiveDeploymentsGetter,
+) {
+    let deadline = Instant::now() + ROUTING_TIMEOUT;
+
+    while Instant::now() < deadline {
+        match active_deployment_getter
+            .get_active_deployments(&service_id)
+            .await
+        {
+            Ok(active) if active.last() == Some(&id) => return,
+            Ok(_) => {}
+            Err(error) => error!(
+                error = &error as &dyn std::error::Error,
+                "failed to get active deployments"
+            ),
+        }
+
+        tokio::time::sleep(ROUTING_INTERVAL).await;
+    }
+
+    warn!("deployment '{id}' was not recorded as running in time, stopping the deployments it replaces anyway");
+}
+
+/// Give the service the chance to clean up with its pre-stop hook, if it has one, before aborting it
+async fn stop(
+    handle: &mut ServeHandle,
+    address: SocketAddr,
+    pre_stop_hook: Option<&str>,
+) -> std::result::Result<std::result::Result<(), shuttle_service::Error>, JoinError> {
+    if let Some(path) = pre_stop_hook {
+        call_pre_stop_hook(address, path).await;
+    }
+
+    handle.abort();
+    handle.await
+}
+
+/// Wait for the deployer to shut down, which is never once a deployment is detached from it
+async fn shutdown_policy(shutdown_recv: &mut Option<ShutdownReceiver>) -> ShutdownPolicy {
+    if let Some(shutdown_recv) = shutdown_recv {
+        while shutdown_recv.changed().await.is_ok() {
+            if let Some(policy) = *shutdown_recv.borrow() {
+                return policy;
+            }
+        }
+    }
+
+    futures::future::pending().await
+}
+
+/// Wait for the deadline of a deployment's run budget, which never comes for a deployment without a budget. The
+/// deadline is fixed when the deployment starts running, so it is not pushed back every time the loop comes around.
+async fn budget_spent(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => futures::future::pending().await,
+    }
+}
+
+/// Hold the deployment back from running until its readiness probe passes. Without a configured probe the root of
+/// the service is checked briefly, but a service failing that check is not stopped.
+async fn wait_until_ready(
+    address: SocketAddr,
+    (handle, _): &mut Started,
+    probe: Option<ReadinessProbe>,
+) -> Result<()> {
+    match probe {
+        Some(probe) => {
+            if let Err(reason) = probe.wait_until_ready(address, handle).await {
+                // The library may only be closed once the service is done with it
+                handle.abort();
+                let _ = handle.await;
+
+                return Err(Error::NotReady(reason));
+            }
+
+            info!("service passed its readiness probe");
+        }
+        None => {
+            if let Err(reason) = ReadinessProbe::fallback()
+                .wait_until_ready(address, handle)
+                .await
+            {
+                info!(
+                    reason,
+                    "service does not answer on '/', so it is taken as ready. Configure a `[readiness]` probe in Shuttle.toml to have the deployment wait for it"
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Hold the deployment back from running until its smoke test passes, if it has one. Unlike the readiness probe, the
+/// test is only run once.
+async fn run_smoke_test(
+    address: SocketAddr,
+    (handle, _): &mut Started,
+    smoke_test: Option<SmokeTest>,
+) -> Result<()> {
+    let Some(smoke_test) = smoke_test else {
+        return Ok(());
+    };
+
+    if let Err(reason) = smoke_test.run(address).await {
+        // The library may only be closed once the service is done with it
+        handle.abort();
+        let _ = handle.await;
+
+        return Err(Error::SmokeTestFailed(reason));
+    }
+
+    info!("service passed its smoke test");
+
+    Ok(())
+}
+
+/// Give a deployment the chance to drain and flush before it is aborted. Never takes longer than
+/// [PRE_STOP_GRACE_PERIOD].
+#[instrument(skip(address))]
+async fn call_pre_stop_hook(address: SocketAddr, path: &str) {
+    let response = reqwest::Client::new()
+        .post(format!("http://{address}{path}"))
+        .timeout(PRE_STOP_GRACE_PERIOD)
+        .send()
+        .await;
+
+    match response {
+        Ok(response) if response.status().is_success() => info!("pre-stop hook completed"),
+        Ok(response) => warn!(status = %response.status(), "pre-stop hook failed"),
+        Err(error) if error.is_timeout() => warn!(
+            "pre-stop hook did not complete within the grace period of {}s",
+            PRE_STOP_GRACE_PERIOD.as_secs()
+        ),
+        Err(error) => warn!(
+            error = &error as &dyn std::error::Error,
+            "failed to call pre-stop hook"
+        ),
+    }
+}
+
+#[instrument(skip(addr, so_path, checksum_path, factory, logger))]
 async fn load_deployment(
     addr: SocketAddr,
     so_path: PathBuf,
+    checksum_path: PathBuf,
     factory: &mut dyn Factory,
     logger: Logger,
 ) -> Result<LoadedService> {
+    verify_checksum(&so_path, &checksum_path)?;
+
     let loader = Loader::from_so_file(so_path)?;
 
     Ok(loader.load(factory, addr, logger).await?)
 }
 
+/// Make sure the `.so` about to be loaded is the same one that was stored after building
+fn verify_checksum(so_path: &Path, checksum_path: &Path) -> Result<()> {
+    // A missing artifact is reported by the loader itself
+    if !so_path.exists() {
+        return Ok(());
+    }
+
+    // Artifacts stored before checksums were recorded have nothing to verify against
+    if !checksum_path.exists() {
+        warn!("no checksum recorded for deployment artifact, skipping integrity check");
+        return Ok(());
+    }
+
+    let expected = fs::read_to_string(checksum_path)?.trim().to_string();
+    let actual = file_checksum(so_path)?;
+
+    if expected != actual {
+        return Err(Error::ArtifactCorrupted { expected, actual });
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use std::{
         collections::BTreeMap,
+        convert::Infallible,
         net::{Ipv4Addr, SocketAddr},
         path::PathBuf,
         process::Command,
         time::Duration,
     };
 
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Body, Request, Response, Server,
+    };
     use shuttle_common::database;
     use shuttle_service::{Factory, Logger};
     use tempfile::Builder;
     use tokio::{
-        sync::{broadcast, mpsc, oneshot},
+        sync::{mpsc, oneshot, watch},
         task::JoinError,
         time::sleep,
     };
     use uuid::Uuid;
 
-    use crate::{deployment::storage_manager::StorageManager, error::Error};
+    use crate::{
+        args::ShutdownPolicy,
+        deployment::{kill, storage_manager::StorageManager},
+        error::{Error, Fault},
+    };
 
-    use super::Built;
+    use super::{ActiveDeploymentsGetter, Built};
 
     const RESOURCES_PATH: &str = "tests/resources";
 
@@ -362,12 +798,27 @@ mod tests {
         Ok(())
     }
 
+    #[derive(Clone)]
+    struct StubActiveDeploymentGetter;
+
+    #[async_trait::async_trait]
+    impl ActiveDeploymentsGetter for StubActiveDeploymentGetter {
+        type Err = std::io::Error;
+
+        async fn get_active_deployments(
+            &self,
+            _service_id: &Uuid,
+        ) -> std::result::Result<Vec<Uuid>, Self::Err> {
+            Ok(vec![])
+        }
+    }
+
     // This test uses the kill signal to make sure a service does stop when asked to
     #[tokio::test]
     async fn can_be_killed() {
         let (built, storage_manager) = make_so_and_built("sleep-async");
         let id = built.id;
-        let (kill_send, kill_recv) = broadcast::channel(1);
+        let (kill_send, kill_recv) = kill::channel(1);
         let (cleanup_send, cleanup_recv) = oneshot::channel();
 
         let handle_cleanup = |result: std::result::Result<
@@ -389,10 +840,15 @@ mod tests {
             .handle(
                 addr,
                 storage_manager,
+                None,
                 &mut factory,
                 logger,
+                None,
                 kill_recv,
+                watch::channel(None).1,
+                StubActiveDeploymentGetter,
                 kill_old_deployments(),
+                None,
                 handle_cleanup,
             )
             .await
@@ -410,11 +866,197 @@ mod tests {
         }
     }
 
+    // A kill signal lost to a lagging kill channel should not leave the service running
+    #[tokio::test]
+    async fn killed_when_kill_is_missed() {
+        let (built, storage_manager) = make_so_and_built("sleep-async");
+        let id = built.id;
+        let (kill_send, kill_recv) = kill::channel(1);
+        let (cleanup_send, cleanup_recv) = oneshot::channel();
+
+        let handle_cleanup = |_result| cleanup_send.send(()).unwrap();
+        let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 8001);
+        let mut factory = StubFactory;
+        let logger = get_logger(built.id);
+
+        built
+            .handle(
+                addr,
+                storage_manager,
+                None,
+                &mut factory,
+                logger,
+                None,
+                kill_recv,
+                watch::channel(None).1,
+                StubActiveDeploymentGetter,
+                kill_old_deployments(),
+                None,
+                handle_cleanup,
+            )
+            .await
+            .unwrap();
+
+        // Give it some time to start up
+        sleep(Duration::from_secs(1)).await;
+
+        // Overflow the channel so that the kill signal is never seen
+        kill_send.send(id).unwrap();
+        kill_send.send(Uuid::new_v4()).unwrap();
+
+        tokio::select! {
+            _ = sleep(Duration::from_secs(1)) => panic!("cleanup should have been called"),
+            Ok(()) = cleanup_recv => {}
+        }
+    }
+
+    // The pre-stop hook should be called on the service before it is stopped
+    #[tokio::test]
+    async fn pre_stop_hook_is_called() {
+        let (mut built, storage_manager) = make_so_and_built("sleep-async");
+        built.pre_stop_hook = Some("/drain".to_string());
+        let id = built.id;
+        let (kill_send, kill_recv) = kill::channel(1);
+        let (cleanup_send, cleanup_recv) = oneshot::channel();
+        let (hook_send, mut hook_recv) = mpsc::unbounded_channel();
+
+        let handle_cleanup = |_result| cleanup_send.send(()).unwrap();
+        let addr = SocketAddr::new(
+            Ipv4Addr::LOCALHOST.into(),
+            portpicker::pick_unused_port().unwrap(),
+        );
+        let mut factory = StubFactory;
+        let logger = get_logger(built.id);
+
+        // Stand in for the service answering the hook
+        let make_service = make_service_fn(move |_| {
+            let hook_send = hook_send.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    hook_send.send(req.uri().path().to_string()).unwrap();
+                    async { Ok::<_, Infallible>(Response::new(Body::empty())) }
+                }))
+            }
+        });
+        tokio::spawn(Server::bind(&addr).serve(make_service));
+
+        built
+            .handle(
+                addr,
+                storage_manager,
+                None,
+                &mut factory,
+                logger,
+                None,
+                kill_recv,
+                watch::channel(None).1,
+                StubActiveDeploymentGetter,
+                kill_old_deployments(),
+                None,
+                handle_cleanup,
+            )
+            .await
+            .unwrap();
+
+        // Give it some time to start up
+        sleep(Duration::from_secs(1)).await;
+
+        kill_send.send(id).unwrap();
+
+        tokio::select! {
+            _ = sleep(Duration::from_secs(2)) => panic!("cleanup should have been called"),
+            Ok(()) = cleanup_recv => {}
+        }
+        assert_eq!(hook_recv.try_recv().unwrap(), "/drain");
+    }
+
+    // Shutting the deployer down stops the service without recording it as stopped by the user
+    #[tokio::test]
+    async fn stopped_on_shutdown() {
+        let (built, storage_manager) = make_so_and_built("sleep-async");
+        let (_kill_send, kill_recv) = kill::channel(1);
+        let (shutdown_send, shutdown_recv) = watch::channel(None);
+
+        let handle_cleanup = |_result| panic!("a shutdown should not clean up the deployment");
+        let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 8001);
+        let mut factory = StubFactory;
+        let logger = get_logger(built.id);
+
+        built
+            .handle(
+                addr,
+                storage_manager,
+                None,
+                &mut factory,
+                logger,
+                None,
+                kill_recv,
+                shutdown_recv,
+                StubActiveDeploymentGetter,
+                kill_old_deployments(),
+                None,
+                handle_cleanup,
+            )
+            .await
+            .unwrap();
+
+        // Give it some time to start up
+        sleep(Duration::from_secs(1)).await;
+
+        shutdown_send.send(Some(ShutdownPolicy::Stop)).unwrap();
+
+        tokio::select! {
+            _ = sleep(Duration::from_secs(1)) => panic!("deployment should have let go of the shutdown channel"),
+            _ = shutdown_send.closed() => {}
+        }
+    }
+
+    // A service running past its budget is stopped without going through the cleanup of a user stop
+    #[tokio::test]
+    async fn stopped_when_budget_is_spent() {
+        let (mut built, storage_manager) = make_so_and_built("sleep-async");
+        built.run_budget = Some(Duration::from_secs(2));
+        let (_kill_send, kill_recv) = kill::channel(1);
+        let (shutdown_send, shutdown_recv) = watch::channel(None);
+
+        let handle_cleanup = |_result| panic!("a spent budget should not be cleaned up as a stop");
+        let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 8001);
+        let mut factory = StubFactory;
+        let logger = get_logger(built.id);
+
+        built
+            .handle(
+                addr,
+                storage_manager,
+                None,
+                &mut factory,
+                logger,
+                None,
+                kill_recv,
+                shutdown_recv,
+                StubActiveDeploymentGetter,
+                kill_old_deployments(),
+                None,
+                handle_cleanup,
+            )
+            .await
+            .unwrap();
+
+        // Still within its budget
+        sleep(Duration::from_secs(1)).await;
+        assert!(!shutdown_send.is_closed());
+
+        tokio::select! {
+            _ = sleep(Duration::from_secs(3)) => panic!("deployment should have stopped once its budget was spent"),
+            _ = shutdown_send.closed() => {}
+        }
+    }
+
     // This test does not use a kill signal to stop the service. Rather the service decided to stop on its own without errors
     #[tokio::test]
     async fn self_stop() {
         let (built, storage_manager) = make_so_and_built("sleep-async");
-        let (_kill_send, kill_recv) = broadcast::channel(1);
+        let (_kill_send, kill_recv) = kill::channel(1);
         let (cleanup_send, cleanup_recv) = oneshot::channel();
 
         let handle_cleanup = |result: std::result::Result<
@@ -437,10 +1079,15 @@ mod tests {
             .handle(
                 addr,
                 storage_manager,
+                None,
                 &mut factory,
                 logger,
+                None,
                 kill_recv,
+                watch::channel(None).1,
+                StubActiveDeploymentGetter,
                 kill_old_deployments(),
+                None,
                 handle_cleanup,
             )
             .await
@@ -456,7 +1103,7 @@ mod tests {
     #[tokio::test]
     async fn panic_in_bind() {
         let (built, storage_manager) = make_so_and_built("bind-panic");
-        let (_kill_send, kill_recv) = broadcast::channel(1);
+        let (_kill_send, kill_recv) = kill::channel(1);
         let (cleanup_send, cleanup_recv): (oneshot::Sender<()>, _) = oneshot::channel();
 
         let handle_cleanup = |result: std::result::Result<
@@ -479,10 +1126,15 @@ mod tests {
             .handle(
                 addr,
                 storage_manager,
+                None,
                 &mut factory,
                 logger,
+                None,
                 kill_recv,
+                watch::channel(None).1,
+                StubActiveDeploymentGetter,
                 kill_old_deployments(),
+                None,
                 handle_cleanup,
             )
             .await
@@ -498,7 +1150,7 @@ mod tests {
     #[tokio::test]
     async fn panic_in_main() {
         let (built, storage_manager) = make_so_and_built("main-panic");
-        let (_kill_send, kill_recv) = broadcast::channel(1);
+        let (_kill_send, kill_recv) = kill::channel(1);
 
         let handle_cleanup = |_result| panic!("the service shouldn't even start");
         let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 8001);
@@ -509,10 +1161,15 @@ mod tests {
             .handle(
                 addr,
                 storage_manager,
+                None,
                 &mut factory,
                 logger,
+                None,
                 kill_recv,
+                watch::channel(None).1,
+                StubActiveDeploymentGetter,
                 kill_old_deployments(),
+                None,
                 handle_cleanup,
             )
             .await;
@@ -522,6 +1179,7 @@ mod tests {
             "expected inner error from main: {:?}",
             result
         );
+        assert_eq!(result.unwrap_err().fault(), Fault::User);
     }
 
     #[tokio::test]
@@ -532,8 +1190,12 @@ mod tests {
             service_id: Uuid::new_v4(),
             tracing_context: Default::default(),
             claim: None,
+            pre_stop_hook: None,
+            image: None,
+            run_budget: None,
+            expires_at: None,
         };
-        let (_kill_send, kill_recv) = broadcast::channel(1);
+        let (_kill_send, kill_recv) = kill::channel(1);
 
         let handle_cleanup = |_result| panic!("no service means no cleanup");
         let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 8001);
@@ -545,10 +1207,15 @@ mod tests {
             .handle(
                 addr,
                 storage_manager,
+                None,
                 &mut factory,
                 logger,
+                None,
                 kill_recv,
+                watch::channel(None).1,
+                StubActiveDeploymentGetter,
                 kill_old_deployments(),
+                None,
                 handle_cleanup,
             )
             .await;
@@ -561,6 +1228,93 @@ mod tests {
             "expected missing 'so' error: {:?}",
             result
         );
+        assert_eq!(result.unwrap_err().fault(), Fault::Platform);
+    }
+
+    #[tokio::test]
+    async fn image_without_runtime() {
+        let built = Built {
+            id: Uuid::new_v4(),
+            service_name: "test".to_string(),
+            service_id: Uuid::new_v4(),
+            tracing_context: Default::default(),
+            claim: None,
+            pre_stop_hook: None,
+            image: Some("ghcr.io/neo/matrix:1.0".to_string()),
+            run_budget: None,
+            expires_at: None,
+        };
+        let (_kill_send, kill_recv) = kill::channel(1);
+
+        let handle_cleanup = |_result| panic!("no service means no cleanup");
+        let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 8001);
+        let storage_manager = get_storage_manager();
+        let mut factory = StubFactory;
+        let logger = get_logger(built.id);
+
+        let result = built
+            .handle(
+                addr,
+                storage_manager,
+                None,
+                &mut factory,
+                logger,
+                None,
+                kill_recv,
+                watch::channel(None).1,
+                StubActiveDeploymentGetter,
+                kill_old_deployments(),
+                None,
+                handle_cleanup,
+            )
+            .await;
+
+        assert!(
+            matches!(result, Err(Error::PrepareLoad(_))),
+            "expected image deployments to be refused: {:?}",
+            result
+        );
+        assert_eq!(result.unwrap_err().fault(), Fault::Platform);
+    }
+
+    #[tokio::test]
+    async fn corrupted_so() {
+        let (built, storage_manager) = make_so_and_built("sleep-async");
+        let (_kill_send, kill_recv) = kill::channel(1);
+
+        // Simulate a partial write of the stored '.so'
+        let so_path = storage_manager.deployment_library_path(&built.id).unwrap();
+        let so = std::fs::read(&so_path).unwrap();
+        std::fs::write(&so_path, &so[..so.len() / 2]).unwrap();
+
+        let handle_cleanup = |_result| panic!("a corrupted service should not be loaded");
+        let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 8001);
+        let mut factory = StubFactory;
+        let logger = get_logger(built.id);
+
+        let result = built
+            .handle(
+                addr,
+                storage_manager,
+                None,
+                &mut factory,
+                logger,
+                None,
+                kill_recv,
+                watch::channel(None).1,
+                StubActiveDeploymentGetter,
+                kill_old_deployments(),
+                None,
+                handle_cleanup,
+            )
+            .await;
+
+        assert!(
+            matches!(result, Err(Error::ArtifactCorrupted { .. })),
+            "expected checksum mismatch error: {:?}",
+            result
+        );
+        assert_eq!(result.unwrap_err().fault(), Fault::Platform);
     }
 
     fn make_so_and_built(crate_name: &str) -> (Built, StorageManager) {
@@ -588,6 +1342,7 @@ mod tests {
         let new_so_path = storage_manager.deployment_library_path(&id).unwrap();
 
         std::fs::copy(so_path, new_so_path).unwrap();
+        storage_manager.record_library_checksum(&id).unwrap();
 
         (
             Built {
@@ -596,6 +1351,10 @@ mod tests {
                 service_id: Uuid::new_v4(),
                 tracing_context: Default::default(),
                 claim: None,
+                pre_stop_hook: None,
+                image: None,
+                run_budget: None,
+                expires_at: None,
             },
             storage_manager,
         )
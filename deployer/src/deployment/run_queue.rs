@@ -0,0 +1,73 @@
+use std::sync::Mutex;
+
+use chrono::Utc;
+use shuttle_common::models::deployment::PendingRun;
+use uuid::Uuid;
+
+/// Built deployments which were sent to the run task but not taken on by it yet. The channel they wait in cannot be
+/// looked into, so they are tracked next to it.
+#[derive(Default)]
+pub struct RunQueue {
+    /// First in line first
+    pending: Mutex<Vec<PendingRun>>,
+}
+
+impl RunQueue {
+    /// Track a deployment which is about to be sent to the run task
+    pub fn push(&self, id: Uuid, service_name: &str) {
+        self.pending.lock().unwrap().push(PendingRun {
+            id,
+            service_name: service_name.to_string(),
+            queued_at: Utc::now(),
+        });
+    }
+
+    /// Stop tracking a deployment once the run task took it on, or once it could not be sent after all
+    pub fn take(&self, id: &Uuid) {
+        let mut pending = self.pending.lock().unwrap();
+
+        if let Some(index) = pending.iter().position(|run| &run.id == id) {
+            pending.remove(index);
+        }
+    }
+
+    pub fn snapshot(&self) -> Vec<PendingRun> {
+        self.pending.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::RunQueue;
+
+    #[test]
+    fn tracks_pending_runs_in_order() {
+        let queue = RunQueue::default();
+        let first = Uuid::new_v4();
+        let second = Uuid::new_v4();
+
+        assert!(queue.snapshot().is_empty());
+
+        queue.push(first, "neo");
+        queue.push(second, "trinity");
+        assert_eq!(
+            queue
+                .snapshot()
+                .iter()
+                .map(|run| run.id)
+                .collect::<Vec<_>>(),
+            vec![first, second]
+        );
+
+        queue.take(&first);
+        let pending = queue.snapshot();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].service_name, "trinity");
+
+        // Taking a deployment which is not tracked is a no-op
+        queue.take(&first);
+        assert_eq!(queue.snapshot().len(), 1);
+    }
+}
@@ -1,22 +1,57 @@
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use serde_json::json;
 use shuttle_common::LogItem;
 use shuttle_service::Logger;
-use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::{
+    sync::mpsc::{self, UnboundedSender},
+    time::interval,
+};
 use uuid::Uuid;
 
 use super::deploy_layer::{self, LogType};
+use super::output::{CapturedOutput, OutputCapture};
+use crate::persistence::{LogLevel, State};
+
+/// How often a deployment which is over its log quota is told how many lines were dropped
+const DROPPED_NOTICE_INTERVAL: Duration = Duration::from_secs(5);
 
 pub trait Factory: Send + 'static {
     fn get_logger(&self, id: Uuid) -> Logger;
+
+    /// Put what gets printed to stdout and stderr on the logs of deployment `id` for as long as the returned guard is
+    /// held, when that output is captured
+    fn capture_output(&self, _id: Uuid) -> Option<CapturedOutput> {
+        None
+    }
 }
 
 /// Factory to create runtime loggers for deployments
 pub struct RuntimeLoggerFactory {
     log_send: crossbeam_channel::Sender<deploy_layer::Log>,
+    log_rate_limit: u32,
+    output_capture: Option<OutputCapture>,
 }
 
 impl RuntimeLoggerFactory {
-    pub fn new(log_send: crossbeam_channel::Sender<deploy_layer::Log>) -> Self {
-        Self { log_send }
+    /// Each deployment may log at most `log_rate_limit` lines per second. Anything above that is dropped.
+    pub fn new(
+        log_send: crossbeam_channel::Sender<deploy_layer::Log>,
+        log_rate_limit: u32,
+    ) -> Self {
+        Self {
+            log_send,
+            log_rate_limit,
+            output_capture: None,
+        }
+    }
+
+    /// Put the captured stdout and stderr of the deployer on the logs of the deployments which print them
+    pub fn output_capture(mut self, output_capture: OutputCapture) -> Self {
+        self.output_capture = Some(output_capture);
+
+        self
     }
 }
 
@@ -25,15 +60,112 @@ impl Factory for RuntimeLoggerFactory {
         let (tx, mut rx): (UnboundedSender<LogItem>, _) = mpsc::unbounded_channel();
 
         let sender = self.log_send.clone();
+        let mut limiter = RateLimiter::new(self.log_rate_limit);
 
         tokio::spawn(async move {
-            while let Some(log) = rx.recv().await {
-                sender.send(log.into()).expect("to send log to persistence");
+            let mut notice_interval = interval(DROPPED_NOTICE_INTERVAL);
+            let mut state = State::Running;
+
+            loop {
+                tokio::select! {
+                    log = rx.recv() => {
+                        let Some(log) = log else {
+                            break;
+                        };
+
+                        if limiter.admit(Instant::now()) {
+                            let log: deploy_layer::Log = log.into();
+                            state = log.state;
+
+                            sender.send(log).expect("to send log to persistence");
+                        }
+                    }
+                    _ = notice_interval.tick() => {
+                        if let Some(dropped) = limiter.take_dropped() {
+                            sender
+                                .send(dropped_notice(id, state, dropped))
+                                .expect("to send log to persistence");
+                        }
+                    }
+                }
+            }
+
+            if let Some(dropped) = limiter.take_dropped() {
+                sender
+                    .send(dropped_notice(id, state, dropped))
+                    .expect("to send log to persistence");
             }
         });
 
         Logger::new(tx, id)
     }
+
+    fn capture_output(&self, id: Uuid) -> Option<CapturedOutput> {
+        let output_capture = self.output_capture.as_ref()?;
+
+        Some(output_capture.attach(id, self.get_logger(id)))
+    }
+}
+
+/// Counts the lines logged in fixed one second windows
+struct RateLimiter {
+    limit: u32,
+    window_start: Option<Instant>,
+    admitted: u32,
+    dropped: u64,
+}
+
+impl RateLimiter {
+    fn new(limit: u32) -> Self {
+        Self {
+            limit,
+            window_start: None,
+            admitted: 0,
+            dropped: 0,
+        }
+    }
+
+    /// Whether a line logged at `now` is within the quota
+    fn admit(&mut self, now: Instant) -> bool {
+        match self.window_start {
+            Some(start) if now.duration_since(start) < Duration::from_secs(1) => {}
+            _ => {
+                self.window_start = Some(now);
+                self.admitted = 0;
+            }
+        }
+
+        if self.admitted < self.limit {
+            self.admitted += 1;
+            true
+        } else {
+            self.dropped += 1;
+            false
+        }
+    }
+
+    /// Number of lines dropped since the last call, if any were
+    fn take_dropped(&mut self) -> Option<u64> {
+        match std::mem::take(&mut self.dropped) {
+            0 => None,
+            dropped => Some(dropped),
+        }
+    }
+}
+
+fn dropped_notice(id: Uuid, state: State, dropped: u64) -> deploy_layer::Log {
+    deploy_layer::Log {
+        id,
+        state,
+        level: LogLevel::Warn,
+        timestamp: Utc::now(),
+        file: None,
+        line: None,
+        target: String::new(),
+        fields: json!({ "message": format!("rate limited: {dropped} lines dropped") }),
+        r#type: LogType::Event,
+        address: None,
+    }
 }
 
 impl From<LogItem> for deploy_layer::Log {
@@ -52,3 +184,30 @@ impl From<LogItem> for deploy_layer::Log {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::RateLimiter;
+
+    #[test]
+    fn rate_limiter() {
+        let mut limiter = RateLimiter::new(2);
+        let start = Instant::now();
+
+        assert!(limiter.admit(start));
+        assert!(limiter.admit(start + Duration::from_millis(100)));
+        assert!(!limiter.admit(start + Duration::from_millis(200)));
+        assert!(!limiter.admit(start + Duration::from_millis(900)));
+        assert_eq!(limiter.take_dropped(), Some(2));
+        assert_eq!(limiter.take_dropped(), None);
+
+        // A new window gets a fresh quota
+        assert!(limiter.admit(start + Duration::from_millis(1000)));
+        assert!(limiter.admit(start + Duration::from_millis(1500)));
+        assert!(!limiter.admit(start + Duration::from_millis(1999)));
+        assert!(limiter.admit(start + Duration::from_millis(2000)));
+        assert_eq!(limiter.take_dropped(), Some(1));
+    }
+}
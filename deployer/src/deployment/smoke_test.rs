@@ -0,0 +1,289 @@
+use std::{fs, io, net::SocketAddr, path::Path, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+use tracing::{debug, instrument};
+
+use crate::error::{Error, Result};
+
+/// Application level check of a deployment, set in the `[smoke-test]` section of `Shuttle.toml`. It runs once the
+/// deployment passed its readiness probe, and the deployment before it keeps the traffic until it passes.
+///
+/// The test is either a GET request to `path`, which has to be answered with `expect-status` and a body containing
+/// `expect-body`, or a `command` which has to exit successfully.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct SmokeTest {
+    pub path: Option<String>,
+    pub expect_status: u16,
+    pub expect_body: Option<String>,
+    /// Run with `sh -c`, with the address of the deployment in `SHUTTLE_ADDRESS`
+    pub command: Option<String>,
+    pub timeout_secs: u64,
+}
+
+/// Only used to pick the `[smoke-test]` section out of `Shuttle.toml`
+#[derive(Deserialize)]
+struct ShuttleToml {
+    #[serde(rename = "smoke-test")]
+    smoke_test: Option<SmokeTest>,
+}
+
+impl Default for SmokeTest {
+    fn default() -> Self {
+        Self {
+            path: None,
+            expect_status: 200,
+            expect_body: None,
+            command: None,
+            timeout_secs: 30,
+        }
+    }
+}
+
+impl SmokeTest {
+    /// Read the smoke test configured in the `Shuttle.toml` of a project, if any
+    pub fn from_project(project_path: &Path) -> Result<Option<Self>> {
+        let config_path = project_path.join("Shuttle.toml");
+
+        if !config_path.is_file() {
+            return Ok(None);
+        }
+
+        let config: ShuttleToml = toml::from_str(&fs::read_to_string(config_path)?)
+            .map_err(|error| Error::SmokeTestConfig(error.to_string()))?;
+
+        if let Some(smoke_test) = &config.smoke_test {
+            smoke_test.validate()?;
+        }
+
+        Ok(config.smoke_test)
+    }
+
+    /// Read a smoke test stored with [SmokeTest::store]
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        match fs::read(path) {
+            Ok(smoke_test) => serde_json::from_slice(&smoke_test)
+                .map(Some)
+                .map_err(|error| Error::SmokeTestConfig(error.to_string())),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    pub fn store(&self, path: &Path) -> Result<()> {
+        let smoke_test = serde_json::to_vec(self).expect("a smoke test to serialize");
+
+        Ok(fs::write(path, smoke_test)?)
+    }
+
+    fn validate(&self) -> Result<()> {
+        match (&self.path, &self.command) {
+            (Some(path), None) if !path.starts_with('/') => {
+                return Err(Error::SmokeTestConfig(
+                    "the smoke test path should start with '/'".to_string(),
+                ))
+            }
+            (Some(_), None) | (None, Some(_)) => {}
+            _ => {
+                return Err(Error::SmokeTestConfig(
+                    "the smoke test should have either a path or a command".to_string(),
+                ))
+            }
+        }
+
+        if !(100..600).contains(&self.expect_status) {
+            return Err(Error::SmokeTestConfig(format!(
+                "{} is not an HTTP status",
+                self.expect_status
+            )));
+        }
+
+        if self.timeout_secs == 0 {
+            return Err(Error::SmokeTestConfig(
+                "the smoke test timeout should be at least 1 second".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Run the test against the service at `address` once. Returns why it failed if it did.
+    #[instrument(skip(self), fields(path = ?self.path, command = ?self.command))]
+    pub async fn run(&self, address: SocketAddr) -> std::result::Result<(), String> {
+        let timeout = Duration::from_secs(self.timeout_secs);
+
+        let result = match (&self.path, &self.command) {
+            (Some(path), _) => tokio::time::timeout(timeout, self.request(address, path)).await,
+            (None, Some(command)) => {
+                tokio::time::timeout(timeout, Self::command(address, command)).await
+            }
+            (None, None) => return Ok(()),
+        };
+
+        let result =
+            result.unwrap_or_else(|_| Err(format!("did not finish within {}s", timeout.as_secs())));
+        debug!(?result, "ran smoke test");
+
+        result
+    }
+
+    async fn request(&self, address: SocketAddr, path: &str) -> std::result::Result<(), String> {
+        let response = reqwest::get(format!("http://{address}{path}"))
+            .await
+            .map_err(|error| error.to_string())?;
+
+        let status = response.status();
+        if status.as_u16() != self.expect_status {
+            return Err(format!(
+                "{path} answered with {status} instead of {}",
+                self.expect_status
+            ));
+        }
+
+        if let Some(expected) = &self.expect_body {
+            let body = response.text().await.map_err(|error| error.to_string())?;
+
+            if !body.contains(expected.as_str()) {
+                return Err(format!(
+                    "the answer of {path} does not contain '{expected}'"
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn command(address: SocketAddr, command: &str) -> std::result::Result<(), String> {
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .env("SHUTTLE_ADDRESS", address.to_string())
+            .kill_on_drop(true)
+            .output()
+            .await
+            .map_err(|error| format!("could not run `{command}`: {error}"))?;
+
+        if output.status.success() {
+            Ok(())
+        } else {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+
+            Err(format!(
+                "`{command}` exited with {}: {}",
+                output.status,
+                stderr.trim()
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        convert::Infallible,
+        net::{Ipv4Addr, SocketAddr},
+    };
+
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Body, Request, Response, Server, StatusCode,
+    };
+    use tempfile::Builder;
+
+    use super::SmokeTest;
+
+    #[test]
+    fn from_project() {
+        let dir = Builder::new().prefix("smoke-test").tempdir().unwrap();
+
+        assert_eq!(SmokeTest::from_project(dir.path()).unwrap(), None);
+
+        std::fs::write(
+            dir.path().join("Shuttle.toml"),
+            r#"
+name = "matrix"
+
+[smoke-test]
+path = "/api/version"
+expect-body = "1.0"
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            SmokeTest::from_project(dir.path()).unwrap(),
+            Some(SmokeTest {
+                path: Some("/api/version".to_string()),
+                expect_body: Some("1.0".to_string()),
+                ..Default::default()
+            })
+        );
+
+        std::fs::write(
+            dir.path().join("Shuttle.toml"),
+            "[smoke-test]\npath = '/'\ncommand = 'true'\n",
+        )
+        .unwrap();
+        assert!(SmokeTest::from_project(dir.path()).is_err());
+
+        std::fs::write(dir.path().join("Shuttle.toml"), "[smoke-test]\n").unwrap();
+        assert!(SmokeTest::from_project(dir.path()).is_err());
+    }
+
+    #[tokio::test]
+    async fn run() {
+        let addr = SocketAddr::new(
+            Ipv4Addr::LOCALHOST.into(),
+            portpicker::pick_unused_port().unwrap(),
+        );
+
+        // Stand in for a service which starts but whose database is broken
+        let make_service = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+                let (status, body) = match req.uri().path() {
+                    "/version" => (StatusCode::OK, "1.0"),
+                    _ => (StatusCode::INTERNAL_SERVER_ERROR, "database is down"),
+                };
+
+                Ok::<_, Infallible>(
+                    Response::builder()
+                        .status(status)
+                        .body(Body::from(body))
+                        .unwrap(),
+                )
+            }))
+        });
+        tokio::spawn(Server::bind(&addr).serve(make_service));
+
+        let version = SmokeTest {
+            path: Some("/version".to_string()),
+            expect_body: Some("1.0".to_string()),
+            ..Default::default()
+        };
+        version.run(addr).await.unwrap();
+
+        let wrong_version = SmokeTest {
+            expect_body: Some("2.0".to_string()),
+            ..version
+        };
+        assert!(wrong_version.run(addr).await.is_err());
+
+        let users = SmokeTest {
+            path: Some("/users".to_string()),
+            ..Default::default()
+        };
+        assert!(users.run(addr).await.is_err());
+
+        let command = SmokeTest {
+            command: Some("test -n \"$SHUTTLE_ADDRESS\"".to_string()),
+            ..Default::default()
+        };
+        command.run(addr).await.unwrap();
+
+        let failing = SmokeTest {
+            command: Some("exit 3".to_string()),
+            ..Default::default()
+        };
+        assert!(failing.run(addr).await.is_err());
+    }
+}
@@ -1,16 +1,49 @@
-use std::{fs, io, path::PathBuf};
+use std::{
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
+use sha2::{Digest, Sha256};
 use uuid::Uuid;
 
+use super::warm_cache::{WarmCache, WarmGuard};
+
 /// Manager to take care of directories for storing project, services and deployment files
 #[derive(Clone)]
 pub struct StorageManager {
     artifacts_path: PathBuf,
+    warm_cache: Option<WarmCache>,
 }
 
 impl StorageManager {
     pub fn new(artifacts_path: PathBuf) -> Self {
-        Self { artifacts_path }
+        Self {
+            artifacts_path,
+            warm_cache: None,
+        }
+    }
+
+    /// Keep up to `capacity` bytes of `.so` files in memory, see [StorageManager::warm_library]
+    pub fn with_warm_cache(mut self, capacity: u64) -> Self {
+        self.warm_cache = Some(WarmCache::new(capacity));
+        self
+    }
+
+    /// Bring the `.so` of a deployment into memory ahead of loading it. It stays there at least until the returned
+    /// guard is dropped, and after that for as long as the warm cache has room. Nothing happens without a warm cache.
+    pub async fn warm_library(&self, deployment_id: &Uuid) -> Result<Option<WarmGuard>, io::Error> {
+        let Some(warm_cache) = &self.warm_cache else {
+            return Ok(None);
+        };
+
+        let library_path = self.deployment_library_path(deployment_id)?;
+
+        warm_cache
+            .warm(*deployment_id, library_path)
+            .await
+            .map(Some)
     }
 
     /// Path of the directory that contains extracted service Cargo projects.
@@ -29,6 +62,17 @@ impl StorageManager {
         Ok(builds_path)
     }
 
+    /// Path for building a single deployment, apart from the other builds of its service
+    pub fn deployment_build_path(&self, deployment_id: &Uuid) -> Result<PathBuf, io::Error> {
+        let build_path = self
+            .artifacts_path
+            .join("shuttle-isolated-builds")
+            .join(deployment_id.to_string());
+        fs::create_dir_all(&build_path)?;
+
+        Ok(build_path)
+    }
+
     /// The directory in which compiled '.so' files are stored.
     pub fn libraries_path(&self) -> Result<PathBuf, io::Error> {
         let libs_path = self.artifacts_path.join("shuttle-libs");
@@ -44,6 +88,90 @@ impl StorageManager {
         Ok(library_path)
     }
 
+    /// Path to the checksum recorded for the `.so` of a service
+    pub fn deployment_library_checksum_path(
+        &self,
+        deployment_id: &Uuid,
+    ) -> Result<PathBuf, io::Error> {
+        let checksum_path = self
+            .libraries_path()?
+            .join(format!("{deployment_id}.sha256"));
+
+        Ok(checksum_path)
+    }
+
+    /// Path to the readiness probe a deployment was built with, if its `Shuttle.toml` configured one
+    pub fn deployment_readiness_probe_path(
+        &self,
+        deployment_id: &Uuid,
+    ) -> Result<PathBuf, io::Error> {
+        let probe_path = self
+            .libraries_path()?
+            .join(format!("{deployment_id}.readiness.json"));
+
+        Ok(probe_path)
+    }
+
+    /// Path to the smoke test a deployment was built with, if its `Shuttle.toml` configured one
+    pub fn deployment_smoke_test_path(&self, deployment_id: &Uuid) -> Result<PathBuf, io::Error> {
+        let smoke_test_path = self
+            .libraries_path()?
+            .join(format!("{deployment_id}.smoke-test.json"));
+
+        Ok(smoke_test_path)
+    }
+
+    /// Path to the restart policy a deployment was built with, if its `Shuttle.toml` configured one
+    pub fn deployment_restart_policy_path(
+        &self,
+        deployment_id: &Uuid,
+    ) -> Result<PathBuf, io::Error> {
+        let policy_path = self
+            .libraries_path()?
+            .join(format!("{deployment_id}.restart-policy.json"));
+
+        Ok(policy_path)
+    }
+
+    /// Remove the stored `.so` of a deployment and everything recorded next to it, returning the number of bytes freed.
+    /// Files which are not there, like those of a deployment which ran an image, are skipped.
+    pub fn remove_deployment_artifacts(&self, deployment_id: &Uuid) -> Result<u64, io::Error> {
+        if let Some(warm_cache) = &self.warm_cache {
+            warm_cache.forget(deployment_id);
+        }
+
+        let mut freed = 0;
+
+        for path in [
+            self.deployment_library_path(deployment_id)?,
+            self.deployment_library_checksum_path(deployment_id)?,
+            self.deployment_readiness_probe_path(deployment_id)?,
+            self.deployment_smoke_test_path(deployment_id)?,
+            self.deployment_restart_policy_path(deployment_id)?,
+        ] {
+            match fs::metadata(&path) {
+                Ok(metadata) => {
+                    fs::remove_file(&path)?;
+                    freed += metadata.len();
+                }
+                Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+                Err(error) => return Err(error),
+            }
+        }
+
+        Ok(freed)
+    }
+
+    /// Hash the stored `.so` of a deployment and record the checksum next to it
+    pub fn record_library_checksum(&self, deployment_id: &Uuid) -> Result<(), io::Error> {
+        let checksum = file_checksum(self.deployment_library_path(deployment_id)?)?;
+
+        fs::write(
+            self.deployment_library_checksum_path(deployment_id)?,
+            checksum,
+        )
+    }
+
     /// Path of the directory to store user files
     pub fn storage_path(&self) -> Result<PathBuf, io::Error> {
         let storage_path = self.artifacts_path.join("shuttle-storage");
@@ -52,6 +180,48 @@ impl StorageManager {
         Ok(storage_path)
     }
 
+    /// Path of the directory holding archives which are still being uploaded
+    pub fn uploads_path(&self) -> Result<PathBuf, io::Error> {
+        let uploads_path = self.artifacts_path.join("shuttle-uploads");
+        fs::create_dir_all(&uploads_path)?;
+
+        Ok(uploads_path)
+    }
+
+    /// Path to the partial archive of an upload
+    pub fn upload_path(&self, upload_id: &Uuid) -> Result<PathBuf, io::Error> {
+        Ok(self.uploads_path()?.join(upload_id.to_string()))
+    }
+
+    /// Path to the file holding the number of bytes an upload was started for
+    pub fn upload_length_path(&self, upload_id: &Uuid) -> Result<PathBuf, io::Error> {
+        Ok(self.uploads_path()?.join(format!("{upload_id}.length")))
+    }
+
+    /// Remove the partial archives which have not received a chunk for longer than `max_age`, along with their lengths
+    pub fn remove_stale_uploads(&self, max_age: Duration) -> Result<(), io::Error> {
+        for entry in fs::read_dir(self.uploads_path()?)? {
+            let entry = entry?;
+
+            // The length is only written when an upload starts, so the last chunk is told by the archive itself
+            let Ok(upload_id) = entry.file_name().to_string_lossy().parse::<Uuid>() else {
+                continue;
+            };
+            let age = entry.metadata()?.modified()?.elapsed().unwrap_or_default();
+
+            if age > max_age {
+                fs::remove_file(entry.path())?;
+
+                match fs::remove_file(self.upload_length_path(&upload_id)?) {
+                    Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+                    _ => {}
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     /// Path to folder for storing deployment files
     pub fn deployment_storage_path<S: AsRef<str>>(
         &self,
@@ -67,3 +237,12 @@ impl StorageManager {
         Ok(storage_path)
     }
 }
+
+/// Hex encoded SHA-256 digest of a file's contents
+pub fn file_checksum(path: impl AsRef<Path>) -> Result<String, io::Error> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+
+    Ok(hex::encode(hasher.finalize()))
+}
@@ -0,0 +1,154 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+
+/// Upper bounds of the duration histograms, in seconds. Builds take minutes while loads take moments, so the
+/// buckets cover both.
+const BOUNDS_SECS: [f64; 12] = [
+    0.1, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1200.0,
+];
+
+/// How long the phases of deployments took on this deployer, since it started
+pub static PHASE_TIMINGS: Lazy<PhaseTimings> = Lazy::new(PhaseTimings::default);
+
+/// A step of the lifecycle of a deployment which is timed
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, strum::Display)]
+#[strum(serialize_all = "snake_case")]
+pub enum Phase {
+    /// Waiting for a slot in the build queue
+    Queue,
+    Build,
+    Load,
+    /// Waiting for the readiness probe to pass
+    Readiness,
+    SmokeTest,
+}
+
+#[derive(Default)]
+pub struct PhaseTimings {
+    histograms: Mutex<BTreeMap<(Phase, bool), Histogram>>,
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; BOUNDS_SECS.len()],
+    count: u64,
+    sum_secs: f64,
+}
+
+impl PhaseTimings {
+    /// Record how long a phase took and whether it went through
+    pub fn observe(&self, phase: Phase, success: bool, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        let mut histograms = self.histograms.lock().unwrap();
+        let histogram = histograms.entry((phase, success)).or_default();
+
+        for (bucket, bound) in histogram.buckets.iter_mut().zip(BOUNDS_SECS) {
+            if secs <= bound {
+                *bucket += 1;
+            }
+        }
+        histogram.count += 1;
+        histogram.sum_secs += secs;
+    }
+
+    /// The histograms in the Prometheus text format
+    pub fn render(&self) -> String {
+        let histograms = self.histograms.lock().unwrap();
+        let mut out = String::new();
+
+        writeln!(
+            out,
+            "# HELP shuttle_deployer_phase_duration_seconds How long each phase of a deployment took"
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "# TYPE shuttle_deployer_phase_duration_seconds histogram"
+        )
+        .unwrap();
+
+        for ((phase, success), histogram) in histograms.iter() {
+            let outcome = if *success { "success" } else { "failure" };
+            let labels = format!("phase=\"{phase}\",outcome=\"{outcome}\"");
+
+            for (bucket, bound) in histogram.buckets.iter().zip(BOUNDS_SECS) {
+                writeln!(
+                    out,
+                    "shuttle_deployer_phase_duration_seconds_bucket{{{labels},le=\"{bound}\"}} {bucket}"
+                )
+                .unwrap();
+            }
+            writeln!(
+                out,
+                "shuttle_deployer_phase_duration_seconds_bucket{{{labels},le=\"+Inf\"}} {}",
+                histogram.count
+            )
+            .unwrap();
+            writeln!(
+                out,
+                "shuttle_deployer_phase_duration_seconds_sum{{{labels}}} {}",
+                histogram.sum_secs
+            )
+            .unwrap();
+            writeln!(
+                out,
+                "shuttle_deployer_phase_duration_seconds_count{{{labels}}} {}",
+                histogram.count
+            )
+            .unwrap();
+        }
+
+        out
+    }
+}
+
+/// Run a phase, recording how long it took in [PHASE_TIMINGS]
+pub async fn timed<T, E>(
+    phase: Phase,
+    phase_future: impl Future<Output = Result<T, E>>,
+) -> Result<T, E> {
+    let started = Instant::now();
+    let result = phase_future.await;
+
+    PHASE_TIMINGS.observe(phase, result.is_ok(), started.elapsed());
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Phase, PhaseTimings};
+
+    #[test]
+    fn render() {
+        let timings = PhaseTimings::default();
+
+        timings.observe(Phase::Build, true, Duration::from_secs(45));
+        timings.observe(Phase::Build, true, Duration::from_secs(90));
+        timings.observe(Phase::Readiness, false, Duration::from_millis(300));
+
+        let rendered = timings.render();
+
+        assert!(rendered.contains(
+            "shuttle_deployer_phase_duration_seconds_bucket{phase=\"build\",outcome=\"success\",le=\"30\"} 0\n"
+        ));
+        assert!(rendered.contains(
+            "shuttle_deployer_phase_duration_seconds_bucket{phase=\"build\",outcome=\"success\",le=\"60\"} 1\n"
+        ));
+        assert!(rendered.contains(
+            "shuttle_deployer_phase_duration_seconds_bucket{phase=\"build\",outcome=\"success\",le=\"+Inf\"} 2\n"
+        ));
+        assert!(rendered
+            .contains("shuttle_deployer_phase_duration_seconds_sum{phase=\"build\",outcome=\"success\"} 135\n"));
+        assert!(rendered.contains(
+            "shuttle_deployer_phase_duration_seconds_count{phase=\"readiness\",outcome=\"failure\"} 1\n"
+        ));
+    }
+}
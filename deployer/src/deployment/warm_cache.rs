@@ -0,0 +1,236 @@
+use std::{
+    fs::File,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use memmap2::{Advice, Mmap};
+use tracing::{debug, trace};
+use uuid::Uuid;
+
+/// Built artifacts kept in memory, so that loading one again does not have to wait on the disk. This is what makes
+/// restarts, rollbacks and replacing deployments fast after the artifact would otherwise have been dropped from the page
+/// cache.
+///
+/// Artifacts are mapped and locked into memory up to `capacity` bytes, evicting the least recently used one first. The
+/// artifact of a running deployment is never evicted: it is held by a [WarmGuard] for as long as the deployment runs.
+#[derive(Clone)]
+pub struct WarmCache {
+    inner: Arc<Mutex<Inner>>,
+}
+
+struct Inner {
+    capacity: u64,
+    /// Least recently used first
+    entries: Vec<Entry>,
+}
+
+struct Entry {
+    id: Uuid,
+    map: Mmap,
+    /// Number of guards keeping the entry from being evicted
+    pins: usize,
+}
+
+/// Keeps an artifact in the cache until it is dropped
+pub struct WarmGuard {
+    inner: Arc<Mutex<Inner>>,
+    id: Uuid,
+}
+
+impl WarmCache {
+    pub fn new(capacity: u64) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                capacity,
+                entries: Vec::new(),
+            })),
+        }
+    }
+
+    /// Bring the artifact of a deployment at `path` into memory, or mark it as the most recently used one if it is
+    /// already there. Reading it in blocks, so that is done on a blocking thread without holding on to the cache.
+    pub async fn warm(&self, id: Uuid, path: PathBuf) -> io::Result<WarmGuard> {
+        if let Some(guard) = self.pin(id, None) {
+            return Ok(guard);
+        }
+
+        let map = tokio::task::spawn_blocking(move || map_artifact(&path))
+            .await
+            .map_err(|error| io::Error::new(io::ErrorKind::Other, error))??;
+
+        Ok(self
+            .pin(id, Some(map))
+            .expect("a new mapping to always be added"))
+    }
+
+    /// Pin the entry of `id` as the most recently used one, adding it with `map` when it is not there yet. Another
+    /// warm of the same artifact may have added it while `map` was read in, and the mapping already there is kept then.
+    fn pin(&self, id: Uuid, map: Option<Mmap>) -> Option<WarmGuard> {
+        let mut inner = self.inner.lock().unwrap();
+
+        let mut entry = match inner.entries.iter().position(|entry| entry.id == id) {
+            Some(index) => inner.entries.remove(index),
+            None => Entry {
+                id,
+                map: map?,
+                pins: 0,
+            },
+        };
+        entry.pins += 1;
+        inner.entries.push(entry);
+        inner.evict();
+
+        Some(WarmGuard {
+            inner: self.inner.clone(),
+            id,
+        })
+    }
+
+    /// Drop the artifact of a deployment from the cache, unless a guard still holds it
+    pub fn forget(&self, id: &Uuid) {
+        self.inner
+            .lock()
+            .unwrap()
+            .entries
+            .retain(|entry| &entry.id != id || entry.pins > 0);
+    }
+
+    /// Bytes of artifacts in memory
+    pub fn size(&self) -> u64 {
+        self.inner.lock().unwrap().size()
+    }
+
+    pub fn contains(&self, id: &Uuid) -> bool {
+        self.inner
+            .lock()
+            .unwrap()
+            .entries
+            .iter()
+            .any(|entry| &entry.id == id)
+    }
+}
+
+impl Inner {
+    fn size(&self) -> u64 {
+        self.entries
+            .iter()
+            .map(|entry| entry.map.len() as u64)
+            .sum()
+    }
+
+    fn evict(&mut self) {
+        let mut size = self.size();
+        let mut index = 0;
+
+        while size > self.capacity && index < self.entries.len() {
+            if self.entries[index].pins > 0 {
+                index += 1;
+                continue;
+            }
+
+            let entry = self.entries.remove(index);
+            size -= entry.map.len() as u64;
+            trace!(id = %entry.id, "evicted artifact from the warm cache");
+        }
+    }
+}
+
+impl Drop for WarmGuard {
+    fn drop(&mut self) {
+        let mut inner = self.inner.lock().unwrap();
+
+        if let Some(entry) = inner.entries.iter_mut().find(|entry| entry.id == self.id) {
+            entry.pins -= 1;
+        }
+
+        inner.evict();
+    }
+}
+
+fn map_artifact(path: &Path) -> io::Result<Mmap> {
+    let file = File::open(path)?;
+
+    // SAFETY: stored artifacts are never written to again, only removed
+    let map = unsafe { Mmap::map(&file)? };
+
+    map.advise(Advice::WillNeed)?;
+
+    // Without the privilege to lock memory, the artifact is still read ahead of the load
+    if let Err(error) = map.lock() {
+        debug!(
+            error = &error as &dyn std::error::Error,
+            "could not lock artifact into memory"
+        );
+    }
+
+    Ok(map)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use tempfile::TempDir;
+    use uuid::Uuid;
+
+    use super::WarmCache;
+
+    fn artifact(dir: &TempDir, size: usize) -> (Uuid, PathBuf) {
+        let id = Uuid::new_v4();
+        let path = dir.path().join(id.to_string());
+        std::fs::write(&path, vec![7; size]).unwrap();
+
+        (id, path)
+    }
+
+    #[tokio::test]
+    async fn evicts_least_recently_used() {
+        let dir = TempDir::new().unwrap();
+        let cache = WarmCache::new(300);
+        let (first, first_path) = artifact(&dir, 100);
+        let (second, second_path) = artifact(&dir, 100);
+        let (third, third_path) = artifact(&dir, 100);
+        let (fourth, fourth_path) = artifact(&dir, 100);
+
+        drop(cache.warm(first, first_path.clone()).await.unwrap());
+        drop(cache.warm(second, second_path).await.unwrap());
+        drop(cache.warm(third, third_path).await.unwrap());
+        assert_eq!(cache.size(), 300);
+
+        // Using the first one again makes the second one the least recently used
+        drop(cache.warm(first, first_path).await.unwrap());
+        drop(cache.warm(fourth, fourth_path).await.unwrap());
+
+        assert!(cache.contains(&first));
+        assert!(!cache.contains(&second));
+        assert!(cache.contains(&third));
+        assert!(cache.contains(&fourth));
+        assert_eq!(cache.size(), 300);
+    }
+
+    #[tokio::test]
+    async fn never_evicts_running_artifacts() {
+        let dir = TempDir::new().unwrap();
+        let cache = WarmCache::new(150);
+        let (running, running_path) = artifact(&dir, 100);
+        let (other, other_path) = artifact(&dir, 100);
+
+        let guard = cache.warm(running, running_path.clone()).await.unwrap();
+        drop(cache.warm(other, other_path).await.unwrap());
+
+        assert!(cache.contains(&running));
+        assert!(!cache.contains(&other));
+
+        // Even when it alone is over the capacity
+        let big_cache = WarmCache::new(50);
+        let big_guard = big_cache.warm(running, running_path).await.unwrap();
+        assert!(big_cache.contains(&running));
+
+        drop(big_guard);
+        assert!(!big_cache.contains(&running));
+        drop(guard);
+        assert!(cache.contains(&running));
+    }
+}
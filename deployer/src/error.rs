@@ -16,8 +16,14 @@ pub enum Error {
     Build(#[source] Box<dyn StdError + Send>),
     #[error("Prepare to load error: {0}")]
     PrepareLoad(String),
+    #[error(
+        "Deployment artifact is corrupted: checksum mismatch (expected {expected}, found {actual})"
+    )]
+    ArtifactCorrupted { expected: String, actual: String },
     #[error("Load error: {0}")]
     Load(#[from] LoaderError),
+    #[error("Artifact validation error: {0}")]
+    Validation(#[source] LoaderError),
     #[error("Run error: {0}")]
     Run(#[from] shuttle_service::Error),
     #[error("Pre-deployment test failure: {0}")]
@@ -30,6 +36,20 @@ pub enum Error {
     OldCleanup(#[source] Box<dyn StdError + Send>),
     #[error("Gateway client error: {0}")]
     GatewayClient(#[from] gateway_client::Error),
+    #[error("The deployer is being drained and does not start new deployments")]
+    Draining,
+    #[error("Invalid readiness probe: {0}")]
+    ReadinessConfig(String),
+    #[error("Service did not become ready: {0}")]
+    NotReady(String),
+    #[error("Invalid smoke test: {0}")]
+    SmokeTestConfig(String),
+    #[error("Smoke test failed: {0}")]
+    SmokeTestFailed(String),
+    #[error("Invalid restart policy: {0}")]
+    RestartPolicyConfig(String),
+    #[error("The build was cancelled")]
+    Cancelled,
 }
 
 #[derive(Error, Debug)]
@@ -42,4 +62,54 @@ pub enum TestError {
     Run(#[from] tokio::task::JoinError),
 }
 
+/// Who is to blame when a deployment fails
+#[derive(Clone, Copy, Debug, PartialEq, Eq, strum::Display)]
+#[strum(serialize_all = "lowercase")]
+pub enum Fault {
+    /// The user's code panicked or returned an error
+    User,
+    /// The deployer, the loader or the infrastructure behind them failed
+    Platform,
+}
+
+impl Error {
+    pub fn fault(&self) -> Fault {
+        match self {
+            Error::Build(_)
+            | Error::PreDeployTestFailure(_)
+            | Error::SecretsParse(_)
+            | Error::ReadinessConfig(_)
+            | Error::NotReady(_)
+            | Error::SmokeTestConfig(_)
+            | Error::SmokeTestFailed(_)
+            | Error::RestartPolicyConfig(_)
+            | Error::Load(LoaderError::GetEntrypoint(_))
+            | Error::Validation(LoaderError::GetEntrypoint(_)) => Fault::User,
+            Error::Run(error) => service_error_fault(error),
+            Error::InputOutput(_)
+            | Error::PrepareLoad(_)
+            | Error::ArtifactCorrupted { .. }
+            | Error::Load(LoaderError::Load(_))
+            | Error::Validation(LoaderError::Load(_))
+            | Error::SecretsSet(_)
+            | Error::OldCleanup(_)
+            | Error::GatewayClient(_)
+            | Error::Draining
+            | Error::Cancelled => Fault::Platform,
+        }
+    }
+}
+
+/// Errors from a service come from the user's code, unless they came from the resources we provide to it
+pub fn service_error_fault(error: &shuttle_service::Error) -> Fault {
+    match error {
+        shuttle_service::Error::Io(_) | shuttle_service::Error::Database(_) => Fault::Platform,
+        shuttle_service::Error::Secret(_)
+        | shuttle_service::Error::BuildPanic(_)
+        | shuttle_service::Error::BindPanic(_)
+        | shuttle_service::Error::StringInterpolation(_)
+        | shuttle_service::Error::Custom(_) => Fault::User,
+    }
+}
+
 pub type Result<T> = std::result::Result<T, Error>;
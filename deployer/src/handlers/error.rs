@@ -8,12 +8,16 @@ use serde::{ser::SerializeMap, Serialize};
 use shuttle_common::models::error::ApiError;
 use tracing::error;
 
+use crate::deployment::ProfileError;
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("Streaming error: {0}")]
     Streaming(#[from] axum::Error),
     #[error("Persistence failure: {0}")]
     Persistence(#[from] crate::persistence::PersistenceError),
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
     #[error("Failed to convert {from} to {to}")]
     Convert {
         from: String,
@@ -22,6 +26,14 @@ pub enum Error {
     },
     #[error("record could not be found")]
     NotFound,
+    #[error("Invalid request: {0}")]
+    BadRequest(String),
+    #[error("The deployer is being drained and does not accept new deployments")]
+    Draining,
+    #[error("Chunk does not continue the upload, which has received {received} bytes")]
+    UploadOffset { received: u64 },
+    #[error("Profiling error: {0}")]
+    Profile(#[from] crate::deployment::ProfileError),
     #[error("Custom error: {0}")]
     Custom(#[from] anyhow::Error),
 }
@@ -45,6 +57,10 @@ impl IntoResponse for Error {
 
         let code = match self {
             Error::NotFound => StatusCode::NOT_FOUND,
+            Error::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Error::Draining => StatusCode::SERVICE_UNAVAILABLE,
+            Error::UploadOffset { .. } => StatusCode::CONFLICT,
+            Error::Profile(ProfileError::Busy) => StatusCode::CONFLICT,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         };
 
@@ -5,37 +5,70 @@ use axum::extract::{Extension, Path, Query};
 use axum::handler::Handler;
 use axum::headers::HeaderMapExt;
 use axum::middleware::from_extractor;
-use axum::routing::{get, post, Router};
+use axum::response::IntoResponse;
+use axum::routing::{delete, get, post, Router};
 use axum::{extract::BodyStream, Json};
 use bytes::BufMut;
-use chrono::{TimeZone, Utc};
+use chrono::{DateTime, TimeZone, Utc};
 use fqdn::FQDN;
 use futures::StreamExt;
+use hyper::header::CONTENT_TYPE;
 use hyper::Uri;
 use shuttle_common::backends::auth::{
     AdminSecretLayer, AuthPublicKey, Claim, JwtAuthenticationLayer, Scope, ScopedLayer,
 };
 use shuttle_common::backends::headers::XShuttleAccountName;
 use shuttle_common::backends::metrics::{Metrics, TraceLayer};
-use shuttle_common::models::secret;
+use shuttle_common::models::deployment::{
+    ActiveDeployment, ArtifactRemoval, DebugDump, DrainStatus, LATEST_TAG,
+};
+use shuttle_common::models::{log_forwarding, secret};
 use shuttle_common::project::ProjectName;
 use shuttle_common::{request_span, LogItem};
 use shuttle_service::loader::clean_crate;
-use tracing::{debug, error, field, instrument, trace};
+use tracing::{debug, error, field, info, instrument, trace};
 use uuid::Uuid;
 
-use crate::deployment::{DeploymentManager, Queued};
-use crate::persistence::{Deployment, Log, Persistence, ResourceManager, SecretGetter, State};
+use crate::deployment::{
+    log_forwarder::{self, Cipher},
+    run_queue::RunQueue,
+    timings::PHASE_TIMINGS,
+    Built, DeploymentManager, Queued, RestartPolicy,
+};
+use crate::error::Fault;
+use crate::persistence::{
+    Deployment, DeploymentFilter, DeploymentRunnable, Log, Persistence, ResourceManager,
+    SecretGetter, SinkType, State,
+};
 
 use std::collections::HashMap;
+use std::path::{Component, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// How long a restart or a forced removal waits for the process of a deployment to go through its pre-stop hook and stop
+const STOP_TIMEOUT: Duration = Duration::from_secs(15);
 
-pub use {self::error::Error, self::error::Result};
+/// How long a profile samples for when the request does not say
+const DEFAULT_PROFILE_SECS: u64 = 10;
+
+/// Longest a profile can sample for, so that the deployer is not slowed down by sampling for long
+const MAX_PROFILE_SECS: u64 = 60;
+
+pub use {
+    self::error::Error, self::error::Result, self::upload::Uploads, self::v1::ArchiveSources,
+};
 
 mod project;
+mod upload;
+mod v1;
 
 pub async fn make_router(
     persistence: Persistence,
     deployment_manager: DeploymentManager,
+    cipher: Cipher,
+    archive_sources: ArchiveSources,
+    uploads: Uploads,
     proxy_fqdn: FQDN,
     admin_secret: String,
     auth_uri: Uri,
@@ -52,15 +85,62 @@ pub async fn make_router(
                 .post(post_service.layer(ScopedLayer::new(vec![Scope::ServiceCreate])))
                 .delete(stop_service.layer(ScopedLayer::new(vec![Scope::ServiceCreate]))),
         )
+        .route(
+            "/projects/:project_name/services/:service_name/restart",
+            post(restart_service.layer(ScopedLayer::new(vec![Scope::DeploymentPush]))),
+        )
         .route(
             "/projects/:project_name/services/:service_name/summary",
             get(get_service_summary).layer(ScopedLayer::new(vec![Scope::Service])),
         )
+        .route(
+            "/projects/:project_name/services/:service_name/uploads",
+            post(upload::start_upload.layer(ScopedLayer::new(vec![Scope::DeploymentPush]))),
+        )
+        .route(
+            "/projects/:project_name/services/:service_name/uploads/:upload_id",
+            get(upload::get_upload.layer(ScopedLayer::new(vec![Scope::DeploymentPush])))
+                .put(upload::put_chunk.layer(ScopedLayer::new(vec![Scope::DeploymentPush]))),
+        )
+        .route(
+            "/projects/:project_name/services/:service_name/uploads/:upload_id/deploy",
+            post(upload::deploy_upload.layer(ScopedLayer::new(vec![Scope::DeploymentPush]))),
+        )
+        .route(
+            "/projects/:project_name/services/:service_name/tags/:tag",
+            get(get_tagged_deployment.layer(ScopedLayer::new(vec![Scope::Deployment]))),
+        )
+        .route(
+            "/projects/:project_name/services/:service_name/log-forwarding",
+            get(get_log_forwarding.layer(ScopedLayer::new(vec![Scope::Service])))
+                .post(post_log_forwarding.layer(ScopedLayer::new(vec![Scope::ServiceCreate])))
+                .delete(delete_log_forwarding.layer(ScopedLayer::new(vec![Scope::ServiceCreate]))),
+        )
         .route(
             "/projects/:project_name/deployments/:deployment_id",
             get(get_deployment.layer(ScopedLayer::new(vec![Scope::Deployment])))
                 .delete(delete_deployment.layer(ScopedLayer::new(vec![Scope::DeploymentPush]))),
         )
+        .route(
+            "/projects/:project_name/deployments/:deployment_id/status",
+            get(get_deployment_status.layer(ScopedLayer::new(vec![Scope::Deployment]))),
+        )
+        .route(
+            "/projects/:project_name/deployments/:deployment_id/summary",
+            get(get_deployment_summary.layer(ScopedLayer::new(vec![Scope::Deployment]))),
+        )
+        .route(
+            "/projects/:project_name/deployments/:deployment_id/artifact",
+            delete(delete_deployment_artifact.layer(ScopedLayer::new(vec![Scope::DeploymentPush]))),
+        )
+        .route(
+            "/projects/:project_name/deployments/:deployment_id/profile",
+            post(post_profile.layer(ScopedLayer::new(vec![Scope::DeploymentPush]))),
+        )
+        .route(
+            "/projects/:project_name/deployments/:deployment_id/cancel",
+            post(cancel_deployment.layer(ScopedLayer::new(vec![Scope::DeploymentPush]))),
+        )
         .route(
             "/projects/:project_name/ws/deployments/:deployment_id/logs",
             get(get_logs_subscribe.layer(ScopedLayer::new(vec![Scope::Logs]))),
@@ -71,14 +151,42 @@ pub async fn make_router(
         )
         .route(
             "/projects/:project_name/secrets/:service_name",
-            get(get_secrets.layer(ScopedLayer::new(vec![Scope::Secret]))),
+            get(get_secrets.layer(ScopedLayer::new(vec![Scope::Secret])))
+                .post(post_secrets.layer(ScopedLayer::new(vec![Scope::SecretWrite]))),
+        )
+        .route(
+            "/projects/:project_name/drain",
+            get(get_drain.layer(ScopedLayer::new(vec![Scope::Admin])))
+                .post(post_drain.layer(ScopedLayer::new(vec![Scope::Admin]))),
+        )
+        .route(
+            "/projects/:project_name/debug",
+            get(get_debug.layer(ScopedLayer::new(vec![Scope::Admin]))),
+        )
+        .route(
+            "/projects/:project_name/metrics",
+            get(get_metrics.layer(ScopedLayer::new(vec![Scope::Admin]))),
         )
         .route(
             "/projects/:project_name/clean",
             post(post_clean.layer(ScopedLayer::new(vec![Scope::DeploymentPush]))),
         )
+        // Stable API for integrations. Breaking changes to these go into a new version instead.
+        .route(
+            "/projects/:project_name/v1/services/:service_name/deployments",
+            get(v1::list_deployments.layer(ScopedLayer::new(vec![Scope::Deployment])))
+                .post(v1::create_deployment.layer(ScopedLayer::new(vec![Scope::DeploymentPush]))),
+        )
+        .route(
+            "/projects/:project_name/v1/deployments/:deployment_id",
+            get(v1::get_deployment.layer(ScopedLayer::new(vec![Scope::Deployment])))
+                .delete(v1::stop_deployment.layer(ScopedLayer::new(vec![Scope::DeploymentPush]))),
+        )
         .layer(Extension(persistence))
         .layer(Extension(deployment_manager))
+        .layer(Extension(cipher))
+        .layer(Extension(archive_sources))
+        .layer(Extension(uploads))
         .layer(Extension(proxy_fqdn))
         .layer(JwtAuthenticationLayer::new(AuthPublicKey::new(auth_uri)))
         .layer(AdminSecretLayer::new(admin_secret))
@@ -125,10 +233,13 @@ async fn list_services(
 async fn get_service(
     Extension(persistence): Extension<Persistence>,
     Path((project_name, service_name)): Path<(String, String)>,
+    Query(params): Query<HashMap<String, String>>,
 ) -> Result<Json<shuttle_common::models::service::Detailed>> {
+    let filter = deployment_filter(&params)?;
+
     if let Some(service) = persistence.get_service_by_name(&service_name).await? {
         let deployments = persistence
-            .get_deployments(&service.id)
+            .get_filtered_deployments(&service.id, &filter)
             .await?
             .into_iter()
             .map(Into::into)
@@ -159,6 +270,37 @@ async fn get_service(
     }
 }
 
+/// Read which deployments to list from the `status` (a comma separated list of states), `since` and `until` (RFC 3339
+/// times) query parameters
+fn deployment_filter(params: &HashMap<String, String>) -> Result<DeploymentFilter> {
+    let states = match params.get("status") {
+        Some(states) => states
+            .split(',')
+            .map(|state| {
+                State::from_str(state.trim())
+                    .map_err(|_| Error::BadRequest(format!("unknown deployment state '{state}'")))
+            })
+            .collect::<Result<_>>()?,
+        None => Vec::new(),
+    };
+    let time = |name: &str| {
+        params
+            .get(name)
+            .map(|time| {
+                DateTime::parse_from_rfc3339(time)
+                    .map(|time| time.with_timezone(&Utc))
+                    .map_err(|_| Error::BadRequest(format!("{name} should be an RFC 3339 time")))
+            })
+            .transpose()
+    };
+
+    Ok(DeploymentFilter {
+        states,
+        since: time("since")?,
+        until: time("until")?,
+    })
+}
+
 #[instrument(skip_all, fields(%project_name, %service_name))]
 async fn get_service_summary(
     Extension(persistence): Extension<Persistence>,
@@ -199,40 +341,296 @@ async fn post_service(
     Query(params): Query<HashMap<String, String>>,
     mut stream: BodyStream,
 ) -> Result<Json<shuttle_common::models::deployment::Response>> {
-    let service = persistence.get_or_create_service(&service_name).await?;
+    if let Some(image) = params.get("image") {
+        return deploy_image(
+            &persistence,
+            &deployment_manager,
+            &service_name,
+            image,
+            &params,
+            claim,
+        )
+        .await
+        .map(Json);
+    }
+
+    let mut data = Vec::new();
+    while let Some(buf) = stream.next().await {
+        let buf = buf?;
+        debug!("Received {} bytes", buf.len());
+        data.put(buf);
+    }
+    debug!("Received a total of {} bytes", data.len());
+
+    deploy_archive(
+        &persistence,
+        &deployment_manager,
+        &service_name,
+        data,
+        &params,
+        claim,
+    )
+    .await
+    .map(Json)
+}
+
+/// Queue a deployment of an archive which was sent by the CLI, with the options it gave as query parameters
+async fn deploy_archive(
+    persistence: &Persistence,
+    deployment_manager: &DeploymentManager,
+    service_name: &str,
+    data: Vec<u8>,
+    params: &HashMap<String, String>,
+    claim: Claim,
+) -> Result<shuttle_common::models::deployment::Response> {
+    let crate_path = params
+        .get("crate-path")
+        .map(|crate_path| parse_crate_path(crate_path))
+        .transpose()?;
+    let deployment = queue_deployment(
+        persistence,
+        deployment_manager,
+        service_name,
+        data,
+        !params.contains_key("no-test"),
+        params.get("pre-stop").cloned(),
+        crate_path,
+        !params.contains_key("no-locked"),
+        parse_run_budget(params)?,
+        parse_ttl(params)?,
+        claim,
+    )
+    .await?;
+
+    if params.contains_key("tag-latest") {
+        persistence
+            .set_deployment_tag(&deployment.service_id, LATEST_TAG, &deployment.id)
+            .await?;
+    }
+
+    Ok(deployment.into())
+}
+
+/// Record a new deployment of a pre-built container image and put it straight on the run queue, since there is
+/// nothing to build
+async fn deploy_image(
+    persistence: &Persistence,
+    deployment_manager: &DeploymentManager,
+    service_name: &str,
+    image: &str,
+    params: &HashMap<String, String>,
+    claim: Claim,
+) -> Result<shuttle_common::models::deployment::Response> {
+    if deployment_manager.is_draining() {
+        return Err(Error::Draining);
+    }
+
+    if !deployment_manager.runs_images() {
+        return Err(Error::BadRequest(
+            "this deployer does not run container images".to_string(),
+        ));
+    }
+
+    check_image(image)?;
+
+    let pre_stop_hook = params.get("pre-stop").cloned();
+    check_pre_stop_hook(pre_stop_hook.as_deref())?;
+    let run_budget = parse_run_budget(params)?;
+    let ttl = parse_ttl(params)?;
+
+    let service = persistence.get_or_create_service(service_name).await?;
     let id = Uuid::new_v4();
 
     let deployment = Deployment {
         id,
         service_id: service.id,
-        state: State::Queued,
+        state: State::Built,
         last_update: Utc::now(),
         address: None,
     };
 
-    let mut data = Vec::new();
-    while let Some(buf) = stream.next().await {
-        let buf = buf?;
-        debug!("Received {} bytes", buf.len());
-        data.put(buf);
+    persistence.insert_deployment(deployment.clone()).await?;
+    persistence.set_image(&id, image).await?;
+
+    if let Some(pre_stop_hook) = &pre_stop_hook {
+        persistence.set_pre_stop_hook(&id, pre_stop_hook).await?;
     }
-    debug!("Received a total of {} bytes", data.len());
+
+    if let Some(run_budget) = run_budget {
+        persistence.set_run_budget(&id, run_budget).await?;
+    }
+
+    let expires_at = expires_at(ttl);
+    if let Some(expires_at) = expires_at {
+        persistence.set_expires_at(&id, expires_at).await?;
+    }
+
+    if params.contains_key("tag-latest") {
+        persistence
+            .set_deployment_tag(&service.id, LATEST_TAG, &id)
+            .await?;
+    }
+
+    let built = Built {
+        id,
+        service_name: service.name,
+        service_id: service.id,
+        tracing_context: Default::default(),
+        claim: Some(claim),
+        pre_stop_hook,
+        image: Some(image.to_string()),
+        run_budget: run_budget.map(|secs| Duration::from_secs(secs.into())),
+        expires_at,
+    };
+
+    deployment_manager.run_push(built).await;
+
+    Ok(deployment.into())
+}
+
+/// Image references are passed on to the container engine's CLI, so anything it could take for an option or which
+/// cannot be part of a reference is turned away
+fn check_image(image: &str) -> Result<()> {
+    let valid = !image.is_empty()
+        && !image.starts_with('-')
+        && image
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-' | '/' | ':' | '@'));
+
+    if !valid {
+        return Err(Error::BadRequest(format!(
+            "'{image}' is not a valid container image reference"
+        )));
+    }
+
+    Ok(())
+}
+
+fn check_pre_stop_hook(pre_stop_hook: Option<&str>) -> Result<()> {
+    if let Some(pre_stop_hook) = pre_stop_hook {
+        if !pre_stop_hook.starts_with('/') {
+            return Err(Error::BadRequest(
+                "the pre-stop hook should be a path starting with '/'".to_string(),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// The run budget of a deployment is given in whole seconds, and a budget of nothing is taken as a mistake
+fn parse_run_budget(params: &HashMap<String, String>) -> Result<Option<u32>> {
+    params
+        .get("run-budget")
+        .map(|run_budget| match run_budget.parse() {
+            Ok(0) | Err(_) => Err(Error::BadRequest(format!(
+                "the run budget '{run_budget}' should be a positive number of seconds"
+            ))),
+            Ok(secs) => Ok(secs),
+        })
+        .transpose()
+}
+
+/// The time to live of a deployment is given in whole seconds, like its run budget
+fn parse_ttl(params: &HashMap<String, String>) -> Result<Option<u32>> {
+    params
+        .get("ttl")
+        .map(|ttl| match ttl.parse() {
+            Ok(0) | Err(_) => Err(Error::BadRequest(format!(
+                "the time to live '{ttl}' should be a positive number of seconds"
+            ))),
+            Ok(secs) => Ok(secs),
+        })
+        .transpose()
+}
+
+/// When a deployment made now with a time to live of `ttl` seconds expires
+fn expires_at(ttl: Option<u32>) -> Option<DateTime<Utc>> {
+    ttl.map(|secs| Utc::now() + chrono::Duration::seconds(secs.into()))
+}
+
+/// The crate of a service which is packaged with a larger directory is given as a `/` separated path inside the archive.
+/// It may not leave the archive.
+fn parse_crate_path(crate_path: &str) -> Result<PathBuf> {
+    let path = PathBuf::from_iter(crate_path.split('/').filter(|part| !part.is_empty()));
+
+    if !path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return Err(Error::BadRequest(format!(
+            "the crate path '{crate_path}' should be a relative path inside the archive"
+        )));
+    }
+
+    Ok(path)
+}
+
+/// Record a new deployment of a service's archive `data` and put it on the build queue
+#[allow(clippy::too_many_arguments)]
+async fn queue_deployment(
+    persistence: &Persistence,
+    deployment_manager: &DeploymentManager,
+    service_name: &str,
+    data: Vec<u8>,
+    will_run_tests: bool,
+    pre_stop_hook: Option<String>,
+    crate_path: Option<PathBuf>,
+    locked: bool,
+    run_budget: Option<u32>,
+    ttl: Option<u32>,
+    claim: Claim,
+) -> Result<Deployment> {
+    if deployment_manager.is_draining() {
+        return Err(Error::Draining);
+    }
+
+    check_pre_stop_hook(pre_stop_hook.as_deref())?;
+
+    let service = persistence.get_or_create_service(service_name).await?;
+    let id = Uuid::new_v4();
+
+    let deployment = Deployment {
+        id,
+        service_id: service.id,
+        state: State::Queued,
+        last_update: Utc::now(),
+        address: None,
+    };
 
     persistence.insert_deployment(deployment.clone()).await?;
 
+    if let Some(pre_stop_hook) = &pre_stop_hook {
+        persistence.set_pre_stop_hook(&id, pre_stop_hook).await?;
+    }
+
+    if let Some(run_budget) = run_budget {
+        persistence.set_run_budget(&id, run_budget).await?;
+    }
+
+    let expires_at = expires_at(ttl);
+    if let Some(expires_at) = expires_at {
+        persistence.set_expires_at(&id, expires_at).await?;
+    }
+
     let queued = Queued {
         id,
         service_name: service.name,
         service_id: service.id,
         data,
-        will_run_tests: !params.contains_key("no-test"),
+        will_run_tests,
         tracing_context: Default::default(),
         claim: Some(claim),
+        pre_stop_hook,
+        crate_path,
+        locked,
+        run_budget: run_budget.map(|secs| Duration::from_secs(secs.into())),
+        expires_at,
     };
 
     deployment_manager.queue_push(queued).await;
 
-    Ok(Json(deployment.into()))
+    Ok(deployment)
 }
 
 #[instrument(skip_all, fields(%project_name, %service_name))]
@@ -271,18 +669,318 @@ async fn stop_service(
     }
 }
 
+/// Stop the running deployment of a service and start its stored artifact again in a fresh process, without building
+/// it again. Nothing happens when the service has no running deployment, which is reported by a summary without one.
+#[instrument(skip_all, fields(%project_name, %service_name))]
+async fn restart_service(
+    Extension(persistence): Extension<Persistence>,
+    Extension(deployment_manager): Extension<DeploymentManager>,
+    Extension(proxy_fqdn): Extension<FQDN>,
+    Path((project_name, service_name)): Path<(String, String)>,
+) -> Result<Json<shuttle_common::models::service::Summary>> {
+    let Some(service) = persistence.get_service_by_name(&service_name).await? else {
+        return Err(Error::NotFound);
+    };
+
+    let resources = persistence
+        .get_resources(&service.id)
+        .await?
+        .into_iter()
+        .map(Into::into)
+        .collect();
+    let mut response = shuttle_common::models::service::Summary {
+        name: service.name,
+        deployment: None,
+        resources,
+        uri: format!("https://{proxy_fqdn}"),
+    };
+
+    let Some(running) = persistence
+        .get_all_runnable_deployments()
+        .await?
+        .into_iter()
+        .find(|deployment| deployment.service_id == service.id)
+    else {
+        return Ok(Json(response));
+    };
+
+    response.deployment = restart_deployment(&persistence, &deployment_manager, running)
+        .await?
+        .map(Into::into);
+
+    Ok(Json(response))
+}
+
+/// Stop a running deployment and start it again from its stored artifact, returning the deployment as it stopped
+async fn restart_deployment(
+    persistence: &Persistence,
+    deployment_manager: &DeploymentManager,
+    running: DeploymentRunnable,
+) -> Result<Option<Deployment>> {
+    if deployment_manager.is_draining() {
+        return Err(Error::Draining);
+    }
+
+    // The same artifact cannot be loaded again before the old process has let go of it
+    let stopped = stop_deployment_and_wait(persistence, deployment_manager, &running.id).await?;
+
+    let built = Built {
+        id: running.id,
+        service_name: running.service_name,
+        service_id: running.service_id,
+        tracing_context: Default::default(),
+        claim: None, // This will cause us to read the resource info from past provisions
+        pre_stop_hook: running.pre_stop_hook,
+        image: running.image,
+        run_budget: running
+            .run_budget
+            .map(|secs| Duration::from_secs(secs.into())),
+        expires_at: running.expires_at,
+    };
+    deployment_manager.run_push(built).await;
+
+    Ok(stopped)
+}
+
+/// Stop a running deployment and wait for it to be done, returning the deployment as it stopped
+async fn stop_deployment_and_wait(
+    persistence: &Persistence,
+    deployment_manager: &DeploymentManager,
+    id: &Uuid,
+) -> Result<Option<Deployment>> {
+    deployment_manager.kill(*id).await;
+
+    tokio::time::timeout(STOP_TIMEOUT, async {
+        loop {
+            match persistence.get_deployment(id).await? {
+                Some(deployment) if deployment.state == State::Running => {
+                    tokio::time::sleep(Duration::from_millis(100)).await
+                }
+                deployment => return Ok::<_, Error>(deployment),
+            }
+        }
+    })
+    .await
+    .map_err(|_| anyhow::anyhow!("deployment did not stop in time"))?
+}
+
+#[instrument(skip_all, fields(%project_name, %service_name))]
+async fn get_log_forwarding(
+    Extension(persistence): Extension<Persistence>,
+    Path((project_name, service_name)): Path<(String, String)>,
+) -> Result<Json<log_forwarding::Response>> {
+    let Some(service) = persistence.get_service_by_name(&service_name).await? else {
+        return Err(Error::NotFound);
+    };
+
+    if let Some(log_forwarding) = persistence.get_log_forwarding(&service.id).await? {
+        Ok(Json(log_forwarding.into()))
+    } else {
+        Err(Error::NotFound)
+    }
+}
+
+#[instrument(skip_all, fields(%project_name, %service_name))]
+async fn post_log_forwarding(
+    Extension(persistence): Extension<Persistence>,
+    Extension(cipher): Extension<Cipher>,
+    Path((project_name, service_name)): Path<(String, String)>,
+    Json(config): Json<log_forwarding::Config>,
+) -> Result<Json<log_forwarding::Response>> {
+    let sink = SinkType::from(config.sink);
+
+    match sink {
+        SinkType::Http | SinkType::ObjectStore => {
+            let is_http = config
+                .endpoint
+                .parse::<Uri>()
+                .map(|uri| matches!(uri.scheme_str(), Some("http" | "https")))
+                .unwrap_or_default();
+
+            if !is_http {
+                return Err(Error::BadRequest(format!(
+                    "endpoint for a {} sink should be an http(s) URL",
+                    config.sink
+                )));
+            }
+        }
+        SinkType::Syslog => {
+            if config.endpoint.rsplit_once(':').is_none() {
+                return Err(Error::BadRequest(
+                    "endpoint for a syslog sink should be in the form 'host:port'".to_string(),
+                ));
+            }
+        }
+    }
+
+    log_forwarder::resolve_endpoint(&sink, &config.endpoint)
+        .await
+        .map_err(|error| Error::BadRequest(error.to_string()))?;
+
+    let service = persistence.get_or_create_service(&service_name).await?;
+    let auth = config.auth.as_deref().map(|auth| cipher.encrypt(auth));
+
+    persistence
+        .set_log_forwarding(&service.id, sink, &config.endpoint, auth)
+        .await?;
+
+    let log_forwarding = persistence
+        .get_log_forwarding(&service.id)
+        .await?
+        .ok_or(Error::NotFound)?;
+
+    Ok(Json(log_forwarding.into()))
+}
+
+#[instrument(skip_all, fields(%project_name, %service_name))]
+async fn delete_log_forwarding(
+    Extension(persistence): Extension<Persistence>,
+    Path((project_name, service_name)): Path<(String, String)>,
+) -> Result<Json<log_forwarding::Response>> {
+    let Some(service) = persistence.get_service_by_name(&service_name).await? else {
+        return Err(Error::NotFound);
+    };
+
+    if let Some(log_forwarding) = persistence.get_log_forwarding(&service.id).await? {
+        persistence.delete_log_forwarding(&service.id).await?;
+
+        Ok(Json(log_forwarding.into()))
+    } else {
+        Err(Error::NotFound)
+    }
+}
+
 #[instrument(skip_all, fields(%project_name, %deployment_id))]
 async fn get_deployment(
     Extension(persistence): Extension<Persistence>,
     Path((project_name, deployment_id)): Path<(String, Uuid)>,
 ) -> Result<Json<shuttle_common::models::deployment::Response>> {
     if let Some(deployment) = persistence.get_deployment(&deployment_id).await? {
-        Ok(Json(deployment.into()))
+        Ok(Json(with_details(&persistence, deployment).await?))
     } else {
         Err(Error::NotFound)
     }
 }
 
+#[instrument(skip_all, fields(%project_name, %deployment_id))]
+async fn get_deployment_status(
+    Extension(persistence): Extension<Persistence>,
+    Path((project_name, deployment_id)): Path<(String, Uuid)>,
+) -> Result<Json<shuttle_common::models::deployment::Status>> {
+    let Some(deployment) = persistence.get_deployment(&deployment_id).await? else {
+        return Err(Error::NotFound);
+    };
+
+    let history: Vec<_> = persistence
+        .get_state_history(&deployment_id)
+        .await?
+        .into_iter()
+        .map(
+            |(state, timestamp)| shuttle_common::models::deployment::StateChange {
+                state: state.into(),
+                timestamp,
+            },
+        )
+        .collect();
+
+    // Every start of a deployment goes through loading, including the first one
+    let loads = history
+        .iter()
+        .filter(|change| matches!(change.state, shuttle_common::deployment::State::Loading))
+        .count();
+
+    Ok(Json(shuttle_common::models::deployment::Status {
+        address: deployment.address,
+        last_error: persistence.get_last_error(&deployment_id).await?,
+        restarts: loads.saturating_sub(1) as u32,
+        artifact_deleted_at: persistence.get_artifact_deleted(&deployment_id).await?,
+        history,
+        deployment: with_details(&persistence, deployment).await?,
+    }))
+}
+
+#[instrument(skip_all, fields(%project_name, %deployment_id))]
+async fn get_deployment_summary(
+    Extension(persistence): Extension<Persistence>,
+    Extension(deployment_manager): Extension<DeploymentManager>,
+    Path((project_name, deployment_id)): Path<(String, Uuid)>,
+) -> Result<Json<shuttle_common::models::deployment::Summary>> {
+    let Some(deployment) = persistence.get_deployment(&deployment_id).await? else {
+        return Err(Error::NotFound);
+    };
+
+    let history: Vec<_> = persistence
+        .get_state_history(&deployment_id)
+        .await?
+        .into_iter()
+        .map(
+            |(state, timestamp)| shuttle_common::models::deployment::StateChange {
+                state: state.into(),
+                timestamp,
+            },
+        )
+        .collect();
+
+    // A built library is identified by its checksum, which is only there once the build went through
+    let artifact = match persistence.get_image(&deployment_id).await? {
+        Some(image) => Some(image),
+        None => deployment_manager
+            .storage_manager()
+            .deployment_library_checksum_path(&deployment_id)
+            .ok()
+            .and_then(|path| std::fs::read_to_string(path).ok())
+            .map(|checksum| format!("sha256:{}", checksum.trim())),
+    };
+
+    let mut summary = shuttle_common::models::deployment::Summary::from_history(
+        deployment_id,
+        deployment.state.into(),
+        &history,
+    );
+    summary.address = deployment.address;
+    summary.artifact = artifact;
+    summary.error = persistence.get_last_error(&deployment_id).await?;
+
+    // Failures on the side of the platform, like a build slot which never came, may well go through on another try
+    if deployment.state == State::Crashed {
+        let fault = persistence.get_last_error_fault(&deployment_id).await?;
+        summary.retryable = Some(fault.as_deref() == Some(&Fault::Platform.to_string()));
+    }
+
+    Ok(Json(summary))
+}
+
+#[instrument(skip_all, fields(%project_name, %service_name, %tag))]
+async fn get_tagged_deployment(
+    Extension(persistence): Extension<Persistence>,
+    Path((project_name, service_name, tag)): Path<(String, String, String)>,
+) -> Result<Json<shuttle_common::models::deployment::Response>> {
+    let Some(service) = persistence.get_service_by_name(&service_name).await? else {
+        return Err(Error::NotFound);
+    };
+
+    if let Some(deployment) = persistence.get_tagged_deployment(&service.id, &tag).await? {
+        Ok(Json(with_details(&persistence, deployment).await?))
+    } else {
+        Err(Error::NotFound)
+    }
+}
+
+/// The details of a single deployment also say how much memory it peaked at and whether it is built with `--locked`
+async fn with_details(
+    persistence: &Persistence,
+    deployment: Deployment,
+) -> Result<shuttle_common::models::deployment::Response> {
+    let peak_memory = persistence.get_peak_memory(&deployment.id).await?;
+    let locked = persistence.get_locked(&deployment.id).await?;
+
+    Ok(shuttle_common::models::deployment::Response {
+        peak_memory,
+        locked,
+        ..deployment.into()
+    })
+}
+
 #[instrument(skip_all, fields(%project_name, %deployment_id))]
 async fn delete_deployment(
     Extension(deployment_manager): Extension<DeploymentManager>,
@@ -298,20 +996,161 @@ async fn delete_deployment(
     }
 }
 
+/// Remove the stored artifact and the logs of a deployment, to free the space they take or to get rid of an old build.
+/// The record of the deployment and its state history are kept. A running deployment is only stopped for this when
+/// forced, while one which is still on its way to running is left alone.
+#[instrument(skip_all, fields(%project_name, %deployment_id))]
+async fn delete_deployment_artifact(
+    Extension(persistence): Extension<Persistence>,
+    Extension(deployment_manager): Extension<DeploymentManager>,
+    Path((project_name, deployment_id)): Path<(String, Uuid)>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<ArtifactRemoval>> {
+    let Some(deployment) = persistence.get_deployment(&deployment_id).await? else {
+        return Err(Error::NotFound);
+    };
+
+    match deployment.state {
+        State::Running if params.contains_key("force") => {
+            stop_deployment_and_wait(&persistence, &deployment_manager, &deployment_id).await?;
+        }
+        State::Running => {
+            return Err(Error::BadRequest(
+                "the deployment is running, stop it first or force its removal".to_string(),
+            ));
+        }
+        State::Queued | State::Building | State::Built | State::Loading => {
+            return Err(Error::BadRequest(format!(
+                "the deployment is {}, its artifact can only be removed once it is done",
+                deployment.state
+            )));
+        }
+        _ => {}
+    }
+
+    let freed_bytes = deployment_manager
+        .storage_manager()
+        .remove_deployment_artifacts(&deployment_id)?;
+    let removed_logs = persistence.delete_deployment_logs(&deployment_id).await?;
+    persistence.set_artifact_deleted(&deployment_id).await?;
+
+    info!(freed_bytes, removed_logs, "removed deployment artifact");
+
+    Ok(Json(ArtifactRemoval {
+        id: deployment_id,
+        freed_bytes,
+        removed_logs,
+    }))
+}
+
+/// Capture a CPU profile of a running deployment over the `seconds` query parameter, rendered as a flamegraph in SVG.
+/// Only one profile is captured at a time.
+#[instrument(skip_all, fields(%project_name, %deployment_id))]
+async fn post_profile(
+    Extension(persistence): Extension<Persistence>,
+    Extension(deployment_manager): Extension<DeploymentManager>,
+    Path((project_name, deployment_id)): Path<(String, Uuid)>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<impl IntoResponse> {
+    let Some(profiler) = deployment_manager.profiler() else {
+        return Err(Error::BadRequest(
+            "profiling is not allowed on this deployer".to_string(),
+        ));
+    };
+
+    let Some(deployment) = persistence.get_deployment(&deployment_id).await? else {
+        return Err(Error::NotFound);
+    };
+
+    if deployment.state != State::Running {
+        return Err(Error::BadRequest(format!(
+            "the deployment is {}, only running deployments can be profiled",
+            deployment.state
+        )));
+    }
+
+    if persistence.get_image(&deployment_id).await?.is_some() {
+        return Err(Error::BadRequest(
+            "deployments of a container image run out of process and cannot be profiled"
+                .to_string(),
+        ));
+    }
+
+    let seconds = match params.get("seconds") {
+        Some(seconds) => match seconds.parse::<u64>() {
+            Ok(seconds) if (1..=MAX_PROFILE_SECS).contains(&seconds) => seconds,
+            _ => {
+                return Err(Error::BadRequest(format!(
+                    "seconds should be a number from 1 to {MAX_PROFILE_SECS}"
+                )))
+            }
+        },
+        None => DEFAULT_PROFILE_SECS,
+    };
+
+    info!(seconds, "capturing profile");
+
+    let svg = profiler.flamegraph(Duration::from_secs(seconds)).await?;
+
+    Ok(([(CONTENT_TYPE, "image/svg+xml")], svg))
+}
+
+#[instrument(skip_all, fields(%project_name, %deployment_id))]
+async fn cancel_deployment(
+    Extension(deployment_manager): Extension<DeploymentManager>,
+    Extension(persistence): Extension<Persistence>,
+    Path((project_name, deployment_id)): Path<(String, Uuid)>,
+) -> Result<Json<shuttle_common::models::deployment::Response>> {
+    let Some(deployment) = persistence.get_deployment(&deployment_id).await? else {
+        return Err(Error::NotFound);
+    };
+
+    if !matches!(deployment.state, State::Queued | State::Building) {
+        return Err(Error::BadRequest(format!(
+            "only queued or building deployments can be cancelled, this one is {}",
+            deployment.state
+        )));
+    }
+
+    if !deployment_manager.cancel(deployment.id).await {
+        return Err(Error::BadRequest(
+            "the build of this deployment is already done".to_string(),
+        ));
+    }
+
+    Ok(Json(deployment.into()))
+}
+
 #[instrument(skip_all, fields(%project_name, %deployment_id))]
 async fn get_logs(
     Extension(persistence): Extension<Persistence>,
     Path((project_name, deployment_id)): Path<(String, Uuid)>,
+    Query(params): Query<HashMap<String, String>>,
 ) -> Result<Json<Vec<LogItem>>> {
+    let tail = tail_param(&params)?;
+    let after = after_param(&params)?;
+    let limit = params
+        .get("limit")
+        .map(|limit| limit.parse::<u32>())
+        .transpose()
+        .map_err(|_| Error::BadRequest("limit should be a number of lines".to_string()))?;
+
     if let Some(deployment) = persistence.get_deployment(&deployment_id).await? {
-        Ok(Json(
-            persistence
-                .get_deployment_logs(&deployment.id)
-                .await?
-                .into_iter()
-                .filter_map(Into::into)
-                .collect(),
-        ))
+        let logs = match tail {
+            Some(count) => {
+                persistence
+                    .get_last_deployment_logs(&deployment.id, count)
+                    .await?
+            }
+            None if after.is_some() || limit.is_some() => {
+                persistence
+                    .get_deployment_logs_after(&deployment.id, after, limit)
+                    .await?
+            }
+            None => persistence.get_deployment_logs(&deployment.id).await?,
+        };
+
+        Ok(Json(logs.into_iter().filter_map(Into::into).collect()))
     } else {
         Err(Error::NotFound)
     }
@@ -320,14 +1159,51 @@ async fn get_logs(
 async fn get_logs_subscribe(
     Extension(persistence): Extension<Persistence>,
     Path((_project_name, deployment_id)): Path<(String, Uuid)>,
+    Query(params): Query<HashMap<String, String>>,
     ws_upgrade: ws::WebSocketUpgrade,
-) -> axum::response::Response {
-    ws_upgrade.on_upgrade(move |s| logs_websocket_handler(s, persistence, deployment_id))
+) -> Result<axum::response::Response> {
+    let tail = tail_param(&params)?;
+    let after = after_param(&params)?;
+
+    Ok(ws_upgrade
+        .on_upgrade(move |s| logs_websocket_handler(s, persistence, deployment_id, tail, after)))
 }
 
-async fn logs_websocket_handler(mut s: WebSocket, persistence: Persistence, id: Uuid) {
+/// The `tail` query parameter, which limits logs to the last this many lines
+fn tail_param(params: &HashMap<String, String>) -> Result<Option<u32>> {
+    params
+        .get("tail")
+        .map(|tail| tail.parse())
+        .transpose()
+        .map_err(|_| Error::BadRequest("tail should be a number of lines".to_string()))
+}
+
+fn after_param(params: &HashMap<String, String>) -> Result<Option<DateTime<Utc>>> {
+    params
+        .get("after")
+        .map(|after| {
+            DateTime::parse_from_rfc3339(after)
+                .map(|after| after.with_timezone(&Utc))
+                .map_err(|_| Error::BadRequest("after should be an RFC 3339 time".to_string()))
+        })
+        .transpose()
+}
+
+/// Stream the logs of a deployment, starting with those it already has. A client picking the stream up again after a
+/// disconnect gives the time of the last line it got as `after`, so that it gets neither gaps nor duplicates.
+async fn logs_websocket_handler(
+    mut s: WebSocket,
+    persistence: Persistence,
+    id: Uuid,
+    tail: Option<u32>,
+    after: Option<DateTime<Utc>>,
+) {
     let mut log_recv = persistence.get_log_subscriber();
-    let backlog = match persistence.get_deployment_logs(&id).await {
+    let backlog = match tail {
+        Some(count) => persistence.get_last_deployment_logs(&id, count).await,
+        None => persistence.get_deployment_logs(&id).await,
+    };
+    let backlog = match backlog {
         Ok(backlog) => backlog,
         Err(error) => {
             error!(
@@ -344,9 +1220,12 @@ async fn logs_websocket_handler(mut s: WebSocket, persistence: Persistence, id:
     };
 
     // Unwrap is safe because it only returns None for out of range numbers or invalid nanosecond
-    let mut last_timestamp = Utc.timestamp_opt(0, 0).unwrap();
+    let mut last_timestamp = after.unwrap_or_else(|| Utc.timestamp_opt(0, 0).unwrap());
 
-    for log in backlog.into_iter() {
+    for log in backlog
+        .into_iter()
+        .filter(|log| after.map_or(true, |after| log.timestamp > after))
+    {
         last_timestamp = log.timestamp;
         if let Some(log_item) = Option::<LogItem>::from(log) {
             let msg = serde_json::to_string(&log_item).expect("to convert log item to json");
@@ -397,6 +1276,55 @@ async fn get_secrets(
     }
 }
 
+/// Replace the secrets of a service with those of the request, or only report what that would change with `dry-run`.
+/// A running deployment whose restart policy asks for it is restarted when the secrets change, to load the new ones.
+#[instrument(skip_all, fields(%project_name, %service_name))]
+async fn post_secrets(
+    Extension(persistence): Extension<Persistence>,
+    Extension(deployment_manager): Extension<DeploymentManager>,
+    Path((project_name, service_name)): Path<(String, String)>,
+    Query(params): Query<HashMap<String, String>>,
+    Json(request): Json<secret::SyncRequest>,
+) -> Result<Json<secret::SyncDiff>> {
+    let Some(service) = persistence.get_service_by_name(&service_name).await? else {
+        return Err(Error::NotFound);
+    };
+
+    let dry_run = params.contains_key("dry-run");
+    let mut diff = persistence
+        .sync_secrets(&service.id, &request, dry_run)
+        .await?;
+
+    if dry_run || diff.is_empty() {
+        return Ok(Json(diff));
+    }
+
+    let Some(running) = persistence
+        .get_all_runnable_deployments()
+        .await?
+        .into_iter()
+        .find(|deployment| deployment.service_id == service.id)
+    else {
+        return Ok(Json(diff));
+    };
+
+    let policy = RestartPolicy::load(
+        &deployment_manager
+            .storage_manager()
+            .deployment_restart_policy_path(&running.id)?,
+    )
+    .map_err(anyhow::Error::new)?;
+
+    if policy.on_secret_change {
+        info!(id = %running.id, "restarting deployment to load its changed secrets");
+
+        restart_deployment(&persistence, &deployment_manager, running).await?;
+        diff.restarted = true;
+    }
+
+    Ok(Json(diff))
+}
+
 async fn post_clean(
     Extension(deployment_manager): Extension<DeploymentManager>,
     Path(project_name): Path<String>,
@@ -411,6 +1339,159 @@ async fn post_clean(
     Ok(Json(lines))
 }
 
+#[instrument(skip_all)]
+async fn get_drain(
+    Extension(persistence): Extension<Persistence>,
+    Extension(deployment_manager): Extension<DeploymentManager>,
+) -> Result<Json<DrainStatus>> {
+    drain_status(&persistence, &deployment_manager)
+        .await
+        .map(Json)
+}
+
+/// Start draining this deployer. Deployments which are running keep going unless `stop=true` is given, in which case
+/// they are stopped gracefully through their pre-stop hooks.
+#[instrument(skip_all)]
+async fn post_drain(
+    Extension(persistence): Extension<Persistence>,
+    Extension(deployment_manager): Extension<DeploymentManager>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<DrainStatus>> {
+    deployment_manager.start_draining();
+
+    if params.get("stop").map(String::as_str) == Some("true") {
+        for deployment in persistence.get_all_runnable_deployments().await? {
+            deployment_manager.kill(deployment.id).await;
+        }
+    }
+
+    drain_status(&persistence, &deployment_manager)
+        .await
+        .map(Json)
+}
+
+/// Dump what the run task has yet to take on and where every unfinished deployment is at, to see what a deployer which
+/// seems stuck is doing
+#[instrument(skip_all)]
+async fn get_debug(
+    Extension(persistence): Extension<Persistence>,
+    Extension(deployment_manager): Extension<DeploymentManager>,
+) -> Result<Json<DebugDump>> {
+    debug_dump(&persistence, deployment_manager.run_queue())
+        .await
+        .map(Json)
+}
+
+async fn debug_dump(persistence: &Persistence, run_queue: &RunQueue) -> Result<DebugDump> {
+    let now = Utc::now();
+    let mut active = Vec::new();
+
+    for deployment in persistence.get_unfinished_deployments().await? {
+        // A running deployment last changed state when it started running
+        let uptime_secs = (deployment.state == State::Running)
+            .then(|| (now - deployment.last_update).num_seconds().max(0) as u64);
+
+        active.push(ActiveDeployment {
+            last_error: persistence.get_last_error(&deployment.id).await?,
+            id: deployment.id,
+            service_id: deployment.service_id,
+            state: deployment.state.into(),
+            address: deployment.address,
+            since: deployment.last_update,
+            uptime_secs,
+        });
+    }
+
+    Ok(DebugDump {
+        run_queue: run_queue.snapshot(),
+        active,
+    })
+}
+
+async fn drain_status(
+    persistence: &Persistence,
+    deployment_manager: &DeploymentManager,
+) -> Result<DrainStatus> {
+    Ok(DrainStatus {
+        draining: deployment_manager.is_draining(),
+        remaining: persistence.count_unfinished_deployments().await?,
+    })
+}
+
+/// How long the phases of deployments took on this deployer, in the Prometheus text format
+async fn get_metrics() -> impl IntoResponse {
+    (
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        PHASE_TIMINGS.render(),
+    )
+}
+
 async fn get_status() -> String {
     "Ok".to_string()
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use chrono::Utc;
+    use shuttle_common::deployment;
+    use uuid::Uuid;
+
+    use super::debug_dump;
+    use crate::deployment::run_queue::RunQueue;
+    use crate::persistence::{Deployment, Persistence, State};
+
+    #[tokio::test]
+    async fn debug_dump_shows_run_queue_and_unfinished_deployments() {
+        let (persistence, _) = Persistence::new_in_memory().await;
+        let service_id = persistence.get_or_create_service("neo").await.unwrap().id;
+        let running = Deployment {
+            id: Uuid::new_v4(),
+            service_id,
+            state: State::Running,
+            last_update: Utc::now() - chrono::Duration::seconds(30),
+            address: Some(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 9876)),
+        };
+        let stopped = Deployment {
+            id: Uuid::new_v4(),
+            service_id,
+            state: State::Stopped,
+            last_update: Utc::now(),
+            address: None,
+        };
+
+        for deployment in [&running, &stopped] {
+            persistence
+                .insert_deployment(deployment.clone())
+                .await
+                .unwrap();
+        }
+
+        let run_queue = RunQueue::default();
+        let pending = Uuid::new_v4();
+        run_queue.push(pending, "neo");
+
+        let dump = debug_dump(&persistence, &run_queue).await.unwrap();
+
+        assert_eq!(
+            dump.run_queue.iter().map(|run| run.id).collect::<Vec<_>>(),
+            vec![pending]
+        );
+        assert_eq!(dump.active.len(), 1, "only unfinished deployments show");
+
+        let active = &dump.active[0];
+        assert_eq!(active.id, running.id);
+        assert_eq!(active.state, deployment::State::Running);
+        assert_eq!(active.address, running.address);
+        assert!(active.uptime_secs.unwrap() >= 30);
+
+        // Taken on by the run task, so no longer waiting
+        run_queue.take(&pending);
+        assert!(debug_dump(&persistence, &run_queue)
+            .await
+            .unwrap()
+            .run_queue
+            .is_empty());
+    }
+}
@@ -0,0 +1,419 @@
+//! Resumable uploads of deployment archives.
+//!
+//! An upload is started empty, for an archive of a given length, and then appended to in chunks, each of which has to
+//! start at the offset the upload has reached. A client whose connection dropped asks for the current offset and carries
+//! on from there instead of sending the whole archive again. Once all of it has arrived, the upload is turned into a
+//! deployment. Chunks of an upload are appended one at a time, so concurrent requests cannot interleave their bytes.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::extract::{BodyStream, Extension, Path, Query};
+use axum::Json;
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use shuttle_common::backends::auth::Claim;
+use shuttle_common::models::deployment::{self, UploadStatus};
+use tokio::fs::{self, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tracing::{debug, instrument, warn};
+use uuid::Uuid;
+
+use crate::deployment::{storage_manager::StorageManager, DeploymentManager};
+use crate::persistence::Persistence;
+
+use super::{deploy_archive, Error, Result};
+
+/// Uploads which have not been touched for this long are given up on
+const UPLOAD_EXPIRY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Uploads in progress, with a lock for each so that only one request writes to or deploys an upload at a time
+#[derive(Clone)]
+pub struct Uploads {
+    max_bytes: u64,
+    locks: Arc<Mutex<HashMap<Uuid, Arc<tokio::sync::Mutex<()>>>>>,
+}
+
+impl Uploads {
+    /// Uploads of archives up to `max_bytes`
+    pub fn new(max_bytes: u64) -> Self {
+        Self {
+            max_bytes,
+            locks: Default::default(),
+        }
+    }
+
+    fn lock(&self, upload_id: &Uuid) -> Arc<tokio::sync::Mutex<()>> {
+        let mut locks = self.locks.lock().unwrap();
+
+        // Nobody is waiting on the locks of uploads which were deployed or given up on
+        locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+
+        locks.entry(*upload_id).or_default().clone()
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct StartParams {
+    length: u64,
+}
+
+#[derive(serde::Deserialize)]
+pub struct ChunkParams {
+    offset: u64,
+}
+
+#[instrument(skip_all, fields(%project_name, %service_name, length = params.length))]
+pub async fn start_upload(
+    Extension(deployment_manager): Extension<DeploymentManager>,
+    Extension(uploads): Extension<Uploads>,
+    Path((project_name, service_name)): Path<(String, String)>,
+    Query(params): Query<StartParams>,
+) -> Result<Json<UploadStatus>> {
+    if deployment_manager.is_draining() {
+        return Err(Error::Draining);
+    }
+
+    if params.length > uploads.max_bytes {
+        return Err(Error::BadRequest(format!(
+            "archive is larger than the limit of {} bytes",
+            uploads.max_bytes
+        )));
+    }
+
+    let storage_manager = deployment_manager.storage_manager();
+
+    if let Err(error) = storage_manager.remove_stale_uploads(UPLOAD_EXPIRY) {
+        warn!(
+            error = &error as &dyn std::error::Error,
+            "failed to remove stale uploads"
+        );
+    }
+
+    let id = create(&storage_manager, params.length).await?;
+
+    Ok(Json(UploadStatus { id, offset: 0 }))
+}
+
+#[instrument(skip_all, fields(%project_name, %service_name, %upload_id))]
+pub async fn get_upload(
+    Extension(deployment_manager): Extension<DeploymentManager>,
+    Path((project_name, service_name, upload_id)): Path<(String, String, Uuid)>,
+) -> Result<Json<UploadStatus>> {
+    let offset = received(&deployment_manager.storage_manager(), &upload_id).await?;
+
+    Ok(Json(UploadStatus {
+        id: upload_id,
+        offset,
+    }))
+}
+
+#[instrument(skip_all, fields(%project_name, %service_name, %upload_id, offset = params.offset))]
+pub async fn put_chunk(
+    Extension(deployment_manager): Extension<DeploymentManager>,
+    Extension(uploads): Extension<Uploads>,
+    Path((project_name, service_name, upload_id)): Path<(String, String, Uuid)>,
+    Query(params): Query<ChunkParams>,
+    stream: BodyStream,
+) -> Result<Json<UploadStatus>> {
+    let lock = uploads.lock(&upload_id);
+    let _guard = lock.lock().await;
+
+    let offset = append(
+        &deployment_manager.storage_manager(),
+        &upload_id,
+        params.offset,
+        stream,
+    )
+    .await?;
+    debug!(offset, "received chunk");
+
+    Ok(Json(UploadStatus {
+        id: upload_id,
+        offset,
+    }))
+}
+
+/// Deploy a completed upload. Takes the same query parameters as a direct deploy.
+#[instrument(skip_all, fields(%project_name, %service_name, %upload_id))]
+pub async fn deploy_upload(
+    Extension(persistence): Extension<Persistence>,
+    Extension(deployment_manager): Extension<DeploymentManager>,
+    Extension(uploads): Extension<Uploads>,
+    Extension(claim): Extension<Claim>,
+    Path((project_name, service_name, upload_id)): Path<(String, String, Uuid)>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<deployment::Response>> {
+    let lock = uploads.lock(&upload_id);
+    let _guard = lock.lock().await;
+
+    let data = take_complete(&deployment_manager.storage_manager(), &upload_id).await?;
+    debug!("Received a total of {} bytes", data.len());
+
+    deploy_archive(
+        &persistence,
+        &deployment_manager,
+        &service_name,
+        data,
+        &params,
+        claim,
+    )
+    .await
+    .map(Json)
+}
+
+/// Start an empty upload for an archive of `length` bytes
+async fn create(storage_manager: &StorageManager, length: u64) -> Result<Uuid> {
+    let id = Uuid::new_v4();
+    fs::write(storage_manager.upload_length_path(&id)?, length.to_string()).await?;
+    fs::write(storage_manager.upload_path(&id)?, b"").await?;
+
+    Ok(id)
+}
+
+/// Append the chunk in `stream` to an upload, which has to have reached `offset`. Returns the offset reached after it.
+async fn append<S, E>(
+    storage_manager: &StorageManager,
+    upload_id: &Uuid,
+    offset: u64,
+    mut stream: S,
+) -> Result<u64>
+where
+    S: Stream<Item = std::result::Result<Bytes, E>> + Unpin,
+    Error: From<E>,
+{
+    let received = received(storage_manager, upload_id).await?;
+    let length = length(storage_manager, upload_id).await?;
+
+    if offset != received {
+        return Err(Error::UploadOffset { received });
+    }
+
+    let path = storage_manager.upload_path(upload_id)?;
+    let mut file = OpenOptions::new().append(true).open(path).await?;
+    let mut offset = received;
+
+    // Whatever made it to disk before the connection dropped counts, so the client can resume right after it
+    while let Some(buf) = stream.next().await {
+        let buf = match buf {
+            Ok(buf) => buf,
+            Err(error) => {
+                file.flush().await?;
+                return Err(error.into());
+            }
+        };
+
+        if offset + buf.len() as u64 > length {
+            file.flush().await?;
+            return Err(Error::BadRequest(format!(
+                "chunk goes past the length of the upload, which is {length} bytes"
+            )));
+        }
+
+        file.write_all(&buf).await?;
+        offset += buf.len() as u64;
+    }
+    file.flush().await?;
+
+    Ok(offset)
+}
+
+/// Take the archive of an upload which has received all of it, removing the upload
+async fn take_complete(storage_manager: &StorageManager, upload_id: &Uuid) -> Result<Vec<u8>> {
+    let received = received(storage_manager, upload_id).await?;
+    let length = length(storage_manager, upload_id).await?;
+
+    if received != length {
+        return Err(Error::BadRequest(format!(
+            "upload is incomplete, it has received {received} of {length} bytes"
+        )));
+    }
+
+    let path = storage_manager.upload_path(upload_id)?;
+    let data = fs::read(&path).await?;
+    fs::remove_file(&path).await?;
+    fs::remove_file(storage_manager.upload_length_path(upload_id)?).await?;
+
+    Ok(data)
+}
+
+/// Number of bytes an upload has received so far
+async fn received(storage_manager: &StorageManager, upload_id: &Uuid) -> Result<u64> {
+    let path = storage_manager.upload_path(upload_id)?;
+
+    match fs::metadata(path).await {
+        Ok(metadata) => Ok(metadata.len()),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Err(Error::NotFound),
+        Err(error) => Err(error.into()),
+    }
+}
+
+/// Number of bytes an upload was started for
+async fn length(storage_manager: &StorageManager, upload_id: &Uuid) -> Result<u64> {
+    let path = storage_manager.upload_length_path(upload_id)?;
+
+    match fs::read_to_string(path).await {
+        Ok(length) => length.trim().parse().map_err(|_| Error::NotFound),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Err(Error::NotFound),
+        Err(error) => Err(error.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use futures::stream;
+    use tempfile::Builder;
+    use uuid::Uuid;
+
+    use super::{append, create, received, take_complete};
+    use crate::deployment::storage_manager::StorageManager;
+    use crate::handlers::Error;
+
+    fn chunk(bytes: &'static [u8]) -> stream::Iter<std::vec::IntoIter<io::Result<Bytes>>> {
+        stream::iter(vec![Ok(Bytes::from_static(bytes))])
+    }
+
+    fn storage_manager() -> (tempfile::TempDir, StorageManager) {
+        let dir = Builder::new().prefix("shuttle-uploads").tempdir().unwrap();
+        let storage_manager = StorageManager::new(dir.path().to_path_buf());
+
+        (dir, storage_manager)
+    }
+
+    #[tokio::test]
+    async fn offset_conflict() {
+        let (_dir, storage_manager) = storage_manager();
+        let id = create(&storage_manager, 8).await.unwrap();
+
+        assert_eq!(
+            append(&storage_manager, &id, 0, chunk(b"red-"))
+                .await
+                .unwrap(),
+            4
+        );
+
+        // Sent again, as by a client which missed the answer to the first attempt
+        assert!(matches!(
+            append(&storage_manager, &id, 0, chunk(b"red-")).await,
+            Err(Error::UploadOffset { received: 4 })
+        ));
+        assert!(matches!(
+            append(&storage_manager, &id, 6, chunk(b"ll")).await,
+            Err(Error::UploadOffset { received: 4 })
+        ));
+        assert_eq!(received(&storage_manager, &id).await.unwrap(), 4);
+    }
+
+    #[tokio::test]
+    async fn length_overrun() {
+        let (_dir, storage_manager) = storage_manager();
+        let id = create(&storage_manager, 8).await.unwrap();
+
+        append(&storage_manager, &id, 0, chunk(b"red-"))
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            append(&storage_manager, &id, 4, chunk(b"pills")).await,
+            Err(Error::BadRequest(_))
+        ));
+        assert_eq!(
+            received(&storage_manager, &id).await.unwrap(),
+            4,
+            "the chunk going over is not kept"
+        );
+    }
+
+    #[tokio::test]
+    async fn resumed_upload() {
+        let (_dir, storage_manager) = storage_manager();
+        let id = create(&storage_manager, 8).await.unwrap();
+
+        // The connection drops after part of the chunk arrived
+        let dropped = stream::iter(vec![
+            Ok(Bytes::from_static(b"red-")),
+            Err(io::Error::from(io::ErrorKind::ConnectionReset)),
+        ]);
+        assert!(append(&storage_manager, &id, 0, dropped).await.is_err());
+
+        let offset = received(&storage_manager, &id).await.unwrap();
+        assert_eq!(offset, 4);
+        assert_eq!(
+            append(&storage_manager, &id, offset, chunk(b"pill"))
+                .await
+                .unwrap(),
+            8
+        );
+
+        assert_eq!(
+            take_complete(&storage_manager, &id).await.unwrap(),
+            b"red-pill"
+        );
+        assert!(
+            matches!(received(&storage_manager, &id).await, Err(Error::NotFound)),
+            "a deployed upload is removed"
+        );
+    }
+
+    #[tokio::test]
+    async fn stale_uploads() {
+        let (_dir, storage_manager) = storage_manager();
+        let max_age = Duration::from_millis(200);
+        let id = create(&storage_manager, 8).await.unwrap();
+
+        // Started a while ago, but still receiving chunks
+        tokio::time::sleep(max_age * 2).await;
+        append(&storage_manager, &id, 0, chunk(b"red-"))
+            .await
+            .unwrap();
+        storage_manager.remove_stale_uploads(max_age).unwrap();
+        assert_eq!(received(&storage_manager, &id).await.unwrap(), 4);
+        assert_eq!(
+            append(&storage_manager, &id, 4, chunk(b"pill"))
+                .await
+                .unwrap(),
+            8
+        );
+
+        tokio::time::sleep(max_age * 2).await;
+        storage_manager.remove_stale_uploads(max_age).unwrap();
+        assert!(matches!(
+            received(&storage_manager, &id).await,
+            Err(Error::NotFound)
+        ));
+        assert!(
+            !storage_manager.upload_length_path(&id).unwrap().exists(),
+            "the length goes along with the archive"
+        );
+    }
+
+    #[tokio::test]
+    async fn deploy_incomplete_upload() {
+        let (_dir, storage_manager) = storage_manager();
+        let id = create(&storage_manager, 8).await.unwrap();
+
+        append(&storage_manager, &id, 0, chunk(b"red-"))
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            take_complete(&storage_manager, &id).await,
+            Err(Error::BadRequest(_))
+        ));
+        assert_eq!(
+            received(&storage_manager, &id).await.unwrap(),
+            4,
+            "an incomplete upload can still be carried on"
+        );
+
+        assert!(matches!(
+            take_complete(&storage_manager, &Uuid::new_v4()).await,
+            Err(Error::NotFound)
+        ));
+    }
+}
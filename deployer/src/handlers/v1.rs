@@ -0,0 +1,215 @@
+//! Version 1 of the stable deployment API, meant to be driven by tools (like GitOps controllers) rather than the CLI.
+//!
+//! All routes are nested under `/projects/:project_name/v1`, authenticate with the usual claim and respond with the
+//! same JSON models as the rest of the API. Errors always have the [shuttle_common::models::error::ApiError] shape.
+
+use std::collections::HashMap;
+
+use axum::extract::{Extension, Path, Query};
+use axum::Json;
+use bytes::BufMut;
+use futures::StreamExt;
+use shuttle_common::backends::auth::Claim;
+use shuttle_common::models::deployment;
+use tracing::{debug, instrument};
+use uuid::Uuid;
+
+use crate::deployment::DeploymentManager;
+use crate::network;
+use crate::persistence::Persistence;
+
+use super::{deployment_filter, queue_deployment, Error, Result};
+
+/// Hosts archives may be downloaded from, and how large they may be
+#[derive(Clone)]
+pub struct ArchiveSources {
+    hosts: Vec<String>,
+    max_bytes: u64,
+}
+
+impl ArchiveSources {
+    pub fn new(hosts: Vec<String>, max_bytes: u64) -> Self {
+        Self {
+            hosts: hosts.into_iter().map(|host| host.to_lowercase()).collect(),
+            max_bytes,
+        }
+    }
+
+    /// Download the archive at `archive_url`, which has to be an https URL on one of the allowed hosts. The host is
+    /// resolved once and its addresses checked before connecting, so that it cannot point the deployer at its own
+    /// network. Redirects are not followed for the same reason.
+    async fn download(&self, archive_url: &str) -> Result<Vec<u8>> {
+        let url = reqwest::Url::parse(archive_url)
+            .map_err(|_| Error::BadRequest("archive_url is not a valid URL".to_string()))?;
+
+        if url.scheme() != "https" {
+            return Err(Error::BadRequest(
+                "archive_url should be an https URL".to_string(),
+            ));
+        }
+
+        let host = url.host_str().unwrap_or_default().to_lowercase();
+
+        if !self.hosts.contains(&host) {
+            return Err(Error::BadRequest(format!(
+                "archives cannot be downloaded from '{host}'"
+            )));
+        }
+
+        let port = url.port_or_known_default().unwrap_or(443);
+        let address = network::resolve_public(&host, port)
+            .await
+            .map_err(|error| Error::BadRequest(error.to_string()))?[0];
+
+        let mut client = reqwest::Client::builder().redirect(reqwest::redirect::Policy::none());
+        if url.domain().is_some() {
+            client = client.resolve(&host, address);
+        }
+
+        let response = client
+            .build()
+            .map_err(|error| Error::Custom(error.into()))?
+            .get(url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|error| Error::BadRequest(format!("failed to download archive: {error}")))?;
+
+        if response.status().is_redirection() {
+            return Err(Error::BadRequest(
+                "archive_url redirects, which is not followed".to_string(),
+            ));
+        }
+
+        let too_large = || {
+            Error::BadRequest(format!(
+                "archive is larger than the limit of {} bytes",
+                self.max_bytes
+            ))
+        };
+
+        if response.content_length().unwrap_or_default() > self.max_bytes {
+            return Err(too_large());
+        }
+
+        let mut data = Vec::new();
+        let mut stream = response.bytes_stream();
+        while let Some(buf) = stream.next().await {
+            let buf = buf.map_err(|error| {
+                Error::BadRequest(format!("failed to download archive: {error}"))
+            })?;
+
+            if (data.len() + buf.len()) as u64 > self.max_bytes {
+                return Err(too_large());
+            }
+
+            data.put(buf);
+        }
+        debug!("Downloaded a total of {} bytes", data.len());
+
+        Ok(data)
+    }
+}
+
+/// Create a deployment from an archive the deployer downloads itself
+#[instrument(skip_all, fields(%project_name, %service_name))]
+pub async fn create_deployment(
+    Extension(persistence): Extension<Persistence>,
+    Extension(deployment_manager): Extension<DeploymentManager>,
+    Extension(archive_sources): Extension<ArchiveSources>,
+    Extension(claim): Extension<Claim>,
+    Path((project_name, service_name)): Path<(String, String)>,
+    Json(request): Json<deployment::CreateFromArchive>,
+) -> Result<Json<deployment::Response>> {
+    let data = archive_sources.download(&request.archive_url).await?;
+
+    let deployment = queue_deployment(
+        &persistence,
+        &deployment_manager,
+        &service_name,
+        data,
+        !request.no_test,
+        request.pre_stop,
+        None,
+        !request.no_locked,
+        request.run_budget,
+        request.ttl,
+        claim,
+    )
+    .await?;
+
+    Ok(Json(deployment.into()))
+}
+
+#[instrument(skip_all, fields(%project_name, %service_name))]
+pub async fn list_deployments(
+    Extension(persistence): Extension<Persistence>,
+    Path((project_name, service_name)): Path<(String, String)>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<Vec<deployment::Response>>> {
+    let filter = deployment_filter(&params)?;
+
+    if let Some(service) = persistence.get_service_by_name(&service_name).await? {
+        let deployments = persistence
+            .get_filtered_deployments(&service.id, &filter)
+            .await?
+            .into_iter()
+            .map(Into::into)
+            .collect();
+
+        Ok(Json(deployments))
+    } else {
+        Err(Error::NotFound)
+    }
+}
+
+#[instrument(skip_all, fields(%project_name, %deployment_id))]
+pub async fn get_deployment(
+    Extension(persistence): Extension<Persistence>,
+    Path((project_name, deployment_id)): Path<(String, Uuid)>,
+) -> Result<Json<deployment::Response>> {
+    if let Some(deployment) = persistence.get_deployment(&deployment_id).await? {
+        Ok(Json(deployment.into()))
+    } else {
+        Err(Error::NotFound)
+    }
+}
+
+#[instrument(skip_all, fields(%project_name, %deployment_id))]
+pub async fn stop_deployment(
+    Extension(persistence): Extension<Persistence>,
+    Extension(deployment_manager): Extension<DeploymentManager>,
+    Path((project_name, deployment_id)): Path<(String, Uuid)>,
+) -> Result<Json<deployment::Response>> {
+    if let Some(deployment) = persistence.get_deployment(&deployment_id).await? {
+        deployment_manager.kill(deployment.id).await;
+
+        Ok(Json(deployment.into()))
+    } else {
+        Err(Error::NotFound)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn refuses_unsafe_urls() {
+        let sources =
+            ArchiveSources::new(vec!["127.0.0.1".to_string(), "Example.com".to_string()], 1);
+
+        for url in [
+            "http://example.com/archive.tar.gz",
+            "file:///etc/passwd",
+            "https://other.example.com/archive.tar.gz",
+            "https://127.0.0.1/archive.tar.gz",
+            "https://169.254.169.254/latest/meta-data",
+        ] {
+            assert!(
+                matches!(sources.download(url).await, Err(Error::BadRequest(_))),
+                "{url} is refused"
+            );
+        }
+    }
+}
@@ -1,26 +1,32 @@
-use std::{convert::Infallible, net::SocketAddr};
+use std::{convert::Infallible, net::SocketAddr, time::Duration};
 
 pub use args::Args;
 pub use deployment::{
-    deploy_layer::DeployLayer, provisioner_factory::AbstractProvisionerFactory,
+    build_process::run_if_requested as run_build_process, deploy_layer::DeployLayer,
+    output::OutputCapture, provisioner_factory::AbstractProvisionerFactory,
     runtime_logger::RuntimeLoggerFactory,
 };
-use deployment::{provisioner_factory, runtime_logger, Built, DeploymentManager};
+use deployment::{provisioner_factory, runtime_logger, Built, DeploymentManager, ImageRuntime};
 use fqdn::FQDN;
 use hyper::{
     server::conn::AddrStream,
     service::{make_service_fn, service_fn},
 };
 pub use persistence::Persistence;
+pub use proxy::AccessLogSampling;
 use proxy::AddressGetter;
-use tracing::{error, info};
+use tokio::sync::oneshot;
+use tracing::{error, info, warn};
 
-use crate::deployment::gateway_client::GatewayClient;
+use crate::deployment::{
+    backend, gateway_client::GatewayClient, instance, log_forwarder, memory, reaper,
+};
 
 mod args;
 mod deployment;
 mod error;
 mod handlers;
+mod network;
 mod persistence;
 mod proxy;
 
@@ -30,20 +36,105 @@ pub async fn start(
     persistence: Persistence,
     args: Args,
 ) {
-    let deployment_manager = DeploymentManager::builder()
+    let mut deployment_manager = DeploymentManager::builder()
         .abstract_factory(abstract_factory)
         .runtime_logger_factory(runtime_logger_factory)
         .build_log_recorder(persistence.clone())
         .secret_recorder(persistence.clone())
+        .build_recorder(persistence.clone())
         .active_deployment_getter(persistence.clone())
         .artifacts_path(args.artifacts_path)
-        .queue_client(GatewayClient::new(args.gateway_uri))
-        .build();
+        .queue_client(GatewayClient::new(
+            args.gateway_uri.clone(),
+            args.admin_secret.clone(),
+        ))
+        .dry_load(args.dry_load)
+        .isolate_builds(args.isolate_builds)
+        .kill_buffer_size(args.kill_buffer_size)
+        .profiling(args.allow_profiling);
+
+    match std::env::current_exe() {
+        Ok(executable) => deployment_manager = deployment_manager.build_process(executable),
+        Err(error) => warn!(
+            error = &error as &dyn std::error::Error,
+            "failed to find the deployer executable, builds will run in this process"
+        ),
+    }
+
+    if let Some(megabytes) = args.warm_cache_size {
+        deployment_manager = deployment_manager.warm_cache(megabytes * 1024 * 1024);
+    }
+
+    if let Some(drain) = args.drain_replaced_secs {
+        deployment_manager = deployment_manager.drain_replaced(Duration::from_secs(drain));
+    }
+
+    if let Some(cli) = args.image_runtime {
+        deployment_manager =
+            deployment_manager.image_runtime(ImageRuntime::new(cli, args.image_network));
+    }
+
+    let deployment_manager = deployment_manager.build();
 
     persistence.cleanup_invalid_states().await.unwrap();
 
+    let cipher = log_forwarder::Cipher::new(
+        args.encryption_key
+            .as_deref()
+            .unwrap_or(args.admin_secret.as_str()),
+    );
+    let (stop_log_forwarder, log_forwarder_stopped) = oneshot::channel();
+    let log_forwarder = tokio::spawn(log_forwarder::task(
+        persistence.get_log_subscriber(),
+        persistence.clone(),
+        cipher.clone(),
+        log_forwarder_stopped,
+    ));
+    tokio::spawn(backend::task(
+        persistence.get_log_subscriber(),
+        GatewayClient::new(args.gateway_uri.clone(), args.admin_secret.clone()),
+        args.project.clone(),
+    ));
+    tokio::spawn(memory::task(persistence.clone()));
+    tokio::spawn(reaper::task(
+        persistence.clone(),
+        deployment_manager.clone(),
+    ));
+
+    let (stop_instance, instance_stopped) = oneshot::channel();
+    let instance = args.instance_address.map(|address| {
+        tokio::spawn(instance::task(
+            GatewayClient::new(args.gateway_uri, args.admin_secret.clone()),
+            args.project.clone(),
+            shuttle_common::models::instance::Registration {
+                address,
+                weight: args.instance_weight,
+            },
+            persistence.clone(),
+            instance_stopped,
+        ))
+    });
+
     let runnable_deployments = persistence.get_all_runnable_deployments().await.unwrap();
     info!(count = %runnable_deployments.len(), "enqueuing runnable deployments");
+
+    // The artifacts are all read ahead at once, while the deployments are loaded one after the other
+    let storage_manager = deployment_manager.storage_manager();
+    futures::future::join_all(
+        runnable_deployments
+            .iter()
+            .filter(|deployment| deployment.image.is_none())
+            .map(|deployment| async {
+                if let Err(error) = storage_manager.warm_library(&deployment.id).await {
+                    warn!(
+                        error = &error as &dyn std::error::Error,
+                        id = %deployment.id,
+                        "failed to warm the artifact of a deployment"
+                    );
+                }
+            }),
+    )
+    .await;
     for existing_deployment in runnable_deployments {
         let built = Built {
             id: existing_deployment.id,
@@ -51,13 +142,22 @@ pub async fn start(
             service_id: existing_deployment.service_id,
             tracing_context: Default::default(),
             claim: None, // This will cause us to read the resource info from past provisions
+            pre_stop_hook: existing_deployment.pre_stop_hook,
+            image: existing_deployment.image,
+            run_budget: existing_deployment
+                .run_budget
+                .map(|secs| Duration::from_secs(secs.into())),
+            expires_at: existing_deployment.expires_at,
         };
         deployment_manager.run_push(built).await;
     }
 
     let router = handlers::make_router(
         persistence,
-        deployment_manager,
+        deployment_manager.clone(),
+        cipher,
+        handlers::ArchiveSources::new(args.archive_hosts, args.archive_size_limit * 1024 * 1024),
+        handlers::Uploads::new(args.archive_size_limit * 1024 * 1024),
         args.proxy_fqdn,
         args.admin_secret,
         args.auth_uri,
@@ -68,16 +168,67 @@ pub async fn start(
 
     info!(address=%args.api_address, "Binding to and listening at address");
 
-    axum::Server::bind(&args.api_address)
-        .serve(make_service)
+    tokio::select! {
+        result = axum::Server::bind(&args.api_address).serve(make_service) => {
+            result.unwrap_or_else(|_| panic!("Failed to bind to address: {}", args.api_address));
+        }
+        _ = terminate_signal() => {
+            info!("received signal to terminate, shutting down");
+        }
+    }
+
+    let shutdown_timeout = Duration::from_secs(args.shutdown_timeout);
+
+    // Stop getting requests before the deployments which would serve them go away
+    let _ = stop_instance.send(());
+    if let Some(instance) = instance {
+        if tokio::time::timeout(shutdown_timeout, instance)
+            .await
+            .is_err()
+        {
+            warn!("instance was not deregistered in time");
+        }
+    }
+    deployment_manager
+        .shutdown(args.shutdown_policy, shutdown_timeout)
+        .await;
+
+    // Send off whatever logs the log forwarder still has batched up
+    let _ = stop_log_forwarder.send(());
+    if tokio::time::timeout(shutdown_timeout, log_forwarder)
         .await
-        .unwrap_or_else(|_| panic!("Failed to bind to address: {}", args.api_address));
+        .is_err()
+    {
+        warn!("log forwarder did not flush its logs in time");
+    }
+}
+
+/// Wait for the deployer to be told to terminate, which an orchestrator does with `SIGTERM`
+async fn terminate_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate =
+            signal(SignalKind::terminate()).expect("to install the SIGTERM handler");
+
+        tokio::select! {
+            _ = terminate.recv() => {}
+            _ = tokio::signal::ctrl_c() => {}
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
 }
 
 pub async fn start_proxy(
     proxy_address: SocketAddr,
     fqdn: FQDN,
     address_getter: impl AddressGetter,
+    access_log: AccessLogSampling,
 ) {
     let make_service = make_service_fn(move |socket: &AddrStream| {
         let remote_address = socket.remote_addr();
@@ -86,7 +237,13 @@ pub async fn start_proxy(
 
         async move {
             Ok::<_, Infallible>(service_fn(move |req| {
-                proxy::handle(remote_address, fqdn.clone(), req, address_getter.clone())
+                proxy::handle(
+                    remote_address,
+                    fqdn.clone(),
+                    req,
+                    address_getter.clone(),
+                    access_log,
+                )
             }))
         }
     });
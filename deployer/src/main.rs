@@ -1,41 +1,82 @@
 use clap::Parser;
-use shuttle_common::backends::tracing::setup_tracing;
+use std::sync::Mutex;
+
+use shuttle_common::backends::tracing::setup_tracing_with_writer;
 use shuttle_deployer::{
-    start, start_proxy, AbstractProvisionerFactory, Args, DeployLayer, Persistence,
-    RuntimeLoggerFactory,
+    run_build_process, start, start_proxy, AbstractProvisionerFactory, AccessLogSampling, Args,
+    DeployLayer, OutputCapture, Persistence, RuntimeLoggerFactory,
 };
-use tokio::select;
+use tokio::{runtime, select};
 use tonic::transport::Endpoint;
-use tracing::trace;
+use tracing::{trace, warn};
 use tracing_subscriber::prelude::*;
 
-// The `multi_thread` is needed to prevent a deadlock in shuttle_service::loader::build_crate() which spawns two threads
-// Without this, both threads just don't start up
-#[tokio::main(flavor = "multi_thread")]
-async fn main() {
+fn main() {
+    // Builds are run in processes of this executable
+    if let Some(code) = run_build_process() {
+        std::process::exit(code);
+    }
+
     let args = Args::parse();
 
+    // The `multi_thread` runtime is needed to prevent a deadlock in shuttle_service::loader::build_crate() which spawns
+    // two threads. Without this, both threads just don't start up
+    let mut runtime = runtime::Builder::new_multi_thread();
+    runtime.enable_all();
+
+    if let Some(worker_threads) = args.worker_threads.filter(|threads| *threads > 0) {
+        runtime.worker_threads(worker_threads);
+    }
+
+    runtime
+        .build()
+        .expect("failed to build the async runtime")
+        .block_on(run(args))
+}
+
+async fn run(args: Args) {
     trace!(args = ?args, "parsed args");
 
+    // Services loaded into the deployer print to its stdout and stderr, so those are captured for their logs while the
+    // deployer prints its own to the stdout from before
+    let (output_capture, stdout) =
+        OutputCapture::start().expect("failed to capture stdout and stderr");
+
     let (persistence, _) = Persistence::new(&args.state).await;
-    setup_tracing(
+    setup_tracing_with_writer(
         tracing_subscriber::registry().with(DeployLayer::new(persistence.clone())),
         "deployer",
+        Mutex::new(stdout),
     );
 
+    if args.worker_threads == Some(0) {
+        warn!("--worker-threads cannot be 0, running one worker thread for every CPU core instead");
+    }
+
     let provisioner_uri = Endpoint::try_from(format!(
         "http://{}:{}",
         args.provisioner_address, args.provisioner_port
     ))
     .expect("provisioner uri is not valid");
 
-    let abstract_factory =
-        AbstractProvisionerFactory::new(provisioner_uri, persistence.clone(), persistence.clone());
+    let abstract_factory = AbstractProvisionerFactory::new(
+        provisioner_uri,
+        persistence.clone(),
+        persistence.clone(),
+        args.environment_kind,
+    );
+
+    let runtime_logger_factory =
+        RuntimeLoggerFactory::new(persistence.get_log_sender(), args.log_rate_limit)
+            .output_capture(output_capture);
 
-    let runtime_logger_factory = RuntimeLoggerFactory::new(persistence.get_log_sender());
+    let access_log = AccessLogSampling {
+        one_in: args.access_log_sample,
+        keep_errors: args.access_log_keep_errors,
+    };
 
     select! {
-        _ = start_proxy(args.proxy_address, args.proxy_fqdn.clone(), persistence.clone()) => {},
+        _ = start_proxy(args.proxy_address, args.proxy_fqdn.clone(), persistence.clone(), access_log) => {},
         _ = start(abstract_factory, runtime_logger_factory, persistence, args) => {},
     }
 }
@@ -0,0 +1,111 @@
+//! Checks on the hosts the deployer connects to on behalf of users, like archive hosts and log sinks, so that they
+//! cannot point the deployer at its own network.
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("failed to resolve '{0}': {1}")]
+    Resolve(String, #[source] io::Error),
+    #[error("'{0}' does not resolve to a public address")]
+    NotPublic(String),
+}
+
+/// Resolve `host`, which can also be an IP literal, to the addresses to connect to on `port`. Fails unless every
+/// address it resolves to is public, so that a name resolving to both public and internal addresses is refused too.
+pub async fn resolve_public(host: &str, port: u16) -> Result<Vec<SocketAddr>, Error> {
+    let literal = host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>();
+    let addresses: Vec<SocketAddr> = match literal {
+        Ok(ip) => vec![SocketAddr::new(ip, port)],
+        Err(_) => tokio::net::lookup_host((host, port))
+            .await
+            .map_err(|error| Error::Resolve(host.to_string(), error))?
+            .collect(),
+    };
+
+    if addresses.is_empty() || !addresses.iter().all(|address| is_public(address.ip())) {
+        return Err(Error::NotPublic(host.to_string()));
+    }
+
+    Ok(addresses)
+}
+
+/// Whether `ip` is reachable over the internet, rather than being a loopback, private, link-local or otherwise special
+/// address
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                || first == 0
+                // Shared address space of carrier-grade NATs
+                || (first == 100 && second & 0b1100_0000 == 64))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+
+                !(ip.is_unspecified()
+                    || ip.is_loopback()
+                    || ip.is_multicast()
+                    // Unique local addresses
+                    || first & 0xfe00 == 0xfc00
+                    // Link-local addresses
+                    || first & 0xffc0 == 0xfe80)
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn public_addresses() {
+        for ip in ["1.1.1.1", "93.184.216.34", "2606:4700:4700::1111"] {
+            assert!(is_public(ip.parse().unwrap()), "{ip} is public");
+        }
+
+        for ip in [
+            "127.0.0.1",
+            "10.0.0.1",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "::ffff:169.254.169.254",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{ip} is not public");
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_internal_literals() {
+        for host in ["127.0.0.1", "169.254.169.254", "[::1]", "localhost"] {
+            assert!(resolve_public(host, 80).await.is_err(), "{host} is refused");
+        }
+
+        assert_eq!(
+            resolve_public("1.1.1.1", 443).await.unwrap(),
+            vec!["1.1.1.1:443".parse().unwrap()]
+        );
+    }
+}
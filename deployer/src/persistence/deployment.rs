@@ -47,10 +47,23 @@ impl From<Deployment> for shuttle_common::models::deployment::Response {
             service_id: deployment.service_id,
             state: deployment.state.into(),
             last_update: deployment.last_update,
+            peak_memory: None,
+            locked: None,
         }
     }
 }
 
+/// Which deployments of a service to list. An empty filter lets all of them through.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DeploymentFilter {
+    /// Only deployments in one of these states, or in any state when empty
+    pub states: Vec<State>,
+    /// Only deployments last updated at or after this time
+    pub since: Option<DateTime<Utc>>,
+    /// Only deployments last updated at or before this time
+    pub until: Option<DateTime<Utc>>,
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub struct DeploymentState {
     pub id: Uuid,
@@ -64,4 +77,8 @@ pub struct DeploymentRunnable {
     pub id: Uuid,
     pub service_name: String,
     pub service_id: Uuid,
+    pub pre_stop_hook: Option<String>,
+    pub image: Option<String>,
+    pub run_budget: Option<u32>,
+    pub expires_at: Option<DateTime<Utc>>,
 }
@@ -1,6 +1,6 @@
 use chrono::{DateTime, Utc};
 use serde_json::{json, Value};
-use shuttle_common::STATE_MESSAGE;
+use shuttle_common::{log::BUILD_PROGRESS_FIELD, STATE_MESSAGE};
 use uuid::Uuid;
 
 use super::State;
@@ -33,6 +33,8 @@ impl From<Log> for Option<shuttle_common::LogItem> {
                 if str_value == STATE_MESSAGE {
                     return Some(log.into());
                 }
+            } else if log.fields.get(BUILD_PROGRESS_FIELD).is_some() {
+                return Some(log.into());
             } else {
                 let msg = extract_message(&log.fields)?;
 
@@ -94,7 +96,7 @@ impl From<shuttle_common::log::Level> for Level {
     }
 }
 
-fn extract_message(fields: &Value) -> Option<String> {
+pub(super) fn extract_message(fields: &Value) -> Option<String> {
     if let Value::Object(ref map) = fields {
         if let Some(message) = map.get("build_line") {
             return Some(message.as_str()?.to_string());
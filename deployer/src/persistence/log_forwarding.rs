@@ -0,0 +1,64 @@
+use chrono::{DateTime, Utc};
+use strum::{Display, EnumString};
+use uuid::Uuid;
+
+#[async_trait::async_trait]
+/// Get the log forwarding configuration of the service a deployment belongs to
+pub trait LogForwardingGetter: Clone + Send + Sync + 'static {
+    type Err: std::error::Error + Send + Sync;
+
+    async fn get_log_forwarding_for_deployment(
+        &self,
+        deployment_id: &Uuid,
+    ) -> Result<Option<LogForwarding>, Self::Err>;
+}
+
+#[derive(sqlx::FromRow, Clone, Debug, Eq, PartialEq)]
+pub struct LogForwarding {
+    pub service_id: Uuid,
+    pub sink: SinkType,
+    pub endpoint: String,
+
+    /// Encrypted authorization value
+    pub auth: Option<String>,
+    pub last_update: DateTime<Utc>,
+}
+
+/// Kinds of sinks logs can be forwarded to
+#[derive(sqlx::Type, Debug, Display, Clone, Copy, EnumString, PartialEq, Eq)]
+pub enum SinkType {
+    Http,
+    Syslog,
+    ObjectStore,
+}
+
+impl From<LogForwarding> for shuttle_common::models::log_forwarding::Response {
+    fn from(log_forwarding: LogForwarding) -> Self {
+        Self {
+            sink: log_forwarding.sink.into(),
+            endpoint: log_forwarding.endpoint,
+            has_auth: log_forwarding.auth.is_some(),
+            last_update: log_forwarding.last_update,
+        }
+    }
+}
+
+impl From<SinkType> for shuttle_common::models::log_forwarding::SinkType {
+    fn from(sink: SinkType) -> Self {
+        match sink {
+            SinkType::Http => Self::Http,
+            SinkType::Syslog => Self::Syslog,
+            SinkType::ObjectStore => Self::ObjectStore,
+        }
+    }
+}
+
+impl From<shuttle_common::models::log_forwarding::SinkType> for SinkType {
+    fn from(sink: shuttle_common::models::log_forwarding::SinkType) -> Self {
+        match sink {
+            shuttle_common::models::log_forwarding::SinkType::Http => Self::Http,
+            shuttle_common::models::log_forwarding::SinkType::Syslog => Self::Syslog,
+            shuttle_common::models::log_forwarding::SinkType::ObjectStore => Self::ObjectStore,
+        }
+    }
+}
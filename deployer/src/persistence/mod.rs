@@ -1,6 +1,7 @@
 mod deployment;
 mod error;
 mod log;
+mod log_forwarding;
 mod resource;
 mod secret;
 mod service;
@@ -8,28 +9,34 @@ mod state;
 mod user;
 
 use crate::deployment::deploy_layer::{self, LogRecorder, LogType};
-use crate::deployment::ActiveDeploymentsGetter;
+use crate::deployment::instance::RunningDeploymentGetter;
+use crate::deployment::memory::PeakMemoryRecorder;
+use crate::deployment::reaper::ExpiredDeploymentsGetter;
+use crate::deployment::{ActiveDeploymentsGetter, BuildRecorder};
 use crate::proxy::AddressGetter;
 use error::{Error, Result};
 
+use std::collections::BTreeMap;
 use std::net::SocketAddr;
 use std::path::Path;
 use std::str::FromStr;
 
-use chrono::Utc;
+use chrono::{DateTime, TimeZone, Utc};
 use serde_json::json;
+use shuttle_common::models::secret::{SyncDiff, SyncRequest};
 use shuttle_common::STATE_MESSAGE;
 use sqlx::migrate::{MigrateDatabase, Migrator};
 use sqlx::sqlite::{Sqlite, SqliteConnectOptions, SqliteJournalMode, SqlitePool};
+use sqlx::QueryBuilder;
 use tokio::sync::broadcast::{self, Receiver, Sender};
 use tokio::task::JoinHandle;
 use tracing::{error, info, instrument, trace};
 use uuid::Uuid;
 
-use self::deployment::DeploymentRunnable;
-pub use self::deployment::{Deployment, DeploymentState};
+pub use self::deployment::{Deployment, DeploymentFilter, DeploymentRunnable, DeploymentState};
 pub use self::error::Error as PersistenceError;
 pub use self::log::{Level as LogLevel, Log};
+pub use self::log_forwarding::{LogForwarding, LogForwardingGetter, SinkType};
 pub use self::resource::{Resource, ResourceManager, Type as ResourceType};
 use self::secret::Secret;
 pub use self::secret::{SecretGetter, SecretRecorder};
@@ -37,6 +44,8 @@ pub use self::service::Service;
 pub use self::state::State;
 pub use self::user::User;
 
+const STREAM_LOG_BUFFER_SIZE: usize = 256;
+
 pub static MIGRATIONS: Migrator = sqlx::migrate!("./migrations");
 
 #[derive(Clone)]
@@ -79,7 +88,7 @@ impl Persistence {
     }
 
     #[allow(dead_code)]
-    async fn new_in_memory() -> (Self, JoinHandle<()>) {
+    pub(crate) async fn new_in_memory() -> (Self, JoinHandle<()>) {
         let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
         Self::from_pool(pool).await
     }
@@ -90,7 +99,8 @@ impl Persistence {
         let (log_send, log_recv): (crossbeam_channel::Sender<deploy_layer::Log>, _) =
             crossbeam_channel::bounded(0);
 
-        let (stream_log_send, _) = broadcast::channel(1);
+        // Sized to let the log forwarder, which is always subscribed, absorb bursts of logs
+        let (stream_log_send, _) = broadcast::channel(STREAM_LOG_BUFFER_SIZE);
         let stream_log_send_clone = stream_log_send.clone();
 
         let pool_cloned = pool.clone();
@@ -185,6 +195,149 @@ impl Persistence {
         .map_err(Error::from)
     }
 
+    pub async fn set_pre_stop_hook(&self, id: &Uuid, pre_stop_hook: &str) -> Result<()> {
+        sqlx::query("UPDATE deployments SET pre_stop_hook = ? WHERE id = ?")
+            .bind(pre_stop_hook)
+            .bind(id)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(Error::from)
+    }
+
+    /// Record the container image a deployment runs instead of a library built from source
+    pub async fn set_image(&self, id: &Uuid, image: &str) -> Result<()> {
+        sqlx::query("UPDATE deployments SET image = ? WHERE id = ?")
+            .bind(image)
+            .bind(id)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(Error::from)
+    }
+
+    /// Container image a deployment runs, if it was not built from source
+    pub async fn get_image(&self, id: &Uuid) -> Result<Option<String>> {
+        let image =
+            sqlx::query_scalar::<_, Option<String>>("SELECT image FROM deployments WHERE id = ?")
+                .bind(id)
+                .fetch_optional(&self.pool)
+                .await?
+                .flatten();
+
+        Ok(image)
+    }
+
+    /// Record the seconds a deployment may run for before it is stopped
+    pub async fn set_run_budget(&self, id: &Uuid, run_budget: u32) -> Result<()> {
+        sqlx::query("UPDATE deployments SET run_budget = ? WHERE id = ?")
+            .bind(run_budget)
+            .bind(id)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(Error::from)
+    }
+
+    /// Record whether a deployment is built with `--locked`
+    pub async fn set_locked(&self, id: &Uuid, locked: bool) -> Result<()> {
+        sqlx::query("UPDATE deployments SET locked = ? WHERE id = ?")
+            .bind(locked)
+            .bind(id)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(Error::from)
+    }
+
+    /// Whether a deployment is built with `--locked`, when that was recorded
+    pub async fn get_locked(&self, id: &Uuid) -> Result<Option<bool>> {
+        sqlx::query_scalar::<_, Option<bool>>("SELECT locked FROM deployments WHERE id = ?")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await
+            .map(Option::flatten)
+            .map_err(Error::from)
+    }
+
+    /// Record when a deployment expires
+    pub async fn set_expires_at(&self, id: &Uuid, expires_at: DateTime<Utc>) -> Result<()> {
+        sqlx::query("UPDATE deployments SET expires_at = ? WHERE id = ?")
+            .bind(expires_at)
+            .bind(id)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(Error::from)
+    }
+
+    /// Bring the secrets of a service in line with `request`, returning which keys that changes. Nothing is written on a
+    /// dry run, so its diff can be shown before applying it.
+    pub async fn sync_secrets(
+        &self,
+        service_id: &Uuid,
+        request: &SyncRequest,
+        dry_run: bool,
+    ) -> Result<SyncDiff> {
+        let mut transaction = self.pool.begin().await?;
+
+        let current: BTreeMap<String, String> =
+            sqlx::query_as::<_, Secret>("SELECT * FROM secrets WHERE service_id = ?")
+                .bind(service_id)
+                .fetch_all(&mut transaction)
+                .await?
+                .into_iter()
+                .map(|secret| (secret.key, secret.value))
+                .collect();
+
+        let mut diff = SyncDiff::default();
+
+        for (key, value) in &request.secrets {
+            match current.get(key) {
+                None => diff.added.push(key.clone()),
+                Some(current) if current != value => diff.changed.push(key.clone()),
+                Some(_) => continue,
+            }
+
+            if !dry_run {
+                sqlx::query(
+                    "INSERT OR REPLACE INTO secrets (service_id, key, value, last_update) VALUES (?, ?, ?, ?)",
+                )
+                .bind(service_id)
+                .bind(key)
+                .bind(value)
+                .bind(Utc::now())
+                .execute(&mut transaction)
+                .await?;
+            }
+        }
+
+        for key in current.keys() {
+            if request.secrets.contains_key(key) {
+                continue;
+            }
+
+            if !request.prune {
+                diff.unmanaged.push(key.clone());
+                continue;
+            }
+
+            diff.removed.push(key.clone());
+
+            if !dry_run {
+                sqlx::query("DELETE FROM secrets WHERE service_id = ? AND key = ?")
+                    .bind(service_id)
+                    .bind(key)
+                    .execute(&mut transaction)
+                    .await?;
+            }
+        }
+
+        transaction.commit().await?;
+
+        Ok(diff)
+    }
+
     pub async fn get_deployment(&self, id: &Uuid) -> Result<Option<Deployment>> {
         get_deployment(&self.pool, id).await
     }
@@ -197,6 +350,41 @@ impl Persistence {
             .map_err(Error::from)
     }
 
+    /// Get the deployments of a service which match `filter`, oldest first
+    pub async fn get_filtered_deployments(
+        &self,
+        service_id: &Uuid,
+        filter: &DeploymentFilter,
+    ) -> Result<Vec<Deployment>> {
+        let mut query =
+            QueryBuilder::<Sqlite>::new("SELECT * FROM deployments WHERE service_id = ");
+        query.push_bind(*service_id);
+
+        if !filter.states.is_empty() {
+            query.push(" AND state IN (");
+            let mut states = query.separated(", ");
+            for state in &filter.states {
+                states.push_bind(*state);
+            }
+            states.push_unseparated(")");
+        }
+
+        if let Some(since) = filter.since {
+            query.push(" AND last_update >= ").push_bind(since);
+        }
+
+        if let Some(until) = filter.until {
+            query.push(" AND last_update <= ").push_bind(until);
+        }
+
+        query
+            .push(" ORDER BY last_update")
+            .build_query_as()
+            .fetch_all(&self.pool)
+            .await
+            .map_err(Error::from)
+    }
+
     pub async fn get_active_deployment(&self, service_id: &Uuid) -> Result<Option<Deployment>> {
         sqlx::query_as("SELECT * FROM deployments WHERE service_id = ? AND state = ?")
             .bind(service_id)
@@ -206,6 +394,71 @@ impl Persistence {
             .map_err(Error::from)
     }
 
+    /// The deployments which are still on their way to, or in, the running state, the longest unchanged first
+    pub async fn get_unfinished_deployments(&self) -> Result<Vec<Deployment>> {
+        sqlx::query_as(
+            "SELECT * FROM deployments WHERE state IN(?, ?, ?, ?, ?) ORDER BY last_update",
+        )
+        .bind(State::Queued)
+        .bind(State::Building)
+        .bind(State::Built)
+        .bind(State::Loading)
+        .bind(State::Running)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(Error::from)
+    }
+
+    /// Count the deployments which are still on their way to, or in, the running state
+    pub async fn count_unfinished_deployments(&self) -> Result<u32> {
+        sqlx::query_scalar("SELECT COUNT(*) FROM deployments WHERE state IN(?, ?, ?, ?, ?)")
+            .bind(State::Queued)
+            .bind(State::Building)
+            .bind(State::Built)
+            .bind(State::Loading)
+            .bind(State::Running)
+            .fetch_one(&self.pool)
+            .await
+            .map_err(Error::from)
+    }
+
+    /// Point the `tag` of a service to one of its deployments, moving it away from the deployment it pointed to before
+    pub async fn set_deployment_tag(
+        &self,
+        service_id: &Uuid,
+        tag: &str,
+        deployment_id: &Uuid,
+    ) -> Result<()> {
+        sqlx::query(
+            "INSERT OR REPLACE INTO deployment_tags (service_id, tag, deployment_id) VALUES (?, ?, ?)",
+        )
+        .bind(service_id)
+        .bind(tag)
+        .bind(deployment_id)
+        .execute(&self.pool)
+        .await
+        .map(|_| ())
+        .map_err(Error::from)
+    }
+
+    pub async fn get_tagged_deployment(
+        &self,
+        service_id: &Uuid,
+        tag: &str,
+    ) -> Result<Option<Deployment>> {
+        sqlx::query_as(
+            r#"SELECT d.*
+                FROM deployment_tags AS t
+                JOIN deployments AS d ON d.id = t.deployment_id
+                WHERE t.service_id = ? AND t.tag = ?"#,
+        )
+        .bind(service_id)
+        .bind(tag)
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(Error::from)
+    }
+
     // Clean up all invalid states inside persistence
     pub async fn cleanup_invalid_states(&self) -> Result<()> {
         sqlx::query("UPDATE deployments SET state = ? WHERE state IN(?, ?, ?, ?)")
@@ -265,7 +518,8 @@ impl Persistence {
 
     pub async fn get_all_runnable_deployments(&self) -> Result<Vec<DeploymentRunnable>> {
         sqlx::query_as(
-            r#"SELECT d.id, service_id, s.name AS service_name
+            r#"SELECT d.id, service_id, s.name AS service_name, d.pre_stop_hook, d.image,
+                d.run_budget, d.expires_at
                 FROM deployments AS d
                 JOIN services AS s ON s.id = d.service_id
                 WHERE state = ?
@@ -277,11 +531,171 @@ impl Persistence {
         .map_err(Error::from)
     }
 
+    /// Highest resident memory in bytes reached by the latest run of a deployment
+    pub async fn get_peak_memory(&self, id: &Uuid) -> Result<Option<u64>> {
+        let peak_memory = sqlx::query_scalar::<_, Option<i64>>(
+            "SELECT peak_memory FROM deployments WHERE id = ?",
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?
+        .flatten();
+
+        Ok(peak_memory.map(|bytes| bytes as u64))
+    }
+
+    /// The states a deployment went through, oldest first, with when it entered each of them
+    pub async fn get_state_history(&self, id: &Uuid) -> Result<Vec<(State, DateTime<Utc>)>> {
+        sqlx::query_as(
+            "SELECT state, timestamp FROM logs WHERE id = ? AND fields = ? ORDER BY timestamp",
+        )
+        .bind(id)
+        .bind(json!(STATE_MESSAGE))
+        .fetch_all(&self.pool)
+        .await
+        .map_err(Error::from)
+    }
+
+    /// Message of the latest error a deployment logged
+    pub async fn get_last_error(&self, id: &Uuid) -> Result<Option<String>> {
+        let fields = self.get_last_error_fields(id).await?;
+
+        Ok(fields.as_ref().and_then(log::extract_message))
+    }
+
+    /// Who was to blame for the last error of a deployment, when it was one the deployer tagged with its fault
+    pub async fn get_last_error_fault(&self, id: &Uuid) -> Result<Option<String>> {
+        let fields = self.get_last_error_fields(id).await?;
+
+        Ok(fields
+            .as_ref()
+            .and_then(|fields| fields.get("fault"))
+            .and_then(|fault| fault.as_str())
+            .map(ToString::to_string))
+    }
+
+    async fn get_last_error_fields(&self, id: &Uuid) -> Result<Option<serde_json::Value>> {
+        sqlx::query_scalar::<_, serde_json::Value>(
+            "SELECT fields FROM logs WHERE id = ? AND level = ? ORDER BY timestamp DESC LIMIT 1",
+        )
+        .bind(id)
+        .bind(LogLevel::Error)
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(Error::from)
+    }
+
+    /// Remove the logs of a deployment, all but its state changes, returning how many lines were removed
+    pub async fn delete_deployment_logs(&self, id: &Uuid) -> Result<u64> {
+        sqlx::query("DELETE FROM logs WHERE id = ? AND fields != ?")
+            .bind(id)
+            .bind(json!(STATE_MESSAGE))
+            .execute(&self.pool)
+            .await
+            .map(|result| result.rows_affected())
+            .map_err(Error::from)
+    }
+
+    /// Record that the artifact and logs of a deployment were removed, while its record stays for the history
+    pub async fn set_artifact_deleted(&self, id: &Uuid) -> Result<()> {
+        sqlx::query("UPDATE deployments SET artifact_deleted_at = ? WHERE id = ?")
+            .bind(Utc::now())
+            .bind(id)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(Error::from)
+    }
+
+    /// When the artifact and logs of a deployment were removed, if they were
+    pub async fn get_artifact_deleted(&self, id: &Uuid) -> Result<Option<DateTime<Utc>>> {
+        let deleted_at = sqlx::query_scalar::<_, Option<DateTime<Utc>>>(
+            "SELECT artifact_deleted_at FROM deployments WHERE id = ?",
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?
+        .flatten();
+
+        Ok(deleted_at)
+    }
+
     pub(crate) async fn get_deployment_logs(&self, id: &Uuid) -> Result<Vec<Log>> {
         // TODO: stress this a bit
         get_deployment_logs(&self.pool, id).await
     }
 
+    /// Get only the last `count` logs of a deployment, oldest first
+    pub(crate) async fn get_last_deployment_logs(&self, id: &Uuid, count: u32) -> Result<Vec<Log>> {
+        sqlx::query_as(
+            "SELECT * FROM (SELECT * FROM logs WHERE id = ? ORDER BY timestamp DESC LIMIT ?) ORDER BY timestamp",
+        )
+        .bind(id)
+        .bind(count)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(Error::from)
+    }
+
+    /// Get the logs of a deployment after `after`, oldest first, up to `limit` of them. Exports page through the logs of
+    /// a deployment with it, giving the time of the last line they got as `after`.
+    pub(crate) async fn get_deployment_logs_after(
+        &self,
+        id: &Uuid,
+        after: Option<DateTime<Utc>>,
+        limit: Option<u32>,
+    ) -> Result<Vec<Log>> {
+        // SQLite takes a negative limit as no limit at all
+        sqlx::query_as(
+            "SELECT * FROM logs WHERE id = ? AND timestamp > ? ORDER BY timestamp LIMIT ?",
+        )
+        .bind(id)
+        .bind(after.unwrap_or_else(|| Utc.timestamp_opt(0, 0).unwrap()))
+        .bind(limit.map_or(-1, i64::from))
+        .fetch_all(&self.pool)
+        .await
+        .map_err(Error::from)
+    }
+
+    /// Set the log forwarding configuration of a service. The `auth` value is expected to already be encrypted.
+    pub async fn set_log_forwarding(
+        &self,
+        service_id: &Uuid,
+        sink: SinkType,
+        endpoint: &str,
+        auth: Option<String>,
+    ) -> Result<()> {
+        sqlx::query(
+            "INSERT OR REPLACE INTO log_forwarding (service_id, sink, endpoint, auth, last_update) VALUES (?, ?, ?, ?, ?)",
+        )
+        .bind(service_id)
+        .bind(sink)
+        .bind(endpoint)
+        .bind(auth)
+        .bind(Utc::now())
+        .execute(&self.pool)
+        .await
+        .map(|_| ())
+        .map_err(Error::from)
+    }
+
+    pub async fn get_log_forwarding(&self, service_id: &Uuid) -> Result<Option<LogForwarding>> {
+        sqlx::query_as("SELECT * FROM log_forwarding WHERE service_id = ?")
+            .bind(service_id)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(Error::from)
+    }
+
+    pub async fn delete_log_forwarding(&self, service_id: &Uuid) -> Result<()> {
+        sqlx::query("DELETE FROM log_forwarding WHERE service_id = ?")
+            .bind(service_id)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(Error::from)
+    }
+
     pub fn get_log_subscriber(&self) -> Receiver<deploy_layer::Log> {
         self.stream_log_send.subscribe()
     }
@@ -354,11 +768,14 @@ impl ResourceManager for Persistence {
     type Err = Error;
 
     async fn insert_resource(&self, resource: &Resource) -> Result<()> {
-        sqlx::query("INSERT OR REPLACE INTO resources (service_id, type, data) VALUES (?, ?, ?)")
-            .bind(resource.service_id)
-            .bind(resource.r#type)
-            .bind(&resource.data)
-            .execute(&self.pool)
+        sqlx::query(
+            "INSERT OR REPLACE INTO resources (service_id, type, data, metadata) VALUES (?, ?, ?, ?)",
+        )
+        .bind(resource.service_id)
+        .bind(resource.r#type)
+        .bind(&resource.data)
+        .bind(&resource.metadata)
+        .execute(&self.pool)
             .await
             .map(|_| ())
             .map_err(Error::from)
@@ -390,6 +807,21 @@ impl SecretRecorder for Persistence {
         .map(|_| ())
         .map_err(Error::from)
     }
+
+    async fn replace_secrets(
+        &self,
+        service_id: &Uuid,
+        secrets: &BTreeMap<String, String>,
+    ) -> Result<()> {
+        let request = SyncRequest {
+            secrets: secrets.clone(),
+            prune: true,
+        };
+
+        self.sync_secrets(service_id, &request, false)
+            .await
+            .map(|_| ())
+    }
 }
 
 #[async_trait::async_trait]
@@ -405,6 +837,27 @@ impl SecretGetter for Persistence {
     }
 }
 
+#[async_trait::async_trait]
+impl LogForwardingGetter for Persistence {
+    type Err = Error;
+
+    async fn get_log_forwarding_for_deployment(
+        &self,
+        deployment_id: &Uuid,
+    ) -> Result<Option<LogForwarding>> {
+        sqlx::query_as(
+            r#"SELECT l.*
+                FROM log_forwarding AS l
+                JOIN deployments AS d ON d.service_id = l.service_id
+                WHERE d.id = ?"#,
+        )
+        .bind(deployment_id)
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(Error::from)
+    }
+}
+
 #[async_trait::async_trait]
 impl AddressGetter for Persistence {
     #[instrument(skip(self))]
@@ -417,7 +870,7 @@ impl AddressGetter for Persistence {
                 FROM deployments AS d
                 JOIN services AS s ON d.service_id = s.id
                 WHERE s.name = ? AND d.state = ?
-                ORDER BY d.last_update"#,
+                ORDER BY d.last_update DESC"#,
         )
         .bind(service_name)
         .bind(State::Running)
@@ -440,6 +893,63 @@ impl AddressGetter for Persistence {
     }
 }
 
+#[async_trait::async_trait]
+impl BuildRecorder for Persistence {
+    type Err = Error;
+
+    async fn record_locked(&self, id: &Uuid, locked: bool) -> Result<()> {
+        self.set_locked(id, locked).await
+    }
+}
+
+#[async_trait::async_trait]
+impl PeakMemoryRecorder for Persistence {
+    type Err = Error;
+
+    async fn record_peak_memory(&self, bytes: u64) -> Result<()> {
+        sqlx::query("UPDATE deployments SET peak_memory = ? WHERE state = ?")
+            .bind(bytes as i64)
+            .bind(State::Running)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(Error::from)
+    }
+}
+
+#[async_trait::async_trait]
+impl RunningDeploymentGetter for Persistence {
+    type Err = Error;
+
+    async fn has_running_deployment(&self) -> Result<bool> {
+        sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM deployments WHERE state = ?)")
+            .bind(State::Running)
+            .fetch_one(&self.pool)
+            .await
+            .map_err(Error::from)
+    }
+}
+
+#[async_trait::async_trait]
+impl ExpiredDeploymentsGetter for Persistence {
+    type Err = Error;
+
+    async fn get_expired_deployments(&self, now: DateTime<Utc>) -> Result<Vec<Uuid>> {
+        sqlx::query_scalar(
+            "SELECT id FROM deployments WHERE expires_at <= ? AND state IN (?, ?, ?, ?, ?)",
+        )
+        .bind(now)
+        .bind(State::Queued)
+        .bind(State::Building)
+        .bind(State::Built)
+        .bind(State::Loading)
+        .bind(State::Running)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(Error::from)
+    }
+}
+
 #[async_trait::async_trait]
 impl ActiveDeploymentsGetter for Persistence {
     type Err = Error;
@@ -449,7 +959,7 @@ impl ActiveDeploymentsGetter for Persistence {
         service_id: &Uuid,
     ) -> std::result::Result<Vec<Uuid>, Self::Err> {
         let ids: Vec<_> = sqlx::query_as::<_, Deployment>(
-            "SELECT * FROM deployments WHERE service_id = ? AND state = ?",
+            "SELECT * FROM deployments WHERE service_id = ? AND state = ? ORDER BY last_update",
         )
         .bind(service_id)
         .bind(State::Running)
@@ -466,11 +976,13 @@ impl ActiveDeploymentsGetter for Persistence {
 
 #[cfg(test)]
 mod tests {
+    use std::collections::BTreeMap;
     use std::net::{Ipv4Addr, SocketAddr};
 
     use chrono::{TimeZone, Utc};
     use rand::Rng;
     use serde_json::json;
+    use sqlx::types::Json;
 
     use super::*;
     use crate::persistence::{
@@ -650,24 +1162,96 @@ mod tests {
             .get_deployments(&service_id)
             .await
             .unwrap()
-            .into_iter()
-            .map(|deployment| (deployment.id, deployment.state))
-            .collect();
-        let expected = vec![
-            (deployment_crashed.id, State::Crashed),
-            (deployment_stopped.id, State::Stopped),
-            (deployment_running.id, State::Running),
-            (queued_id, State::Stopped),
-            (built_id, State::Stopped),
-            (building_id, State::Stopped),
-            (loading_id, State::Stopped),
-        ];
-
+            .into_iter()
+            .map(|deployment| (deployment.id, deployment.state))
+            .collect();
+        let expected = vec![
+            (deployment_crashed.id, State::Crashed),
+            (deployment_stopped.id, State::Stopped),
+            (deployment_running.id, State::Running),
+            (queued_id, State::Stopped),
+            (built_id, State::Stopped),
+            (building_id, State::Stopped),
+            (loading_id, State::Stopped),
+        ];
+
+        assert_eq!(
+            actual, expected,
+            "invalid states should be moved to the stopped state"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn deployment_tags() {
+        let (p, _) = Persistence::new_in_memory().await;
+
+        let service_id = add_service(&p.pool).await.unwrap();
+        let [first, second] = [Uuid::new_v4(), Uuid::new_v4()];
+
+        for id in [first, second] {
+            p.insert_deployment(Deployment {
+                id,
+                service_id,
+                state: State::Running,
+                last_update: Utc::now(),
+                address: None,
+            })
+            .await
+            .unwrap();
+        }
+
+        assert!(p
+            .get_tagged_deployment(&service_id, "latest")
+            .await
+            .unwrap()
+            .is_none());
+
+        p.set_deployment_tag(&service_id, "latest", &first)
+            .await
+            .unwrap();
+        p.set_deployment_tag(&service_id, "latest", &second)
+            .await
+            .unwrap();
+
+        let tagged = p
+            .get_tagged_deployment(&service_id, "latest")
+            .await
+            .unwrap()
+            .unwrap();
         assert_eq!(
-            actual, expected,
-            "invalid states should be moved to the stopped state"
+            tagged.id, second,
+            "the tag should move to the newest deployment"
         );
     }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn count_unfinished_deployments() {
+        let (p, _) = Persistence::new_in_memory().await;
+
+        let service_id = add_service(&p.pool).await.unwrap();
+
+        for state in [
+            State::Queued,
+            State::Building,
+            State::Running,
+            State::Crashed,
+            State::Stopped,
+            State::Completed,
+        ] {
+            p.insert_deployment(Deployment {
+                id: Uuid::new_v4(),
+                service_id,
+                state,
+                last_update: Utc::now(),
+                address: None,
+            })
+            .await
+            .unwrap();
+        }
+
+        assert_eq!(p.count_unfinished_deployments().await.unwrap(), 3);
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn fetching_runnable_deployments() {
         let (p, _) = Persistence::new_in_memory().await;
@@ -720,6 +1304,11 @@ mod tests {
         ] {
             p.insert_deployment(deployment).await.unwrap();
         }
+        p.set_pre_stop_hook(&id_2, "/drain").await.unwrap();
+        p.set_image(&id_3, "ghcr.io/neo/matrix:1.0").await.unwrap();
+        p.set_run_budget(&id_3, 3600).await.unwrap();
+        let expires_at = Utc.with_ymd_and_hms(2022, 4, 26, 4, 42, 32).unwrap();
+        p.set_expires_at(&id_3, expires_at).await.unwrap();
 
         let runnable = p.get_all_runnable_deployments().await.unwrap();
         assert_eq!(
@@ -729,16 +1318,28 @@ mod tests {
                     id: id_1,
                     service_name: "foo".to_string(),
                     service_id: foo_id,
+                    pre_stop_hook: None,
+                    image: None,
+                    run_budget: None,
+                    expires_at: None,
                 },
                 DeploymentRunnable {
                     id: id_2,
                     service_name: "bar".to_string(),
                     service_id: bar_id,
+                    pre_stop_hook: Some("/drain".to_string()),
+                    image: None,
+                    run_budget: None,
+                    expires_at: None,
                 },
                 DeploymentRunnable {
                     id: id_3,
                     service_name: "foo".to_string(),
                     service_id: foo_id,
+                    pre_stop_hook: None,
+                    image: Some("ghcr.io/neo/matrix:1.0".to_string()),
+                    run_budget: Some(3600),
+                    expires_at: Some(expires_at),
                 },
             ]
         );
@@ -768,6 +1369,76 @@ mod tests {
         assert_eq!(logs.first().unwrap(), &log);
     }
 
+    #[tokio::test(flavor = "multi_thread")]
+    async fn peak_memory() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let stopped = add_deployment(&p.pool).await.unwrap();
+
+        assert_eq!(p.get_peak_memory(&stopped).await.unwrap(), None);
+
+        p.record_peak_memory(1024).await.unwrap();
+        assert_eq!(p.get_peak_memory(&stopped).await.unwrap(), Some(1024));
+
+        sqlx::query("UPDATE deployments SET state = ? WHERE id = ?")
+            .bind(State::Stopped)
+            .bind(stopped)
+            .execute(&p.pool)
+            .await
+            .unwrap();
+        let running = add_deployment(&p.pool).await.unwrap();
+
+        // Only running deployments take on new peaks
+        p.record_peak_memory(4096).await.unwrap();
+        assert_eq!(p.get_peak_memory(&stopped).await.unwrap(), Some(1024));
+        assert_eq!(p.get_peak_memory(&running).await.unwrap(), Some(4096));
+        assert_eq!(p.get_peak_memory(&Uuid::new_v4()).await.unwrap(), None);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn locked() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let locked = add_deployment(&p.pool).await.unwrap();
+        let unlocked = add_deployment(&p.pool).await.unwrap();
+        let unknown = add_deployment(&p.pool).await.unwrap();
+
+        p.set_locked(&locked, true).await.unwrap();
+        p.set_locked(&unlocked, false).await.unwrap();
+
+        assert_eq!(p.get_locked(&locked).await.unwrap(), Some(true));
+        assert_eq!(p.get_locked(&unlocked).await.unwrap(), Some(false));
+        assert_eq!(p.get_locked(&unknown).await.unwrap(), None);
+        assert_eq!(p.get_locked(&Uuid::new_v4()).await.unwrap(), None);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn expired_deployments() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let now = Utc::now();
+        let expired = add_deployment(&p.pool).await.unwrap();
+        let not_yet = add_deployment(&p.pool).await.unwrap();
+        let stopped = add_deployment(&p.pool).await.unwrap();
+        add_deployment(&p.pool).await.unwrap();
+
+        p.set_expires_at(&expired, now - chrono::Duration::minutes(1))
+            .await
+            .unwrap();
+        p.set_expires_at(&not_yet, now + chrono::Duration::minutes(1))
+            .await
+            .unwrap();
+        p.set_expires_at(&stopped, now - chrono::Duration::minutes(1))
+            .await
+            .unwrap();
+        sqlx::query("UPDATE deployments SET state = ? WHERE id = ?")
+            .bind(State::Stopped)
+            .bind(stopped)
+            .execute(&p.pool)
+            .await
+            .unwrap();
+
+        // Deployments which are done already have nothing left to stop
+        assert_eq!(p.get_expired_deployments(now).await.unwrap(), [expired]);
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn logs_for_deployment() {
         let (p, _) = Persistence::new_in_memory().await;
@@ -812,7 +1483,10 @@ mod tests {
         let logs = p.get_deployment_logs(&deployment_a).await.unwrap();
         assert!(!logs.is_empty(), "there should be two logs");
 
-        assert_eq!(logs, vec![log_a1, log_a2]);
+        assert_eq!(logs, vec![log_a1, log_a2.clone()]);
+
+        let logs = p.get_last_deployment_logs(&deployment_a, 1).await.unwrap();
+        assert_eq!(logs, vec![log_a2]);
     }
 
     #[tokio::test(flavor = "multi_thread")]
@@ -909,6 +1583,167 @@ mod tests {
         );
     }
 
+    #[tokio::test(flavor = "multi_thread")]
+    async fn state_history_and_last_error() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let id = add_deployment(&p.pool).await.unwrap();
+
+        assert!(p.get_state_history(&id).await.unwrap().is_empty());
+        assert_eq!(p.get_last_error(&id).await.unwrap(), None);
+        assert_eq!(p.get_last_error_fault(&id).await.unwrap(), None);
+
+        let log = |second, state, level, fields| Log {
+            id,
+            timestamp: Utc.with_ymd_and_hms(2023, 1, 1, 0, 0, second).unwrap(),
+            state,
+            level,
+            file: None,
+            line: None,
+            target: String::new(),
+            fields,
+        };
+
+        for log in [
+            log(0, State::Queued, Level::Info, json!(STATE_MESSAGE)),
+            log(1, State::Loading, Level::Info, json!(STATE_MESSAGE)),
+            log(
+                2,
+                State::Loading,
+                Level::Error,
+                json!({ "message": { "rendered": "no database" } }),
+            ),
+            log(
+                3,
+                State::Loading,
+                Level::Error,
+                json!({ "message": { "rendered": "failed to load" }, "fault": "platform" }),
+            ),
+            log(4, State::Crashed, Level::Info, json!(STATE_MESSAGE)),
+        ] {
+            insert_log(&p.pool, log).await.unwrap();
+        }
+
+        assert_eq!(
+            p.get_state_history(&id).await.unwrap(),
+            vec![
+                (
+                    State::Queued,
+                    Utc.with_ymd_and_hms(2023, 1, 1, 0, 0, 0).unwrap()
+                ),
+                (
+                    State::Loading,
+                    Utc.with_ymd_and_hms(2023, 1, 1, 0, 0, 1).unwrap()
+                ),
+                (
+                    State::Crashed,
+                    Utc.with_ymd_and_hms(2023, 1, 1, 0, 0, 4).unwrap()
+                ),
+            ]
+        );
+        assert_eq!(
+            p.get_last_error(&id).await.unwrap().as_deref(),
+            Some("failed to load")
+        );
+        assert_eq!(
+            p.get_last_error_fault(&id).await.unwrap().as_deref(),
+            Some("platform")
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn deployment_logs_after() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let id = add_deployment(&p.pool).await.unwrap();
+        let other = add_deployment(&p.pool).await.unwrap();
+
+        let log = |id, second, message| Log {
+            id,
+            timestamp: Utc.with_ymd_and_hms(2023, 1, 1, 0, 0, second).unwrap(),
+            state: State::Running,
+            level: Level::Info,
+            file: None,
+            line: None,
+            target: String::new(),
+            fields: json!({ "message": message }),
+        };
+
+        for log in [
+            log(id, 0, "neo"),
+            log(id, 1, "trinity"),
+            log(other, 2, "morpheus"),
+            log(id, 3, "tank"),
+        ] {
+            insert_log(&p.pool, log).await.unwrap();
+        }
+
+        let messages = |logs: Vec<Log>| {
+            logs.into_iter()
+                .map(|log| log.fields["message"].as_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        let first_page = p
+            .get_deployment_logs_after(&id, None, Some(2))
+            .await
+            .unwrap();
+        let last_seen = first_page.last().unwrap().timestamp;
+        assert_eq!(messages(first_page), vec!["neo", "trinity"]);
+
+        let next_page = p
+            .get_deployment_logs_after(&id, Some(last_seen), Some(2))
+            .await
+            .unwrap();
+        assert_eq!(messages(next_page), vec!["tank"]);
+
+        assert_eq!(
+            p.get_deployment_logs_after(&id, None, None)
+                .await
+                .unwrap()
+                .len(),
+            3
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn delete_deployment_logs() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let id = add_deployment(&p.pool).await.unwrap();
+        let other = add_deployment(&p.pool).await.unwrap();
+
+        let log = |id, second, state, fields| Log {
+            id,
+            timestamp: Utc.with_ymd_and_hms(2023, 1, 1, 0, 0, second).unwrap(),
+            state,
+            level: Level::Info,
+            file: None,
+            line: None,
+            target: String::new(),
+            fields,
+        };
+
+        for log in [
+            log(id, 0, State::Queued, json!(STATE_MESSAGE)),
+            log(id, 1, State::Running, json!(STATE_MESSAGE)),
+            log(id, 2, State::Running, json!({ "message": "neo" })),
+            log(id, 3, State::Running, json!({ "message": "trinity" })),
+            log(id, 4, State::Stopped, json!(STATE_MESSAGE)),
+            log(other, 5, State::Running, json!({ "message": "morpheus" })),
+        ] {
+            insert_log(&p.pool, log).await.unwrap();
+        }
+
+        assert_eq!(p.get_artifact_deleted(&id).await.unwrap(), None);
+
+        assert_eq!(p.delete_deployment_logs(&id).await.unwrap(), 2);
+        p.set_artifact_deleted(&id).await.unwrap();
+
+        assert_eq!(p.get_deployment_logs(&id).await.unwrap().len(), 3);
+        assert_eq!(p.get_state_history(&id).await.unwrap().len(), 3);
+        assert_eq!(p.get_deployment_logs(&other).await.unwrap().len(), 1);
+        assert!(p.get_artifact_deleted(&id).await.unwrap().is_some());
+        assert_eq!(p.get_artifact_deleted(&other).await.unwrap(), None);
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn deployment_resources() {
         let (p, _) = Persistence::new_in_memory().await;
@@ -921,6 +1756,7 @@ mod tests {
                 resource::database::SharedType::Postgres,
             )),
             data: json!({"username": "root"}),
+            metadata: Default::default(),
         };
         let resource2 = Resource {
             service_id,
@@ -928,6 +1764,10 @@ mod tests {
                 resource::database::AwsRdsType::MariaDB,
             )),
             data: json!({"uri": "postgres://localhost"}),
+            metadata: Json(BTreeMap::from([(
+                "purpose".to_string(),
+                "reports".to_string(),
+            )])),
         };
         let resource3 = Resource {
             service_id: service_id2,
@@ -935,6 +1775,7 @@ mod tests {
                 resource::database::AwsRdsType::Postgres,
             )),
             data: json!({"username": "admin"}),
+            metadata: Default::default(),
         };
         // This makes sure only the last instance of a type is saved (clashes with [resource1])
         let resource4 = Resource {
@@ -943,6 +1784,10 @@ mod tests {
                 resource::database::SharedType::Postgres,
             )),
             data: json!({"username": "foo"}),
+            metadata: Json(BTreeMap::from([(
+                "purpose".to_string(),
+                "sessions".to_string(),
+            )])),
         };
 
         for resource in [&resource1, &resource2, &resource3, &resource4] {
@@ -1003,6 +1848,161 @@ mod tests {
         assert_eq!(actual, expected);
     }
 
+    #[tokio::test(flavor = "multi_thread")]
+    async fn replace_secrets() {
+        let (p, _) = Persistence::new_in_memory().await;
+
+        let service_id = add_service(&p.pool).await.unwrap();
+
+        p.insert_secret(&service_id, "removed", "old")
+            .await
+            .unwrap();
+        p.insert_secret(&service_id, "changed", "old")
+            .await
+            .unwrap();
+
+        p.replace_secrets(
+            &service_id,
+            &BTreeMap::from([
+                ("changed".to_string(), "new".to_string()),
+                ("added".to_string(), "new".to_string()),
+            ]),
+        )
+        .await
+        .unwrap();
+
+        let secrets: Vec<_> = p
+            .get_secrets(&service_id)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|secret| (secret.key, secret.value))
+            .collect();
+
+        assert_eq!(
+            secrets,
+            [
+                ("added".to_string(), "new".to_string()),
+                ("changed".to_string(), "new".to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn sync_secrets() {
+        let (p, _) = Persistence::new_in_memory().await;
+
+        let service_id = add_service(&p.pool).await.unwrap();
+
+        for (key, value) in [("kept", "same"), ("changed", "old"), ("stale", "gone")] {
+            p.insert_secret(&service_id, key, value).await.unwrap();
+        }
+
+        let mut request = SyncRequest {
+            secrets: BTreeMap::from([
+                ("added".to_string(), "new".to_string()),
+                ("changed".to_string(), "new".to_string()),
+                ("kept".to_string(), "same".to_string()),
+            ]),
+            prune: false,
+        };
+        let keys = |secrets: Vec<Secret>| -> Vec<(String, String)> {
+            secrets
+                .into_iter()
+                .map(|secret| (secret.key, secret.value))
+                .collect()
+        };
+
+        let diff = p.sync_secrets(&service_id, &request, true).await.unwrap();
+        assert_eq!(
+            diff,
+            SyncDiff {
+                added: vec!["added".to_string()],
+                changed: vec!["changed".to_string()],
+                removed: vec![],
+                unmanaged: vec!["stale".to_string()],
+                restarted: false,
+            }
+        );
+        assert_eq!(
+            keys(p.get_secrets(&service_id).await.unwrap()),
+            [("changed", "old"), ("kept", "same"), ("stale", "gone")]
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+        );
+
+        request.prune = true;
+        let diff = p.sync_secrets(&service_id, &request, false).await.unwrap();
+        assert_eq!(diff.removed, vec!["stale".to_string()]);
+        assert!(diff.unmanaged.is_empty());
+        assert_eq!(
+            keys(p.get_secrets(&service_id).await.unwrap()),
+            [("added", "new"), ("changed", "new"), ("kept", "same")]
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+        );
+
+        assert!(p
+            .sync_secrets(&service_id, &request, true)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn log_forwarding() {
+        let (p, _) = Persistence::new_in_memory().await;
+
+        let service_id = add_service(&p.pool).await.unwrap();
+        let deployment_id = Uuid::new_v4();
+        p.insert_deployment(Deployment {
+            id: deployment_id,
+            service_id,
+            state: State::Running,
+            last_update: Utc::now(),
+            address: None,
+        })
+        .await
+        .unwrap();
+
+        assert!(p
+            .get_log_forwarding_for_deployment(&deployment_id)
+            .await
+            .unwrap()
+            .is_none());
+
+        p.set_log_forwarding(&service_id, SinkType::Http, "http://old", None)
+            .await
+            .unwrap();
+        p.set_log_forwarding(
+            &service_id,
+            SinkType::Syslog,
+            "logs.example.com:514",
+            Some("encrypted".to_string()),
+        )
+        .await
+        .unwrap();
+
+        let mut actual = p
+            .get_log_forwarding_for_deployment(&deployment_id)
+            .await
+            .unwrap()
+            .unwrap();
+        actual.last_update = Default::default();
+
+        assert_eq!(
+            actual,
+            LogForwarding {
+                service_id,
+                sink: SinkType::Syslog,
+                endpoint: "logs.example.com:514".to_string(),
+                auth: Some("encrypted".to_string()),
+                last_update: Default::default(),
+            }
+        );
+
+        p.delete_log_forwarding(&service_id).await.unwrap();
+        assert!(p.get_log_forwarding(&service_id).await.unwrap().is_none());
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn service() {
         let (p, _) = Persistence::new_in_memory().await;
@@ -1119,6 +2119,87 @@ mod tests {
         assert_eq!(actual, vec![id_1, id_2]);
     }
 
+    #[tokio::test(flavor = "multi_thread")]
+    async fn deployment_filters() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let service_id = add_service(&p.pool).await.unwrap();
+        let crashed_early = Uuid::new_v4();
+        let stopped = Uuid::new_v4();
+        let crashed_late = Uuid::new_v4();
+
+        for (id, state, last_update) in [
+            (
+                crashed_early,
+                State::Crashed,
+                Utc.with_ymd_and_hms(2022, 4, 25, 4, 29, 33).unwrap(),
+            ),
+            (
+                stopped,
+                State::Stopped,
+                Utc.with_ymd_and_hms(2022, 4, 26, 4, 29, 44).unwrap(),
+            ),
+            (
+                crashed_late,
+                State::Crashed,
+                Utc.with_ymd_and_hms(2022, 4, 27, 4, 33, 48).unwrap(),
+            ),
+        ] {
+            p.insert_deployment(Deployment {
+                id,
+                service_id,
+                state,
+                last_update,
+                address: None,
+            })
+            .await
+            .unwrap();
+        }
+
+        let ids = |deployments: Vec<Deployment>| {
+            deployments
+                .into_iter()
+                .map(|deployment| deployment.id)
+                .collect::<Vec<_>>()
+        };
+
+        let all = p
+            .get_filtered_deployments(&service_id, &Default::default())
+            .await
+            .unwrap();
+        assert_eq!(ids(all), vec![crashed_early, stopped, crashed_late]);
+
+        let crashed = DeploymentFilter {
+            states: vec![State::Crashed],
+            ..Default::default()
+        };
+        let actual = p
+            .get_filtered_deployments(&service_id, &crashed)
+            .await
+            .unwrap();
+        assert_eq!(ids(actual), vec![crashed_early, crashed_late]);
+
+        let crashed_since = DeploymentFilter {
+            since: Some(Utc.with_ymd_and_hms(2022, 4, 26, 0, 0, 0).unwrap()),
+            ..crashed
+        };
+        let actual = p
+            .get_filtered_deployments(&service_id, &crashed_since)
+            .await
+            .unwrap();
+        assert_eq!(ids(actual), vec![crashed_late]);
+
+        let in_range = DeploymentFilter {
+            states: vec![State::Crashed, State::Stopped],
+            since: Some(Utc.with_ymd_and_hms(2022, 4, 25, 12, 0, 0).unwrap()),
+            until: Some(Utc.with_ymd_and_hms(2022, 4, 26, 12, 0, 0).unwrap()),
+        };
+        let actual = p
+            .get_filtered_deployments(&service_id, &in_range)
+            .await
+            .unwrap();
+        assert_eq!(ids(actual), vec![stopped]);
+    }
+
     async fn add_deployment(pool: &SqlitePool) -> Result<Uuid> {
         let service_id = add_service(pool).await?;
         let deployment_id = Uuid::new_v4();
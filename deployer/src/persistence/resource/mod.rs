@@ -2,9 +2,10 @@ pub mod database;
 
 use sqlx::{
     sqlite::{SqliteArgumentValue, SqliteValueRef},
+    types::Json,
     Database, Sqlite,
 };
-use std::{borrow::Cow, fmt::Display, str::FromStr};
+use std::{borrow::Cow, collections::BTreeMap, fmt::Display, str::FromStr};
 use uuid::Uuid;
 
 pub use self::database::Type as DatabaseType;
@@ -23,6 +24,8 @@ pub struct Resource {
     pub service_id: Uuid,
     pub r#type: Type,
     pub data: serde_json::Value,
+    /// Key-value pairs the service tagged the resource with, to tell it apart from others of the same type
+    pub metadata: Json<BTreeMap<String, String>>,
 }
 
 impl From<Resource> for shuttle_common::models::resource::Response {
@@ -31,6 +34,7 @@ impl From<Resource> for shuttle_common::models::resource::Response {
             service_id: resource.service_id,
             r#type: resource.r#type.into(),
             data: resource.data,
+            metadata: resource.metadata.0,
         }
     }
 }
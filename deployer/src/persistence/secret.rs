@@ -1,3 +1,5 @@
+use std::collections::BTreeMap;
+
 use chrono::{DateTime, Utc};
 use uuid::Uuid;
 
@@ -12,6 +14,14 @@ pub trait SecretRecorder: Clone + Send + Sync + 'static {
         key: &str,
         value: &str,
     ) -> Result<(), Self::Err>;
+
+    /// Make `secrets` the secrets of the service, removing those it had which are not among them. Either all of that
+    /// happens or none of it does.
+    async fn replace_secrets(
+        &self,
+        service_id: &Uuid,
+        secrets: &BTreeMap<String, String>,
+    ) -> Result<(), Self::Err>;
 }
 
 #[async_trait::async_trait]
@@ -2,6 +2,7 @@ use strum::{Display, EnumString};
 
 /// States a deployment can be in
 #[derive(sqlx::Type, Debug, Display, Clone, Copy, EnumString, PartialEq, Eq)]
+#[strum(ascii_case_insensitive)]
 pub enum State {
     /// Deployment is queued to be build
     Queued,
@@ -24,9 +25,19 @@ pub enum State {
     /// Deployment was running, but has been stopped by the user.
     Stopped,
 
+    /// Deployment was running, but was stopped once it used up the run-time budget it was deployed with
+    #[strum(to_string = "BudgetExceeded", serialize = "budget-exceeded")]
+    BudgetExceeded,
+
+    /// Deployment outlived the time to live it was deployed with, and was stopped or never got to run
+    Expired,
+
     /// Something in the deployment process failed
     Crashed,
 
+    /// Deployment was cancelled by the user before it was built
+    Cancelled,
+
     /// We never expect this state and entering this state should be considered a bug
     Unknown,
 }
@@ -47,7 +58,10 @@ impl From<State> for shuttle_common::deployment::State {
             State::Running => Self::Running,
             State::Completed => Self::Completed,
             State::Stopped => Self::Stopped,
+            State::BudgetExceeded => Self::BudgetExceeded,
+            State::Expired => Self::Expired,
             State::Crashed => Self::Crashed,
+            State::Cancelled => Self::Cancelled,
             State::Unknown => Self::Unknown,
         }
     }
@@ -63,7 +77,10 @@ impl From<shuttle_common::deployment::State> for State {
             shuttle_common::deployment::State::Running => Self::Running,
             shuttle_common::deployment::State::Completed => Self::Completed,
             shuttle_common::deployment::State::Stopped => Self::Stopped,
+            shuttle_common::deployment::State::BudgetExceeded => Self::BudgetExceeded,
+            shuttle_common::deployment::State::Expired => Self::Expired,
             shuttle_common::deployment::State::Crashed => Self::Crashed,
+            shuttle_common::deployment::State::Cancelled => Self::Cancelled,
             shuttle_common::deployment::State::Unknown => Self::Unknown,
         }
     }
@@ -1,6 +1,8 @@
 use std::{
     convert::Infallible,
     net::{IpAddr, SocketAddr},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Instant,
 };
 
 use async_trait::async_trait;
@@ -13,29 +15,96 @@ use hyper::{
 };
 use hyper_reverse_proxy::{ProxyError, ReverseProxy};
 use once_cell::sync::Lazy;
-use opentelemetry::global;
+use opentelemetry::{
+    global,
+    trace::{TraceContextExt, TraceId},
+};
 use opentelemetry_http::HeaderExtractor;
-use shuttle_common::backends::headers::XShuttleProject;
-use tracing::{error, field, instrument, trace, Span};
+use shuttle_common::backends::headers::{XShuttleProject, X_SHUTTLE_NOT_DEPLOYED};
+use tracing::{error, field, info, instrument, trace, Span};
 use tracing_opentelemetry::OpenTelemetrySpanExt;
 
+use crate::deployment::drain::IN_FLIGHT;
+
 static PROXY_CLIENT: Lazy<ReverseProxy<HttpConnector<GaiResolver>>> =
     Lazy::new(|| ReverseProxy::new(Client::new()));
 static SERVER_HEADER: Lazy<HeaderValue> = Lazy::new(|| "shuttle.rs".parse().unwrap());
+/// Requests seen without a trace ID, to sample those by
+static UNTRACED_REQUESTS: AtomicU64 = AtomicU64::new(0);
+
+/// Which proxied requests get an access log line
+#[derive(Clone, Copy, Debug)]
+pub struct AccessLogSampling {
+    /// Log one in this many requests. Nothing is logged when `0`.
+    pub one_in: u32,
+    /// Log every request which fails with a server error, whether it is sampled or not
+    pub keep_errors: bool,
+}
+
+impl AccessLogSampling {
+    /// The sample is picked by trace ID, so every hop of a trace makes the same choice. Requests without a trace
+    /// (when tracing is not exported) are sampled by counting them instead.
+    fn should_log(&self, trace_id: TraceId, status: StatusCode) -> bool {
+        if self.keep_errors && status.is_server_error() {
+            return true;
+        }
 
-#[instrument(name = "proxy_request", skip(address_getter), fields(http.method = %req.method(), http.uri = %req.uri(), http.status_code = field::Empty, service = field::Empty))]
+        match self.one_in {
+            0 => false,
+            1 => true,
+            one_in if trace_id == TraceId::INVALID => {
+                UNTRACED_REQUESTS.fetch_add(1, Ordering::Relaxed) % one_in as u64 == 0
+            }
+            one_in => u128::from_be_bytes(trace_id.to_bytes()) % one_in as u128 == 0,
+        }
+    }
+}
+
+#[instrument(name = "proxy_request", skip(address_getter, access_log), fields(http.method = %req.method(), http.uri = %req.uri(), http.status_code = field::Empty, service = field::Empty))]
 pub async fn handle(
     remote_address: SocketAddr,
     fqdn: FQDN,
     req: Request<Body>,
     address_getter: impl AddressGetter,
+    access_log: AccessLogSampling,
 ) -> Result<Response<Body>, Infallible> {
+    let started = Instant::now();
     let span = Span::current();
     let parent_context = global::get_text_map_propagator(|propagator| {
         propagator.extract(&HeaderExtractor(req.headers()))
     });
     span.set_parent(parent_context);
 
+    let trace_id = span.context().span().span_context().trace_id();
+    let method = req.method().clone();
+    let uri = req.uri().clone();
+
+    let response = proxy(remote_address, fqdn, req, address_getter).await;
+
+    if access_log.should_log(trace_id, response.status()) {
+        info!(
+            target: "access_log",
+            %remote_address,
+            %method,
+            %uri,
+            status = response.status().as_u16(),
+            latency_ms = started.elapsed().as_millis() as u64,
+            "{method} {uri} {}",
+            response.status().as_u16()
+        );
+    }
+
+    Ok(response)
+}
+
+async fn proxy(
+    remote_address: SocketAddr,
+    fqdn: FQDN,
+    req: Request<Body>,
+    address_getter: impl AddressGetter,
+) -> Response<Body> {
+    let span = Span::current();
+
     let host: FQDN = match req.headers().get(HOST) {
         Some(host) => host
             .to_str()
@@ -45,19 +114,19 @@ pub async fn handle(
             .to_owned(),
         None => {
             trace!("proxy request has no host header");
-            return Ok(Response::builder()
+            return Response::builder()
                 .status(StatusCode::BAD_REQUEST)
                 .body(Body::empty())
-                .unwrap());
+                .unwrap();
         }
     };
 
     if host != fqdn {
         trace!(?host, "proxy won't serve foreign domain");
-        return Ok(Response::builder()
+        return Response::builder()
             .status(StatusCode::BAD_REQUEST)
             .body(Body::from("this domain is not served by proxy"))
-            .unwrap());
+            .unwrap();
     }
 
     // We only have one service per project, and its name coincides
@@ -66,10 +135,10 @@ pub async fn handle(
         Some(project) => project.0,
         None => {
             trace!("proxy request has no X-Shuttle-Project header");
-            return Ok(Response::builder()
+            return Response::builder()
                 .status(StatusCode::BAD_REQUEST)
                 .body(Body::from("request has no X-Shuttle-Project header"))
-                .unwrap());
+                .unwrap();
         }
     };
 
@@ -79,28 +148,32 @@ pub async fn handle(
     let proxy_address = match address_getter.get_address_for_service(&service).await {
         Ok(Some(address)) => address,
         Ok(None) => {
-            trace!(?host, service, "service not found on this server");
-            let response_body = format!("could not find service: {}", service);
-            return Ok(Response::builder()
-                .status(StatusCode::NOT_FOUND)
+            trace!(?host, service, "service has no running deployment");
+            let response_body = format!("service has no running deployment: {}", service);
+            return Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .header(&X_SHUTTLE_NOT_DEPLOYED, "true")
                 .body(response_body.into())
-                .unwrap());
+                .unwrap();
         }
         Err(err) => {
             error!(error = %err, service, "proxy failed to find address for host");
 
             let response_body = format!("failed to find service for host: {}", host);
-            return Ok(Response::builder()
+            return Response::builder()
                 .status(StatusCode::INTERNAL_SERVER_ERROR)
                 .body(response_body.into())
-                .unwrap());
+                .unwrap();
         }
     };
 
+    // The deployment replacing this one waits on the request before it stops this one
+    let _in_flight = IN_FLIGHT.track(proxy_address);
+
     match reverse_proxy(remote_address.ip(), &proxy_address.to_string(), req).await {
         Ok(response) => {
             Span::current().record("http.status_code", response.status().as_u16());
-            Ok(response)
+            response
         }
         Err(error) => {
             match error {
@@ -117,10 +190,10 @@ pub async fn handle(
                     "error while handling request needing upgrade in reverse proxy"
                 ),
             };
-            Ok(Response::builder()
+            Response::builder()
                 .status(StatusCode::INTERNAL_SERVER_ERROR)
                 .body(Body::empty())
-                .unwrap())
+                .unwrap()
         }
     }
 }
@@ -146,3 +219,111 @@ async fn reverse_proxy(
 
     Ok(response)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use async_trait::async_trait;
+    use hyper::{header::HOST, Body, Request, StatusCode};
+    use opentelemetry::trace::TraceId;
+    use shuttle_common::backends::headers::{X_SHUTTLE_NOT_DEPLOYED, X_SHUTTLE_PROJECT};
+
+    use super::{proxy, AccessLogSampling, AddressGetter};
+    use crate::handlers::Error;
+
+    /// Knows of no running deployment, or fails to look one up when `fails` is set
+    #[derive(Clone)]
+    struct StubAddressGetter {
+        fails: bool,
+    }
+
+    #[async_trait]
+    impl AddressGetter for StubAddressGetter {
+        async fn get_address_for_service(
+            &self,
+            _service_name: &str,
+        ) -> crate::handlers::Result<Option<SocketAddr>> {
+            if self.fails {
+                Err(Error::NotFound)
+            } else {
+                Ok(None)
+            }
+        }
+    }
+
+    fn request() -> Request<Body> {
+        Request::builder()
+            .header(HOST, "matrix.unittest.shuttleapp.rs")
+            .header(&X_SHUTTLE_PROJECT, "matrix")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn not_deployed() {
+        let fqdn = "matrix.unittest.shuttleapp.rs".parse().unwrap();
+        let remote_address = "127.0.0.1:8000".parse().unwrap();
+
+        let response = proxy(
+            remote_address,
+            fqdn,
+            request(),
+            StubAddressGetter { fails: false },
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[&X_SHUTTLE_NOT_DEPLOYED], "true");
+
+        // A deployer which cannot tell is not the same as one with nothing deployed
+        let fqdn = "matrix.unittest.shuttleapp.rs".parse().unwrap();
+        let response = proxy(
+            remote_address,
+            fqdn,
+            request(),
+            StubAddressGetter { fails: true },
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(!response.headers().contains_key(&X_SHUTTLE_NOT_DEPLOYED));
+    }
+
+    #[test]
+    fn access_log_sampling() {
+        let sampled = TraceId::from_bytes(40u128.to_be_bytes());
+        let skipped = TraceId::from_bytes(41u128.to_be_bytes());
+
+        let every_tenth = AccessLogSampling {
+            one_in: 10,
+            keep_errors: false,
+        };
+        assert!(every_tenth.should_log(sampled, StatusCode::OK));
+        assert!(every_tenth.should_log(sampled, StatusCode::BAD_GATEWAY));
+        assert!(!every_tenth.should_log(skipped, StatusCode::OK));
+        assert!(!every_tenth.should_log(skipped, StatusCode::BAD_GATEWAY));
+
+        let with_errors = AccessLogSampling {
+            keep_errors: true,
+            ..every_tenth
+        };
+        assert!(!with_errors.should_log(skipped, StatusCode::NOT_FOUND));
+        assert!(with_errors.should_log(skipped, StatusCode::BAD_GATEWAY));
+
+        let off = AccessLogSampling {
+            one_in: 0,
+            keep_errors: false,
+        };
+        assert!(!off.should_log(sampled, StatusCode::OK));
+
+        let all = AccessLogSampling {
+            one_in: 1,
+            keep_errors: false,
+        };
+        assert!(all.should_log(skipped, StatusCode::OK));
+
+        let untraced = (0..100)
+            .filter(|_| every_tenth.should_log(TraceId::INVALID, StatusCode::OK))
+            .count();
+        assert_eq!(untraced, 10);
+    }
+}
@@ -0,0 +1,3 @@
+-- Domains sharing one SAN certificate point to the first domain of their group.
+-- Domains without a group only have a certificate for themselves.
+ALTER TABLE custom_domains ADD COLUMN certificate_group TEXT;
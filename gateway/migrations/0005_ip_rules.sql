@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS ip_rules (
+  project_name TEXT NOT NULL REFERENCES projects (project_name),
+  cidr TEXT NOT NULL,
+  action TEXT NOT NULL,
+  PRIMARY KEY (project_name, cidr)
+);
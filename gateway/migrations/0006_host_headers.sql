@@ -0,0 +1,5 @@
+CREATE TABLE IF NOT EXISTS host_headers (
+  project_name TEXT PRIMARY KEY REFERENCES projects (project_name),
+  policy TEXT NOT NULL,
+  value TEXT
+);
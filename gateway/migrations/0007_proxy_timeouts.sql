@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS proxy_timeouts (
+  project_name TEXT PRIMARY KEY REFERENCES projects (project_name),
+  response_secs INTEGER,
+  stalled_body TEXT NOT NULL,
+  idle_secs INTEGER
+);
@@ -0,0 +1,4 @@
+-- The environment of a project a domain serves, as named in the `[env.<name>]`
+-- section of its Shuttle.toml. The project of the domain is the one that
+-- environment deploys to. Domains without one serve the base project.
+ALTER TABLE custom_domains ADD COLUMN environment TEXT;
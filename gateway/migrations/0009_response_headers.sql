@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS response_headers (
+  project_name TEXT NOT NULL REFERENCES projects (project_name),
+  name TEXT NOT NULL,
+  action TEXT NOT NULL,
+  value TEXT,
+  PRIMARY KEY (project_name, name)
+);
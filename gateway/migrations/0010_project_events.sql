@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS project_events (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  project_name TEXT NOT NULL,
+  state JSON NOT NULL,
+  timestamp TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS project_events_project_name ON project_events (project_name, id);
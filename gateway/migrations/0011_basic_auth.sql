@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS basic_auth (
+  project_name TEXT PRIMARY KEY REFERENCES projects (project_name),
+  username TEXT NOT NULL,
+  salt BLOB NOT NULL,
+  password_hash BLOB NOT NULL,
+  exempt JSON NOT NULL
+);
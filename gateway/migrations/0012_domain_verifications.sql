@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS domain_verifications (
+  fqdn TEXT NOT NULL,
+  project_name TEXT NOT NULL REFERENCES projects (project_name),
+  token TEXT NOT NULL,
+  PRIMARY KEY (fqdn, project_name)
+);
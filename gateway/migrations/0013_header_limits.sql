@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS header_limits (
+  project_name TEXT PRIMARY KEY REFERENCES projects (project_name),
+  max_total_size INTEGER,
+  max_count INTEGER,
+  max_header_size INTEGER
+);
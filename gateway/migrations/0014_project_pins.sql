@@ -0,0 +1,3 @@
+CREATE TABLE IF NOT EXISTS project_pins (
+  project_name TEXT PRIMARY KEY REFERENCES projects (project_name)
+);
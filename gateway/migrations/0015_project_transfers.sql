@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS project_transfers (
+  project_name TEXT PRIMARY KEY REFERENCES projects (project_name),
+  from_account TEXT NOT NULL,
+  to_account TEXT NOT NULL,
+  created_at TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS project_transfers_to_account ON project_transfers (to_account);
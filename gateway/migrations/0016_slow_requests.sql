@@ -0,0 +1 @@
+ALTER TABLE proxy_timeouts ADD COLUMN slow_request_ms INTEGER;
@@ -4,6 +4,7 @@ use std::task::{Context, Poll};
 use std::time::Duration;
 
 use axum::body::boxed;
+use axum::headers::{HeaderMapExt, Host};
 use axum::response::Response;
 use fqdn::FQDN;
 use futures::future::BoxFuture;
@@ -11,7 +12,7 @@ use hyper::server::conn::AddrStream;
 use hyper::{Body, Request};
 use instant_acme::{
     Account, AccountCredentials, Authorization, AuthorizationStatus, Challenge, ChallengeType,
-    Identifier, KeyAuthorization, LetsEncrypt, NewAccount, NewOrder, Order, OrderStatus,
+    Identifier, LetsEncrypt, NewAccount, NewOrder, Order, OrderStatus,
 };
 use rcgen::{Certificate, CertificateParams, DistinguishedName};
 use tokio::sync::Mutex;
@@ -24,40 +25,53 @@ use crate::{Error, ProjectName};
 
 const MAX_RETRIES: usize = 15;
 
+const CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+
 #[derive(Debug, Eq, PartialEq)]
 pub struct CustomDomain {
     pub fqdn: FQDN,
     pub project_name: ProjectName,
     pub certificate: String,
     pub private_key: String,
+    /// Domain naming the group of domains sharing this certificate
+    pub certificate_group: FQDN,
+    /// Environment of the project this domain serves, if it is not the base one
+    pub environment: Option<String>,
 }
 
 /// An ACME client implementation that completes Http01 challenges
 /// It is safe to clone this type as it functions as a singleton
 #[derive(Clone, Default)]
-pub struct AcmeClient(Arc<Mutex<HashMap<String, KeyAuthorization>>>);
+pub struct AcmeClient(Arc<Mutex<HashMap<(String, String), String>>>);
 
 impl AcmeClient {
     pub fn new() -> Self {
         Self(Arc::new(Mutex::new(HashMap::default())))
     }
 
-    async fn add_http01_challenge_authorization(&self, token: String, key: KeyAuthorization) {
-        trace!(token, "saving acme http01 challenge");
-        self.0.lock().await.insert(token, key);
+    /// Answer the challenge `token` with `key` for requests to `domain`, until it is removed
+    async fn add_http01_challenge_authorization(&self, domain: &str, token: String, key: String) {
+        trace!(domain, token, "saving acme http01 challenge");
+        self.0
+            .lock()
+            .await
+            .insert((challenge_host(domain), token), key);
     }
 
-    async fn get_http01_challenge_authorization(&self, token: &str) -> Option<String> {
+    async fn get_http01_challenge_authorization(&self, host: &str, token: &str) -> Option<String> {
         self.0
             .lock()
             .await
-            .get(token)
-            .map(|key| key.as_str().to_owned())
+            .get(&(challenge_host(host), token.to_string()))
+            .cloned()
     }
 
-    async fn remove_http01_challenge_authorization(&self, token: &str) {
-        trace!(token, "removing acme http01 challenge");
-        self.0.lock().await.remove(token);
+    async fn remove_http01_challenge_authorization(&self, domain: &str, token: &str) {
+        trace!(domain, token, "removing acme http01 challenge");
+        self.0
+            .lock()
+            .await
+            .remove(&(challenge_host(domain), token.to_string()));
     }
 
     /// Create a new ACME account that can be restored using by deserializing the returned JSON into a [instant_acme::AccountCredentials]
@@ -92,14 +106,21 @@ impl AcmeClient {
     }
 
     /// Create an ACME-signed certificate and return it and its
-    /// associated PEM-encoded private key
+    /// associated PEM-encoded private key. When more than one
+    /// identifier is given, the certificate covers all of them as
+    /// Subject Alternative Names.
     pub async fn create_certificate(
         &self,
-        identifier: &str,
+        identifiers: &[String],
         challenge_type: ChallengeType,
         credentials: AccountCredentials<'_>,
     ) -> Result<(String, String), AcmeClientError> {
-        trace!(identifier, "requesting acme certificate");
+        trace!(?identifiers, "requesting acme certificate");
+
+        if identifiers.is_empty() {
+            error!("no identifiers to request a certificate for");
+            return Err(AcmeClientError::OrderCreation);
+        }
 
         let account = Account::from_credentials(credentials).map_err(|error| {
             error!(
@@ -111,7 +132,10 @@ impl AcmeClient {
 
         let (mut order, state) = account
             .new_order(&NewOrder {
-                identifiers: &[Identifier::Dns(identifier.to_string())],
+                identifiers: &identifiers
+                    .iter()
+                    .map(|identifier| Identifier::Dns(identifier.to_string()))
+                    .collect::<Vec<_>>(),
             })
             .await
             .map_err(|error| {
@@ -128,17 +152,14 @@ impl AcmeClient {
                     AcmeClientError::AuthorizationCreation
                 })?;
 
-        // There should only ever be 1 authorization as we only provide 1 domain at a time
-        debug_assert!(authorizations.len() == 1);
-        let authorization = &authorizations[0];
-
-        trace!(?authorization, "got authorization");
+        // There is one authorization for every identifier in the order
+        debug_assert!(authorizations.len() == identifiers.len());
 
-        self.complete_challenge(challenge_type, authorization, &mut order)
+        self.complete_challenges(challenge_type, &authorizations, &mut order)
             .await?;
 
         let certificate = {
-            let mut params = CertificateParams::new(vec![identifier.to_owned()]);
+            let mut params = CertificateParams::new(identifiers.to_vec());
             params.distinguished_name = DistinguishedName::new();
             Certificate::from_params(params).map_err(|error| {
                 error!(%error, "failed to create certificate");
@@ -211,83 +232,113 @@ impl AcmeClient {
         Ok(())
     }
 
-    async fn complete_challenge(
+    /// Complete the challenges of all the authorizations of an order
+    /// and wait for the order to be ready
+    async fn complete_challenges(
         &self,
         ty: ChallengeType,
-        authorization: &Authorization,
+        authorizations: &[Authorization],
         order: &mut Order,
     ) -> Result<(), AcmeClientError> {
-        // Don't complete challenge for orders that are already valid
-        if let AuthorizationStatus::Valid = authorization.status {
-            return Ok(());
-        }
-        let challenge = Self::find_challenge(ty, authorization)?;
-        match ty {
-            ChallengeType::Http01 => self.complete_http01_challenge(challenge, order).await,
-            ChallengeType::Dns01 => {
-                self.complete_dns01_challenge(&authorization.identifier, challenge, order)
+        let mut http01_tokens = Vec::new();
+
+        let res = async {
+            for authorization in authorizations {
+                trace!(?authorization, "got authorization");
+
+                // Don't complete challenge for orders that are already valid
+                if let AuthorizationStatus::Valid = authorization.status {
+                    continue;
+                }
+                let challenge = Self::find_challenge(ty, authorization)?;
+                match ty {
+                    ChallengeType::Http01 => {
+                        let Identifier::Dns(domain) = &authorization.identifier;
+                        self.prepare_http01_challenge(domain, challenge, order)
+                            .await;
+                        http01_tokens.push((domain.clone(), challenge.token.clone()));
+                    }
+                    ChallengeType::Dns01 => {
+                        self.prepare_dns01_challenge(&authorization.identifier, challenge, order)
+                            .await
+                    }
+                    _ => return Err(AcmeClientError::ChallengeNotSupported),
+                }
+
+                order
+                    .set_challenge_ready(&challenge.url)
                     .await
+                    .map_err(|error| {
+                        error!(%error, "failed to mark challenge as ready");
+                        AcmeClientError::SetReadyFailed
+                    })?;
             }
-            _ => Err(AcmeClientError::ChallengeNotSupported),
+
+            self.wait_for_termination(order).await
         }
+        .await;
+
+        for (domain, token) in http01_tokens {
+            self.remove_http01_challenge_authorization(&domain, &token)
+                .await;
+        }
+
+        res
     }
 
-    async fn complete_dns01_challenge(
+    async fn prepare_dns01_challenge(
         &self,
         identifier: &Identifier,
         challenge: &Challenge,
         order: &mut Order,
-    ) -> Result<(), AcmeClientError> {
+    ) {
         let Identifier::Dns(domain) = identifier;
 
         let digest = order.key_authorization(challenge).dns_value();
+        // A wildcard and the domain under it are both checked at `_acme-challenge.{domain}`, so the records of an
+        // order can share a name and all of them have to stay up until the order is done
         warn!("dns-01 challenge: _acme-challenge.{domain} 300 IN TXT \"{digest}\"");
 
         // Wait 60 secs to insert the record manually and for it to
         // propagate before moving on
         sleep(Duration::from_secs(60)).await;
-
-        order
-            .set_challenge_ready(&challenge.url)
-            .await
-            .map_err(|error| {
-                error!(%error, "failed to mark challenge as ready");
-                AcmeClientError::SetReadyFailed
-            })?;
-
-        self.wait_for_termination(order).await
     }
 
-    async fn complete_http01_challenge(
+    async fn prepare_http01_challenge(
         &self,
+        domain: &str,
         challenge: &Challenge,
         order: &mut Order,
-    ) -> Result<(), AcmeClientError> {
+    ) {
         trace!(?challenge, "will complete challenge");
 
         self.add_http01_challenge_authorization(
+            domain,
             challenge.token.clone(),
-            order.key_authorization(challenge),
+            order.key_authorization(challenge).as_str().to_owned(),
         )
         .await;
+    }
+}
 
-        order
-            .set_challenge_ready(&challenge.url)
-            .await
-            .map_err(|error| {
-                error!(%error, "failed to mark challenge as ready");
-                AcmeClientError::SetReadyFailed
-            })?;
-
-        let res = self.wait_for_termination(order).await;
-
-        self.remove_http01_challenge_authorization(&challenge.token)
-            .await;
-
-        res
+/// The challenge to prove control of `identifiers` with. Wildcards can only be proven through DNS-01, which needs a
+/// record published on the domain, so any other domain is proven through HTTP-01 answered by the bouncer instead.
+pub fn challenge_type_for(identifiers: &[String]) -> ChallengeType {
+    if identifiers
+        .iter()
+        .any(|identifier| identifier.starts_with("*."))
+    {
+        ChallengeType::Dns01
+    } else {
+        ChallengeType::Http01
     }
 }
 
+/// Hosts are compared without their case or a trailing dot, which do not make them a different domain
+fn challenge_host(host: &str) -> String {
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
 #[derive(Debug, strum::Display)]
 pub enum AcmeClientError {
     AccountCreation,
@@ -347,7 +398,8 @@ where
 
 impl<ReqBody, S> Service<Request<ReqBody>> for ChallengeResponder<S>
 where
-    S: Service<Request<ReqBody>, Response = Response, Error = Error> + Send + 'static,
+    ReqBody: Send + 'static,
+    S: Service<Request<ReqBody>, Response = Response, Error = Error> + Clone + Send + 'static,
     S::Future: Send + 'static,
 {
     type Response = S::Response;
@@ -358,45 +410,164 @@ where
         self.inner.poll_ready(cx)
     }
 
+    /// Only challenges which are active for the host of the request are answered here. Every other request, including
+    /// those for other `/.well-known/` paths or for challenges of a project's own ACME client, goes on as usual.
     fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
-        if !req.uri().path().starts_with("/.well-known/acme-challenge/") {
-            let future = self.inner.call(req);
-            return Box::pin(async move {
-                let response: Response = future.await?;
-                Ok(response)
-            });
-        }
-
-        let token = match req
+        let challenge = req
             .uri()
             .path()
-            .strip_prefix("/.well-known/acme-challenge/")
-        {
-            Some(token) => token.to_string(),
-            None => {
-                return Box::pin(async {
-                    Ok(Response::builder()
-                        .status(404)
-                        .body(boxed(Body::empty()))
-                        .unwrap())
-                })
-            }
-        };
+            .strip_prefix(CHALLENGE_PATH)
+            .filter(|token| !token.is_empty() && !token.contains('/'))
+            .map(ToString::to_string)
+            .zip(
+                req.headers()
+                    .typed_get::<Host>()
+                    .map(|host| host.hostname().to_string()),
+            );
 
-        trace!(token, "responding to certificate challenge");
+        let Some((token, host)) = challenge else {
+            return Box::pin(self.inner.call(req));
+        };
 
+        // The key has to be looked up before the request can be handed on, which needs the inner service now
         let client = self.client.clone();
+        let clone = self.inner.clone();
+        let inner = std::mem::replace(&mut self.inner, clone);
 
         Box::pin(async move {
-            let (status, body) = match client.get_http01_challenge_authorization(&token).await {
-                Some(key) => (200, Body::from(key)),
-                None => (404, Body::empty()),
-            };
-
-            Ok(Response::builder()
-                .status(status)
-                .body(boxed(body))
-                .unwrap())
+            match client
+                .get_http01_challenge_authorization(&host, &token)
+                .await
+            {
+                Some(key) => {
+                    trace!(host, token, "responding to certificate challenge");
+
+                    Ok(Response::builder()
+                        .status(200)
+                        .body(boxed(Body::from(key)))
+                        .unwrap())
+                }
+                None => {
+                    let mut inner = inner;
+                    inner.call(req).await
+                }
+            }
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::body::boxed;
+    use axum::response::Response;
+    use hyper::{Body, Request};
+    use tower::{service_fn, Layer, ServiceExt};
+
+    use instant_acme::ChallengeType;
+
+    use super::{challenge_type_for, AcmeClient, ChallengeResponderLayer};
+    use crate::Error;
+
+    async fn respond(client: &AcmeClient, host: &str, path: &str) -> (u16, String) {
+        // Stands in for the bouncer, which sends everything on to HTTPS
+        let bouncer = service_fn(|_req: Request<Body>| async {
+            Ok::<_, Error>(
+                Response::builder()
+                    .status(301)
+                    .body(boxed(Body::from("backend")))
+                    .unwrap(),
+            )
+        });
+        let request = Request::builder()
+            .uri(path)
+            .header("Host", host)
+            .body(Body::empty())
+            .unwrap();
+
+        let response = ChallengeResponderLayer::new(client.clone())
+            .layer(bouncer)
+            .oneshot(request)
+            .await
+            .unwrap();
+        let status = response.status().as_u16();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn intercepts_active_challenges_only() {
+        let client = AcmeClient::new();
+        let path = "/.well-known/acme-challenge/token";
+
+        // Without a challenge going on, the project may serve the path itself
+        assert_eq!(
+            respond(&client, "neo.the.matrix", path).await,
+            (301, "backend".to_string())
+        );
+
+        client
+            .add_http01_challenge_authorization(
+                "neo.the.matrix",
+                "token".to_string(),
+                "token.key".to_string(),
+            )
+            .await;
+
+        assert_eq!(
+            respond(&client, "Neo.The.Matrix:80", path).await,
+            (200, "token.key".to_string())
+        );
+
+        // Only for the host the challenge is for
+        assert_eq!(
+            respond(&client, "trinity.the.matrix", path).await,
+            (301, "backend".to_string())
+        );
+
+        // Other well-known paths are always left to the project
+        for path in [
+            "/.well-known/security.txt",
+            "/.well-known/acme-challenge/other",
+            "/.well-known/acme-challenge/token/more",
+        ] {
+            assert_eq!(
+                respond(&client, "neo.the.matrix", path).await,
+                (301, "backend".to_string())
+            );
+        }
+
+        client
+            .remove_http01_challenge_authorization("neo.the.matrix", "token")
+            .await;
+        assert_eq!(
+            respond(&client, "neo.the.matrix", path).await,
+            (301, "backend".to_string())
+        );
+    }
+
+    #[test]
+    fn picks_challenge_type() {
+        let identifiers = |identifiers: &[&str]| -> Vec<String> {
+            identifiers.iter().map(ToString::to_string).collect()
+        };
+
+        assert_eq!(
+            challenge_type_for(&identifiers(&["neo.the.matrix"])),
+            ChallengeType::Http01
+        );
+        assert_eq!(
+            challenge_type_for(&identifiers(&["neo.the.matrix", "www.neo.the.matrix"])),
+            ChallengeType::Http01
+        );
+        assert_eq!(
+            challenge_type_for(&identifiers(&["*.the.matrix"])),
+            ChallengeType::Dns01
+        );
+        // One wildcard takes the whole order to DNS-01, like the certificate of the public domain
+        assert_eq!(
+            challenge_type_for(&identifiers(&["*.shuttleapp.rs", "shuttleapp.rs"])),
+            ChallengeType::Dns01
+        );
+    }
+}
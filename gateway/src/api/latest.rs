@@ -1,28 +1,35 @@
+use std::collections::BTreeMap;
 use std::io::Cursor;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Duration;
 
 use axum::body::Body;
-use axum::extract::{Extension, Path, State};
+use axum::extract::{Extension, Path, Query, State};
 use axum::handler::Handler;
+use axum::headers::HeaderMapExt;
 use axum::http::Request;
-use axum::middleware::from_extractor;
+use axum::middleware::{from_extractor, from_fn, Next};
 use axum::response::Response;
-use axum::routing::{any, get, post};
+use axum::routing::{any, delete, get, post};
 use axum::{Json as AxumJson, Router};
+use axum_server::Handle;
 use fqdn::FQDN;
-use futures::Future;
+use futures::{Future, FutureExt};
 use http::{StatusCode, Uri};
-use instant_acme::{AccountCredentials, ChallengeType};
+use instant_acme::AccountCredentials;
 use serde::{Deserialize, Serialize};
 use shuttle_common::backends::auth::{
-    AuthPublicKey, JwtAuthenticationLayer, Scope, ScopedLayer, EXP_MINUTES,
+    AuthPublicKey, Claim, JwtAuthenticationLayer, Scope, ScopedLayer, EXP_MINUTES,
 };
 use shuttle_common::backends::cache::CacheManager;
+use shuttle_common::backends::headers::XShuttleAdminSecret;
 use shuttle_common::backends::metrics::{Metrics, TraceLayer};
 use shuttle_common::models::error::ErrorKind;
-use shuttle_common::models::{project, stats};
+use shuttle_common::models::{
+    basic_auth, domain_verification, header_limits, host_header, instance, ip_rule, project,
+    response_header, stats, timeouts, usage, version,
+};
 use shuttle_common::request_span;
 use tokio::sync::mpsc::Sender;
 use tokio::sync::{Mutex, MutexGuard};
@@ -30,8 +37,11 @@ use tracing::{field, instrument, trace};
 use ttl_cache::TtlCache;
 use uuid::Uuid;
 
-use crate::acme::{AcmeClient, CustomDomain};
+use crate::acme::{challenge_type_for, AcmeClient, CustomDomain};
+use crate::args::QueueFullPolicy;
 use crate::auth::{ScopedUser, User};
+use crate::connections::{ConnectionLimit, ConnectionsStatus};
+use crate::ip_filter::parse_cidr;
 use crate::project::{ContainerInspectResponseExt, Project, ProjectCreating};
 use crate::task::{self, BoxedTask, TaskResult};
 use crate::tls::GatewayCertResolver;
@@ -39,6 +49,7 @@ use crate::worker::WORKER_QUEUE_SIZE;
 use crate::{Error, GatewayService, ProjectName};
 
 use super::auth_layer::ShuttleAuthLayer;
+use super::limits::{ControlLimits, Limiter};
 
 pub const SVC_DEGRADED_THRESHOLD: usize = 128;
 
@@ -50,29 +61,93 @@ pub enum GatewayStatus {
     Unhealthy,
 }
 
+/// Occupancy of the worker queue and how it behaves once full
+#[derive(Serialize, Deserialize)]
+pub struct QueueStatus {
+    policy: QueueFullPolicy,
+    depth: usize,
+    capacity: usize,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct StatusResponse {
     status: GatewayStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    queue: Option<QueueStatus>,
+    /// Connections held open by the user proxy
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    connections: Option<ConnectionsStatus>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ComponentStatus {
+    Ok,
+    Unavailable,
+}
+
+impl From<bool> for ComponentStatus {
+    fn from(ok: bool) -> Self {
+        if ok {
+            Self::Ok
+        } else {
+            Self::Unavailable
+        }
+    }
+}
+
+/// What the gateway needs to be up before it can take traffic
+#[derive(Serialize, Deserialize)]
+pub struct ReadinessResponse {
+    database: ComponentStatus,
+    worker: ComponentStatus,
+    /// Only known when the API was given a handle to the user proxy
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    user_proxy: Option<ComponentStatus>,
+}
+
+impl ReadinessResponse {
+    pub fn is_ready(&self) -> bool {
+        self.database == ComponentStatus::Ok
+            && self.worker == ComponentStatus::Ok
+            && self.user_proxy != Some(ComponentStatus::Unavailable)
+    }
 }
 
 impl StatusResponse {
     pub fn healthy() -> Self {
         Self {
             status: GatewayStatus::Healthy,
+            queue: None,
+            connections: None,
         }
     }
 
     pub fn degraded() -> Self {
         Self {
             status: GatewayStatus::Degraded,
+            queue: None,
+            connections: None,
         }
     }
 
     pub fn unhealthy() -> Self {
         Self {
             status: GatewayStatus::Unhealthy,
+            queue: None,
+            connections: None,
         }
     }
+
+    pub fn with_queue(mut self, queue: QueueStatus) -> Self {
+        self.queue = Some(queue);
+        self
+    }
+
+    pub fn with_connections(mut self, connections: ConnectionsStatus) -> Self {
+        self.connections = Some(connections);
+        self
+    }
 }
 
 #[instrument(skip(service))]
@@ -106,6 +181,15 @@ async fn get_projects_list(
     Ok(AxumJson(projects))
 }
 
+async fn get_account_usage(
+    State(RouterState { service, .. }): State<RouterState>,
+    User { name, .. }: User,
+) -> Result<AxumJson<usage::Response>, Error> {
+    let usage = service.account_usage(&name).await?;
+
+    Ok(AxumJson(usage))
+}
+
 // async fn get_projects_list_with_filter(
 //     State(RouterState { service, .. }): State<RouterState>,
 //     User { name, .. }: User,
@@ -200,7 +284,14 @@ async fn route_project(
         .await
 }
 
-async fn get_status(State(RouterState { sender, .. }): State<RouterState>) -> Response<Body> {
+async fn get_status(
+    State(RouterState {
+        service,
+        sender,
+        connections,
+        ..
+    }): State<RouterState>,
+) -> Response<Body> {
     let (status, body) = if sender.is_closed() || sender.capacity() == 0 {
         (
             StatusCode::INTERNAL_SERVER_ERROR,
@@ -211,6 +302,14 @@ async fn get_status(State(RouterState { sender, .. }): State<RouterState>) -> Re
     } else {
         (StatusCode::OK, StatusResponse::healthy())
     };
+    let mut body = body.with_queue(QueueStatus {
+        policy: service.queue_full_policy(),
+        depth: WORKER_QUEUE_SIZE.saturating_sub(sender.capacity()),
+        capacity: WORKER_QUEUE_SIZE,
+    });
+    if let Some(connections) = connections {
+        body = body.with_connections(connections.status());
+    }
 
     let body = serde_json::to_vec(&body).unwrap();
     Response::builder()
@@ -219,6 +318,45 @@ async fn get_status(State(RouterState { sender, .. }): State<RouterState>) -> Re
         .unwrap()
 }
 
+/// Liveness only says the process is up and able to answer requests
+async fn get_healthz() -> AxumJson<StatusResponse> {
+    AxumJson(StatusResponse::healthy())
+}
+
+async fn get_version() -> AxumJson<version::Response> {
+    AxumJson(version::Response {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        protocol_version: version::PROTOCOL_VERSION,
+    })
+}
+
+async fn get_readyz(
+    State(RouterState {
+        service,
+        sender,
+        user_proxy,
+        ..
+    }): State<RouterState>,
+) -> (StatusCode, AxumJson<ReadinessResponse>) {
+    let database = service.ping_db().await.is_ok();
+    let worker = !sender.is_closed() && sender.capacity() > 0;
+    let user_proxy =
+        user_proxy.map(|handle| matches!(handle.listening().now_or_never(), Some(Some(_))).into());
+
+    let body = ReadinessResponse {
+        database: database.into(),
+        worker: worker.into(),
+        user_proxy,
+    };
+    let status = if body.is_ready() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (status, AxumJson(body))
+}
+
 #[instrument(skip_all)]
 async fn post_load(
     State(RouterState { running_builds, .. }): State<RouterState>,
@@ -300,6 +438,46 @@ async fn revive_projects(
         .map_err(|_| Error::from_kind(ErrorKind::Internal))
 }
 
+#[instrument(skip_all)]
+async fn get_tls_errors(
+    Extension(resolver): Extension<Arc<GatewayCertResolver>>,
+) -> AxumJson<BTreeMap<String, u64>> {
+    AxumJson(resolver.tls_errors().counts())
+}
+
+/// Serve the certificates stored for the group of a custom domain again, after they were rotated out-of-band
+#[instrument(skip_all, fields(%fqdn))]
+async fn reload_certificate(
+    State(RouterState { service, .. }): State<RouterState>,
+    Extension(resolver): Extension<Arc<GatewayCertResolver>>,
+    Path(fqdn): Path<String>,
+) -> Result<String, Error> {
+    let fqdn: FQDN = fqdn
+        .parse()
+        .map_err(|_err| Error::from(ErrorKind::InvalidCustomDomain))?;
+    let CustomDomain {
+        certificate_group, ..
+    } = service.project_details_for_custom_domain(&fqdn).await?;
+
+    let mut reloaded = Vec::new();
+    for domain in service
+        .iter_custom_domains()
+        .await?
+        .filter(|domain| domain.certificate_group == certificate_group)
+    {
+        let mut buf = Vec::new();
+        buf.extend(domain.certificate.as_bytes());
+        buf.extend(domain.private_key.as_bytes());
+        resolver
+            .replace_pem(&domain.fqdn.to_string(), Cursor::new(buf))
+            .await?;
+
+        reloaded.push(domain.fqdn.to_string());
+    }
+
+    Ok(format!("certificate reloaded for {}", reloaded.join(", ")))
+}
+
 #[instrument(skip_all, fields(%email, ?acme_server))]
 async fn create_acme_account(
     Extension(acme_client): Extension<AcmeClient>,
@@ -311,38 +489,89 @@ async fn create_acme_account(
     Ok(AxumJson(res))
 }
 
+/// The TXT record a project has to publish before a domain can be added to it, and whether it is there yet
 #[instrument(skip_all, fields(%project_name, %fqdn))]
+async fn get_domain_verification(
+    State(RouterState { service, .. }): State<RouterState>,
+    Path((project_name, fqdn)): Path<(ProjectName, String)>,
+) -> Result<AxumJson<domain_verification::Response>, Error> {
+    let fqdn: FQDN = fqdn
+        .parse()
+        .map_err(|_err| Error::from(ErrorKind::InvalidCustomDomain))?;
+
+    // Makes sure the project exists before keeping a token for it
+    service.find_project(&project_name).await?;
+
+    let token = service
+        .domain_verification_token(&project_name, &fqdn)
+        .await?;
+    let verified = service.domain_verified(&project_name, &fqdn).await?;
+
+    Ok(AxumJson(domain_verification::Response {
+        record_name: crate::domain_verification::record_name(&fqdn),
+        record_value: crate::domain_verification::record_value(&token),
+        fqdn: fqdn.to_string(),
+        verified,
+    }))
+}
+
+#[derive(Deserialize)]
+struct CertificateParams {
+    /// Environment of the project the domains serve, when `project_name` is the project it deploys to
+    environment: Option<String>,
+}
+
+#[instrument(skip_all, fields(%project_name, %fqdns, ?environment))]
 async fn request_acme_certificate(
     State(RouterState {
         service, sender, ..
     }): State<RouterState>,
     Extension(acme_client): Extension<AcmeClient>,
     Extension(resolver): Extension<Arc<GatewayCertResolver>>,
-    Path((project_name, fqdn)): Path<(ProjectName, String)>,
+    Path((project_name, fqdns)): Path<(ProjectName, String)>,
+    Query(CertificateParams { environment }): Query<CertificateParams>,
     AxumJson(credentials): AxumJson<AccountCredentials<'_>>,
 ) -> Result<String, Error> {
-    let fqdn: FQDN = fqdn
-        .parse()
+    let environment = environment.filter(|environment| !environment.is_empty());
+
+    // Several domains can be given as a comma separated list to share
+    // one SAN certificate. The first one names the group.
+    let fqdns = fqdns
+        .split(',')
+        .map(|fqdn| fqdn.trim().parse::<FQDN>())
+        .collect::<Result<Vec<_>, _>>()
         .map_err(|_err| Error::from(ErrorKind::InvalidCustomDomain))?;
+    let fqdn = fqdns[0].clone();
 
-    let (certs, private_key) = match service.project_details_for_custom_domain(&fqdn).await {
-        Ok(CustomDomain {
-            certificate,
-            private_key,
-            ..
-        }) => (certificate, private_key),
-        Err(err) if err.kind() == ErrorKind::CustomDomainNotFound => {
-            let (certs, private_key) = acme_client
-                .create_certificate(&fqdn.to_string(), ChallengeType::Http01, credentials)
-                .await?;
-            service
-                .create_custom_domain(project_name.clone(), &fqdn, &certs, &private_key)
-                .await?;
-            (certs, private_key)
+    // Before asking for a certificate, so going over the limit does not use up any of the ACME rate limits
+    service
+        .check_custom_domain_limit(&project_name, &fqdns)
+        .await?;
+    service
+        .check_domain_ownership(&project_name, &fqdns)
+        .await?;
+
+    let (certs, private_key) = match existing_certificate(&service, &project_name, &fqdns).await? {
+        Some(existing) => existing,
+        None => {
+            let identifiers: Vec<_> = fqdns.iter().map(ToString::to_string).collect();
+            acme_client
+                .create_certificate(&identifiers, challenge_type_for(&identifiers), credentials)
+                .await?
         }
-        Err(err) => return Err(err),
     };
 
+    // Stored even when the certificate is reused, so the domains can be moved to another environment
+    service
+        .create_custom_domain_group(
+            project_name.clone(),
+            &fqdns,
+            environment.as_deref(),
+            &certs,
+            &private_key,
+        )
+        .await?;
+
     let project = service.find_project(&project_name).await?;
     let idle_minutes = project.container().unwrap().idle_minutes();
 
@@ -369,16 +598,416 @@ async fn request_acme_certificate(
         .send(&sender)
         .await?;
 
-    let mut buf = Vec::new();
-    buf.extend(certs.as_bytes());
-    buf.extend(private_key.as_bytes());
-    resolver
-        .serve_pem(&fqdn.to_string(), Cursor::new(buf))
-        .await?;
+    for fqdn in &fqdns {
+        let mut buf = Vec::new();
+        buf.extend(certs.as_bytes());
+        buf.extend(private_key.as_bytes());
+        resolver
+            .serve_pem(&fqdn.to_string(), Cursor::new(buf))
+            .await?;
+    }
 
     Ok("certificate created".to_string())
 }
 
+/// Get the certificate already covering exactly this group of domains of the project, if any. The domains of other
+/// projects are refused
+async fn existing_certificate(
+    service: &GatewayService,
+    project_name: &ProjectName,
+    fqdns: &[FQDN],
+) -> Result<Option<(String, String)>, Error> {
+    let mut existing = None;
+
+    for fqdn in fqdns {
+        match service.project_details_for_custom_domain(fqdn).await {
+            Ok(CustomDomain {
+                project_name: owner,
+                ..
+            }) if &owner != project_name => {
+                return Err(Error::from_kind(ErrorKind::CustomDomainAlreadyExists))
+            }
+            Ok(CustomDomain {
+                certificate,
+                private_key,
+                certificate_group,
+                ..
+            }) if certificate_group == fqdns[0] => existing = Some((certificate, private_key)),
+            Ok(_) => return Ok(None),
+            Err(err) if err.kind() == ErrorKind::CustomDomainNotFound => return Ok(None),
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(existing)
+}
+
+#[instrument(skip(service))]
+async fn get_project_traffic(
+    State(RouterState { service, .. }): State<RouterState>,
+    ScopedUser { scope, .. }: ScopedUser,
+) -> Result<AxumJson<stats::TrafficResponse>, Error> {
+    service.find_project(&scope).await?;
+
+    Ok(AxumJson(service.traffic().summary(&scope)))
+}
+
+#[instrument(skip(service))]
+async fn get_project_events(
+    State(RouterState { service, .. }): State<RouterState>,
+    ScopedUser { scope, .. }: ScopedUser,
+) -> Result<AxumJson<Vec<project::Event>>, Error> {
+    service.find_project(&scope).await?;
+
+    Ok(AxumJson(service.project_events(&scope).await?))
+}
+
+#[instrument(skip(service))]
+async fn get_project_instances(
+    State(RouterState { service, .. }): State<RouterState>,
+    ScopedUser { scope, .. }: ScopedUser,
+) -> Result<AxumJson<Vec<instance::Response>>, Error> {
+    let container = service
+        .find_project(&scope)
+        .await?
+        .target_ip()?
+        .map(|target_ip| SocketAddr::new(target_ip, 8000));
+
+    Ok(AxumJson(service.balancer().instances(&scope, container)))
+}
+
+/// Let the deployer of a project through to the routes it calls on the gateway, which it proves by sending the admin
+/// secret it was started with. Requests without it need an admin claim to get past the [ScopedLayer] on these routes.
+async fn deployer_claim(
+    service: Arc<GatewayService>,
+    project_name: ProjectName,
+    mut req: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    if let Ok(Some(XShuttleAdminSecret(secret))) = req.headers().typed_try_get() {
+        match service.control_key_from_project_name(&project_name).await {
+            Ok(control_key) if control_key == secret => {
+                req.extensions_mut()
+                    .insert(Claim::new(project_name.to_string(), vec![Scope::Deployer]));
+            }
+            Ok(_) => trace!(%project_name, "admin secret does not match the project"),
+            Err(error) => trace!(%project_name, error = %error, "failed to get the control key"),
+        }
+    }
+
+    next.run(req).await
+}
+
+/// Called by an instance of a project, on the internal network like the build queue, to get requests and keep getting
+/// them
+#[instrument(skip(service))]
+async fn post_instance(
+    State(RouterState { service, .. }): State<RouterState>,
+    Path(project_name): Path<ProjectName>,
+    AxumJson(registration): AxumJson<instance::Registration>,
+) -> Result<AxumJson<()>, Error> {
+    if registration.weight == 0 {
+        return Err(Error::from_kind(ErrorKind::InvalidInstance));
+    }
+
+    service.find_project(&project_name).await?;
+    service.balancer().register(&project_name, &registration);
+
+    Ok(AxumJson(()))
+}
+
+#[instrument(skip(service))]
+async fn delete_instance(
+    State(RouterState { service, .. }): State<RouterState>,
+    Path(project_name): Path<ProjectName>,
+    AxumJson(registration): AxumJson<instance::Registration>,
+) -> Result<AxumJson<()>, Error> {
+    if !service
+        .balancer()
+        .deregister(&project_name, registration.address)
+    {
+        trace!(%project_name, address = %registration.address, "instance was not registered");
+    }
+
+    Ok(AxumJson(()))
+}
+
+/// Called by the deployer of a project when a deployment starts or stops running, so that no more requests go out on
+/// the connections made to the one before
+#[instrument(skip(service))]
+async fn delete_backend(
+    State(RouterState { service, .. }): State<RouterState>,
+    Path(project_name): Path<ProjectName>,
+) -> Result<AxumJson<()>, Error> {
+    service.backend_pool().invalidate(&project_name);
+
+    Ok(AxumJson(()))
+}
+
+#[instrument(skip(service))]
+async fn get_host_header(
+    State(RouterState { service, .. }): State<RouterState>,
+    ScopedUser { scope, .. }: ScopedUser,
+) -> Result<AxumJson<host_header::Policy>, Error> {
+    service.find_project(&scope).await?;
+
+    Ok(AxumJson(service.host_header_for_project(&scope).await?))
+}
+
+#[instrument(skip(service))]
+async fn post_host_header(
+    State(RouterState { service, .. }): State<RouterState>,
+    ScopedUser { scope, .. }: ScopedUser,
+    AxumJson(policy): AxumJson<host_header::Policy>,
+) -> Result<AxumJson<host_header::Policy>, Error> {
+    service.find_project(&scope).await?;
+    service.set_host_header(&scope, &policy).await?;
+
+    Ok(AxumJson(service.host_header_for_project(&scope).await?))
+}
+
+#[instrument(skip(service))]
+async fn get_basic_auth(
+    State(RouterState { service, .. }): State<RouterState>,
+    ScopedUser { scope, .. }: ScopedUser,
+) -> Result<AxumJson<basic_auth::Response>, Error> {
+    service.find_project(&scope).await?;
+
+    let basic_auth = service.basic_auth_for_project(&scope).await?;
+
+    Ok(AxumJson(
+        basic_auth
+            .map(|basic_auth| basic_auth.response())
+            .unwrap_or_default(),
+    ))
+}
+
+// The config holds a password, so it is kept out of the span
+#[instrument(skip(service, config))]
+async fn post_basic_auth(
+    State(RouterState { service, .. }): State<RouterState>,
+    ScopedUser { scope, .. }: ScopedUser,
+    AxumJson(config): AxumJson<basic_auth::Config>,
+) -> Result<AxumJson<basic_auth::Response>, Error> {
+    service.find_project(&scope).await?;
+    service.set_basic_auth(&scope, &config).await?;
+
+    Ok(AxumJson(basic_auth::Response {
+        username: Some(config.username),
+        exempt: config.exempt,
+    }))
+}
+
+#[instrument(skip(service))]
+async fn delete_basic_auth(
+    State(RouterState { service, .. }): State<RouterState>,
+    ScopedUser { scope, .. }: ScopedUser,
+) -> Result<AxumJson<basic_auth::Response>, Error> {
+    service.find_project(&scope).await?;
+    service.remove_basic_auth(&scope).await?;
+
+    Ok(AxumJson(basic_auth::Response::default()))
+}
+
+#[instrument(skip(service))]
+async fn get_proxy_timeouts(
+    State(RouterState { service, .. }): State<RouterState>,
+    ScopedUser { scope, .. }: ScopedUser,
+) -> Result<AxumJson<timeouts::Config>, Error> {
+    service.find_project(&scope).await?;
+
+    Ok(AxumJson(service.proxy_timeouts_for_project(&scope).await?))
+}
+
+#[instrument(skip(service))]
+async fn get_pin(
+    State(RouterState { service, .. }): State<RouterState>,
+    ScopedUser { scope, .. }: ScopedUser,
+) -> Result<AxumJson<project::Pin>, Error> {
+    service.find_project(&scope).await?;
+
+    Ok(AxumJson(project::Pin {
+        pinned: service.keep_warm().is_pinned(&scope),
+    }))
+}
+
+#[instrument(skip(service))]
+async fn post_pin(
+    State(RouterState { service, .. }): State<RouterState>,
+    ScopedUser { scope, .. }: ScopedUser,
+) -> Result<AxumJson<project::Pin>, Error> {
+    service.find_project(&scope).await?;
+    service.pin_project(&scope).await?;
+
+    Ok(AxumJson(project::Pin { pinned: true }))
+}
+
+#[instrument(skip(service))]
+async fn delete_pin(
+    State(RouterState { service, .. }): State<RouterState>,
+    ScopedUser { scope, .. }: ScopedUser,
+) -> Result<AxumJson<project::Pin>, Error> {
+    service.find_project(&scope).await?;
+    service.unpin_project(&scope).await?;
+
+    Ok(AxumJson(project::Pin { pinned: false }))
+}
+
+#[instrument(skip(service))]
+async fn get_transfers(
+    State(RouterState { service, .. }): State<RouterState>,
+    User { name, .. }: User,
+) -> Result<AxumJson<Vec<project::Transfer>>, Error> {
+    Ok(AxumJson(service.transfers_for_account(&name).await?))
+}
+
+#[instrument(skip(service))]
+async fn post_transfer(
+    State(RouterState { service, .. }): State<RouterState>,
+    ScopedUser { scope, .. }: ScopedUser,
+    AxumJson(request): AxumJson<project::TransferRequest>,
+) -> Result<AxumJson<project::Transfer>, Error> {
+    let to_account = request
+        .to_account
+        .parse()
+        .map_err(|_| Error::from_kind(ErrorKind::InvalidTransfer))?;
+
+    // Admins can act on projects they do not own, but the offer is always made on behalf of the owner
+    let from_account = service.account_name_from_project(&scope).await?;
+
+    Ok(AxumJson(
+        service
+            .create_transfer(&scope, &from_account, &to_account)
+            .await?,
+    ))
+}
+
+#[instrument(skip(service))]
+async fn delete_transfer(
+    State(RouterState { service, .. }): State<RouterState>,
+    User { name, .. }: User,
+    Path(project): Path<ProjectName>,
+) -> Result<AxumJson<Vec<project::Transfer>>, Error> {
+    service.cancel_transfer(&project, &name).await?;
+
+    // The transfers still pending, as there is nothing left of the cancelled one to show
+    Ok(AxumJson(service.transfers_for_account(&name).await?))
+}
+
+#[instrument(skip(service, claim))]
+async fn post_transfer_accept(
+    State(RouterState { service, .. }): State<RouterState>,
+    User { name, claim, .. }: User,
+    Path(project): Path<ProjectName>,
+) -> Result<AxumJson<project::Response>, Error> {
+    let is_admin = claim.scopes.contains(&Scope::Admin);
+
+    service.accept_transfer(&project, &name, is_admin).await?;
+
+    Ok(AxumJson(project::Response {
+        name: project.to_string(),
+        state: service.find_project(&project).await?.into(),
+    }))
+}
+
+#[instrument(skip(service))]
+async fn get_header_limits(
+    State(RouterState { service, .. }): State<RouterState>,
+    ScopedUser { scope, .. }: ScopedUser,
+) -> Result<AxumJson<header_limits::Config>, Error> {
+    service.find_project(&scope).await?;
+
+    Ok(AxumJson(service.header_limits_for_project(&scope).await?))
+}
+
+#[instrument(skip(service))]
+async fn post_header_limits(
+    State(RouterState { service, .. }): State<RouterState>,
+    ScopedUser { scope, .. }: ScopedUser,
+    AxumJson(config): AxumJson<header_limits::Config>,
+) -> Result<AxumJson<header_limits::Config>, Error> {
+    service.find_project(&scope).await?;
+    service.set_header_limits(&scope, &config).await?;
+
+    Ok(AxumJson(service.header_limits_for_project(&scope).await?))
+}
+
+#[instrument(skip(service))]
+async fn post_proxy_timeouts(
+    State(RouterState { service, .. }): State<RouterState>,
+    ScopedUser { scope, .. }: ScopedUser,
+    AxumJson(config): AxumJson<timeouts::Config>,
+) -> Result<AxumJson<timeouts::Config>, Error> {
+    service.find_project(&scope).await?;
+    service.set_proxy_timeouts(&scope, &config).await?;
+
+    Ok(AxumJson(service.proxy_timeouts_for_project(&scope).await?))
+}
+
+#[instrument(skip(service))]
+async fn get_response_headers(
+    State(RouterState { service, .. }): State<RouterState>,
+    ScopedUser { scope, .. }: ScopedUser,
+) -> Result<AxumJson<response_header::Rules>, Error> {
+    service.find_project(&scope).await?;
+
+    Ok(AxumJson(
+        service.response_headers_for_project(&scope).await?,
+    ))
+}
+
+#[instrument(skip(service))]
+async fn post_response_headers(
+    State(RouterState { service, .. }): State<RouterState>,
+    ScopedUser { scope, .. }: ScopedUser,
+    AxumJson(rules): AxumJson<response_header::Rules>,
+) -> Result<AxumJson<response_header::Rules>, Error> {
+    service.find_project(&scope).await?;
+    service.set_response_headers(&scope, &rules).await?;
+
+    Ok(AxumJson(
+        service.response_headers_for_project(&scope).await?,
+    ))
+}
+
+#[instrument(skip(service))]
+async fn get_ip_rules(
+    State(RouterState { service, .. }): State<RouterState>,
+    Path(project_name): Path<ProjectName>,
+) -> Result<AxumJson<Vec<ip_rule::Rule>>, Error> {
+    service.find_project(&project_name).await?;
+
+    Ok(AxumJson(service.ip_rules_for_project(&project_name).await?))
+}
+
+#[instrument(skip(service))]
+async fn post_ip_rule(
+    State(RouterState { service, .. }): State<RouterState>,
+    Path(project_name): Path<ProjectName>,
+    AxumJson(rule): AxumJson<ip_rule::Rule>,
+) -> Result<AxumJson<Vec<ip_rule::Rule>>, Error> {
+    service.find_project(&project_name).await?;
+
+    let net = parse_cidr(&rule.cidr)?;
+    service
+        .set_ip_rule(&project_name, &net, rule.action)
+        .await?;
+
+    Ok(AxumJson(service.ip_rules_for_project(&project_name).await?))
+}
+
+#[instrument(skip(service))]
+async fn delete_ip_rule(
+    State(RouterState { service, .. }): State<RouterState>,
+    Path(project_name): Path<ProjectName>,
+    AxumJson(request): AxumJson<ip_rule::DeleteRequest>,
+) -> Result<AxumJson<Vec<ip_rule::Rule>>, Error> {
+    let net = parse_cidr(&request.cidr)?;
+    service.delete_ip_rule(&project_name, &net).await?;
+
+    Ok(AxumJson(service.ip_rules_for_project(&project_name).await?))
+}
+
 async fn get_projects(
     State(RouterState { service, .. }): State<RouterState>,
 ) -> Result<AxumJson<Vec<project::AdminResponse>>, Error> {
@@ -397,12 +1026,17 @@ pub(crate) struct RouterState {
     pub service: Arc<GatewayService>,
     pub sender: Sender<BoxedTask>,
     pub running_builds: Arc<Mutex<TtlCache<Uuid, ()>>>,
+    pub user_proxy: Option<Handle>,
+    pub connections: Option<ConnectionLimit>,
 }
 
 pub struct ApiBuilder {
     router: Router<RouterState>,
     service: Option<Arc<GatewayService>>,
     sender: Option<Sender<BoxedTask>>,
+    user_proxy: Option<Handle>,
+    connections: Option<ConnectionLimit>,
+    limits: ControlLimits,
     bind: Option<SocketAddr>,
 }
 
@@ -418,6 +1052,9 @@ impl ApiBuilder {
             router: Router::new(),
             service: None,
             sender: None,
+            user_proxy: None,
+            connections: None,
+            limits: Default::default(),
             bind: None,
         }
     }
@@ -430,12 +1067,25 @@ impl ApiBuilder {
                 post(create_acme_account.layer(ScopedLayer::new(vec![Scope::AcmeCreate]))),
             )
             .route(
-                "/admin/acme/request/:project_name/:fqdn",
+                "/admin/acme/request/:project_name/:fqdns",
                 post(
                     request_acme_certificate
                         .layer(ScopedLayer::new(vec![Scope::CustomDomainCreate])),
                 ),
             )
+            .route(
+                "/admin/acme/verification/:project_name/:fqdn",
+                get(get_domain_verification
+                    .layer(ScopedLayer::new(vec![Scope::CustomDomainCreate]))),
+            )
+            .route(
+                "/admin/tls/errors",
+                get(get_tls_errors.layer(ScopedLayer::new(vec![Scope::Admin]))),
+            )
+            .route(
+                "/admin/tls/reload/:fqdn",
+                post(reload_certificate.layer(ScopedLayer::new(vec![Scope::Admin]))),
+            )
             .layer(Extension(acme))
             .layer(Extension(resolver));
         self
@@ -451,6 +1101,25 @@ impl ApiBuilder {
         self
     }
 
+    /// Let readiness checks see whether the user proxy is accepting connections
+    pub fn with_user_proxy_handle(mut self, handle: Handle) -> Self {
+        self.user_proxy = Some(handle);
+        self
+    }
+
+    /// Report the connections held open by the user proxy in the status
+    pub fn with_user_proxy_connections(mut self, connections: ConnectionLimit) -> Self {
+        self.connections = Some(connections);
+        self
+    }
+
+    /// Limit the time, body size and number of the requests handled at once. They apply to every route, whenever
+    /// they were added.
+    pub fn with_limits(mut self, limits: ControlLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
     pub fn binding_to(mut self, addr: SocketAddr) -> Self {
         self.bind = Some(addr);
         self
@@ -473,13 +1142,34 @@ impl ApiBuilder {
     }
 
     pub fn with_default_routes(mut self) -> Self {
+        let service = self
+            .service
+            .clone()
+            .expect("a GatewayService is required for the default routes");
+        let deployer_auth = from_fn(
+            move |Path(project_name): Path<ProjectName>, req: Request<Body>, next: Next<Body>| {
+                deployer_claim(service.clone(), project_name, req, next)
+            },
+        );
+
         self.router = self
             .router
             .route("/", get(get_status))
+            .route("/healthz", get(get_healthz))
+            .route("/readyz", get(get_readyz))
+            .route("/version", get(get_version))
             .route(
                 "/projects",
                 get(get_projects_list.layer(ScopedLayer::new(vec![Scope::Project]))),
             )
+            .route(
+                "/account/usage",
+                get(get_account_usage.layer(ScopedLayer::new(vec![Scope::Project]))),
+            )
+            .route(
+                "/transfers",
+                get(get_transfers.layer(ScopedLayer::new(vec![Scope::Project]))),
+            )
             // .route(
             //     "/projects/:state",
             //     get(get_projects_list_with_filter.layer(ScopedLayer::new(vec![Scope::Project]))),
@@ -490,8 +1180,75 @@ impl ApiBuilder {
                     .delete(delete_project.layer(ScopedLayer::new(vec![Scope::ProjectCreate])))
                     .post(post_project.layer(ScopedLayer::new(vec![Scope::ProjectCreate]))),
             )
+            .route(
+                "/projects/:project_name/traffic",
+                get(get_project_traffic.layer(ScopedLayer::new(vec![Scope::Project]))),
+            )
+            .route(
+                "/projects/:project_name/events",
+                get(get_project_events.layer(ScopedLayer::new(vec![Scope::Project]))),
+            )
+            .route(
+                "/projects/:project_name/instances",
+                get(get_project_instances.layer(ScopedLayer::new(vec![Scope::Project]))),
+            )
+            .route(
+                "/projects/:project_name/host-header",
+                get(get_host_header.layer(ScopedLayer::new(vec![Scope::Project])))
+                    .post(post_host_header.layer(ScopedLayer::new(vec![Scope::ProjectCreate]))),
+            )
+            .route(
+                "/projects/:project_name/basic-auth",
+                get(get_basic_auth.layer(ScopedLayer::new(vec![Scope::Project])))
+                    .post(post_basic_auth.layer(ScopedLayer::new(vec![Scope::ProjectCreate])))
+                    .delete(delete_basic_auth.layer(ScopedLayer::new(vec![Scope::ProjectCreate]))),
+            )
+            .route(
+                "/projects/:project_name/timeouts",
+                get(get_proxy_timeouts.layer(ScopedLayer::new(vec![Scope::Project])))
+                    .post(post_proxy_timeouts.layer(ScopedLayer::new(vec![Scope::ProjectCreate]))),
+            )
+            .route(
+                "/projects/:project_name/pin",
+                get(get_pin.layer(ScopedLayer::new(vec![Scope::Project])))
+                    .post(post_pin.layer(ScopedLayer::new(vec![Scope::ProjectCreate])))
+                    .delete(delete_pin.layer(ScopedLayer::new(vec![Scope::ProjectCreate]))),
+            )
+            .route(
+                "/projects/:project_name/transfer",
+                post(post_transfer.layer(ScopedLayer::new(vec![Scope::ProjectCreate])))
+                    .delete(delete_transfer.layer(ScopedLayer::new(vec![Scope::ProjectCreate]))),
+            )
+            .route(
+                "/projects/:project_name/transfer/accept",
+                post(post_transfer_accept.layer(ScopedLayer::new(vec![Scope::ProjectCreate]))),
+            )
+            .route(
+                "/projects/:project_name/header-limits",
+                get(get_header_limits.layer(ScopedLayer::new(vec![Scope::Project])))
+                    .post(post_header_limits.layer(ScopedLayer::new(vec![Scope::ProjectCreate]))),
+            )
+            .route(
+                "/projects/:project_name/response-headers",
+                get(get_response_headers.layer(ScopedLayer::new(vec![Scope::Project]))).post(
+                    post_response_headers.layer(ScopedLayer::new(vec![Scope::ProjectCreate])),
+                ),
+            )
             .route("/projects/:project_name/*any", any(route_project))
             .route("/stats/load", post(post_load).delete(delete_load))
+            .route(
+                "/instances/:project_name",
+                post(post_instance)
+                    .delete(delete_instance)
+                    .layer(ScopedLayer::new(vec![Scope::Deployer]))
+                    .layer(deployer_auth.clone()),
+            )
+            .route(
+                "/backends/:project_name",
+                delete(delete_backend)
+                    .layer(ScopedLayer::new(vec![Scope::Deployer]))
+                    .layer(deployer_auth),
+            )
             .route(
                 "/admin/projects",
                 get(get_projects.layer(ScopedLayer::new(vec![Scope::Admin]))),
@@ -500,6 +1257,13 @@ impl ApiBuilder {
                 "/admin/revive",
                 post(revive_projects.layer(ScopedLayer::new(vec![Scope::Admin]))),
             )
+            .route(
+                "/admin/projects/:project_name/ip-rules",
+                get(get_ip_rules)
+                    .post(post_ip_rule)
+                    .delete(delete_ip_rule)
+                    .layer(ScopedLayer::new(vec![Scope::Admin])),
+            )
             .route(
                 "/admin/stats/load",
                 get(get_load_admin)
@@ -537,11 +1301,17 @@ impl ApiBuilder {
 
         let running_builds = Arc::new(Mutex::new(TtlCache::new(concurrent_builds)));
 
-        self.router.with_state(RouterState {
-            service,
-            sender,
-            running_builds,
-        })
+        let limiter = Limiter::new(self.limits);
+
+        self.router
+            .layer(from_fn(move |req, next| limiter.clone().enforce(req, next)))
+            .with_state(RouterState {
+                service,
+                sender,
+                running_builds,
+                user_proxy: self.user_proxy,
+                connections: self.connections,
+            })
     }
 
     pub fn serve(self) -> impl Future<Output = Result<(), hyper::Error>> {
@@ -717,6 +1487,112 @@ pub mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn instances_and_backends_need_the_deployer_secret() -> anyhow::Result<()> {
+        let world = World::new().await;
+        let service = Arc::new(GatewayService::init(world.args(), world.pool()).await);
+
+        let (sender, mut receiver) = channel::<BoxedTask>(256);
+        tokio::spawn(async move {
+            while receiver.recv().await.is_some() {
+                // do not do any work with inbound requests
+            }
+        });
+
+        let mut router = ApiBuilder::new()
+            .with_service(Arc::clone(&service))
+            .with_sender(sender)
+            .with_default_routes()
+            .with_auth_service(world.context().auth_uri)
+            .into_router();
+
+        let neo_key = world.create_user("neo");
+        let authorization = Authorization::bearer(&neo_key).unwrap();
+
+        router
+            .call(
+                Request::builder()
+                    .method("POST")
+                    .uri("/projects/matrix")
+                    .body(Body::empty())
+                    .unwrap()
+                    .with_header(&authorization),
+            )
+            .map_ok(|resp| assert_eq!(resp.status(), StatusCode::OK))
+            .await
+            .unwrap();
+
+        let post_instance = || {
+            let registration = instance::Registration {
+                address: "10.0.0.7:8000".parse().unwrap(),
+                weight: 1,
+            };
+
+            Request::builder()
+                .method("POST")
+                .uri("/instances/matrix")
+                .header("Content-Type", "application/json")
+                .body(Body::from(serde_json::to_vec(&registration).unwrap()))
+                .unwrap()
+        };
+
+        router
+            .call(post_instance())
+            .map_ok(|resp| assert_eq!(resp.status(), StatusCode::UNAUTHORIZED))
+            .await
+            .unwrap();
+
+        router
+            .call(post_instance().with_header(&XShuttleAdminSecret("guess".to_string())))
+            .map_ok(|resp| assert_eq!(resp.status(), StatusCode::UNAUTHORIZED))
+            .await
+            .unwrap();
+
+        router
+            .call(post_instance().with_header(&authorization))
+            .map_ok(|resp| assert_eq!(resp.status(), StatusCode::FORBIDDEN))
+            .await
+            .unwrap();
+
+        let control_key = service
+            .control_key_from_project_name(&"matrix".parse().unwrap())
+            .await?;
+
+        router
+            .call(post_instance().with_header(&XShuttleAdminSecret(control_key.clone())))
+            .map_ok(|resp| assert_eq!(resp.status(), StatusCode::OK))
+            .await
+            .unwrap();
+
+        let delete_backend = || {
+            Request::builder()
+                .method("DELETE")
+                .uri("/backends/matrix")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        router
+            .call(delete_backend())
+            .map_ok(|resp| assert_eq!(resp.status(), StatusCode::UNAUTHORIZED))
+            .await
+            .unwrap();
+
+        router
+            .call(delete_backend().with_header(&authorization))
+            .map_ok(|resp| assert_eq!(resp.status(), StatusCode::FORBIDDEN))
+            .await
+            .unwrap();
+
+        router
+            .call(delete_backend().with_header(&XShuttleAdminSecret(control_key)))
+            .map_ok(|resp| assert_eq!(resp.status(), StatusCode::OK))
+            .await
+            .unwrap();
+
+        Ok(())
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn status() {
         let world = World::new().await;
@@ -754,6 +1630,10 @@ pub mod tests {
         let resp = router.call(get_status()).await.unwrap();
         assert_eq!(resp.status(), StatusCode::OK);
 
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["queue"]["policy"], "backpressure");
+
         let matrix: ProjectName = "matrix".parse().unwrap();
 
         let neo_key = world.create_user("neo");
@@ -783,4 +1663,58 @@ pub mod tests {
         let resp = router.call(get_status()).await.unwrap();
         assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
     }
+
+    #[tokio::test]
+    async fn health_and_readiness() {
+        let world = World::new().await;
+        let service = Arc::new(GatewayService::init(world.args(), world.pool()).await);
+
+        let (sender, mut receiver) = channel::<BoxedTask>(1);
+        let worker = tokio::spawn(async move { while receiver.recv().await.is_some() {} });
+        let user_proxy = Handle::new();
+
+        let mut router = ApiBuilder::new()
+            .with_service(Arc::clone(&service))
+            .with_sender(sender)
+            .with_user_proxy_handle(user_proxy)
+            .with_default_routes()
+            .with_auth_service(world.context().auth_uri)
+            .into_router();
+
+        let get = |uri: &str| {
+            Request::builder()
+                .method("GET")
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let resp = router.call(get("/healthz")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let resp = router.call(get("/version")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["protocol_version"], version::PROTOCOL_VERSION);
+
+        // The user proxy was never started
+        let resp = router.call(get("/readyz")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["database"], "ok");
+        assert_eq!(body["worker"], "ok");
+        assert_eq!(body["user_proxy"], "unavailable");
+
+        worker.abort();
+        let _ = worker.await;
+
+        let resp = router.call(get("/readyz")).await.unwrap();
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["worker"], "unavailable");
+    }
 }
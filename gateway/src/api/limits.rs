@@ -0,0 +1,298 @@
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::body::Body;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use futures::TryStreamExt;
+use http::header::{CONNECTION, CONTENT_LENGTH, UPGRADE};
+use http::Request;
+use tokio::sync::Semaphore;
+use tracing::warn;
+
+use crate::{Error, ErrorKind};
+
+/// Protects the control API from requests which are too slow, too large or too many at once, so that a stuck upload
+/// or a burst of requests cannot take all of its capacity. The user proxy has limits of its own.
+///
+/// Websocket upgrades, like the ones logs are streamed over, are meant to stay open. They are neither timed nor counted
+/// against the concurrent requests, but any other request asking for an upgrade is.
+#[derive(Clone, Debug, Default)]
+pub struct ControlLimits {
+    /// How long a request has to be handled in, reading its body included
+    pub request_timeout: Option<Duration>,
+    pub max_body_size: Option<u64>,
+    /// Requests over it are turned away straight away rather than queued
+    pub max_concurrent_requests: Option<usize>,
+}
+
+#[derive(Clone)]
+pub(crate) struct Limiter {
+    limits: ControlLimits,
+    slots: Option<Arc<Semaphore>>,
+}
+
+impl Limiter {
+    pub(crate) fn new(limits: ControlLimits) -> Self {
+        Self {
+            slots: limits
+                .max_concurrent_requests
+                .map(|max| Arc::new(Semaphore::new(max))),
+            limits,
+        }
+    }
+
+    pub(crate) async fn enforce(self, req: Request<Body>, next: Next<Body>) -> Response {
+        let req = match self.limits.max_body_size {
+            Some(max) => match limit_body(req, max) {
+                Ok(req) => req,
+                Err(error) => return error.into_response(),
+            },
+            None => req,
+        };
+
+        if is_websocket_upgrade(&req) {
+            return next.run(req).await;
+        }
+
+        let _slot = match &self.slots {
+            Some(slots) => match slots.clone().try_acquire_owned() {
+                Ok(slot) => Some(slot),
+                Err(_) => {
+                    warn!(uri = %req.uri(), "too many concurrent control requests, turning one away");
+                    return Error::from_kind(ErrorKind::ServiceUnavailable).into_response();
+                }
+            },
+            None => None,
+        };
+
+        match self.limits.request_timeout {
+            Some(request_timeout) => {
+                match tokio::time::timeout(request_timeout, next.run(req)).await {
+                    Ok(response) => response,
+                    Err(_) => {
+                        warn!("control request was not handled in time");
+                        Error::from_kind(ErrorKind::RequestTimeout).into_response()
+                    }
+                }
+            }
+            None => next.run(req).await,
+        }
+    }
+}
+
+/// Whether `req` asks to become a websocket, which takes both its `Connection` header listing `upgrade` and its
+/// `Upgrade` header naming `websocket`
+fn is_websocket_upgrade<B>(req: &Request<B>) -> bool {
+    let connection_upgrade = req
+        .headers()
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case("upgrade"));
+    let upgrade_websocket = req
+        .headers()
+        .get(UPGRADE)
+        .and_then(|value| value.to_str().ok())
+        .map_or(false, |value| {
+            value.trim().eq_ignore_ascii_case("websocket")
+        });
+
+    connection_upgrade && upgrade_websocket
+}
+
+/// Turn away a request which says its body is over `max`, and cut off a body which turns out to be over it while read
+fn limit_body(req: Request<Body>, max: u64) -> Result<Request<Body>, Error> {
+    let content_length = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse::<u64>().ok());
+
+    if content_length.map_or(false, |length| length > max) {
+        return Err(Error::from_kind(ErrorKind::RequestTooLarge));
+    }
+
+    let mut read = 0;
+
+    Ok(req.map(|body| {
+        Body::wrap_stream(
+            body.map_err(|error| io::Error::new(io::ErrorKind::Other, error))
+                .and_then(move |chunk| {
+                    read += chunk.len() as u64;
+
+                    futures::future::ready(if read > max {
+                        Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "the request body is too large",
+                        ))
+                    } else {
+                        Ok(chunk)
+                    })
+                }),
+        )
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::body::Body;
+    use axum::middleware::from_fn;
+    use axum::routing::post;
+    use axum::Router;
+    use http::{Request, StatusCode};
+    use tokio::sync::oneshot;
+    use tower::ServiceExt;
+
+    use super::{ControlLimits, Limiter};
+
+    fn router(limits: ControlLimits) -> Router {
+        let limiter = Limiter::new(limits);
+
+        Router::new()
+            .route(
+                "/slow",
+                post(|| async {
+                    tokio::time::sleep(Duration::from_millis(200)).await;
+                }),
+            )
+            .route(
+                "/echo",
+                post(|body: String| async move { body.len().to_string() }),
+            )
+            .layer(from_fn(move |req, next| limiter.clone().enforce(req, next)))
+    }
+
+    fn post_to(uri: &str, body: &'static str) -> Request<Body> {
+        Request::post(uri).body(Body::from(body)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn request_timeout() {
+        let router = router(ControlLimits {
+            request_timeout: Some(Duration::from_millis(50)),
+            ..Default::default()
+        });
+
+        let response = router.clone().oneshot(post_to("/slow", "")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::REQUEST_TIMEOUT);
+
+        let response = router.oneshot(post_to("/echo", "neo")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn only_websocket_upgrades_are_exempt() {
+        let router = router(ControlLimits {
+            request_timeout: Some(Duration::from_millis(50)),
+            max_body_size: Some(4),
+            ..Default::default()
+        });
+
+        let websocket = || {
+            Request::post("/slow")
+                .header("Connection", "keep-alive, Upgrade")
+                .header("Upgrade", "websocket")
+        };
+
+        let response = router
+            .clone()
+            .oneshot(websocket().body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = router
+            .clone()
+            .oneshot(
+                websocket()
+                    .header("Content-Length", "7")
+                    .body(Body::from("trinity"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        // Asking for an upgrade without it being to a websocket, or without the connection header, is not enough
+        for request in [
+            Request::post("/slow").header("Upgrade", "websocket"),
+            Request::post("/slow")
+                .header("Connection", "upgrade")
+                .header("Upgrade", "h2c"),
+        ] {
+            let response = router
+                .clone()
+                .oneshot(request.body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::REQUEST_TIMEOUT);
+        }
+    }
+
+    #[tokio::test]
+    async fn max_body_size() {
+        let router = router(ControlLimits {
+            max_body_size: Some(4),
+            ..Default::default()
+        });
+
+        let response = router
+            .clone()
+            .oneshot(post_to("/echo", "trinity"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        // Without a length up front, the body is cut off once it goes over
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            sender.send_data("tri".into()).await.unwrap();
+            sender.send_data("nity".into()).await.unwrap();
+        });
+        let response = router
+            .clone()
+            .oneshot(Request::post("/echo").body(body).unwrap())
+            .await
+            .unwrap();
+        assert_ne!(response.status(), StatusCode::OK);
+
+        let response = router.oneshot(post_to("/echo", "neo")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn max_concurrent_requests() {
+        let router = router(ControlLimits {
+            max_concurrent_requests: Some(1),
+            ..Default::default()
+        });
+
+        let (started_send, started_recv) = oneshot::channel();
+        let slow = tokio::spawn({
+            let router = router.clone();
+            async move {
+                let response = router.oneshot(post_to("/slow", ""));
+                started_send.send(()).unwrap();
+                response.await.unwrap()
+            }
+        });
+        started_recv.await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let response = router
+            .clone()
+            .oneshot(post_to("/echo", "neo"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        assert_eq!(slow.await.unwrap().status(), StatusCode::OK);
+        let response = router.oneshot(post_to("/echo", "neo")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
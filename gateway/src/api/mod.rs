@@ -1,3 +1,4 @@
 mod auth_layer;
 
 pub mod latest;
+pub mod limits;
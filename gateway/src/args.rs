@@ -1,8 +1,13 @@
-use std::{net::SocketAddr, path::PathBuf};
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
 
 use clap::{Parser, Subcommand, ValueEnum};
 use fqdn::FQDN;
 use http::Uri;
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+
+use crate::ip_filter::parse_cidr;
+use crate::AccountName;
 
 #[derive(Parser, Debug)]
 pub struct Args {
@@ -10,6 +15,10 @@ pub struct Args {
     #[arg(long, default_value = "./")]
     pub state: PathBuf,
 
+    /// Number of threads the async runtime runs tasks on. One for every CPU core when not set
+    #[arg(long)]
+    pub worker_threads: Option<usize>,
+
     #[command(subcommand)]
     pub command: Commands,
 }
@@ -20,6 +29,46 @@ pub enum UseTls {
     Enable,
 }
 
+/// Application protocols the user proxy can negotiate with clients over TLS
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum AlpnProtocol {
+    #[value(name = "http/1.1")]
+    Http1,
+    H2,
+}
+
+impl AlpnProtocol {
+    /// ID of the protocol in the TLS handshake
+    pub fn id(&self) -> &'static [u8] {
+        match self {
+            Self::Http1 => b"http/1.1",
+            Self::H2 => b"h2",
+        }
+    }
+}
+
+/// What to do with a new task when the worker queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum QueueFullPolicy {
+    /// Wait for room in the queue, up to the send timeout
+    Backpressure,
+    /// Reject the task straight away
+    FailFast,
+}
+
+/// What the proxy does with requests for a project which is in an error state
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ErroredProjectPolicy {
+    /// Answer with a 503 straight away
+    Report,
+    /// Answer with a 503, but start the project again in the background
+    Recover,
+    /// Start the project again and hold the request until it is back, or
+    /// answer with a 503 when it is not back in time
+    Hold,
+}
+
 #[derive(Subcommand, Debug)]
 pub enum Commands {
     Start(StartArgs),
@@ -33,12 +82,84 @@ pub struct StartArgs {
     /// Address to bind the bouncer service to
     #[arg(long, default_value = "127.0.0.1:7999")]
     pub bouncer: SocketAddr,
+    /// Do not start the bouncer, for setups which need neither the
+    /// redirect to HTTPS nor HTTP-01 challenges to be answered
+    #[arg(long, conflicts_with = "bouncer")]
+    pub no_bouncer: bool,
+    /// Redirect requests for hosts which are not a project to this
+    /// URL instead of answering them with a not found error
+    #[arg(long)]
+    pub unknown_host_redirect: Option<Uri>,
+    /// HTML page to answer requests for projects which have nothing
+    /// deployed with, instead of the default error. It is served
+    /// with a 503 status
+    #[arg(long)]
+    pub not_deployed_page: Option<PathBuf>,
+    /// Redirect requests for the bare public domain, which is not a
+    /// project, to this URL
+    #[arg(long, conflicts_with_all = ["apex_page", "apex_status"])]
+    pub apex_redirect: Option<Uri>,
+    /// HTML page to answer requests for the bare public domain with
+    #[arg(long, conflicts_with = "apex_status")]
+    pub apex_page: Option<PathBuf>,
+    /// Status to answer requests for the bare public domain with,
+    /// like 404 or 410. They are answered like unknown hosts when
+    /// none of the apex options is given
+    #[arg(long)]
+    pub apex_status: Option<http::StatusCode>,
+    /// Maximum number of connections the user proxy holds open at
+    /// once, across all projects. Unlimited when not set
+    #[arg(long)]
+    pub max_connections: Option<usize>,
+    /// Seconds the control API has to handle a request in, reading
+    /// its body included. Unlimited when not set
+    #[arg(long)]
+    pub control_request_timeout: Option<u64>,
+    /// Largest request body, in bytes, the control API accepts, like
+    /// the archive of a deployment. Unlimited when not set
+    #[arg(long)]
+    pub control_max_body_size: Option<u64>,
+    /// Maximum number of requests the control API handles at once.
+    /// Requests over it are answered with a 503. Unlimited when not set
+    #[arg(long)]
+    pub control_max_concurrent_requests: Option<usize>,
+    /// Addresses, or CIDRs, of load balancers which relay connections
+    /// to the user proxy and bouncer with a PROXY protocol header
+    /// (version 1 or 2) giving the actual client. Connections from
+    /// them must start with one, and no other connection is trusted
+    /// to have one
+    #[arg(long, value_delimiter = ',', value_parser = parse_trusted_peer)]
+    pub proxy_protocol_from: Vec<IpNet>,
     /// Address to bind the user proxy to
     #[arg(long, default_value = "127.0.0.1:8000")]
     pub user: SocketAddr,
     /// Allows to disable the use of TLS in the user proxy service (DANGEROUS)
     #[arg(long, default_value = "enable")]
     pub use_tls: UseTls,
+    /// Number of TLS sessions the user proxy remembers for clients
+    /// resuming them. 0 turns the session cache off
+    #[arg(long, default_value = "256")]
+    pub tls_session_cache_size: usize,
+    /// File of session ticket keys shared by all gateway replicas,
+    /// one base64 encoded 32 byte key per line with the current key
+    /// first. Every process uses a key of its own when not set
+    #[arg(long)]
+    pub tls_ticket_keys: Option<PathBuf>,
+    /// Protocols the user proxy offers to clients over TLS, in order
+    /// of preference. A client which asks for none of them is turned
+    /// away during the handshake
+    #[arg(long, value_delimiter = ',', default_value = "http/1.1")]
+    pub tls_alpn_protocols: Vec<AlpnProtocol>,
+    /// How often all projects have their health checked, in seconds
+    /// or as a duration like `2m`. A sweep which takes longer than
+    /// this delays the next one rather than having them pile up
+    #[arg(long, default_value = "60", value_parser = parse_interval)]
+    pub health_check_interval: Duration,
+    /// Certificates are renewed once they expire within this long,
+    /// in seconds or as a duration like `30days`. This covers the
+    /// certificate of the gateway and those of custom domains
+    #[arg(long, default_value = "30days", value_parser = parse_interval)]
+    pub certificate_renewal_threshold: Duration,
     #[command(flatten)]
     pub context: ContextArgs,
 }
@@ -68,4 +189,177 @@ pub struct ContextArgs {
     /// The path to the docker daemon socket
     #[arg(long, default_value = "/var/run/docker.sock")]
     pub docker_host: String,
+    /// Maximum number of idle keep-alive connections the proxy keeps
+    /// open to each project
+    #[arg(long, default_value = "32")]
+    pub proxy_max_idle_connections: usize,
+    /// Seconds after which an idle keep-alive connection to a project
+    /// is closed
+    #[arg(long, default_value = "90")]
+    pub proxy_idle_timeout: u64,
+    /// Most bytes all the headers of a request to a project, or of
+    /// its response, can take together before it is turned away.
+    /// Projects can set limits of their own
+    #[arg(long, default_value = "32768")]
+    pub proxy_max_headers_size: u64,
+    /// Most headers a request to a project, or its response, can have
+    #[arg(long, default_value = "100")]
+    pub proxy_max_headers: u64,
+    /// Most bytes the name and value of any one header can take
+    #[arg(long, default_value = "8192")]
+    pub proxy_max_header_size: u64,
+    /// What to do with new work when the worker queue is full
+    #[arg(long, default_value = "backpressure")]
+    pub queue_full_policy: QueueFullPolicy,
+    /// Seconds to wait for room in a full worker queue when using
+    /// the `backpressure` policy
+    #[arg(long, default_value = "9")]
+    pub queue_send_timeout: u64,
+    /// Windows, in seconds, over which the traffic stats of projects
+    /// are reported
+    #[arg(long, value_delimiter = ',', default_value = "60,300,3600")]
+    pub traffic_windows: Vec<u64>,
+    /// Seconds a registered instance of a project is sent requests for
+    /// without registering again
+    #[arg(long, default_value = "30")]
+    pub instance_ttl: u64,
+    /// Maximum number of projects an account can own, not counting
+    /// destroyed ones. Admins are not limited. Unlimited when not set
+    #[arg(long)]
+    pub max_projects_per_account: Option<u64>,
+    /// Maximum number of custom domains a project can have, to keep
+    /// certificate requests within the rate limits of the ACME
+    /// provider. Unlimited when not set
+    #[arg(long)]
+    pub max_custom_domains_per_project: Option<u64>,
+    /// Limits on the custom domains of the projects of particular
+    /// accounts, given as `<account>=<limit>`, which take the place of
+    /// `--max-custom-domains-per-project` for them
+    #[arg(long, value_delimiter = ',', value_parser = parse_account_limit)]
+    pub max_custom_domains_for: Vec<(AccountName, u64)>,
+    /// Add custom domains to projects without them proving they
+    /// control the domain through a TXT record. Only meant for
+    /// gateways which serve a single tenant
+    #[arg(long)]
+    pub no_domain_verification: bool,
+    /// What to do with requests for projects which are in an error
+    /// state
+    #[arg(long, default_value = "report")]
+    pub errored_project_policy: ErroredProjectPolicy,
+    /// Seconds to hold a request for an errored project while it is
+    /// started again, when using the `hold` policy
+    #[arg(long, default_value = "10")]
+    pub errored_project_hold: u64,
+    /// Tell clients why a project is in an error state. The reason can
+    /// contain details of the infrastructure, so it is only given in
+    /// logs by default
+    #[arg(long)]
+    pub expose_project_errors: bool,
+    /// Number of most recently active projects which are never put to
+    /// sleep, whatever their idle timers say, so that projects with
+    /// bursty but regular traffic do not keep starting cold
+    #[arg(long, default_value = "0")]
+    pub keep_warm_projects: usize,
+    /// Milliseconds after which a request to a project, counted until
+    /// the project sent the headers of its response, is logged as a
+    /// warning. Projects can set a threshold of their own. Slow
+    /// requests of projects without one are not logged when not set
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub slow_request_threshold: Option<u64>,
+}
+
+fn parse_trusted_peer(peer: &str) -> Result<IpNet, String> {
+    parse_cidr(peer).map_err(|_| format!("'{peer}' is neither an IP address nor a CIDR"))
+}
+
+fn parse_interval(interval: &str) -> Result<Duration, String> {
+    let duration = match interval.parse() {
+        Ok(seconds) => Duration::from_secs(seconds),
+        Err(_) => humantime::parse_duration(interval)
+            .map_err(|_| format!("'{interval}' is neither a number of seconds nor a duration"))?,
+    };
+
+    if duration.is_zero() {
+        return Err("the interval has to be longer than 0".to_string());
+    }
+
+    Ok(duration)
+}
+
+fn parse_account_limit(limit: &str) -> Result<(AccountName, u64), String> {
+    let (account_name, limit) = limit
+        .split_once('=')
+        .ok_or_else(|| format!("'{limit}' is not of the form <account>=<limit>"))?;
+
+    let account_name = account_name
+        .parse()
+        .map_err(|_| format!("'{account_name}' is not a valid account name"))?;
+    let limit = limit
+        .parse()
+        .map_err(|_| format!("'{limit}' is not a valid limit"))?;
+
+    Ok((account_name, limit))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use clap::Parser;
+
+    use super::{Args, Commands};
+
+    fn health_check_interval(interval: &str) -> Result<Duration, clap::Error> {
+        let args = Args::try_parse_from(["gateway", "start", "--health-check-interval", interval])?;
+        let Commands::Start(start_args) = args.command;
+
+        Ok(start_args.health_check_interval)
+    }
+
+    #[test]
+    fn parses_health_check_interval() {
+        let args = Args::try_parse_from(["gateway", "start"]).unwrap();
+        let Commands::Start(start_args) = args.command;
+        assert_eq!(start_args.health_check_interval, Duration::from_secs(60));
+
+        assert_eq!(
+            health_check_interval("90").unwrap(),
+            Duration::from_secs(90)
+        );
+        assert_eq!(
+            health_check_interval("2m 30s").unwrap(),
+            Duration::from_secs(150)
+        );
+        assert_eq!(
+            health_check_interval("500ms").unwrap(),
+            Duration::from_millis(500)
+        );
+
+        assert!(health_check_interval("0").is_err());
+        assert!(health_check_interval("0s").is_err());
+        assert!(health_check_interval("soon").is_err());
+    }
+
+    #[test]
+    fn parses_certificate_renewal_threshold() {
+        let args = Args::try_parse_from(["gateway", "start"]).unwrap();
+        let Commands::Start(start_args) = args.command;
+        assert_eq!(
+            start_args.certificate_renewal_threshold,
+            Duration::from_secs(30 * 24 * 60 * 60)
+        );
+
+        let args = Args::try_parse_from([
+            "gateway",
+            "start",
+            "--certificate-renewal-threshold",
+            "2weeks",
+        ])
+        .unwrap();
+        let Commands::Start(start_args) = args.command;
+        assert_eq!(
+            start_args.certificate_renewal_threshold,
+            Duration::from_secs(14 * 24 * 60 * 60)
+        );
+    }
 }
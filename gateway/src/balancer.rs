@@ -0,0 +1,382 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use shuttle_common::models::instance;
+use tracing::trace;
+
+use crate::ProjectName;
+
+/// How long an instance which failed a request is skipped for before it gets requests again
+const DOWN_FOR: Duration = Duration::from_secs(10);
+
+/// Spreads the requests of projects over their instances with a smooth weighted round-robin, so an instance with
+/// twice the weight gets twice the requests without getting them in bursts.
+///
+/// The container of a project is always one of its instances, with a weight of 1 unless it registered itself with
+/// another. Other instances register themselves, and have to keep doing so within the TTL to not be dropped. A project
+/// without any registered instances goes straight to its container, like it would without the balancer.
+///
+/// Health is tracked from the requests which go through the proxy: an instance which fails one is skipped for a
+/// while. When every instance of a project is down they are all tried anyway, since failing on the proxy would not
+/// help anyone.
+pub struct Balancer {
+    ttl: Duration,
+    projects: Mutex<HashMap<ProjectName, Vec<Instance>>>,
+}
+
+struct Instance {
+    address: SocketAddr,
+    weight: u32,
+    /// When the registration runs out, or nothing for the container of the project
+    expires: Option<Instant>,
+    down_until: Option<Instant>,
+    /// Running score of the smooth weighted round-robin
+    current: i64,
+}
+
+impl Instance {
+    fn is_up(&self, now: Instant) -> bool {
+        self.down_until.map_or(true, |down_until| down_until <= now)
+    }
+}
+
+impl Balancer {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            projects: Default::default(),
+        }
+    }
+
+    /// Add an instance to a project, or refresh its registration and weight when it is already there
+    pub fn register(&self, project_name: &ProjectName, registration: &instance::Registration) {
+        self.register_at(project_name, registration, Instant::now())
+    }
+
+    /// Take an instance out of a project, returning whether it was registered
+    pub fn deregister(&self, project_name: &ProjectName, address: SocketAddr) -> bool {
+        let mut projects = self.projects.lock().unwrap();
+        let Some(instances) = projects.get_mut(project_name) else {
+            return false;
+        };
+
+        let count = instances.len();
+        instances.retain(|instance| instance.expires.is_none() || instance.address != address);
+        let removed = instances.len() != count;
+
+        if !instances.iter().any(|instance| instance.expires.is_some()) {
+            projects.remove(project_name);
+        }
+
+        removed
+    }
+
+    /// Choose the instance of a project to send the next request to, given where its container is
+    pub fn pick(&self, project_name: &ProjectName, container: SocketAddr) -> SocketAddr {
+        self.pick_at(project_name, container, Instant::now())
+    }
+
+    /// Record whether a request sent to an instance of a project went through
+    pub fn report(&self, project_name: &ProjectName, address: SocketAddr, healthy: bool) {
+        self.report_at(project_name, address, healthy, Instant::now())
+    }
+
+    /// The instances of a project, its container included when it is known
+    pub fn instances(
+        &self,
+        project_name: &ProjectName,
+        container: Option<SocketAddr>,
+    ) -> Vec<instance::Response> {
+        self.instances_at(project_name, container, Instant::now())
+    }
+
+    fn register_at(
+        &self,
+        project_name: &ProjectName,
+        registration: &instance::Registration,
+        now: Instant,
+    ) {
+        let mut projects = self.projects.lock().unwrap();
+        let instances = projects.entry(project_name.clone()).or_default();
+        let expires = Some(now + self.ttl);
+
+        match instances
+            .iter_mut()
+            .find(|instance| instance.address == registration.address)
+        {
+            Some(instance) => {
+                instance.weight = registration.weight;
+                instance.expires = expires;
+            }
+            None => {
+                trace!(%project_name, address = %registration.address, "instance registered");
+                instances.push(Instance {
+                    address: registration.address,
+                    weight: registration.weight,
+                    expires,
+                    down_until: None,
+                    current: 0,
+                });
+            }
+        }
+    }
+
+    fn pick_at(
+        &self,
+        project_name: &ProjectName,
+        container: SocketAddr,
+        now: Instant,
+    ) -> SocketAddr {
+        let mut projects = self.projects.lock().unwrap();
+        let Some(instances) = projects.get_mut(project_name) else {
+            return container;
+        };
+
+        // Drop lapsed registrations and a container which moved, then make sure the current container is in
+        instances.retain(|instance| match instance.expires {
+            Some(expires) => expires > now,
+            None => instance.address == container,
+        });
+        if !instances.iter().any(|instance| instance.expires.is_some()) {
+            projects.remove(project_name);
+            return container;
+        }
+        if !instances
+            .iter()
+            .any(|instance| instance.address == container)
+        {
+            instances.push(Instance {
+                address: container,
+                weight: 1,
+                expires: None,
+                down_until: None,
+                current: 0,
+            });
+        }
+
+        let all_down = !instances.iter().any(|instance| instance.is_up(now));
+        let mut total = 0;
+        let mut best: Option<(usize, i64)> = None;
+
+        for (index, instance) in instances.iter_mut().enumerate() {
+            if !all_down && !instance.is_up(now) {
+                continue;
+            }
+
+            instance.current += i64::from(instance.weight);
+            total += i64::from(instance.weight);
+
+            if best.map_or(true, |(_, current)| current < instance.current) {
+                best = Some((index, instance.current));
+            }
+        }
+
+        let (best, _) = best.expect("a project with instances to have one to choose");
+        let chosen = &mut instances[best];
+        chosen.current -= total;
+
+        chosen.address
+    }
+
+    fn report_at(
+        &self,
+        project_name: &ProjectName,
+        address: SocketAddr,
+        healthy: bool,
+        now: Instant,
+    ) {
+        let mut projects = self.projects.lock().unwrap();
+        let Some(instance) = projects.get_mut(project_name).and_then(|instances| {
+            instances
+                .iter_mut()
+                .find(|instance| instance.address == address)
+        }) else {
+            return;
+        };
+
+        if healthy {
+            instance.down_until = None;
+        } else {
+            trace!(%project_name, %address, "instance failed a request, skipping it for a while");
+            instance.down_until = Some(now + DOWN_FOR);
+        }
+    }
+
+    fn instances_at(
+        &self,
+        project_name: &ProjectName,
+        container: Option<SocketAddr>,
+        now: Instant,
+    ) -> Vec<instance::Response> {
+        let projects = self.projects.lock().unwrap();
+
+        let mut instances: Vec<_> = projects
+            .get(project_name)
+            .into_iter()
+            .flatten()
+            .filter(|instance| match instance.expires {
+                Some(expires) => expires > now,
+                None => Some(instance.address) == container,
+            })
+            .map(|instance| instance::Response {
+                address: instance.address,
+                weight: instance.weight,
+                healthy: instance.is_up(now),
+                registered: instance.expires.is_some(),
+            })
+            .collect();
+
+        if let Some(container) = container {
+            if !instances
+                .iter()
+                .any(|instance| instance.address == container)
+            {
+                instances.insert(
+                    0,
+                    instance::Response {
+                        address: container,
+                        weight: 1,
+                        healthy: true,
+                        registered: false,
+                    },
+                );
+            }
+        }
+
+        instances
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+
+    use shuttle_common::models::instance::Registration;
+
+    use super::{Balancer, DOWN_FOR};
+    use crate::ProjectName;
+
+    fn registration(address: &str, weight: u32) -> Registration {
+        Registration {
+            address: address.parse().unwrap(),
+            weight,
+        }
+    }
+
+    fn picks(
+        balancer: &Balancer,
+        project_name: &ProjectName,
+        container: SocketAddr,
+        now: Instant,
+        count: usize,
+    ) -> Vec<String> {
+        (0..count)
+            .map(|_| balancer.pick_at(project_name, container, now).to_string())
+            .collect()
+    }
+
+    #[test]
+    fn container_only() {
+        let balancer = Balancer::new(Duration::from_secs(30));
+        let project_name: ProjectName = "matrix".parse().unwrap();
+        let container = "10.0.0.2:8000".parse().unwrap();
+
+        assert_eq!(
+            picks(&balancer, &project_name, container, Instant::now(), 2),
+            ["10.0.0.2:8000", "10.0.0.2:8000"]
+        );
+    }
+
+    #[test]
+    fn weighted_round_robin() {
+        let balancer = Balancer::new(Duration::from_secs(30));
+        let project_name: ProjectName = "matrix".parse().unwrap();
+        let container = "10.0.0.2:8000".parse().unwrap();
+        let now = Instant::now();
+
+        balancer.register_at(&project_name, &registration("10.0.0.3:8000", 2), now);
+
+        // The heavier instance gets twice the requests, without getting them back to back every time
+        assert_eq!(
+            picks(&balancer, &project_name, container, now, 6),
+            [
+                "10.0.0.3:8000",
+                "10.0.0.2:8000",
+                "10.0.0.3:8000",
+                "10.0.0.3:8000",
+                "10.0.0.2:8000",
+                "10.0.0.3:8000",
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_instance_is_skipped() {
+        let balancer = Balancer::new(Duration::from_secs(30));
+        let project_name: ProjectName = "matrix".parse().unwrap();
+        let container = "10.0.0.2:8000".parse().unwrap();
+        let instance = "10.0.0.3:8000".parse().unwrap();
+        let now = Instant::now();
+
+        balancer.register_at(&project_name, &registration("10.0.0.3:8000", 1), now);
+        balancer.report_at(&project_name, instance, false, now);
+
+        assert_eq!(
+            picks(&balancer, &project_name, container, now, 2),
+            ["10.0.0.2:8000", "10.0.0.2:8000"]
+        );
+
+        // When everything is down, everything is tried
+        balancer.report_at(&project_name, container, false, now);
+        assert_eq!(picks(&balancer, &project_name, container, now, 2).len(), 2);
+
+        let later = now + DOWN_FOR;
+        balancer.report_at(&project_name, container, true, later);
+        assert!(picks(&balancer, &project_name, container, later, 2)
+            .contains(&"10.0.0.3:8000".to_string()));
+    }
+
+    #[test]
+    fn registrations_lapse() {
+        let balancer = Balancer::new(Duration::from_secs(30));
+        let project_name: ProjectName = "matrix".parse().unwrap();
+        let container = "10.0.0.2:8000".parse().unwrap();
+        let now = Instant::now();
+
+        balancer.register_at(&project_name, &registration("10.0.0.3:8000", 1), now);
+        assert_eq!(
+            balancer
+                .instances_at(&project_name, Some(container), now)
+                .len(),
+            2
+        );
+
+        let later = now + Duration::from_secs(31);
+        assert_eq!(
+            picks(&balancer, &project_name, container, later, 2),
+            ["10.0.0.2:8000", "10.0.0.2:8000"]
+        );
+        assert_eq!(
+            balancer
+                .instances_at(&project_name, Some(container), later)
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn deregister() {
+        let balancer = Balancer::new(Duration::from_secs(30));
+        let project_name: ProjectName = "matrix".parse().unwrap();
+        let instance = "10.0.0.3:8000".parse().unwrap();
+
+        balancer.register(&project_name, &registration("10.0.0.3:8000", 1));
+
+        assert!(balancer.deregister(&project_name, instance));
+        assert!(!balancer.deregister(&project_name, instance));
+        assert!(balancer.instances(&project_name, None).is_empty());
+    }
+}
@@ -0,0 +1,316 @@
+use std::collections::{HashMap, HashSet};
+use std::num::NonZeroU32;
+use std::sync::Mutex;
+
+use axum::headers::authorization::Basic;
+use axum::headers::{Authorization, HeaderMapExt};
+use axum::response::{IntoResponse, Response};
+use hyper::header::{HeaderValue, WWW_AUTHENTICATE};
+use hyper::HeaderMap;
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::{digest, pbkdf2};
+use shuttle_common::models::basic_auth;
+use shuttle_common::models::error::ApiError;
+
+use crate::{Error, ErrorKind, ProjectName};
+
+/// Iterations of PBKDF2 the stored passwords are hashed with. Kept low since the password is checked for every client
+/// of a project, and it only guards sites like staging ones rather than accounts.
+const ITERATIONS: NonZeroU32 = match NonZeroU32::new(10_000) {
+    Some(iterations) => iterations,
+    None => unreachable!(),
+};
+
+const SALT_LENGTH: usize = 16;
+
+/// Challenges have to reach the ACME responder whatever the project asks of other requests
+const ACME_CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+
+/// Most credentials kept as verified for a project, past which they are all verified again
+const MAX_VERIFIED: usize = 256;
+
+/// Basic auth a project is protected with, holding the hash of its password
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BasicAuth {
+    pub username: String,
+    pub salt: Vec<u8>,
+    pub password_hash: Vec<u8>,
+    pub exempt: Vec<String>,
+}
+
+impl BasicAuth {
+    /// Check the credentials being set and hash the password with a new salt
+    pub fn new(config: &basic_auth::Config) -> Result<Self, Error> {
+        if config.username.is_empty()
+            || config.username.contains(':')
+            || config.password.is_empty()
+            || config
+                .exempt
+                .iter()
+                .any(|path| !path.starts_with('/') || !is_normalized(path))
+        {
+            return Err(Error::from_kind(ErrorKind::InvalidBasicAuth));
+        }
+
+        let mut salt = vec![0; SALT_LENGTH];
+        SystemRandom::new()
+            .fill(&mut salt)
+            .map_err(|_| Error::from_kind(ErrorKind::Internal))?;
+
+        let mut password_hash = vec![0; digest::SHA256_OUTPUT_LEN];
+        pbkdf2::derive(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            ITERATIONS,
+            &salt,
+            config.password.as_bytes(),
+            &mut password_hash,
+        );
+
+        Ok(Self {
+            username: config.username.clone(),
+            salt,
+            password_hash,
+            exempt: config.exempt.clone(),
+        })
+    }
+
+    /// Whether a request to `path` goes through without credentials
+    pub fn exempts(&self, path: &str) -> bool {
+        if !is_normalized(path) {
+            return false;
+        }
+
+        path.starts_with(ACME_CHALLENGE_PATH)
+            || self.exempt.iter().any(|exempt| {
+                path.strip_prefix(exempt.as_str()).map_or(false, |rest| {
+                    rest.is_empty() || rest.starts_with('/') || exempt.ends_with('/')
+                })
+            })
+    }
+
+    /// Whether the request with these headers has the right credentials
+    pub fn allows(&self, headers: &HeaderMap) -> bool {
+        headers
+            .typed_get::<Authorization<Basic>>()
+            .map_or(false, |Authorization(basic)| self.verify(&basic))
+    }
+
+    fn verify(&self, basic: &Basic) -> bool {
+        basic.username() == self.username
+            && pbkdf2::verify(
+                pbkdf2::PBKDF2_HMAC_SHA256,
+                ITERATIONS,
+                &self.salt,
+                basic.password().as_bytes(),
+                &self.password_hash,
+            )
+            .is_ok()
+    }
+
+    pub fn response(&self) -> basic_auth::Response {
+        basic_auth::Response {
+            username: Some(self.username.clone()),
+            exempt: self.exempt.clone(),
+        }
+    }
+
+    /// Digest of credentials along with the salt and hash they are checked against, so that credentials verified
+    /// against one password are never taken for verified against another
+    fn credentials_digest(&self, basic: &Basic) -> Vec<u8> {
+        let mut context = digest::Context::new(&digest::SHA256);
+        context.update(&self.salt);
+        context.update(&self.password_hash);
+        context.update(basic.username().as_bytes());
+        context.update(b":");
+        context.update(basic.password().as_bytes());
+
+        context.finish().as_ref().to_vec()
+    }
+}
+
+/// Whether a path has nothing the service could resolve to another path, such as the dot-segments of
+/// `/health/../admin` or encoded dots and separators, so that it can be matched against exempt paths as is
+fn is_normalized(path: &str) -> bool {
+    let lowercase = path.to_ascii_lowercase();
+
+    !path.contains('\\')
+        && !["%2e", "%2f", "%5c"]
+            .iter()
+            .any(|encoded| lowercase.contains(encoded))
+        && !path
+            .split('/')
+            .any(|segment| segment == "." || segment == "..")
+}
+
+/// Credentials which passed the basic auth of their project, so that PBKDF2 runs once for each client rather than for
+/// every request it makes
+#[derive(Default)]
+pub struct VerifiedCredentials {
+    projects: Mutex<HashMap<ProjectName, HashSet<Vec<u8>>>>,
+}
+
+impl VerifiedCredentials {
+    /// Whether the request with these headers has the right credentials, verifying them only when they were not yet
+    pub fn allows(
+        &self,
+        project_name: &ProjectName,
+        basic_auth: &BasicAuth,
+        headers: &HeaderMap,
+    ) -> bool {
+        let Some(Authorization(basic)) = headers.typed_get::<Authorization<Basic>>() else {
+            return false;
+        };
+
+        let digest = basic_auth.credentials_digest(&basic);
+        if self
+            .projects
+            .lock()
+            .unwrap()
+            .get(project_name)
+            .map_or(false, |verified| verified.contains(&digest))
+        {
+            return true;
+        }
+
+        if !basic_auth.verify(&basic) {
+            return false;
+        }
+
+        let mut projects = self.projects.lock().unwrap();
+        let verified = projects.entry(project_name.clone()).or_default();
+        if verified.len() >= MAX_VERIFIED {
+            verified.clear();
+        }
+        verified.insert(digest);
+
+        true
+    }
+
+    /// Drop the credentials verified for a project, for when its basic auth is written
+    pub fn invalidate(&self, project_name: &ProjectName) {
+        self.projects.lock().unwrap().remove(project_name);
+    }
+}
+
+/// Ask the client for credentials to a project
+pub fn unauthorized_response(project_name: &ProjectName) -> Response {
+    let body = ApiError::from(ErrorKind::Unauthorized);
+    let challenge = HeaderValue::from_str(&format!(
+        "Basic realm=\"{project_name}\", charset=\"UTF-8\""
+    ))
+    .expect("a project name to be a valid header value");
+
+    (
+        body.status(),
+        [(WWW_AUTHENTICATE, challenge)],
+        axum::Json(body),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::headers::{Authorization, HeaderMapExt};
+    use hyper::{HeaderMap, StatusCode};
+    use shuttle_common::models::basic_auth::Config;
+
+    use super::{unauthorized_response, BasicAuth, VerifiedCredentials};
+
+    fn config(username: &str, password: &str, exempt: &[&str]) -> Config {
+        Config {
+            username: username.to_string(),
+            password: password.to_string(),
+            exempt: exempt.iter().map(ToString::to_string).collect(),
+        }
+    }
+
+    fn credentials(username: &str, password: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.typed_insert(Authorization::basic(username, password));
+        headers
+    }
+
+    #[test]
+    fn allows() {
+        let basic_auth = BasicAuth::new(&config("neo", "there is no spoon", &[])).unwrap();
+
+        assert!(basic_auth.allows(&credentials("neo", "there is no spoon")));
+        assert!(!basic_auth.allows(&credentials("neo", "there is a spoon")));
+        assert!(!basic_auth.allows(&credentials("smith", "there is no spoon")));
+        assert!(!basic_auth.allows(&HeaderMap::new()));
+
+        // Every password gets a salt of its own
+        let again = BasicAuth::new(&config("neo", "there is no spoon", &[])).unwrap();
+        assert_ne!(basic_auth.password_hash, again.password_hash);
+    }
+
+    #[test]
+    fn exempts() {
+        let basic_auth = BasicAuth::new(&config("neo", "spoon", &["/health", "/public/"])).unwrap();
+
+        assert!(basic_auth.exempts("/health"));
+        assert!(basic_auth.exempts("/health/db"));
+        assert!(basic_auth.exempts("/public/logo.png"));
+        assert!(basic_auth.exempts("/.well-known/acme-challenge/token"));
+        assert!(!basic_auth.exempts("/healthy"));
+        assert!(!basic_auth.exempts("/public"));
+        assert!(!basic_auth.exempts("/"));
+
+        // Paths which the service could resolve out of the exempt ones
+        assert!(!basic_auth.exempts("/health/../admin"));
+        assert!(!basic_auth.exempts("/health/./../admin"));
+        assert!(!basic_auth.exempts("/health/%2e%2e/admin"));
+        assert!(!basic_auth.exempts("/health/%2E%2E/admin"));
+        assert!(!basic_auth.exempts("/health/..%2fadmin"));
+        assert!(!basic_auth.exempts("/health\\..\\admin"));
+        assert!(!basic_auth.exempts("/.well-known/acme-challenge/../../admin"));
+    }
+
+    #[test]
+    fn verified_credentials() {
+        let verified = VerifiedCredentials::default();
+        let matrix = "matrix".parse().unwrap();
+        let basic_auth = BasicAuth::new(&config("neo", "there is no spoon", &[])).unwrap();
+
+        for _ in 0..2 {
+            assert!(verified.allows(
+                &matrix,
+                &basic_auth,
+                &credentials("neo", "there is no spoon")
+            ));
+            assert!(!verified.allows(
+                &matrix,
+                &basic_auth,
+                &credentials("neo", "there is a spoon")
+            ));
+        }
+
+        // Credentials verified against the old password do not pass the new one, even before being dropped
+        let changed = BasicAuth::new(&config("neo", "there is a spoon", &[])).unwrap();
+        assert!(!verified.allows(&matrix, &changed, &credentials("neo", "there is no spoon")));
+
+        verified.invalidate(&matrix);
+        assert!(verified.allows(&matrix, &changed, &credentials("neo", "there is a spoon")));
+        assert!(!verified.allows(&matrix, &changed, &credentials("neo", "there is no spoon")));
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(BasicAuth::new(&config("", "spoon", &[])).is_err());
+        assert!(BasicAuth::new(&config("neo:one", "spoon", &[])).is_err());
+        assert!(BasicAuth::new(&config("neo", "", &[])).is_err());
+        assert!(BasicAuth::new(&config("neo", "spoon", &["health"])).is_err());
+        assert!(BasicAuth::new(&config("neo", "spoon", &["/health/.."])).is_err());
+    }
+
+    #[test]
+    fn unauthorized() {
+        let response = unauthorized_response(&"matrix".parse().unwrap());
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            response.headers()["www-authenticate"],
+            "Basic realm=\"matrix\", charset=\"UTF-8\""
+        );
+    }
+}
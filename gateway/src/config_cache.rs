@@ -0,0 +1,104 @@
+//! In-memory copies of the settings the proxy looks up for every request to a project, so that proxying does not query
+//! the state database each time. The gateway is the only writer of these settings, and it drops the copy of a project
+//! whenever it writes the settings of that project.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::{Error, ProjectName};
+
+/// The values of one setting, for the projects which were proxied to since it was last written
+pub struct ConfigCache<T> {
+    entries: Mutex<HashMap<ProjectName, T>>,
+    /// Bumped by every invalidation, so that a value loaded from before one is not cached after it
+    generation: AtomicU64,
+}
+
+impl<T: Clone> ConfigCache<T> {
+    pub fn new() -> Self {
+        Self {
+            entries: Default::default(),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    /// Get the value for a project, calling `load` for it when it is not cached
+    pub async fn get_or_load<F>(&self, project_name: &ProjectName, load: F) -> Result<T, Error>
+    where
+        F: Future<Output = Result<T, Error>>,
+    {
+        if let Some(value) = self.entries.lock().unwrap().get(project_name) {
+            return Ok(value.clone());
+        }
+
+        let generation = self.generation.load(Ordering::Acquire);
+        let value = load.await?;
+
+        let mut entries = self.entries.lock().unwrap();
+        if self.generation.load(Ordering::Acquire) == generation {
+            entries.insert(project_name.clone(), value.clone());
+        }
+
+        Ok(value)
+    }
+
+    /// Drop the value of a project, for it to be loaded again on its next request
+    pub fn invalidate(&self, project_name: &ProjectName) {
+        let mut entries = self.entries.lock().unwrap();
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        entries.remove(project_name);
+    }
+}
+
+impl<T: Clone> Default for ConfigCache<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn loads_once_until_invalidated() {
+        let cache = ConfigCache::new();
+        let loads = AtomicUsize::new(0);
+        let project: ProjectName = "matrix".parse().unwrap();
+
+        let load = || async { Ok::<_, Error>(loads.fetch_add(1, Ordering::SeqCst)) };
+
+        assert_eq!(cache.get_or_load(&project, load()).await.unwrap(), 0);
+        assert_eq!(cache.get_or_load(&project, load()).await.unwrap(), 0);
+
+        cache.invalidate(&project);
+        assert_eq!(cache.get_or_load(&project, load()).await.unwrap(), 1);
+        assert_eq!(cache.get_or_load(&project, load()).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn does_not_keep_values_loaded_before_an_invalidation() {
+        let cache = ConfigCache::new();
+        let project: ProjectName = "matrix".parse().unwrap();
+
+        let stale = cache
+            .get_or_load(&project, async {
+                // The setting is written while it is being loaded
+                cache.invalidate(&project);
+                Ok("stale")
+            })
+            .await
+            .unwrap();
+        assert_eq!(stale, "stale");
+
+        let fresh = cache
+            .get_or_load(&project, async { Ok("fresh") })
+            .await
+            .unwrap();
+        assert_eq!(fresh, "fresh");
+    }
+}
@@ -0,0 +1,222 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use axum_server::accept::Accept;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::timeout;
+use tower::Service;
+use tracing::warn;
+
+/// How long a new connection may wait for a slot once the listener is full, before it is closed
+const QUEUE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Connections held open by a listener, optionally capped to a maximum.
+///
+/// The cap covers the whole listener, no matter which project a connection ends up at. Connections over it wait
+/// briefly for one to close and are then dropped without a response, since there is nothing cheaper to tell them.
+#[derive(Clone)]
+pub struct ConnectionLimit {
+    max: Option<usize>,
+    slots: Option<Arc<Semaphore>>,
+    counts: Arc<Counts>,
+    queue_timeout: Duration,
+}
+
+#[derive(Default)]
+struct Counts {
+    open: AtomicUsize,
+    rejected: AtomicU64,
+}
+
+/// Counts reported by the control plane's status
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ConnectionsStatus {
+    pub open: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max: Option<usize>,
+    pub rejected: u64,
+}
+
+impl ConnectionLimit {
+    /// Without a maximum connections are only counted
+    pub fn new(max: Option<usize>) -> Self {
+        Self {
+            max,
+            slots: max.map(|max| Arc::new(Semaphore::new(max))),
+            counts: Default::default(),
+            queue_timeout: QUEUE_TIMEOUT,
+        }
+    }
+
+    pub fn status(&self) -> ConnectionsStatus {
+        ConnectionsStatus {
+            open: self.counts.open.load(Ordering::Relaxed),
+            max: self.max,
+            rejected: self.counts.rejected.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Wrap the acceptor of a listener so that every connection it accepts takes up a slot until it is closed
+    pub fn acceptor<A>(&self, inner: A) -> ConnectionLimitAcceptor<A> {
+        ConnectionLimitAcceptor {
+            inner,
+            limit: self.clone(),
+        }
+    }
+
+    async fn acquire(&self) -> Option<ConnectionGuard> {
+        let permit = match &self.slots {
+            Some(slots) => match timeout(self.queue_timeout, slots.clone().acquire_owned()).await {
+                Ok(Ok(permit)) => Some(permit),
+                _ => {
+                    self.counts.rejected.fetch_add(1, Ordering::Relaxed);
+                    return None;
+                }
+            },
+            None => None,
+        };
+
+        self.counts.open.fetch_add(1, Ordering::Relaxed);
+
+        Some(ConnectionGuard {
+            counts: self.counts.clone(),
+            _permit: permit,
+        })
+    }
+}
+
+/// Releases the slot of a connection once it is closed
+struct ConnectionGuard {
+    counts: Arc<Counts>,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.counts.open.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Clone)]
+pub struct ConnectionLimitAcceptor<A> {
+    inner: A,
+    limit: ConnectionLimit,
+}
+
+impl<I, S, A> Accept<I, S> for ConnectionLimitAcceptor<A>
+where
+    A: Accept<I, S> + Clone + Send + 'static,
+    A::Future: Send,
+    I: Send + 'static,
+    S: Send + 'static,
+{
+    type Stream = A::Stream;
+    type Service = Limited<A::Service>;
+    type Future = Pin<Box<dyn Future<Output = io::Result<(Self::Stream, Self::Service)>> + Send>>;
+
+    fn accept(&self, stream: I, service: S) -> Self::Future {
+        let inner = self.inner.clone();
+        let limit = self.limit.clone();
+
+        Box::pin(async move {
+            let Some(guard) = limit.acquire().await else {
+                warn!(
+                    max = limit.max,
+                    "dropping connection, the listener is at its maximum"
+                );
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "too many open connections",
+                ));
+            };
+
+            let (stream, service) = inner.accept(stream, service).await?;
+
+            Ok((
+                stream,
+                Limited {
+                    inner: service,
+                    _guard: Arc::new(guard),
+                },
+            ))
+        })
+    }
+}
+
+/// The service of a connection, holding on to its slot for as long as the connection is served
+#[derive(Clone)]
+pub struct Limited<S> {
+    inner: S,
+    _guard: Arc<ConnectionGuard>,
+}
+
+impl<S, R> Service<R> for Limited<S>
+where
+    S: Service<R>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: R) -> Self::Future {
+        self.inner.call(req)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum_server::accept::{Accept, DefaultAcceptor};
+
+    use super::{ConnectionLimit, ConnectionsStatus};
+
+    #[tokio::test]
+    async fn limit() {
+        let mut limit = ConnectionLimit::new(Some(1));
+        limit.queue_timeout = Duration::from_millis(10);
+        let acceptor = limit.acceptor(DefaultAcceptor::new());
+
+        let (_, first) = acceptor.accept((), ()).await.unwrap();
+        assert!(
+            acceptor.accept((), ()).await.is_err(),
+            "a second connection should not fit"
+        );
+        assert_eq!(
+            limit.status(),
+            ConnectionsStatus {
+                open: 1,
+                max: Some(1),
+                rejected: 1
+            }
+        );
+
+        drop(first);
+        let (_, _second) = acceptor.accept((), ()).await.unwrap();
+        assert_eq!(limit.status().open, 1);
+    }
+
+    #[tokio::test]
+    async fn unlimited() {
+        let limit = ConnectionLimit::new(None);
+        let acceptor = limit.acceptor(DefaultAcceptor::new());
+
+        let connections = futures::future::try_join_all((0..64).map(|_| acceptor.accept((), ())))
+            .await
+            .unwrap();
+        assert_eq!(limit.status().open, 64);
+
+        drop(connections);
+        assert_eq!(limit.status().open, 0);
+    }
+}
@@ -0,0 +1,177 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use http::HeaderMap;
+use hyper::body::{Body, Bytes, HttpBody, SizeHint};
+use pin_project::pin_project;
+use shuttle_common::models::timeouts::StalledBody;
+use tokio::time::{sleep_until, Instant, Sleep};
+use tracing::warn;
+
+/// Body of a backend response which is cut off when the backend takes too long to send it.
+///
+/// Its headers have already gone out to the client by the time the body stalls, so there is no status left to
+/// answer with. Failing the body makes hyper close the connection, which at least tells the client the response is
+/// incomplete rather than leaving it waiting on the rest.
+#[pin_project]
+pub struct DeadlineBody {
+    #[pin]
+    inner: Body,
+    #[pin]
+    deadline: Option<Sleep>,
+    /// How far every chunk pushes the deadline back. Without it the deadline is fixed.
+    idle: Option<Duration>,
+}
+
+impl DeadlineBody {
+    /// `response_deadline` is when the whole response was due, if it had to be in by some time
+    pub fn new(
+        inner: Body,
+        stalled_body: &StalledBody,
+        response_deadline: Option<Instant>,
+    ) -> Self {
+        match stalled_body {
+            StalledBody::Wait { idle_secs } => {
+                let idle = Duration::from_secs(*idle_secs);
+
+                Self::with(inner, Instant::now().checked_add(idle), Some(idle))
+            }
+            StalledBody::Abort => Self::with(inner, response_deadline, None),
+            StalledBody::Unlimited => Self::with(inner, None, None),
+        }
+    }
+
+    fn with(inner: Body, deadline: Option<Instant>, idle: Option<Duration>) -> Self {
+        Self {
+            inner,
+            deadline: deadline.map(sleep_until),
+            idle,
+        }
+    }
+
+    fn poll_deadline(deadline: Pin<&mut Option<Sleep>>, cx: &mut Context<'_>) -> Poll<axum::Error> {
+        match deadline.as_pin_mut() {
+            Some(deadline) if deadline.poll(cx).is_ready() => {
+                warn!("backend stalled while sending a response body, closing the connection");
+
+                Poll::Ready(axum::Error::new(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "backend stalled while sending the response body",
+                )))
+            }
+            _ => Poll::Pending,
+        }
+    }
+}
+
+impl HttpBody for DeadlineBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let mut this = self.project();
+
+        if let Poll::Ready(data) = this.inner.poll_data(cx) {
+            if let (Some(idle), Some(deadline)) = (this.idle, this.deadline.as_mut().as_pin_mut()) {
+                if let Some(at) = Instant::now().checked_add(*idle) {
+                    deadline.reset(at);
+                }
+            }
+
+            return Poll::Ready(data.map(|data| data.map_err(axum::Error::new)));
+        }
+
+        Self::poll_deadline(this.deadline, cx).map(|error| Some(Err(error)))
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        let this = self.project();
+
+        if let Poll::Ready(trailers) = this.inner.poll_trailers(cx) {
+            return Poll::Ready(trailers.map_err(axum::Error::new));
+        }
+
+        Self::poll_deadline(this.deadline, cx).map(Err)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use hyper::body::{Body, Bytes, HttpBody};
+    use tokio::time::{sleep, Instant};
+
+    use super::DeadlineBody;
+
+    #[tokio::test]
+    async fn idle() {
+        let (mut sender, inner) = Body::channel();
+        let mut body = Box::pin(DeadlineBody::with(
+            inner,
+            Some(Instant::now() + Duration::from_millis(100)),
+            Some(Duration::from_millis(100)),
+        ));
+
+        tokio::spawn(async move {
+            for _ in 0..3 {
+                sleep(Duration::from_millis(60)).await;
+                sender.send_data(Bytes::from("chunk")).await.unwrap();
+            }
+
+            // Stall without closing the body
+            sleep(Duration::from_secs(60)).await;
+            drop(sender);
+        });
+
+        // Every chunk comes in before the body has been idle for too long, even if they add up to more
+        for _ in 0..3 {
+            assert_eq!(body.data().await.unwrap().unwrap(), "chunk");
+        }
+
+        assert!(body.data().await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn fixed() {
+        let (mut sender, inner) = Body::channel();
+        let mut body = Box::pin(DeadlineBody::with(
+            inner,
+            Some(Instant::now() + Duration::from_millis(100)),
+            None,
+        ));
+
+        tokio::spawn(async move {
+            loop {
+                sleep(Duration::from_millis(30)).await;
+                if sender.send_data(Bytes::from("chunk")).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut chunks = 0;
+        while let Some(Ok(_)) = body.data().await {
+            chunks += 1;
+        }
+
+        assert!(chunks < 5, "the body should be cut off at the deadline");
+    }
+}
@@ -0,0 +1,129 @@
+//! Proof that a project controls a custom domain, asked for before the domain is added to it.
+//!
+//! Certificates only show that whoever asked for them can answer for a domain, which the gateway itself does for every
+//! domain pointed at it. A project has to publish a token of its own in a TXT record of the domain instead, so that
+//! one tenant cannot take a domain which points at the gateway for another.
+
+use async_trait::async_trait;
+use fqdn::FQDN;
+use tracing::{debug, warn};
+use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+use trust_dns_resolver::error::{ResolveError, ResolveErrorKind};
+use trust_dns_resolver::TokioAsyncResolver;
+
+use crate::{Error, ErrorKind};
+
+/// Label the verification record sits under, so that it does not get in the way of other TXT records of the domain
+const RECORD_LABEL: &str = "_shuttle-challenge";
+
+const RECORD_VALUE_PREFIX: &str = "shuttle-verification=";
+
+/// Name of the TXT record which verifies `fqdn`
+pub fn record_name(fqdn: &FQDN) -> String {
+    format!("{RECORD_LABEL}.{fqdn}")
+}
+
+pub fn record_value(token: &str) -> String {
+    format!("{RECORD_VALUE_PREFIX}{token}")
+}
+
+#[async_trait]
+pub trait TxtLookup: Send + Sync {
+    /// The TXT records at `name`, with the strings of each record joined. A name without any has none.
+    async fn txt_records(&self, name: &str) -> Result<Vec<String>, Error>;
+}
+
+pub struct DnsTxtLookup {
+    resolver: TokioAsyncResolver,
+}
+
+impl DnsTxtLookup {
+    /// Resolve through the nameservers of the system, or through public ones when those cannot be read
+    pub fn new() -> Result<Self, ResolveError> {
+        let resolver = match TokioAsyncResolver::tokio_from_system_conf() {
+            Ok(resolver) => resolver,
+            Err(error) => {
+                warn!(
+                    error = &error as &dyn std::error::Error,
+                    "could not read the DNS configuration of the system, using public nameservers"
+                );
+                TokioAsyncResolver::tokio(ResolverConfig::default(), ResolverOpts::default())?
+            }
+        };
+
+        Ok(Self { resolver })
+    }
+}
+
+#[async_trait]
+impl TxtLookup for DnsTxtLookup {
+    async fn txt_records(&self, name: &str) -> Result<Vec<String>, Error> {
+        match self.resolver.txt_lookup(name).await {
+            Ok(lookup) => Ok(lookup
+                .iter()
+                .map(|txt| {
+                    txt.txt_data()
+                        .iter()
+                        .map(|data| String::from_utf8_lossy(data))
+                        .collect()
+                })
+                .collect()),
+            Err(error) if matches!(error.kind(), ResolveErrorKind::NoRecordsFound { .. }) => {
+                Ok(Vec::new())
+            }
+            Err(error) => Err(Error::source(ErrorKind::Internal, error)),
+        }
+    }
+}
+
+/// Whether the verification record of `fqdn` carries `token`
+pub async fn verifies(lookup: &dyn TxtLookup, fqdn: &FQDN, token: &str) -> Result<bool, Error> {
+    let name = record_name(fqdn);
+    let expected = record_value(token);
+    let records = lookup.txt_records(&name).await?;
+
+    debug!(%name, ?records, "looked up the domain verification record");
+
+    Ok(records.iter().any(|record| record.trim() == expected))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::collections::HashMap;
+
+    use async_trait::async_trait;
+
+    use super::{record_name, record_value, verifies, TxtLookup};
+    use crate::Error;
+
+    /// Stands in for DNS, with the records of each name
+    #[derive(Default)]
+    pub struct FakeTxtLookup(pub HashMap<String, Vec<String>>);
+
+    #[async_trait]
+    impl TxtLookup for FakeTxtLookup {
+        async fn txt_records(&self, name: &str) -> Result<Vec<String>, Error> {
+            Ok(self.0.get(name).cloned().unwrap_or_default())
+        }
+    }
+
+    #[tokio::test]
+    async fn verifies_token() {
+        let fqdn = "neo.the.matrix".parse().unwrap();
+        assert_eq!(record_name(&fqdn), "_shuttle-challenge.neo.the.matrix");
+
+        let mut lookup = FakeTxtLookup::default();
+        assert!(!verifies(&lookup, &fqdn, "red-pill").await.unwrap());
+
+        lookup.0.insert(
+            record_name(&fqdn),
+            vec!["v=spf1 -all".to_string(), record_value("blue-pill")],
+        );
+        assert!(!verifies(&lookup, &fqdn, "red-pill").await.unwrap());
+        assert!(verifies(&lookup, &fqdn, "blue-pill").await.unwrap());
+
+        // The record of a parent domain does not count for its subdomains
+        let other = "trinity.neo.the.matrix".parse().unwrap();
+        assert!(!verifies(&lookup, &other, "blue-pill").await.unwrap());
+    }
+}
@@ -0,0 +1,102 @@
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+use shuttle_common::models::ip_rule::Action;
+
+use crate::{Error, ErrorKind};
+
+/// The allow and deny lists of client IPs for a project
+#[derive(Clone, Debug, Default)]
+pub struct IpFilter {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+}
+
+impl IpFilter {
+    pub fn new(rules: impl IntoIterator<Item = (IpNet, Action)>) -> Self {
+        let mut filter = Self::default();
+
+        for (net, action) in rules {
+            match action {
+                Action::Allow => filter.allow.push(net),
+                Action::Deny => filter.deny.push(net),
+            }
+        }
+
+        filter
+    }
+
+    /// Deny rules take precedence over allow rules. A filter without any allow rules lets
+    /// through every client which is not denied.
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        let ip = canonical(ip);
+
+        if self.deny.iter().any(|net| net.contains(&ip)) {
+            return false;
+        }
+
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip))
+    }
+}
+
+/// Parse a CIDR, treating a lone address as a network of just that address
+pub fn parse_cidr(cidr: &str) -> Result<IpNet, Error> {
+    let cidr = cidr.trim();
+
+    cidr.parse::<IpNet>()
+        .or_else(|_| cidr.parse::<IpAddr>().map(IpNet::from))
+        .map(|net| net.trunc())
+        .map_err(|_| Error::from_kind(ErrorKind::InvalidIpRule))
+}
+
+/// Clients on a dual-stack listener can show up as IPv4-mapped IPv6 addresses
+pub(crate) fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => IpAddr::V6(v6),
+        },
+        ip => ip,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shuttle_common::models::ip_rule::Action;
+
+    use super::{parse_cidr, IpFilter};
+
+    #[test]
+    fn allows() {
+        let filter = IpFilter::new([
+            (parse_cidr("10.0.0.0/8").unwrap(), Action::Allow),
+            (parse_cidr("2001:db8::/32").unwrap(), Action::Allow),
+            (parse_cidr("10.1.2.3").unwrap(), Action::Deny),
+        ]);
+
+        assert!(filter.allows("10.20.30.40".parse().unwrap()));
+        assert!(filter.allows("::ffff:10.20.30.40".parse().unwrap()));
+        assert!(filter.allows("2001:db8::1".parse().unwrap()));
+        assert!(!filter.allows("10.1.2.3".parse().unwrap()));
+        assert!(!filter.allows("192.168.0.1".parse().unwrap()));
+        assert!(!filter.allows("2001:db9::1".parse().unwrap()));
+
+        let deny_only = IpFilter::new([(parse_cidr("192.168.0.0/16").unwrap(), Action::Deny)]);
+
+        assert!(deny_only.allows("10.0.0.1".parse().unwrap()));
+        assert!(!deny_only.allows("192.168.1.1".parse().unwrap()));
+
+        assert!(IpFilter::default().allows("::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            parse_cidr("10.1.2.3/8").unwrap(),
+            "10.0.0.0/8".parse().unwrap()
+        );
+        assert_eq!(parse_cidr("::1").unwrap(), "::1/128".parse().unwrap());
+        assert!(parse_cidr("10.0.0.0/33").is_err());
+        assert!(parse_cidr("not an ip").is_err());
+    }
+}
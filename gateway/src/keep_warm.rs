@@ -0,0 +1,117 @@
+//! Projects which are kept from idling because they got requests lately, so that traffic which comes in bursts does not
+//! run into a cold start every time, or because they were pinned to stay up for a while, like for a demo.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::ProjectName;
+
+/// The most recently active projects, up to a fixed number of them, and the pinned ones
+pub struct KeepWarm {
+    capacity: usize,
+    /// When each of the projects last got a request
+    last_active: Mutex<HashMap<ProjectName, Instant>>,
+    pinned: Mutex<HashSet<ProjectName>>,
+}
+
+impl KeepWarm {
+    /// Keep the `capacity` most recently active projects warm. `0` keeps none.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            last_active: Mutex::new(HashMap::new()),
+            pinned: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Count a request to a project
+    pub fn touch(&self, project_name: &ProjectName) {
+        self.touch_at(project_name, Instant::now())
+    }
+
+    /// Whether a project is among the most recently active ones, or pinned
+    pub fn contains(&self, project_name: &ProjectName) -> bool {
+        self.is_pinned(project_name) || self.last_active.lock().unwrap().contains_key(project_name)
+    }
+
+    /// Keep a project warm until it is unpinned, whether it gets requests or not
+    pub fn pin(&self, project_name: &ProjectName) {
+        self.pinned.lock().unwrap().insert(project_name.clone());
+    }
+
+    pub fn unpin(&self, project_name: &ProjectName) {
+        self.pinned.lock().unwrap().remove(project_name);
+    }
+
+    pub fn is_pinned(&self, project_name: &ProjectName) -> bool {
+        self.pinned.lock().unwrap().contains(project_name)
+    }
+
+    fn touch_at(&self, project_name: &ProjectName, now: Instant) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut last_active = self.last_active.lock().unwrap();
+        last_active.insert(project_name.clone(), now);
+
+        if last_active.len() > self.capacity {
+            let least_recent = last_active
+                .iter()
+                .min_by_key(|(_, at)| **at)
+                .map(|(project_name, _)| project_name.clone());
+
+            if let Some(least_recent) = least_recent {
+                last_active.remove(&least_recent);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::KeepWarm;
+
+    #[test]
+    fn keeps_most_recently_active() {
+        let keep_warm = KeepWarm::new(2);
+        let now = Instant::now();
+        let matrix = "matrix".parse().unwrap();
+        let nebuchadnezzar = "nebuchadnezzar".parse().unwrap();
+        let zion = "zion".parse().unwrap();
+
+        keep_warm.touch_at(&matrix, now);
+        keep_warm.touch_at(&nebuchadnezzar, now + Duration::from_secs(1));
+        keep_warm.touch_at(&matrix, now + Duration::from_secs(2));
+        keep_warm.touch_at(&zion, now + Duration::from_secs(3));
+
+        assert!(keep_warm.contains(&matrix));
+        assert!(!keep_warm.contains(&nebuchadnezzar));
+        assert!(keep_warm.contains(&zion));
+    }
+
+    #[test]
+    fn keeps_none_without_capacity() {
+        let keep_warm = KeepWarm::new(0);
+        let matrix = "matrix".parse().unwrap();
+
+        keep_warm.touch(&matrix);
+
+        assert!(!keep_warm.contains(&matrix));
+    }
+
+    #[test]
+    fn keeps_pinned() {
+        let keep_warm = KeepWarm::new(0);
+        let matrix = "matrix".parse().unwrap();
+
+        keep_warm.pin(&matrix);
+        assert!(keep_warm.contains(&matrix));
+
+        keep_warm.unpin(&matrix);
+        assert!(!keep_warm.contains(&matrix));
+    }
+}
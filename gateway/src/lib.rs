@@ -22,11 +22,21 @@ pub mod acme;
 pub mod api;
 pub mod args;
 pub mod auth;
+pub mod balancer;
+pub mod basic_auth;
+pub mod config_cache;
+pub mod connections;
+pub mod deadline;
+pub mod domain_verification;
+pub mod ip_filter;
+pub mod keep_warm;
 pub mod project;
 pub mod proxy;
+pub mod proxy_protocol;
 pub mod service;
 pub mod task;
 pub mod tls;
+pub mod traffic;
 pub mod worker;
 
 use crate::service::{ContainerSettings, GatewayService};
@@ -173,7 +183,7 @@ impl std::fmt::Display for ProjectName {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, sqlx::Type, Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, sqlx::Type, Serialize)]
 #[sqlx(transparent)]
 pub struct AccountName(String);
 
@@ -221,6 +231,11 @@ pub trait DockerContext: Send + Sync {
     fn docker(&self) -> &Docker;
 
     fn container_settings(&self) -> &ContainerSettings;
+
+    /// Whether a project is kept from idling, whatever its own idle timer says, because it is pinned or was active lately
+    fn keeps_warm(&self, _project_name: &ProjectName) -> bool {
+        false
+    }
 }
 
 #[async_trait]
@@ -351,7 +366,9 @@ pub mod tests {
 
     use crate::acme::AcmeClient;
     use crate::api::latest::ApiBuilder;
-    use crate::args::{ContextArgs, StartArgs, UseTls};
+    use crate::args::{
+        AlpnProtocol, ContextArgs, ErroredProjectPolicy, QueueFullPolicy, StartArgs, UseTls,
+    };
     use crate::proxy::UserServiceBuilder;
     use crate::service::{ContainerSettings, GatewayService, MIGRATIONS};
     use crate::worker::Worker;
@@ -602,7 +619,23 @@ pub mod tests {
                 control,
                 user,
                 bouncer,
+                no_bouncer: false,
+                unknown_host_redirect: None,
+                not_deployed_page: None,
+                apex_redirect: None,
+                apex_page: None,
+                apex_status: None,
+                max_connections: None,
+                control_request_timeout: None,
+                control_max_body_size: None,
+                control_max_concurrent_requests: None,
+                proxy_protocol_from: Vec::new(),
                 use_tls: UseTls::Disable,
+                tls_session_cache_size: 256,
+                tls_ticket_keys: None,
+                tls_alpn_protocols: vec![AlpnProtocol::Http1],
+                health_check_interval: Duration::from_secs(60),
+                certificate_renewal_threshold: Duration::from_secs(30 * 24 * 60 * 60),
                 context: ContextArgs {
                     docker_host,
                     image,
@@ -611,6 +644,24 @@ pub mod tests {
                     auth_uri: auth_uri.clone(),
                     network_name,
                     proxy_fqdn: FQDN::from_str("test.shuttleapp.rs").unwrap(),
+                    proxy_max_idle_connections: 32,
+                    proxy_idle_timeout: 90,
+                    proxy_max_headers_size: 32768,
+                    proxy_max_headers: 100,
+                    proxy_max_header_size: 8192,
+                    queue_full_policy: QueueFullPolicy::Backpressure,
+                    queue_send_timeout: 9,
+                    traffic_windows: vec![60, 300, 3600],
+                    instance_ttl: 30,
+                    max_projects_per_account: None,
+                    max_custom_domains_per_project: None,
+                    max_custom_domains_for: Vec::new(),
+                    no_domain_verification: false,
+                    errored_project_policy: ErroredProjectPolicy::Report,
+                    errored_project_hold: 10,
+                    expose_project_errors: false,
+                    keep_warm_projects: 0,
+                    slow_request_threshold: None,
                 },
             };
 
@@ -775,10 +826,12 @@ pub mod tests {
             .with_auth_service(world.context().auth_uri)
             .binding_to(world.args.control);
 
+        let not_deployed_page = "<h1>Nothing deployed to the matrix</h1>";
         let user = UserServiceBuilder::new()
             .with_service(Arc::clone(&service))
             .with_task_sender(log_out.clone())
             .with_public(world.fqdn())
+            .with_not_deployed_page(Some(not_deployed_page.to_string()))
             .with_user_proxy_binding_to(world.args.user);
 
         let _gateway = tokio::spawn(async move {
@@ -844,6 +897,23 @@ pub mod tests {
             .await
             .unwrap();
 
+        // The deployer of the project answers with X_SHUTTLE_NOT_DEPLOYED, which the proxy turns into its page
+        println!("request the matrix project, which has nothing deployed");
+        world
+            .client(world.args.user)
+            .request(
+                Request::get("/")
+                    .header("Host", format!("matrix.{}", world.fqdn()))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .map_ok(|resp| {
+                assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+                assert_eq!(resp.body().as_slice(), not_deployed_page.as_bytes());
+            })
+            .await
+            .unwrap();
+
         println!("delete matrix project");
         api_client
             .request(
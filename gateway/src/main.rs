@@ -1,35 +1,68 @@
+use chrono::Utc;
 use clap::Parser;
 use fqdn::FQDN;
 use futures::prelude::*;
 use instant_acme::{AccountCredentials, ChallengeType};
+use rustls::server::ProducesTickets;
+use rustls::Ticketer;
 use shuttle_common::backends::tracing::setup_tracing;
-use shuttle_gateway::acme::{AcmeClient, CustomDomain};
+use shuttle_common::models::error::ErrorKind;
+use shuttle_gateway::acme::{challenge_type_for, AcmeClient, CustomDomain};
 use shuttle_gateway::api::latest::{ApiBuilder, SVC_DEGRADED_THRESHOLD};
+use shuttle_gateway::api::limits::ControlLimits;
 use shuttle_gateway::args::StartArgs;
 use shuttle_gateway::args::{Args, Commands, UseTls};
-use shuttle_gateway::proxy::UserServiceBuilder;
+use shuttle_gateway::connections::ConnectionLimit;
+use shuttle_gateway::proxy::{Apex, UserServiceBuilder};
+use shuttle_gateway::proxy_protocol::ProxyProtocol;
 use shuttle_gateway::service::{GatewayService, MIGRATIONS};
 use shuttle_gateway::task;
-use shuttle_gateway::tls::{make_tls_acceptor, ChainAndPrivateKey};
+use shuttle_gateway::tls::{
+    alpn_ids, make_tls_acceptor, ChainAndPrivateKey, GatewayCertResolver, SessionResumption,
+    SharedTicketer,
+};
 use shuttle_gateway::worker::{Worker, WORKER_QUEUE_SIZE};
+use shuttle_gateway::Error;
 use sqlx::migrate::MigrateDatabase;
 use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqliteSynchronous};
 use sqlx::{Sqlite, SqlitePool};
+use std::collections::BTreeMap;
 use std::io::{self, Cursor};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::Arc;
 use std::time::Duration;
+use tokio::time::MissedTickBehavior;
 use tracing::{debug, error, info, info_span, trace, warn, Instrument};
 
-#[tokio::main(flavor = "multi_thread")]
-async fn main() -> io::Result<()> {
+/// How often the shared session ticket keys are read again to pick up rotations
+const TICKET_KEYS_RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often certificates are checked for being close to expiring
+const CERTIFICATE_RENEWAL_CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+
+fn main() -> io::Result<()> {
     let args = Args::parse();
 
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime.enable_all();
+
+    if let Some(worker_threads) = args.worker_threads.filter(|threads| *threads > 0) {
+        runtime.worker_threads(worker_threads);
+    }
+
+    runtime.build()?.block_on(run(args))
+}
+
+async fn run(args: Args) -> io::Result<()> {
     trace!(args = ?args, "parsed args");
 
     setup_tracing(tracing_subscriber::registry(), "gateway");
 
+    if args.worker_threads == Some(0) {
+        warn!("--worker-threads cannot be 0, running one worker thread for every CPU core instead");
+    }
+
     let db_path = args.state.join("gateway.sqlite");
     let db_uri = db_path.to_str().unwrap();
 
@@ -58,6 +91,9 @@ async fn main() -> io::Result<()> {
 }
 
 async fn start(db: SqlitePool, fs: PathBuf, args: StartArgs) -> io::Result<()> {
+    let alpn_protocols = alpn_ids(&args.tls_alpn_protocols)
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+
     let gateway = Arc::new(GatewayService::init(args.context.clone(), db).await);
 
     let worker = Worker::new();
@@ -87,13 +123,17 @@ async fn start(db: SqlitePool, fs: PathBuf, args: StartArgs) -> io::Result<()> {
             .map_err(|err| error!("worker error: {}", err)),
     );
 
-    // Every 60secs go over all `::Ready` projects and check their
-    // health
+    // Every `--health-check-interval` go over all `::Ready` projects
+    // and check their health
     let ambulance_handle = tokio::spawn({
         let gateway = Arc::clone(&gateway);
         let sender = sender.clone();
+        let health_check_interval = args.health_check_interval;
         async move {
-            let mut interval = tokio::time::interval(Duration::from_secs(60));
+            let mut interval = tokio::time::interval(health_check_interval);
+            // A sweep running over the interval pushes the next one back, instead of
+            // the missed ticks firing one after another once it is done
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
             interval.tick().await; // first tick is immediate
 
             loop {
@@ -138,22 +178,74 @@ async fn start(db: SqlitePool, fs: PathBuf, args: StartArgs) -> io::Result<()> {
         }
     });
 
+    // Drop the pooled connections of projects which are no longer proxied to
+    tokio::spawn({
+        let gateway = Arc::clone(&gateway);
+        // An interval cannot be zero
+        let eviction_interval = Duration::from_secs(args.context.proxy_idle_timeout.max(1));
+
+        async move {
+            let mut interval = tokio::time::interval(eviction_interval);
+
+            loop {
+                interval.tick().await;
+                gateway.backend_pool().evict_idle();
+            }
+        }
+    });
+
     let acme_client = AcmeClient::new();
 
+    let connections = ConnectionLimit::new(args.max_connections);
+
     let mut api_builder = ApiBuilder::new()
         .with_service(Arc::clone(&gateway))
         .with_sender(sender.clone())
+        .with_user_proxy_connections(connections.clone())
+        .with_limits(ControlLimits {
+            request_timeout: args.control_request_timeout.map(Duration::from_secs),
+            max_body_size: args.control_max_body_size,
+            max_concurrent_requests: args.control_max_concurrent_requests,
+        })
         .binding_to(args.control);
 
+    let not_deployed_page = args
+        .not_deployed_page
+        .as_deref()
+        .map(std::fs::read_to_string)
+        .transpose()?;
+
+    let apex = match (&args.apex_redirect, &args.apex_page, args.apex_status) {
+        (Some(location), _, _) => Some(Apex::Redirect(location.clone())),
+        (_, Some(page), _) => Some(Apex::Page(std::fs::read_to_string(page)?.into())),
+        (_, _, Some(status)) => Some(Apex::Status(status)),
+        (None, None, None) => None,
+    };
+
     let mut user_builder = UserServiceBuilder::new()
         .with_service(Arc::clone(&gateway))
         .with_task_sender(sender)
         .with_public(args.context.proxy_fqdn.clone())
         .with_user_proxy_binding_to(args.user)
-        .with_bouncer(args.bouncer);
+        .with_bouncer((!args.no_bouncer).then_some(args.bouncer))
+        .with_unknown_host_redirect(args.unknown_host_redirect.clone())
+        .with_not_deployed_page(not_deployed_page)
+        .with_apex(apex)
+        .with_connection_limit(connections)
+        .with_proxy_protocol(ProxyProtocol::new(args.proxy_protocol_from.clone()));
 
     if let UseTls::Enable = args.use_tls {
-        let (resolver, tls_acceptor) = make_tls_acceptor();
+        if args.no_bouncer {
+            warn!("the bouncer is disabled, so HTTP-01 challenges cannot be answered and plain HTTP requests are not redirected to HTTPS");
+        }
+
+        let (resolver, tls_acceptor) = make_tls_acceptor(
+            SessionResumption {
+                cache_size: args.tls_session_cache_size,
+                ticketer: make_ticketer(args.tls_ticket_keys.as_deref()),
+            },
+            alpn_protocols,
+        );
 
         user_builder = user_builder
             .with_acme(acme_client.clone())
@@ -177,16 +269,41 @@ async fn start(db: SqlitePool, fs: PathBuf, args: StartArgs) -> io::Result<()> {
                 .unwrap();
         }
 
-        tokio::spawn(async move {
-            // make sure we have a certificate for ourselves
-            let certs = init_certs(fs, args.context.proxy_fqdn.clone(), acme_client.clone()).await;
-            resolver.serve_default_der(certs).await.unwrap();
+        tokio::spawn({
+            let gateway = Arc::clone(&gateway);
+            let public = args.context.proxy_fqdn.clone();
+            let renewal_threshold = args.certificate_renewal_threshold;
+            async move {
+                // make sure we have a certificate for ourselves
+                let certs = init_certs(&fs, public.clone(), acme_client.clone()).await;
+                resolver.serve_default_der(certs).await.unwrap();
+
+                // Every `CERTIFICATE_RENEWAL_CHECK_INTERVAL` renew the certificates expiring
+                // within `--certificate-renewal-threshold`, starting straight away
+                let mut interval = tokio::time::interval(CERTIFICATE_RENEWAL_CHECK_INTERVAL);
+                interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+                loop {
+                    interval.tick().await;
+
+                    renew_certs(
+                        &gateway,
+                        &fs,
+                        &public,
+                        &acme_client,
+                        &resolver,
+                        renewal_threshold,
+                    )
+                    .await;
+                }
+            }
         });
     } else {
         warn!("TLS is disabled in the proxy service. This is only acceptable in testing, and should *never* be used in deployments.");
     };
 
     let api_handle = api_builder
+        .with_user_proxy_handle(user_builder.user_handle())
         .with_default_routes()
         .with_auth_service(args.context.auth_uri)
         .with_default_traces()
@@ -206,46 +323,242 @@ async fn start(db: SqlitePool, fs: PathBuf, args: StartArgs) -> io::Result<()> {
     Ok(())
 }
 
+/// Tickets are left out when the shared keys cannot be loaded, so that clients fall back to full handshakes instead of
+/// getting tickets the other replicas cannot decrypt
+fn make_ticketer(shared_keys: Option<&Path>) -> Option<Arc<dyn ProducesTickets>> {
+    let Some(path) = shared_keys else {
+        return match Ticketer::new() {
+            Ok(ticketer) => Some(ticketer),
+            Err(error) => {
+                warn!(
+                    ?error,
+                    "failed to create a session ticketer, session tickets are disabled"
+                );
+                None
+            }
+        };
+    };
+
+    let ticketer = match SharedTicketer::load(path) {
+        Ok(ticketer) => Arc::new(ticketer),
+        Err(error) => {
+            warn!(
+                error = &error as &dyn std::error::Error,
+                path = %path.display(),
+                "failed to load the session ticket keys, session tickets are disabled"
+            );
+            return None;
+        }
+    };
+
+    tokio::spawn({
+        let ticketer = ticketer.clone();
+
+        async move {
+            let mut interval = tokio::time::interval(TICKET_KEYS_RELOAD_INTERVAL);
+
+            loop {
+                interval.tick().await;
+
+                if let Err(error) = ticketer.reload() {
+                    warn!(
+                        error = &error as &dyn std::error::Error,
+                        "failed to reload the session ticket keys, keeping the current ones"
+                    );
+                }
+            }
+        }
+    });
+
+    Some(ticketer)
+}
+
 async fn init_certs<P: AsRef<Path>>(fs: P, public: FQDN, acme: AcmeClient) -> ChainAndPrivateKey {
     let tls_path = fs.as_ref().join("ssl.pem");
 
     match ChainAndPrivateKey::load_pem(&tls_path) {
-        Ok(valid) => valid,
+        // A wildcard does not cover the domain it is under, so certificates made before the apex was added are
+        // replaced
+        Ok(valid) if valid.is_valid_for(&public.to_string()) => valid,
+        Ok(_) => {
+            warn!(
+                "certificate at {} does not cover {public} itself, creating a new one...",
+                tls_path.display()
+            );
+            create_certs(fs, public, acme).await.unwrap()
+        }
         Err(_) => {
-            let creds_path = fs.as_ref().join("acme.json");
             warn!(
                 "no valid certificate found at {}, creating one...",
                 tls_path.display()
             );
+            create_certs(fs, public, acme).await.unwrap()
+        }
+    }
+}
 
-            if !creds_path.exists() {
-                panic!(
-                    "no ACME credentials found at {}, cannot continue with certificate creation",
-                    creds_path.display()
-                );
-            }
+/// Create a certificate for `public` and all of its subdomains, and save it for the next start
+async fn create_certs<P: AsRef<Path>>(
+    fs: P,
+    public: FQDN,
+    acme: AcmeClient,
+) -> Result<ChainAndPrivateKey, Error> {
+    let tls_path = fs.as_ref().join("ssl.pem");
+    let creds = load_credentials(&fs)?;
 
-            let creds = std::fs::File::open(creds_path).unwrap();
-            let creds: AccountCredentials = serde_json::from_reader(&creds).unwrap();
+    let identifiers = [format!("*.{public}"), public.to_string()];
 
-            let identifier = format!("*.{public}");
+    // Use ::Dns01 challenge because that's the only supported
+    // challenge type for wildcard domains
+    let (chain, private_key) = acme
+        .create_certificate(&identifiers, ChallengeType::Dns01, creds)
+        .await?;
 
-            // Use ::Dns01 challenge because that's the only supported
-            // challenge type for wildcard domains
-            let (chain, private_key) = acme
-                .create_certificate(&identifier, ChallengeType::Dns01, creds)
-                .await
-                .unwrap();
+    let mut buf = Vec::new();
+    buf.extend(chain.as_bytes());
+    buf.extend(private_key.as_bytes());
 
-            let mut buf = Vec::new();
-            buf.extend(chain.as_bytes());
-            buf.extend(private_key.as_bytes());
+    let certs = ChainAndPrivateKey::parse_pem(Cursor::new(buf))?;
+
+    certs.clone().save_pem(&tls_path)?;
+
+    Ok(certs)
+}
+
+/// The credentials of the ACME account certificates are made with
+fn load_credentials<P: AsRef<Path>>(fs: P) -> Result<AccountCredentials<'static>, Error> {
+    let creds_path = fs.as_ref().join("acme.json");
+
+    if !creds_path.exists() {
+        return Err(Error::custom(
+            ErrorKind::Internal,
+            format!(
+                "no ACME credentials found at {}, cannot continue with certificate creation",
+                creds_path.display()
+            ),
+        ));
+    }
 
-            let certs = ChainAndPrivateKey::parse_pem(Cursor::new(buf)).unwrap();
+    let creds = std::fs::File::open(creds_path)?;
 
-            certs.clone().save_pem(&tls_path).unwrap();
+    serde_json::from_reader(&creds).map_err(|error| Error::source(ErrorKind::Internal, error))
+}
+
+/// Renew the certificate of the gateway and those of custom domains which expire within `threshold`, serving the new
+/// ones straight away. Custom domains are renewed with the ACME account the gateway uses for itself.
+async fn renew_certs(
+    gateway: &GatewayService,
+    fs: &Path,
+    public: &FQDN,
+    acme: &AcmeClient,
+    resolver: &GatewayCertResolver,
+    threshold: Duration,
+) {
+    let due = |certs: Result<ChainAndPrivateKey, Error>| {
+        certs.map_or(true, |certs| certs.expires_within(threshold, Utc::now()))
+    };
 
-            certs
+    if due(ChainAndPrivateKey::load_pem(fs.join("ssl.pem"))) {
+        info!(fqdn = %public, "renewing certificate");
+
+        match create_certs(fs, public.clone(), acme.clone()).await {
+            Ok(certs) => {
+                if let Err(error) = resolver.serve_default_der(certs).await {
+                    error!(
+                        error = &error as &dyn std::error::Error,
+                        fqdn = %public,
+                        "failed to serve renewed certificate"
+                    );
+                }
+            }
+            Err(error) => error!(
+                error = &error as &dyn std::error::Error,
+                fqdn = %public,
+                "failed to renew certificate"
+            ),
+        }
+    }
+
+    let custom_domains = match gateway.iter_custom_domains().await {
+        Ok(custom_domains) => custom_domains,
+        Err(error) => {
+            error!(
+                error = &error as &dyn std::error::Error,
+                "failed to get custom domains to renew"
+            );
+            return;
+        }
+    };
+
+    // The domains of a group share one certificate, which is renewed for all of them at once
+    let mut groups: BTreeMap<String, Vec<CustomDomain>> = BTreeMap::new();
+    for custom_domain in custom_domains {
+        groups
+            .entry(custom_domain.certificate_group.to_string())
+            .or_default()
+            .push(custom_domain);
+    }
+
+    for (group, mut custom_domains) in groups {
+        let certs = custom_domains[0].certificate.clone() + &custom_domains[0].private_key;
+        if !due(ChainAndPrivateKey::parse_pem(Cursor::new(certs))) {
+            continue;
+        }
+
+        // The domain naming the group goes first, like when the certificate was asked for
+        custom_domains.sort_by_key(|custom_domain| custom_domain.fqdn.to_string() != group);
+
+        info!(fqdn = %group, domains = custom_domains.len(), "renewing certificate");
+
+        if let Err(error) =
+            renew_custom_domain_group(gateway, fs, acme, resolver, &custom_domains).await
+        {
+            error!(
+                error = &error as &dyn std::error::Error,
+                fqdn = %group,
+                "failed to renew certificate"
+            );
         }
     }
 }
+
+async fn renew_custom_domain_group(
+    gateway: &GatewayService,
+    fs: &Path,
+    acme: &AcmeClient,
+    resolver: &GatewayCertResolver,
+    custom_domains: &[CustomDomain],
+) -> Result<(), Error> {
+    let fqdns: Vec<FQDN> = custom_domains
+        .iter()
+        .map(|custom_domain| custom_domain.fqdn.clone())
+        .collect();
+    let identifiers: Vec<_> = fqdns.iter().map(ToString::to_string).collect();
+
+    let (certs, private_key) = acme
+        .create_certificate(
+            &identifiers,
+            challenge_type_for(&identifiers),
+            load_credentials(fs)?,
+        )
+        .await?;
+
+    gateway
+        .create_custom_domain_group(
+            custom_domains[0].project_name.clone(),
+            &fqdns,
+            custom_domains[0].environment.as_deref(),
+            &certs,
+            &private_key,
+        )
+        .await?;
+
+    for fqdn in &identifiers {
+        let mut buf = Vec::new();
+        buf.extend(certs.as_bytes());
+        buf.extend(private_key.as_bytes());
+        resolver.replace_pem(fqdn, Cursor::new(buf)).await?;
+    }
+
+    Ok(())
+}
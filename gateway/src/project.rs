@@ -1103,9 +1103,18 @@ where
                     // for an "active" discord will be to close to the 20_000_000 of an idle framework. And
                     // discord will have more traffic in anyway. So using the 100_000_000 threshold of an
                     // active framework for now
-                    if cpu_per_minute < 100_000_000 {
+                    let idle = cpu_per_minute < 100_000_000;
+
+                    if idle && !ctx.keeps_warm(&service.name) {
                         Ok(Self::Next::Idle(ProjectStopping { container }))
                     } else {
+                        if idle {
+                            debug!(
+                                "{} is idle but pinned or among the most recently active projects, keeping it warm",
+                                service.name
+                            );
+                        }
+
                         Ok(Self::Next::Ready(ProjectReady {
                             container,
                             service,
@@ -1,40 +1,179 @@
+use std::collections::HashMap;
 use std::convert::Infallible;
+use std::error::Error as StdError;
 use std::future::Future;
 use std::io;
 use std::net::SocketAddr;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
+use arc_swap::ArcSwap;
 use axum::headers::{HeaderMapExt, Host};
-use axum::response::{IntoResponse, Response};
+use axum::response::{Html, IntoResponse, Redirect, Response};
 use axum_server::accept::DefaultAcceptor;
-use axum_server::tls_rustls::RustlsAcceptor;
+use axum_server::Handle;
 use fqdn::{fqdn, FQDN};
 use futures::future::{ready, Ready};
 use futures::prelude::*;
 use hyper::body::{Body, HttpBody};
 use hyper::client::connect::dns::GaiResolver;
 use hyper::client::HttpConnector;
+use hyper::header::{HeaderName, HeaderValue, AUTHORIZATION, HOST};
 use hyper::server::conn::AddrStream;
-use hyper::{Client, Request};
-use hyper_reverse_proxy::ReverseProxy;
-use once_cell::sync::Lazy;
+use hyper::{Client, HeaderMap, Request, StatusCode, Uri, Version};
+use hyper_reverse_proxy::{ProxyError, ReverseProxy};
 use opentelemetry::global;
 use opentelemetry_http::HeaderInjector;
-use shuttle_common::backends::headers::XShuttleProject;
+use shuttle_common::backends::headers::{XShuttleProject, X_SHUTTLE_NOT_DEPLOYED};
+use shuttle_common::models::error::ApiError;
+use shuttle_common::models::{header_limits, host_header, response_header};
 use tokio::sync::mpsc::Sender;
+use tokio::time::timeout_at;
 use tower::{Service, ServiceBuilder};
-use tracing::{debug_span, error, field, trace};
+use tracing::{debug, debug_span, error, field, trace, warn};
 use tracing_opentelemetry::OpenTelemetrySpanExt;
 
 use crate::acme::{AcmeClient, ChallengeResponderLayer, CustomDomain};
+use crate::basic_auth::unauthorized_response;
+use crate::connections::ConnectionLimit;
+use crate::deadline::DeadlineBody;
+use crate::project::{Project, ProjectError};
+use crate::proxy_protocol::{ClientAddr, ProxyProtocol};
 use crate::service::GatewayService;
 use crate::task::BoxedTask;
-use crate::{Error, ErrorKind};
+use crate::tls::GatewayTlsAcceptor;
+use crate::{Error, ErrorKind, ProjectName};
 
-static PROXY_CLIENT: Lazy<ReverseProxy<HttpConnector<GaiResolver>>> =
-    Lazy::new(|| ReverseProxy::new(Client::new()));
+type BackendProxy = Arc<ReverseProxy<HttpConnector<GaiResolver>>>;
+
+/// Keep-alive connection pools to the backends of projects.
+///
+/// Every project gets its own client so that all of its pooled connections can be
+/// dropped at once when its backend goes away or is replaced. The deployer of a project
+/// says when that happens. Clients of projects which have not been proxied to for longer
+/// than the idle timeout have no connections left and are evicted by [BackendPool::evict_idle].
+///
+/// The table of backends is never changed in place. Updates copy it and swap the copy in,
+/// so lookups on the request path are lock-free and always see a consistent snapshot.
+/// Requests which are in flight keep their own handle on the client they started with.
+#[derive(Clone)]
+pub struct BackendPool {
+    max_idle_per_backend: usize,
+    idle_timeout: Duration,
+    started: Instant,
+    backends: Arc<ArcSwap<HashMap<ProjectName, Backend>>>,
+}
+
+#[derive(Clone)]
+struct Backend {
+    target_url: String,
+    proxy: BackendProxy,
+    /// Milliseconds since the pool was started at which the backend was last proxied to
+    last_used: Arc<AtomicU64>,
+}
+
+impl BackendPool {
+    pub fn new(max_idle_per_backend: usize, idle_timeout: Duration) -> Self {
+        Self {
+            max_idle_per_backend,
+            idle_timeout,
+            started: Instant::now(),
+            backends: Default::default(),
+        }
+    }
+
+    /// Get the pooled client for a project's backend at `target_url`. A new pool
+    /// is started when the backend moved to a different address.
+    pub fn proxy_for(&self, project_name: &ProjectName, target_url: &str) -> BackendProxy {
+        if let Some(backend) = self.backends.load().get(project_name) {
+            if backend.target_url == target_url {
+                backend.last_used.store(self.now(), Ordering::Relaxed);
+                return backend.proxy.clone();
+            }
+        }
+
+        let fresh = self.new_backend(target_url);
+        let previous = self.backends.rcu(|backends| {
+            let mut backends = HashMap::clone(backends);
+
+            if backends
+                .get(project_name)
+                .map_or(true, |backend| backend.target_url != target_url)
+            {
+                backends.insert(project_name.clone(), fresh.clone());
+            }
+
+            backends
+        });
+
+        // A concurrent request may have swapped in a pool for the same backend first
+        match previous.get(project_name) {
+            Some(backend) if backend.target_url == target_url => backend.proxy.clone(),
+            Some(_) => {
+                trace!(%project_name, target_url, "backend moved, dropping its pooled connections");
+                fresh.proxy
+            }
+            None => fresh.proxy,
+        }
+    }
+
+    /// Drop all pooled connections to a project's backend
+    pub fn invalidate(&self, project_name: &ProjectName) {
+        let previous = self.backends.rcu(|backends| {
+            let mut backends = HashMap::clone(backends);
+            backends.remove(project_name);
+            backends
+        });
+
+        if previous.contains_key(project_name) {
+            trace!(%project_name, "dropped pooled connections");
+        }
+    }
+
+    /// Drop the clients of backends which were not proxied to for longer than the idle
+    /// timeout. Returns how many were dropped.
+    pub fn evict_idle(&self) -> usize {
+        let cutoff = self
+            .now()
+            .saturating_sub(self.idle_timeout.as_millis() as u64);
+        let is_idle = |backend: &Backend| backend.last_used.load(Ordering::Relaxed) <= cutoff;
+
+        if !self.backends.load().values().any(is_idle) {
+            return 0;
+        }
+
+        let previous = self.backends.rcu(|backends| {
+            let mut backends = HashMap::clone(backends);
+            backends.retain(|_, backend| !is_idle(backend));
+            backends
+        });
+        let evicted = previous.len().saturating_sub(self.backends.load().len());
+
+        trace!(evicted, "evicted idle backends");
+
+        evicted
+    }
+
+    fn new_backend(&self, target_url: &str) -> Backend {
+        let client = Client::builder()
+            .pool_max_idle_per_host(self.max_idle_per_backend)
+            .pool_idle_timeout(self.idle_timeout)
+            .build_http();
+
+        Backend {
+            target_url: target_url.to_string(),
+            proxy: Arc::new(ReverseProxy::new(client)),
+            last_used: Arc::new(AtomicU64::new(self.now())),
+        }
+    }
+
+    fn now(&self) -> u64 {
+        self.started.elapsed().as_millis() as u64
+    }
+}
 
 pub trait AsResponderTo<R> {
     fn as_responder_to(&self, req: R) -> Self;
@@ -68,12 +207,35 @@ where
     }
 }
 
+/// How requests for the bare public domain are answered, since it is not the host of a project
+#[derive(Clone, Debug)]
+pub enum Apex {
+    Redirect(Uri),
+    Page(Arc<str>),
+    Status(StatusCode),
+}
+
+impl Apex {
+    fn response(&self) -> Response {
+        match self {
+            Self::Redirect(location) => Redirect::temporary(&location.to_string()).into_response(),
+            Self::Page(page) => Html(page.to_string()).into_response(),
+            Self::Status(status) => status.into_response(),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct UserProxy {
     gateway: Arc<GatewayService>,
     task_sender: Sender<BoxedTask>,
     remote_addr: SocketAddr,
     public: FQDN,
+    /// Where requests for hosts which are not a project are sent, instead of getting a not found error
+    unknown_host_redirect: Option<Uri>,
+    /// Page answering requests for projects which have nothing deployed, instead of the error
+    not_deployed_page: Option<Arc<str>>,
+    apex: Option<Apex>,
 }
 
 impl<'r> AsResponderTo<&'r AddrStream> for UserProxy {
@@ -90,6 +252,22 @@ impl UserProxy {
         task_sender: Sender<BoxedTask>,
         mut req: Request<Body>,
     ) -> Result<Response, Error> {
+        let started = Instant::now();
+
+        // A client speaking HTTP/2 only names the host in the URI, while backends are always spoken to in HTTP/1.1
+        if req.version() == Version::HTTP_2 {
+            if let Some(host) = req
+                .uri()
+                .authority()
+                .filter(|_| !req.headers().contains_key(HOST))
+                .and_then(|authority| HeaderValue::from_str(authority.as_str()).ok())
+            {
+                req.headers_mut().insert(HOST, host);
+            }
+
+            *req.version_mut() = Version::HTTP_11;
+        }
+
         let span = debug_span!("proxy", http.method = %req.method(), http.host = ?req.headers().get("Host"), http.uri = %req.uri(), http.status_code = field::Empty, project = field::Empty);
         trace!(?req, "serving proxy request");
 
@@ -99,6 +277,10 @@ impl UserProxy {
             .map(|host| fqdn!(host.hostname()))
             .ok_or_else(|| Error::from_kind(ErrorKind::ProjectNotFound))?;
 
+        if let Some(apex) = self.apex.as_ref().filter(|_| fqdn == self.public) {
+            return Ok(apex.response());
+        }
+
         let project_name =
             if fqdn.is_subdomain_of(&self.public) && fqdn.depth() - self.public.depth() == 1 {
                 fqdn.labels()
@@ -115,6 +297,48 @@ impl UserProxy {
                 return Err(Error::from_kind(ErrorKind::ProjectNotFound));
             };
 
+        // Behind a load balancer the peer of the connection is the load balancer, not the client
+        let client_addr = req
+            .extensions()
+            .get::<ClientAddr>()
+            .map_or(self.remote_addr, |ClientAddr(client_addr)| *client_addr);
+        let client_ip = client_addr.ip();
+        if !self
+            .gateway
+            .ip_filter_for_project(&project_name)
+            .await?
+            .allows(client_ip)
+        {
+            trace!(%client_ip, %project_name, "client is not allowed to reach project");
+            return Err(Error::from_kind(ErrorKind::Forbidden));
+        }
+
+        let header_limits = self
+            .gateway
+            .header_limits_for_project(&project_name)
+            .await?
+            .or(self.gateway.default_header_limits());
+        if exceeds_header_limits(req.headers(), &header_limits) {
+            trace!(%project_name, "request headers are over the limits of the project");
+            return Err(Error::from_kind(ErrorKind::RequestHeadersTooLarge));
+        }
+
+        // Checked before the project is found, so requests without credentials do not wake it up
+        if let Some(basic_auth) = self.gateway.basic_auth_for_project(&project_name).await? {
+            if !basic_auth.exempts(req.uri().path()) {
+                if !self
+                    .gateway
+                    .allows_basic_auth(&project_name, &basic_auth, req.headers())
+                {
+                    trace!(%project_name, "request does not have the basic auth credentials of the project");
+                    return Ok(unauthorized_response(&project_name));
+                }
+
+                // The credentials are for the platform, not for the service
+                req.headers_mut().remove(AUTHORIZATION);
+            }
+        }
+
         req.headers_mut()
             .typed_insert(XShuttleProject(project_name.to_string()));
 
@@ -122,15 +346,40 @@ impl UserProxy {
             .gateway
             .find_or_start_project(&project_name, task_sender)
             .await?;
+        self.gateway.keep_warm().touch(&project_name);
 
         // Record current project for tracing purposes
         span.record("project", &project_name.to_string());
 
+        if let Project::Errored(error) = &project {
+            return Ok(errored_response(
+                error,
+                self.gateway.expose_project_errors(),
+            ));
+        }
+
         let target_ip = project
             .target_ip()?
             .ok_or_else(|| Error::from_kind(ErrorKind::ProjectNotReady))?;
 
-        let target_url = format!("http://{}:{}", target_ip, 8000);
+        let container = SocketAddr::new(target_ip, 8000);
+        let container_url = format!("http://{container}");
+        let target = self.gateway.balancer().pick(&project_name, container);
+        let target_url = format!("http://{target}");
+
+        match self.gateway.host_header_for_project(&project_name).await? {
+            host_header::Policy::Preserve => {}
+            host_header::Policy::Backend => {
+                let host = HeaderValue::try_from(target.to_string())
+                    .expect("a socket address to be a valid header value");
+                req.headers_mut().insert(HOST, host);
+            }
+            host_header::Policy::Fixed(host) => {
+                let host = HeaderValue::from_str(&host)
+                    .map_err(|_| Error::from_kind(ErrorKind::InvalidHostHeader))?;
+                req.headers_mut().insert(HOST, host);
+            }
+        }
 
         let cx = span.context();
 
@@ -138,13 +387,130 @@ impl UserProxy {
             propagator.inject_context(&cx, &mut HeaderInjector(req.headers_mut()))
         });
 
-        let proxy = PROXY_CLIENT
-            .call(self.remote_addr.ip(), &target_url, req)
-            .await
-            .map_err(|_| Error::from_kind(ErrorKind::ProjectUnavailable))?;
+        let timeouts = self
+            .gateway
+            .proxy_timeouts_for_project(&project_name)
+            .await?;
+        let deadline = timeouts
+            .response_secs
+            .and_then(|secs| tokio::time::Instant::now().checked_add(Duration::from_secs(secs)));
+
+        // Only kept when slow requests of the project are logged
+        let slow_request = timeouts
+            .slow_request_ms
+            .map(Duration::from_millis)
+            .or(self.gateway.default_slow_request_threshold())
+            .map(|threshold| SlowRequest {
+                threshold,
+                path: req.uri().path().to_string(),
+            });
+
+        // The pool of a project follows its container, and holds the connections to its other instances too
+        let backend = self
+            .gateway
+            .backend_pool()
+            .proxy_for(&project_name, &container_url);
+        let call = backend.call(client_ip, &target_url, req);
+
+        let result = match deadline {
+            Some(deadline) => match timeout_at(deadline, call).await {
+                Ok(result) => result,
+                Err(_) => {
+                    let latency = started.elapsed();
+                    self.gateway.traffic().record(
+                        &project_name,
+                        StatusCode::GATEWAY_TIMEOUT,
+                        latency,
+                    );
+                    if let Some(slow_request) = &slow_request {
+                        slow_request.log(&project_name, StatusCode::GATEWAY_TIMEOUT, latency);
+                    }
+
+                    return Err(Error::from_kind(ErrorKind::GatewayTimeout));
+                }
+            },
+            None => call.await,
+        };
+
+        let status = result
+            .as_ref()
+            .map_or(StatusCode::BAD_GATEWAY, |response| response.status());
+        let latency = started.elapsed();
+        self.gateway
+            .traffic()
+            .record(&project_name, status, latency);
+        if let Some(slow_request) = &slow_request {
+            slow_request.log(&project_name, status, latency);
+        }
+
+        // An instance with nothing deployed is as good as down to the other instances
+        let healthy = result.as_ref().map_or(false, |response| {
+            !response.headers().contains_key(&X_SHUTTLE_NOT_DEPLOYED)
+        });
+        self.gateway
+            .balancer()
+            .report(&project_name, target, healthy);
+
+        let proxy = match result {
+            Ok(proxy) => proxy,
+            Err(ProxyError::HyperError(error)) if is_connection_reset(&error) => {
+                debug!(%project_name, %target, "backend reset the connection before responding");
+
+                // The other pooled connections to a backend which went away are as dead as this one
+                self.gateway.backend_pool().invalidate(&project_name);
+
+                return Err(Error::from_kind(ErrorKind::ProjectConnectionReset));
+            }
+            Err(_) => return Err(Error::from_kind(ErrorKind::ProjectUnavailable)),
+        };
+
+        // The project is up, but its deployer has no deployment to pass the request on to
+        if proxy.headers().contains_key(&X_SHUTTLE_NOT_DEPLOYED) {
+            return Err(Error::from_kind(ErrorKind::ProjectNotDeployed));
+        }
+
+        if exceeds_header_limits(proxy.headers(), &header_limits) {
+            trace!(%project_name, "response headers are over the limits of the project");
+            return Err(Error::from_kind(ErrorKind::ResponseHeadersTooLarge));
+        }
+
+        let (mut parts, body) = proxy.into_parts();
+
+        for (name, rule) in self
+            .gateway
+            .response_headers_for_project(&project_name)
+            .await?
+        {
+            let Ok(name) = HeaderName::try_from(name) else {
+                continue;
+            };
+
+            match rule {
+                response_header::Rule::Set(value) => {
+                    if let Ok(value) = HeaderValue::try_from(value) {
+                        parts.headers.insert(name, value);
+                    }
+                }
+                response_header::Rule::Remove => {
+                    parts.headers.remove(name);
+                }
+            }
+        }
 
-        let (parts, body) = proxy.into_parts();
-        let body = <Body as HttpBody>::map_err(body, axum::Error::new).boxed_unsync();
+        // The status is out by the time the body is cut short, so the connection to the client is closed to tell it the
+        // response is incomplete. The backend is only marked as down for the requests after this one.
+        let gateway = self.gateway.clone();
+        let body = DeadlineBody::new(body, &timeouts.stalled_body, deadline)
+            .map_err(move |error| {
+                if is_connection_reset(&error) {
+                    debug!(%project_name, %target, "backend reset the connection while sending a response body");
+                    gateway.balancer().report(&project_name, target, false);
+                    gateway.backend_pool().invalidate(&project_name);
+                }
+
+                error
+            })
+            .boxed_unsync();
 
         span.record("http.status_code", parts.status.as_u16());
 
@@ -152,6 +518,93 @@ impl UserProxy {
     }
 }
 
+/// A request which is logged when the backend takes longer than `threshold` to send the headers of its response. The body
+/// streams to the client once the headers are passed on, so the time it takes is not counted.
+struct SlowRequest {
+    threshold: Duration,
+    path: String,
+}
+
+impl SlowRequest {
+    fn is_slow(&self, latency: Duration) -> bool {
+        latency > self.threshold
+    }
+
+    fn log(&self, project_name: &ProjectName, status: StatusCode, latency: Duration) {
+        if self.is_slow(latency) {
+            warn!(
+                %project_name,
+                path = %self.path,
+                %status,
+                latency_ms = latency.as_millis() as u64,
+                threshold_ms = self.threshold.as_millis() as u64,
+                "slow request"
+            );
+        }
+    }
+}
+
+/// Whether headers go over any of the limits which are set
+fn exceeds_header_limits(headers: &HeaderMap, limits: &header_limits::Config) -> bool {
+    let mut total_size = 0;
+
+    for (name, value) in headers {
+        let size = (name.as_str().len() + value.len()) as u64;
+
+        if limits.max_header_size.map_or(false, |max| size > max) {
+            return true;
+        }
+
+        total_size += size;
+    }
+
+    limits
+        .max_count
+        .map_or(false, |max| headers.len() as u64 > max)
+        || limits.max_total_size.map_or(false, |max| total_size > max)
+}
+
+/// Whether an error comes down to the backend closing or resetting its connection, which is what a crashing service
+/// looks like from the proxy
+fn is_connection_reset(error: &(dyn StdError + 'static)) -> bool {
+    let mut source = Some(error);
+
+    while let Some(error) = source {
+        if let Some(error) = error.downcast_ref::<io::Error>() {
+            if matches!(
+                error.kind(),
+                io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::UnexpectedEof
+            ) {
+                return true;
+            }
+        }
+
+        if let Some(error) = error.downcast_ref::<hyper::Error>() {
+            if error.is_incomplete_message() || error.is_closed() {
+                return true;
+            }
+        }
+
+        source = error.source();
+    }
+
+    false
+}
+
+/// Answer for a project in an error state, which only tells why when the gateway is set up to
+fn errored_response(error: &ProjectError, expose: bool) -> Response {
+    let mut body = ApiError::from(ErrorKind::ProjectErrored);
+
+    if expose {
+        body.message = format!("{}: {error}", body.message);
+    }
+
+    (body.status(), axum::Json(body)).into_response()
+}
+
 impl Service<Request<Body>> for UserProxy {
     type Response = Response;
     type Error = Error;
@@ -164,17 +617,43 @@ impl Service<Request<Body>> for UserProxy {
 
     fn call(&mut self, req: Request<Body>) -> Self::Future {
         let task_sender = self.task_sender.clone();
+        let unknown_host_redirect = self.unknown_host_redirect.clone();
+        let not_deployed_page = self.not_deployed_page.clone();
         self.clone()
             .proxy(task_sender, req)
-            .or_else(|err: Error| future::ready(Ok(err.into_response())))
+            .or_else(move |err: Error| {
+                future::ready(Ok(error_response(
+                    err,
+                    unknown_host_redirect.as_ref(),
+                    not_deployed_page.as_deref(),
+                )))
+            })
             .boxed()
     }
 }
 
+/// Answer to a request the user proxy failed on, which is a redirect or a page of the gateway for some errors
+fn error_response(
+    err: Error,
+    unknown_host_redirect: Option<&Uri>,
+    not_deployed_page: Option<&str>,
+) -> Response {
+    match (err.kind(), unknown_host_redirect, not_deployed_page) {
+        (ErrorKind::ProjectNotFound, Some(location), _) => {
+            Redirect::temporary(&location.to_string()).into_response()
+        }
+        (ErrorKind::ProjectNotDeployed, _, Some(page)) => {
+            (StatusCode::SERVICE_UNAVAILABLE, Html(page.to_string())).into_response()
+        }
+        _ => err.into_response(),
+    }
+}
+
 #[derive(Clone)]
 pub struct Bouncer {
     gateway: Arc<GatewayService>,
     public: FQDN,
+    apex: Option<Apex>,
 }
 
 impl<'r> AsResponderTo<&'r AddrStream> for Bouncer {
@@ -193,6 +672,11 @@ impl Bouncer {
 
         let path = req.uri();
 
+        // The wildcard certificate does not cover the bare domain, so it is not sent on to HTTPS
+        if let Some(apex) = self.apex.as_ref().filter(|_| fqdn == self.public) {
+            return Ok(apex.response());
+        }
+
         if fqdn.is_subdomain_of(&self.public)
             || self
                 .gateway
@@ -232,10 +716,16 @@ pub struct UserServiceBuilder {
     service: Option<Arc<GatewayService>>,
     task_sender: Option<Sender<BoxedTask>>,
     acme: Option<AcmeClient>,
-    tls_acceptor: Option<RustlsAcceptor<DefaultAcceptor>>,
+    tls_acceptor: Option<GatewayTlsAcceptor>,
     bouncer_binds_to: Option<SocketAddr>,
     user_binds_to: Option<SocketAddr>,
+    user_handle: Handle,
     public: Option<FQDN>,
+    unknown_host_redirect: Option<Uri>,
+    not_deployed_page: Option<String>,
+    apex: Option<Apex>,
+    connections: ConnectionLimit,
+    proxy_protocol: ProxyProtocol,
 }
 
 impl Default for UserServiceBuilder {
@@ -254,6 +744,12 @@ impl UserServiceBuilder {
             tls_acceptor: None,
             bouncer_binds_to: None,
             user_binds_to: None,
+            user_handle: Handle::new(),
+            unknown_host_redirect: None,
+            not_deployed_page: None,
+            apex: None,
+            connections: ConnectionLimit::new(None),
+            proxy_protocol: ProxyProtocol::default(),
         }
     }
 
@@ -272,8 +768,42 @@ impl UserServiceBuilder {
         self
     }
 
-    pub fn with_bouncer(mut self, bound_to: SocketAddr) -> Self {
-        self.bouncer_binds_to = Some(bound_to);
+    /// Serve the bouncer, which redirects plain HTTP requests to HTTPS and answers ACME challenges, on `bound_to`.
+    /// Without one, plain HTTP requests only reach the user proxy when TLS is disabled.
+    pub fn with_bouncer(mut self, bound_to: Option<SocketAddr>) -> Self {
+        self.bouncer_binds_to = bound_to;
+        self
+    }
+
+    /// Redirect requests for hosts which are not a project, or a custom domain of one, to `location`
+    pub fn with_unknown_host_redirect(mut self, location: Option<Uri>) -> Self {
+        self.unknown_host_redirect = location;
+        self
+    }
+
+    /// Answer requests for projects which are up but have no running deployment with this HTML page, rather than an
+    /// error which reads like the project is broken
+    pub fn with_not_deployed_page(mut self, page: Option<String>) -> Self {
+        self.not_deployed_page = page;
+        self
+    }
+
+    /// Answer requests for the bare public domain with `apex`, rather than like those for any host which is not a
+    /// project
+    pub fn with_apex(mut self, apex: Option<Apex>) -> Self {
+        self.apex = apex;
+        self
+    }
+
+    /// Count the connections to the user proxy against `connections`, which may cap them
+    pub fn with_connection_limit(mut self, connections: ConnectionLimit) -> Self {
+        self.connections = connections;
+        self
+    }
+
+    /// Read the PROXY protocol header load balancers put in front of their connections, to know the actual clients
+    pub fn with_proxy_protocol(mut self, proxy_protocol: ProxyProtocol) -> Self {
+        self.proxy_protocol = proxy_protocol;
         self
     }
 
@@ -287,11 +817,16 @@ impl UserServiceBuilder {
         self
     }
 
-    pub fn with_tls(mut self, acceptor: RustlsAcceptor<DefaultAcceptor>) -> Self {
+    pub fn with_tls(mut self, acceptor: GatewayTlsAcceptor) -> Self {
         self.tls_acceptor = Some(acceptor);
         self
     }
 
+    /// Handle to the user proxy's server, which tells when it is listening
+    pub fn user_handle(&self) -> Handle {
+        self.user_handle.clone()
+    }
+
     pub fn serve(self) -> impl Future<Output = Result<(), io::Error>> {
         let service = self.service.expect("a GatewayService is required");
         let task_sender = self.task_sender.expect("a task sender is required");
@@ -305,36 +840,46 @@ impl UserServiceBuilder {
             task_sender,
             remote_addr: "127.0.0.1:80".parse().unwrap(),
             public: public.clone(),
+            unknown_host_redirect: self.unknown_host_redirect,
+            not_deployed_page: self.not_deployed_page.map(Arc::from),
+            apex: self.apex.clone(),
         };
 
         let bouncer = self.bouncer_binds_to.as_ref().map(|_| Bouncer {
             gateway: service.clone(),
             public: public.clone(),
+            apex: self.apex,
         });
 
         let mut futs = Vec::new();
         if let Some(tls_acceptor) = self.tls_acceptor {
             // TLS is enabled
-            let bouncer = bouncer.expect("TLS cannot be enabled without a bouncer");
-            let bouncer_binds_to = self.bouncer_binds_to.unwrap();
+            if let Some(bouncer) = bouncer {
+                let bouncer_binds_to = self.bouncer_binds_to.unwrap();
 
-            let acme = self
-                .acme
-                .expect("TLS cannot be enabled without an ACME client");
+                let acme = self
+                    .acme
+                    .expect("TLS cannot be enabled without an ACME client");
 
-            let bouncer = ServiceBuilder::new()
-                .layer(ChallengeResponderLayer::new(acme))
-                .service(bouncer);
+                let bouncer = ServiceBuilder::new()
+                    .layer(ChallengeResponderLayer::new(acme))
+                    .service(bouncer);
 
-            let bouncer = axum_server::Server::bind(bouncer_binds_to)
-                .serve(bouncer.into_make_service())
-                .map(|handle| ("bouncer (with challenge responder)", handle))
-                .boxed();
+                let bouncer = axum_server::Server::bind(bouncer_binds_to)
+                    .acceptor(self.proxy_protocol.acceptor(DefaultAcceptor::new()))
+                    .serve(bouncer.into_make_service())
+                    .map(|handle| ("bouncer (with challenge responder)", handle))
+                    .boxed();
 
-            futs.push(bouncer);
+                futs.push(bouncer);
+            }
 
             let user_with_tls = axum_server::Server::bind(user_binds_to)
-                .acceptor(tls_acceptor)
+                .handle(self.user_handle)
+                .acceptor(
+                    self.connections
+                        .acceptor(self.proxy_protocol.acceptor(tls_acceptor)),
+                )
                 .serve(user_proxy.into_make_service())
                 .map(|handle| ("user proxy (with TLS)", handle))
                 .boxed();
@@ -344,6 +889,7 @@ impl UserServiceBuilder {
                 // bouncer is enabled
                 let bouncer_binds_to = self.bouncer_binds_to.unwrap();
                 let bouncer = axum_server::Server::bind(bouncer_binds_to)
+                    .acceptor(self.proxy_protocol.acceptor(DefaultAcceptor::new()))
                     .serve(bouncer.into_make_service())
                     .map(|handle| ("bouncer (without challenge responder)", handle))
                     .boxed();
@@ -351,6 +897,11 @@ impl UserServiceBuilder {
             }
 
             let user_without_tls = axum_server::Server::bind(user_binds_to)
+                .handle(self.user_handle)
+                .acceptor(
+                    self.connections
+                        .acceptor(self.proxy_protocol.acceptor(DefaultAcceptor::new())),
+                )
                 .serve(user_proxy.into_make_service())
                 .map(|handle| ("user proxy (no TLS)", handle))
                 .boxed();
@@ -363,3 +914,256 @@ impl UserServiceBuilder {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use hyper::header::{HeaderValue, COOKIE};
+    use hyper::{HeaderMap, StatusCode};
+    use shuttle_common::models::header_limits;
+
+    use super::{
+        error_response, exceeds_header_limits, is_connection_reset, Apex, BackendPool, SlowRequest,
+    };
+    use crate::{Error, ErrorKind};
+
+    #[test]
+    fn connection_reset() {
+        let reset = std::io::Error::new(std::io::ErrorKind::ConnectionReset, "reset by peer");
+        assert!(is_connection_reset(&reset));
+
+        // As it comes out of the body of a response
+        let wrapped = axum::Error::new(std::io::Error::from(std::io::ErrorKind::UnexpectedEof));
+        assert!(is_connection_reset(&wrapped));
+
+        // A body which stalled was cut off by the proxy, not by the backend
+        let stalled = axum::Error::new(std::io::Error::from(std::io::ErrorKind::TimedOut));
+        assert!(!is_connection_reset(&stalled));
+    }
+
+    #[tokio::test]
+    async fn not_deployed_page() {
+        let page = "<h1>Nothing here yet</h1>";
+
+        let response = error_response(
+            Error::from_kind(ErrorKind::ProjectNotDeployed),
+            None,
+            Some(page),
+        );
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            response.headers()[hyper::header::CONTENT_TYPE],
+            "text/html; charset=utf-8"
+        );
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, page);
+
+        // Without a page the error of the gateway is sent as it is
+        let response = error_response(Error::from_kind(ErrorKind::ProjectNotDeployed), None, None);
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("has not been deployed"));
+
+        // The page is only for projects which have nothing deployed
+        let response = error_response(
+            Error::from_kind(ErrorKind::ProjectNotReady),
+            None,
+            Some(page),
+        );
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_ne!(body, page);
+    }
+
+    #[test]
+    fn slow_requests() {
+        let slow_request = SlowRequest {
+            threshold: Duration::from_millis(500),
+            path: "/red-pill".to_string(),
+        };
+
+        assert!(!slow_request.is_slow(Duration::from_millis(499)));
+        assert!(!slow_request.is_slow(Duration::from_millis(500)));
+        assert!(slow_request.is_slow(Duration::from_millis(501)));
+
+        #[derive(Clone, Default)]
+        struct Captured(Arc<Mutex<Vec<u8>>>);
+
+        impl std::io::Write for Captured {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let captured = Captured::default();
+        let subscriber = || {
+            let writer = captured.clone();
+            tracing_subscriber::fmt()
+                .with_writer(move || writer.clone())
+                .with_ansi(false)
+                .finish()
+        };
+        let project_name = "matrix".parse().unwrap();
+
+        tracing::subscriber::with_default(subscriber(), || {
+            slow_request.log(&project_name, StatusCode::OK, Duration::from_millis(200));
+        });
+        assert!(
+            captured.0.lock().unwrap().is_empty(),
+            "requests within the threshold are not logged"
+        );
+
+        tracing::subscriber::with_default(subscriber(), || {
+            slow_request.log(
+                &project_name,
+                StatusCode::GATEWAY_TIMEOUT,
+                Duration::from_millis(750),
+            );
+        });
+        let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        assert!(output.contains("slow request"), "{output}");
+        for field in [
+            "project_name=matrix",
+            "path=/red-pill",
+            "status=504 Gateway Timeout",
+            "latency_ms=750",
+            "threshold_ms=500",
+        ] {
+            assert!(output.contains(field), "{field} in {output}");
+        }
+    }
+
+    #[test]
+    fn header_limits() {
+        let mut headers = HeaderMap::new();
+        headers.insert(COOKIE, HeaderValue::from_static("session=red-pill"));
+        headers.append("x-operator", HeaderValue::from_static("tank"));
+        headers.append("x-operator", HeaderValue::from_static("dozer"));
+
+        assert!(!exceeds_header_limits(
+            &headers,
+            &header_limits::Config::default()
+        ));
+
+        let fits = header_limits::Config {
+            max_total_size: Some(51),
+            max_count: Some(3),
+            max_header_size: Some(22),
+        };
+        assert!(!exceeds_header_limits(&headers, &fits));
+
+        for over in [
+            header_limits::Config {
+                max_total_size: Some(50),
+                ..fits.clone()
+            },
+            // Every value of a repeated header counts
+            header_limits::Config {
+                max_count: Some(2),
+                ..fits.clone()
+            },
+            header_limits::Config {
+                max_header_size: Some(21),
+                ..fits.clone()
+            },
+        ] {
+            assert!(exceeds_header_limits(&headers, &over), "{over}");
+        }
+    }
+
+    #[test]
+    fn backend_pool_reuse_and_invalidation() {
+        let pool = BackendPool::new(8, Duration::from_secs(30));
+        let project = "matrix".parse().unwrap();
+        let other_project = "neo".parse().unwrap();
+
+        let proxy = pool.proxy_for(&project, "http://10.0.0.2:8000");
+
+        assert!(
+            Arc::ptr_eq(&proxy, &pool.proxy_for(&project, "http://10.0.0.2:8000")),
+            "the same backend should reuse its pool"
+        );
+        assert!(!Arc::ptr_eq(
+            &proxy,
+            &pool.proxy_for(&other_project, "http://10.0.0.2:8000")
+        ));
+
+        let moved = pool.proxy_for(&project, "http://10.0.0.3:8000");
+        assert!(
+            !Arc::ptr_eq(&proxy, &moved),
+            "a replaced backend should get a fresh pool"
+        );
+
+        pool.invalidate(&project);
+        assert!(!Arc::ptr_eq(
+            &moved,
+            &pool.proxy_for(&project, "http://10.0.0.3:8000")
+        ));
+    }
+
+    #[test]
+    fn backend_pool_eviction() {
+        let project: crate::ProjectName = "matrix".parse().unwrap();
+
+        let pool = BackendPool::new(8, Duration::from_secs(30));
+        let proxy = pool.proxy_for(&project, "http://10.0.0.2:8000");
+        assert_eq!(pool.evict_idle(), 0, "recently used backends are kept");
+        assert!(Arc::ptr_eq(
+            &proxy,
+            &pool.proxy_for(&project, "http://10.0.0.2:8000")
+        ));
+
+        let pool = BackendPool::new(8, Duration::ZERO);
+        let proxy = pool.proxy_for(&project, "http://10.0.0.2:8000");
+        assert_eq!(pool.evict_idle(), 1);
+        assert_eq!(pool.evict_idle(), 0);
+        assert!(!Arc::ptr_eq(
+            &proxy,
+            &pool.proxy_for(&project, "http://10.0.0.2:8000")
+        ));
+    }
+
+    #[test]
+    fn backend_pool_concurrent_lookups() {
+        let pool = BackendPool::new(8, Duration::from_secs(30));
+        let project: crate::ProjectName = "matrix".parse().unwrap();
+
+        let proxies: Vec<_> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..8)
+                .map(|_| scope.spawn(|| pool.proxy_for(&project, "http://10.0.0.2:8000")))
+                .collect();
+
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect()
+        });
+
+        assert!(
+            proxies.iter().all(|proxy| Arc::ptr_eq(proxy, &proxies[0])),
+            "racing lookups should all end up on the pool which was swapped in"
+        );
+    }
+
+    #[test]
+    fn apex_responses() {
+        let response = Apex::Redirect("https://www.shuttle.rs/".parse().unwrap()).response();
+        assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+        assert_eq!(response.headers()["location"], "https://www.shuttle.rs/");
+
+        let response = Apex::Page("<h1>shuttle</h1>".into()).response();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()["content-type"],
+            "text/html; charset=utf-8"
+        );
+
+        let response = Apex::Status(StatusCode::GONE).response();
+        assert_eq!(response.status(), StatusCode::GONE);
+    }
+}
@@ -0,0 +1,369 @@
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use axum::http::Request;
+use axum_server::accept::Accept;
+use hyper::server::conn::AddrStream;
+use ipnet::IpNet;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::time::timeout;
+use tower::Service;
+use tracing::{trace, warn};
+
+use crate::ip_filter::canonical;
+
+/// How long a trusted load balancer gets to send the PROXY header of a new connection
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest line a version 1 header can take, as given by the specification
+const V1_MAX_LENGTH: usize = 107;
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Which peers of a listener are load balancers putting a PROXY protocol header (version 1 or 2) in front of every
+/// connection, to pass on the address of the client they are relaying.
+///
+/// Only connections from those peers are expected to have a header, and they must have one. Anyone else could
+/// otherwise claim to be any client, getting around the IP rules of projects.
+#[derive(Clone, Default)]
+pub struct ProxyProtocol {
+    trusted: Arc<Vec<IpNet>>,
+}
+
+/// Address of the client a load balancer relayed a request for, put in the extensions of the request
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClientAddr(pub SocketAddr);
+
+impl ProxyProtocol {
+    /// Without any trusted peers connections are passed on as they are
+    pub fn new(trusted: Vec<IpNet>) -> Self {
+        Self {
+            trusted: Arc::new(trusted),
+        }
+    }
+
+    /// Wrap the acceptor of a listener so that it reads the header of connections from trusted peers first
+    pub fn acceptor<A>(&self, inner: A) -> ProxyProtocolAcceptor<A> {
+        ProxyProtocolAcceptor {
+            inner,
+            protocol: self.clone(),
+        }
+    }
+
+    fn trusts(&self, peer: IpAddr) -> bool {
+        let peer = canonical(peer);
+
+        self.trusted.iter().any(|net| net.contains(&peer))
+    }
+}
+
+#[derive(Clone)]
+pub struct ProxyProtocolAcceptor<A> {
+    inner: A,
+    protocol: ProxyProtocol,
+}
+
+impl<S, A> Accept<AddrStream, S> for ProxyProtocolAcceptor<A>
+where
+    A: Accept<AddrStream, WithClientAddr<S>> + Clone + Send + 'static,
+    A::Future: Send,
+    S: Send + 'static,
+{
+    type Stream = A::Stream;
+    type Service = A::Service;
+    type Future = Pin<Box<dyn Future<Output = io::Result<(Self::Stream, Self::Service)>> + Send>>;
+
+    fn accept(&self, mut stream: AddrStream, service: S) -> Self::Future {
+        let inner = self.inner.clone();
+        let protocol = self.protocol.clone();
+
+        Box::pin(async move {
+            let peer = stream.remote_addr();
+
+            let client = if protocol.trusts(peer.ip()) {
+                match timeout(HEADER_TIMEOUT, read_header(&mut stream)).await {
+                    Ok(Ok(client)) => {
+                        trace!(%peer, ?client, "read PROXY protocol header");
+                        client
+                    }
+                    Ok(Err(error)) => {
+                        warn!(%peer, %error, "dropping connection with an invalid PROXY protocol header");
+                        return Err(error);
+                    }
+                    Err(_) => {
+                        warn!(%peer, "dropping connection which did not send a PROXY protocol header in time");
+                        return Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "no PROXY protocol header",
+                        ));
+                    }
+                }
+            } else {
+                None
+            };
+
+            inner
+                .accept(
+                    stream,
+                    WithClientAddr {
+                        inner: service,
+                        client,
+                    },
+                )
+                .await
+        })
+    }
+}
+
+/// The service of a connection, telling every request on it which client it is from when that is not the peer
+#[derive(Clone)]
+pub struct WithClientAddr<S> {
+    inner: S,
+    client: Option<SocketAddr>,
+}
+
+impl<S, B> Service<Request<B>> for WithClientAddr<S>
+where
+    S: Service<Request<B>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<B>) -> Self::Future {
+        if let Some(client) = self.client {
+            req.extensions_mut().insert(ClientAddr(client));
+        }
+
+        self.inner.call(req)
+    }
+}
+
+/// Read a version 1 or 2 header off the start of a connection, without reading any further.
+///
+/// Returns the source address it gives, if any. Health checks made by the load balancer itself, and connections it
+/// relays for protocols other than TCP, come without one.
+pub async fn read_header<R: AsyncRead + Unpin>(stream: &mut R) -> io::Result<Option<SocketAddr>> {
+    let mut prefix = [0; 6];
+    stream.read_exact(&mut prefix).await?;
+
+    if prefix == V1_PREFIX {
+        read_v1(stream).await
+    } else if prefix == V2_SIGNATURE[..6] {
+        read_v2(stream).await
+    } else {
+        Err(invalid("expected a PROXY protocol header"))
+    }
+}
+
+async fn read_v1<R: AsyncRead + Unpin>(stream: &mut R) -> io::Result<Option<SocketAddr>> {
+    // Read a byte at a time since anything after the line belongs to the client
+    let mut line = Vec::with_capacity(V1_MAX_LENGTH);
+    while !line.ends_with(b"\r\n") {
+        if line.len() + V1_PREFIX.len() >= V1_MAX_LENGTH {
+            return Err(invalid("version 1 header is too long"));
+        }
+
+        line.push(stream.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid("version 1 header is not ASCII"))?;
+    let mut parts = line.split(' ');
+
+    match parts.next() {
+        Some("UNKNOWN") => return Ok(None),
+        Some("TCP4" | "TCP6") => {}
+        _ => return Err(invalid("unknown version 1 protocol")),
+    }
+
+    let parts: Vec<_> = parts.collect();
+    let [source, _destination, source_port, _destination_port] = parts[..] else {
+        return Err(invalid("malformed version 1 header"));
+    };
+
+    let ip: IpAddr = source
+        .parse()
+        .map_err(|_| invalid("invalid version 1 source address"))?;
+    let port: u16 = source_port
+        .parse()
+        .map_err(|_| invalid("invalid version 1 source port"))?;
+
+    Ok(Some(SocketAddr::new(ip, port)))
+}
+
+async fn read_v2<R: AsyncRead + Unpin>(stream: &mut R) -> io::Result<Option<SocketAddr>> {
+    let mut rest = [0; 10];
+    stream.read_exact(&mut rest).await?;
+
+    if rest[..6] != V2_SIGNATURE[6..] {
+        return Err(invalid("expected a PROXY protocol header"));
+    }
+
+    let version_command = rest[6];
+    let family = rest[7];
+    let length = u16::from_be_bytes([rest[8], rest[9]]);
+
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported version 2 header version"));
+    }
+
+    let mut addresses = vec![0; length as usize];
+    stream.read_exact(&mut addresses).await?;
+
+    match version_command & 0x0f {
+        // LOCAL, a connection made by the load balancer itself
+        0x0 => return Ok(None),
+        // PROXY
+        0x1 => {}
+        _ => return Err(invalid("unknown version 2 command")),
+    }
+
+    let port = |at: usize| u16::from_be_bytes([addresses[at], addresses[at + 1]]);
+
+    match family >> 4 {
+        // AF_INET
+        0x1 if addresses.len() >= 12 => {
+            let ip: [u8; 4] = addresses[..4].try_into().unwrap();
+
+            Ok(Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port(8))))
+        }
+        // AF_INET6
+        0x2 if addresses.len() >= 36 => {
+            let ip: [u8; 16] = addresses[..16].try_into().unwrap();
+
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port(32))))
+        }
+        0x1 | 0x2 => Err(invalid("version 2 addresses are too short")),
+        // AF_UNSPEC and AF_UNIX have no address to go by
+        _ => Ok(None),
+    }
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use tokio::io::AsyncReadExt;
+
+    use super::{read_header, ProxyProtocol};
+
+    async fn read(header: &[u8]) -> io::Result<Option<String>> {
+        let stream = [header, b"GET / HTTP/1.1\r\n"].concat();
+        let mut reader = stream.as_slice();
+
+        let client = read_header(&mut reader).await?;
+
+        // Nothing past the header should have been read
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).await?;
+        assert_eq!(rest, "GET / HTTP/1.1\r\n");
+
+        Ok(client.map(|client| client.to_string()))
+    }
+
+    #[tokio::test]
+    async fn v1() {
+        assert_eq!(
+            read(b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 443\r\n")
+                .await
+                .unwrap()
+                .as_deref(),
+            Some("203.0.113.7:51234")
+        );
+        assert_eq!(
+            read(b"PROXY TCP6 2001:db8::7 2001:db8::1 51234 443\r\n")
+                .await
+                .unwrap()
+                .as_deref(),
+            Some("[2001:db8::7]:51234")
+        );
+        assert_eq!(read(b"PROXY UNKNOWN\r\n").await.unwrap(), None);
+
+        assert!(read(b"PROXY TCP4 203.0.113.7 10.0.0.1 51234\r\n")
+            .await
+            .is_err());
+        assert!(read(b"PROXY UDP4 203.0.113.7 10.0.0.1 51234 443\r\n")
+            .await
+            .is_err());
+        assert!(read(&[b"PROXY TCP4 ".as_slice(), &[b'1'; 128]].concat())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn v2() {
+        let signature = b"\r\n\r\n\0\r\nQUIT\n".as_slice();
+
+        let inet = [
+            signature,
+            &[0x21, 0x11, 0, 12],
+            &[203, 0, 113, 7],
+            &[10, 0, 0, 1],
+            &51234u16.to_be_bytes(),
+            &443u16.to_be_bytes(),
+        ]
+        .concat();
+        assert_eq!(
+            read(&inet).await.unwrap().as_deref(),
+            Some("203.0.113.7:51234")
+        );
+
+        let mut source = [0; 16];
+        source[15] = 7;
+        let inet6_with_tlv = [
+            signature,
+            &[0x21, 0x21, 0, 36 + 4],
+            &source,
+            &[0; 16],
+            &51234u16.to_be_bytes(),
+            &443u16.to_be_bytes(),
+            // A TLV which is skipped over
+            &[0x04, 0, 1, 0xff],
+        ]
+        .concat();
+        assert_eq!(
+            read(&inet6_with_tlv).await.unwrap().as_deref(),
+            Some("[::7]:51234")
+        );
+
+        let local = [signature, &[0x20, 0x00, 0, 0]].concat();
+        assert_eq!(read(&local).await.unwrap(), None);
+
+        let short = [signature, &[0x21, 0x11, 0, 4], &[203, 0, 113, 7]].concat();
+        assert!(read(&short).await.is_err());
+
+        let version_one = [signature, &[0x11, 0x11, 0, 0]].concat();
+        assert!(read(&version_one).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn no_header() {
+        assert!(read(b"").await.is_err());
+    }
+
+    #[test]
+    fn trusts() {
+        let protocol = ProxyProtocol::new(vec!["10.0.0.0/8".parse().unwrap()]);
+
+        assert!(protocol.trusts("10.1.2.3".parse().unwrap()));
+        assert!(protocol.trusts("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!protocol.trusts("192.168.0.1".parse().unwrap()));
+        assert!(!ProxyProtocol::default().trusts("10.1.2.3".parse().unwrap()));
+    }
+}
@@ -1,33 +1,50 @@
+use std::collections::HashMap;
 use std::net::Ipv4Addr;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use axum::body::Body;
 use axum::headers::HeaderMapExt;
-use axum::http::Request;
+use axum::http::{HeaderName, HeaderValue, Request};
 use axum::response::Response;
 use bollard::{Docker, API_DEFAULT_VERSION};
-use fqdn::Fqdn;
+use chrono::{DateTime, Utc};
+use fqdn::{Fqdn, FQDN};
 use hyper::client::connect::dns::GaiResolver;
 use hyper::client::HttpConnector;
-use hyper::Client;
+use hyper::{Client, HeaderMap};
 use hyper_reverse_proxy::ReverseProxy;
+use ipnet::IpNet;
 use once_cell::sync::Lazy;
 use opentelemetry::global;
 use opentelemetry_http::HeaderInjector;
+use rand::distributions::{Alphanumeric, DistString};
 use shuttle_common::backends::headers::{XShuttleAccountName, XShuttleAdminSecret};
+use shuttle_common::models::{
+    basic_auth, header_limits, host_header, ip_rule, project, response_header, timeouts, usage,
+};
 use sqlx::error::DatabaseError;
 use sqlx::migrate::Migrator;
 use sqlx::sqlite::SqlitePool;
 use sqlx::types::Json as SqlxJson;
 use sqlx::{query, Error as SqlxError, Row};
 use tokio::sync::mpsc::Sender;
+use tokio::time::timeout;
 use tracing::{debug, trace, Span};
 use tracing_opentelemetry::OpenTelemetrySpanExt;
 
 use crate::acme::CustomDomain;
-use crate::args::ContextArgs;
+use crate::args::{ContextArgs, ErroredProjectPolicy, QueueFullPolicy};
+use crate::balancer::Balancer;
+use crate::basic_auth::{BasicAuth, VerifiedCredentials};
+use crate::config_cache::ConfigCache;
+use crate::domain_verification::{self, DnsTxtLookup, TxtLookup};
+use crate::ip_filter::{parse_cidr, IpFilter};
+use crate::keep_warm::KeepWarm;
 use crate::project::{Project, ProjectCreating};
+use crate::proxy::BackendPool;
 use crate::task::{self, BoxedTask, TaskBuilder};
+use crate::traffic::TrafficStats;
 use crate::worker::TaskRouter;
 use crate::{AccountName, DockerContext, Error, ErrorKind, ProjectDetails, ProjectName};
 
@@ -35,6 +52,25 @@ pub static MIGRATIONS: Migrator = sqlx::migrate!("./migrations");
 static PROXY_CLIENT: Lazy<ReverseProxy<HttpConnector<GaiResolver>>> =
     Lazy::new(|| ReverseProxy::new(Client::new()));
 
+/// Number of recorded states kept in the history of each project
+const PROJECT_EVENTS_KEPT: i64 = 50;
+
+/// Response headers which say how a response is framed or carried over the connection, so they are not for projects
+/// to change
+const FRAMING_HEADERS: &[&str] = &[
+    "connection",
+    "content-length",
+    "keep-alive",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Shortest time between two attempts at starting the same errored project again
+const RECOVERY_INTERVAL: Duration = Duration::from_secs(30);
+
 impl From<SqlxError> for Error {
     fn from(err: SqlxError) -> Self {
         debug!("internal SQLx error: {err}");
@@ -158,17 +194,23 @@ impl ContainerSettings {
 pub struct GatewayContextProvider {
     docker: Docker,
     settings: ContainerSettings,
+    keep_warm: Arc<KeepWarm>,
 }
 
 impl GatewayContextProvider {
-    pub fn new(docker: Docker, settings: ContainerSettings) -> Self {
-        Self { docker, settings }
+    pub fn new(docker: Docker, settings: ContainerSettings, keep_warm: Arc<KeepWarm>) -> Self {
+        Self {
+            docker,
+            settings,
+            keep_warm,
+        }
     }
 
     pub fn context(&self) -> GatewayContext {
         GatewayContext {
             docker: self.docker.clone(),
             settings: self.settings.clone(),
+            keep_warm: self.keep_warm.clone(),
         }
     }
 }
@@ -177,6 +219,34 @@ pub struct GatewayService {
     provider: GatewayContextProvider,
     db: SqlitePool,
     task_router: TaskRouter<BoxedTask>,
+    backend_pool: BackendPool,
+    traffic: TrafficStats,
+    balancer: Balancer,
+    queue_full_policy: QueueFullPolicy,
+    queue_send_timeout: Duration,
+    max_projects_per_account: Option<u64>,
+    max_custom_domains_per_project: Option<u64>,
+    max_custom_domains_for: HashMap<AccountName, u64>,
+    /// Checks the TXT records of custom domains, unless verifying them is turned off
+    txt_lookup: Option<Arc<dyn TxtLookup>>,
+    errored_project_policy: ErroredProjectPolicy,
+    errored_project_hold: Duration,
+    expose_project_errors: bool,
+    /// Limits on headers for the projects which do not set their own
+    header_limits: header_limits::Config,
+    /// Threshold over which requests are logged as slow, for the projects which do not set their own
+    slow_request_threshold: Option<Duration>,
+    /// When each errored project was last started again, to not do it on every request it gets
+    recoveries: Mutex<HashMap<ProjectName, Instant>>,
+    keep_warm: Arc<KeepWarm>,
+    /// Settings of projects the proxy looks up for every request
+    ip_filters: ConfigCache<IpFilter>,
+    host_headers: ConfigCache<host_header::Policy>,
+    proxy_timeouts: ConfigCache<timeouts::Config>,
+    project_header_limits: ConfigCache<header_limits::Config>,
+    response_headers: ConfigCache<response_header::Rules>,
+    basic_auths: ConfigCache<Option<BasicAuth>>,
+    verified_credentials: VerifiedCredentials,
 }
 
 impl GatewayService {
@@ -189,17 +259,125 @@ impl GatewayService {
 
         let container_settings = ContainerSettings::builder().from_args(&args).await;
 
-        let provider = GatewayContextProvider::new(docker, container_settings);
+        let keep_warm = Arc::new(KeepWarm::new(args.keep_warm_projects));
+
+        let pinned = query("SELECT project_name FROM project_pins")
+            .fetch_all(&db)
+            .await
+            .expect("pinned projects to be loaded");
+        for row in pinned {
+            keep_warm.pin(&row.get::<ProjectName, _>("project_name"));
+        }
+
+        let provider = GatewayContextProvider::new(docker, container_settings, keep_warm.clone());
 
         let task_router = TaskRouter::new();
 
+        let backend_pool = BackendPool::new(
+            args.proxy_max_idle_connections,
+            Duration::from_secs(args.proxy_idle_timeout),
+        );
+
+        let traffic = TrafficStats::new(
+            args.traffic_windows
+                .iter()
+                .map(|secs| Duration::from_secs(*secs))
+                .collect(),
+        );
+
+        let balancer = Balancer::new(Duration::from_secs(args.instance_ttl));
+
+        let txt_lookup = (!args.no_domain_verification).then(|| {
+            Arc::new(DnsTxtLookup::new().expect("a DNS resolver to be set up"))
+                as Arc<dyn TxtLookup>
+        });
+
         Self {
             provider,
             db,
             task_router,
+            backend_pool,
+            traffic,
+            balancer,
+            queue_full_policy: args.queue_full_policy,
+            queue_send_timeout: Duration::from_secs(args.queue_send_timeout),
+            max_projects_per_account: args.max_projects_per_account,
+            max_custom_domains_per_project: args.max_custom_domains_per_project,
+            max_custom_domains_for: args.max_custom_domains_for.into_iter().collect(),
+            txt_lookup,
+            errored_project_policy: args.errored_project_policy,
+            errored_project_hold: Duration::from_secs(args.errored_project_hold),
+            expose_project_errors: args.expose_project_errors,
+            slow_request_threshold: args.slow_request_threshold.map(Duration::from_millis),
+            header_limits: header_limits::Config {
+                max_total_size: Some(args.proxy_max_headers_size),
+                max_count: Some(args.proxy_max_headers),
+                max_header_size: Some(args.proxy_max_header_size),
+            },
+            recoveries: Default::default(),
+            keep_warm,
+            ip_filters: ConfigCache::new(),
+            host_headers: ConfigCache::new(),
+            proxy_timeouts: ConfigCache::new(),
+            project_header_limits: ConfigCache::new(),
+            response_headers: ConfigCache::new(),
+            basic_auths: ConfigCache::new(),
+            verified_credentials: Default::default(),
         }
     }
 
+    /// Whether clients are told why a project is in an error state
+    pub fn expose_project_errors(&self) -> bool {
+        self.expose_project_errors
+    }
+
+    /// Limits on the headers of requests to projects and of their responses, where projects do not set their own
+    pub fn default_header_limits(&self) -> &header_limits::Config {
+        &self.header_limits
+    }
+
+    /// Threshold over which requests to projects are logged as slow, where projects do not set their own
+    pub fn default_slow_request_threshold(&self) -> Option<Duration> {
+        self.slow_request_threshold
+    }
+
+    /// How new tasks are handled when the worker queue is full
+    pub fn queue_full_policy(&self) -> QueueFullPolicy {
+        self.queue_full_policy
+    }
+
+    /// Maximum time to wait for room in the worker queue under [`QueueFullPolicy::Backpressure`]
+    pub fn queue_send_timeout(&self) -> Duration {
+        self.queue_send_timeout
+    }
+
+    /// Keep-alive connections to the user services of projects
+    pub fn backend_pool(&self) -> &BackendPool {
+        &self.backend_pool
+    }
+
+    /// Requests which went through the user proxy to each project
+    pub fn traffic(&self) -> &TrafficStats {
+        &self.traffic
+    }
+
+    /// Instances the user proxy spreads the requests of each project over
+    pub fn balancer(&self) -> &Balancer {
+        &self.balancer
+    }
+
+    /// The most recently active projects, which are kept from idling
+    pub fn keep_warm(&self) -> &KeepWarm {
+        &self.keep_warm
+    }
+
+    /// Make a trivial query to check the state database can be reached
+    pub async fn ping_db(&self) -> Result<(), Error> {
+        query("SELECT 1").execute(&self.db).await?;
+
+        Ok(())
+    }
+
     pub async fn route(
         &self,
         project: &Project,
@@ -277,6 +455,50 @@ impl GatewayService {
         Ok(iter)
     }
 
+    /// What the account uses of the platform, against its limits
+    pub async fn account_usage(
+        &self,
+        account_name: &AccountName,
+    ) -> Result<usage::Response, Error> {
+        let projects: Vec<_> = self
+            .iter_user_projects_detailed(account_name.clone())
+            .await?
+            .map(|(_, project)| project)
+            .filter(|project| !project.is_destroyed())
+            .collect();
+
+        Ok(usage::Response {
+            account_name: account_name.to_string(),
+            projects: usage::Quota {
+                used: projects.len() as u64,
+                limit: self.max_projects_per_account,
+            },
+            running_projects: projects.iter().filter(|project| project.is_ready()).count() as u64,
+        })
+    }
+
+    /// Make sure the account has room for one more project, unless it is an admin
+    async fn check_project_limit(
+        &self,
+        account_name: &AccountName,
+        is_admin: bool,
+    ) -> Result<(), Error> {
+        if is_admin || self.max_projects_per_account.is_none() {
+            return Ok(());
+        }
+
+        if self
+            .account_usage(account_name)
+            .await?
+            .projects
+            .is_exhausted()
+        {
+            return Err(Error::from_kind(ErrorKind::ProjectLimitReached));
+        }
+
+        Ok(())
+    }
+
     pub async fn iter_user_projects_detailed_filtered(
         &self,
         account_name: AccountName,
@@ -315,9 +537,83 @@ impl GatewayService {
                 .bind(project_name),
         };
         query.execute(&self.db).await?;
+        self.record_project_event(project_name, project).await?;
+        Ok(())
+    }
+
+    /// Add the state a project is now in to its history, when it is one it settles in and differs from the last one
+    /// recorded. Only the most recent [PROJECT_EVENTS_KEPT] are kept.
+    async fn record_project_event(
+        &self,
+        project_name: &ProjectName,
+        project: &Project,
+    ) -> Result<(), Error> {
+        let state = project::State::from(project.clone());
+        if !matches!(
+            state,
+            project::State::Creating { .. }
+                | project::State::Ready
+                | project::State::Stopped
+                | project::State::Destroyed
+                | project::State::Errored { .. }
+        ) {
+            return Ok(());
+        }
+
+        let mut transaction = self.db.begin().await?;
+
+        let last = query(
+            "SELECT state FROM project_events WHERE project_name = ?1 ORDER BY id DESC LIMIT 1",
+        )
+        .bind(project_name)
+        .fetch_optional(&mut transaction)
+        .await?
+        .map(|row| row.get::<SqlxJson<project::State>, _>("state").0);
+
+        if last.as_ref() == Some(&state) {
+            return Ok(());
+        }
+
+        query("INSERT INTO project_events (project_name, state, timestamp) VALUES (?1, ?2, ?3)")
+            .bind(project_name)
+            .bind(SqlxJson(&state))
+            .bind(Utc::now().to_rfc3339())
+            .execute(&mut transaction)
+            .await?;
+        query("DELETE FROM project_events WHERE project_name = ?1 AND id NOT IN (SELECT id FROM project_events WHERE project_name = ?1 ORDER BY id DESC LIMIT ?2)")
+            .bind(project_name)
+            .bind(PROJECT_EVENTS_KEPT)
+            .execute(&mut transaction)
+            .await?;
+
+        transaction.commit().await?;
+
         Ok(())
     }
 
+    /// The recorded states of a project, oldest first
+    pub async fn project_events(
+        &self,
+        project_name: &ProjectName,
+    ) -> Result<Vec<project::Event>, Error> {
+        let events = query(
+            "SELECT state, timestamp FROM project_events WHERE project_name = ?1 ORDER BY id",
+        )
+        .bind(project_name)
+        .fetch_all(&self.db)
+        .await?
+        .into_iter()
+        .map(|row| project::Event {
+            state: row.get::<SqlxJson<project::State>, _>("state").0,
+            timestamp: DateTime::parse_from_rfc3339(row.get("timestamp"))
+                .expect("a recorded timestamp to be valid")
+                .with_timezone(&Utc),
+        })
+        .collect();
+
+        Ok(events)
+    }
+
     pub async fn account_name_from_project(
         &self,
         project_name: &ProjectName,
@@ -381,6 +677,8 @@ impl GatewayService {
             let project = row.get::<SqlxJson<Project>, _>("project_state").0;
             if project.is_destroyed() {
                 // But is in `::Destroyed` state, recreate it
+                self.check_project_limit(&account_name, is_admin).await?;
+
                 let mut creating = ProjectCreating::new_with_random_initial_key(
                     project_name.clone(),
                     idle_minutes,
@@ -408,6 +706,8 @@ impl GatewayService {
             // TODO: remove this check when we update the project name rules
             // in shuttle-common
             if project_name.is_valid() {
+                self.check_project_limit(&account_name, is_admin).await?;
+
                 // Otherwise attempt to create a new one. This will fail
                 // outright if the project already exists (this happens if
                 // it belongs to another account).
@@ -449,6 +749,7 @@ impl GatewayService {
             })?;
 
         let project = project.0;
+        self.record_project_event(&project_name, &project).await?;
 
         Ok(project)
     }
@@ -460,19 +761,182 @@ impl GatewayService {
         certs: &str,
         private_key: &str,
     ) -> Result<(), Error> {
-        query("INSERT OR REPLACE INTO custom_domains (fqdn, project_name, certificate, private_key) VALUES (?1, ?2, ?3, ?4)")
+        self.create_custom_domain_group(project_name, &[fqdn.to_owned()], None, certs, private_key)
+            .await
+    }
+
+    /// Store a group of domains which share one SAN certificate. The
+    /// first domain names the group.
+    ///
+    /// When the domains are for an environment of a project, `project_name`
+    /// is the project that environment deploys to.
+    pub async fn create_custom_domain_group(
+        &self,
+        project_name: ProjectName,
+        fqdns: &[FQDN],
+        environment: Option<&str>,
+        certs: &str,
+        private_key: &str,
+    ) -> Result<(), Error> {
+        let group = fqdns
+            .first()
+            .ok_or_else(|| Error::from(ErrorKind::InvalidCustomDomain))?;
+
+        let mut transaction = self.db.begin().await?;
+
+        for fqdn in fqdns {
+            // Replacing the certificate of a domain is fine, taking the domain of another project is not
+            let owner: Option<ProjectName> =
+                query("SELECT project_name FROM custom_domains WHERE fqdn = ?1")
+                    .bind(fqdn.to_string())
+                    .fetch_optional(&mut transaction)
+                    .await?
+                    .map(|row| row.get("project_name"));
+
+            if matches!(owner, Some(owner) if owner != project_name) {
+                return Err(Error::from_kind(ErrorKind::CustomDomainAlreadyExists));
+            }
+
+            query("INSERT OR REPLACE INTO custom_domains (fqdn, project_name, certificate, private_key, certificate_group, environment) VALUES (?1, ?2, ?3, ?4, ?5, ?6)")
+                .bind(fqdn.to_string())
+                .bind(&project_name)
+                .bind(certs)
+                .bind(private_key)
+                .bind(group.to_string())
+                .bind(environment)
+                .execute(&mut transaction)
+                .await?;
+        }
+
+        transaction.commit().await?;
+
+        Ok(())
+    }
+
+    /// Make sure a project has room for the domains of a group which it does not have yet, under the limit of its
+    /// account or the one for every project
+    pub async fn check_custom_domain_limit(
+        &self,
+        project_name: &ProjectName,
+        fqdns: &[FQDN],
+    ) -> Result<(), Error> {
+        let limit = if self.max_custom_domains_for.is_empty() {
+            self.max_custom_domains_per_project
+        } else {
+            let account_name = self.account_name_from_project(project_name).await?;
+            self.max_custom_domains_for
+                .get(&account_name)
+                .copied()
+                .or(self.max_custom_domains_per_project)
+        };
+        let Some(limit) = limit else {
+            return Ok(());
+        };
+
+        let existing: Vec<String> =
+            query("SELECT fqdn FROM custom_domains WHERE project_name = ?1")
+                .bind(project_name)
+                .fetch_all(&self.db)
+                .await?
+                .into_iter()
+                .map(|row| row.get("fqdn"))
+                .collect();
+
+        // Domains the project already has are only getting a new certificate
+        let added = fqdns
+            .iter()
+            .filter(|fqdn| !existing.contains(&fqdn.to_string()))
+            .count();
+
+        if added > 0 && (existing.len() + added) as u64 > limit {
+            return Err(Error::from_kind(ErrorKind::CustomDomainLimitReached));
+        }
+
+        Ok(())
+    }
+
+    /// The token a project has to publish in the verification record of a domain, made the first time it is asked for
+    pub async fn domain_verification_token(
+        &self,
+        project_name: &ProjectName,
+        fqdn: &FQDN,
+    ) -> Result<String, Error> {
+        let token = Alphanumeric.sample_string(&mut rand::thread_rng(), 32);
+
+        query("INSERT OR IGNORE INTO domain_verifications (fqdn, project_name, token) VALUES (?1, ?2, ?3)")
             .bind(fqdn.to_string())
-            .bind(&project_name)
-            .bind(certs)
-            .bind(private_key)
+            .bind(project_name)
+            .bind(token)
             .execute(&self.db)
             .await?;
 
+        let token =
+            query("SELECT token FROM domain_verifications WHERE fqdn = ?1 AND project_name = ?2")
+                .bind(fqdn.to_string())
+                .bind(project_name)
+                .fetch_one(&self.db)
+                .await?
+                .get("token");
+
+        Ok(token)
+    }
+
+    /// Whether a project has published its token in the verification record of a domain. Every domain passes when
+    /// verification is turned off.
+    pub async fn domain_verified(
+        &self,
+        project_name: &ProjectName,
+        fqdn: &FQDN,
+    ) -> Result<bool, Error> {
+        let Some(txt_lookup) = &self.txt_lookup else {
+            return Ok(true);
+        };
+
+        let token: Option<String> =
+            query("SELECT token FROM domain_verifications WHERE fqdn = ?1 AND project_name = ?2")
+                .bind(fqdn.to_string())
+                .bind(project_name)
+                .fetch_optional(&self.db)
+                .await?
+                .map(|row| row.get("token"));
+
+        match token {
+            Some(token) => domain_verification::verifies(txt_lookup.as_ref(), fqdn, &token).await,
+            None => Ok(false),
+        }
+    }
+
+    /// Make sure a project controls the domains of a group which it does not have yet
+    pub async fn check_domain_ownership(
+        &self,
+        project_name: &ProjectName,
+        fqdns: &[FQDN],
+    ) -> Result<(), Error> {
+        let existing: Vec<String> =
+            query("SELECT fqdn FROM custom_domains WHERE project_name = ?1")
+                .bind(project_name)
+                .fetch_all(&self.db)
+                .await?
+                .into_iter()
+                .map(|row| row.get("fqdn"))
+                .collect();
+
+        // Domains the project already has were verified when they were added
+        for fqdn in fqdns
+            .iter()
+            .filter(|fqdn| !existing.contains(&fqdn.to_string()))
+        {
+            if !self.domain_verified(project_name, fqdn).await? {
+                debug!(%project_name, %fqdn, "custom domain is not verified");
+                return Err(Error::from_kind(ErrorKind::DomainNotVerified));
+            }
+        }
+
         Ok(())
     }
 
     pub async fn iter_custom_domains(&self) -> Result<impl Iterator<Item = CustomDomain>, Error> {
-        query("SELECT fqdn, project_name, certificate, private_key FROM custom_domains")
+        query("SELECT fqdn, project_name, certificate, private_key, COALESCE(certificate_group, fqdn) AS certificate_group, environment FROM custom_domains")
             .fetch_all(&self.db)
             .await
             .map(|res| {
@@ -481,6 +945,8 @@ impl GatewayService {
                     project_name: row.try_get("project_name").unwrap(),
                     certificate: row.get("certificate"),
                     private_key: row.get("private_key"),
+                    certificate_group: row.get::<&str, _>("certificate_group").parse().unwrap(),
+                    environment: row.get("environment"),
                 })
             })
             .map_err(|_| Error::from_kind(ErrorKind::Internal))
@@ -491,7 +957,7 @@ impl GatewayService {
         project_name: &ProjectName,
     ) -> Result<CustomDomain, Error> {
         let custom_domain = query(
-            "SELECT fqdn, project_name, certificate, private_key FROM custom_domains WHERE project_name = ?1",
+            "SELECT fqdn, project_name, certificate, private_key, COALESCE(certificate_group, fqdn) AS certificate_group, environment FROM custom_domains WHERE project_name = ?1 ORDER BY fqdn = COALESCE(certificate_group, fqdn) DESC",
         )
         .bind(project_name.to_string())
         .fetch_optional(&self.db)
@@ -501,6 +967,8 @@ impl GatewayService {
             project_name: row.try_get("project_name").unwrap(),
             certificate: row.get("certificate"),
             private_key: row.get("private_key"),
+            certificate_group: row.get::<&str, _>("certificate_group").parse().unwrap(),
+            environment: row.get("environment"),
         })
         .ok_or_else(|| Error::from(ErrorKind::CustomDomainNotFound))?;
         Ok(custom_domain)
@@ -511,7 +979,7 @@ impl GatewayService {
         fqdn: &Fqdn,
     ) -> Result<CustomDomain, Error> {
         let custom_domain = query(
-            "SELECT fqdn, project_name, certificate, private_key FROM custom_domains WHERE fqdn = ?1",
+            "SELECT fqdn, project_name, certificate, private_key, COALESCE(certificate_group, fqdn) AS certificate_group, environment FROM custom_domains WHERE fqdn = ?1",
         )
         .bind(fqdn.to_string())
         .fetch_optional(&self.db)
@@ -521,315 +989,1727 @@ impl GatewayService {
             project_name: row.try_get("project_name").unwrap(),
             certificate: row.get("certificate"),
             private_key: row.get("private_key"),
+            certificate_group: row.get::<&str, _>("certificate_group").parse().unwrap(),
+            environment: row.get("environment"),
         })
         .ok_or_else(|| Error::from(ErrorKind::CustomDomainNotFound))?;
         Ok(custom_domain)
     }
 
-    pub async fn iter_projects_detailed(
+    pub async fn set_ip_rule(
         &self,
-    ) -> Result<impl Iterator<Item = ProjectDetails>, Error> {
-        let iter = query("SELECT project_name, account_name FROM projects")
-            .fetch_all(&self.db)
-            .await?
-            .into_iter()
-            .map(|row| ProjectDetails {
-                project_name: row.try_get("project_name").unwrap(),
-                account_name: row.try_get("account_name").unwrap(),
-            });
-        Ok(iter)
-    }
+        project_name: &ProjectName,
+        net: &IpNet,
+        action: ip_rule::Action,
+    ) -> Result<(), Error> {
+        query("INSERT OR REPLACE INTO ip_rules (project_name, cidr, action) VALUES (?1, ?2, ?3)")
+            .bind(project_name)
+            .bind(net.to_string())
+            .bind(action.to_string())
+            .execute(&self.db)
+            .await?;
 
-    pub fn context(&self) -> GatewayContext {
-        self.provider.context()
-    }
+        self.ip_filters.invalidate(project_name);
 
-    /// Create a builder for a new [ProjectTask]
-    pub fn new_task(self: &Arc<Self>) -> TaskBuilder {
-        TaskBuilder::new(self.clone())
+        Ok(())
     }
 
-    /// Find a project by name. And start the project if it is idle, waiting for it to start up
-    pub async fn find_or_start_project(
-        self: &Arc<Self>,
+    pub async fn delete_ip_rule(
+        &self,
         project_name: &ProjectName,
-        task_sender: Sender<BoxedTask>,
-    ) -> Result<Project, Error> {
-        let mut project = self.find_project(project_name).await?;
-
-        // Start the project if it is idle
-        if project.is_stopped() {
-            trace!(%project_name, "starting up idle project");
+        net: &IpNet,
+    ) -> Result<(), Error> {
+        let result = query("DELETE FROM ip_rules WHERE project_name = ?1 AND cidr = ?2")
+            .bind(project_name)
+            .bind(net.to_string())
+            .execute(&self.db)
+            .await?;
 
-            let handle = self
-                .new_task()
-                .project(project_name.clone())
-                .and_then(task::start())
-                .and_then(task::run_until_done())
-                .and_then(task::check_health())
-                .send(&task_sender)
-                .await?;
+        if result.rows_affected() == 0 {
+            Err(Error::from_kind(ErrorKind::IpRuleNotFound))
+        } else {
+            self.ip_filters.invalidate(project_name);
 
-            // Wait for project to come up and set new state
-            handle.await;
-            project = self.find_project(project_name).await?;
+            Ok(())
         }
-
-        Ok(project)
-    }
-
-    pub fn task_router(&self) -> TaskRouter<BoxedTask> {
-        self.task_router.clone()
     }
-}
 
-#[derive(Clone)]
-pub struct GatewayContext {
-    docker: Docker,
-    settings: ContainerSettings,
-}
+    pub async fn ip_rules_for_project(
+        &self,
+        project_name: &ProjectName,
+    ) -> Result<Vec<ip_rule::Rule>, Error> {
+        let rules =
+            query("SELECT cidr, action FROM ip_rules WHERE project_name = ?1 ORDER BY cidr")
+                .bind(project_name)
+                .fetch_all(&self.db)
+                .await?
+                .into_iter()
+                .map(|row| ip_rule::Rule {
+                    cidr: row.get("cidr"),
+                    action: row.get::<&str, _>("action").parse().unwrap(),
+                })
+                .collect();
 
-impl DockerContext for GatewayContext {
-    fn docker(&self) -> &Docker {
-        &self.docker
+        Ok(rules)
     }
 
-    fn container_settings(&self) -> &ContainerSettings {
-        &self.settings
+    /// Get the filter for the clients allowed to reach a project
+    pub async fn ip_filter_for_project(
+        &self,
+        project_name: &ProjectName,
+    ) -> Result<IpFilter, Error> {
+        self.ip_filters
+            .get_or_load(project_name, async {
+                let rules = self.ip_rules_for_project(project_name).await?;
+
+                Ok(IpFilter::new(rules.into_iter().filter_map(|rule| {
+                    parse_cidr(&rule.cidr).ok().map(|net| (net, rule.action))
+                })))
+            })
+            .await
     }
-}
 
-#[cfg(test)]
-pub mod tests {
-    use fqdn::FQDN;
+    /// Set which `Host` header the proxy forwards requests to a project with
+    pub async fn set_host_header(
+        &self,
+        project_name: &ProjectName,
+        policy: &host_header::Policy,
+    ) -> Result<(), Error> {
+        match policy {
+            host_header::Policy::Preserve => {
+                query("DELETE FROM host_headers WHERE project_name = ?1")
+                    .bind(project_name)
+                    .execute(&self.db)
+                    .await?;
+            }
+            host_header::Policy::Backend => {
+                query("INSERT OR REPLACE INTO host_headers (project_name, policy, value) VALUES (?1, 'backend', NULL)")
+                    .bind(project_name)
+                    .execute(&self.db)
+                    .await?;
+            }
+            host_header::Policy::Fixed(host) => {
+                // Only a host and port, so the value can always be sent as is
+                if host.contains('@') || host.parse::<http::uri::Authority>().is_err() {
+                    return Err(Error::from_kind(ErrorKind::InvalidHostHeader));
+                }
 
-    use super::*;
-    use crate::task::{self, TaskResult};
-    use crate::tests::{assert_err_kind, World};
-    use crate::{Error, ErrorKind};
+                query("INSERT OR REPLACE INTO host_headers (project_name, policy, value) VALUES (?1, 'fixed', ?2)")
+                    .bind(project_name)
+                    .bind(host)
+                    .execute(&self.db)
+                    .await?;
+            }
+        }
 
-    #[tokio::test]
-    async fn service_create_find_delete_project() -> anyhow::Result<()> {
-        let world = World::new().await;
-        let svc = Arc::new(GatewayService::init(world.args(), world.pool()).await);
+        self.host_headers.invalidate(project_name);
 
-        let neo: AccountName = "neo".parse().unwrap();
-        let trinity: AccountName = "trinity".parse().unwrap();
-        let matrix: ProjectName = "matrix".parse().unwrap();
+        Ok(())
+    }
 
-        let creating_same_project_name = |project: &Project, project_name: &ProjectName| {
-            matches!(
+    pub async fn host_header_for_project(
+        &self,
+        project_name: &ProjectName,
+    ) -> Result<host_header::Policy, Error> {
+        self.host_headers
+            .get_or_load(project_name, async {
+                let policy =
+                    query("SELECT policy, value FROM host_headers WHERE project_name = ?1")
+                        .bind(project_name)
+                        .fetch_optional(&self.db)
+                        .await?
+                        .map(|row| match row.get::<&str, _>("policy") {
+                            "backend" => host_header::Policy::Backend,
+                            "fixed" => host_header::Policy::Fixed(row.get("value")),
+                            _ => host_header::Policy::Preserve,
+                        })
+                        .unwrap_or_default();
+
+                Ok(policy)
+            })
+            .await
+    }
+
+    /// Protect a project with basic auth, replacing the credentials it had
+    pub async fn set_basic_auth(
+        &self,
+        project_name: &ProjectName,
+        config: &basic_auth::Config,
+    ) -> Result<(), Error> {
+        let basic_auth = BasicAuth::new(config)?;
+
+        query("INSERT OR REPLACE INTO basic_auth (project_name, username, salt, password_hash, exempt) VALUES (?1, ?2, ?3, ?4, ?5)")
+            .bind(project_name)
+            .bind(&basic_auth.username)
+            .bind(&basic_auth.salt)
+            .bind(&basic_auth.password_hash)
+            .bind(SqlxJson(&basic_auth.exempt))
+            .execute(&self.db)
+            .await?;
+
+        self.basic_auths.invalidate(project_name);
+        self.verified_credentials.invalidate(project_name);
+
+        Ok(())
+    }
+
+    pub async fn remove_basic_auth(&self, project_name: &ProjectName) -> Result<(), Error> {
+        query("DELETE FROM basic_auth WHERE project_name = ?1")
+            .bind(project_name)
+            .execute(&self.db)
+            .await?;
+
+        self.basic_auths.invalidate(project_name);
+        self.verified_credentials.invalidate(project_name);
+
+        Ok(())
+    }
+
+    pub async fn basic_auth_for_project(
+        &self,
+        project_name: &ProjectName,
+    ) -> Result<Option<BasicAuth>, Error> {
+        self.basic_auths
+            .get_or_load(project_name, async {
+                let basic_auth = query(
+                    "SELECT username, salt, password_hash, exempt FROM basic_auth WHERE project_name = ?1",
+                )
+                .bind(project_name)
+                .fetch_optional(&self.db)
+                .await?
+                .map(|row| BasicAuth {
+                    username: row.get("username"),
+                    salt: row.get("salt"),
+                    password_hash: row.get("password_hash"),
+                    exempt: row.get::<SqlxJson<Vec<String>>, _>("exempt").0,
+                });
+
+                Ok(basic_auth)
+            })
+            .await
+    }
+
+    /// Whether a request to a project has the credentials of its basic auth, which are only verified once per client
+    pub fn allows_basic_auth(
+        &self,
+        project_name: &ProjectName,
+        basic_auth: &BasicAuth,
+        headers: &HeaderMap,
+    ) -> bool {
+        self.verified_credentials
+            .allows(project_name, basic_auth, headers)
+    }
+
+    /// Keep a project from idling until it is unpinned, whatever its idle timer says
+    pub async fn pin_project(&self, project_name: &ProjectName) -> Result<(), Error> {
+        query("INSERT OR IGNORE INTO project_pins (project_name) VALUES (?1)")
+            .bind(project_name)
+            .execute(&self.db)
+            .await?;
+
+        self.keep_warm.pin(project_name);
+
+        Ok(())
+    }
+
+    pub async fn unpin_project(&self, project_name: &ProjectName) -> Result<(), Error> {
+        query("DELETE FROM project_pins WHERE project_name = ?1")
+            .bind(project_name)
+            .execute(&self.db)
+            .await?;
+
+        self.keep_warm.unpin(project_name);
+
+        Ok(())
+    }
+
+    /// Offer a project to another account. It stays with `from_account` until `to_account` accepts it, and a new offer
+    /// replaces a pending one.
+    pub async fn create_transfer(
+        &self,
+        project_name: &ProjectName,
+        from_account: &AccountName,
+        to_account: &AccountName,
+    ) -> Result<project::Transfer, Error> {
+        if from_account == to_account || to_account.to_string().trim().is_empty() {
+            return Err(Error::from_kind(ErrorKind::InvalidTransfer));
+        }
+
+        let transfer = project::Transfer {
+            project_name: project_name.to_string(),
+            from_account: from_account.to_string(),
+            to_account: to_account.to_string(),
+            created_at: Utc::now(),
+        };
+
+        query("INSERT OR REPLACE INTO project_transfers (project_name, from_account, to_account, created_at) VALUES (?1, ?2, ?3, ?4)")
+            .bind(project_name)
+            .bind(from_account)
+            .bind(to_account)
+            .bind(transfer.created_at.to_rfc3339())
+            .execute(&self.db)
+            .await?;
+
+        Ok(transfer)
+    }
+
+    /// The pending transfers of projects to or from an account, oldest first
+    pub async fn transfers_for_account(
+        &self,
+        account_name: &AccountName,
+    ) -> Result<Vec<project::Transfer>, Error> {
+        let transfers = query("SELECT project_name, from_account, to_account, created_at FROM project_transfers WHERE from_account = ?1 OR to_account = ?1 ORDER BY created_at")
+            .bind(account_name)
+            .fetch_all(&self.db)
+            .await?
+            .into_iter()
+            .map(|row| project::Transfer {
+                project_name: row.get("project_name"),
+                from_account: row.get("from_account"),
+                to_account: row.get("to_account"),
+                created_at: DateTime::parse_from_rfc3339(row.get("created_at"))
+                    .expect("a recorded timestamp to be valid")
+                    .with_timezone(&Utc),
+            })
+            .collect();
+
+        Ok(transfers)
+    }
+
+    /// Call off the pending transfer of a project, either by the account offering it or by the one declining it
+    pub async fn cancel_transfer(
+        &self,
+        project_name: &ProjectName,
+        account_name: &AccountName,
+    ) -> Result<(), Error> {
+        let cancelled = query("DELETE FROM project_transfers WHERE project_name = ?1 AND (from_account = ?2 OR to_account = ?2)")
+            .bind(project_name)
+            .bind(account_name)
+            .execute(&self.db)
+            .await?
+            .rows_affected();
+
+        if cancelled == 0 {
+            return Err(Error::from_kind(ErrorKind::TransferNotFound));
+        }
+
+        Ok(())
+    }
+
+    /// Move a project offered to `account_name` over to it. Its deployments, custom domains and secrets are all kept by
+    /// the project, so they move with it. The transfer no longer holds when the project changed hands since the offer.
+    pub async fn accept_transfer(
+        &self,
+        project_name: &ProjectName,
+        account_name: &AccountName,
+        is_admin: bool,
+    ) -> Result<(), Error> {
+        self.check_project_limit(account_name, is_admin).await?;
+
+        let mut transaction = self.db.begin().await?;
+
+        let from_account: String = query(
+            "SELECT from_account FROM project_transfers WHERE project_name = ?1 AND to_account = ?2",
+        )
+        .bind(project_name)
+        .bind(account_name)
+        .fetch_optional(&mut transaction)
+        .await?
+        .map(|row| row.get("from_account"))
+        .ok_or_else(|| Error::from_kind(ErrorKind::TransferNotFound))?;
+
+        query("DELETE FROM project_transfers WHERE project_name = ?1")
+            .bind(project_name)
+            .execute(&mut transaction)
+            .await?;
+
+        let moved = query(
+            "UPDATE projects SET account_name = ?1 WHERE project_name = ?2 AND account_name = ?3",
+        )
+        .bind(account_name)
+        .bind(project_name)
+        .bind(&from_account)
+        .execute(&mut transaction)
+        .await?
+        .rows_affected();
+
+        if moved == 0 {
+            // Dropping the transaction rolls it back, leaving the stale transfer to be cancelled
+            return Err(Error::from_kind(ErrorKind::TransferNotFound));
+        }
+
+        transaction.commit().await?;
+
+        debug!(%project_name, from_account, to_account = %account_name, "project transferred");
+
+        Ok(())
+    }
+
+    /// Set how long the proxy waits on the backend of a project
+    pub async fn set_proxy_timeouts(
+        &self,
+        project_name: &ProjectName,
+        config: &timeouts::Config,
+    ) -> Result<(), Error> {
+        if *config == timeouts::Config::default() {
+            query("DELETE FROM proxy_timeouts WHERE project_name = ?1")
+                .bind(project_name)
+                .execute(&self.db)
+                .await?;
+
+            self.proxy_timeouts.invalidate(project_name);
+
+            return Ok(());
+        }
+
+        let (stalled_body, idle_secs) = match config.stalled_body {
+            timeouts::StalledBody::Wait { idle_secs } => ("wait", Some(idle_secs)),
+            // Aborting at the deadline means there has to be one
+            timeouts::StalledBody::Abort if config.response_secs.is_some() => ("abort", None),
+            timeouts::StalledBody::Abort => {
+                return Err(Error::from_kind(ErrorKind::InvalidTimeouts))
+            }
+            timeouts::StalledBody::Unlimited => ("unlimited", None),
+        };
+
+        // Bounded so that they neither wrap once stored nor overflow the deadlines made from them
+        let in_range =
+            |value: Option<u64>, max: u64| value.map_or(true, |value| (1..=max).contains(&value));
+        if !in_range(config.response_secs, timeouts::MAX_SECS)
+            || !in_range(idle_secs, timeouts::MAX_SECS)
+            || !in_range(config.slow_request_ms, timeouts::MAX_SECS * 1000)
+        {
+            return Err(Error::from_kind(ErrorKind::InvalidTimeouts));
+        }
+
+        query("INSERT OR REPLACE INTO proxy_timeouts (project_name, response_secs, stalled_body, idle_secs, slow_request_ms) VALUES (?1, ?2, ?3, ?4, ?5)")
+            .bind(project_name)
+            .bind(config.response_secs.map(|secs| secs as i64))
+            .bind(stalled_body)
+            .bind(idle_secs.map(|secs| secs as i64))
+            .bind(config.slow_request_ms.map(|ms| ms as i64))
+            .execute(&self.db)
+            .await?;
+
+        self.proxy_timeouts.invalidate(project_name);
+
+        Ok(())
+    }
+
+    pub async fn proxy_timeouts_for_project(
+        &self,
+        project_name: &ProjectName,
+    ) -> Result<timeouts::Config, Error> {
+        self.proxy_timeouts
+            .get_or_load(project_name, async {
+            let config = query(
+                "SELECT response_secs, stalled_body, idle_secs, slow_request_ms FROM proxy_timeouts WHERE project_name = ?1",
+            )
+            .bind(project_name)
+            .fetch_optional(&self.db)
+            .await?
+            .map(|row| timeouts::Config {
+                response_secs: row
+                    .get::<Option<i64>, _>("response_secs")
+                    .map(|secs| secs as u64),
+                stalled_body: match row.get::<&str, _>("stalled_body") {
+                    "wait" => timeouts::StalledBody::Wait {
+                        idle_secs: row.get::<i64, _>("idle_secs") as u64,
+                    },
+                    "abort" => timeouts::StalledBody::Abort,
+                    _ => timeouts::StalledBody::Unlimited,
+                },
+                slow_request_ms: row
+                    .get::<Option<i64>, _>("slow_request_ms")
+                    .map(|ms| ms as u64),
+            })
+            .unwrap_or_default();
+
+            Ok(config)
+            })
+            .await
+    }
+
+    /// Set limits on the headers of requests to a project and of its responses, in place of those of the gateway
+    pub async fn set_header_limits(
+        &self,
+        project_name: &ProjectName,
+        config: &header_limits::Config,
+    ) -> Result<(), Error> {
+        if *config == header_limits::Config::default() {
+            query("DELETE FROM header_limits WHERE project_name = ?1")
+                .bind(project_name)
+                .execute(&self.db)
+                .await?;
+
+            self.project_header_limits.invalidate(project_name);
+
+            return Ok(());
+        }
+
+        if [
+            config.max_total_size,
+            config.max_count,
+            config.max_header_size,
+        ]
+        .contains(&Some(0))
+        {
+            return Err(Error::from_kind(ErrorKind::InvalidHeaderLimits));
+        }
+
+        query("INSERT OR REPLACE INTO header_limits (project_name, max_total_size, max_count, max_header_size) VALUES (?1, ?2, ?3, ?4)")
+            .bind(project_name)
+            .bind(config.max_total_size.map(|size| size as i64))
+            .bind(config.max_count.map(|count| count as i64))
+            .bind(config.max_header_size.map(|size| size as i64))
+            .execute(&self.db)
+            .await?;
+
+        self.project_header_limits.invalidate(project_name);
+
+        Ok(())
+    }
+
+    /// The limits on headers a project set for itself, without those of the gateway
+    pub async fn header_limits_for_project(
+        &self,
+        project_name: &ProjectName,
+    ) -> Result<header_limits::Config, Error> {
+        self.project_header_limits
+            .get_or_load(project_name, async {
+            let config = query(
+                "SELECT max_total_size, max_count, max_header_size FROM header_limits WHERE project_name = ?1",
+            )
+            .bind(project_name)
+            .fetch_optional(&self.db)
+            .await?
+            .map(|row| header_limits::Config {
+                max_total_size: row
+                    .get::<Option<i64>, _>("max_total_size")
+                    .map(|size| size as u64),
+                max_count: row
+                    .get::<Option<i64>, _>("max_count")
+                    .map(|count| count as u64),
+                max_header_size: row
+                    .get::<Option<i64>, _>("max_header_size")
+                    .map(|size| size as u64),
+            })
+            .unwrap_or_default();
+
+            Ok(config)
+            })
+            .await
+    }
+
+    /// Replace the rules for which headers of responses from a project are overridden or removed
+    pub async fn set_response_headers(
+        &self,
+        project_name: &ProjectName,
+        rules: &response_header::Rules,
+    ) -> Result<(), Error> {
+        let mut transaction = self.db.begin().await?;
+
+        query("DELETE FROM response_headers WHERE project_name = ?1")
+            .bind(project_name)
+            .execute(&mut transaction)
+            .await?;
+
+        for (name, rule) in rules {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| Error::from_kind(ErrorKind::InvalidResponseHeader))?;
+
+            // The proxy takes care of how responses are framed and passed on
+            if FRAMING_HEADERS.contains(&name.as_str()) {
+                return Err(Error::from_kind(ErrorKind::InvalidResponseHeader));
+            }
+
+            let (action, value) = match rule {
+                response_header::Rule::Set(value) => {
+                    HeaderValue::from_str(value)
+                        .map_err(|_| Error::from_kind(ErrorKind::InvalidResponseHeader))?;
+
+                    ("set", Some(value))
+                }
+                response_header::Rule::Remove => ("remove", None),
+            };
+
+            query("INSERT INTO response_headers (project_name, name, action, value) VALUES (?1, ?2, ?3, ?4)")
+                .bind(project_name)
+                .bind(name.as_str())
+                .bind(action)
+                .bind(value)
+                .execute(&mut transaction)
+                .await?;
+        }
+
+        transaction.commit().await?;
+
+        self.response_headers.invalidate(project_name);
+
+        Ok(())
+    }
+
+    pub async fn response_headers_for_project(
+        &self,
+        project_name: &ProjectName,
+    ) -> Result<response_header::Rules, Error> {
+        self.response_headers
+            .get_or_load(project_name, async {
+                let rules = query(
+                    "SELECT name, action, value FROM response_headers WHERE project_name = ?1",
+                )
+                .bind(project_name)
+                .fetch_all(&self.db)
+                .await?
+                .into_iter()
+                .map(|row| {
+                    let rule = match row.get::<&str, _>("action") {
+                        "set" => response_header::Rule::Set(row.get("value")),
+                        _ => response_header::Rule::Remove,
+                    };
+
+                    (row.get("name"), rule)
+                })
+                .collect();
+
+                Ok(rules)
+            })
+            .await
+    }
+
+    pub async fn iter_projects_detailed(
+        &self,
+    ) -> Result<impl Iterator<Item = ProjectDetails>, Error> {
+        let iter = query("SELECT project_name, account_name FROM projects")
+            .fetch_all(&self.db)
+            .await?
+            .into_iter()
+            .map(|row| ProjectDetails {
+                project_name: row.try_get("project_name").unwrap(),
+                account_name: row.try_get("account_name").unwrap(),
+            });
+        Ok(iter)
+    }
+
+    pub fn context(&self) -> GatewayContext {
+        self.provider.context()
+    }
+
+    /// Create a builder for a new [ProjectTask]
+    pub fn new_task(self: &Arc<Self>) -> TaskBuilder {
+        TaskBuilder::new(self.clone())
+    }
+
+    /// Find a project by name. And start the project if it is idle, waiting for it to start up
+    pub async fn find_or_start_project(
+        self: &Arc<Self>,
+        project_name: &ProjectName,
+        task_sender: Sender<BoxedTask>,
+    ) -> Result<Project, Error> {
+        let mut project = self.find_project(project_name).await?;
+
+        // Start the project if it is idle
+        if project.is_stopped() {
+            trace!(%project_name, "starting up idle project");
+
+            let handle = self
+                .new_task()
+                .project(project_name.clone())
+                .and_then(task::start())
+                .and_then(task::run_until_done())
+                .and_then(task::check_health())
+                .send(&task_sender)
+                .await?;
+
+            // Wait for project to come up and set new state
+            handle.await;
+            project = self.find_project(project_name).await?;
+        }
+
+        if matches!(project, Project::Errored(_))
+            && self.errored_project_policy != ErroredProjectPolicy::Report
+            && self.should_recover(project_name)
+        {
+            trace!(%project_name, "starting up errored project");
+
+            let handle = self
+                .new_task()
+                .project(project_name.clone())
+                .and_then(task::recover())
+                .and_then(task::run_until_done())
+                .and_then(task::check_health())
+                .send(&task_sender)
+                .await?;
+
+            if self.errored_project_policy == ErroredProjectPolicy::Hold {
+                if timeout(self.errored_project_hold, handle).await.is_err() {
+                    debug!(%project_name, "errored project did not come back in time");
+                }
+
+                project = self.find_project(project_name).await?;
+            }
+        }
+
+        Ok(project)
+    }
+
+    /// Errored projects are started again at most once every [`RECOVERY_INTERVAL`], however many requests they get
+    fn should_recover(&self, project_name: &ProjectName) -> bool {
+        let now = Instant::now();
+        let mut recoveries = self.recoveries.lock().unwrap();
+
+        recoveries.retain(|_, last| now.duration_since(*last) < RECOVERY_INTERVAL);
+
+        if recoveries.contains_key(project_name) {
+            false
+        } else {
+            recoveries.insert(project_name.clone(), now);
+            true
+        }
+    }
+
+    pub fn task_router(&self) -> TaskRouter<BoxedTask> {
+        self.task_router.clone()
+    }
+}
+
+#[derive(Clone)]
+pub struct GatewayContext {
+    docker: Docker,
+    settings: ContainerSettings,
+    keep_warm: Arc<KeepWarm>,
+}
+
+impl DockerContext for GatewayContext {
+    fn docker(&self) -> &Docker {
+        &self.docker
+    }
+
+    fn container_settings(&self) -> &ContainerSettings {
+        &self.settings
+    }
+
+    fn keeps_warm(&self, project_name: &ProjectName) -> bool {
+        self.keep_warm.contains(project_name)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use axum::headers::Authorization;
+    use fqdn::FQDN;
+
+    use super::*;
+    use crate::domain_verification::tests::FakeTxtLookup;
+    use crate::project::ProjectError;
+    use crate::task::{self, TaskResult};
+    use crate::tests::{assert_err_kind, World};
+    use crate::{Error, ErrorKind};
+
+    #[tokio::test]
+    async fn service_create_find_delete_project() -> anyhow::Result<()> {
+        let world = World::new().await;
+        let svc = Arc::new(GatewayService::init(world.args(), world.pool()).await);
+
+        let neo: AccountName = "neo".parse().unwrap();
+        let trinity: AccountName = "trinity".parse().unwrap();
+        let matrix: ProjectName = "matrix".parse().unwrap();
+
+        let creating_same_project_name = |project: &Project, project_name: &ProjectName| {
+            matches!(
                 project,
                 Project::Creating(creating) if creating.project_name() == project_name
             )
         };
 
-        let project = svc
-            .create_project(matrix.clone(), neo.clone(), false, 0)
+        let project = svc
+            .create_project(matrix.clone(), neo.clone(), false, 0)
+            .await
+            .unwrap();
+
+        assert!(creating_same_project_name(&project, &matrix));
+
+        assert_eq!(svc.find_project(&matrix).await.unwrap(), project);
+        assert_eq!(
+            svc.iter_projects_detailed()
+                .await
+                .unwrap()
+                .next()
+                .expect("to get one project with its user"),
+            ProjectDetails {
+                project_name: matrix.clone(),
+                account_name: neo.clone(),
+            }
+        );
+        assert_eq!(
+            svc.iter_user_projects_detailed(neo.clone())
+                .await
+                .unwrap()
+                .map(|item| item.0)
+                .collect::<Vec<_>>(),
+            vec![matrix.clone()]
+        );
+
+        // assert_eq!(
+        //     svc.iter_user_projects_detailed_filtered(neo.clone(), "ready".to_string())
+        //         .await
+        //         .unwrap()
+        //         .next()
+        //         .expect("to get one project with its user and a valid Ready status"),
+        //     (matrix.clone(), project)
+        // );
+
+        // assert_eq!(
+        //     svc.iter_user_projects_detailed_filtered(neo.clone(), "destroyed".to_string())
+        //         .await
+        //         .unwrap()
+        //         .next(),
+        //     None
+        // );
+
+        let mut work = svc
+            .new_task()
+            .project(matrix.clone())
+            .and_then(task::destroy())
+            .build();
+
+        while let TaskResult::Pending(_) = work.poll(()).await {}
+        assert!(matches!(work.poll(()).await, TaskResult::Done(())));
+
+        // After project has been destroyed...
+        assert!(matches!(
+            svc.find_project(&matrix).await,
+            Ok(Project::Destroyed(_))
+        ));
+
+        // If recreated by a different user
+        assert!(matches!(
+            svc.create_project(matrix.clone(), trinity.clone(), false, 0)
+                .await,
+            Err(Error {
+                kind: ErrorKind::ProjectAlreadyExists,
+                ..
+            })
+        ));
+
+        // If recreated by the same user
+        assert!(matches!(
+            svc.create_project(matrix.clone(), neo, false, 0).await,
+            Ok(Project::Creating(_))
+        ));
+
+        let mut work = svc
+            .new_task()
+            .project(matrix.clone())
+            .and_then(task::destroy())
+            .build();
+
+        while let TaskResult::Pending(_) = work.poll(()).await {}
+        assert!(matches!(work.poll(()).await, TaskResult::Done(())));
+
+        // After project has been destroyed again...
+        assert!(matches!(
+            svc.find_project(&matrix).await,
+            Ok(Project::Destroyed(_))
+        ));
+
+        // If recreated by an admin
+        assert!(matches!(
+            svc.create_project(matrix, trinity, true, 0).await,
+            Ok(Project::Creating(_))
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn service_create_ready_kill_restart_docker() -> anyhow::Result<()> {
+        let world = World::new().await;
+        let svc = Arc::new(GatewayService::init(world.args(), world.pool()).await);
+
+        let neo: AccountName = "neo".parse().unwrap();
+        let matrix: ProjectName = "matrix".parse().unwrap();
+
+        svc.create_project(matrix.clone(), neo.clone(), false, 0)
+            .await
+            .unwrap();
+
+        let mut task = svc.new_task().project(matrix.clone()).build();
+
+        while let TaskResult::Pending(_) = task.poll(()).await {
+            // keep polling
+        }
+
+        let project = svc.find_project(&matrix).await.unwrap();
+        println!("{:?}", project);
+        assert!(project.is_ready());
+
+        let container = project.container().unwrap();
+        svc.context()
+            .docker()
+            .kill_container::<String>(container.name.unwrap().strip_prefix('/').unwrap(), None)
+            .await
+            .unwrap();
+
+        println!("killed container");
+
+        let mut ambulance_task = svc
+            .new_task()
+            .project(matrix.clone())
+            .and_then(task::check_health())
+            .build();
+
+        // the first poll will trigger a refresh
+        let _ = ambulance_task.poll(()).await;
+
+        let project = svc.find_project(&matrix).await.unwrap();
+        println!("{:?}", project);
+        assert!(!project.is_ready());
+
+        // the subsequent will trigger a restart task
+        while let TaskResult::Pending(_) = ambulance_task.poll(()).await {
+            // keep polling
+        }
+
+        let project = svc.find_project(&matrix).await.unwrap();
+        println!("{:?}", project);
+        assert!(project.is_ready());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn service_create_find_custom_domain() -> anyhow::Result<()> {
+        let world = World::new().await;
+        let svc = Arc::new(GatewayService::init(world.args(), world.pool()).await);
+
+        let account: AccountName = "neo".parse().unwrap();
+        let project_name: ProjectName = "matrix".parse().unwrap();
+        let domain: FQDN = "neo.the.matrix".parse().unwrap();
+        let certificate = "dummy certificate";
+        let private_key = "dummy private key";
+
+        assert_err_kind!(
+            svc.project_details_for_custom_domain(&domain).await,
+            ErrorKind::CustomDomainNotFound
+        );
+
+        let _ = svc
+            .create_project(project_name.clone(), account.clone(), false, 0)
+            .await
+            .unwrap();
+
+        svc.create_custom_domain(project_name.clone(), &domain, certificate, private_key)
+            .await
+            .unwrap();
+
+        let custom_domain = svc
+            .project_details_for_custom_domain(&domain)
+            .await
+            .unwrap();
+
+        assert_eq!(custom_domain.project_name, project_name);
+        assert_eq!(custom_domain.certificate, certificate);
+        assert_eq!(custom_domain.private_key, private_key);
+
+        // Should auto replace the domain details
+        let certificate = "dummy certificate update";
+        let private_key = "dummy private key update";
+
+        svc.create_custom_domain(project_name.clone(), &domain, certificate, private_key)
+            .await
+            .unwrap();
+
+        let custom_domain = svc
+            .project_details_for_custom_domain(&domain)
+            .await
+            .unwrap();
+
+        assert_eq!(custom_domain.project_name, project_name);
+        assert_eq!(custom_domain.certificate, certificate);
+        assert_eq!(custom_domain.private_key, private_key);
+
+        // Another project cannot take the domain over
+        let other: ProjectName = "other".parse().unwrap();
+        svc.create_project(other.clone(), account, false, 0).await?;
+
+        assert_err_kind!(
+            svc.create_custom_domain(other, &domain, "other certificate", "other private key")
+                .await,
+            ErrorKind::CustomDomainAlreadyExists
+        );
+
+        let custom_domain = svc.project_details_for_custom_domain(&domain).await?;
+        assert_eq!(custom_domain.project_name, project_name);
+        assert_eq!(custom_domain.certificate, certificate);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn service_basic_auth() -> anyhow::Result<()> {
+        let world = World::new().await;
+        let svc = Arc::new(GatewayService::init(world.args(), world.pool()).await);
+
+        let matrix: ProjectName = "matrix".parse().unwrap();
+        svc.create_project(matrix.clone(), "neo".parse().unwrap(), false, 0)
+            .await?;
+
+        assert_eq!(svc.basic_auth_for_project(&matrix).await?, None);
+
+        let config = basic_auth::Config {
+            username: "neo".to_string(),
+            password: "there is no spoon".to_string(),
+            exempt: vec!["/health".to_string()],
+        };
+        svc.set_basic_auth(&matrix, &config).await?;
+
+        let basic_auth = svc.basic_auth_for_project(&matrix).await?.unwrap();
+        assert_eq!(
+            basic_auth.response(),
+            basic_auth::Response {
+                username: Some("neo".to_string()),
+                exempt: vec!["/health".to_string()],
+            }
+        );
+        assert!(basic_auth.exempts("/health"));
+
+        // The cached credentials are dropped along with the ones they were checked against
+        let mut headers = HeaderMap::new();
+        headers.typed_insert(Authorization::basic("neo", "there is no spoon"));
+        assert!(svc.allows_basic_auth(&matrix, &basic_auth, &headers));
+
+        svc.set_basic_auth(
+            &matrix,
+            &basic_auth::Config {
+                password: "there is a spoon".to_string(),
+                ..config.clone()
+            },
+        )
+        .await?;
+        let changed = svc.basic_auth_for_project(&matrix).await?.unwrap();
+        assert_ne!(changed, basic_auth);
+        assert!(!svc.allows_basic_auth(&matrix, &changed, &headers));
+
+        assert_err_kind!(
+            svc.set_basic_auth(
+                &matrix,
+                &basic_auth::Config {
+                    username: "neo:one".to_string(),
+                    ..config
+                }
+            )
+            .await,
+            ErrorKind::InvalidBasicAuth
+        );
+
+        svc.remove_basic_auth(&matrix).await?;
+        assert_eq!(svc.basic_auth_for_project(&matrix).await?, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn service_project_events() -> anyhow::Result<()> {
+        let world = World::new().await;
+        let svc = Arc::new(GatewayService::init(world.args(), world.pool()).await);
+
+        let matrix: ProjectName = "matrix".parse().unwrap();
+        let project = svc
+            .create_project(matrix.clone(), "neo".parse().unwrap(), false, 0)
+            .await?;
+
+        // Staying in a state is not recorded again
+        let errored = Project::Errored(ProjectError::internal("container went away"));
+        svc.update_project(&matrix, &project).await?;
+        svc.update_project(&matrix, &errored).await?;
+        svc.update_project(&matrix, &errored).await?;
+        svc.update_project(&matrix, &project).await?;
+
+        let states: Vec<_> = svc
+            .project_events(&matrix)
+            .await?
+            .into_iter()
+            .map(|event| event.state)
+            .collect();
+        assert_eq!(
+            states,
+            [
+                project::State::Creating { recreate_count: 0 },
+                project::State::Errored {
+                    message: "container went away".to_string()
+                },
+                project::State::Creating { recreate_count: 0 },
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn service_custom_domain_limit() -> anyhow::Result<()> {
+        let world = World::new().await;
+        let args = ContextArgs {
+            max_custom_domains_per_project: Some(2),
+            max_custom_domains_for: vec![("trinity".parse().unwrap(), 3)],
+            ..world.args()
+        };
+        let svc = Arc::new(GatewayService::init(args, world.pool()).await);
+
+        let matrix: ProjectName = "matrix".parse().unwrap();
+        let reloaded: ProjectName = "reloaded".parse().unwrap();
+        svc.create_project(matrix.clone(), "neo".parse().unwrap(), false, 0)
+            .await?;
+        svc.create_project(reloaded.clone(), "trinity".parse().unwrap(), false, 0)
+            .await?;
+
+        let domains: Vec<FQDN> = [
+            "neo.the.matrix",
+            "trinity.the.matrix",
+            "morpheus.the.matrix",
+        ]
+        .iter()
+        .map(|fqdn| fqdn.parse().unwrap())
+        .collect();
+
+        svc.check_custom_domain_limit(&matrix, &domains[..2])
+            .await?;
+        assert_err_kind!(
+            svc.check_custom_domain_limit(&matrix, &domains).await,
+            ErrorKind::CustomDomainLimitReached
+        );
+
+        svc.create_custom_domain_group(matrix.clone(), &domains[..2], None, "cert", "key")
+            .await?;
+        assert_err_kind!(
+            svc.check_custom_domain_limit(&matrix, &domains[2..]).await,
+            ErrorKind::CustomDomainLimitReached
+        );
+
+        // Getting a new certificate for domains the project has is not adding any
+        svc.check_custom_domain_limit(&matrix, &domains[..1])
+            .await?;
+
+        // The limit of an account takes the place of the one for every project
+        svc.check_custom_domain_limit(&reloaded, &domains).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn service_domain_verification() -> anyhow::Result<()> {
+        let world = World::new().await;
+        let mut svc = GatewayService::init(world.args(), world.pool()).await;
+
+        let matrix: ProjectName = "matrix".parse().unwrap();
+        let reloaded: ProjectName = "reloaded".parse().unwrap();
+        svc.create_project(matrix.clone(), "neo".parse().unwrap(), false, 0)
+            .await?;
+        svc.create_project(reloaded.clone(), "trinity".parse().unwrap(), false, 0)
+            .await?;
+
+        let fqdn: FQDN = "neo.the.matrix".parse().unwrap();
+        let token = svc.domain_verification_token(&matrix, &fqdn).await?;
+        assert_eq!(svc.domain_verification_token(&matrix, &fqdn).await?, token);
+        assert_ne!(
+            svc.domain_verification_token(&reloaded, &fqdn).await?,
+            token
+        );
+
+        let mut lookup = FakeTxtLookup::default();
+        lookup.0.insert(
+            domain_verification::record_name(&fqdn),
+            vec![domain_verification::record_value(&token)],
+        );
+        svc.txt_lookup = Some(Arc::new(lookup));
+
+        svc.check_domain_ownership(&matrix, &[fqdn.clone()]).await?;
+
+        // Another tenant pointing the same domain at the gateway does not have the token
+        assert_err_kind!(
+            svc.check_domain_ownership(&reloaded, &[fqdn.clone()]).await,
+            ErrorKind::DomainNotVerified
+        );
+
+        let other: FQDN = "trinity.the.matrix".parse().unwrap();
+        assert_err_kind!(
+            svc.check_domain_ownership(&matrix, &[fqdn.clone(), other.clone()])
+                .await,
+            ErrorKind::DomainNotVerified
+        );
+
+        // Domains the project already has are only getting a new certificate
+        svc.create_custom_domain_group(matrix.clone(), &[other.clone()], None, "cert", "key")
+            .await?;
+        svc.check_domain_ownership(&matrix, &[fqdn, other]).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn service_create_custom_domain_group() -> anyhow::Result<()> {
+        let world = World::new().await;
+        let svc = Arc::new(GatewayService::init(world.args(), world.pool()).await);
+
+        let account: AccountName = "neo".parse().unwrap();
+        let project_name: ProjectName = "matrix".parse().unwrap();
+        let domains: Vec<FQDN> = vec![
+            "the.matrix".parse().unwrap(),
+            "neo.the.matrix".parse().unwrap(),
+            "trinity.the.matrix".parse().unwrap(),
+        ];
+        let certificate = "dummy san certificate";
+        let private_key = "dummy private key";
+
+        let _ = svc
+            .create_project(project_name.clone(), account.clone(), false, 0)
+            .await
+            .unwrap();
+
+        svc.create_custom_domain_group(
+            project_name.clone(),
+            &domains,
+            None,
+            certificate,
+            private_key,
+        )
+        .await
+        .unwrap();
+
+        for domain in &domains {
+            let custom_domain = svc.project_details_for_custom_domain(domain).await.unwrap();
+
+            assert_eq!(custom_domain.project_name, project_name);
+            assert_eq!(custom_domain.certificate, certificate);
+            assert_eq!(custom_domain.certificate_group, domains[0]);
+        }
+
+        // The project should be served under the domain naming the group
+        let custom_domain = svc.find_custom_domain_for_project(&project_name).await?;
+        assert_eq!(custom_domain.fqdn, domains[0]);
+
+        assert_err_kind!(
+            svc.create_custom_domain_group(project_name, &[], None, certificate, private_key)
+                .await,
+            ErrorKind::InvalidCustomDomain
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn service_custom_domain_environments() -> anyhow::Result<()> {
+        let world = World::new().await;
+        let svc = Arc::new(GatewayService::init(world.args(), world.pool()).await);
+
+        let account: AccountName = "neo".parse().unwrap();
+        let production: ProjectName = "matrix".parse().unwrap();
+        let staging: ProjectName = "matrix-staging".parse().unwrap();
+        let production_domain: FQDN = "the.matrix".parse().unwrap();
+        let staging_domain: FQDN = "staging.the.matrix".parse().unwrap();
+
+        for project_name in [&production, &staging] {
+            svc.create_project(project_name.clone(), account.clone(), false, 0)
+                .await?;
+        }
+
+        svc.create_custom_domain(production.clone(), &production_domain, "cert", "key")
+            .await?;
+        svc.create_custom_domain_group(
+            staging.clone(),
+            &[staging_domain.clone()],
+            Some("staging"),
+            "staging cert",
+            "staging key",
+        )
+        .await?;
+
+        let custom_domain = svc
+            .project_details_for_custom_domain(&production_domain)
+            .await?;
+        assert_eq!(custom_domain.project_name, production);
+        assert_eq!(custom_domain.environment, None);
+
+        let custom_domain = svc
+            .project_details_for_custom_domain(&staging_domain)
+            .await?;
+        assert_eq!(custom_domain.project_name, staging);
+        assert_eq!(custom_domain.environment.as_deref(), Some("staging"));
+
+        let custom_domain = svc.find_custom_domain_for_project(&staging).await?;
+        assert_eq!(custom_domain.fqdn, staging_domain);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn service_ip_rules() -> anyhow::Result<()> {
+        let world = World::new().await;
+        let svc = Arc::new(GatewayService::init(world.args(), world.pool()).await);
+
+        let account: AccountName = "neo".parse().unwrap();
+        let project_name: ProjectName = "matrix".parse().unwrap();
+
+        let _ = svc
+            .create_project(project_name.clone(), account.clone(), false, 0)
+            .await
+            .unwrap();
+
+        let zion = parse_cidr("10.0.0.0/8").unwrap();
+        let agent = parse_cidr("10.0.0.1").unwrap();
+
+        svc.set_ip_rule(&project_name, &zion, ip_rule::Action::Allow)
+            .await?;
+        svc.set_ip_rule(&project_name, &agent, ip_rule::Action::Deny)
+            .await?;
+
+        let filter = svc.ip_filter_for_project(&project_name).await?;
+        assert!(filter.allows("10.0.0.2".parse().unwrap()));
+        assert!(!filter.allows("10.0.0.1".parse().unwrap()));
+        assert!(!filter.allows("192.168.0.1".parse().unwrap()));
+
+        svc.delete_ip_rule(&project_name, &zion).await?;
+        assert_eq!(
+            svc.ip_rules_for_project(&project_name).await?,
+            vec![ip_rule::Rule {
+                cidr: "10.0.0.1/32".to_string(),
+                action: ip_rule::Action::Deny,
+            }]
+        );
+
+        // The cached filter does not outlive the rule
+        let filter = svc.ip_filter_for_project(&project_name).await?;
+        assert!(filter.allows("192.168.0.1".parse().unwrap()));
+
+        assert_err_kind!(
+            svc.delete_ip_rule(&project_name, &zion).await,
+            ErrorKind::IpRuleNotFound
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn service_proxy_timeouts() -> anyhow::Result<()> {
+        let world = World::new().await;
+        let svc = Arc::new(GatewayService::init(world.args(), world.pool()).await);
+
+        let account: AccountName = "neo".parse().unwrap();
+        let project_name: ProjectName = "matrix".parse().unwrap();
+
+        let _ = svc
+            .create_project(project_name.clone(), account.clone(), false, 0)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            svc.proxy_timeouts_for_project(&project_name).await?,
+            timeouts::Config::default()
+        );
+
+        let wait = timeouts::Config {
+            response_secs: Some(30),
+            stalled_body: timeouts::StalledBody::Wait { idle_secs: 10 },
+            slow_request_ms: Some(500),
+        };
+        svc.set_proxy_timeouts(&project_name, &wait).await?;
+        assert_eq!(svc.proxy_timeouts_for_project(&project_name).await?, wait);
+
+        assert_err_kind!(
+            svc.set_proxy_timeouts(
+                &project_name,
+                &timeouts::Config {
+                    response_secs: None,
+                    stalled_body: timeouts::StalledBody::Abort,
+                    slow_request_ms: None,
+                }
+            )
+            .await,
+            ErrorKind::InvalidTimeouts
+        );
+        assert_err_kind!(
+            svc.set_proxy_timeouts(
+                &project_name,
+                &timeouts::Config {
+                    slow_request_ms: Some(0),
+                    ..Default::default()
+                }
+            )
+            .await,
+            ErrorKind::InvalidTimeouts
+        );
+        assert_err_kind!(
+            svc.set_proxy_timeouts(
+                &project_name,
+                &timeouts::Config {
+                    response_secs: Some(u64::MAX),
+                    ..Default::default()
+                }
+            )
+            .await,
+            ErrorKind::InvalidTimeouts
+        );
+        assert_err_kind!(
+            svc.set_proxy_timeouts(
+                &project_name,
+                &timeouts::Config {
+                    stalled_body: timeouts::StalledBody::Wait {
+                        idle_secs: timeouts::MAX_SECS + 1,
+                    },
+                    ..Default::default()
+                }
+            )
+            .await,
+            ErrorKind::InvalidTimeouts
+        );
+
+        svc.set_proxy_timeouts(&project_name, &timeouts::Config::default())
+            .await?;
+        assert_eq!(
+            svc.proxy_timeouts_for_project(&project_name).await?,
+            timeouts::Config::default()
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn service_pin_project() -> anyhow::Result<()> {
+        let world = World::new().await;
+        let svc = Arc::new(GatewayService::init(world.args(), world.pool()).await);
+
+        let account: AccountName = "neo".parse().unwrap();
+        let project_name: ProjectName = "matrix".parse().unwrap();
+
+        let _ = svc
+            .create_project(project_name.clone(), account.clone(), false, 0)
             .await
             .unwrap();
 
-        assert!(creating_same_project_name(&project, &matrix));
+        assert!(!svc.keep_warm().is_pinned(&project_name));
 
-        assert_eq!(svc.find_project(&matrix).await.unwrap(), project);
+        svc.pin_project(&project_name).await?;
+        assert!(svc.keep_warm().is_pinned(&project_name));
+
+        // Pins outlive a restart of the gateway
+        let restarted = GatewayService::init(world.args(), world.pool()).await;
+        assert!(restarted.keep_warm().is_pinned(&project_name));
+
+        restarted.unpin_project(&project_name).await?;
+        assert!(!restarted.keep_warm().is_pinned(&project_name));
+
+        let restarted = GatewayService::init(world.args(), world.pool()).await;
+        assert!(!restarted.keep_warm().is_pinned(&project_name));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn service_transfer_project() -> anyhow::Result<()> {
+        let world = World::new().await;
+        let svc = Arc::new(GatewayService::init(world.args(), world.pool()).await);
+
+        let neo: AccountName = "neo".parse().unwrap();
+        let trinity: AccountName = "trinity".parse().unwrap();
+        let morpheus: AccountName = "morpheus".parse().unwrap();
+        let matrix: ProjectName = "matrix".parse().unwrap();
+
+        svc.create_project(matrix.clone(), neo.clone(), false, 0)
+            .await
+            .unwrap();
+
+        assert_err_kind!(
+            svc.create_transfer(&matrix, &neo, &neo).await,
+            ErrorKind::InvalidTransfer
+        );
+        assert_err_kind!(
+            svc.create_transfer(&matrix, &neo, &"".parse().unwrap())
+                .await,
+            ErrorKind::InvalidTransfer
+        );
+
+        let transfer = svc.create_transfer(&matrix, &neo, &trinity).await?;
         assert_eq!(
-            svc.iter_projects_detailed()
-                .await
-                .unwrap()
-                .next()
-                .expect("to get one project with its user"),
-            ProjectDetails {
-                project_name: matrix.clone(),
-                account_name: neo.clone(),
-            }
+            svc.transfers_for_account(&neo).await?,
+            vec![transfer.clone()]
         );
+        assert_eq!(svc.transfers_for_account(&trinity).await?, vec![transfer]);
+        assert!(svc.transfers_for_account(&morpheus).await?.is_empty());
+
+        // Until it is accepted, the project stays with its owner and only the account it is offered to can accept it
         assert_eq!(
-            svc.iter_user_projects_detailed(neo.clone())
-                .await
-                .unwrap()
-                .map(|item| item.0)
-                .collect::<Vec<_>>(),
+            svc.iter_user_projects(&neo).await?.collect::<Vec<_>>(),
             vec![matrix.clone()]
         );
+        assert_err_kind!(
+            svc.accept_transfer(&matrix, &morpheus, false).await,
+            ErrorKind::TransferNotFound
+        );
 
-        // assert_eq!(
-        //     svc.iter_user_projects_detailed_filtered(neo.clone(), "ready".to_string())
-        //         .await
-        //         .unwrap()
-        //         .next()
-        //         .expect("to get one project with its user and a valid Ready status"),
-        //     (matrix.clone(), project)
-        // );
+        svc.accept_transfer(&matrix, &trinity, false).await?;
+        assert_eq!(svc.account_name_from_project(&matrix).await?, trinity);
+        assert!(svc.iter_user_projects(&neo).await?.next().is_none());
+        assert!(svc.transfers_for_account(&trinity).await?.is_empty());
 
-        // assert_eq!(
-        //     svc.iter_user_projects_detailed_filtered(neo.clone(), "destroyed".to_string())
-        //         .await
-        //         .unwrap()
-        //         .next(),
-        //     None
-        // );
+        // A transfer can be cancelled by either side, but only once
+        svc.create_transfer(&matrix, &trinity, &neo).await?;
+        assert_err_kind!(
+            svc.cancel_transfer(&matrix, &morpheus).await,
+            ErrorKind::TransferNotFound
+        );
+        svc.cancel_transfer(&matrix, &neo).await?;
+        assert_err_kind!(
+            svc.cancel_transfer(&matrix, &trinity).await,
+            ErrorKind::TransferNotFound
+        );
+        assert_err_kind!(
+            svc.accept_transfer(&matrix, &neo, false).await,
+            ErrorKind::TransferNotFound
+        );
 
-        let mut work = svc
-            .new_task()
-            .project(matrix.clone())
-            .and_then(task::destroy())
-            .build();
+        // An offer made by an account which no longer owns the project does not hold
+        svc.create_transfer(&matrix, &morpheus, &neo).await?;
+        assert_err_kind!(
+            svc.accept_transfer(&matrix, &neo, false).await,
+            ErrorKind::TransferNotFound
+        );
+        assert_eq!(svc.account_name_from_project(&matrix).await?, trinity);
 
-        while let TaskResult::Pending(_) = work.poll(()).await {}
-        assert!(matches!(work.poll(()).await, TaskResult::Done(())));
+        Ok(())
+    }
 
-        // After project has been destroyed...
-        assert!(matches!(
-            svc.find_project(&matrix).await,
-            Ok(Project::Destroyed(_))
-        ));
+    #[tokio::test]
+    async fn service_header_limits() -> anyhow::Result<()> {
+        let world = World::new().await;
+        let svc = Arc::new(GatewayService::init(world.args(), world.pool()).await);
 
-        // If recreated by a different user
-        assert!(matches!(
-            svc.create_project(matrix.clone(), trinity.clone(), false, 0)
-                .await,
-            Err(Error {
-                kind: ErrorKind::ProjectAlreadyExists,
-                ..
-            })
-        ));
+        let account: AccountName = "neo".parse().unwrap();
+        let project_name: ProjectName = "matrix".parse().unwrap();
 
-        // If recreated by the same user
-        assert!(matches!(
-            svc.create_project(matrix.clone(), neo, false, 0).await,
-            Ok(Project::Creating(_))
-        ));
+        let _ = svc
+            .create_project(project_name.clone(), account.clone(), false, 0)
+            .await
+            .unwrap();
 
-        let mut work = svc
-            .new_task()
-            .project(matrix.clone())
-            .and_then(task::destroy())
-            .build();
+        assert_eq!(
+            svc.header_limits_for_project(&project_name).await?,
+            header_limits::Config::default()
+        );
 
-        while let TaskResult::Pending(_) = work.poll(()).await {}
-        assert!(matches!(work.poll(()).await, TaskResult::Done(())));
+        let large_cookies = header_limits::Config {
+            max_total_size: Some(65536),
+            max_count: None,
+            max_header_size: Some(16384),
+        };
+        svc.set_header_limits(&project_name, &large_cookies).await?;
+        assert_eq!(
+            svc.header_limits_for_project(&project_name).await?,
+            large_cookies
+        );
+        assert_eq!(
+            large_cookies.or(svc.default_header_limits()),
+            header_limits::Config {
+                max_total_size: Some(65536),
+                max_count: Some(100),
+                max_header_size: Some(16384),
+            }
+        );
 
-        // After project has been destroyed again...
-        assert!(matches!(
-            svc.find_project(&matrix).await,
-            Ok(Project::Destroyed(_))
-        ));
+        assert_err_kind!(
+            svc.set_header_limits(
+                &project_name,
+                &header_limits::Config {
+                    max_count: Some(0),
+                    ..Default::default()
+                }
+            )
+            .await,
+            ErrorKind::InvalidHeaderLimits
+        );
 
-        // If recreated by an admin
-        assert!(matches!(
-            svc.create_project(matrix, trinity, true, 0).await,
-            Ok(Project::Creating(_))
-        ));
+        svc.set_header_limits(&project_name, &header_limits::Config::default())
+            .await?;
+        assert_eq!(
+            svc.header_limits_for_project(&project_name).await?,
+            header_limits::Config::default()
+        );
 
         Ok(())
     }
 
     #[tokio::test]
-    async fn service_create_ready_kill_restart_docker() -> anyhow::Result<()> {
+    async fn service_response_headers() -> anyhow::Result<()> {
         let world = World::new().await;
         let svc = Arc::new(GatewayService::init(world.args(), world.pool()).await);
 
-        let neo: AccountName = "neo".parse().unwrap();
-        let matrix: ProjectName = "matrix".parse().unwrap();
+        let account: AccountName = "neo".parse().unwrap();
+        let project_name: ProjectName = "matrix".parse().unwrap();
 
-        svc.create_project(matrix.clone(), neo.clone(), false, 0)
+        let _ = svc
+            .create_project(project_name.clone(), account.clone(), false, 0)
             .await
             .unwrap();
 
-        let mut task = svc.new_task().project(matrix.clone()).build();
+        assert!(svc
+            .response_headers_for_project(&project_name)
+            .await?
+            .is_empty());
+
+        let rules = response_header::Rules::from([
+            (
+                "server".to_string(),
+                response_header::Rule::Set("zion".to_string()),
+            ),
+            ("x-powered-by".to_string(), response_header::Rule::Remove),
+        ]);
+        svc.set_response_headers(&project_name, &rules).await?;
+        assert_eq!(
+            svc.response_headers_for_project(&project_name).await?,
+            rules
+        );
 
-        while let TaskResult::Pending(_) = task.poll(()).await {
-            // keep polling
+        for invalid in [
+            ("content-length".to_string(), response_header::Rule::Remove),
+            ("bad header".to_string(), response_header::Rule::Remove),
+            (
+                "server".to_string(),
+                response_header::Rule::Set("line\nbreak".to_string()),
+            ),
+        ] {
+            assert_err_kind!(
+                svc.set_response_headers(&project_name, &response_header::Rules::from([invalid]))
+                    .await,
+                ErrorKind::InvalidResponseHeader
+            );
         }
 
-        let project = svc.find_project(&matrix).await.unwrap();
-        println!("{:?}", project);
-        assert!(project.is_ready());
+        // Nothing changed by the rejected rules
+        assert_eq!(
+            svc.response_headers_for_project(&project_name).await?,
+            rules
+        );
 
-        let container = project.container().unwrap();
-        svc.context()
-            .docker()
-            .kill_container::<String>(container.name.unwrap().strip_prefix('/').unwrap(), None)
-            .await
-            .unwrap();
+        svc.set_response_headers(&project_name, &response_header::Rules::new())
+            .await?;
+        assert!(svc
+            .response_headers_for_project(&project_name)
+            .await?
+            .is_empty());
 
-        println!("killed container");
+        Ok(())
+    }
 
-        let mut ambulance_task = svc
-            .new_task()
-            .project(matrix.clone())
-            .and_then(task::check_health())
-            .build();
+    #[tokio::test]
+    async fn service_errored_project_recoveries() -> anyhow::Result<()> {
+        let world = World::new().await;
+        let svc = GatewayService::init(world.args(), world.pool()).await;
 
-        // the first poll will trigger a refresh
-        let _ = ambulance_task.poll(()).await;
+        let matrix: ProjectName = "matrix".parse().unwrap();
+        let reloaded: ProjectName = "reloaded".parse().unwrap();
 
-        let project = svc.find_project(&matrix).await.unwrap();
-        println!("{:?}", project);
-        assert!(!project.is_ready());
+        assert!(svc.should_recover(&matrix));
+        assert!(
+            !svc.should_recover(&matrix),
+            "a project should not be started again on every request"
+        );
+        assert!(svc.should_recover(&reloaded));
 
-        // the subsequent will trigger a restart task
-        while let TaskResult::Pending(_) = ambulance_task.poll(()).await {
-            // keep polling
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn service_account_usage() -> anyhow::Result<()> {
+        let world = World::new().await;
+        let args = ContextArgs {
+            max_projects_per_account: Some(2),
+            ..world.args()
+        };
+        let svc = Arc::new(GatewayService::init(args, world.pool()).await);
+
+        let neo: AccountName = "neo".parse().unwrap();
+        let admin: AccountName = "admin".parse().unwrap();
+
+        assert_eq!(
+            svc.account_usage(&neo).await?,
+            usage::Response {
+                account_name: "neo".to_string(),
+                projects: usage::Quota {
+                    used: 0,
+                    limit: Some(2),
+                },
+                running_projects: 0,
+            }
+        );
+
+        for project in ["matrix", "reloaded"] {
+            svc.create_project(project.parse().unwrap(), neo.clone(), false, 0)
+                .await?;
         }
 
-        let project = svc.find_project(&matrix).await.unwrap();
-        println!("{:?}", project);
-        assert!(project.is_ready());
+        let usage = svc.account_usage(&neo).await?;
+        assert_eq!(usage.projects.used, 2);
+        assert!(usage.projects.is_exhausted());
+
+        assert_err_kind!(
+            svc.create_project("revolutions".parse().unwrap(), neo.clone(), false, 0)
+                .await,
+            ErrorKind::ProjectLimitReached
+        );
+
+        // Admins are not held to the limit
+        for project in ["zion", "nebuchadnezzar", "logos"] {
+            svc.create_project(project.parse().unwrap(), admin.clone(), true, 0)
+                .await?;
+        }
+        assert_eq!(svc.account_usage(&admin).await?.projects.used, 3);
 
         Ok(())
     }
 
     #[tokio::test]
-    async fn service_create_find_custom_domain() -> anyhow::Result<()> {
+    async fn service_host_header() -> anyhow::Result<()> {
         let world = World::new().await;
         let svc = Arc::new(GatewayService::init(world.args(), world.pool()).await);
 
         let account: AccountName = "neo".parse().unwrap();
         let project_name: ProjectName = "matrix".parse().unwrap();
-        let domain: FQDN = "neo.the.matrix".parse().unwrap();
-        let certificate = "dummy certificate";
-        let private_key = "dummy private key";
-
-        assert_err_kind!(
-            svc.project_details_for_custom_domain(&domain).await,
-            ErrorKind::CustomDomainNotFound
-        );
 
         let _ = svc
             .create_project(project_name.clone(), account.clone(), false, 0)
             .await
             .unwrap();
 
-        svc.create_custom_domain(project_name.clone(), &domain, certificate, private_key)
-            .await
-            .unwrap();
-
-        let custom_domain = svc
-            .project_details_for_custom_domain(&domain)
-            .await
-            .unwrap();
-
-        assert_eq!(custom_domain.project_name, project_name);
-        assert_eq!(custom_domain.certificate, certificate);
-        assert_eq!(custom_domain.private_key, private_key);
+        assert_eq!(
+            svc.host_header_for_project(&project_name).await?,
+            host_header::Policy::Preserve
+        );
 
-        // Should auto replace the domain details
-        let certificate = "dummy certificate update";
-        let private_key = "dummy private key update";
+        let fixed = host_header::Policy::Fixed("construct.local:8080".to_string());
+        svc.set_host_header(&project_name, &fixed).await?;
+        assert_eq!(svc.host_header_for_project(&project_name).await?, fixed);
 
-        svc.create_custom_domain(project_name.clone(), &domain, certificate, private_key)
-            .await
-            .unwrap();
+        svc.set_host_header(&project_name, &host_header::Policy::Backend)
+            .await?;
+        assert_eq!(
+            svc.host_header_for_project(&project_name).await?,
+            host_header::Policy::Backend
+        );
 
-        let custom_domain = svc
-            .project_details_for_custom_domain(&domain)
-            .await
-            .unwrap();
+        assert_err_kind!(
+            svc.set_host_header(
+                &project_name,
+                &host_header::Policy::Fixed("neo@construct.local".to_string())
+            )
+            .await,
+            ErrorKind::InvalidHostHeader
+        );
 
-        assert_eq!(custom_domain.project_name, project_name);
-        assert_eq!(custom_domain.certificate, certificate);
-        assert_eq!(custom_domain.private_key, private_key);
+        svc.set_host_header(&project_name, &host_header::Policy::Preserve)
+            .await?;
+        assert_eq!(
+            svc.host_header_for_project(&project_name).await?,
+            host_header::Policy::Preserve
+        );
 
         Ok(())
     }
@@ -10,15 +10,14 @@ use tokio::time::{sleep, timeout};
 use tracing::{error, info_span, trace, warn};
 use uuid::Uuid;
 
+use crate::args::QueueFullPolicy;
 use crate::project::*;
 use crate::service::{GatewayContext, GatewayService};
-use crate::worker::TaskRouter;
+use crate::worker::{TaskRouter, WORKER_QUEUE_SIZE};
 use crate::{AccountName, EndState, Error, ErrorKind, ProjectName, Refresh, State};
 
 // Default maximum _total_ time a task is allowed to run
 pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(300);
-// Maximum time we'll wait for a task to successfully be sent down the channel
-pub const TASK_SEND_TIMEOUT: Duration = Duration::from_secs(9);
 // Maximum time before a task is considered degraded
 pub const PROJECT_TASK_MAX_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
 
@@ -135,6 +134,20 @@ pub fn start() -> impl Task<ProjectContext, Output = Project, Error = Error> {
     })
 }
 
+/// Start a project which is in an error state again, from the container it had before erroring. Projects which are not
+/// errored anymore are left alone.
+pub fn recover() -> impl Task<ProjectContext, Output = Project, Error = Error> {
+    run(|ctx| async move {
+        match ctx.state {
+            errored @ Project::Errored(_) => match errored.start() {
+                Ok(state) => TaskResult::Done(state),
+                Err(err) => TaskResult::Err(err),
+            },
+            state => TaskResult::Done(state),
+        }
+    })
+}
+
 pub fn check_health() -> impl Task<ProjectContext, Output = Project, Error = Error> {
     run(|ctx| async move {
         match ctx.state.refresh(&ctx.gateway).await {
@@ -210,12 +223,29 @@ impl TaskBuilder {
 
     pub async fn send(self, sender: &Sender<BoxedTask>) -> Result<TaskHandle, Error> {
         let project_name = self.project_name.clone().expect("project_name is required");
-        let task_router = self.service.task_router();
+        let service = self.service.clone();
         let (task, handle) = AndThenNotify::after(self.build());
-        let task = Route::<BoxedTask>::to(project_name, Box::new(task), task_router);
-        match timeout(TASK_SEND_TIMEOUT, sender.send(Box::new(task))).await {
-            Ok(Ok(_)) => Ok(handle),
-            _ => Err(Error::from_kind(ErrorKind::ServiceUnavailable)),
+        let task = Route::<BoxedTask>::to(project_name, Box::new(task), service.task_router());
+        let task: BoxedTask = Box::new(task);
+
+        let policy = service.queue_full_policy();
+        let sent = match policy {
+            QueueFullPolicy::Backpressure => matches!(
+                timeout(service.queue_send_timeout(), sender.send(task)).await,
+                Ok(Ok(_))
+            ),
+            QueueFullPolicy::FailFast => sender.try_send(task).is_ok(),
+        };
+
+        if sent {
+            Ok(handle)
+        } else {
+            warn!(
+                ?policy,
+                queue_depth = WORKER_QUEUE_SIZE.saturating_sub(sender.capacity()),
+                "worker queue is full, rejecting task"
+            );
+            Err(Error::from_kind(ErrorKind::ServiceUnavailable))
         }
     }
 }
@@ -1,21 +1,35 @@
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::{BufReader, Read, Write};
-use std::path::Path;
-use std::sync::Arc;
-
-use axum_server::accept::DefaultAcceptor;
-use axum_server::tls_rustls::{RustlsAcceptor, RustlsConfig};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::future::Future;
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum_server::accept::Accept;
+use chrono::{DateTime, TimeZone, Utc};
 use futures::executor::block_on;
 use pem::Pem;
-use rustls::server::{ClientHello, ResolvesServerCert};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use rustls::server::{
+    Acceptor, ClientHello, NoServerSessionStorage, ProducesTickets, ResolvesServerCert,
+    ServerSessionMemoryCache,
+};
 use rustls::sign::{self, CertifiedKey};
 use rustls::{Certificate, PrivateKey, ServerConfig};
 use rustls_pemfile::Item;
 use shuttle_common::models::error::ErrorKind;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::runtime::Handle;
 use tokio::sync::RwLock;
+use tokio::time::timeout;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::LazyConfigAcceptor;
+use tracing::{debug, trace, warn};
 
+use crate::args::AlpnProtocol;
 use crate::Error;
 
 #[derive(Clone)]
@@ -43,7 +57,7 @@ impl ChainAndPrivateKey {
 
         Ok(Self {
             chain,
-            private_key: private_key.unwrap(),
+            private_key: private_key.ok_or_else(|| Error::from_kind(ErrorKind::Internal))?,
         })
     }
 
@@ -69,6 +83,43 @@ impl ChainAndPrivateKey {
         Ok(pem::encode_many(&pems))
     }
 
+    /// Whether the leaf certificate of the chain is valid for `dns_name`, through a wildcard or otherwise
+    pub fn is_valid_for(&self, dns_name: &str) -> bool {
+        let Some(leaf) = self.chain.first() else {
+            return false;
+        };
+        let Ok(leaf) = webpki::EndEntityCert::try_from(leaf.0.as_slice()) else {
+            return false;
+        };
+        let Ok(dns_name) = webpki::DnsNameRef::try_from_ascii_str(dns_name) else {
+            return false;
+        };
+
+        leaf.verify_is_valid_for_dns_name(dns_name).is_ok()
+    }
+
+    /// When the leaf certificate of the chain stops being valid
+    pub fn not_after(&self) -> Option<DateTime<Utc>> {
+        let leaf = self.chain.first()?;
+        let (_, leaf) = x509_parser::parse_x509_certificate(&leaf.0).ok()?;
+
+        Utc.timestamp_opt(leaf.validity().not_after.timestamp(), 0)
+            .single()
+    }
+
+    /// Whether the leaf certificate of the chain stops being valid within `threshold` of `now`. A chain it cannot be
+    /// told of is due as well.
+    pub fn expires_within(&self, threshold: Duration, now: DateTime<Utc>) -> bool {
+        let Some(not_after) = self.not_after() else {
+            return true;
+        };
+        let Ok(threshold) = chrono::Duration::from_std(threshold) else {
+            return true;
+        };
+
+        not_after - now < threshold
+    }
+
     pub fn into_certified_key(self) -> Result<CertifiedKey, Error> {
         let signing_key = sign::any_supported_type(&self.private_key)
             .map_err(|_| Error::from_kind(ErrorKind::Internal))?;
@@ -83,9 +134,47 @@ impl ChainAndPrivateKey {
     }
 }
 
+/// How long a client gets to complete the TLS handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Number of server names TLS errors are counted for separately. Clients pick the server name, so anything over this
+/// is counted under [OTHER_SERVER_NAMES] to keep the counts from growing without bound.
+const MAX_TRACKED_SERVER_NAMES: usize = 1024;
+const OTHER_SERVER_NAMES: &str = "<other>";
+const NO_SERVER_NAME: &str = "<none>";
+
+/// Failed TLS handshakes of the user proxy, by the server name the client asked for
+#[derive(Default)]
+pub struct TlsErrors {
+    by_server_name: Mutex<HashMap<String, u64>>,
+}
+
+impl TlsErrors {
+    pub fn record(&self, server_name: Option<&str>) {
+        let mut by_server_name = self.by_server_name.lock().unwrap();
+        let mut key = server_name.unwrap_or(NO_SERVER_NAME);
+
+        if !by_server_name.contains_key(key) && by_server_name.len() >= MAX_TRACKED_SERVER_NAMES {
+            key = OTHER_SERVER_NAMES;
+        }
+
+        *by_server_name.entry(key.to_string()).or_default() += 1;
+    }
+
+    pub fn counts(&self) -> BTreeMap<String, u64> {
+        self.by_server_name
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(server_name, count)| (server_name.clone(), *count))
+            .collect()
+    }
+}
+
 pub struct GatewayCertResolver {
     keys: RwLock<HashMap<String, Arc<CertifiedKey>>>,
     default: RwLock<Option<Arc<CertifiedKey>>>,
+    errors: Arc<TlsErrors>,
 }
 
 impl Default for GatewayCertResolver {
@@ -99,9 +188,15 @@ impl GatewayCertResolver {
         Self {
             keys: RwLock::new(HashMap::default()),
             default: RwLock::new(None),
+            errors: Default::default(),
         }
     }
 
+    /// Failed handshakes of the connections this resolver serves certificates to
+    pub fn tls_errors(&self) -> Arc<TlsErrors> {
+        self.errors.clone()
+    }
+
     /// Get the loaded [CertifiedKey] associated with the given
     /// domain.
     pub async fn get(&self, sni: &str) -> Option<Arc<CertifiedKey>> {
@@ -133,33 +228,441 @@ impl GatewayCertResolver {
         let certs = ChainAndPrivateKey::parse_pem(rd)?;
         self.serve_der(sni, certs).await
     }
+
+    /// Swap the certificate served for a domain with one which was rotated out-of-band. New connections get the new
+    /// certificate while established ones keep theirs. The old certificate stays when the new one cannot be used for
+    /// the domain.
+    pub async fn replace_pem<R: Read>(&self, sni: &str, rd: R) -> Result<(), Error> {
+        let certs = ChainAndPrivateKey::parse_pem(rd)
+            .map_err(|_| Error::from_kind(ErrorKind::InvalidCertificate))?;
+
+        if !certs.is_valid_for(sni) {
+            return Err(Error::from_kind(ErrorKind::InvalidCertificate));
+        }
+
+        let certified_key = certs
+            .into_certified_key()
+            .map_err(|_| Error::from_kind(ErrorKind::InvalidCertificate))?;
+        let replaced = self
+            .keys
+            .write()
+            .await
+            .insert(sni.to_string(), Arc::new(certified_key))
+            .is_some();
+
+        debug!(sni, replaced, "reloaded custom domain certificate");
+
+        Ok(())
+    }
 }
 
 impl ResolvesServerCert for GatewayCertResolver {
     fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
-        let sni = client_hello.server_name()?;
+        let Some(sni) = client_hello.server_name() else {
+            debug!("client did not send a server name, no certificate to serve");
+            return None;
+        };
         let handle = Handle::current();
         let _ = handle.enter();
         block_on(async move {
             if let Some(cert) = self.get(sni).await {
+                trace!(sni, "serving custom domain certificate");
+                Some(cert)
+            } else if let Some(cert) = self.default.read().await.clone() {
+                trace!(sni, "serving default certificate");
                 Some(cert)
             } else {
-                self.default.read().await.clone()
+                warn!(sni, "no certificate to serve, not even a default one");
+                None
+            }
+        })
+    }
+}
+
+/// How long a session ticket can be used to resume a session. A key which is rotated out should stay in the key file
+/// for at least this long.
+const TICKET_LIFETIME_SECS: u32 = 12 * 60 * 60;
+
+/// Encrypts TLS session tickets with keys read from a file, so that a session started with one replica of the gateway
+/// can be resumed with any other.
+///
+/// The file has one base64 encoded 32 byte key per line. The first key encrypts new tickets while the others are only
+/// used to decrypt, so keys are rotated by adding a new first line and dropping the last one once its tickets have
+/// expired.
+pub struct SharedTicketer {
+    path: PathBuf,
+    keys: std::sync::RwLock<Vec<LessSafeKey>>,
+}
+
+impl SharedTicketer {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let keys = Self::read_keys(&path)?;
+
+        Ok(Self {
+            path,
+            keys: std::sync::RwLock::new(keys),
+        })
+    }
+
+    /// Pick up rotated keys. The keys in use are kept when the file cannot be read.
+    pub fn reload(&self) -> Result<(), Error> {
+        let keys = Self::read_keys(&self.path)?;
+        *self.keys.write().unwrap() = keys;
+
+        Ok(())
+    }
+
+    fn read_keys(path: &Path) -> Result<Vec<LessSafeKey>, Error> {
+        let keys = fs::read_to_string(path)?
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                let key =
+                    base64::decode(line).map_err(|_| Error::from_kind(ErrorKind::Internal))?;
+                let key = UnboundKey::new(&AES_256_GCM, &key)
+                    .map_err(|_| Error::from_kind(ErrorKind::Internal))?;
+
+                Ok(LessSafeKey::new(key))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        if keys.is_empty() {
+            return Err(Error::from_kind(ErrorKind::Internal));
+        }
+
+        Ok(keys)
+    }
+}
+
+impl ProducesTickets for SharedTicketer {
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    fn lifetime(&self) -> u32 {
+        TICKET_LIFETIME_SECS
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        let mut nonce = [0; NONCE_LEN];
+        SystemRandom::new().fill(&mut nonce).ok()?;
+
+        let mut in_out = plain.to_vec();
+        self.keys
+            .read()
+            .unwrap()
+            .first()?
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::empty(),
+                &mut in_out,
+            )
+            .ok()?;
+
+        Some([nonce.as_slice(), &in_out].concat())
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        if cipher.len() < NONCE_LEN {
+            return None;
+        }
+
+        let (nonce, ciphertext) = cipher.split_at(NONCE_LEN);
+
+        self.keys.read().unwrap().iter().find_map(|key| {
+            let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+            let mut in_out = ciphertext.to_vec();
+            let plain = key.open_in_place(nonce, Aad::empty(), &mut in_out).ok()?;
+
+            Some(plain.to_vec())
+        })
+    }
+}
+
+/// How clients can skip the full handshake when they come back
+pub struct SessionResumption {
+    /// Number of sessions remembered by this process. `0` turns the session cache off.
+    pub cache_size: usize,
+    /// Produces the session tickets handed to clients. Tickets are not handed out when `None`.
+    pub ticketer: Option<Arc<dyn ProducesTickets>>,
+}
+
+/// Accepts TLS connections, keeping track of the handshakes which fail
+#[derive(Clone)]
+pub struct GatewayTlsAcceptor {
+    config: Arc<ServerConfig>,
+    errors: Arc<TlsErrors>,
+}
+
+impl<I, S> Accept<I, S> for GatewayTlsAcceptor
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Send + 'static,
+{
+    type Stream = TlsStream<I>;
+    type Service = S;
+    type Future = Pin<Box<dyn Future<Output = io::Result<(Self::Stream, Self::Service)>> + Send>>;
+
+    fn accept(&self, stream: I, service: S) -> Self::Future {
+        let config = self.config.clone();
+        let errors = self.errors.clone();
+
+        Box::pin(async move {
+            // Only known once the client hello is in
+            let mut server_name = None;
+
+            let handshake = async {
+                let acceptor =
+                    Acceptor::new().map_err(|error| io::Error::new(io::ErrorKind::Other, error))?;
+                let start = LazyConfigAcceptor::new(acceptor, stream).await?;
+                server_name = Some(start.client_hello().server_name().map(str::to_string));
+
+                start.into_stream(config).await
+            };
+
+            let result = match timeout(HANDSHAKE_TIMEOUT, handshake).await {
+                Ok(result) => result,
+                Err(_) => Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "handshake timed out",
+                )),
+            };
+
+            match (result, server_name) {
+                (Ok(stream), _) => Ok((stream, service)),
+                (Err(error), Some(server_name)) => {
+                    warn!(
+                        sni = server_name.as_deref().unwrap_or(NO_SERVER_NAME),
+                        reason = %error,
+                        "TLS handshake failed"
+                    );
+                    errors.record(server_name.as_deref());
+
+                    Err(error)
+                }
+                // Clients which go away before saying which server they want are not worth counting
+                (Err(error), None) => {
+                    debug!(reason = %error, "connection closed before the TLS client hello");
+
+                    Err(error)
+                }
             }
         })
     }
 }
 
-pub fn make_tls_acceptor() -> (Arc<GatewayCertResolver>, RustlsAcceptor<DefaultAcceptor>) {
+/// IDs of the protocols to offer over ALPN, in the order they are given. Each protocol can only be given once.
+pub fn alpn_ids(protocols: &[AlpnProtocol]) -> Result<Vec<Vec<u8>>, String> {
+    if protocols.is_empty() {
+        return Err("at least one ALPN protocol has to be offered".to_string());
+    }
+
+    let mut ids: Vec<Vec<u8>> = Vec::with_capacity(protocols.len());
+
+    for protocol in protocols {
+        let id = protocol.id().to_vec();
+
+        if ids.contains(&id) {
+            return Err(format!(
+                "the ALPN protocol '{}' is given more than once",
+                String::from_utf8_lossy(&id)
+            ));
+        }
+
+        ids.push(id);
+    }
+
+    Ok(ids)
+}
+
+pub fn make_tls_acceptor(
+    resumption: SessionResumption,
+    alpn_protocols: Vec<Vec<u8>>,
+) -> (Arc<GatewayCertResolver>, GatewayTlsAcceptor) {
     let resolver = Arc::new(GatewayCertResolver::new());
 
     let mut server_config = ServerConfig::builder()
         .with_safe_defaults()
         .with_no_client_auth()
         .with_cert_resolver(Arc::clone(&resolver) as Arc<dyn ResolvesServerCert>);
-    server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    server_config.alpn_protocols = alpn_protocols;
 
-    let rustls_config = RustlsConfig::from_config(Arc::new(server_config));
+    server_config.session_storage = if resumption.cache_size == 0 {
+        Arc::new(NoServerSessionStorage {})
+    } else {
+        ServerSessionMemoryCache::new(resumption.cache_size)
+    };
+    if let Some(ticketer) = resumption.ticketer {
+        server_config.ticketer = ticketer;
+    }
+
+    let acceptor = GatewayTlsAcceptor {
+        config: Arc::new(server_config),
+        errors: resolver.tls_errors(),
+    };
+
+    (resolver, acceptor)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::time::Duration;
 
-    (resolver, RustlsAcceptor::new(rustls_config))
+    use chrono::{TimeZone, Utc};
+    use rustls::server::ProducesTickets;
+    use tempfile::NamedTempFile;
+
+    use super::{
+        alpn_ids, ChainAndPrivateKey, GatewayCertResolver, SharedTicketer, TlsErrors,
+        MAX_TRACKED_SERVER_NAMES,
+    };
+    use crate::args::AlpnProtocol;
+
+    fn self_signed(names: &[&str]) -> ChainAndPrivateKey {
+        let names = names.iter().map(ToString::to_string).collect::<Vec<_>>();
+        let cert = rcgen::generate_simple_self_signed(names).unwrap();
+        let pem = cert.serialize_pem().unwrap() + &cert.serialize_private_key_pem();
+
+        ChainAndPrivateKey::parse_pem(pem.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn valid_for_apex() {
+        let wildcard = self_signed(&["*.shuttleapp.rs"]);
+        assert!(wildcard.is_valid_for("matrix.shuttleapp.rs"));
+        assert!(!wildcard.is_valid_for("shuttleapp.rs"));
+
+        let both = self_signed(&["*.shuttleapp.rs", "shuttleapp.rs"]);
+        assert!(both.is_valid_for("matrix.shuttleapp.rs"));
+        assert!(both.is_valid_for("shuttleapp.rs"));
+        assert!(!both.is_valid_for("the.matrix.shuttleapp.rs"));
+    }
+
+    #[test]
+    fn expiry() {
+        let now = Utc.with_ymd_and_hms(2023, 1, 1, 0, 0, 0).unwrap();
+        let expiring = |year, month, day| {
+            let mut params = rcgen::CertificateParams::new(vec!["neo.the.matrix".to_string()]);
+            params.not_after = rcgen::date_time_ymd(year, month, day);
+            let cert = rcgen::Certificate::from_params(params).unwrap();
+            let pem = cert.serialize_pem().unwrap() + &cert.serialize_private_key_pem();
+
+            ChainAndPrivateKey::parse_pem(pem.as_bytes()).unwrap()
+        };
+        let thirty_days = Duration::from_secs(30 * 24 * 60 * 60);
+
+        let certs = expiring(2023, 1, 20);
+        assert_eq!(
+            certs.not_after(),
+            Some(Utc.with_ymd_and_hms(2023, 1, 20, 0, 0, 0).unwrap())
+        );
+        assert!(certs.expires_within(thirty_days, now));
+        assert!(!certs.expires_within(Duration::from_secs(60 * 60), now));
+
+        assert!(!expiring(2023, 3, 1).expires_within(thirty_days, now));
+        assert!(expiring(2022, 12, 1).expires_within(thirty_days, now));
+    }
+
+    #[tokio::test]
+    async fn replace_certificate() {
+        let pem = |names: &[&str]| self_signed(names).into_pem().unwrap();
+        let resolver = GatewayCertResolver::new();
+
+        resolver
+            .serve_pem("neo.the.matrix", pem(&["neo.the.matrix"]).as_bytes())
+            .await
+            .unwrap();
+        let served = resolver.get("neo.the.matrix").await.unwrap();
+
+        let rotated = pem(&["neo.the.matrix"]);
+        resolver
+            .replace_pem("neo.the.matrix", rotated.as_bytes())
+            .await
+            .unwrap();
+        let reloaded = resolver.get("neo.the.matrix").await.unwrap();
+        assert_ne!(reloaded.cert, served.cert);
+
+        // Neither a certificate for another domain nor garbage takes the place of the working one
+        assert!(resolver
+            .replace_pem("neo.the.matrix", pem(&["trinity.the.matrix"]).as_bytes())
+            .await
+            .is_err());
+        assert!(resolver
+            .replace_pem("neo.the.matrix", "not a certificate".as_bytes())
+            .await
+            .is_err());
+        assert_eq!(
+            resolver.get("neo.the.matrix").await.unwrap().cert,
+            reloaded.cert
+        );
+    }
+
+    fn key_line(byte: u8) -> String {
+        format!("{}\n", base64::encode([byte; 32]))
+    }
+
+    #[test]
+    fn shared_ticketer_rotation() {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(key_line(1).as_bytes()).unwrap();
+
+        let ticketer = SharedTicketer::load(file.path()).unwrap();
+        let replica = SharedTicketer::load(file.path()).unwrap();
+
+        let ticket = ticketer.encrypt(b"session state").unwrap();
+        assert_ne!(ticket, ticketer.encrypt(b"session state").unwrap());
+        assert_eq!(replica.decrypt(&ticket).unwrap(), b"session state");
+
+        // Rotate in a new key while keeping the old one around for decryption
+        std::fs::write(file.path(), key_line(2) + &key_line(1)).unwrap();
+        ticketer.reload().unwrap();
+
+        let rotated = ticketer.encrypt(b"new session").unwrap();
+        assert_eq!(ticketer.decrypt(&ticket).unwrap(), b"session state");
+        assert!(
+            replica.decrypt(&rotated).is_none(),
+            "replica has not picked up the new key yet"
+        );
+
+        // A broken file leaves the keys in use alone
+        std::fs::write(file.path(), "not a key").unwrap();
+        assert!(ticketer.reload().is_err());
+        assert_eq!(ticketer.decrypt(&rotated).unwrap(), b"new session");
+
+        assert!(ticketer.decrypt(b"short").is_none());
+        assert!(SharedTicketer::load(file.path()).is_err());
+    }
+
+    #[test]
+    fn tls_errors_bounded() {
+        let errors = TlsErrors::default();
+
+        errors.record(Some("custom.example.com"));
+        errors.record(Some("custom.example.com"));
+        errors.record(None);
+
+        for i in 0..MAX_TRACKED_SERVER_NAMES {
+            errors.record(Some(&format!("made-up-{i}.example.com")));
+        }
+
+        let counts = errors.counts();
+        assert_eq!(counts["custom.example.com"], 2);
+        assert_eq!(counts["<none>"], 1);
+        assert_eq!(counts.len(), MAX_TRACKED_SERVER_NAMES + 1);
+        assert_eq!(counts["<other>"], 2);
+    }
+
+    #[test]
+    fn alpn_protocols() {
+        assert_eq!(
+            alpn_ids(&[AlpnProtocol::H2, AlpnProtocol::Http1]).unwrap(),
+            vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+        );
+
+        assert!(alpn_ids(&[]).is_err());
+        assert!(alpn_ids(&[AlpnProtocol::Http1, AlpnProtocol::Http1]).is_err());
+    }
 }
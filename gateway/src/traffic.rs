@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use http::StatusCode;
+use shuttle_common::models::stats::{TrafficResponse, TrafficWindow};
+
+use crate::ProjectName;
+
+/// Width of the slots requests are counted in
+const BUCKET_WIDTH: Duration = Duration::from_secs(10);
+
+/// Upper bounds of the latency histogram. Slower requests are counted in the last slot.
+const LATENCY_BOUNDS_MS: [u64; 13] = [
+    1, 2, 5, 10, 25, 50, 100, 250, 500, 1_000, 2_500, 10_000, 60_000,
+];
+
+/// Request counts and latencies of every project going through the user proxy.
+///
+/// Each project has a ring of fixed width buckets covering the largest window, so memory stays
+/// bounded no matter how much traffic a project gets. Percentiles are estimated from a coarse
+/// histogram and reported as the upper bound of the slot they fall in.
+pub struct TrafficStats {
+    windows: Vec<Duration>,
+    started: Instant,
+    projects: Mutex<HashMap<ProjectName, Vec<Bucket>>>,
+}
+
+#[derive(Clone, Default)]
+struct Bucket {
+    /// Number of [BUCKET_WIDTH]s since the stats were started, telling whether this slot of the
+    /// ring is current or left over from an earlier lap
+    index: u64,
+    requests: u64,
+    errors: u64,
+    latencies: [u64; LATENCY_BOUNDS_MS.len()],
+}
+
+impl TrafficStats {
+    pub fn new(windows: Vec<Duration>) -> Self {
+        Self {
+            windows,
+            started: Instant::now(),
+            projects: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Count a request to a project which was answered with `status` after `latency`
+    pub fn record(&self, project_name: &ProjectName, status: StatusCode, latency: Duration) {
+        self.record_at(project_name, status, latency, Instant::now())
+    }
+
+    /// Traffic of a project over each of the configured windows
+    pub fn summary(&self, project_name: &ProjectName) -> TrafficResponse {
+        self.summary_at(project_name, Instant::now())
+    }
+
+    fn record_at(
+        &self,
+        project_name: &ProjectName,
+        status: StatusCode,
+        latency: Duration,
+        now: Instant,
+    ) {
+        let index = self.bucket_index(now);
+        let ring_len = self.ring_len();
+
+        let mut projects = self.projects.lock().unwrap();
+        let ring = projects
+            .entry(project_name.clone())
+            .or_insert_with(|| vec![Bucket::default(); ring_len]);
+        let bucket = &mut ring[(index % ring_len as u64) as usize];
+
+        if bucket.index != index {
+            *bucket = Bucket {
+                index,
+                ..Default::default()
+            };
+        }
+
+        bucket.requests += 1;
+        if status.is_server_error() {
+            bucket.errors += 1;
+        }
+
+        let latency_ms = latency.as_millis() as u64;
+        let slot = LATENCY_BOUNDS_MS
+            .iter()
+            .position(|bound| latency_ms <= *bound)
+            .unwrap_or(LATENCY_BOUNDS_MS.len() - 1);
+        bucket.latencies[slot] += 1;
+    }
+
+    fn summary_at(&self, project_name: &ProjectName, now: Instant) -> TrafficResponse {
+        let current = self.bucket_index(now);
+        let projects = self.projects.lock().unwrap();
+        let ring = projects.get(project_name);
+
+        let windows = self
+            .windows
+            .iter()
+            .map(|window| {
+                let span = Self::buckets_in(*window);
+                let mut total = Bucket::default();
+
+                for bucket in ring.into_iter().flatten() {
+                    // Only buckets of the current lap which fall inside the window
+                    if bucket.index <= current && current - bucket.index < span {
+                        total.requests += bucket.requests;
+                        total.errors += bucket.errors;
+                        for (sum, count) in total.latencies.iter_mut().zip(bucket.latencies) {
+                            *sum += count;
+                        }
+                    }
+                }
+
+                TrafficWindow {
+                    seconds: window.as_secs(),
+                    requests: total.requests,
+                    errors: total.errors,
+                    p50_ms: percentile(&total.latencies, total.requests, 50),
+                    p95_ms: percentile(&total.latencies, total.requests, 95),
+                }
+            })
+            .collect();
+
+        TrafficResponse { windows }
+    }
+
+    fn bucket_index(&self, now: Instant) -> u64 {
+        (now.duration_since(self.started).as_secs() / BUCKET_WIDTH.as_secs()) + 1
+    }
+
+    fn ring_len(&self) -> usize {
+        self.windows
+            .iter()
+            .map(|window| Self::buckets_in(*window))
+            .max()
+            .unwrap_or(1) as usize
+    }
+
+    fn buckets_in(window: Duration) -> u64 {
+        ((window.as_secs() + BUCKET_WIDTH.as_secs() - 1) / BUCKET_WIDTH.as_secs()).max(1)
+    }
+}
+
+fn percentile(latencies: &[u64], requests: u64, percent: u64) -> Option<u64> {
+    if requests == 0 {
+        return None;
+    }
+
+    let rank = (requests * percent + 99) / 100;
+    let mut seen = 0;
+
+    latencies
+        .iter()
+        .zip(LATENCY_BOUNDS_MS)
+        .find_map(|(count, bound)| {
+            seen += count;
+            (seen >= rank).then_some(bound)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use http::StatusCode;
+
+    use super::TrafficStats;
+
+    #[test]
+    fn windows() {
+        let stats = TrafficStats::new(vec![Duration::from_secs(60), Duration::from_secs(300)]);
+        let project = "matrix".parse().unwrap();
+        let start = Instant::now();
+
+        for _ in 0..18 {
+            stats.record_at(&project, StatusCode::OK, Duration::from_millis(4), start);
+        }
+        stats.record_at(
+            &project,
+            StatusCode::BAD_GATEWAY,
+            Duration::from_millis(400),
+            start,
+        );
+        stats.record_at(
+            &project,
+            StatusCode::NOT_FOUND,
+            Duration::from_millis(400),
+            start,
+        );
+
+        let later = start + Duration::from_secs(120);
+        stats.record_at(&project, StatusCode::OK, Duration::from_millis(40), later);
+
+        let summary = stats.summary_at(&project, later);
+        let [minute, five_minutes] = summary.windows.as_slice() else {
+            panic!("expected two windows");
+        };
+
+        assert_eq!(minute.seconds, 60);
+        assert_eq!(minute.requests, 1);
+        assert_eq!(minute.errors, 0);
+        assert_eq!(minute.p50_ms, Some(50));
+
+        assert_eq!(five_minutes.requests, 21);
+        assert_eq!(five_minutes.errors, 1);
+        assert_eq!(five_minutes.p50_ms, Some(5));
+        assert_eq!(five_minutes.p95_ms, Some(500));
+
+        // Everything has fallen out of the ring by now
+        let summary = stats.summary_at(&project, start + Duration::from_secs(900));
+        assert_eq!(summary.windows[1].requests, 0);
+        assert_eq!(summary.windows[1].p50_ms, None);
+
+        let unknown = stats.summary_at(&"unknown".parse().unwrap(), later);
+        assert_eq!(unknown.windows[0].requests, 0);
+    }
+}
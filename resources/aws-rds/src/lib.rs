@@ -1,5 +1,7 @@
 #![doc = include_str!("../README.md")]
 
+use std::collections::BTreeMap;
+
 use async_trait::async_trait;
 use paste::paste;
 use shuttle_service::{
@@ -16,6 +18,7 @@ macro_rules! aws_engine {
             #[doc = "A resource connected to an AWS RDS " $struct_ident " instance"]
             pub struct $struct_ident{
                 local_uri: Option<String>,
+                metadata: BTreeMap<String, String>,
             }
 
             #[cfg(feature = $feature)]
@@ -23,14 +26,14 @@ macro_rules! aws_engine {
             #[async_trait]
             impl ResourceBuilder<$pool_path> for $struct_ident {
                 fn new() -> Self {
-                    Self { local_uri: None }
+                    Self { local_uri: None, metadata: BTreeMap::new() }
                 }
 
                 async fn build(self, factory: &mut dyn Factory, runtime: &Runtime) -> Result<$pool_path, shuttle_service::Error> {
                     let connection_string = match factory.get_environment() {
                         shuttle_service::Environment::Production => {
                             factory
-                                .get_db_connection_string(Type::AwsRds(AwsRdsEngine::$struct_ident))
+                                .get_db_connection_string_with_metadata(Type::AwsRds(AwsRdsEngine::$struct_ident), self.metadata.clone())
                                 .await?
                         }
                         shuttle_service::Environment::Local => {
@@ -38,7 +41,7 @@ macro_rules! aws_engine {
                                 local_uri
                             } else {
                                 factory
-                                    .get_db_connection_string(Type::AwsRds(AwsRdsEngine::$struct_ident))
+                                    .get_db_connection_string_with_metadata(Type::AwsRds(AwsRdsEngine::$struct_ident), self.metadata.clone())
                                     .await?
                             }
                         }
@@ -69,6 +72,13 @@ macro_rules! aws_engine {
 
                     self
                 }
+
+                /// Tag the database with `key=value` pairs separated by commas, like `purpose=reports,retention=30d`
+                pub fn metadata(mut self, metadata: &str) -> Self {
+                    self.metadata.extend(parse_metadata(metadata));
+
+                    self
+                }
             }
         }
     };
@@ -94,3 +104,15 @@ aws_engine!(
     sqlx::mysql::MySqlPoolOptions,
     MariaDB
 );
+
+/// Split `key=value` pairs separated by commas. A key without a value gets an empty one
+fn parse_metadata(metadata: &str) -> impl Iterator<Item = (String, String)> + '_ {
+    metadata
+        .split(',')
+        .filter(|pair| !pair.trim().is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+
+            (key.trim().to_string(), value.trim().to_string())
+        })
+}
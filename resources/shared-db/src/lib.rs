@@ -1,5 +1,7 @@
 #![doc = include_str!("../README.md")]
 
+use std::collections::BTreeMap;
+
 use tokio::runtime::Runtime;
 
 use async_trait::async_trait;
@@ -8,6 +10,7 @@ use shuttle_service::{database, error::CustomError, Error, Factory, ResourceBuil
 #[cfg(feature = "postgres")]
 pub struct Postgres {
     local_uri: Option<String>,
+    metadata: BTreeMap<String, String>,
 }
 
 #[cfg(feature = "postgres")]
@@ -15,7 +18,10 @@ pub struct Postgres {
 #[async_trait]
 impl ResourceBuilder<sqlx::PgPool> for Postgres {
     fn new() -> Self {
-        Self { local_uri: None }
+        Self {
+            local_uri: None,
+            metadata: BTreeMap::new(),
+        }
     }
 
     async fn build(
@@ -26,9 +32,10 @@ impl ResourceBuilder<sqlx::PgPool> for Postgres {
         let connection_string = match factory.get_environment() {
             shuttle_service::Environment::Production => {
                 factory
-                    .get_db_connection_string(database::Type::Shared(
-                        database::SharedEngine::Postgres,
-                    ))
+                    .get_db_connection_string_with_metadata(
+                        database::Type::Shared(database::SharedEngine::Postgres),
+                        self.metadata.clone(),
+                    )
                     .await?
             }
             shuttle_service::Environment::Local => {
@@ -36,9 +43,10 @@ impl ResourceBuilder<sqlx::PgPool> for Postgres {
                     local_uri
                 } else {
                     factory
-                        .get_db_connection_string(database::Type::Shared(
-                            database::SharedEngine::Postgres,
-                        ))
+                        .get_db_connection_string_with_metadata(
+                            database::Type::Shared(database::SharedEngine::Postgres),
+                            self.metadata.clone(),
+                        )
                         .await?
                 }
             }
@@ -69,11 +77,19 @@ impl Postgres {
 
         self
     }
+
+    /// Tag the database with `key=value` pairs separated by commas, like `purpose=sessions,retention=30d`
+    pub fn metadata(mut self, metadata: &str) -> Self {
+        self.metadata.extend(parse_metadata(metadata));
+
+        self
+    }
 }
 
 #[cfg(feature = "mongodb")]
 pub struct MongoDb {
     local_uri: Option<String>,
+    metadata: BTreeMap<String, String>,
 }
 
 /// Get a `mongodb::Database` from any factory
@@ -81,7 +97,10 @@ pub struct MongoDb {
 #[async_trait]
 impl ResourceBuilder<mongodb::Database> for MongoDb {
     fn new() -> Self {
-        Self { local_uri: None }
+        Self {
+            local_uri: None,
+            metadata: BTreeMap::new(),
+        }
     }
 
     async fn build(
@@ -91,7 +110,10 @@ impl ResourceBuilder<mongodb::Database> for MongoDb {
     ) -> Result<mongodb::Database, crate::Error> {
         let connection_string = match factory.get_environment() {
             shuttle_service::Environment::Production => factory
-                .get_db_connection_string(database::Type::Shared(database::SharedEngine::MongoDb))
+                .get_db_connection_string_with_metadata(
+                    database::Type::Shared(database::SharedEngine::MongoDb),
+                    self.metadata.clone(),
+                )
                 .await
                 .map_err(CustomError::new)?,
             shuttle_service::Environment::Local => {
@@ -99,9 +121,10 @@ impl ResourceBuilder<mongodb::Database> for MongoDb {
                     local_uri
                 } else {
                     factory
-                        .get_db_connection_string(database::Type::Shared(
-                            database::SharedEngine::MongoDb,
-                        ))
+                        .get_db_connection_string_with_metadata(
+                            database::Type::Shared(database::SharedEngine::MongoDb),
+                            self.metadata.clone(),
+                        )
                         .await
                         .map_err(CustomError::new)?
                 }
@@ -142,4 +165,23 @@ impl MongoDb {
 
         self
     }
+
+    /// Tag the database with `key=value` pairs separated by commas, like `purpose=sessions,retention=30d`
+    pub fn metadata(mut self, metadata: &str) -> Self {
+        self.metadata.extend(parse_metadata(metadata));
+
+        self
+    }
+}
+
+/// Split `key=value` pairs separated by commas. A key without a value gets an empty one
+fn parse_metadata(metadata: &str) -> impl Iterator<Item = (String, String)> + '_ {
+    metadata
+        .split(',')
+        .filter(|pair| !pair.trim().is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+
+            (key.trim().to_string(), value.trim().to_string())
+        })
 }
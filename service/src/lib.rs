@@ -165,6 +165,38 @@
 //! cargo shuttle deploy --name=$PROJECT_NAME
 //! ```
 //!
+//! ##### Drain your service before it is stopped
+//!
+//! When a deployment is stopped, for instance because a newer one replaces it, shuttle can first send a `POST` request
+//! to a path of your service so that it can finish in-flight work and flush its state. Add a `pre-stop` entry in the
+//! `Shuttle.toml`:
+//!
+//! ```toml
+//! pre-stop = "/drain"
+//! ```
+//!
+//! The service gets 10 seconds to answer before it is stopped anyway. The path can also be given with
+//! `cargo shuttle deploy --pre-stop=/drain`.
+//!
+//! ##### Tell when your service is ready
+//!
+//! A new deployment only starts running, and replaces the previous one, once its readiness probe passes. Configure the
+//! probe in a `[readiness]` section of the `Shuttle.toml`:
+//!
+//! ```toml
+//! [readiness]
+//! type = "http"            # or "tcp", or "none" to skip the probe
+//! path = "/health"         # requested by "http" probes, which pass on a 2xx status
+//! initial-delay-secs = 0
+//! interval-secs = 1
+//! timeout-secs = 1
+//! success-threshold = 1    # passes in a row needed to be ready
+//! failure-threshold = 30   # failures in a row after which the deployment crashes
+//! ```
+//!
+//! Every key is optional. Without a `[readiness]` section, `/` is requested for a second or so, and the deployment
+//! runs whether it answers or not.
+//!
 //! ##### Using Podman instead of Docker
 //! If you are using [Podman](https://podman.io/) instead of Docker, then `cargo shuttle run` will give
 //! `got unexpected error while inspecting docker container: error trying to connect: No such file or directory` error.
@@ -310,6 +342,21 @@ pub trait Factory: Send + Sync {
         db_type: database::Type,
     ) -> Result<String, crate::Error>;
 
+    /// Declare that the [Service][Service] requires a database, tagged with metadata like its purpose to tell it apart
+    /// from other databases of the service.
+    ///
+    /// Returns the connection string to the provisioned database. Factories which have nowhere to keep the metadata
+    /// drop it.
+    async fn get_db_connection_string_with_metadata(
+        &mut self,
+        db_type: database::Type,
+        metadata: BTreeMap<String, String>,
+    ) -> Result<String, crate::Error> {
+        let _ = metadata;
+
+        self.get_db_connection_string(db_type).await
+    }
+
     /// Get all the secrets for a service
     async fn get_secrets(&mut self) -> Result<BTreeMap<String, String>, crate::Error>;
 
@@ -5,15 +5,16 @@ use std::panic::AssertUnwindSafe;
 use std::path::{Path, PathBuf};
 
 use anyhow::{anyhow, Context};
-use cargo::core::compiler::{CompileMode, MessageFormat};
+use cargo::core::compiler::{CompileMode, MessageFormat, UnitInterner};
 use cargo::core::{Manifest, PackageId, Shell, Summary, Verbosity, Workspace};
-use cargo::ops::{clean, compile, CleanOptions, CompileOptions};
+use cargo::ops::{clean, compile, create_bcx, CleanOptions, CompileOptions};
 use cargo::util::interning::InternedString;
 use cargo::util::{homedir, ToSemver};
 use cargo::Config;
 use cargo_metadata::Message;
 use crossbeam_channel::Sender;
-use libloading::{Library, Symbol};
+pub use libloading::Library;
+use libloading::Symbol;
 use pipe::PipeWriter;
 use thiserror::Error as ThisError;
 use tracing::{error, trace};
@@ -66,6 +67,27 @@ impl Loader {
         }
     }
 
+    /// Check that a `.so` file can be loaded, without starting its service. Unlike [Loader::from_so_file], every symbol
+    /// the library needs is resolved straight away, so missing ones are found now rather than once the service gets to
+    /// calling them.
+    pub fn dry_load<P: AsRef<OsStr>>(so_path: P) -> Result<(), LoaderError> {
+        trace!(so_path = so_path.as_ref().to_str(), "dry loading .so path");
+        unsafe {
+            let lib = open_eagerly(so_path).map_err(LoaderError::Load)?;
+
+            {
+                let entrypoint: Symbol<CreateService> = lib
+                    .get(ENTRYPOINT_SYMBOL_NAME)
+                    .map_err(LoaderError::GetEntrypoint)?;
+
+                // The service is dropped again without ever being bootstrapped
+                drop(Box::from_raw(entrypoint()));
+            }
+
+            lib.close().map_err(LoaderError::Load)
+        }
+    }
+
     pub async fn load(
         self,
         factory: &mut dyn Factory,
@@ -100,11 +122,25 @@ impl Loader {
     }
 }
 
+#[cfg(unix)]
+unsafe fn open_eagerly<P: AsRef<OsStr>>(so_path: P) -> Result<Library, libloading::Error> {
+    use libloading::os::unix::{Library, RTLD_LOCAL, RTLD_NOW};
+
+    Library::open(Some(so_path), RTLD_NOW | RTLD_LOCAL).map(Into::into)
+}
+
+#[cfg(not(unix))]
+unsafe fn open_eagerly<P: AsRef<OsStr>>(so_path: P) -> Result<Library, libloading::Error> {
+    Library::new(so_path)
+}
+
 /// Given a project directory path, builds the crate
+/// With `locked`, the build fails instead of changing `Cargo.lock`, like `cargo build --locked`
 pub async fn build_crate(
     deployment_id: Uuid,
     project_path: &Path,
     release_mode: bool,
+    locked: bool,
     tx: Sender<Message>,
 ) -> anyhow::Result<PathBuf> {
     let (read, write) = pipe::pipe();
@@ -128,7 +164,10 @@ pub async fn build_crate(
         }
     });
 
-    let config = get_config(write)?;
+    let mut config = get_config(write)?;
+    if locked {
+        lock_dependencies(&mut config)?;
+    }
     let manifest_path = project_path.join("Cargo.toml");
     let mut ws = Workspace::new(&manifest_path, &config)?;
 
@@ -147,6 +186,36 @@ pub async fn build_crate(
     Ok(compilation?.cdylibs[0].path.clone())
 }
 
+/// Count the units cargo will compile for a project. Each of them ends in a `compiler-artifact` message, even when it
+/// is still fresh, so this is the total to measure the progress of [build_crate] against.
+pub fn compile_unit_count(
+    project_path: &Path,
+    release_mode: bool,
+    locked: bool,
+) -> anyhow::Result<usize> {
+    let mut config = config_with_shell(Shell::from_write(Box::new(std::io::sink())))?;
+    // Resolving the units would otherwise update `Cargo.lock` before the build gets to check it
+    if locked {
+        lock_dependencies(&mut config)?;
+    }
+    let manifest_path = project_path.join("Cargo.toml");
+    let mut ws = Workspace::new(&manifest_path, &config)?;
+
+    if let Ok(current) = ws.current_mut() {
+        ensure_cdylib(current.manifest_mut())?;
+    }
+
+    let opts = get_compile_options(&config, release_mode)?;
+    let interner = UnitInterner::new();
+    let bcx = create_bcx(&ws, &opts, &interner)?;
+
+    Ok(bcx
+        .unit_graph
+        .keys()
+        .filter(|unit| !unit.mode.is_run_custom_build())
+        .count())
+}
+
 pub fn clean_crate(project_path: &Path, release_mode: bool) -> anyhow::Result<Vec<String>> {
     let (read, write) = pipe::pipe();
     let project_path = project_path.to_owned();
@@ -194,7 +263,17 @@ pub fn clean_crate(project_path: &Path, release_mode: bool) -> anyhow::Result<Ve
 
 /// Get the default compile config with output redirected to writer
 pub fn get_config(writer: PipeWriter) -> anyhow::Result<Config> {
-    let mut shell = Shell::from_write(Box::new(writer));
+    config_with_shell(Shell::from_write(Box::new(writer)))
+}
+
+/// Make cargo refuse to change `Cargo.lock`, like it does when given `--locked`
+pub fn lock_dependencies(config: &mut Config) -> anyhow::Result<()> {
+    config.configure(0, false, None, false, true, false, &None, &[], &[])?;
+
+    Ok(())
+}
+
+fn config_with_shell(mut shell: Shell) -> anyhow::Result<Config> {
     shell.set_verbosity(Verbosity::Normal);
     let cwd = std::env::current_dir()
         .with_context(|| "couldn't get the current directory of the process")?;
@@ -324,4 +403,15 @@ mod tests {
             assert!(matches!(result, Err(LoaderError::Load(_))));
         }
     }
+
+    mod dry_load {
+        use crate::loader::{Loader, LoaderError};
+
+        #[test]
+        fn invalid() {
+            let result = Loader::dry_load("invalid.so");
+
+            assert!(matches!(result, Err(LoaderError::Load(_))));
+        }
+    }
 }
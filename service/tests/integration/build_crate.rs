@@ -6,9 +6,15 @@ use shuttle_service::loader::build_crate;
 async fn not_shuttle() {
     let (tx, _) = crossbeam_channel::unbounded();
     let project_path = format!("{}/tests/resources/not-shuttle", env!("CARGO_MANIFEST_DIR"));
-    let so_path = build_crate(Default::default(), Path::new(&project_path), false, tx)
-        .await
-        .unwrap();
+    let so_path = build_crate(
+        Default::default(),
+        Path::new(&project_path),
+        false,
+        false,
+        tx,
+    )
+    .await
+    .unwrap();
 
     assert!(
         so_path
@@ -27,20 +33,30 @@ async fn not_shuttle() {
 async fn not_lib() {
     let (tx, _) = crossbeam_channel::unbounded();
     let project_path = format!("{}/tests/resources/not-lib", env!("CARGO_MANIFEST_DIR"));
-    build_crate(Default::default(), Path::new(&project_path), false, tx)
-        .await
-        .unwrap();
+    build_crate(
+        Default::default(),
+        Path::new(&project_path),
+        false,
+        false,
+        tx,
+    )
+    .await
+    .unwrap();
 }
 
 #[tokio::test(flavor = "multi_thread")]
 async fn not_cdylib() {
     let (tx, _) = crossbeam_channel::unbounded();
     let project_path = format!("{}/tests/resources/not-cdylib", env!("CARGO_MANIFEST_DIR"));
-    assert!(
-        build_crate(Default::default(), Path::new(&project_path), false, tx)
-            .await
-            .is_ok()
-    );
+    assert!(build_crate(
+        Default::default(),
+        Path::new(&project_path),
+        false,
+        false,
+        tx
+    )
+    .await
+    .is_ok());
     assert!(PathBuf::from(project_path)
         .join("target/debug/libnot_cdylib.so")
         .exists());
@@ -50,11 +66,15 @@ async fn not_cdylib() {
 async fn is_cdylib() {
     let (tx, _) = crossbeam_channel::unbounded();
     let project_path = format!("{}/tests/resources/is-cdylib", env!("CARGO_MANIFEST_DIR"));
-    assert!(
-        build_crate(Default::default(), Path::new(&project_path), false, tx)
-            .await
-            .is_ok()
-    );
+    assert!(build_crate(
+        Default::default(),
+        Path::new(&project_path),
+        false,
+        false,
+        tx
+    )
+    .await
+    .is_ok());
     assert!(PathBuf::from(project_path)
         .join("target/debug/libis_cdylib.so")
         .exists());
@@ -68,7 +88,13 @@ async fn not_found() {
         "{}/tests/resources/non-existing",
         env!("CARGO_MANIFEST_DIR")
     );
-    build_crate(Default::default(), Path::new(&project_path), false, tx)
-        .await
-        .unwrap();
+    build_crate(
+        Default::default(),
+        Path::new(&project_path),
+        false,
+        false,
+        tx,
+    )
+    .await
+    .unwrap();
 }